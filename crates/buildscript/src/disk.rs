@@ -0,0 +1,459 @@
+//! Writing GPT disk images with a FAT32 EFI system partition
+//!
+//! The `image` task writes one of these when xorriso isn't installed. The image has a protective
+//! MBR, a GPT with a single EFI system partition at 1 MiB, and the backup GPT in its last sectors.
+//! The file system has a cluster per sector, and every name gets long file name entries, so names
+//! aren't limited to 8.3. Timestamps are fixed, and the GUIDs and the volume ID are hashed from the
+//! files, so the same files always make the same image.
+
+use std::collections::BTreeMap;
+
+const SECTOR: usize = 512;
+/// Where the partition starts, in sectors
+const PARTITION_START: usize = 2048;
+
+const GPT_ENTRIES: usize = 128;
+const GPT_ENTRY_SIZE: usize = 128;
+const GPT_ENTRY_SECTORS: usize = GPT_ENTRIES * GPT_ENTRY_SIZE / SECTOR;
+const GPT_HEADER_SIZE: usize = 92;
+/// C12A7328-F81F-11D2-BA4B-00A0C93EC93B, with its first three fields little endian
+const ESP_TYPE: [u8; 16] = [
+    0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B,
+];
+
+/// The sectors before the FATs, with the boot sector, the FSInfo sector and their backups
+const RESERVED_SECTORS: usize = 32;
+const FSINFO_SECTOR: usize = 1;
+const BACKUP_BOOT_SECTOR: usize = 6;
+/// A little over the 65525 clusters FAT32 needs, anything less is read as FAT16
+const MIN_CLUSTERS: usize = 65536;
+const ROOT_CLUSTER: u32 = 2;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+const DIR_ENTRY: usize = 32;
+/// UTF-16 units per long file name entry
+const LFN_CHARS: usize = 13;
+
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LFN: u8 = 0x0F;
+/// 1980-01-01, the earliest date FAT has
+const FAT_DATE: u16 = (1 << 5) | 1;
+
+fn put(buf: &mut [u8], offset: usize, bytes: &[u8]) {
+    buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// SplitMix64, to spread the hash of the files over the GUIDs
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Returns a version 4 GUID made from `seed`
+fn guid(seed: u64) -> [u8; 16] {
+    let mut guid = [0; 16];
+    guid[..8].copy_from_slice(&mix(seed).to_le_bytes());
+    guid[8..].copy_from_slice(&mix(!seed).to_le_bytes());
+    guid[7] = (guid[7] & 0x0F) | 0x40;
+    guid[8] = (guid[8] & 0x3F) | 0x80;
+    guid
+}
+
+enum Node<'a> {
+    File(&'a [u8]),
+    Dir(BTreeMap<&'a str, Node<'a>>),
+}
+
+fn insert<'a>(dir: &mut BTreeMap<&'a str, Node<'a>>, path: &'a str, data: &'a [u8]) {
+    match path.split_once('/') {
+        Some((name, rest)) => match dir.entry(name).or_insert_with(|| Node::Dir(BTreeMap::new())) {
+            Node::Dir(children) => insert(children, rest, data),
+            Node::File(_) => panic!("{} is both a file and a directory", name),
+        },
+        None => {
+            dir.insert(path, Node::File(data));
+        }
+    }
+}
+
+/// Returns the 8.3 name of an entry, the name itself in upper case if it is one
+///
+/// Other names get a numbered name, unique in the directory as `index` is.
+fn short_name(name: &str, index: usize) -> [u8; 11] {
+    let (stem, ext) = name
+        .rsplit_once('.')
+        .filter(|(stem, _)| !stem.is_empty())
+        .unwrap_or((name, ""));
+    let valid = |c: char| c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c);
+    let mut short = [b' '; 11];
+    if (1..=8).contains(&stem.len()) && ext.len() <= 3 && stem.chars().chain(ext.chars()).all(valid) {
+        put(&mut short, 0, stem.to_ascii_uppercase().as_bytes());
+        put(&mut short, 8, ext.to_ascii_uppercase().as_bytes());
+        return short;
+    }
+    let tail = format!("~{}", index + 1);
+    let basis: String = stem
+        .chars()
+        .filter(|c| valid(*c))
+        .take(8 - tail.len())
+        .collect::<String>()
+        .to_ascii_uppercase();
+    put(&mut short, 0, basis.as_bytes());
+    put(&mut short, basis.len(), tail.as_bytes());
+    let ext: String = ext.chars().filter(|c| valid(*c)).take(3).collect();
+    put(&mut short, 8, ext.to_ascii_uppercase().as_bytes());
+    short
+}
+
+fn lfn_entries(name: &str) -> usize {
+    name.encode_utf16().count().div_ceil(LFN_CHARS)
+}
+
+/// Returns the size of a directory, with the `.` and `..` entries unless it is the root
+fn dir_size(children: &BTreeMap<&str, Node>, root: bool) -> usize {
+    let dots = if root { 0 } else { 2 };
+    let entries: usize = children.keys().map(|name| lfn_entries(name) + 1).sum();
+    (dots + entries) * DIR_ENTRY
+}
+
+fn push_short_entry(out: &mut Vec<u8>, short: &[u8; 11], attr: u8, cluster: u32, size: u32) {
+    let mut entry = [0; DIR_ENTRY];
+    put(&mut entry, 0, short);
+    entry[11] = attr;
+    put(&mut entry, 16, &FAT_DATE.to_le_bytes());
+    put(&mut entry, 18, &FAT_DATE.to_le_bytes());
+    put(&mut entry, 20, &((cluster >> 16) as u16).to_le_bytes());
+    put(&mut entry, 24, &FAT_DATE.to_le_bytes());
+    put(&mut entry, 26, &(cluster as u16).to_le_bytes());
+    put(&mut entry, 28, &size.to_le_bytes());
+    out.extend_from_slice(&entry);
+}
+
+/// Pushes the long file name entries of `name`, the last part first as FAT stores them
+fn push_lfn_entries(out: &mut Vec<u8>, name: &str, short: &[u8; 11]) {
+    let checksum = short
+        .iter()
+        .fold(0u8, |sum, byte| sum.rotate_right(1).wrapping_add(*byte));
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = lfn_entries(name);
+    // Terminated if there is room, and padded with 0xFFFF
+    if units.len() < count * LFN_CHARS {
+        units.push(0);
+    }
+    units.resize(count * LFN_CHARS, 0xFFFF);
+    for part in (0..count).rev() {
+        let mut entry = [0; DIR_ENTRY];
+        entry[0] = (part + 1) as u8 | if part == count - 1 { 0x40 } else { 0 };
+        entry[11] = ATTR_LFN;
+        entry[13] = checksum;
+        let chars = &units[part * LFN_CHARS..][..LFN_CHARS];
+        let offsets = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+        for (offset, unit) in offsets.zip(chars) {
+            put(&mut entry, offset, &unit.to_le_bytes());
+        }
+        out.extend_from_slice(&entry);
+    }
+}
+
+/// The clusters of the file system, allocated in order
+struct Fat {
+    table: Vec<u32>,
+    data: Vec<u8>,
+}
+
+impl Fat {
+    /// Allocates a chain of clusters for `len` bytes, returning the first, or 0 if `len` is 0
+    fn allocate(&mut self, len: usize) -> u32 {
+        if len == 0 {
+            return 0;
+        }
+        let first = self.table.len();
+        let end = first + len.div_ceil(SECTOR);
+        self.table.extend((first + 1..end).map(|next| next as u32));
+        self.table.push(END_OF_CHAIN);
+        self.data.resize(self.data.len() + (end - first) * SECTOR, 0);
+        first as u32
+    }
+
+    fn write(&mut self, cluster: u32, bytes: &[u8]) {
+        put(&mut self.data, (cluster - ROOT_CLUSTER) as usize * SECTOR, bytes);
+    }
+
+    /// Writes the children of a directory, and the directory itself to `cluster`
+    fn write_dir(&mut self, children: &BTreeMap<&str, Node>, cluster: u32, parent: u32) {
+        let mut entries = Vec::new();
+        if cluster != ROOT_CLUSTER {
+            push_short_entry(&mut entries, b".          ", ATTR_DIRECTORY, cluster, 0);
+            // The root is cluster 0 to `..`
+            let parent = if parent == ROOT_CLUSTER { 0 } else { parent };
+            push_short_entry(&mut entries, b"..         ", ATTR_DIRECTORY, parent, 0);
+        }
+        for (index, (name, node)) in children.iter().enumerate() {
+            let short = short_name(name, index);
+            push_lfn_entries(&mut entries, name, &short);
+            match node {
+                Node::File(data) => {
+                    let first = self.allocate(data.len());
+                    if first != 0 {
+                        self.write(first, data);
+                    }
+                    push_short_entry(&mut entries, &short, ATTR_ARCHIVE, first, data.len() as u32);
+                }
+                Node::Dir(grandchildren) => {
+                    let first = self.allocate(dir_size(grandchildren, false));
+                    self.write_dir(grandchildren, first, cluster);
+                    push_short_entry(&mut entries, &short, ATTR_DIRECTORY, first, 0);
+                }
+            }
+        }
+        self.write(cluster, &entries);
+    }
+}
+
+/// Returns a FAT32 file system with the files under `root`
+fn fat32(root: &BTreeMap<&str, Node>, volume_id: u32) -> Vec<u8> {
+    let mut fat = Fat {
+        // The media byte, and the end of chain marker
+        table: vec![0x0FFF_FFF8, END_OF_CHAIN],
+        data: Vec::new(),
+    };
+    // Even an empty root needs a cluster
+    let root_cluster = fat.allocate(dir_size(root, true).max(DIR_ENTRY));
+    assert_eq!(root_cluster, ROOT_CLUSTER);
+    fat.write_dir(root, ROOT_CLUSTER, 0);
+
+    let used = fat.table.len() - 2;
+    let clusters = (used + used / 8).max(MIN_CLUSTERS);
+    let fat_sectors = ((clusters + 2) * 4).div_ceil(SECTOR);
+    let sectors = RESERVED_SECTORS + 2 * fat_sectors + clusters;
+
+    let mut boot = [0; SECTOR];
+    put(&mut boot, 0, &[0xEB, 0x58, 0x90]);
+    put(&mut boot, 3, b"HADRON  ");
+    put(&mut boot, 11, &(SECTOR as u16).to_le_bytes());
+    boot[13] = 1;
+    put(&mut boot, 14, &(RESERVED_SECTORS as u16).to_le_bytes());
+    boot[16] = 2;
+    boot[21] = 0xF8;
+    put(&mut boot, 24, &63u16.to_le_bytes());
+    put(&mut boot, 26, &255u16.to_le_bytes());
+    put(&mut boot, 28, &(PARTITION_START as u32).to_le_bytes());
+    put(&mut boot, 32, &(sectors as u32).to_le_bytes());
+    put(&mut boot, 36, &(fat_sectors as u32).to_le_bytes());
+    put(&mut boot, 44, &ROOT_CLUSTER.to_le_bytes());
+    put(&mut boot, 48, &(FSINFO_SECTOR as u16).to_le_bytes());
+    put(&mut boot, 50, &(BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+    boot[64] = 0x80;
+    boot[66] = 0x29;
+    put(&mut boot, 67, &volume_id.to_le_bytes());
+    put(&mut boot, 71, b"HADRON ESP FAT32   ");
+    put(&mut boot, 510, &[0x55, 0xAA]);
+
+    let mut fsinfo = [0; SECTOR];
+    put(&mut fsinfo, 0, &0x4161_5252u32.to_le_bytes());
+    put(&mut fsinfo, 484, &0x6141_7272u32.to_le_bytes());
+    put(&mut fsinfo, 488, &((clusters - used) as u32).to_le_bytes());
+    put(&mut fsinfo, 492, &(fat.table.len() as u32).to_le_bytes());
+    put(&mut fsinfo, 508, &0xAA55_0000u32.to_le_bytes());
+
+    let mut fs = vec![0; sectors * SECTOR];
+    for start in [0, BACKUP_BOOT_SECTOR] {
+        put(&mut fs, start * SECTOR, &boot);
+        put(&mut fs, (start + FSINFO_SECTOR) * SECTOR, &fsinfo);
+    }
+    let table: Vec<u8> = fat.table.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+    for copy in 0..2 {
+        put(&mut fs, (RESERVED_SECTORS + copy * fat_sectors) * SECTOR, &table);
+    }
+    put(&mut fs, (RESERVED_SECTORS + 2 * fat_sectors) * SECTOR, &fat.data);
+    fs
+}
+
+fn gpt_header(
+    lba: usize,
+    backup_lba: usize,
+    entries_lba: usize,
+    sectors: usize,
+    disk: &[u8; 16],
+    entries: &[u8],
+) -> [u8; SECTOR] {
+    let mut header = [0; SECTOR];
+    put(&mut header, 0, b"EFI PART");
+    put(&mut header, 8, &0x0001_0000u32.to_le_bytes());
+    put(&mut header, 12, &(GPT_HEADER_SIZE as u32).to_le_bytes());
+    put(&mut header, 24, &(lba as u64).to_le_bytes());
+    put(&mut header, 32, &(backup_lba as u64).to_le_bytes());
+    put(&mut header, 40, &((2 + GPT_ENTRY_SECTORS) as u64).to_le_bytes());
+    put(
+        &mut header,
+        48,
+        &((sectors - 2 - GPT_ENTRY_SECTORS) as u64).to_le_bytes(),
+    );
+    put(&mut header, 56, disk);
+    put(&mut header, 72, &(entries_lba as u64).to_le_bytes());
+    put(&mut header, 80, &(GPT_ENTRIES as u32).to_le_bytes());
+    put(&mut header, 84, &(GPT_ENTRY_SIZE as u32).to_le_bytes());
+    put(&mut header, 88, &crc32(entries).to_le_bytes());
+    let crc = crc32(&header[..GPT_HEADER_SIZE]);
+    put(&mut header, 16, &crc.to_le_bytes());
+    header
+}
+
+/// Returns a disk image with an EFI system partition holding `files`, by their `/` separated paths
+pub fn disk_image(files: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut root = BTreeMap::new();
+    let mut hash = 0xCBF2_9CE4_8422_2325u64;
+    for (path, data) in files {
+        insert(&mut root, path, data);
+        for byte in path.as_bytes().iter().chain(data) {
+            hash = (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01B3);
+        }
+    }
+    let fs = fat32(&root, mix(hash) as u32);
+
+    let partition_sectors = fs.len() / SECTOR;
+    let sectors = PARTITION_START + partition_sectors + GPT_ENTRY_SECTORS + 1;
+    let mut image = vec![0; sectors * SECTOR];
+
+    // The protective MBR, with a partition covering the whole disk
+    put(&mut image, 446, &[0x00, 0x00, 0x02, 0x00, 0xEE, 0xFF, 0xFF, 0xFF]);
+    put(&mut image, 454, &1u32.to_le_bytes());
+    put(
+        &mut image,
+        458,
+        &((sectors - 1).min(u32::MAX as usize) as u32).to_le_bytes(),
+    );
+    put(&mut image, 510, &[0x55, 0xAA]);
+
+    let mut entries = vec![0; GPT_ENTRIES * GPT_ENTRY_SIZE];
+    put(&mut entries, 0, &ESP_TYPE);
+    put(&mut entries, 16, &guid(hash ^ 1));
+    put(&mut entries, 32, &(PARTITION_START as u64).to_le_bytes());
+    put(
+        &mut entries,
+        40,
+        &((PARTITION_START + partition_sectors - 1) as u64).to_le_bytes(),
+    );
+    let name: Vec<u8> = "EFI system partition"
+        .encode_utf16()
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect();
+    put(&mut entries, 56, &name);
+
+    let disk = guid(hash);
+    let backup = sectors - 1;
+    let backup_entries = backup - GPT_ENTRY_SECTORS;
+    put(&mut image, SECTOR, &gpt_header(1, backup, 2, sectors, &disk, &entries));
+    put(&mut image, 2 * SECTOR, &entries);
+    put(&mut image, PARTITION_START * SECTOR, &fs);
+    put(&mut image, backup_entries * SECTOR, &entries);
+    put(
+        &mut image,
+        backup * SECTOR,
+        &gpt_header(backup, 1, backup_entries, sectors, &disk, &entries),
+    );
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> usize {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap()) as usize
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> usize {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize
+    }
+
+    /// Reads a file out of the file system, following the FAT and the long file names
+    fn read(fs: &[u8], path: &str) -> Option<Vec<u8>> {
+        let fat = RESERVED_SECTORS * SECTOR;
+        let data = (RESERVED_SECTORS + 2 * u32_at(fs, 36)) * SECTOR;
+        let chain = |mut cluster: usize| {
+            let mut bytes = Vec::new();
+            while cluster >= 2 && cluster < END_OF_CHAIN as usize {
+                bytes.extend_from_slice(&fs[data + (cluster - 2) * SECTOR..][..SECTOR]);
+                cluster = u32_at(fs, fat + cluster * 4);
+            }
+            bytes
+        };
+        let (mut cluster, mut size) = (ROOT_CLUSTER as usize, 0);
+        for name in path.split('/') {
+            let dir = chain(cluster);
+            let mut long = Vec::new();
+            let mut found = None;
+            for entry in dir.chunks(DIR_ENTRY).take_while(|entry| entry[0] != 0) {
+                if entry[11] == ATTR_LFN {
+                    let offsets = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+                    let part: Vec<u16> = offsets.map(|offset| u16_at(entry, offset) as u16).collect();
+                    long.splice(0..0, part);
+                    continue;
+                }
+                let end = long.iter().position(|unit| *unit == 0).unwrap_or(long.len());
+                if String::from_utf16(&long[..end]).unwrap() == name {
+                    found = Some((u16_at(entry, 20) << 16 | u16_at(entry, 26), u32_at(entry, 28)));
+                }
+                long.clear();
+            }
+            (cluster, size) = found?;
+        }
+        Some(chain(cluster)[..size].to_vec())
+    }
+
+    #[test]
+    fn test_disk_image() {
+        let kernel: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+        let files = [
+            ("EFI/BOOT/BOOTX64.EFI".to_string(), b"efi".to_vec()),
+            ("boot/hadron-kernel".to_string(), kernel.clone()),
+            ("boot/limine/limine.conf".to_string(), b"timeout: 0\n".to_vec()),
+            ("boot/a long name that takes two entries.txt".to_string(), Vec::new()),
+        ];
+        let image = disk_image(&files);
+        assert_eq!(image, disk_image(&files));
+        assert_eq!(image[510..512], [0x55, 0xAA]);
+
+        let header = &image[SECTOR..][..GPT_HEADER_SIZE];
+        assert_eq!(&header[..8], b"EFI PART");
+        let mut zeroed = header.to_vec();
+        put(&mut zeroed, 16, &[0; 4]);
+        assert_eq!(u32_at(header, 16), crc32(&zeroed) as usize);
+        let entries = &image[2 * SECTOR..][..GPT_ENTRIES * GPT_ENTRY_SIZE];
+        assert_eq!(u32_at(header, 88), crc32(entries) as usize);
+        let backup = &image[image.len() - SECTOR..];
+        assert_eq!(u32_at(backup, 24), image.len() / SECTOR - 1);
+        assert_eq!(entries[..16], ESP_TYPE);
+        let (first, last) = (u32_at(entries, 32), u32_at(entries, 40));
+        assert_eq!(first, PARTITION_START);
+
+        let fs = &image[first * SECTOR..(last + 1) * SECTOR];
+        assert_eq!(&fs[82..90], b"FAT32   ");
+        assert_eq!(u32_at(fs, 32), last + 1 - first);
+        for (path, data) in &files {
+            assert_eq!(read(fs, path).as_ref(), Some(data), "{}", path);
+        }
+        assert_eq!(read(fs, "boot/missing"), None);
+        assert_eq!(short_name("BOOTX64.EFI", 0), *b"BOOTX64 EFI");
+        assert_eq!(short_name("hadron-kernel", 1), *b"HADRON~2   ");
+        assert_eq!(short_name("limine.conf", 0), *b"LIMINE~1CON");
+    }
+}
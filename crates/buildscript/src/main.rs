@@ -1,4 +1,12 @@
-use std::{fmt::Display, process::Command, str::FromStr};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    str::FromStr,
+};
+
+mod disk;
+mod seal;
 
 #[derive(Debug)]
 pub enum Task {
@@ -8,6 +16,9 @@ pub enum Task {
     Menuconfig,
     Defconfig,
     Test,
+    Golden,
+    VerifyRepro,
+    Image,
 }
 
 impl FromStr for Task {
@@ -21,6 +32,9 @@ impl FromStr for Task {
             "menuconfig" => Ok(Task::Menuconfig),
             "defconfig" => Ok(Task::Defconfig),
             "test" => Ok(Task::Test),
+            "golden" => Ok(Task::Golden),
+            "verify-repro" => Ok(Task::VerifyRepro),
+            "image" => Ok(Task::Image),
             _ => Err(format!("Invalid task: {}", s)),
         }
     }
@@ -35,12 +49,22 @@ impl Display for Task {
             Task::Menuconfig => write!(f, "menuconfig"),
             Task::Defconfig => write!(f, "defconfig"),
             Task::Test => write!(f, "test"),
+            Task::Golden => write!(f, "golden"),
+            Task::VerifyRepro => write!(f, "verify-repro"),
+            Task::Image => write!(f, "image"),
         }
     }
 }
 
+/// The config written by menuconfig, which the kernel build script reads too
 const CONFIG_PATH: &str = "target/generated/kconfgen.toml";
 
+/// Returns where cargo puts the kernel in `target_dir`, for the release or debug profile
+fn kernel_path(target_dir: &str, release: bool) -> String {
+    let profile = if release { "release" } else { "debug" };
+    format!("{}/x86_64-unknown-hadron/{}/hadron-kernel", target_dir, profile)
+}
+
 fn main() {
     let mut args = std::env::args().skip(1);
     let task = Task::from_str(&args.next().unwrap_or("build".to_string())).unwrap();
@@ -52,52 +76,103 @@ fn main() {
         Task::Menuconfig => menuconfig(),
         Task::Defconfig => defconfig(),
         Task::Test => test(args.collect()),
+        Task::Golden => golden(args.collect()),
+        Task::VerifyRepro => verify_repro(args.collect()),
+        Task::Image => image(args.collect()),
     }
 }
 fn build(args: Vec<String>) {
     println!("building kernel...");
-    build_kernel("build", args);
+    build_kernel("build", &[], args);
 }
 
 fn run(args: Vec<String>) {
     println!("running kernel...");
-    build_kernel("run", args);
+    build_kernel("run", &[], args);
 }
 
-fn build_kernel(arg: &str, args: Vec<String>) {
-    /*
-    if !std::fs::exists(CONFIG_PATH).unwrap_or(false) {
-        eprintln!("Failed to read config file at {}", CONFIG_PATH);
-        println!("Generate a config using make defconfig");
-        return;
+/// Runs cargo on the kernel, sealing the image first when it is built to be run
+///
+/// `--release` in `args` selects the release profile, the rest are passed on after `--`.
+fn build_kernel(arg: &str, extra_features: &[&str], args: Vec<String>) -> ExitStatus {
+    let release = args.iter().any(|arg| arg == "--release");
+    let kernel = kernel_path("target", release);
+    let profile_command = |arg| {
+        let mut command = kernel_command(arg, extra_features);
+        if release {
+            command.arg("--release");
+        }
+        command
+    };
+    if arg == "run" {
+        let status = profile_command("build").status().unwrap();
+        if !status.success() {
+            return status;
+        }
+        seal_kernel(&kernel);
+    }
+    let mut command = profile_command(arg);
+    command.arg("--");
+    command.args(args.iter().filter(|arg| *arg != "--release"));
+    let status = command.status().unwrap();
+    if arg == "build" && status.success() {
+        seal_kernel(&kernel);
+    }
+    status
+}
+
+/// Writes the hashes the kernel verifies itself against at boot into the image
+fn seal_kernel(path: &str) {
+    match seal::seal(path) {
+        Ok(segments) => println!("sealed {} ({} segments)", path, segments),
+        Err(err) => {
+            eprintln!("failed to seal the kernel: {}", err);
+            std::process::exit(1);
+        }
     }
-    let config = menuconfig::deserialize(CONFIG_PATH).unwrap();
-    */
+}
+
+/// Returns the cargo command for the kernel
+///
+/// The kernel build script reads the config itself, see `menuconfig::codegen`. Paths are trimmed
+/// from the binary, and `SOURCE_DATE_EPOCH` defaults to the time of the last commit, so builds of a
+/// commit are identical wherever they are made.
+fn kernel_command(arg: &str, extra_features: &[&str]) -> Command {
     let mut command = Command::new("cargo");
     command.arg(arg);
     command.args(&["--package", "hadron-kernel"]);
-    /*
-    if !config.get::<bool>("debug").unwrap() {
-        command.args(&["--release"]);
-    }
-    let mut features: Vec<&'static str> = Vec::new();
-    if config.get::<bool>("serial").unwrap() {
-        features.push("printk_serial");
-    }
 
-    if !features.is_empty() {
-        command.args(&["--features", &features.join(",")]);
+    if !extra_features.is_empty() {
+        command.args(&["--features", &extra_features.join(",")]);
     }
-    */
 
     command.args(&["--target", "targets/x86_64-unknown-hadron.json"]);
     command.args(&[
         "-Zbuild-std=core,alloc,compiler_builtins",
         "-Zbuild-std-features=compiler-builtins-mem",
     ]);
-    command.arg("--");
-    command.args(args);
-    command.status().unwrap();
+    command.args(&[
+        "-Ztrim-paths",
+        "--config",
+        "profile.dev.trim-paths=\"all\"",
+        "--config",
+        "profile.release.trim-paths=\"all\"",
+    ]);
+    if std::env::var_os("SOURCE_DATE_EPOCH").is_none()
+        && let Some(epoch) = commit_epoch()
+    {
+        command.env("SOURCE_DATE_EPOCH", epoch);
+    }
+    command
+}
+
+/// Returns the time of the last commit in seconds since the UNIX epoch
+fn commit_epoch() -> Option<String> {
+    let output = Command::new("git").args(["log", "-1", "--format=%ct"]).output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
 
 fn clean() {
@@ -139,5 +214,284 @@ fn test(args: Vec<String>) {
         command.status().unwrap();
     }
 
-    build_kernel("test", args);
+    build_kernel("test", &[], args);
+}
+
+/// The devices of the `golden` task, matching `kernel/src/dev/pci/golden.manifest`
+///
+/// Every device has a fixed address, so the enumeration result doesn't depend on QEMU defaults.
+const GOLDEN_DEVICES: &[&str] = &[
+    "-nodefaults",
+    "-device",
+    "VGA,addr=01.0",
+    "-device",
+    "e1000,addr=02.0",
+    "-device",
+    "qemu-xhci,addr=03.0",
+    "-device",
+    "pci-bridge,chassis_nr=1,id=bridge1,addr=04.0",
+    "-device",
+    "e1000,bus=bridge1,addr=01.0",
+    "-device",
+    "isa-debug-exit,iobase=0xf4,iosize=0x04",
+];
+/// The exit code of QEMU when the kernel writes 0x10 to the debug exit device
+const GOLDEN_SUCCESS: i32 = (0x10 << 1) | 1;
+
+/// Boots the kernel with a fixed set of devices, and checks that PCI enumeration finds them
+fn golden(args: Vec<String>) {
+    println!("Running PCI golden test");
+    let mut qemu_args: Vec<String> = GOLDEN_DEVICES.iter().map(|arg| arg.to_string()).collect();
+    qemu_args.extend(args);
+    let status = build_kernel("run", &["pci_golden"], qemu_args);
+    if status.code() != Some(GOLDEN_SUCCESS) {
+        eprintln!("PCI golden test failed ({})", status);
+        std::process::exit(1);
+    }
+    println!("PCI golden test passed");
+}
+
+/// Where `verify-repro` builds, from scratch each time
+const REPRO_TARGET_DIR: &str = "target/repro";
+
+/// Builds the kernel twice from scratch, and checks that the two binaries are identical
+///
+/// Both builds use the same target directory, so paths that aren't trimmed match too. Passing
+/// `--release` checks release builds.
+fn verify_repro(args: Vec<String>) {
+    let release = args.iter().any(|arg| arg == "--release");
+    let kernel = kernel_path(REPRO_TARGET_DIR, release);
+
+    let mut images = Vec::new();
+    for pass in 1..=2 {
+        println!("verify-repro: build {} of 2", pass);
+        _ = std::fs::remove_dir_all(REPRO_TARGET_DIR);
+        let mut command = kernel_command("build", &[]);
+        command.args(["--target-dir", REPRO_TARGET_DIR]);
+        if release {
+            command.arg("--release");
+        }
+        if !command.status().unwrap().success() {
+            eprintln!("verify-repro: build {} failed", pass);
+            std::process::exit(1);
+        }
+        seal_kernel(&kernel);
+        images.push(std::fs::read(&kernel).unwrap());
+    }
+
+    let (first, second) = (&images[0], &images[1]);
+    if first == second {
+        println!("verify-repro: {} is reproducible ({} bytes)", kernel, first.len());
+        return;
+    }
+    let offset = first
+        .iter()
+        .zip(second)
+        .position(|(a, b)| a != b)
+        .unwrap_or(first.len().min(second.len()));
+    eprintln!(
+        "verify-repro: the builds differ, {} and {} bytes, first at offset {:#x}",
+        first.len(),
+        second.len(),
+        offset
+    );
+    std::process::exit(1);
+}
+
+/// Where the `image` task stages its files and writes the image
+const IMAGE_DIR: &str = "target/image";
+/// The Limine release the images boot with, the same as `[workspace.metadata.image-runner]`
+const LIMINE_BRANCH: &str = "v8.x-binary";
+const LIMINE_REPO: &str = "https://github.com/limine-bootloader/limine.git";
+
+/// Returns whether `program` can be run
+fn has_program(program: &str) -> bool {
+    Command::new(program)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+/// Returns the directory with the Limine binaries, cloning the release into `target/limine` if
+/// neither `--limine` nor `LIMINE_DIR` name one
+fn limine_dir(dir: Option<String>) -> PathBuf {
+    if let Some(dir) = dir.or_else(|| std::env::var("LIMINE_DIR").ok()) {
+        return PathBuf::from(dir);
+    }
+    let dir = PathBuf::from("target/limine");
+    if !dir.exists() {
+        println!("image: fetching Limine {}", LIMINE_BRANCH);
+        let status = Command::new("git")
+            .args(["clone", "--depth=1", "--branch", LIMINE_BRANCH, LIMINE_REPO])
+            .arg(&dir)
+            .status()
+            .unwrap();
+        if !status.success() {
+            eprintln!("image: failed to fetch Limine, pass its binaries with --limine <dir>");
+            std::process::exit(1);
+        }
+    }
+    dir
+}
+
+/// Returns the `limine` tool that installs the BIOS stages, building the one of the release if
+/// it has the source
+fn limine_tool(dir: &Path) -> Option<PathBuf> {
+    let tool = dir.join("limine");
+    if !tool.exists() && dir.join("limine.c").exists() {
+        _ = Command::new("make").arg("-C").arg(dir).stdout(Stdio::null()).status();
+    }
+    if tool.exists() {
+        return Some(tool);
+    }
+    has_program("limine").then(|| PathBuf::from("limine"))
+}
+
+/// Reads a file for the image, exiting if it can't
+fn image_file(path: &Path) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|err| {
+        eprintln!("image: failed to read {}: {}", path.display(), err);
+        std::process::exit(1);
+    })
+}
+
+/// Builds a bootable image of the kernel, for QEMU or for writing to a USB stick
+///
+/// `image [--iso|--disk] [--release] [--initramfs <tar>] [--cmdline <cmdline>] [--limine <dir>]`
+///
+/// The image has the sealed kernel, `limine.conf` with the command line filled in, the Limine
+/// binaries, and the initramfs next to the kernel, where the kernel asks Limine to load it from.
+/// `--iso` writes a hybrid ISO with xorriso, which boots from a CD or a disk, and `--disk` a GPT
+/// disk image with an EFI system partition, see `disk`. Without either, it is an ISO if xorriso is
+/// installed. Both boot with UEFI, and with BIOS too if the `limine` tool is there to install the
+/// BIOS stages.
+fn image(args: Vec<String>) {
+    let mut iso = None;
+    let mut release = false;
+    let mut initramfs = None;
+    let mut cmdline = String::new();
+    let mut limine = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().unwrap_or_else(|| {
+                eprintln!("image: {} needs a value", arg);
+                std::process::exit(1);
+            })
+        };
+        match arg.as_str() {
+            "--iso" => iso = Some(true),
+            "--disk" => iso = Some(false),
+            "--release" => release = true,
+            "--initramfs" => initramfs = Some(PathBuf::from(value())),
+            "--cmdline" => cmdline = value(),
+            "--limine" => limine = Some(value()),
+            _ => {
+                eprintln!("image: unknown argument {}", arg);
+                std::process::exit(1);
+            }
+        }
+    }
+    let iso = iso.unwrap_or_else(|| has_program("xorriso"));
+
+    println!("building kernel...");
+    let build_args = if release {
+        vec!["--release".to_string()]
+    } else {
+        Vec::new()
+    };
+    if !build_kernel("build", &[], build_args).success() {
+        std::process::exit(1);
+    }
+    let kernel = kernel_path("target", release);
+
+    let limine = limine_dir(limine);
+    let config = String::from_utf8(image_file(Path::new("limine.conf")))
+        .unwrap()
+        .replace("{{BINARY_NAME}}", "boot/hadron-kernel")
+        .replace("{{CMDLINE}}", &cmdline);
+    let mut files = vec![
+        ("boot/hadron-kernel".to_string(), image_file(Path::new(&kernel))),
+        ("boot/limine/limine.conf".to_string(), config.into_bytes()),
+        (
+            "boot/limine/limine-bios.sys".to_string(),
+            image_file(&limine.join("limine-bios.sys")),
+        ),
+        (
+            "EFI/BOOT/BOOTX64.EFI".to_string(),
+            image_file(&limine.join("BOOTX64.EFI")),
+        ),
+    ];
+    if let Some(initramfs) = &initramfs {
+        files.push(("boot/initramfs.tar".to_string(), image_file(initramfs)));
+    }
+
+    let output = if iso {
+        if !has_program("xorriso") {
+            eprintln!("image: --iso needs xorriso, or use --disk");
+            std::process::exit(1);
+        }
+        for name in ["limine-bios-cd.bin", "limine-uefi-cd.bin"] {
+            files.push((format!("boot/limine/{}", name), image_file(&limine.join(name))));
+        }
+        let root = Path::new(IMAGE_DIR).join("iso_root");
+        _ = std::fs::remove_dir_all(&root);
+        for (path, data) in &files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        }
+        let output = Path::new(IMAGE_DIR).join("hadron.iso");
+        let mut command = Command::new("xorriso");
+        command.args(["-as", "mkisofs", "-R", "-r", "-J"]);
+        command.args([
+            "-b",
+            "boot/limine/limine-bios-cd.bin",
+            "-no-emul-boot",
+            "-boot-load-size",
+            "4",
+        ]);
+        command.args(["-boot-info-table", "-hfsplus", "-apm-block-size", "2048"]);
+        command.args([
+            "--efi-boot",
+            "boot/limine/limine-uefi-cd.bin",
+            "-efi-boot-part",
+            "--efi-boot-image",
+        ]);
+        command
+            .arg("--protective-msdos-label")
+            .arg(&root)
+            .arg("-o")
+            .arg(&output);
+        // xorriso takes its timestamps from here, so the image is as reproducible as the kernel
+        if std::env::var_os("SOURCE_DATE_EPOCH").is_none()
+            && let Some(epoch) = commit_epoch()
+        {
+            command.env("SOURCE_DATE_EPOCH", epoch);
+        }
+        if !command.status().unwrap().success() {
+            eprintln!("image: xorriso failed");
+            std::process::exit(1);
+        }
+        output
+    } else {
+        let output = Path::new(IMAGE_DIR).join("hadron.img");
+        std::fs::create_dir_all(IMAGE_DIR).unwrap();
+        std::fs::write(&output, disk::disk_image(&files)).unwrap();
+        output
+    };
+
+    match limine_tool(&limine) {
+        Some(tool) => {
+            let status = Command::new(tool).arg("bios-install").arg(&output).status().unwrap();
+            if !status.success() {
+                eprintln!("image: failed to install the Limine BIOS stages");
+                std::process::exit(1);
+            }
+        }
+        None => println!("image: no limine tool to install the BIOS stages, the image only boots with UEFI"),
+    }
+    println!("image: wrote {}", output.display());
 }
@@ -0,0 +1,158 @@
+//! Sealing the kernel image after linking
+//!
+//! The kernel verifies its loaded image against the hashes written here, see
+//! `kernel/src/boot/image.rs`, which also has the layout of the seal. Every read only segment is
+//! hashed with FNV-1a, with the words the bootloader relocates set to their value at the link
+//! address, so the hashes don't depend on where the kernel is loaded.
+
+use std::ops::Range;
+
+const MAGIC: &[u8; 16] = b"hadron-imageseal";
+const MAX_RANGES: usize = 4;
+
+const PT_LOAD: u32 = 1;
+const PF_W: u32 = 1 << 1;
+const SHT_RELA: u32 = 4;
+const R_X86_64_RELATIVE: u64 = 8;
+
+/// The sizes of the ELF structures, and of the seal before its ranges
+const RELA_SIZE: usize = 24;
+const SEAL_HEADER: usize = 16 + 3 * 8;
+const SEAL_RANGE: usize = 4 * 8;
+
+fn u16_at(image: &[u8], offset: usize) -> Result<u16, String> {
+    image
+        .get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| format!("truncated at {:#x}", offset))
+}
+
+fn u32_at(image: &[u8], offset: usize) -> Result<u32, String> {
+    image
+        .get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| format!("truncated at {:#x}", offset))
+}
+
+fn u64_at(image: &[u8], offset: usize) -> Result<u64, String> {
+    image
+        .get(offset..offset + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| format!("truncated at {:#x}", offset))
+}
+
+/// A loadable segment, as its link address, file offset and size in the file
+struct Segment {
+    vaddr: u64,
+    offset: usize,
+    len: usize,
+    flags: u32,
+}
+
+impl Segment {
+    fn file_range(&self, vaddr: u64) -> Option<usize> {
+        let offset = vaddr.checked_sub(self.vaddr)? as usize;
+        (offset < self.len).then_some(self.offset + offset)
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+/// Writes the seal into a linked kernel, returning the number of sealed segments
+pub fn seal(path: &str) -> Result<usize, String> {
+    let mut image = std::fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    if image.get(..4) != Some(b"\x7fELF") || image.get(4) != Some(&2) {
+        return Err(format!("{}: not a 64 bit ELF file", path));
+    }
+
+    let phoff = u64_at(&image, 0x20)? as usize;
+    let phentsize = u16_at(&image, 0x36)? as usize;
+    let phnum = u16_at(&image, 0x38)? as usize;
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let header = phoff + i * phentsize;
+        if u32_at(&image, header)? != PT_LOAD {
+            continue;
+        }
+        segments.push(Segment {
+            flags: u32_at(&image, header + 4)?,
+            offset: u64_at(&image, header + 8)? as usize,
+            vaddr: u64_at(&image, header + 0x10)?,
+            len: u64_at(&image, header + 0x20)? as usize,
+        });
+    }
+    segments.sort_by_key(|segment| segment.vaddr);
+    let sealed: Vec<&Segment> = segments
+        .iter()
+        .filter(|segment| segment.flags & PF_W == 0 && segment.len > 0)
+        .collect();
+    if sealed.is_empty() || sealed.len() > MAX_RANGES || sealed[0].vaddr != segments[0].vaddr {
+        return Err(format!("{}: unexpected segments", path));
+    }
+
+    // The relocations are in a read only segment themselves
+    let shoff = u64_at(&image, 0x28)? as usize;
+    let shentsize = u16_at(&image, 0x3A)? as usize;
+    let shnum = u16_at(&image, 0x3C)? as usize;
+    let mut rela = (0, 0);
+    for i in 0..shnum {
+        let header = shoff + i * shentsize;
+        if u32_at(&image, header + 4)? == SHT_RELA {
+            rela = (u64_at(&image, header + 0x10)?, u64_at(&image, header + 0x20)?);
+        }
+    }
+
+    // Relocated words hold their value at the link address, which a RELATIVE relocation's
+    // addend is
+    let mut linked = image.clone();
+    if rela.1 > 0 {
+        let table = sealed
+            .iter()
+            .find_map(|segment| segment.file_range(rela.0))
+            .ok_or_else(|| format!("{}: relocations outside of the read only segments", path))?;
+        for entry in 0..rela.1 as usize / RELA_SIZE {
+            let entry = table + entry * RELA_SIZE;
+            let offset = u64_at(&image, entry)?;
+            let Some(slot) = sealed.iter().find_map(|segment| segment.file_range(offset)) else {
+                continue;
+            };
+            if u64_at(&image, entry + 8)? & 0xFFFF_FFFF != R_X86_64_RELATIVE {
+                return Err(format!("{}: unsupported relocation at {:#x}", path, offset));
+            }
+            let addend = u64_at(&image, entry + 16)?;
+            linked[slot..slot + 8].copy_from_slice(&addend.to_le_bytes());
+        }
+    }
+
+    let seals: Vec<Range<usize>> = image
+        .windows(MAGIC.len())
+        .enumerate()
+        .filter(|(_, window)| window == MAGIC)
+        .map(|(offset, _)| offset..offset + SEAL_HEADER + MAX_RANGES * SEAL_RANGE)
+        .collect();
+    let [seal] = seals.as_slice() else {
+        return Err(format!("{}: expected one seal, found {}", path, seals.len()));
+    };
+    if sealed
+        .iter()
+        .any(|segment| seal.start < segment.offset + segment.len && segment.offset < seal.end)
+    {
+        return Err(format!("{}: the seal is in a read only segment", path));
+    }
+
+    let mut fields = vec![rela.0, rela.1, sealed.len() as u64];
+    for segment in &sealed {
+        let hash = fnv1a(&linked[segment.offset..segment.offset + segment.len]);
+        fields.extend([segment.vaddr, segment.len as u64, segment.flags as u64, hash]);
+    }
+    for (i, field) in fields.iter().enumerate() {
+        let offset = seal.start + MAGIC.len() + i * 8;
+        image[offset..offset + 8].copy_from_slice(&field.to_le_bytes());
+    }
+    std::fs::write(path, image).map_err(|err| format!("{}: {}", path, err))?;
+    Ok(sealed.len())
+}
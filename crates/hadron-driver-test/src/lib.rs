@@ -0,0 +1,495 @@
+//! Mock kernel services for testing drivers on the host
+//!
+//! A [`MockKernel`] provides the driver API table backed by fake devices: PCI functions with a
+//! [`FakeConfigSpace`], device memory made of [`FakeMmio`] regions, and interrupts raised by the
+//! test. Once installed, [`InstalledKernel::api`] is handed to the driver in place of the kernel's
+//! table, and the test inspects what the driver did through the [`InstalledKernel`].
+//!
+//! The mock is installed for the current thread, so every test gets its own.
+
+mod mmio;
+mod pci;
+
+use std::{
+    alloc::{self, Layout},
+    cell::RefCell,
+    collections::BTreeMap,
+    ffi::c_void,
+    marker::PhantomData,
+    ptr,
+};
+
+use hadron_kernel::{
+    module::{
+        abi::ABI_VERSION,
+        api::{ApiIrqHandler, KernelApi},
+    },
+    util::kprint::LogLevel,
+};
+
+pub use mmio::{FakeMmio, ReadFn, WriteFn};
+pub use pci::FakeConfigSpace;
+
+/// The first vector handed out by `request_irq`, like the kernel's first free vector
+pub const FIRST_VECTOR: u8 = 0x30;
+/// The alignment of DMA buffers, the size of a page
+const DMA_ALIGN: usize = 4096;
+
+/// A PCI function address, as bus, device and function
+pub type Bdf = (u8, u8, u8);
+
+/// A mapping of part of a [`FakeMmio`] region, returned by `mmio_map`
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+    virt: usize,
+    size: usize,
+    region: usize,
+    offset: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    config: BTreeMap<Bdf, FakeConfigSpace>,
+    mmio: Vec<FakeMmio>,
+    mappings: Vec<Mapping>,
+    irqs: Vec<(u8, ApiIrqHandler, usize)>,
+    logs: Vec<(LogLevel, String)>,
+    time_ns: u64,
+    /// Bytes of the heap allocated through the API and not freed yet
+    heap_bytes: usize,
+    /// The DMA buffers allocated through the API, by address
+    dma: BTreeMap<usize, Layout>,
+}
+
+thread_local! {
+    static STATE: RefCell<Option<State>> = const { RefCell::new(None) };
+}
+
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    STATE.with_borrow_mut(|state| f(state.as_mut().expect("no mock kernel is installed on this thread")))
+}
+
+/// Builds the fake devices of a mock kernel
+#[derive(Debug, Default)]
+pub struct MockKernel {
+    state: State,
+}
+
+impl MockKernel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a PCI function
+    pub fn pci(mut self, bdf: Bdf, space: FakeConfigSpace) -> Self {
+        self.state.config.insert(bdf, space);
+        self
+    }
+
+    /// Adds a region of device memory
+    pub fn mmio(mut self, region: FakeMmio) -> Self {
+        assert!(
+            !self
+                .state
+                .mmio
+                .iter()
+                .any(|other| region.phys < other.phys + other.size() as u64
+                    && other.phys < region.phys + region.size() as u64),
+            "MMIO regions overlap"
+        );
+        self.state.mmio.push(region);
+        self
+    }
+
+    /// Sets the initial monotonic time
+    pub fn time_ns(mut self, ns: u64) -> Self {
+        self.state.time_ns = ns;
+        self
+    }
+
+    /// Installs the mock for the current thread, until the returned handle is dropped
+    pub fn install(self) -> InstalledKernel {
+        STATE.with_borrow_mut(|state| {
+            assert!(state.is_none(), "a mock kernel is already installed on this thread");
+            *state = Some(self.state);
+        });
+        InstalledKernel { _thread: PhantomData }
+    }
+}
+
+/// A mock kernel installed on the current thread
+#[derive(Debug)]
+pub struct InstalledKernel {
+    /// The mock belongs to the thread it was installed on
+    _thread: PhantomData<*const ()>,
+}
+
+impl InstalledKernel {
+    /// Returns the API table to hand to the driver
+    pub fn api(&self) -> &'static KernelApi {
+        &MOCK_API
+    }
+
+    /// Returns the messages logged so far
+    pub fn logs(&self) -> Vec<(LogLevel, String)> {
+        with_state(|state| state.logs.clone())
+    }
+
+    pub fn advance(&self, ns: u64) {
+        with_state(|state| state.time_ns += ns);
+    }
+
+    /// Returns the vectors requested by the driver
+    pub fn irqs(&self) -> Vec<u8> {
+        with_state(|state| state.irqs.iter().map(|(vector, ..)| *vector).collect())
+    }
+
+    /// Delivers an interrupt, returning whether a handler was registered on the vector
+    ///
+    /// The handler runs on the current thread, and may use the API.
+    pub fn raise_irq(&self, vector: u8) -> bool {
+        let irq = with_state(|state| state.irqs.iter().find(|(other, ..)| *other == vector).copied());
+        match irq {
+            Some((vector, handler, data)) => {
+                handler(vector, data as *mut c_void);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns a copy of the configuration space of a PCI function
+    pub fn config_space(&self, bdf: Bdf) -> Option<FakeConfigSpace> {
+        with_state(|state| state.config.get(&bdf).cloned())
+    }
+
+    /// Returns the current value of a register, without calling the read callback
+    pub fn reg(&self, phys: u64, offset: usize) -> u32 {
+        with_state(|state| {
+            let region = state
+                .mmio
+                .iter()
+                .find(|region| region.contains(phys, offset + 4))
+                .expect("no MMIO region at the address");
+            region.reg((phys - region.phys) as usize + offset)
+        })
+    }
+
+    /// Returns the number of live mappings of device memory
+    pub fn mappings(&self) -> usize {
+        with_state(|state| state.mappings.len())
+    }
+
+    /// Returns the heap bytes and DMA buffers the driver still holds
+    pub fn leaks(&self) -> (usize, usize) {
+        with_state(|state| (state.heap_bytes, state.dma.len()))
+    }
+}
+
+impl Drop for InstalledKernel {
+    fn drop(&mut self) {
+        let state = STATE.with_borrow_mut(|state| state.take());
+        // Leaked DMA buffers are freed, so a failing test doesn't leak memory into the next one
+        for (addr, layout) in state.map(|state| state.dma).unwrap_or_default() {
+            unsafe { alloc::dealloc(addr as *mut u8, layout) };
+        }
+    }
+}
+
+static MOCK_API: KernelApi = KernelApi {
+    version: ABI_VERSION,
+    size: size_of::<KernelApi>(),
+    log,
+    alloc: heap_alloc,
+    free: heap_free,
+    monotonic_ns,
+    pci_read_u32,
+    pci_write_u32,
+    mmio_map,
+    mmio_unmap,
+    request_irq,
+    free_irq,
+    dma_alloc,
+    dma_free,
+    mmio_read_u32,
+    mmio_write_u32,
+    mmio_read_u16,
+    mmio_write_u16,
+};
+
+unsafe extern "C" fn log(level: u8, msg: *const u8, len: usize) {
+    let level = match level {
+        0 => LogLevel::Debug,
+        1 => LogLevel::Info,
+        2 => LogLevel::Warn,
+        3 => LogLevel::Error,
+        _ => LogLevel::Fatal,
+    };
+    let msg = unsafe { std::slice::from_raw_parts(msg, len) };
+    let msg = String::from_utf8_lossy(msg).into_owned();
+    with_state(|state| state.logs.push((level, msg)));
+}
+
+extern "C" fn heap_alloc(size: usize, align: usize) -> *mut c_void {
+    match Layout::from_size_align(size, align) {
+        Ok(layout) if size != 0 => {
+            with_state(|state| state.heap_bytes += size);
+            unsafe { alloc::alloc(layout).cast() }
+        }
+        _ => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn heap_free(ptr: *mut c_void, size: usize, align: usize) {
+    if let Ok(layout) = Layout::from_size_align(size, align)
+        && !ptr.is_null()
+    {
+        with_state(|state| state.heap_bytes -= size);
+        unsafe { alloc::dealloc(ptr.cast(), layout) };
+    }
+}
+
+extern "C" fn monotonic_ns() -> u64 {
+    with_state(|state| state.time_ns)
+}
+
+extern "C" fn pci_read_u32(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    with_state(|state| match state.config.get(&(bus, device, function)) {
+        Some(space) => space.read_u32(offset),
+        // Like a real bus, missing functions read as all ones
+        None => u32::MAX,
+    })
+}
+
+extern "C" fn pci_write_u32(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    with_state(|state| {
+        if let Some(space) = state.config.get_mut(&(bus, device, function)) {
+            space.write_u32(offset, value);
+        }
+    })
+}
+
+unsafe extern "C" fn mmio_map(phys: u64, size: usize) -> *mut c_void {
+    with_state(|state| {
+        let Some(region) = state.mmio.iter().position(|region| region.contains(phys, size)) else {
+            return ptr::null_mut();
+        };
+        let offset = (phys - state.mmio[region].phys) as usize;
+        // The registers are never reallocated, so the mapping points into them directly
+        let virt = unsafe { state.mmio[region].regs.as_mut_ptr().cast::<u8>().add(offset) };
+        state.mappings.push(Mapping {
+            virt: virt as usize,
+            size,
+            region,
+            offset,
+        });
+        virt.cast()
+    })
+}
+
+unsafe extern "C" fn mmio_unmap(virt: *mut c_void, size: usize) {
+    with_state(|state| {
+        let idx = state
+            .mappings
+            .iter()
+            .position(|mapping| mapping.virt == virt as usize && mapping.size == size)
+            .expect("unmapping memory that wasn't mapped");
+        state.mappings.swap_remove(idx);
+    })
+}
+
+/// Returns the region and register offset of an access, panicking if it isn't mapped
+fn register(state: &State, addr: usize, size: usize) -> (usize, usize) {
+    let mapping = state
+        .mappings
+        .iter()
+        .find(|mapping| addr >= mapping.virt && addr + size <= mapping.virt + mapping.size)
+        .unwrap_or_else(|| panic!("register access at {:#x} outside of mapped device memory", addr));
+    (mapping.region, mapping.offset + addr - mapping.virt)
+}
+
+unsafe extern "C" fn mmio_read_u32(addr: *const c_void) -> u32 {
+    with_state(|state| {
+        let (region, offset) = register(state, addr as usize, 4);
+        state.mmio[region].read(offset)
+    })
+}
+
+unsafe extern "C" fn mmio_write_u32(addr: *mut c_void, value: u32) {
+    with_state(|state| {
+        let (region, offset) = register(state, addr as usize, 4);
+        state.mmio[region].write(offset, value);
+    })
+}
+
+/// Reads half of the 32-bit register holding a 16-bit one, so callbacks see full registers
+unsafe extern "C" fn mmio_read_u16(addr: *const c_void) -> u16 {
+    with_state(|state| {
+        let (region, offset) = register(state, addr as usize, 2);
+        assert_eq!(offset % 2, 0, "unaligned register access at {:#x}", offset);
+        let value = state.mmio[region].read(offset & !3);
+        (value >> ((offset & 2) * 8)) as u16
+    })
+}
+
+/// Writes half of the 32-bit register holding a 16-bit one, keeping the other half
+unsafe extern "C" fn mmio_write_u16(addr: *mut c_void, value: u16) {
+    with_state(|state| {
+        let (region, offset) = register(state, addr as usize, 2);
+        assert_eq!(offset % 2, 0, "unaligned register access at {:#x}", offset);
+        let shift = (offset & 2) * 8;
+        let old = state.mmio[region].reg(offset & !3);
+        let value = (old & !(0xFFFF << shift)) | ((value as u32) << shift);
+        state.mmio[region].write(offset & !3, value);
+    })
+}
+
+extern "C" fn request_irq(handler: ApiIrqHandler, data: *mut c_void) -> i32 {
+    with_state(|state| {
+        let Some(vector) = (FIRST_VECTOR..=u8::MAX).find(|vector| state.irqs.iter().all(|(other, ..)| other != vector))
+        else {
+            return -1;
+        };
+        state.irqs.push((vector, handler, data as usize));
+        vector as i32
+    })
+}
+
+extern "C" fn free_irq(vector: u8) {
+    with_state(|state| state.irqs.retain(|(other, ..)| *other != vector));
+}
+
+unsafe extern "C" fn dma_alloc(size: usize, phys: *mut u64) -> *mut c_void {
+    let Ok(layout) = Layout::from_size_align(size.max(1), DMA_ALIGN) else {
+        return ptr::null_mut();
+    };
+    let virt = unsafe { alloc::alloc_zeroed(layout) };
+    if virt.is_null() {
+        return ptr::null_mut();
+    }
+    with_state(|state| state.dma.insert(virt as usize, layout));
+    // There is no IOMMU to emulate, devices see host addresses
+    unsafe { phys.write(virt as u64) };
+    virt.cast()
+}
+
+unsafe extern "C" fn dma_free(virt: *mut c_void, _size: usize) {
+    let layout =
+        with_state(|state| state.dma.remove(&(virt as usize))).expect("freeing a buffer that wasn't allocated");
+    unsafe { alloc::dealloc(virt.cast(), layout) };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ptr::NonNull,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use super::*;
+
+    const BAR: u32 = 0xFE00_0000;
+    const STATUS: usize = 0x00;
+    const DOORBELL: usize = 0x04;
+    const IRQ_COUNT: usize = 0x08;
+
+    /// A driver for a device that counts doorbell writes
+    struct ToyDriver {
+        api: &'static KernelApi,
+        regs: NonNull<u8>,
+        vector: u8,
+    }
+
+    extern "C" fn toy_irq(_vector: u8, data: *mut c_void) {
+        let driver = unsafe { &*(data as *const ToyDriver) };
+        let count = unsafe { driver.api.read_u32(driver.regs, IRQ_COUNT) };
+        driver.api.log(LogLevel::Info, format_args!("toy: interrupt {}", count));
+    }
+
+    impl ToyDriver {
+        fn probe(api: &'static KernelApi, bdf: Bdf) -> Option<Box<Self>> {
+            if (api.pci_read_u32)(bdf.0, bdf.1, bdf.2, 0) != 0x1234_8086 {
+                return None;
+            }
+            (api.pci_write_u32)(bdf.0, bdf.1, bdf.2, 0x10, u32::MAX);
+            let size = !(api.pci_read_u32)(bdf.0, bdf.1, bdf.2, 0x10) + 1;
+            (api.pci_write_u32)(bdf.0, bdf.1, bdf.2, 0x10, BAR);
+            let phys = (api.pci_read_u32)(bdf.0, bdf.1, bdf.2, 0x10);
+            let regs = unsafe { api.map_mmio(hadron_kernel::arch::PhysAddr::new(phys as usize), size as usize) }?;
+            if unsafe { api.read_u32(regs, STATUS) } & 1 == 0 {
+                return None;
+            }
+            let mut driver = Box::new(Self { api, regs, vector: 0 });
+            let data = &mut *driver as *mut Self as *mut c_void;
+            driver.vector = u8::try_from((api.request_irq)(toy_irq, data)).ok()?;
+            Some(driver)
+        }
+
+        fn ring(&self) {
+            unsafe { self.api.write_u32(self.regs, DOORBELL, 1) };
+        }
+    }
+
+    impl Drop for ToyDriver {
+        fn drop(&mut self) {
+            (self.api.free_irq)(self.vector);
+            unsafe { (self.api.mmio_unmap)(self.regs.as_ptr().cast(), 0x1000) };
+        }
+    }
+
+    #[test]
+    fn mock_kernel_toy_driver() {
+        static READS: AtomicU32 = AtomicU32::new(0);
+        let kernel = MockKernel::new()
+            .pci((0, 3, 0), FakeConfigSpace::new(0x8086, 0x1234).with_bar(0, BAR, 0x1000))
+            .mmio(
+                FakeMmio::new(BAR as u64, 0x1000)
+                    .with_reg(STATUS, 1)
+                    .on_read(|regs, offset| {
+                        READS.fetch_add(1, Ordering::Relaxed);
+                        regs[offset / 4]
+                    })
+                    .on_write(|regs, offset, _| {
+                        if offset == DOORBELL {
+                            regs[IRQ_COUNT / 4] += 1;
+                        }
+                    }),
+            )
+            .install();
+        assert!(ToyDriver::probe(kernel.api(), (0, 4, 0)).is_none());
+
+        let driver = ToyDriver::probe(kernel.api(), (0, 3, 0)).unwrap();
+        assert_eq!(kernel.irqs(), [FIRST_VECTOR]);
+        driver.ring();
+        driver.ring();
+        assert_eq!(kernel.reg(BAR as u64, IRQ_COUNT), 2);
+        assert!(kernel.raise_irq(FIRST_VECTOR));
+        assert!(!kernel.raise_irq(FIRST_VECTOR + 1));
+        assert_eq!(READS.load(Ordering::Relaxed), 2);
+        assert_eq!(kernel.logs(), [(LogLevel::Info, String::from("toy: interrupt 2"))]);
+
+        drop(driver);
+        assert!(kernel.irqs().is_empty());
+        assert_eq!(kernel.mappings(), 0);
+    }
+
+    #[test]
+    fn mock_kernel_dma_and_time() {
+        let kernel = MockKernel::new().time_ns(1_000).install();
+        let api = kernel.api();
+        kernel.advance(500);
+        assert_eq!((api.monotonic_ns)(), 1_500);
+
+        let mut phys = 0;
+        let buf = unsafe { (api.dma_alloc)(100, &mut phys) };
+        assert!(!buf.is_null());
+        assert_eq!(phys % DMA_ALIGN as u64, 0);
+        let heap = (api.alloc)(32, 8);
+        assert_eq!(kernel.leaks(), (32, 1));
+        unsafe {
+            (api.free)(heap, 32, 8);
+            (api.dma_free)(buf, 100);
+        }
+        assert_eq!(kernel.leaks(), (0, 0));
+    }
+}
@@ -0,0 +1,90 @@
+//! Fake device memory
+
+use std::fmt;
+
+/// Computes the value of a register read, given the registers and the offset
+pub type ReadFn = Box<dyn FnMut(&[u32], usize) -> u32>;
+/// Handles a register write, given the registers, the offset and the value
+pub type WriteFn = Box<dyn FnMut(&mut [u32], usize, u32)>;
+
+/// A region of fake device memory at a physical address
+///
+/// The registers are backed by memory, so a region behaves like RAM unless callbacks emulate the
+/// device: status registers that change on their own, or writes that start something. 16-bit
+/// accesses read or write half of a register, so the callbacks always see whole registers.
+pub struct FakeMmio {
+    pub(crate) phys: u64,
+    pub(crate) regs: Box<[u32]>,
+    on_read: Option<ReadFn>,
+    on_write: Option<WriteFn>,
+}
+
+impl FakeMmio {
+    pub fn new(phys: u64, size: usize) -> Self {
+        assert_eq!(size % 4, 0, "MMIO regions are made of 32-bit registers");
+        Self {
+            phys,
+            regs: vec![0; size / 4].into_boxed_slice(),
+            on_read: None,
+            on_write: None,
+        }
+    }
+
+    /// Sets the initial value of a register
+    pub fn with_reg(mut self, offset: usize, value: u32) -> Self {
+        self.regs[offset / 4] = value;
+        self
+    }
+
+    pub fn on_read(mut self, read: impl FnMut(&[u32], usize) -> u32 + 'static) -> Self {
+        self.on_read = Some(Box::new(read));
+        self
+    }
+
+    pub fn on_write(mut self, write: impl FnMut(&mut [u32], usize, u32) + 'static) -> Self {
+        self.on_write = Some(Box::new(write));
+        self
+    }
+
+    pub fn size(&self) -> usize {
+        self.regs.len() * 4
+    }
+
+    /// Returns whether the physical range is inside the region
+    pub(crate) fn contains(&self, phys: u64, size: usize) -> bool {
+        phys >= self.phys && phys + size as u64 <= self.phys + self.size() as u64
+    }
+
+    pub fn reg(&self, offset: usize) -> u32 {
+        self.regs[offset / 4]
+    }
+
+    pub(crate) fn read(&mut self, offset: usize) -> u32 {
+        check_offset(offset);
+        match &mut self.on_read {
+            Some(read) => read(&self.regs, offset),
+            None => self.regs[offset / 4],
+        }
+    }
+
+    pub(crate) fn write(&mut self, offset: usize, value: u32) {
+        check_offset(offset);
+        match &mut self.on_write {
+            Some(write) => write(&mut self.regs, offset, value),
+            None => self.regs[offset / 4] = value,
+        }
+    }
+}
+
+fn check_offset(offset: usize) {
+    assert_eq!(offset % 4, 0, "unaligned register access at {:#x}", offset);
+}
+
+impl fmt::Debug for FakeMmio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FakeMmio")
+            .field("phys", &format_args!("{:#x}", self.phys))
+            .field("size", &self.size())
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,107 @@
+//! Fake PCI configuration space
+
+/// The configuration space of a fake PCI function
+///
+/// Reads and writes go to the 256 bytes of the space, except for BARs, which answer size probes
+/// like real hardware: after writing all ones, a BAR reads back the mask of its size.
+#[derive(Debug, Clone)]
+pub struct FakeConfigSpace {
+    data: [u8; 256],
+    /// The size of every BAR, 0 if it is not implemented
+    bar_sizes: [u32; 6],
+}
+
+impl FakeConfigSpace {
+    pub const VENDOR_ID: u8 = 0x00;
+    pub const COMMAND: u8 = 0x04;
+    pub const CLASS: u8 = 0x08;
+    pub const BAR0: u8 = 0x10;
+    pub const INTERRUPT_LINE: u8 = 0x3C;
+
+    pub fn new(vendor_id: u16, device_id: u16) -> Self {
+        let mut space = Self {
+            data: [0; 256],
+            bar_sizes: [0; 6],
+        };
+        space.write_u32(Self::VENDOR_ID, (device_id as u32) << 16 | vendor_id as u32);
+        space
+    }
+
+    /// Sets the class, subclass and programming interface
+    pub fn with_class(mut self, class: u8, subclass: u8, prog_if: u8) -> Self {
+        let revision = self.read_u32(Self::CLASS) & 0xFF;
+        let value = (class as u32) << 24 | (subclass as u32) << 16 | (prog_if as u32) << 8 | revision;
+        self.data[Self::CLASS as usize..][..4].copy_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    /// Adds a 32-bit memory BAR at `addr`, `size` must be a power of two
+    pub fn with_bar(mut self, bar: usize, addr: u32, size: u32) -> Self {
+        assert!(size.is_power_of_two() && size >= 16, "invalid BAR size {:#x}", size);
+        assert_eq!(addr & (size - 1), 0, "BAR address must be aligned to its size");
+        self.bar_sizes[bar] = size;
+        self.data[Self::BAR0 as usize + bar * 4..][..4].copy_from_slice(&addr.to_le_bytes());
+        self
+    }
+
+    pub fn with_interrupt_line(mut self, line: u8) -> Self {
+        self.data[Self::INTERRUPT_LINE as usize] = line;
+        self
+    }
+
+    /// Sets bytes of the space directly, like capabilities
+    pub fn with_bytes(mut self, offset: u8, bytes: &[u8]) -> Self {
+        self.data[offset as usize..][..bytes.len()].copy_from_slice(bytes);
+        self
+    }
+
+    fn bar(offset: u8) -> Option<usize> {
+        let bar = offset.checked_sub(Self::BAR0)? as usize / 4;
+        (bar < 6).then_some(bar)
+    }
+
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        let offset = offset as usize & !3;
+        u32::from_le_bytes(self.data[offset..][..4].try_into().unwrap())
+    }
+
+    pub fn write_u32(&mut self, offset: u8, value: u32) {
+        let value = match Self::bar(offset & !3) {
+            // The address bits below the size are hardwired to 0, and so are unimplemented BARs
+            Some(bar) => match self.bar_sizes[bar] {
+                0 => 0,
+                size => value & !(size - 1),
+            },
+            None => value,
+        };
+        let offset = offset as usize & !3;
+        self.data[offset..][..4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&self) -> &[u8; 256] {
+        &self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_space_bar_sizing() {
+        let mut space = FakeConfigSpace::new(0x8086, 0x1234)
+            .with_class(0x03, 0x00, 0x00)
+            .with_bar(0, 0xFE00_0000, 0x10_0000);
+        assert_eq!(space.read_u32(FakeConfigSpace::VENDOR_ID), 0x1234_8086);
+        assert_eq!(space.read_u32(FakeConfigSpace::CLASS) >> 8, 0x03_0000);
+
+        space.write_u32(FakeConfigSpace::BAR0, u32::MAX);
+        assert_eq!(space.read_u32(FakeConfigSpace::BAR0), 0xFFF0_0000);
+        space.write_u32(FakeConfigSpace::BAR0, 0xFE00_0000);
+        assert_eq!(space.read_u32(FakeConfigSpace::BAR0), 0xFE00_0000);
+
+        // BAR1 is not implemented
+        space.write_u32(FakeConfigSpace::BAR0 + 4, u32::MAX);
+        assert_eq!(space.read_u32(FakeConfigSpace::BAR0 + 4), 0);
+    }
+}
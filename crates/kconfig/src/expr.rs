@@ -0,0 +1,285 @@
+//! Expressions used by `depends` and conditional defaults.
+//!
+//! An expression refers to other options by their full name, and supports `!`, `&&`, `||`,
+//! parentheses, and comparisons (`==`, `!=`, `<`, `<=`, `>`, `>=`) against literals, such as
+//! `tick_1000 && !kasan` or `heap_size >= 0x100000`. Literals are `true`, `false`, decimal and
+//! `0x` prefixed integers, and double quoted strings.
+
+use std::cmp::Ordering;
+
+use crate::ConfigValue;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprError {
+    /// The expression is malformed at the given byte offset
+    Parse { offset: usize, message: &'static str },
+    /// The expression refers to an option that doesn't exist
+    UnknownOption(String),
+    /// The operands have types the operator doesn't apply to
+    Type(&'static str),
+}
+
+impl std::fmt::Display for ExprError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExprError::Parse { offset, message } => write!(f, "{} at offset {}", message, offset),
+            ExprError::UnknownOption(name) => write!(f, "unknown option '{}'", name),
+            ExprError::Type(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(ConfigValue),
+    Option(String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Box<Expr>, CompareOp, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Self, ExprError> {
+        let mut parser = Parser { source, offset: 0 };
+        let expr = parser.or()?;
+        parser.skip_whitespace();
+        if parser.offset != source.len() {
+            return Err(parser.error("unexpected trailing input"));
+        }
+        Ok(expr)
+    }
+
+    /// Calls `f` with the name of every option the expression refers to.
+    pub fn visit_options(&self, f: &mut impl FnMut(&str)) {
+        match self {
+            Expr::Literal(_) => {}
+            Expr::Option(name) => f(name),
+            Expr::Not(expr) => expr.visit_options(f),
+            Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) | Expr::Compare(lhs, _, rhs) => {
+                lhs.visit_options(f);
+                rhs.visit_options(f);
+            }
+        }
+    }
+
+    /// Evaluates the expression, looking options up with `lookup`.
+    pub fn eval<E: From<ExprError>>(
+        &self,
+        lookup: &mut impl FnMut(&str) -> Result<ConfigValue, E>,
+    ) -> Result<ConfigValue, E> {
+        Ok(match self {
+            Expr::Literal(value) => value.clone(),
+            Expr::Option(name) => lookup(name)?,
+            Expr::Not(expr) => ConfigValue::Bool(!expr.eval_bool(lookup)?),
+            // Short circuiting, so the right hand side may refer to options the left guards
+            Expr::And(lhs, rhs) => ConfigValue::Bool(lhs.eval_bool(lookup)? && rhs.eval_bool(lookup)?),
+            Expr::Or(lhs, rhs) => ConfigValue::Bool(lhs.eval_bool(lookup)? || rhs.eval_bool(lookup)?),
+            Expr::Compare(lhs, op, rhs) => {
+                let ordering = compare(&lhs.eval(lookup)?, &rhs.eval(lookup)?)?;
+                ConfigValue::Bool(match op {
+                    CompareOp::Eq => ordering == Ordering::Equal,
+                    CompareOp::Ne => ordering != Ordering::Equal,
+                    CompareOp::Lt => ordering == Ordering::Less,
+                    CompareOp::Le => ordering != Ordering::Greater,
+                    CompareOp::Gt => ordering == Ordering::Greater,
+                    CompareOp::Ge => ordering != Ordering::Less,
+                })
+            }
+        })
+    }
+
+    pub fn eval_bool<E: From<ExprError>>(
+        &self,
+        lookup: &mut impl FnMut(&str) -> Result<ConfigValue, E>,
+    ) -> Result<bool, E> {
+        match self.eval(lookup)? {
+            ConfigValue::Bool(value) => Ok(value),
+            _ => Err(ExprError::Type("expected a bool").into()),
+        }
+    }
+}
+
+/// Compares two values, integers and hex values with each other.
+pub(crate) fn compare(lhs: &ConfigValue, rhs: &ConfigValue) -> Result<Ordering, ExprError> {
+    match (lhs, rhs) {
+        (ConfigValue::Bool(lhs), ConfigValue::Bool(rhs)) => Ok(lhs.cmp(rhs)),
+        (ConfigValue::String(lhs), ConfigValue::String(rhs)) => Ok(lhs.cmp(rhs)),
+        _ => match (lhs.as_integer(), rhs.as_integer()) {
+            (Some(lhs), Some(rhs)) => Ok(lhs.cmp(&rhs)),
+            _ => Err(ExprError::Type("compared values of different types")),
+        },
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    offset: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &'static str) -> ExprError {
+        ExprError::Parse {
+            offset: self.offset,
+            message,
+        }
+    }
+
+    fn rest(&self) -> &str {
+        &self.source[self.offset..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.offset += rest.len() - rest.trim_start().len();
+    }
+
+    /// Consumes `token` if the input continues with it.
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.offset += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, ExprError> {
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, ExprError> {
+        let mut expr = self.comparison()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.comparison()?));
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ExprError> {
+        let lhs = self.unary()?;
+        // Longer operators first, so `<=` isn't taken for `<`
+        const OPS: [(&str, CompareOp); 6] = [
+            ("==", CompareOp::Eq),
+            ("!=", CompareOp::Ne),
+            ("<=", CompareOp::Le),
+            (">=", CompareOp::Ge),
+            ("<", CompareOp::Lt),
+            (">", CompareOp::Gt),
+        ];
+        for (token, op) in OPS {
+            if self.eat(token) {
+                return Ok(Expr::Compare(Box::new(lhs), op, Box::new(self.unary()?)));
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err(self.error("expected ')'"));
+            }
+            return Ok(expr);
+        }
+        self.atom()
+    }
+
+    fn atom(&mut self) -> Result<Expr, ExprError> {
+        self.skip_whitespace();
+        let source = self.source;
+        let rest = &source[self.offset..];
+        if let Some(string) = rest.strip_prefix('"') {
+            let len = string.find('"').ok_or_else(|| self.error("unterminated string"))?;
+            self.offset += len + 2;
+            return Ok(Expr::Literal(ConfigValue::String(string[..len].to_string())));
+        }
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(rest.len());
+        let token = &rest[..len];
+        let expr = match token {
+            "" => return Err(self.error("expected an option or a literal")),
+            "true" => Expr::Literal(ConfigValue::Bool(true)),
+            "false" => Expr::Literal(ConfigValue::Bool(false)),
+            _ if token.starts_with(|c: char| c.is_ascii_digit()) => {
+                let value = match token.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16).map(ConfigValue::Hex),
+                    None => token.parse().map(ConfigValue::Int),
+                };
+                Expr::Literal(value.map_err(|_| self.error("invalid integer"))?)
+            }
+            _ => Expr::Option(token.to_string()),
+        };
+        self.offset += len;
+        Ok(expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn eval(source: &str, options: &HashMap<&str, ConfigValue>) -> Result<bool, ExprError> {
+        Expr::parse(source)?.eval_bool(&mut |name| {
+            options
+                .get(name)
+                .cloned()
+                .ok_or_else(|| ExprError::UnknownOption(name.to_string()))
+        })
+    }
+
+    #[test]
+    fn test_expr() {
+        let options = HashMap::from([
+            ("a", ConfigValue::Bool(true)),
+            ("b", ConfigValue::Bool(false)),
+            ("mem.size", ConfigValue::Hex(0x2000)),
+            ("hz", ConfigValue::Int(250)),
+            ("name", ConfigValue::String("hadron".to_string())),
+        ]);
+        assert_eq!(eval("a", &options), Ok(true));
+        assert_eq!(eval("!a || b", &options), Ok(false));
+        assert_eq!(eval("a && !(b || false)", &options), Ok(true));
+        assert_eq!(eval("mem.size >= 0x1000 && hz < 1000", &options), Ok(true));
+        assert_eq!(eval("hz == 250 && mem.size != 8192", &options), Ok(false));
+        assert_eq!(eval("name == \"hadron\"", &options), Ok(true));
+        // The right hand side isn't evaluated if the left decides
+        assert_eq!(eval("b && missing", &options), Ok(false));
+        assert_eq!(
+            eval("a && missing", &options),
+            Err(ExprError::UnknownOption("missing".to_string()))
+        );
+        assert_eq!(eval("hz", &options), Err(ExprError::Type("expected a bool")));
+        assert!(matches!(eval("name == 1", &options), Err(ExprError::Type(_))));
+        assert!(matches!(
+            eval("a &&", &options),
+            Err(ExprError::Parse { offset: 4, .. })
+        ));
+        assert!(matches!(eval("(a", &options), Err(ExprError::Parse { .. })));
+        assert!(matches!(eval("a b", &options), Err(ExprError::Parse { offset: 2, .. })));
+    }
+}
@@ -3,6 +3,88 @@ use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
 use std::{collections::HashMap, path::Path};
 
+mod expr;
+
+pub use expr::{CompareOp, Expr, ExprError};
+
+/// How deep dependencies may refer to options with dependencies of their own, to catch cycles.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// A dependency or default of an option is not a valid expression
+    Expr {
+        option: String,
+        error: ExprError,
+    },
+    /// The dependencies of an option refer back to the option
+    Cycle(String),
+    UnknownOption(String),
+    /// A value doesn't have the type of its option
+    Type {
+        option: String,
+        expected: ConfigType,
+    },
+    /// A value is outside of the range of its option
+    Range {
+        option: String,
+        value: ConfigValue,
+        min: ConfigValue,
+        max: ConfigValue,
+    },
+    /// A choice doesn't have exactly one option selected
+    Choice {
+        choice: String,
+        selected: usize,
+    },
+}
+
+impl ConfigError {
+    /// Attributes an expression error to the option whose expression it is in.
+    fn in_option(self, name: &str) -> Self {
+        match self {
+            ConfigError::Expr { option, error } if option.is_empty() => ConfigError::Expr {
+                option: name.to_string(),
+                error,
+            },
+            err => err,
+        }
+    }
+}
+
+impl From<ExprError> for ConfigError {
+    fn from(error: ExprError) -> Self {
+        ConfigError::Expr {
+            option: String::new(),
+            error,
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Expr { option, error } => write!(f, "{}: {}", option, error),
+            ConfigError::Cycle(option) => write!(f, "{}: the dependencies refer back to it", option),
+            ConfigError::UnknownOption(option) => write!(f, "unknown option '{}'", option),
+            ConfigError::Type { option, expected } => write!(f, "{}: expected a {} value", option, expected),
+            ConfigError::Range {
+                option,
+                value,
+                min,
+                max,
+            } => {
+                write!(f, "{}: {} is outside of {} to {}", option, value, min, max)
+            }
+            ConfigError::Choice { choice, selected } => {
+                write!(f, "{}: {} options are selected instead of one", choice, selected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
 /// A node in the configuration tree.
 ///
 /// This is used to represent the configuration tree, according to how it looks in the file system.
@@ -46,37 +128,131 @@ impl ConfigNode {
         })
     }
 
+    /// Returns the directories of the node and its children, relative to the node.
+    pub fn dirs(&self) -> Vec<std::path::PathBuf> {
+        let mut dirs = vec![std::path::PathBuf::new()];
+        for child in &self.children {
+            dirs.extend(child.dirs().into_iter().map(|dir| Path::new(&child.path).join(dir)));
+        }
+        dirs
+    }
+
+    /// Flattens the tree into a config with every option set to its default.
+    ///
+    /// Panics if an option or choice is invalid, like the parsing of the files.
     pub fn flatten(&self) -> Config {
         let mut config = Config::default();
-        self.flatten_recursive("", &mut config);
+        let mut defaults = Vec::new();
+        self.flatten_recursive("", &mut config, &mut defaults);
+        config.resolve_defaults(&defaults);
         config
     }
 
-    fn flatten_recursive(&self, prefix: &str, config: &mut Config) {
-        // So if the path is empty, we just use the prefix.
-        let prefix = if prefix.is_empty() {
+    fn flatten_recursive(&self, prefix: &str, config: &mut Config, defaults: &mut Vec<ConditionalDefault>) {
+        // The root node has no path, and its options no prefix.
+        let prefix = if self.path.is_empty() {
             prefix.to_string()
         } else {
-            format!("{}{}.", prefix, self.path)
+            format!("{}{}.", prefix, self.config.prefix.as_deref().unwrap_or(&self.path))
         };
         for child in &self.children {
-            child.flatten_recursive(&prefix, config);
+            child.flatten_recursive(&prefix, config, defaults);
         }
 
         for (name, option) in &self.config.options {
             let name = format!("{}{}", prefix, name);
+            let value_of = |value: &toml::Value| {
+                ConfigValue::from_value(option.type_, value.clone())
+                    .unwrap_or_else(|| panic!("{}: expected a {} value", name, option.type_))
+            };
+            let value = match &option.default {
+                toml::Value::Array(cases) => {
+                    let cases: Vec<_> = cases
+                        .iter()
+                        .map(|case| {
+                            let condition = case.get("if").map(|condition| {
+                                let condition = condition.as_str().expect("Default condition must be a string");
+                                Expr::parse(condition).unwrap_or_else(|err| panic!("{}: {}", name, err))
+                            });
+                            let value = case.get("value").expect("Default case must have a value");
+                            (condition, value_of(value))
+                        })
+                        .collect();
+                    // Until the conditions are evaluated, the value of the unconditional case
+                    let value = cases
+                        .iter()
+                        .find(|(condition, _)| condition.is_none())
+                        .map_or_else(|| ConfigValue::zero(option.type_), |(_, value)| value.clone());
+                    defaults.push(ConditionalDefault {
+                        option: name.clone(),
+                        cases,
+                    });
+                    value
+                }
+                default => value_of(default),
+            };
+            let range = option.range.as_ref().map(|[min, max]| {
+                assert!(
+                    matches!(option.type_, ConfigType::Int | ConfigType::Hex),
+                    "{}: only int and hex options have a range",
+                    name
+                );
+                (value_of(min), value_of(max))
+            });
             let option = ConfigOption {
                 name: name.clone(),
                 description: option.description.clone(),
                 depends: option.depends.clone(),
-                type_: option.type_.clone(),
-                value: ConfigValue::from_value(option.type_.clone(), option.default.clone()),
+                type_: option.type_,
+                value,
+                range,
             };
             config.options.insert(name, option);
         }
+
+        for (name, choice) in &self.config.choices {
+            let name = format!("{}{}", prefix, name);
+            let options: Vec<String> = choice
+                .options
+                .iter()
+                .map(|option| format!("{}{}", prefix, option))
+                .collect();
+            let default = format!("{}{}", prefix, choice.default);
+            assert!(
+                options.contains(&default),
+                "{}: the default is not one of the options",
+                name
+            );
+            for option in &options {
+                let option = config
+                    .options
+                    .get_mut(option)
+                    .unwrap_or_else(|| panic!("{}: unknown option '{}'", name, option));
+                assert!(
+                    option.type_ == ConfigType::Bool,
+                    "{}: {} is not a bool",
+                    name,
+                    option.name
+                );
+                option.value = ConfigValue::Bool(option.name == default);
+            }
+            let choice = ConfigChoice {
+                name: name.clone(),
+                description: choice.description.clone(),
+                depends: choice.depends.clone(),
+                options,
+            };
+            config.choices.insert(name, choice);
+        }
     }
 }
 
+/// A default that depends on other options, the value of the first case whose condition holds.
+struct ConditionalDefault {
+    option: String,
+    cases: Vec<(Option<Expr>, ConfigValue)>,
+}
+
 /// The kernel configuration.
 ///
 /// This is a flattened representation of the configuration tree.
@@ -85,6 +261,9 @@ pub struct Config {
     /// The options of the config, with their full name (with the prefix).
     #[serde(rename = "option")]
     pub options: HashMap<String, ConfigOption>,
+    /// The groups of bool options of which exactly one is selected, with their full name.
+    #[serde(default, rename = "choice")]
+    pub choices: HashMap<String, ConfigChoice>,
 }
 
 impl Config {
@@ -106,7 +285,21 @@ impl Config {
         let mut file = std::fs::File::open(path)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
-        Ok(toml::from_str(&contents)?)
+        let mut config: Self = toml::from_str(&contents)?;
+        // Values are read without their type, hex values look like strings
+        for option in config.options.values_mut() {
+            let coerce = |value: ConfigValue| {
+                value.coerce(option.type_).ok_or_else(|| ConfigError::Type {
+                    option: option.name.clone(),
+                    expected: option.type_,
+                })
+            };
+            option.value = coerce(option.value.clone())?;
+            if let Some((min, max)) = option.range.clone() {
+                option.range = Some((coerce(min)?, coerce(max)?));
+            }
+        }
+        Ok(config)
     }
 
     /// Generic extractor: panics if the conversion fails.
@@ -119,51 +312,319 @@ impl Config {
         v.clone().try_into().unwrap()
     }
 
+    /// Returns the value of an option, or `None` if there is no such option or its dependencies aren't met.
     pub fn get<T>(&self, key: &str) -> Option<T>
     where
         T: TryFrom<ConfigValue> + DeserializeOwned,
         <T as TryFrom<ConfigValue>>::Error: std::fmt::Debug,
     {
-        self.options.get(key).map(|v| Self::expect_type(&v.value))
+        let option = self.options.get(key)?;
+        self.is_enabled(key).then(|| Self::expect_type(&option.value))
     }
 
     pub fn get_mut(&mut self, key: &str) -> Option<&mut ConfigOption> {
         self.options.get_mut(key)
     }
+
+    /// Returns whether the dependencies of an option or choice are met.
+    ///
+    /// The options of a choice also depend on the choice. Options with invalid dependencies are disabled,
+    /// see [`Config::validate`].
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.check_enabled(name, 0).unwrap_or(false)
+    }
+
+    fn check_enabled(&self, name: &str, depth: usize) -> Result<bool, ConfigError> {
+        if depth > MAX_DEPTH {
+            return Err(ConfigError::Cycle(name.to_string()));
+        }
+        let depends = match (self.options.get(name), self.choices.get(name)) {
+            (Some(option), _) => &option.depends,
+            (None, Some(choice)) => &choice.depends,
+            (None, None) => return Err(ConfigError::UnknownOption(name.to_string())),
+        };
+        for depend in depends {
+            let expr = Expr::parse(depend).map_err(|err| ConfigError::from(err).in_option(name))?;
+            let met = expr
+                .eval_bool(&mut |option| self.effective_value(option, depth + 1))
+                .map_err(|err| err.in_option(name))?;
+            if !met {
+                return Ok(false);
+            }
+        }
+        match self
+            .choices
+            .values()
+            .find(|choice| choice.options.iter().any(|option| option == name))
+        {
+            Some(choice) => self.check_enabled(&choice.name, depth + 1),
+            None => Ok(true),
+        }
+    }
+
+    /// Returns the value of an option as expressions see it, bool options are off if they are disabled.
+    fn effective_value(&self, name: &str, depth: usize) -> Result<ConfigValue, ConfigError> {
+        let option = self
+            .options
+            .get(name)
+            .ok_or_else(|| ExprError::UnknownOption(name.to_string()))?;
+        if option.type_ == ConfigType::Bool && !self.check_enabled(name, depth)? {
+            return Ok(ConfigValue::Bool(false));
+        }
+        Ok(option.value.clone())
+    }
+
+    /// Sets an option, checking the value against its type and range.
+    pub fn set(&mut self, name: &str, value: ConfigValue) -> Result<(), ConfigError> {
+        let option = self
+            .options
+            .get(name)
+            .ok_or_else(|| ConfigError::UnknownOption(name.to_string()))?;
+        let value = option.check(value)?;
+        self.options.get_mut(name).unwrap().value = value;
+        Ok(())
+    }
+
+    /// Selects an option of a choice, deselecting the others.
+    pub fn select(&mut self, choice: &str, selected: &str) -> Result<(), ConfigError> {
+        let choice = self
+            .choices
+            .get(choice)
+            .ok_or_else(|| ConfigError::UnknownOption(choice.to_string()))?;
+        if !choice.options.iter().any(|option| option == selected) {
+            return Err(ConfigError::UnknownOption(selected.to_string()));
+        }
+        for name in &choice.options {
+            if let Some(option) = self.options.get_mut(name) {
+                option.value = ConfigValue::Bool(name == selected);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the selected option of a choice, if exactly one is selected.
+    pub fn selected(&self, choice: &str) -> Option<&str> {
+        let mut selected = self.choices.get(choice)?.options.iter().filter(|name| {
+            self.options
+                .get(name.as_str())
+                .is_some_and(|option| option.value == ConfigValue::Bool(true))
+        });
+        match (selected.next(), selected.next()) {
+            (Some(name), None) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Checks that the dependencies are valid expressions without cycles, the values have the type and are
+    /// in the range of their option, and enabled choices have exactly one option selected.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut names: Vec<&String> = self.options.keys().chain(self.choices.keys()).collect();
+        names.sort();
+        for name in names {
+            // Reported for the option checked, rather than wherever the cycle was noticed
+            self.check_enabled(name, 0).map_err(|err| match err {
+                ConfigError::Cycle(_) => ConfigError::Cycle(name.clone()),
+                err => err,
+            })?;
+            if let Some(option) = self.options.get(name) {
+                option.check(option.value.clone())?;
+            }
+            if let Some(choice) = self.choices.get(name)
+                && self.is_enabled(name)
+            {
+                let selected = choice
+                    .options
+                    .iter()
+                    .filter(|option| self.options[option.as_str()].value == ConfigValue::Bool(true))
+                    .count();
+                if selected != 1 {
+                    return Err(ConfigError::Choice {
+                        choice: name.clone(),
+                        selected,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluates the conditional defaults until they no longer change, so conditions may refer to options with
+    /// conditional defaults themselves.
+    fn resolve_defaults(&mut self, defaults: &[ConditionalDefault]) {
+        for _ in 0..=defaults.len() {
+            let mut changed = false;
+            for default in defaults {
+                let mut value = None;
+                for (condition, case) in &default.cases {
+                    let holds = match condition {
+                        Some(condition) => condition
+                            .eval_bool(&mut |name| self.effective_value(name, 0))
+                            .unwrap_or_else(|err| panic!("{}", err.in_option(&default.option))),
+                        None => true,
+                    };
+                    if holds {
+                        value = Some(case.clone());
+                        break;
+                    }
+                }
+                let option = self.options.get_mut(&default.option).unwrap();
+                let value = value.unwrap_or_else(|| ConfigValue::zero(option.type_));
+                if option.value != value {
+                    option.value = value;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return;
+            }
+        }
+        panic!("The conditional defaults refer to each other in a cycle");
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ConfigOption {
     pub name: String,
     pub description: String,
+    /// Expressions that must all hold for the option to be enabled, see [`Expr`].
     pub depends: Vec<String>,
     pub type_: ConfigType,
     pub value: ConfigValue,
+    /// The smallest and largest value of an int or hex option.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<(ConfigValue, ConfigValue)>,
 }
 
-impl Into<bool> for ConfigValue {
-    fn into(self) -> bool {
-        match self {
-            ConfigValue::Bool(b) => b,
+impl ConfigOption {
+    /// Checks a value against the type and range of the option, converting integers to hex values.
+    pub fn check(&self, value: ConfigValue) -> Result<ConfigValue, ConfigError> {
+        let value = value.coerce(self.type_).ok_or_else(|| ConfigError::Type {
+            option: self.name.clone(),
+            expected: self.type_,
+        })?;
+        if let Some((min, max)) = &self.range {
+            let below = expr::compare(&value, min).is_ok_and(|ordering| ordering.is_lt());
+            let above = expr::compare(&value, max).is_ok_and(|ordering| ordering.is_gt());
+            if below || above {
+                return Err(ConfigError::Range {
+                    option: self.name.clone(),
+                    value,
+                    min: min.clone(),
+                    max: max.clone(),
+                });
+            }
+        }
+        Ok(value)
+    }
+}
+
+/// A group of bool options of which exactly one is selected.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ConfigChoice {
+    pub name: String,
+    pub description: String,
+    pub depends: Vec<String>,
+    /// The full names of the options.
+    pub options: Vec<String>,
+}
+
+impl TryFrom<ConfigValue> for bool {
+    type Error = ConfigValue;
+
+    fn try_from(value: ConfigValue) -> Result<Self, Self::Error> {
+        match value {
+            ConfigValue::Bool(b) => Ok(b),
+            value => Err(value),
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+impl TryFrom<ConfigValue> for i64 {
+    type Error = ConfigValue;
+
+    fn try_from(value: ConfigValue) -> Result<Self, Self::Error> {
+        match value {
+            ConfigValue::Int(i) => Ok(i),
+            ConfigValue::Hex(h) => i64::try_from(h).map_err(|_| ConfigValue::Hex(h)),
+            value => Err(value),
+        }
+    }
+}
+
+impl TryFrom<ConfigValue> for u64 {
+    type Error = ConfigValue;
+
+    fn try_from(value: ConfigValue) -> Result<Self, Self::Error> {
+        match value {
+            ConfigValue::Hex(h) => Ok(h),
+            ConfigValue::Int(i) => u64::try_from(i).map_err(|_| ConfigValue::Int(i)),
+            value => Err(value),
+        }
+    }
+}
+
+impl TryFrom<ConfigValue> for String {
+    type Error = ConfigValue;
+
+    fn try_from(value: ConfigValue) -> Result<Self, Self::Error> {
+        match value {
+            ConfigValue::String(s) => Ok(s),
+            value => Err(value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum ConfigType {
     #[serde(rename = "bool")]
     Bool,
+    #[serde(rename = "int")]
+    Int,
+    /// An unsigned integer, written in hex.
+    #[serde(rename = "hex")]
+    Hex,
+    #[serde(rename = "string")]
+    String,
+}
+
+impl std::fmt::Display for ConfigType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ConfigType::Bool => "bool",
+            ConfigType::Int => "int",
+            ConfigType::Hex => "hex",
+            ConfigType::String => "string",
+        })
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ConfigValue {
     Bool(bool),
+    Int(i64),
+    Hex(u64),
+    String(String),
 }
 
 impl ConfigValue {
     fn as_value(&self) -> toml::Value {
         match self {
             ConfigValue::Bool(b) => toml::Value::Boolean(*b),
+            ConfigValue::Int(i) => toml::Value::Integer(*i),
+            // TOML integers are signed, and hex values are easier to read as hex anyway
+            ConfigValue::Hex(h) => toml::Value::String(format!("{:#x}", h)),
+            ConfigValue::String(s) => toml::Value::String(s.clone()),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigValue::Bool(b) => write!(f, "{}", b),
+            ConfigValue::Int(i) => write!(f, "{}", i),
+            ConfigValue::Hex(h) => write!(f, "{:#x}", h),
+            ConfigValue::String(s) => f.write_str(s),
         }
     }
 }
@@ -194,22 +655,84 @@ impl ConfigValue {
     pub fn from_untyped_value(value: toml::Value) -> Self {
         match value {
             toml::Value::Boolean(b) => ConfigValue::Bool(b),
-            _ => panic!("Invalid value for bool"),
+            toml::Value::Integer(i) => ConfigValue::Int(i),
+            toml::Value::String(s) => ConfigValue::String(s),
+            _ => panic!("Invalid config value {}", value),
+        }
+    }
+
+    /// Converts a value to the given type, returning `None` if it doesn't have the type.
+    pub fn from_value(type_: ConfigType, value: toml::Value) -> Option<Self> {
+        match value {
+            toml::Value::Boolean(_) | toml::Value::Integer(_) | toml::Value::String(_) => {
+                Self::from_untyped_value(value).coerce(type_)
+            }
+            _ => None,
         }
     }
 
-    pub fn from_value(type_: ConfigType, value: toml::Value) -> Self {
+    /// Parses a value of the given type as it is entered, hex values with or without `0x`.
+    pub fn parse(type_: ConfigType, text: &str) -> Option<Self> {
+        let text = text.trim();
         match type_ {
-            ConfigType::Bool => match value {
-                toml::Value::Boolean(b) => ConfigValue::Bool(b),
-                _ => panic!("Invalid value for bool"),
-            },
+            ConfigType::Bool => text.parse().ok().map(ConfigValue::Bool),
+            ConfigType::Int => text.parse().ok().map(ConfigValue::Int),
+            ConfigType::Hex => {
+                let digits = text.strip_prefix("0x").unwrap_or(text);
+                u64::from_str_radix(digits, 16).ok().map(ConfigValue::Hex)
+            }
+            ConfigType::String => Some(ConfigValue::String(text.to_string())),
+        }
+    }
+
+    /// Converts the value to the given type, where integers are hex values too.
+    pub fn coerce(self, type_: ConfigType) -> Option<Self> {
+        match (self, type_) {
+            (value @ ConfigValue::Bool(_), ConfigType::Bool)
+            | (value @ ConfigValue::Int(_), ConfigType::Int)
+            | (value @ ConfigValue::Hex(_), ConfigType::Hex)
+            | (value @ ConfigValue::String(_), ConfigType::String) => Some(value),
+            (ConfigValue::Int(i), ConfigType::Hex) => u64::try_from(i).ok().map(ConfigValue::Hex),
+            (ConfigValue::String(s), ConfigType::Hex) => {
+                let digits = s.strip_prefix("0x")?;
+                u64::from_str_radix(digits, 16).ok().map(ConfigValue::Hex)
+            }
+            _ => None,
+        }
+    }
+
+    /// The value of an option without a default.
+    pub fn zero(type_: ConfigType) -> Self {
+        match type_ {
+            ConfigType::Bool => ConfigValue::Bool(false),
+            ConfigType::Int => ConfigValue::Int(0),
+            ConfigType::Hex => ConfigValue::Hex(0),
+            ConfigType::String => ConfigValue::String(String::new()),
+        }
+    }
+
+    pub fn type_(&self) -> ConfigType {
+        match self {
+            ConfigValue::Bool(_) => ConfigType::Bool,
+            ConfigValue::Int(_) => ConfigType::Int,
+            ConfigValue::Hex(_) => ConfigType::Hex,
+            ConfigValue::String(_) => ConfigType::String,
         }
     }
 
     pub fn as_bool(&self) -> bool {
         match self {
             ConfigValue::Bool(b) => *b,
+            _ => panic!("Not a bool"),
+        }
+    }
+
+    /// Returns the value of an int or hex value.
+    pub fn as_integer(&self) -> Option<i128> {
+        match self {
+            ConfigValue::Int(i) => Some(*i as i128),
+            ConfigValue::Hex(h) => Some(*h as i128),
+            _ => None,
         }
     }
 }
@@ -225,15 +748,32 @@ pub struct RawConfig {
     /// The options of the config, using [option.<name>] as the key.
     #[serde(default, rename = "option")]
     options: HashMap<String, RawConfigOption>,
+    /// The choices of the config, using [choice.<name>] as the key.
+    #[serde(default, rename = "choice")]
+    choices: HashMap<String, RawConfigChoice>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RawConfigOption {
     description: String,
+    #[serde(default, alias = "depends_on")]
     depends: Vec<String>,
     #[serde(rename = "type")]
     type_: ConfigType,
+    /// A value, or an array of `{ value, if }` cases, the first whose `if` expression holds
     default: toml::Value,
+    #[serde(default)]
+    range: Option<[toml::Value; 2]>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RawConfigChoice {
+    description: String,
+    #[serde(default, alias = "depends_on")]
+    depends: Vec<String>,
+    /// The names of the bool options to choose from, in the same file.
+    options: Vec<String>,
+    default: String,
 }
 
 #[cfg(test)]
@@ -304,6 +844,7 @@ default = true
                     depends: vec![],
                     type_: ConfigType::Bool,
                     default: toml::Value::Boolean(true),
+                    range: None,
                 },
             ),
             (
@@ -313,6 +854,7 @@ default = true
                     depends: vec![],
                     type_: ConfigType::Bool,
                     default: toml::Value::Boolean(false),
+                    range: None,
                 },
             ),
         ]);
@@ -322,6 +864,7 @@ default = true
                 include: vec![],
                 prefix: Some("child2".to_string()),
                 options: options.clone(),
+                choices: HashMap::new(),
             },
             children: Vec::new(),
         };
@@ -333,6 +876,7 @@ default = true
                 depends: vec!["child2.test1".to_string()],
                 type_: ConfigType::Bool,
                 default: toml::Value::Boolean(false),
+                range: None,
             },
         )]);
         let node = ConfigNode {
@@ -341,6 +885,7 @@ default = true
                 include: vec![],
                 prefix: Some("child1".to_string()),
                 options,
+                choices: HashMap::new(),
             },
             children: vec![node],
         };
@@ -351,6 +896,7 @@ default = true
                 include: vec![],
                 prefix: None,
                 options: HashMap::new(),
+                choices: HashMap::new(),
             },
             children: vec![node],
         };
@@ -374,6 +920,7 @@ default = true
                         depends: vec![],
                         type_: ConfigType::Bool,
                         value: ConfigValue::Bool(true),
+                        range: None,
                     },
                 ),
                 (
@@ -384,13 +931,115 @@ default = true
                         depends: vec![],
                         type_: ConfigType::Bool,
                         value: ConfigValue::Bool(false),
+                        range: None,
                     },
                 ),
             ]),
+            choices: HashMap::new(),
         };
         let tmpfile = tempfile::NamedTempFile::new().unwrap();
         options.serialize(tmpfile.path()).unwrap();
         let new_config = Config::deserialize(tmpfile.path()).unwrap();
         assert_eq!(new_config.options, options.options);
     }
+
+    #[test]
+    fn test_depends_and_choices() {
+        let root = tempfile::tempdir().unwrap().keep().join("test-config");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(
+            root.join("kconfig.toml"),
+            r#"
+[option.debug]
+description = "Debug"
+type = "bool"
+default = true
+
+[option.trace]
+description = "Tracing"
+depends_on = ["debug"]
+type = "bool"
+default = true
+
+[option.tick_100]
+description = "100 Hz"
+type = "bool"
+default = false
+
+[option.tick_1000]
+description = "1000 Hz"
+type = "bool"
+default = false
+
+[choice.tick]
+description = "Tick frequency"
+depends = ["debug || !debug"]
+options = ["tick_100", "tick_1000"]
+default = "tick_1000"
+
+[option.hz]
+description = "Ticks per second"
+type = "int"
+default = [{ value = 100, if = "tick_100" }, { value = 1000, if = "tick_1000 && trace" }, { value = 250 }]
+range = [10, 2000]
+
+[option.heap]
+description = "Heap size"
+depends = ["hz >= 100"]
+type = "hex"
+default = "0x100000"
+range = ["0x1000", "0x10000000"]
+
+[option.name]
+description = "Host name"
+type = "string"
+default = "hadron"
+"#,
+        )
+        .unwrap();
+
+        let mut config = Config::from_root(&root);
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.selected("tick"), Some("tick_1000"));
+        assert_eq!(config.get::<i64>("hz"), Some(1000));
+        assert_eq!(config.get::<u64>("heap"), Some(0x100000));
+        assert_eq!(config.get::<String>("name"), Some("hadron".to_string()));
+
+        config.set("debug", ConfigValue::Bool(false)).unwrap();
+        assert!(!config.is_enabled("trace"));
+        assert_eq!(config.get::<bool>("trace"), None);
+        config.select("tick", "tick_100").unwrap();
+        assert_eq!(config.get::<bool>("tick_1000"), Some(false));
+
+        assert!(matches!(
+            config.set("hz", ConfigValue::Int(5)),
+            Err(ConfigError::Range { .. })
+        ));
+        assert!(matches!(
+            config.set("name", ConfigValue::Bool(true)),
+            Err(ConfigError::Type { .. })
+        ));
+        config.set("heap", ConfigValue::Int(0x2000)).unwrap();
+        assert_eq!(config.options["heap"].value, ConfigValue::Hex(0x2000));
+
+        // Hex values are written as strings, and read back as hex values
+        let tmpfile = tempfile::NamedTempFile::new().unwrap();
+        config.serialize(tmpfile.path()).unwrap();
+        let read = Config::deserialize(tmpfile.path()).unwrap();
+        assert_eq!(read.options, config.options);
+        assert_eq!(read.choices, config.choices);
+
+        config.options.get_mut("tick_1000").unwrap().value = ConfigValue::Bool(true);
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Choice {
+                choice: "tick".to_string(),
+                selected: 2,
+            })
+        );
+        config.options.get_mut("debug").unwrap().depends = vec!["trace".to_string()];
+        assert_eq!(config.validate(), Err(ConfigError::Cycle("debug".to_string())));
+        config.options.get_mut("debug").unwrap().depends = vec!["missing &&".to_string()];
+        assert!(matches!(config.validate(), Err(ConfigError::Expr { .. })));
+    }
 }
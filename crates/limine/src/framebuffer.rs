@@ -90,6 +90,18 @@ impl<'a> Framebuffer<'a> {
         unsafe { self.inner.r0.blue_mask_shift }
     }
 
+    /// Returns the EDID blob of the display, if the firmware provided one.
+    ///
+    /// # Safety
+    /// The blob lives in bootloader reclaimable memory, it must not have been reclaimed.
+    pub unsafe fn edid(&self) -> Option<&'a [u8]> {
+        let (size, ptr) = unsafe { (self.inner.r0.edid_size, self.inner.r0.edid_ptr) };
+        if size == 0 || ptr == 0 {
+            return None;
+        }
+        Some(unsafe { core::slice::from_raw_parts(ptr as *const u8, size as usize) })
+    }
+
     // TODO: Video Mode Iter
 }
 
@@ -1,8 +1,11 @@
 //! Types for representing modules.
-//! This is not entirely implemented yet.
+//!
+//! Modules are usually listed in the bootloader configuration, but an executable can also ask for
+//! modules itself with internal modules (see [`crate::request::ModuleRequest::with_internal_modules`]).
+//! Either way, they are returned as [`crate::file::File`]s.
 
 use core::{
-    ffi::c_char,
+    ffi::{CStr, c_char},
     ops::{BitOr, BitOrAssign},
 };
 
@@ -12,9 +15,16 @@ use core::{
 pub struct ModuleFlags(u64);
 
 impl ModuleFlags {
-    pub const REQUIRED: u64 = 1 << 0;
-    pub const COMPRESSED: u64 = 1 << 1;
-    pub const EMPTY: u64 = 0;
+    /// Fail to boot if the module can't be found.
+    pub const REQUIRED: Self = Self(1 << 0);
+    /// The module is GZ-compressed and should be decompressed by the bootloader.
+    pub const COMPRESSED: Self = Self(1 << 1);
+    pub const EMPTY: Self = Self(0);
+
+    /// Returns the raw flags.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
 }
 
 impl BitOr for ModuleFlags {
@@ -31,14 +41,43 @@ impl BitOrAssign for ModuleFlags {
     }
 }
 
+/// A module requested by the executable itself.
+///
+/// Requires revision 1 of the [`crate::request::ModuleRequest`].
 #[repr(C)]
 pub struct InternalModule {
-    name: *const c_char,
+    /// The path of the module, relative to the executable unless it is absolute.
+    path: *const c_char,
+    /// The command line passed to the module.
     cmdline: *const c_char,
     flags: u64,
 }
 
+// SAFETY: The strings are `'static` and never written to.
+unsafe impl Sync for InternalModule {}
+
 impl InternalModule {
+    /// Creates a new internal module.
+    pub const fn new(path: &'static CStr, cmdline: &'static CStr, flags: ModuleFlags) -> Self {
+        Self {
+            path: path.as_ptr(),
+            cmdline: cmdline.as_ptr(),
+            flags: flags.0,
+        }
+    }
+
+    /// Returns the path of the module.
+    pub fn path(&self) -> &CStr {
+        // SAFETY: The path was created from a `'static` C string.
+        unsafe { CStr::from_ptr(self.path) }
+    }
+
+    /// Returns the command line passed to the module.
+    pub fn cmdline(&self) -> &CStr {
+        // SAFETY: The command line was created from a `'static` C string.
+        unsafe { CStr::from_ptr(self.cmdline) }
+    }
+
     pub fn flags(&self) -> ModuleFlags {
         ModuleFlags(self.flags)
     }
@@ -7,8 +7,8 @@ use crate::{
     response::{
         BootTimeResponse, BootloaderInfoResponse, EfiMemoryMapResponse, EfiSystemTableResponse, EntryPointResponse,
         ExecutableAddressResponse, ExecutableFileResponse, FirmwareTypeResponse, FramebufferResponse, HhdmResponse,
-        MemoryMapResponse, ModuleResponse, MultiprocessorResponse, PagingModeResponse, Response, RsdpResponse,
-        SmBiosResponse, StackSizeResponse,
+        MemoryMapResponse, ModuleResponse, MultiprocessorResponse, PagingMode, PagingModeResponse, Response,
+        RsdpResponse, SmBiosResponse, StackSizeResponse,
     },
 };
 
@@ -193,18 +193,21 @@ impl FramebufferRequest {
     }
 }
 
-/// Requests the current paging mode.
-/// This is not completely implemented or supported, refer to the specification for more information on how to use this request.
+/// Requests a paging mode.
+///
+/// The bootloader enables the preferred mode if the CPU supports it, and otherwise the best
+/// supported mode between `min_mode` and `max_mode`. If no mode in that range is supported, the
+/// response is not provided and the default mode is used.
 #[repr(C)]
 pub struct PagingModeRequest {
     id: [u64; 4],
     revision: u64,
     response: Response<PagingModeResponse>,
-    paging_mode: u64,
+    paging_mode: PagingMode,
     /// Only in revision 1+.
-    max_mode: u64,
+    max_mode: PagingMode,
     /// Only in revision 1+.
-    min_mode: u64,
+    min_mode: PagingMode,
 }
 
 impl PagingModeRequest {
@@ -212,7 +215,11 @@ impl PagingModeRequest {
     request_boilerplate!(PagingModeResponse);
 
     /// Creates a new request.
-    pub const fn new(paging_mode: u64, max_mode: u64, min_mode: u64) -> Self {
+    pub const fn new(paging_mode: PagingMode, max_mode: PagingMode, min_mode: PagingMode) -> Self {
+        assert!(
+            min_mode.bits() <= paging_mode.bits() && paging_mode.bits() <= max_mode.bits(),
+            "preferred paging mode is outside of the allowed range"
+        );
         Self {
             id: request_magic!(0x95c1a0edab0944cb, 0xa4e5cb3842f7488a),
             revision: Self::LATEST_REVISION,
@@ -300,28 +307,43 @@ pub struct ModuleRequest {
     response: Response<ModuleResponse>,
 
     internal_module_count: u64,
-    internal_modules: NonNull<NonNull<InternalModule>>,
+    internal_modules: NonNull<&'static InternalModule>,
 }
 
 unsafe impl Send for ModuleRequest {}
 unsafe impl Sync for ModuleRequest {}
 
 impl ModuleRequest {
-    // TODO: Technically internal modules are revision 1, but we don't support revision 1.
-    pub const LATEST_REVISION: u64 = 0;
+    /// Revision 1 adds internal modules.
+    pub const LATEST_REVISION: u64 = 1;
     request_boilerplate!(ModuleResponse);
 
     /// Creates a new request.
     pub const fn new() -> Self {
+        Self::with_internal_modules(&[])
+    }
+
+    /// Creates a new request, asking the bootloader to also load the given modules.
+    ///
+    /// Internal modules are returned in the response along with the modules listed in the
+    /// bootloader configuration.
+    pub const fn with_internal_modules(modules: &'static [&'static InternalModule]) -> Self {
         Self {
             id: request_magic!(0x3e7e279702be32af, 0xca1c4f3bd1280cee),
             revision: Self::LATEST_REVISION,
             response: Response::none(),
 
-            internal_module_count: 0,
-            internal_modules: NonNull::dangling(),
+            internal_module_count: modules.len() as u64,
+            // SAFETY: A slice pointer is never null, the bootloader only reads `internal_module_count` entries.
+            internal_modules: unsafe { NonNull::new_unchecked(modules.as_ptr() as *mut &'static InternalModule) },
         }
     }
+
+    /// Returns the internal modules of the request.
+    pub fn internal_modules(&self) -> &[&'static InternalModule] {
+        // SAFETY: The pointer and count were created from a `'static` slice.
+        unsafe { core::slice::from_raw_parts(self.internal_modules.as_ptr(), self.internal_module_count as usize) }
+    }
 }
 
 /// A request to get the RSDP Address.
@@ -390,6 +412,8 @@ impl ExecutableAddressRequest {
     }
 }
 
+/// A request for the SMBIOS entry points.
+/// Only provided if the firmware has SMBIOS tables.
 #[repr(C)]
 pub struct SmBiosRequest {
     id: [u64; 4],
@@ -411,6 +435,8 @@ impl SmBiosRequest {
     }
 }
 
+/// A request for the EFI system table.
+/// Only provided when booted from UEFI.
 #[repr(C)]
 pub struct EfiSystemTableRequest {
     id: [u64; 4],
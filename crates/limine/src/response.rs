@@ -134,11 +134,52 @@ impl FramebufferResponse {
     }
 }
 
+/// A paging mode, whose values depend on the architecture.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PagingMode(u64);
+
+#[cfg(not(feature = "risc-v"))]
+impl PagingMode {
+    /// 4-level paging, with 48-bit virtual addresses.
+    pub const FOUR_LEVEL: Self = Self(0);
+    /// 5-level paging, with 57-bit virtual addresses.
+    pub const FIVE_LEVEL: Self = Self(1);
+    /// The mode used if the request is not given.
+    pub const DEFAULT: Self = Self::FOUR_LEVEL;
+}
+
+#[cfg(feature = "risc-v")]
+impl PagingMode {
+    /// Sv39, with 39-bit virtual addresses.
+    pub const SV39: Self = Self(0);
+    /// Sv48, with 48-bit virtual addresses.
+    pub const SV48: Self = Self(1);
+    /// Sv57, with 57-bit virtual addresses.
+    pub const SV57: Self = Self(2);
+    /// The mode used if the request is not given.
+    pub const DEFAULT: Self = Self::SV48;
+}
+
+impl PagingMode {
+    /// Returns the raw mode.
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+}
+
 /// The response to the [`PagingModeRequest`].
 #[repr(C)]
 pub struct PagingModeResponse {
     pub revision: u64,
-    pub paging_mode: u64,
+    paging_mode: u64,
+}
+
+impl PagingModeResponse {
+    /// Returns the paging mode the bootloader enabled.
+    pub fn paging_mode(&self) -> PagingMode {
+        PagingMode(self.paging_mode)
+    }
 }
 
 /// The response to the [`MemoryMapRequest`].
@@ -205,6 +246,16 @@ impl ModuleResponse {
         self.modules_count as usize
     }
 
+    /// Returns the number of modules.
+    pub fn len(&self) -> usize {
+        self.count()
+    }
+
+    /// Returns whether no modules were loaded.
+    pub fn is_empty(&self) -> bool {
+        self.modules_count == 0
+    }
+
     /// Returns an iterator over the modules.
     pub fn modules(&self) -> FileIter<'_> {
         // SAFETY: The modules pointer is valid because it is a pointer to an array of pointers.
@@ -239,13 +290,29 @@ pub struct ExecutableAddressResponse {
     pub virtual_address: u64,
 }
 
+/// The response to the [`SmBiosRequest`].
+///
+/// With base revision 3 or later, the addresses are physical, and otherwise they are in the HHDM.
 #[repr(C)]
 pub struct SmBiosResponse {
     pub revision: u64,
-    pub entry_32: u64,
-    pub entry_64: u64,
+    entry_32: u64,
+    entry_64: u64,
+}
+
+impl SmBiosResponse {
+    /// Returns the address of the 32-bit (SMBIOS 2.x) entry point, if there is one.
+    pub fn entry_32(&self) -> Option<u64> {
+        (self.entry_32 != 0).then_some(self.entry_32)
+    }
+
+    /// Returns the address of the 64-bit (SMBIOS 3.x) entry point, if there is one.
+    pub fn entry_64(&self) -> Option<u64> {
+        (self.entry_64 != 0).then_some(self.entry_64)
+    }
 }
 
+/// The response to the [`EfiSystemTableRequest`].
 #[repr(C)]
 pub struct EfiSystemTableResponse {
     pub revision: u64,
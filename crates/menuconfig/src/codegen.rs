@@ -0,0 +1,165 @@
+//! Generates the `config.rs` the kernel includes from its build script.
+//!
+//! Every option becomes a constant named after it in upper case, with dots as underscores: bool
+//! options are `bool`s (off if their dependencies aren't met), int options `i64`s, hex options
+//! `usize`s and string options `&str`s. Bool options that are on also set
+//! `cfg(kconfig = "<option>")`, so code can be left out entirely.
+
+use std::{fmt::Write, path::Path};
+
+use kconfig::{Config, ConfigNode, ConfigType, ConfigValue};
+
+/// Returns the name of the constant of an option.
+pub fn const_name(option: &str) -> String {
+    option.replace(['.', '-'], "_").to_uppercase()
+}
+
+/// Reads the config at `config_path`, with the defaults of the `kconfig.toml` files under `root` for the
+/// options it doesn't set, or only the defaults if there is no config yet.
+///
+/// Prints `cargo:rerun-if-changed` for the files it reads, and panics if the config is invalid, so the build
+/// fails with the reason.
+pub fn load(root: &Path, config_path: &Path) -> Config {
+    let node = ConfigNode::from_fs(root).expect("No kconfig.toml in the root");
+    for dir in node.dirs() {
+        println!(
+            "cargo:rerun-if-changed={}",
+            root.join(dir).join("kconfig.toml").display()
+        );
+    }
+    println!("cargo:rerun-if-changed={}", config_path.display());
+    let mut config = node.flatten();
+    if config_path.exists() {
+        let saved = Config::deserialize(config_path)
+            .unwrap_or_else(|err| panic!("Failed to read {}: {}", config_path.display(), err));
+        // Options that were removed or changed type since the config was saved keep their default
+        for (name, option) in saved.options {
+            if config
+                .options
+                .get(&name)
+                .is_some_and(|default| default.type_ == option.type_)
+            {
+                _ = config.set(&name, option.value);
+            }
+        }
+    }
+    if let Err(err) = config.validate() {
+        panic!("Invalid config {}: {}", config_path.display(), err);
+    }
+    config
+}
+
+/// Returns the options in order, so the generated code doesn't change between builds.
+fn sorted(config: &Config) -> Vec<&kconfig::ConfigOption> {
+    let mut options: Vec<_> = config.options.values().collect();
+    options.sort_by(|a, b| a.name.cmp(&b.name));
+    options
+}
+
+/// Returns the bool options that are on.
+pub fn enabled(config: &Config) -> Vec<&str> {
+    sorted(config)
+        .into_iter()
+        .filter(|option| option.type_ == ConfigType::Bool && config.get::<bool>(&option.name) == Some(true))
+        .map(|option| option.name.as_str())
+        .collect()
+}
+
+/// Generates the constants of the options.
+pub fn generate(config: &Config) -> String {
+    let mut out = String::from("// Generated by menuconfig from the kernel config, do not edit\n");
+    for option in sorted(config) {
+        let name = const_name(&option.name);
+        writeln!(out, "\n/// {}", option.description).unwrap();
+        match &option.value {
+            ConfigValue::Bool(_) => {
+                let value = config.get::<bool>(&option.name).unwrap_or(false);
+                writeln!(out, "pub const {}: bool = {};", name, value)
+            }
+            ConfigValue::Int(value) => writeln!(out, "pub const {}: i64 = {};", name, value),
+            ConfigValue::Hex(value) => writeln!(out, "pub const {}: usize = {:#x};", name, value),
+            ConfigValue::String(value) => writeln!(out, "pub const {}: &str = {:?};", name, value),
+        }
+        .unwrap();
+    }
+    writeln!(
+        out,
+        "\n/// The bool options that are on, which also set `cfg(kconfig = \"<option>\")`\npub const ENABLED: &[&str] = &{:?};",
+        enabled(config)
+    )
+    .unwrap();
+    out
+}
+
+/// Writes `config.rs` to `out_dir`, and prints the `cfg`s of the bool options.
+pub fn build_script(config: &Config, out_dir: &Path) {
+    std::fs::write(out_dir.join("config.rs"), generate(config)).expect("Failed to write config.rs");
+    let values: Vec<String> = sorted(config)
+        .into_iter()
+        .filter(|option| option.type_ == ConfigType::Bool)
+        .map(|option| format!("{:?}", option.name))
+        .collect();
+    println!("cargo:rustc-check-cfg=cfg(kconfig, values({}))", values.join(", "));
+    for option in enabled(config) {
+        println!("cargo:rustc-cfg=kconfig={:?}", option);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use kconfig::ConfigOption;
+
+    use super::*;
+
+    fn option(name: &str, depends: &[&str], value: ConfigValue) -> (String, ConfigOption) {
+        let option = ConfigOption {
+            name: name.to_string(),
+            description: format!("The {} option", name),
+            depends: depends.iter().map(|depend| depend.to_string()).collect(),
+            type_: value.type_(),
+            value,
+            range: None,
+        };
+        (name.to_string(), option)
+    }
+
+    #[test]
+    fn test_generate() {
+        let config = Config {
+            options: HashMap::from([
+                option("heap_size", &[], ConfigValue::Hex(0x80000)),
+                option("drivers.nvme", &[], ConfigValue::Bool(true)),
+                option("drivers.virtio-gpu", &["!drivers.nvme"], ConfigValue::Bool(true)),
+                option("hz", &[], ConfigValue::Int(250)),
+                option("name", &[], ConfigValue::String("\"hadron\"".to_string())),
+            ]),
+            choices: HashMap::new(),
+        };
+        assert_eq!(enabled(&config), ["drivers.nvme"]);
+        assert_eq!(
+            generate(&config),
+            r#"// Generated by menuconfig from the kernel config, do not edit
+
+/// The drivers.nvme option
+pub const DRIVERS_NVME: bool = true;
+
+/// The drivers.virtio-gpu option
+pub const DRIVERS_VIRTIO_GPU: bool = false;
+
+/// The heap_size option
+pub const HEAP_SIZE: usize = 0x80000;
+
+/// The hz option
+pub const HZ: i64 = 250;
+
+/// The name option
+pub const NAME: &str = "\"hadron\"";
+
+/// The bool options that are on, which also set `cfg(kconfig = "<option>")`
+pub const ENABLED: &[&str] = &["drivers.nvme"];
+"#
+        );
+    }
+}
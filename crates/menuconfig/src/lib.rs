@@ -1,5 +1,6 @@
 pub use kconfig::{Config, ConfigOption};
 
+pub mod codegen;
 #[cfg(feature = "menuconfig")]
 pub mod term;
 
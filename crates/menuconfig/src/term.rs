@@ -1,14 +1,15 @@
 use std::collections::HashMap;
 
 use crossterm::event::{Event, KeyCode, KeyEvent};
-use kconfig::{Config, ConfigOption};
+use kconfig::{Config, ConfigOption, ConfigType};
 use ratatui::{prelude::*, widgets::*};
 
 #[derive(Debug, Clone)]
 enum ConfigValue {
     Bool(bool),
+    Int(i64),
+    Hex(u64),
     String(String),
-    List(Vec<ConfigValue>),
 }
 
 impl ConfigValue {
@@ -25,31 +26,37 @@ impl ConfigValue {
             _ => panic!("Not a string"),
         }
     }
-
-    fn as_list(&self) -> Vec<ConfigValue> {
-        match self {
-            ConfigValue::List(l) => l.clone(),
-            _ => panic!("Not a list"),
-        }
-    }
 }
 
 trait ConfigItem {
-    fn get_value(&self) -> ConfigValue;
+    /// Returns the value of the option the item edits, `None` for items that only group others
+    fn get_value(&self) -> Option<ConfigValue>;
     fn get_height(&self) -> u16;
     fn render(&self, f: &mut Frame, area: Rect, selected: bool);
     fn on_event(&mut self, event: KeyEvent) -> bool;
     fn on_deselect(&mut self) {}
+    /// Items whose dependencies aren't met are greyed out and ignore input
+    fn set_enabled(&mut self, _enabled: bool) {}
+}
+
+fn item_style(selected: bool, enabled: bool) -> Style {
+    match (selected, enabled) {
+        (true, true) => Style::default().fg(Color::Yellow),
+        (true, false) => Style::default().fg(Color::DarkGray).add_modifier(Modifier::REVERSED),
+        (false, true) => Style::default(),
+        (false, false) => Style::default().fg(Color::DarkGray),
+    }
 }
 
 struct ConfigToggle {
     name: String,
     value: bool,
+    enabled: bool,
 }
 
 impl ConfigItem for ConfigToggle {
-    fn get_value(&self) -> ConfigValue {
-        ConfigValue::Bool(self.value)
+    fn get_value(&self) -> Option<ConfigValue> {
+        Some(ConfigValue::Bool(self.value))
     }
 
     fn get_height(&self) -> u16 {
@@ -64,22 +71,117 @@ impl ConfigItem for ConfigToggle {
             if self.value { "[x]" } else { "[ ]" },
             self.name.as_str()
         ))
-        .style(if selected {
-            Style::default().fg(Color::Yellow)
-        } else {
-            Style::default()
-        });
+        .style(item_style(selected, self.enabled));
         f.render_widget(content, block.inner(area));
     }
 
     fn on_event(&mut self, event: KeyEvent) -> bool {
-        if event.code == KeyCode::Enter {
+        if self.enabled && event.code == KeyCode::Enter {
             self.value = !self.value;
             true
         } else {
             false
         }
     }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+}
+
+/// An int, hex or string option, edited as text and checked against its type and range
+struct ConfigInput {
+    option: ConfigOption,
+    /// The text being entered, while the item is edited
+    text: Option<String>,
+    error: Option<String>,
+    enabled: bool,
+}
+
+impl ConfigInput {
+    fn new(option: ConfigOption) -> Self {
+        Self {
+            option,
+            text: None,
+            error: None,
+            enabled: true,
+        }
+    }
+
+    fn commit(&mut self, text: &str) -> Result<(), String> {
+        let value = kconfig::ConfigValue::parse(self.option.type_, text)
+            .ok_or_else(|| format!("expected a {} value", self.option.type_))?;
+        self.option.value = self.option.check(value).map_err(|err| err.to_string())?;
+        Ok(())
+    }
+}
+
+impl ConfigItem for ConfigInput {
+    fn get_value(&self) -> Option<ConfigValue> {
+        Some(match &self.option.value {
+            kconfig::ConfigValue::Bool(b) => ConfigValue::Bool(*b),
+            kconfig::ConfigValue::Int(i) => ConfigValue::Int(*i),
+            kconfig::ConfigValue::Hex(h) => ConfigValue::Hex(*h),
+            kconfig::ConfigValue::String(s) => ConfigValue::String(s.clone()),
+        })
+    }
+
+    fn get_height(&self) -> u16 {
+        2
+    }
+
+    fn render(&self, f: &mut Frame, area: Rect, selected: bool) {
+        let block = Block::default().borders(Borders::BOTTOM);
+        f.render_widget(&block, area);
+        let mut line = match &self.text {
+            Some(text) => Line::from(format!("({}) {}_", self.option.name, text)),
+            None => Line::from(format!("({}) {}", self.option.name, self.option.value)),
+        }
+        .style(item_style(selected, self.enabled));
+        if let Some(error) = &self.error {
+            line.push_span(Span::styled(format!("  {}", error), Style::default().fg(Color::Red)));
+        }
+        f.render_widget(Paragraph::new(line), block.inner(area));
+    }
+
+    fn on_event(&mut self, event: KeyEvent) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let Some(text) = &mut self.text else {
+            if event.code == KeyCode::Enter {
+                self.text = Some(self.option.value.to_string());
+                return true;
+            }
+            return false;
+        };
+        // Takes all input while editing, so it has to be committed or cancelled first
+        match event.code {
+            KeyCode::Char(c) => text.push(c),
+            KeyCode::Backspace => {
+                text.pop();
+            }
+            KeyCode::Esc => self.on_deselect(),
+            KeyCode::Enter => {
+                let text = text.clone();
+                match self.commit(&text) {
+                    Ok(()) => self.on_deselect(),
+                    Err(err) => self.error = Some(err),
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn on_deselect(&mut self) {
+        self.text = None;
+        self.error = None;
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
 }
 
 #[derive(Debug)]
@@ -87,6 +189,7 @@ struct ConfigChoice<T: Clone + std::fmt::Display> {
     name: String,
     selected: usize,
     choices: Vec<T>,
+    enabled: bool,
 }
 
 impl<T> ConfigChoice<T>
@@ -98,6 +201,7 @@ where
             name,
             selected,
             choices,
+            enabled: true,
         }
     }
 }
@@ -106,8 +210,8 @@ impl<T> ConfigItem for ConfigChoice<T>
 where
     T: Clone + std::fmt::Display,
 {
-    fn get_value(&self) -> ConfigValue {
-        ConfigValue::String(self.choices[self.selected].to_string())
+    fn get_value(&self) -> Option<ConfigValue> {
+        Some(ConfigValue::String(self.choices[self.selected].to_string()))
     }
 
     fn get_height(&self) -> u16 {
@@ -122,16 +226,14 @@ where
             self.choices[self.selected].to_string(),
             self.name.as_str()
         ))
-        .style(if selected {
-            Style::default().fg(Color::Yellow)
-        } else {
-            Style::default()
-        });
+        .style(item_style(selected, self.enabled));
         f.render_widget(content, block.inner(area));
     }
 
     fn on_event(&mut self, event: KeyEvent) -> bool {
-        if event.code == KeyCode::Right {
+        if !self.enabled {
+            false
+        } else if event.code == KeyCode::Right {
             self.selected = (self.selected + 1) % self.choices.len();
             true
         } else if event.code == KeyCode::Left {
@@ -141,6 +243,10 @@ where
             false
         }
     }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
 }
 
 struct ConfigSection {
@@ -156,8 +262,8 @@ impl ConfigSection {
 }
 
 impl ConfigItem for ConfigSection {
-    fn get_value(&self) -> ConfigValue {
-        ConfigValue::List(self.items.iter().map(|item| item.get_value()).collect())
+    fn get_value(&self) -> Option<ConfigValue> {
+        None
     }
 
     fn get_height(&self) -> u16 {
@@ -250,6 +356,26 @@ impl ConfigMenu {
         self.items.get(*self.item_ids.get(name)?)
     }
 
+    /// Writes the values of the items to the config, and greys out the items that it disables
+    fn sync(&mut self, config: &mut Config) {
+        for (name, idx) in &self.item_ids {
+            let Some(value) = self.items[*idx].get_value() else {
+                continue;
+            };
+            if config.choices.contains_key(name) {
+                if let ConfigValue::String(selected) = value {
+                    _ = config.select(name, &selected);
+                }
+            } else {
+                // The items only hold values that are valid for the option
+                _ = config.set(name, value.into());
+            }
+        }
+        for (name, idx) in &self.item_ids {
+            self.items[*idx].set_enabled(config.is_enabled(name));
+        }
+    }
+
     fn on_event(&mut self, event: KeyEvent) -> bool {
         if self
             .items
@@ -302,11 +428,13 @@ impl ConfigMenu {
     }
 }
 
-impl Into<kconfig::ConfigValue> for ConfigValue {
-    fn into(self) -> kconfig::ConfigValue {
-        match self {
+impl From<ConfigValue> for kconfig::ConfigValue {
+    fn from(value: ConfigValue) -> Self {
+        match value {
             ConfigValue::Bool(b) => kconfig::ConfigValue::Bool(b),
-            _ => unimplemented!(),
+            ConfigValue::Int(i) => kconfig::ConfigValue::Int(i),
+            ConfigValue::Hex(h) => kconfig::ConfigValue::Hex(h),
+            ConfigValue::String(s) => kconfig::ConfigValue::String(s),
         }
     }
 }
@@ -316,15 +444,36 @@ pub fn run(mut config: Config) -> Result<Config, Box<dyn std::error::Error>> {
 
     let mut menu = ConfigMenu::default();
 
-    for (name, item) in &config.options {
-        menu.add_item(
-            name.clone(),
-            Box::new(ConfigToggle {
+    let mut names: Vec<&String> = config.options.keys().chain(config.choices.keys()).collect();
+    names.sort();
+    for name in names {
+        if let Some(choice) = config.choices.get(name) {
+            let selected = config
+                .selected(name)
+                .and_then(|selected| choice.options.iter().position(|option| option == selected))
+                .unwrap_or(0);
+            menu.add_item(
+                name.clone(),
+                Box::new(ConfigChoice::new(name.clone(), choice.options.clone(), selected)),
+            );
+            continue;
+        }
+        // The options of a choice are set through the choice
+        if config.choices.values().any(|choice| choice.options.contains(name)) {
+            continue;
+        }
+        let option = &config.options[name];
+        let item: Box<dyn ConfigItem> = match option.type_ {
+            ConfigType::Bool => Box::new(ConfigToggle {
                 name: name.clone(),
-                value: item.value.as_bool(),
+                value: option.value.as_bool(),
+                enabled: true,
             }),
-        );
+            ConfigType::Int | ConfigType::Hex | ConfigType::String => Box::new(ConfigInput::new(option.clone())),
+        };
+        menu.add_item(name.clone(), item);
     }
+    menu.sync(&mut config);
 
     loop {
         terminal.draw(|f| {
@@ -366,14 +515,10 @@ pub fn run(mut config: Config) -> Result<Config, Box<dyn std::error::Error>> {
                     break;
                 }
             }
+            menu.sync(&mut config);
         }
     }
     ratatui::restore();
 
-    for (name, option) in &mut config.options {
-        let idx = *menu.item_ids.get(name.as_str()).unwrap();
-        option.value = menu.items[idx].get_value().into()
-    }
-
     Ok(config)
 }
@@ -1,2 +0,0 @@
-mod bochs_vga;
-mod simplefb;
@@ -1,4 +1,8 @@
+use std::{path::Path, process::Command};
+
 fn main() {
+    build_info();
+    config();
     if cfg!(feature = "test") {
         return;
     }
@@ -7,3 +11,38 @@ fn main() {
     println!("cargo:rerun-if-changed={}", linker_file);
     println!("cargo:rustc-link-arg=-T{}", linker_file);
 }
+
+/// Runs git in the repository, returning its trimmed output if it succeeded
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
+
+/// Generates `config.rs` from the config the buildscript reads too, see `config`
+fn config() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+    let config = menuconfig::codegen::load(root, &root.join("target/generated/kconfgen.toml"));
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    menuconfig::codegen::build_script(&config, Path::new(&out_dir));
+}
+
+/// Passes the build info to `util::build_info`, without anything that changes between two builds
+///
+/// The build time is `SOURCE_DATE_EPOCH` if set, the time of the last commit otherwise, so two
+/// builds of the same commit are identical.
+fn build_info() {
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+    let epoch = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .or_else(|| git(&["log", "-1", "--format=%ct"]))
+        .filter(|epoch| epoch.parse::<u64>().is_ok())
+        .unwrap_or_else(|| "0".to_string());
+    let commit = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=HADRON_BUILD_EPOCH={}", epoch);
+    println!("cargo:rustc-env=HADRON_BUILD_COMMIT={}", commit);
+}
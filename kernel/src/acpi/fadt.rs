@@ -0,0 +1,89 @@
+//! The Fixed ACPI Description Table
+//!
+//! Only the parts the kernel uses are parsed, so far the ACPI PM timer. The table grew with every
+//! revision, so fields are read from the raw bytes after checking the length.
+
+use crate::acpi::GenericAddress;
+
+pub const SIGNATURE: &[u8; 4] = b"FACP";
+
+const PM_TMR_BLK: usize = 76;
+const PM_TMR_LEN: usize = 91;
+const FLAGS: usize = 112;
+const X_PM_TMR_BLK: usize = 208;
+
+/// The PM timer counts with 32 bits instead of 24
+const FLAG_TMR_VAL_EXT: u32 = 1 << 8;
+
+/// The ACPI power management timer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmTimerInfo {
+    pub port: u16,
+    /// Whether the counter has 32 bits, rather than 24
+    pub extended: bool,
+}
+
+fn read_u32(table: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(table.get(offset..offset + 4)?.try_into().unwrap()))
+}
+
+/// Finds the PM timer in the bytes of a FADT, preferring the 64-bit address of ACPI 2.0
+///
+/// Only timers in I/O space are returned, which is where every PC has it.
+pub fn pm_timer(table: &[u8]) -> Option<PmTimerInfo> {
+    let extended = read_u32(table, FLAGS)? & FLAG_TMR_VAL_EXT != 0;
+    if let Some(bytes) = table.get(X_PM_TMR_BLK..X_PM_TMR_BLK + size_of::<GenericAddress>()) {
+        // SAFETY: The bytes are as long as the packed structure
+        let addr = unsafe { bytes.as_ptr().cast::<GenericAddress>().read_unaligned() };
+        let address = addr.address;
+        if addr.address_space == GenericAddress::SPACE_SYSTEM_IO && address != 0 && address <= u16::MAX as u64 {
+            return Some(PmTimerInfo {
+                port: address as u16,
+                extended,
+            });
+        }
+    }
+    let port = read_u32(table, PM_TMR_BLK)?;
+    if port == 0 || port > u16::MAX as u32 || table.get(PM_TMR_LEN).is_none_or(|len| *len < 4) {
+        return None;
+    }
+    Some(PmTimerInfo {
+        port: port as u16,
+        extended,
+    })
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fadt_pm_timer() {
+        let mut table = [0u8; 244];
+        table[PM_TMR_BLK..PM_TMR_BLK + 4].copy_from_slice(&0x608u32.to_le_bytes());
+        table[PM_TMR_LEN] = 4;
+        assert_eq!(
+            pm_timer(&table[..116]),
+            Some(PmTimerInfo {
+                port: 0x608,
+                extended: false
+            })
+        );
+
+        // The extended address wins over the legacy one
+        table[FLAGS..FLAGS + 4].copy_from_slice(&FLAG_TMR_VAL_EXT.to_le_bytes());
+        table[X_PM_TMR_BLK] = GenericAddress::SPACE_SYSTEM_IO;
+        table[X_PM_TMR_BLK + 4..X_PM_TMR_BLK + 12].copy_from_slice(&0xB008u64.to_le_bytes());
+        assert_eq!(
+            pm_timer(&table),
+            Some(PmTimerInfo {
+                port: 0xB008,
+                extended: true
+            })
+        );
+
+        assert_eq!(pm_timer(&table[..100]), None);
+        table[PM_TMR_LEN] = 0;
+        assert_eq!(pm_timer(&table[..116]), None);
+    }
+}
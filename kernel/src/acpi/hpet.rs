@@ -0,0 +1,30 @@
+//! The High Precision Event Timer description table
+
+use crate::acpi::{AcpiTable, GenericAddress, SdtHeader};
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct HpetTable {
+    pub header: SdtHeader,
+    /// Hardware revision, comparator count, counter size and vendor of the first timer block
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    /// The minimum tick in periodic mode without losing interrupts
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+unsafe impl AcpiTable for HpetTable {
+    const SIGNATURE: &[u8; 4] = b"HPET";
+}
+
+impl HpetTable {
+    pub fn vendor_id(&self) -> u16 {
+        (self.event_timer_block_id >> 16) as u16
+    }
+
+    pub fn comparator_count(&self) -> u8 {
+        ((self.event_timer_block_id >> 8) & 0x1F) as u8 + 1
+    }
+}
@@ -0,0 +1,291 @@
+//! The Multiple APIC Description Table
+//!
+//! The MADT is parsed once into a [`CpuTopology`], which SMP bring-up and interrupt routing use
+//! instead of walking the table themselves.
+
+use alloc::vec::Vec;
+
+use crate::{
+    acpi::{AcpiTable, SdtHeader},
+    arch::PhysAddr,
+};
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub header: SdtHeader,
+    pub local_apic_address: u32,
+    pub flags: u32,
+}
+
+unsafe impl AcpiTable for Madt {
+    const SIGNATURE: &[u8; 4] = b"APIC";
+}
+
+impl Madt {
+    /// The system also has dual 8259 PICs, which have to be masked before using the IO APICs
+    pub const FLAG_PCAT_COMPAT: u32 = 1 << 0;
+}
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+const ENTRY_LOCAL_X2APIC_NMI: u8 = 10;
+
+const LAPIC_ENABLED: u32 = 1 << 0;
+const LAPIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// Applies to every processor in a local APIC NMI entry
+const ALL_PROCESSORS: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// Whatever the bus the interrupt comes from uses
+    BusDefault,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// Whatever the bus the interrupt comes from uses
+    BusDefault,
+    Edge,
+    Level,
+}
+
+/// Decodes the MPS INTI flags used by overrides and NMI entries
+fn inti_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::BusDefault,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::BusDefault,
+    };
+    (polarity, trigger)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    /// The ACPI processor UID, which the NMI entries and the namespace refer to
+    pub uid: u32,
+    pub apic_id: u32,
+    pub is_bsp: bool,
+    /// The processor is enabled, and can be started right away
+    pub enabled: bool,
+    /// The processor is disabled, but can be hot plugged later
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The first global system interrupt this IO APIC handles
+    pub gsi_base: u32,
+}
+
+/// An ISA interrupt that isn't identity mapped to a global system interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// A local APIC LINT pin that is connected to NMI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    /// The ACPI processor UID, or `None` for every processor
+    pub processor_uid: Option<u32>,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// The processors and interrupt controllers of the system
+#[derive(Debug, Clone)]
+pub struct CpuTopology {
+    pub local_apic_address: PhysAddr,
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+impl CpuTopology {
+    /// Builds the topology from the entries of the MADT
+    ///
+    /// `bsp_apic_id` is the APIC ID of the processor we are running on.
+    pub fn from_madt(table: &[u8], bsp_apic_id: u32) -> Option<Self> {
+        if table.len() < size_of::<Madt>() {
+            return None;
+        }
+        let madt = unsafe { table.as_ptr().cast::<Madt>().read_unaligned() };
+        let mut topology = Self {
+            local_apic_address: PhysAddr::new(madt.local_apic_address as usize),
+            has_legacy_pics: madt.flags & Madt::FLAG_PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let u16_at = |entry: &[u8], offset: usize| u16::from_le_bytes([entry[offset], entry[offset + 1]]);
+        let u32_at = |entry: &[u8], offset: usize| u32::from_le_bytes(entry[offset..offset + 4].try_into().unwrap());
+
+        let mut entries = &table[size_of::<Madt>()..];
+        while let [ty, len, ..] = *entries {
+            let len = len as usize;
+            if len < 2 || len > entries.len() {
+                break;
+            }
+            let entry = &entries[..len];
+            entries = &entries[len..];
+
+            match (ty, len) {
+                (ENTRY_LOCAL_APIC, 8..) => topology.add_processor(entry[2] as u32, entry[3] as u32, u32_at(entry, 4)),
+                (ENTRY_LOCAL_X2APIC, 16..) => {
+                    topology.add_processor(u32_at(entry, 12), u32_at(entry, 4), u32_at(entry, 8))
+                }
+                (ENTRY_IO_APIC, 12..) => topology.io_apics.push(IoApic {
+                    id: entry[2],
+                    address: PhysAddr::new(u32_at(entry, 4) as usize),
+                    gsi_base: u32_at(entry, 8),
+                }),
+                (ENTRY_INTERRUPT_OVERRIDE, 10..) => {
+                    let (polarity, trigger) = inti_flags(u16_at(entry, 8));
+                    topology.overrides.push(InterruptOverride {
+                        isa_irq: entry[3],
+                        gsi: u32_at(entry, 4),
+                        polarity,
+                        trigger,
+                    });
+                }
+                (ENTRY_LOCAL_APIC_NMI, 6..) => {
+                    let uid = match entry[2] {
+                        0xFF => ALL_PROCESSORS,
+                        uid => uid as u32,
+                    };
+                    topology.add_nmi(uid, u16_at(entry, 3), entry[5]);
+                }
+                (ENTRY_LOCAL_X2APIC_NMI, 12..) => topology.add_nmi(u32_at(entry, 4), u16_at(entry, 2), entry[8]),
+                (ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE, 12..) => {
+                    topology.local_apic_address =
+                        PhysAddr::new(u64::from_le_bytes(entry[4..12].try_into().unwrap()) as usize)
+                }
+                _ => {}
+            }
+        }
+
+        for processor in &mut topology.processors {
+            processor.is_bsp = processor.apic_id == bsp_apic_id;
+        }
+        Some(topology)
+    }
+
+    fn add_processor(&mut self, uid: u32, apic_id: u32, flags: u32) {
+        let enabled = flags & LAPIC_ENABLED != 0;
+        let online_capable = flags & LAPIC_ONLINE_CAPABLE != 0;
+        // Neither enabled nor online capable means the processor can never be used
+        if !enabled && !online_capable {
+            return;
+        }
+        // Firmware may list a processor as both a local APIC and a local x2APIC
+        if self.processors.iter().any(|cpu| cpu.apic_id == apic_id) {
+            return;
+        }
+        self.processors.push(Processor {
+            uid,
+            apic_id,
+            is_bsp: false,
+            enabled,
+            online_capable,
+        });
+    }
+
+    fn add_nmi(&mut self, uid: u32, flags: u16, lint: u8) {
+        let (polarity, trigger) = inti_flags(flags);
+        self.nmis.push(LocalApicNmi {
+            processor_uid: (uid != ALL_PROCESSORS).then_some(uid),
+            lint,
+            polarity,
+            trigger,
+        });
+    }
+
+    /// Returns the bootstrap processor
+    pub fn bsp(&self) -> Option<&Processor> {
+        self.processors.iter().find(|cpu| cpu.is_bsp)
+    }
+
+    /// Returns the processors other than the BSP that can be started
+    pub fn application_processors(&self) -> impl Iterator<Item = &Processor> {
+        self.processors.iter().filter(|cpu| !cpu.is_bsp && cpu.enabled)
+    }
+
+    /// Returns the IO APIC handling a global system interrupt
+    pub fn io_apic_for_gsi(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics
+            .iter()
+            .filter(|ioapic| ioapic.gsi_base <= gsi)
+            .max_by_key(|ioapic| ioapic.gsi_base)
+    }
+
+    /// Translates an ISA IRQ to its global system interrupt, with the override if there is one
+    pub fn isa_irq_to_gsi(&self, irq: u8) -> (u32, Option<&InterruptOverride>) {
+        match self.overrides.iter().find(|iso| iso.isa_irq == irq) {
+            Some(iso) => (iso.gsi, Some(iso)),
+            None => (irq as u32, None),
+        }
+    }
+
+    /// Returns the NMI configuration that applies to a processor
+    pub fn nmis_for(&self, processor_uid: u32) -> impl Iterator<Item = &LocalApicNmi> {
+        self.nmis
+            .iter()
+            .filter(move |nmi| nmi.processor_uid.is_none_or(|uid| uid == processor_uid))
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn madt_topology() {
+        let mut table = alloc::vec![0u8; size_of::<Madt>()];
+        table[36..40].copy_from_slice(&0xFEE0_0000u32.to_le_bytes());
+        table[40] = Madt::FLAG_PCAT_COMPAT as u8;
+        // Two enabled processors, and one that can never be started
+        table.extend_from_slice(&[ENTRY_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+        table.extend_from_slice(&[ENTRY_LOCAL_APIC, 8, 1, 1, 1, 0, 0, 0]);
+        table.extend_from_slice(&[ENTRY_LOCAL_APIC, 8, 2, 2, 0, 0, 0, 0]);
+        table.extend_from_slice(&[ENTRY_IO_APIC, 12, 0, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+        // ISA IRQ 0 is routed to GSI 2, edge triggered and active high
+        table.extend_from_slice(&[ENTRY_INTERRUPT_OVERRIDE, 10, 0, 0, 2, 0, 0, 0, 0b0101, 0]);
+        table.extend_from_slice(&[ENTRY_LOCAL_APIC_NMI, 6, 0xFF, 0, 0, 1]);
+
+        let topology = CpuTopology::from_madt(&table, 0).unwrap();
+        assert!(topology.has_legacy_pics);
+        assert_eq!(topology.processors.len(), 2);
+        assert_eq!(topology.bsp().unwrap().apic_id, 0);
+        assert_eq!(topology.application_processors().count(), 1);
+        assert_eq!(topology.io_apic_for_gsi(2).unwrap().address, PhysAddr::new(0xFEC0_0000));
+
+        let (gsi, iso) = topology.isa_irq_to_gsi(0);
+        assert_eq!(gsi, 2);
+        assert_eq!(iso.unwrap().trigger, TriggerMode::Edge);
+        assert_eq!(topology.isa_irq_to_gsi(1).0, 1);
+        assert_eq!(topology.nmis_for(1).next().unwrap().lint, 1);
+    }
+}
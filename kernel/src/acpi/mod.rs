@@ -0,0 +1,244 @@
+//! ACPI table discovery
+//!
+//! The RSDP and every table referenced by the RSDT/XSDT are mapped once at boot,
+//! and stay mapped so drivers can look them up by signature. The MADT is parsed into a
+//! [`CpuTopology`] at the same time.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{PhysAddr, VirtAddr},
+    mm::mmio::{self, MmioSpaceExhausted},
+    sync::{Once, RwLock},
+};
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+
+use madt::{CpuTopology, Madt};
+
+/// The Root System Description Pointer
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    pub signature: [u8; 8],
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub revision: u8,
+    pub rsdt_address: u32,
+    // Fields below are only valid for revision >= 2
+    pub length: u32,
+    pub xsdt_address: u64,
+    pub extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+impl Rsdp {
+    const SIGNATURE: &[u8; 8] = b"RSD PTR ";
+    /// The size of the ACPI 1.0 part of the structure
+    const V1_SIZE: usize = 20;
+}
+
+/// The header shared by all System Description Tables
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// An ACPI Generic Address Structure
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SPACE_SYSTEM_MEMORY: u8 = 0;
+    pub const SPACE_SYSTEM_IO: u8 = 1;
+}
+
+/// A marker for tables that begin with a [`SdtHeader`]
+///
+/// # Safety
+/// The type must be `repr(C, packed)` and start with a [`SdtHeader`].
+pub unsafe trait AcpiTable {
+    const SIGNATURE: &[u8; 4];
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AcpiError {
+    InvalidRsdp,
+    InvalidChecksum([u8; 4]),
+    Mmio(MmioSpaceExhausted),
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidRsdp => f.write_str("invalid RSDP"),
+            Self::InvalidChecksum(sig) => write!(f, "invalid checksum for table {}", sig.escape_ascii()),
+            Self::Mmio(err) => write!(f, "failed to map table: {}", err),
+        }
+    }
+}
+
+impl core::error::Error for AcpiError {}
+
+impl From<MmioSpaceExhausted> for AcpiError {
+    fn from(err: MmioSpaceExhausted) -> Self {
+        Self::Mmio(err)
+    }
+}
+
+/// A table that has been mapped into the kernel
+#[derive(Debug, Clone, Copy)]
+pub struct MappedTable {
+    pub signature: [u8; 4],
+    pub phys: PhysAddr,
+    pub virt: VirtAddr,
+    pub length: usize,
+}
+
+impl MappedTable {
+    /// Returns the bytes of the whole table, including the header
+    pub fn bytes(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.length) }
+    }
+}
+
+#[derive(Debug)]
+pub struct AcpiTables {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    tables: Vec<MappedTable>,
+}
+
+impl AcpiTables {
+    /// Returns the first table with the given signature
+    pub fn find_raw(&self, signature: &[u8; 4]) -> Option<&MappedTable> {
+        self.tables.iter().find(|table| &table.signature == signature)
+    }
+
+    /// Returns the first table of the given type
+    pub fn find<T: AcpiTable>(&self) -> Option<&'static T> {
+        let table = self.find_raw(T::SIGNATURE)?;
+        if table.length < size_of::<T>() {
+            return None;
+        }
+        Some(unsafe { &*table.virt.as_ptr::<T>() })
+    }
+
+    pub fn iter(&self) -> core::slice::Iter<'_, MappedTable> {
+        self.tables.iter()
+    }
+}
+
+static ACPI: RwLock<Option<AcpiTables>> = RwLock::new(None);
+static TOPOLOGY: Once<CpuTopology> = Once::new();
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Maps the table at the given address, validating its checksum
+unsafe fn map_table(phys: PhysAddr) -> Result<MappedTable, AcpiError> {
+    let header = unsafe { mmio::map(phys, size_of::<SdtHeader>())? };
+    let sdt = unsafe { header.virt().as_ptr::<SdtHeader>().read_unaligned() };
+    unsafe { mmio::unmap(header) };
+
+    let length = sdt.length as usize;
+    let region = unsafe { mmio::map(phys, length)? };
+    let table = MappedTable {
+        signature: sdt.signature,
+        phys,
+        virt: region.virt(),
+        length,
+    };
+    if !checksum(table.bytes()) {
+        unsafe { mmio::unmap(region) };
+        return Err(AcpiError::InvalidChecksum(sdt.signature));
+    }
+    Ok(table)
+}
+
+/// Parses the RSDP and maps all tables from the RSDT/XSDT
+///
+/// # Safety
+/// The address must be the physical address of the RSDP, and the frame allocator must be initialized.
+pub unsafe fn init(rsdp_addr: PhysAddr) -> Result<(), AcpiError> {
+    let region = unsafe { mmio::map(rsdp_addr, size_of::<Rsdp>())? };
+    let rsdp = unsafe { region.virt().as_ptr::<Rsdp>().read_unaligned() };
+    let rsdp_bytes = unsafe { core::slice::from_raw_parts(region.virt().as_ptr::<u8>(), size_of::<Rsdp>()) };
+    let valid = &rsdp.signature == Rsdp::SIGNATURE
+        && checksum(&rsdp_bytes[..Rsdp::V1_SIZE])
+        && (rsdp.revision < 2 || checksum(&rsdp_bytes[..(rsdp.length as usize).min(size_of::<Rsdp>())]));
+    unsafe { mmio::unmap(region) };
+    if !valid {
+        return Err(AcpiError::InvalidRsdp);
+    }
+
+    let (root_phys, entry_size) = match rsdp.revision {
+        0 | 1 => (PhysAddr::new(rsdp.rsdt_address as usize), 4),
+        _ => (PhysAddr::new(rsdp.xsdt_address as usize), 8),
+    };
+    let root = unsafe { map_table(root_phys)? };
+    let entries = &root.bytes()[size_of::<SdtHeader>()..];
+
+    let mut tables = Vec::with_capacity(entries.len() / entry_size);
+    for entry in entries.chunks_exact(entry_size) {
+        let addr = match entry_size {
+            4 => u32::from_le_bytes(entry.try_into().unwrap()) as usize,
+            _ => u64::from_le_bytes(entry.try_into().unwrap()) as usize,
+        };
+        // A single corrupt table shouldn't prevent us from using the others
+        if let Ok(table) = unsafe { map_table(PhysAddr::new(addr)) } {
+            tables.push(table);
+        }
+    }
+    tables.push(root);
+
+    let tables = AcpiTables {
+        revision: rsdp.revision,
+        oem_id: rsdp.oem_id,
+        tables,
+    };
+    if let Some(madt) = tables.find_raw(Madt::SIGNATURE) {
+        let bsp_apic_id = crate::arch::x86_64::cpu::initial_apic_id();
+        if let Some(topology) = CpuTopology::from_madt(madt.bytes(), bsp_apic_id) {
+            TOPOLOGY.call_once(|| topology);
+        }
+    }
+    *ACPI.write() = Some(tables);
+    Ok(())
+}
+
+/// Runs the closure with the ACPI tables, if they have been initialized
+pub fn with_tables<R>(f: impl FnOnce(&AcpiTables) -> R) -> Option<R> {
+    ACPI.read().as_ref().map(f)
+}
+
+/// Returns the processors and interrupt controllers described by the MADT
+pub fn cpu_topology() -> Option<&'static CpuTopology> {
+    TOPOLOGY.get()
+}
+
+/// Returns the first table of the given type
+pub fn find_table<T: AcpiTable>() -> Option<&'static T> {
+    ACPI.read().as_ref()?.find::<T>()
+}
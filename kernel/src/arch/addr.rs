@@ -1,4 +1,10 @@
-use core::fmt;
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// The number of implemented virtual address bits, which depends on the paging mode
+static VIRT_ADDR_BITS: AtomicU32 = AtomicU32::new(48);
 
 #[derive(Debug, Clone, Copy)]
 pub struct InvalidVirtAddr;
@@ -25,22 +31,46 @@ impl fmt::Debug for VirtAddr {
 impl VirtAddr {
     pub const NULL: Self = Self(0);
 
-    /// Checks if an address is canonical
-    const fn is_canonical(addr: usize) -> bool {
-        if (addr & 0x0000_8000_0000_0000) != 0 {
-            (addr & 0xFFFF_0000_0000_0000) == 0xFFFF_0000_0000_0000
-        } else {
-            (addr & 0xFFFF_0000_0000_0000) == 0
-        }
+    /// The virtual address bits with 4-level paging
+    pub const BITS_4_LEVEL: u32 = 48;
+    /// The virtual address bits with 5-level paging, the most of any paging mode
+    pub const BITS_5_LEVEL: u32 = 57;
+
+    /// Returns the number of implemented virtual address bits, 57 with 5-level paging
+    pub fn bits() -> u32 {
+        VIRT_ADDR_BITS.load(Ordering::Relaxed)
     }
 
-    /// Creates a VirtAddr, and canonicalizing it
-    pub const fn new_truncate(addr: usize) -> Self {
-        if (addr & 0x0000_8000_0000_0000) != 0 {
-            Self((addr & 0x0000_FFFF_FFFF_FFFF) | 0xFFFF_0000_0000_0000)
+    /// Returns whether 5-level paging is enabled
+    pub fn is_five_level() -> bool {
+        Self::bits() == Self::BITS_5_LEVEL
+    }
+
+    /// Sets the paging mode, which must be done before any address of the upper 5-level half is used
+    pub fn set_five_level(enabled: bool) {
+        let bits = if enabled {
+            Self::BITS_5_LEVEL
         } else {
-            Self(addr & 0x0000_FFFF_FFFF_FFFF)
-        }
+            Self::BITS_4_LEVEL
+        };
+        VIRT_ADDR_BITS.store(bits, Ordering::Relaxed);
+    }
+
+    /// Checks if an address is canonical with `bits` implemented bits
+    const fn is_canonical_with(addr: usize, bits: u32) -> bool {
+        let upper = addr >> (bits - 1);
+        upper == 0 || upper == usize::MAX >> (bits - 1)
+    }
+
+    /// Checks if an address is canonical in the current paging mode
+    pub fn is_canonical(addr: usize) -> bool {
+        Self::is_canonical_with(addr, Self::bits())
+    }
+
+    /// Creates a VirtAddr, and canonicalizing it for the current paging mode
+    pub fn new_truncate(addr: usize) -> Self {
+        let unused = usize::BITS - Self::bits();
+        Self((((addr << unused) as isize) >> unused) as usize)
     }
 
     pub fn from_ptr<T>(ptr: *const T) -> Self {
@@ -48,18 +78,22 @@ impl VirtAddr {
     }
 
     /// # Panics
-    /// This function panics if the address is not canonical.
-    /// If you want to get an error type instead, see [`VirtAddr::try_new`]
+    /// This function panics if the address is not canonical with 57 bits.
+    /// To be usable in constants, this doesn't depend on the paging mode, so addresses which are only
+    /// canonical with 5-level paging are accepted. If you want to get an error type instead, or
+    /// check against the current paging mode, see [`VirtAddr::try_new`]
     pub const fn new(addr: usize) -> Self {
-        match Self::try_new(addr) {
-            Ok(addr) => addr,
-            Err(_) => panic!("virtual address is not canonical"),
+        if !Self::is_canonical_with(addr, Self::BITS_5_LEVEL) {
+            panic!("virtual address is not canonical");
         }
+        // SAFETY: We checked that it is canonical
+        unsafe { Self::new_unchecked(addr) }
     }
 
     /// # Errors
-    /// This function will return a InvalidVirtAddr if the address is not canonical
-    pub const fn try_new(addr: usize) -> Result<Self, InvalidVirtAddr> {
+    /// This function will return a InvalidVirtAddr if the address is not canonical in the current
+    /// paging mode
+    pub fn try_new(addr: usize) -> Result<Self, InvalidVirtAddr> {
         if !Self::is_canonical(addr) {
             return Err(InvalidVirtAddr);
         }
@@ -79,6 +113,11 @@ impl VirtAddr {
         unsafe { core::mem::transmute(self.0) }
     }
 
+    /// Only meaningful with 5-level paging
+    pub fn p5_index(&self) -> usize {
+        (self.as_usize() >> 48) & 0x1FF
+    }
+
     pub fn p4_index(&self) -> usize {
         (self.as_usize() >> 39) & 0x1FF
     }
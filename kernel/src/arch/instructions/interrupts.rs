@@ -19,3 +19,48 @@ pub unsafe fn disable() {
         unimplemented!();
     }
 }
+
+/// Enables interrupts and halts until the next one
+///
+/// Interrupts are only enabled after the next instruction, so an interrupt arriving in between
+/// can't be missed.
+///
+/// # Safety
+/// Interrupts are left enabled, so the caller must be ready to handle them.
+#[inline]
+pub unsafe fn enable_and_hlt() {
+    if cfg!(target_arch = "x86_64") {
+        unsafe { asm!("sti", "hlt", options(nomem, nostack)) }
+    } else {
+        unimplemented!();
+    }
+}
+
+/// Returns whether interrupts are enabled on the current CPU
+#[inline]
+pub fn are_enabled() -> bool {
+    if cfg!(target_arch = "x86_64") {
+        let flags: u64;
+        unsafe { asm!("pushfq", "pop {}", out(reg) flags, options(nomem, preserves_flags)) };
+        flags & (1 << 9) != 0
+    } else {
+        unimplemented!();
+    }
+}
+
+/// Runs the closure with interrupts disabled, restoring the previous state afterwards
+#[inline]
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let enabled = are_enabled();
+    if enabled {
+        unsafe { disable() };
+    }
+    let ret = f();
+    if enabled {
+        unsafe { enable() };
+    }
+    ret
+}
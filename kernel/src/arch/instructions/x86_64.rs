@@ -36,3 +36,11 @@ pub unsafe fn invlpg(addr: VirtAddr) {
         );
     }
 }
+
+/// Halts until the next interrupt, forever if interrupts are disabled
+#[inline]
+pub fn hlt() {
+    unsafe {
+        asm!("hlt", options(nomem, nostack, preserves_flags));
+    }
+}
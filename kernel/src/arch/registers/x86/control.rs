@@ -3,6 +3,55 @@ use crate::{
     mm::paging::{PhysFrame, Size4KiB},
 };
 
+pub struct Cr0;
+
+impl Cr0 {
+    pub fn read_raw() -> usize {
+        let out: usize;
+        unsafe {
+            core::arch::asm!(
+                "mov {}, cr0",
+                out(reg) out,
+                options(nostack, preserves_flags)
+            );
+        }
+        out
+    }
+
+    pub fn read() -> Cr0Flags {
+        Cr0Flags::from_bits_retain(Self::read_raw())
+    }
+
+    /// # Safety
+    /// Changes how the CPU runs, the caller must keep paging and protection working.
+    pub unsafe fn write(flags: Cr0Flags) {
+        unsafe {
+            core::arch::asm!(
+                "mov cr0, {}",
+                in(reg) flags.bits(),
+                options(nostack, preserves_flags)
+            );
+        }
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Cr0Flags: usize {
+        const PROTECTED_MODE = 1 << 0;
+        /// WAIT/FWAIT honour [`Self::TASK_SWITCHED`]
+        const MONITOR_COPROCESSOR = 1 << 1;
+        /// x87 instructions raise #NM, and SSE ones #UD
+        const EMULATE_COPROCESSOR = 1 << 2;
+        /// The next x87 or SIMD instruction raises #NM
+        const TASK_SWITCHED = 1 << 3;
+        /// x87 errors raise #MF instead of going through the legacy PIC
+        const NUMERIC_ERROR = 1 << 5;
+        const WRITE_PROTECT = 1 << 16;
+        const PAGING = 1 << 31;
+    }
+}
+
 pub struct Cr2;
 
 impl Cr2 {
@@ -50,6 +99,11 @@ impl Cr3 {
         PhysAddr::new(Self::read() & Self::ADDR_MASK)
     }
 
+    /// Returns the raw value, with the flags and PCID
+    pub fn read_raw() -> usize {
+        Self::read()
+    }
+
     pub unsafe fn write(frame: PhysFrame<Size4KiB>, flags: Cr3Flags) {
         //assert_unsafe_precondition!()
         unsafe {
@@ -64,3 +118,48 @@ bitflags::bitflags! {
         const CACHE_DISABLE = 1 << 4;
     }
 }
+
+pub struct Cr4;
+
+impl Cr4 {
+    pub fn read() -> Cr4Flags {
+        let out: usize;
+        unsafe {
+            core::arch::asm!(
+                "mov {}, cr4",
+                out(reg) out,
+                options(nostack, preserves_flags)
+            );
+        }
+        Cr4Flags::from_bits_retain(out)
+    }
+
+    /// # Safety
+    /// Changes how the CPU runs, the caller must keep paging and protection working.
+    pub unsafe fn write(flags: Cr4Flags) {
+        unsafe {
+            core::arch::asm!(
+                "mov cr4, {}",
+                in(reg) flags.bits(),
+                options(nostack, preserves_flags)
+            );
+        }
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Cr4Flags: usize {
+        const PAGE_SIZE_EXTENSION = 1 << 4;
+        const PHYSICAL_ADDRESS_EXTENSION = 1 << 5;
+        const PAGE_GLOBAL = 1 << 7;
+        const OSFXSR = 1 << 9;
+        const OSXMMEXCPT = 1 << 10;
+        /// 5-level paging, with 57-bit virtual addresses
+        const LA57 = 1 << 12;
+        const FSGSBASE = 1 << 16;
+        const OSXSAVE = 1 << 18;
+        const SMEP = 1 << 20;
+        const SMAP = 1 << 21;
+    }
+}
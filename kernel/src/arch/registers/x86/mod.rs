@@ -1,4 +1,5 @@
 pub mod control;
+pub mod msr;
 mod rflags;
 pub mod segmentation;
 pub use rflags::RFlags;
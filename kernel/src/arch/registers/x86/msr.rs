@@ -0,0 +1,68 @@
+//! Model Specific Registers
+
+/// A Model Specific Register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msr(u32);
+
+impl Msr {
+    pub const IA32_APIC_BASE: Self = Self(0x1B);
+    /// Bits 52:50 select the platform flag an Intel microcode update has to match
+    pub const IA32_PLATFORM_ID: Self = Self(0x17);
+    /// Loads an Intel microcode update, written with the address of its data
+    pub const IA32_BIOS_UPDT_TRIG: Self = Self(0x79);
+    /// The microcode revision, in the upper half on Intel and the lower half on AMD
+    pub const IA32_BIOS_SIGN_ID: Self = Self(0x8B);
+    /// Added to the TSC of the current CPU, if CPUID.07H:EBX.TSC_ADJUST is set
+    pub const IA32_TSC_ADJUST: Self = Self(0x3B);
+    pub const IA32_GS_BASE: Self = Self(0xC000_0101);
+    pub const IA32_KERNEL_GS_BASE: Self = Self(0xC000_0102);
+    pub const IA32_EFER: Self = Self(0xC000_0080);
+    /// Loads an AMD microcode patch, written with its address
+    pub const AMD64_PATCH_LOADER: Self = Self(0xC001_0020);
+    /// The number of machine check banks, and which machine check features are supported
+    pub const IA32_MCG_CAP: Self = Self(0x179);
+    pub const IA32_MCG_STATUS: Self = Self(0x17A);
+    pub const IA32_PMC0: Self = Self(0xC1);
+    pub const IA32_PERFEVTSEL0: Self = Self(0x186);
+    pub const IA32_PERF_GLOBAL_STATUS: Self = Self(0x38E);
+    pub const IA32_PERF_GLOBAL_CTRL: Self = Self(0x38F);
+    pub const IA32_PERF_GLOBAL_OVF_CTRL: Self = Self(0x390);
+
+    pub const fn new(reg: u32) -> Self {
+        Self(reg)
+    }
+
+    /// Reads the MSR
+    ///
+    /// # Safety
+    /// The MSR must exist on the current CPU, and reading it must not have side effects.
+    pub unsafe fn read(&self) -> u64 {
+        let (high, low): (u32, u32);
+        unsafe {
+            core::arch::asm!(
+                "rdmsr",
+                in("ecx") self.0,
+                out("eax") low,
+                out("edx") high,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        ((high as u64) << 32) | (low as u64)
+    }
+
+    /// Writes the MSR
+    ///
+    /// # Safety
+    /// The MSR must exist on the current CPU, and the value must not break memory safety.
+    pub unsafe fn write(&mut self, value: u64) {
+        unsafe {
+            core::arch::asm!(
+                "wrmsr",
+                in("ecx") self.0,
+                in("eax") value as u32,
+                in("edx") (value >> 32) as u32,
+                options(nostack, preserves_flags)
+            );
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Local APIC
+//!
+//! Only the parts needed to acknowledge interrupts, address MSIs, send fixed IPIs and deliver
+//! performance counter NMIs are implemented for now. The frequency of the timer is measured
+//! against the PIT, though nothing uses the timer yet.
+//!
+//! When the CPU supports it, the APIC is switched to x2APIC mode, where the registers are MSRs
+//! instead of memory mapped, APIC IDs are 32 bits, and the ICR is written at once. Machines with
+//! more than 255 CPUs and some hypervisors need it. `nox2apic` on the command line keeps the xAPIC
+//! mode.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    arch::{
+        PhysAddr, VirtAddr,
+        registers::msr::Msr,
+        x86_64::{
+            cpu::{self, Feature},
+            pit,
+        },
+    },
+    dev::resource::{self, ResourceKind},
+    irq,
+    mm::mmio,
+    sync::cell::RacyCell,
+};
+
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xB0;
+const REG_SPURIOUS: usize = 0xF0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_LVT_PERF: usize = 0x340;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3E0;
+/// Only in x2APIC mode, sends an IPI to the current CPU
+const REG_SELF_IPI: usize = 0x3F0;
+
+/// The x2APIC MSR of a register is its offset divided by 16, from this MSR on
+const X2APIC_MSR_BASE: u32 = 0x800;
+/// The APIC is enabled
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+/// The APIC is in x2APIC mode, only set together with [`APIC_BASE_ENABLE`]
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+/// Set in a local vector table entry to mask it
+const LVT_MASKED: u32 = 1 << 16;
+/// Divides the bus clock by 16 for the timer
+const TIMER_DIVIDE_16: u32 = 0b0011;
+/// The calibration window of the timer in milliseconds
+const TIMER_CALIBRATE_MS: u64 = 10;
+
+/// The delivery mode of a local vector table entry that raises an NMI
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// Set in the low ICR register while an IPI hasn't been accepted yet, only in xAPIC mode
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// The destination shorthand that sends an IPI to the sender
+const ICR_SELF: u32 = 0b01 << 18;
+/// The delivery mode of an IPI that raises an NMI, the vector is ignored
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// The vector used for spurious interrupts
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// The base address of the MSI address window
+pub const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+
+/// How the registers are accessed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicMode {
+    /// Memory mapped at the given address
+    XApic(VirtAddr),
+    /// Through MSRs
+    X2Apic,
+}
+
+impl fmt::Display for ApicMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::XApic(_) => "xAPIC",
+            Self::X2Apic => "x2APIC",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct LocalApic {
+    mode: ApicMode,
+}
+
+impl LocalApic {
+    fn read(&self, reg: usize) -> u32 {
+        match self.mode {
+            ApicMode::XApic(base) => unsafe { mmio::read(base, reg) },
+            ApicMode::X2Apic => unsafe { x2apic_msr(reg).read() as u32 },
+        }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        match self.mode {
+            ApicMode::XApic(base) => unsafe { mmio::write(base, reg, value) },
+            ApicMode::X2Apic => unsafe { x2apic_msr(reg).write(value as u64) },
+        }
+    }
+
+    pub fn mode(&self) -> ApicMode {
+        self.mode
+    }
+
+    /// Returns the APIC ID of the current CPU
+    pub fn id(&self) -> u32 {
+        match self.mode {
+            ApicMode::XApic(_) => self.read(REG_ID) >> 24,
+            ApicMode::X2Apic => self.read(REG_ID),
+        }
+    }
+
+    /// Signals end of interrupt
+    pub fn eoi(&self) {
+        self.write(REG_EOI, 0);
+    }
+
+    /// Sends a fixed interrupt with `vector` to the CPU with the given APIC ID
+    pub fn send_ipi(&self, apic_id: u32, vector: u8) {
+        self.write_icr(apic_id, vector as u32);
+    }
+
+    /// Sends an NMI to the CPU with the given APIC ID, which gets through even with interrupts disabled
+    pub fn send_nmi(&self, apic_id: u32) {
+        self.write_icr(apic_id, ICR_DELIVERY_NMI);
+    }
+
+    /// Sends a fixed interrupt with `vector` to the current CPU
+    pub fn send_self_ipi(&self, vector: u8) {
+        match self.mode {
+            ApicMode::XApic(_) => self.write_icr(0, ICR_SELF | vector as u32),
+            ApicMode::X2Apic => self.write(REG_SELF_IPI, vector as u32),
+        }
+    }
+
+    fn write_icr(&self, apic_id: u32, low: u32) {
+        match self.mode {
+            ApicMode::XApic(_) => {
+                while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                    core::hint::spin_loop();
+                }
+                self.write(REG_ICR_HIGH, apic_id << 24);
+                // Writing the low register sends the IPI
+                self.write(REG_ICR_LOW, low);
+            }
+            // A single MSR, with the destination in the upper half
+            ApicMode::X2Apic => unsafe { x2apic_msr(REG_ICR_LOW).write(((apic_id as u64) << 32) | low as u64) },
+        }
+    }
+
+    /// Masks the timer, returning its local vector table entry for [`restore_timer`](Self::restore_timer)
+    pub fn mask_timer(&self) -> u32 {
+        let lvt = self.read(REG_LVT_TIMER);
+        self.write(REG_LVT_TIMER, lvt | LVT_MASKED);
+        lvt
+    }
+
+    /// Puts back the timer entry saved by [`mask_timer`](Self::mask_timer)
+    pub fn restore_timer(&self, lvt: u32) {
+        self.write(REG_LVT_TIMER, lvt);
+    }
+
+    /// Delivers performance counter overflows as NMIs
+    ///
+    /// The entry masks itself when it delivers an NMI, so this has to be called again after
+    /// every overflow.
+    pub fn set_perf_nmi(&self) {
+        self.write(REG_LVT_PERF, LVT_DELIVERY_NMI);
+    }
+
+    /// Measures the frequency of the timer in Hz, with the bus clock divided by 16
+    ///
+    /// The timer is left masked and stopped.
+    ///
+    /// # Safety
+    /// Uses PIT channel 2, see [`pit::calibrate_against`].
+    unsafe fn calibrate_timer(&self) -> Result<u64, pit::PitError> {
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        self.write(REG_TIMER_INITIAL, u32::MAX);
+        // The timer counts down
+        let read = || (u32::MAX - self.read(REG_TIMER_CURRENT)) as u64;
+        let hz = unsafe { pit::calibrate_against(read, TIMER_CALIBRATE_MS) };
+        self.write(REG_TIMER_INITIAL, 0);
+        hz
+    }
+
+    /// Returns the MSI address and data that deliver `vector` to this APIC
+    ///
+    /// MSIs only address 8 bit APIC IDs without interrupt remapping.
+    pub fn msi_message(&self, vector: u8) -> (u32, u32) {
+        (MSI_ADDRESS_BASE | ((self.id() & 0xFF) << 12), vector as u32)
+    }
+}
+
+fn x2apic_msr(reg: usize) -> Msr {
+    Msr::new(X2APIC_MSR_BASE + (reg >> 4) as u32)
+}
+
+static LAPIC: RacyCell<Option<LocalApic>> = RacyCell::new(None);
+/// The frequency of the timer in Hz, 0 if it couldn't be measured
+static TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the local APIC, if it has been initialized
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LAPIC.get().as_ref()
+}
+
+fn eoi(vector: u8) {
+    // Spurious interrupts must not be acknowledged
+    if vector == SPURIOUS_VECTOR {
+        return;
+    }
+    if let Some(lapic) = local_apic() {
+        lapic.eoi();
+    }
+}
+
+/// Switches the local APIC of the BSP to x2APIC mode or maps it, software enables it, uses it to
+/// acknowledge interrupts, and measures its timer, returning the mode it is in
+///
+/// # Safety
+/// Must only be called once, with interrupts disabled, after the frame allocator is initialized.
+pub unsafe fn init() -> Result<ApicMode, mmio::MmioSpaceExhausted> {
+    let mut apic_base = Msr::IA32_APIC_BASE;
+    let base = unsafe { apic_base.read() };
+    let mode = if cpu::has(Feature::X2Apic) && !crate::boot::cmdline().flag("nox2apic") {
+        unsafe { apic_base.write(base | APIC_BASE_ENABLE | APIC_BASE_X2APIC) };
+        ApicMode::X2Apic
+    } else {
+        let phys = PhysAddr::new((base & 0x000F_FFFF_FFFF_F000) as usize);
+        let region = unsafe { mmio::map(phys, 0x1000)? };
+        let start = phys.as_usize() as u64;
+        resource::reserve(ResourceKind::Mmio, start..=start + 0xFFF, "local apic");
+        ApicMode::XApic(region.virt())
+    };
+
+    let lapic = LocalApic { mode };
+    // Software enable the APIC, and set the spurious vector
+    lapic.write(
+        REG_SPURIOUS,
+        lapic.read(REG_SPURIOUS) | (1 << 8) | SPURIOUS_VECTOR as u32,
+    );
+    match unsafe { lapic.calibrate_timer() } {
+        Ok(hz) => TIMER_HZ.store(hz, Ordering::Relaxed),
+        Err(err) => crate::kprintln!(Warn, "apic: can't measure the timer: {}", err),
+    }
+    LAPIC.replace(Some(lapic));
+
+    irq::set_eoi_handler(Some(eoi));
+    Ok(mode)
+}
+
+/// Returns the frequency of the timer in Hz, with the bus clock divided by 16, or 0 if unknown
+pub fn timer_frequency() -> u64 {
+    TIMER_HZ.load(Ordering::Relaxed)
+}
@@ -1,6 +1,6 @@
 use core::marker::PhantomData;
 
-use spin::Mutex;
+use crate::sync::Mutex;
 
 use crate::{
     arch::{
@@ -9,6 +9,7 @@ use crate::{
         registers::segmentation::SegmentSelector,
         x86_64::core::{idt::PrivilegeLevel, tss::TaskStateSegment},
     },
+    mm::stack,
     util::bits::BitHelper,
 };
 
@@ -196,6 +197,8 @@ pub struct Selectors {
 
 impl Selectors {
     pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
+    /// The stack used when an interrupt or exception arrives in ring 3
+    pub const RING_0_STACK_INDEX: usize = 0;
 
     pub const fn empty() -> Self {
         Self {
@@ -216,9 +219,22 @@ lazy_static::lazy_static! {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[Selectors::DOUBLE_FAULT_IST_INDEX] = {
             const STACK_SIZE: usize = 4096 * 8;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            static mut STACK: [u64; STACK_SIZE / 8] = [0; STACK_SIZE / 8];
             #[allow(static_mut_refs)]
             let stack_start = VirtAddr::from_ptr(&raw const STACK);
+            // SAFETY: The TSS isn't loaded yet, so nothing runs on the stack
+            unsafe { stack::poison(stack_start, STACK_SIZE) };
+            stack::register("double fault", stack_start, STACK_SIZE);
+            stack_start + STACK_SIZE
+        };
+        tss.privilege_stack_table[Selectors::RING_0_STACK_INDEX] = {
+            const STACK_SIZE: usize = 4096 * 8;
+            static mut STACK: [u64; STACK_SIZE / 8] = [0; STACK_SIZE / 8];
+            #[allow(static_mut_refs)]
+            let stack_start = VirtAddr::from_ptr(&raw const STACK);
+            // SAFETY: The TSS isn't loaded yet, so nothing runs on the stack
+            unsafe { stack::poison(stack_start, STACK_SIZE) };
+            stack::register("ring 0", stack_start, STACK_SIZE);
             stack_start + STACK_SIZE
         };
         tss
@@ -232,8 +248,9 @@ pub unsafe fn init() {
 
     let kernel_code = gdt.0.append(Descriptor::kernel_code64());
     let kernel_data = gdt.0.append(Descriptor::kernel_data());
-    let user_code = gdt.0.append(Descriptor::user_code64());
+    // SYSRET loads SS and CS from consecutive entries, so user data has to come before user code
     let user_data = gdt.0.append(Descriptor::user_data());
+    let user_code = gdt.0.append(Descriptor::user_code64());
     let tss_selector = gdt.0.append(Descriptor::tss_segment(&TSS_SEGMENT));
 
     gdt.0.load();
@@ -1,32 +1,577 @@
-use crate::{arch::registers::control::Cr2, util::machine_state::MachineState};
+//! CPU exception handlers
+//!
+//! Every exception enters through a stub that saves the general purpose registers below the
+//! interrupt frame, so [`exception_report`] can show the whole state of the CPU at the exception,
+//! along with the decoded error code and the top of the stack. The kernel doesn't recover from
+//! exceptions yet, so the report becomes the panic message. The only exceptions that return are
+//! NMIs raised by the [watchdog](crate::arch::x86_64::watchdog).
 
-use super::InterruptStackFrame;
+use core::{
+    arch::{asm, naked_asm},
+    fmt,
+};
 
-pub(super) extern "x86-interrupt" fn divide_by_zero(stack_frame: InterruptStackFrame) {
-    panic!("FAULT: DIVIDE BY ZERO\nstack frame: {:#?}", stack_frame);
+use crate::{
+    arch::{
+        VirtAddr,
+        registers::{
+            control::{Cr0, Cr2, Cr3, Cr4},
+            msr::Msr,
+        },
+    },
+    mm::page_table::KernelPageTable,
+    util::machine_state::MachineState,
+};
+
+use super::ud::{CodeContext, Location, UdKind};
+
+pub const DIVIDE_ERROR: u8 = 0;
+pub const DEBUG: u8 = 1;
+pub const NMI: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const OVERFLOW: u8 = 4;
+pub const BOUND_RANGE: u8 = 5;
+pub const INVALID_OPCODE: u8 = 6;
+pub const DEVICE_NOT_AVAILABLE: u8 = 7;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_SEGMENT: u8 = 12;
+pub const GENERAL_PROTECTION: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+pub const X87_FLOATING_POINT: u8 = 16;
+pub const ALIGNMENT_CHECK: u8 = 17;
+pub const MACHINE_CHECK: u8 = 18;
+pub const SIMD_FLOATING_POINT: u8 = 19;
+pub const VIRTUALIZATION: u8 = 20;
+pub const CONTROL_PROTECTION: u8 = 21;
+pub const HV_INJECTION: u8 = 28;
+pub const VMM_COMMUNICATION: u8 = 29;
+pub const SECURITY: u8 = 30;
+
+/// The words of the stack shown in a report
+const STACK_WORDS: usize = 16;
+/// The most frames in a backtrace
+const BACKTRACE_FRAMES: usize = 16;
+
+/// The registers saved by the entry stubs, followed by the interrupt frame
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for exceptions without an error code
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
 }
 
-pub(super) extern "x86-interrupt" fn debug(stack_frame: InterruptStackFrame) {
-    panic!("TRAP: DEBUG\nstack frame: {:#?}", stack_frame);
+/// How an exception relates to the instruction that caused it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionKind {
+    /// Reported before the instruction, which is restarted on return
+    Fault,
+    /// Reported after the instruction
+    Trap,
+    /// Not restartable
+    Abort,
+    Interrupt,
 }
 
-pub(super) extern "x86-interrupt" fn breakpoint(stack_frame: InterruptStackFrame) {
-    panic!("TRAP: BREAKPOINT\nstack frame: {:#?}", stack_frame);
+impl fmt::Display for ExceptionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Fault => "FAULT",
+            Self::Trap => "TRAP",
+            Self::Abort => "ABORT",
+            Self::Interrupt => "INTERRUPT",
+        })
+    }
 }
 
-pub(super) extern "x86-interrupt" fn double_fault(stack_frame: InterruptStackFrame, err_code: u64) -> ! {
-    let state = MachineState::from_stack_frame(&stack_frame);
-    panic!(
-        "ABORT: DOUBLE_FAULT\nerror_code: {}\nstack frame: {:#?}\nstate: {}",
-        err_code, stack_frame, state
-    );
+/// Returns the name, mnemonic and kind of an exception vector
+pub fn describe(vector: u8) -> (&'static str, &'static str, ExceptionKind) {
+    use ExceptionKind::*;
+    match vector {
+        DIVIDE_ERROR => ("DIVIDE_ERROR", "#DE", Fault),
+        DEBUG => ("DEBUG", "#DB", Trap),
+        NMI => ("NON_MASKABLE_INTERRUPT", "NMI", Interrupt),
+        BREAKPOINT => ("BREAKPOINT", "#BP", Trap),
+        OVERFLOW => ("OVERFLOW", "#OF", Trap),
+        BOUND_RANGE => ("BOUND_RANGE_EXCEEDED", "#BR", Fault),
+        INVALID_OPCODE => ("INVALID_OPCODE", "#UD", Fault),
+        DEVICE_NOT_AVAILABLE => ("DEVICE_NOT_AVAILABLE", "#NM", Fault),
+        DOUBLE_FAULT => ("DOUBLE_FAULT", "#DF", Abort),
+        INVALID_TSS => ("INVALID_TSS", "#TS", Fault),
+        SEGMENT_NOT_PRESENT => ("SEGMENT_NOT_PRESENT", "#NP", Fault),
+        STACK_SEGMENT => ("STACK_SEGMENT_FAULT", "#SS", Fault),
+        GENERAL_PROTECTION => ("GENERAL_PROTECTION_FAULT", "#GP", Fault),
+        PAGE_FAULT => ("PAGE_FAULT", "#PF", Fault),
+        X87_FLOATING_POINT => ("X87_FLOATING_POINT", "#MF", Fault),
+        ALIGNMENT_CHECK => ("ALIGNMENT_CHECK", "#AC", Fault),
+        MACHINE_CHECK => ("MACHINE_CHECK", "#MC", Abort),
+        SIMD_FLOATING_POINT => ("SIMD_FLOATING_POINT", "#XM", Fault),
+        VIRTUALIZATION => ("VIRTUALIZATION", "#VE", Fault),
+        CONTROL_PROTECTION => ("CONTROL_PROTECTION", "#CP", Fault),
+        HV_INJECTION => ("HYPERVISOR_INJECTION", "#HV", Fault),
+        VMM_COMMUNICATION => ("VMM_COMMUNICATION", "#VC", Fault),
+        SECURITY => ("SECURITY", "#SX", Fault),
+        _ => ("RESERVED", "reserved", Abort),
+    }
+}
+
+/// An error code, decoded by the format the exception uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    None,
+    /// The selector of a segment or IDT entry, for #TS, #NP, #SS and #GP
+    Selector(u64),
+    PageFault(u64),
+    ControlProtection(u64),
+    Raw(u64),
+}
+
+impl ErrorCode {
+    pub fn decode(vector: u8, code: u64) -> Self {
+        match vector {
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT | GENERAL_PROTECTION => Self::Selector(code),
+            PAGE_FAULT => Self::PageFault(code),
+            CONTROL_PROTECTION => Self::ControlProtection(code),
+            DOUBLE_FAULT | ALIGNMENT_CHECK | VMM_COMMUNICATION | SECURITY => Self::Raw(code),
+            _ => Self::None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::None => f.write_str("none"),
+            Self::Selector(0) => f.write_str("0x0 (not caused by a selector)"),
+            Self::Selector(code) => {
+                let table = match (code >> 1) & 0b11 {
+                    0b00 => "GDT",
+                    0b10 => "LDT",
+                    _ => "IDT",
+                };
+                write!(f, "{:#x} ({} index {}", code, table, (code >> 3) & 0x1FFF)?;
+                if code & 1 != 0 {
+                    f.write_str(", during an external event")?;
+                }
+                f.write_str(")")
+            }
+            Self::PageFault(code) => {
+                let access = if code & (1 << 4) != 0 {
+                    "instruction fetch"
+                } else if code & (1 << 1) != 0 {
+                    "write"
+                } else {
+                    "read"
+                };
+                let cause = if code & 1 != 0 {
+                    "protection violation"
+                } else {
+                    "page not present"
+                };
+                let mode = if code & (1 << 2) != 0 { "user" } else { "kernel" };
+                write!(f, "{:#x} ({} {}, {}", code, mode, access, cause)?;
+                for (bit, name) in [(3, "reserved bit set"), (5, "protection key"), (6, "shadow stack")] {
+                    if code & (1 << bit) != 0 {
+                        write!(f, ", {}", name)?;
+                    }
+                }
+                f.write_str(")")
+            }
+            Self::ControlProtection(code) => {
+                let cause = match code & 0x7FFF {
+                    1 => "near return",
+                    2 => "far return or iret",
+                    3 => "missing endbranch",
+                    4 => "rstorssp",
+                    5 => "setssbsy",
+                    _ => "unknown",
+                };
+                write!(f, "{:#x} ({})", code, cause)
+            }
+            Self::Raw(code) => write!(f, "{:#x}", code),
+        }
+    }
+}
+
+/// The machine check banks with a valid error, as bank, status and address
+fn machine_check_banks() -> impl Iterator<Item = (u32, u64, Option<u64>)> {
+    const STATUS_VALID: u64 = 1 << 63;
+    const STATUS_ADDR_VALID: u64 = 1 << 58;
+    // SAFETY: The machine check MSRs exist if the CPU raised a machine check
+    let banks = unsafe { Msr::IA32_MCG_CAP.read() } as u32 & 0xFF;
+    (0..banks).filter_map(|bank| {
+        let status = unsafe { Msr::new(0x401 + bank * 4).read() };
+        if status & STATUS_VALID == 0 {
+            return None;
+        }
+        let addr = (status & STATUS_ADDR_VALID != 0).then(|| unsafe { Msr::new(0x402 + bank * 4).read() });
+        Some((bank, status, addr))
+    })
+}
+
+/// An exception with the state captured at entry
+pub struct Report<'a> {
+    frame: &'a ExceptionFrame,
+    cr2: VirtAddr,
+}
+
+impl<'a> Report<'a> {
+    /// Captures the state that isn't in the frame, before anything can change it
+    pub fn new(frame: &'a ExceptionFrame) -> Self {
+        Self {
+            frame,
+            cr2: Cr2::read(),
+        }
+    }
+
+    /// Returns the registers at the time of the exception
+    pub fn machine_state(&self) -> MachineState {
+        let frame = self.frame;
+        // The data segments are unchanged by the exception, so they come from the current values
+        MachineState {
+            rax: frame.rax,
+            rbx: frame.rbx,
+            rcx: frame.rcx,
+            rdx: frame.rdx,
+            r8: frame.r8,
+            r9: frame.r9,
+            r10: frame.r10,
+            r11: frame.r11,
+            r12: frame.r12,
+            r13: frame.r13,
+            r14: frame.r14,
+            r15: frame.r15,
+            rdi: frame.rdi,
+            rsi: frame.rsi,
+            rsp: frame.rsp,
+            rbp: frame.rbp,
+            rflags: frame.rflags,
+            cs: frame.cs as u16,
+            ss: frame.ss as u16,
+            cr0: Cr0::read_raw() as u64,
+            cr2: self.cr2.as_u64(),
+            cr3: Cr3::read_raw() as u64,
+            cr4: Cr4::read().bits() as u64,
+            // SAFETY: EFER exists on every x86_64 CPU
+            efer: unsafe { Msr::IA32_EFER.read() },
+            ..MachineState::with_rip(frame.rip)
+        }
+    }
+
+    /// Writes the top of the stack, stopping at the first unmapped word
+    fn write_stack(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let page_table = KernelPageTable::new(Cr3::addr());
+        writeln!(f, "stack:")?;
+        for idx in 0..STACK_WORDS {
+            let Some(addr) = (self.frame.rsp as usize)
+                .checked_add(idx * size_of::<u64>())
+                .and_then(|addr| VirtAddr::try_new(addr).ok())
+                .filter(|addr| addr.as_usize().is_multiple_of(size_of::<u64>()))
+                .filter(|addr| page_table.translate(*addr).is_some())
+            else {
+                return writeln!(f, "  (unmapped)");
+            };
+            // SAFETY: The word is mapped and aligned
+            let word = unsafe { core::ptr::read_volatile(addr.as_ptr::<u64>()) };
+            writeln!(f, "  {:#018x}: {:#018x}", addr.as_usize(), word)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frame = self.frame;
+        let vector = frame.vector as u8;
+        let (name, mnemonic, kind) = describe(vector);
+        writeln!(f, "{}: {} ({}, vector {})", kind, name, mnemonic, vector)?;
+        writeln!(f, "at: {}", Location(frame.rip as usize))?;
+        writeln!(f, "error code: {}", ErrorCode::decode(vector, frame.error_code))?;
+        match vector {
+            PAGE_FAULT => writeln!(f, "address: {:#x}", self.cr2)?,
+            INVALID_OPCODE => {
+                let context = CodeContext::read(VirtAddr::new(frame.rip as usize));
+                writeln!(f, "{}", UdKind::classify(context.before(), context.code()))?;
+                writeln!(f, "code: {}", context)?;
+            }
+            SIMD_FLOATING_POINT => writeln!(f, "mxcsr: {:#x}", crate::arch::x86_64::fpu::mxcsr())?,
+            MACHINE_CHECK => {
+                writeln!(f, "mcg status: {:#x}", unsafe { Msr::IA32_MCG_STATUS.read() })?;
+                for (bank, status, addr) in machine_check_banks() {
+                    write!(f, "bank {}: status {:#018x}", bank, status)?;
+                    match addr {
+                        Some(addr) => writeln!(f, " address {:#x}", addr)?,
+                        None => writeln!(f)?,
+                    }
+                }
+            }
+            _ => {}
+        }
+        if frame.cs & 0b11 == 3 {
+            writeln!(f, "in user mode")?;
+        }
+        writeln!(f, "registers:")?;
+        write!(f, "{}", self.machine_state())?;
+        self.write_stack(f)?;
+        writeln!(f, "backtrace:")?;
+        write!(f, "{}", Backtrace::capture(frame.rip as usize, frame.rbp as usize))
+    }
 }
 
-pub(super) extern "x86-interrupt" fn page_fault(stack_frame: InterruptStackFrame, err_code: u64) {
-    panic!(
-        "TRAP: PAGE_FAULT\nstack frame: {:#?}\nerr_code = {}\nat: {:#x}",
-        stack_frame,
-        err_code,
-        Cr2::read()
+/// The return addresses found by following the frame pointers
+///
+/// Captured without locking or allocating, so it works from an NMI. The kernel may be built
+/// without frame pointers, so the walk stops at the first frame that doesn't look like one, and
+/// can miss callers that didn't set one up.
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    frames: [usize; BACKTRACE_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Walks the frames starting at the given instruction and frame pointer
+    pub fn capture(rip: usize, rbp: usize) -> Self {
+        let page_table = KernelPageTable::new(Cr3::addr());
+        let is_frame = |rbp: usize| {
+            rbp.is_multiple_of(size_of::<u64>())
+                && [rbp, rbp + size_of::<u64>()]
+                    .into_iter()
+                    .all(|addr| VirtAddr::try_new(addr).is_ok_and(|addr| page_table.translate(addr).is_some()))
+        };
+        let mut backtrace = Self {
+            frames: [0; BACKTRACE_FRAMES],
+            len: 1,
+        };
+        backtrace.frames[0] = rip;
+        let mut rbp = rbp;
+        while backtrace.len < BACKTRACE_FRAMES && is_frame(rbp) {
+            // SAFETY: Both words of the frame are mapped and aligned
+            let (next, ret) = unsafe {
+                let frame = rbp as *const usize;
+                (frame.read_volatile(), frame.add(1).read_volatile())
+            };
+            if ret == 0 {
+                break;
+            }
+            backtrace.frames[backtrace.len] = ret;
+            backtrace.len += 1;
+            // Frames are pushed towards lower addresses, so the caller's frame is always above
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+        backtrace
+    }
+
+    /// Captures the backtrace of the caller
+    #[inline(always)]
+    pub fn here() -> Self {
+        let (rip, rbp): (usize, usize);
+        unsafe {
+            asm!(
+                "lea {}, [rip]",
+                "mov {}, rbp",
+                out(reg) rip,
+                out(reg) rbp,
+                options(nomem, nostack, preserves_flags)
+            )
+        };
+        Self::capture(rip, rbp)
+    }
+
+    /// Returns the addresses, starting with where it was captured
+    pub fn frames(&self) -> &[usize] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, addr) in self.frames().iter().enumerate() {
+            writeln!(f, "  #{} {}", idx, Location(*addr))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reports an exception with the state of the CPU, and halts
+pub fn exception_report(frame: &ExceptionFrame) -> ! {
+    panic!("{}", Report::new(frame))
+}
+
+/// Handles an exception, returning only if it was handled and execution can continue
+extern "C" fn exception_entry(frame: &ExceptionFrame) {
+    if frame.vector == NMI as u64 {
+        // Doesn't return if the CPU is asked to stop
+        crate::arch::x86_64::panic_stop::handle_nmi(frame);
+        if crate::arch::x86_64::watchdog::handle_nmi(frame) {
+            return;
+        }
+    }
+    if frame.vector == PAGE_FAULT as u64 {
+        let addr = Cr2::read();
+        crate::trace!(PageFault, addr.as_usize(), frame.error_code, frame.rip);
+        if crate::process::handle_page_fault(addr, frame.error_code) {
+            return;
+        }
+    }
+    exception_report(frame)
+}
+
+/// Saves the general purpose registers, and enters [`exception_entry`] with the frame
+///
+/// The stubs have pushed the vector and an error code, so the CPU aligned stack is still 16 byte
+/// aligned after the 15 registers. If the exception was handled, the registers are restored and
+/// the vector and error code are dropped before returning.
+#[unsafe(naked)]
+unsafe extern "C" fn common_stub() {
+    naked_asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "cld",
+        "call {entry}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "add rsp, 16",
+        "iretq",
+        entry = sym exception_entry,
     );
 }
+
+macro_rules! exception_stubs {
+    ($($vector:ident $($error_code:ident)?),* $(,)?) => {
+        /// Returns the entry stub of an exception vector
+        pub(super) fn stub(vector: u8) -> VirtAddr {
+            $(
+                if vector == $vector {
+                    #[unsafe(naked)]
+                    unsafe extern "C" fn stub() -> ! {
+                        naked_asm!(
+                            exception_stubs!(@error_code $($error_code)?),
+                            "push {vector}",
+                            "jmp {common}",
+                            vector = const $vector,
+                            common = sym common_stub,
+                        );
+                    }
+                    return VirtAddr::new(stub as *const () as usize);
+                }
+            )*
+            panic!("no exception stub for vector {}", vector)
+        }
+    };
+    // The CPU pushes an error code for some exceptions, the stub pushes one for the others
+    (@error_code error_code) => { "" };
+    (@error_code) => { "push 0" };
+}
+
+exception_stubs![
+    DIVIDE_ERROR,
+    DEBUG,
+    NMI,
+    BREAKPOINT,
+    OVERFLOW,
+    BOUND_RANGE,
+    INVALID_OPCODE,
+    DEVICE_NOT_AVAILABLE,
+    DOUBLE_FAULT error_code,
+    INVALID_TSS error_code,
+    SEGMENT_NOT_PRESENT error_code,
+    STACK_SEGMENT error_code,
+    GENERAL_PROTECTION error_code,
+    PAGE_FAULT error_code,
+    X87_FLOATING_POINT,
+    ALIGNMENT_CHECK error_code,
+    MACHINE_CHECK,
+    SIMD_FLOATING_POINT,
+    VIRTUALIZATION,
+    CONTROL_PROTECTION error_code,
+    HV_INJECTION,
+    VMM_COMMUNICATION error_code,
+    SECURITY error_code,
+];
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn exception_error_codes() {
+        let decode = |vector, code| ErrorCode::decode(vector, code).to_string();
+        assert_eq!(decode(DIVIDE_ERROR, 0), "none");
+        assert_eq!(decode(GENERAL_PROTECTION, 0), "0x0 (not caused by a selector)");
+        assert_eq!(
+            decode(GENERAL_PROTECTION, 0x29),
+            "0x29 (GDT index 5, during an external event)"
+        );
+        assert_eq!(decode(SEGMENT_NOT_PRESENT, (0x30 << 3) | 0b010), "0x182 (IDT index 48)");
+        assert_eq!(decode(PAGE_FAULT, 0b0010), "0x2 (kernel write, page not present)");
+        assert_eq!(
+            decode(PAGE_FAULT, 0b10101),
+            "0x15 (user instruction fetch, protection violation)"
+        );
+        assert_eq!(
+            decode(PAGE_FAULT, 0b1001),
+            "0x9 (kernel read, protection violation, reserved bit set)"
+        );
+        assert_eq!(decode(CONTROL_PROTECTION, 3), "0x3 (missing endbranch)");
+        assert_eq!(decode(ALIGNMENT_CHECK, 0), "0x0");
+
+        assert_eq!(describe(PAGE_FAULT), ("PAGE_FAULT", "#PF", ExceptionKind::Fault));
+        assert_eq!(describe(9).0, "RESERVED");
+        assert_eq!(size_of::<ExceptionFrame>(), 22 * size_of::<u64>());
+    }
+}
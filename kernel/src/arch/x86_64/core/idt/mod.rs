@@ -3,7 +3,7 @@
 
 use core::{fmt, marker::PhantomData};
 
-use spin::Mutex;
+use crate::sync::Mutex;
 
 use crate::{
     arch::{
@@ -15,6 +15,10 @@ use crate::{
 };
 
 mod handlers;
+mod stubs;
+mod ud;
+
+pub use handlers::{Backtrace, ExceptionFrame, Report, exception_report};
 
 /// A Basic Handler for a x86-interrupt
 /// Arguments:
@@ -285,16 +289,51 @@ static_assertions::assert_eq_size!(Entry<HandlerFn>, [u8; 16]);
 pub static IDT: Mutex<InterruptDescriptorTable> = Mutex::new(InterruptDescriptorTable::new());
 
 pub fn init() {
-    let mut idt = IDT.lock();
-
-    idt.divide_error.set_handler_fn(handlers::divide_by_zero);
-    idt.breakpoint.set_handler_fn(handlers::breakpoint);
+    let mut guard = IDT.lock();
+    let idt = &mut *guard;
+
+    let exceptions = [
+        (&mut idt.divide_error, handlers::DIVIDE_ERROR),
+        (&mut idt.debug, handlers::DEBUG),
+        (&mut idt.non_maskable_interrupt, handlers::NMI),
+        (&mut idt.breakpoint, handlers::BREAKPOINT),
+        (&mut idt.overflow, handlers::OVERFLOW),
+        (&mut idt.bound_range_exceeded, handlers::BOUND_RANGE),
+        (&mut idt.invalid_opcode, handlers::INVALID_OPCODE),
+        (&mut idt.device_not_available, handlers::DEVICE_NOT_AVAILABLE),
+        (&mut idt.x87_floating_point, handlers::X87_FLOATING_POINT),
+        (&mut idt.simd_floating_point, handlers::SIMD_FLOATING_POINT),
+        (&mut idt.virtualization, handlers::VIRTUALIZATION),
+        (&mut idt.hv_injection_exception, handlers::HV_INJECTION),
+    ];
+    for (entry, vector) in exceptions {
+        entry.set_handler_addr(handlers::stub(vector));
+    }
+    let exceptions = [
+        (&mut idt.invalid_tss, handlers::INVALID_TSS),
+        (&mut idt.segment_not_present, handlers::SEGMENT_NOT_PRESENT),
+        (&mut idt.stack_segment_fault, handlers::STACK_SEGMENT),
+        (&mut idt.general_protection_fault, handlers::GENERAL_PROTECTION),
+        (&mut idt.page_fault, handlers::PAGE_FAULT),
+        (&mut idt.alignment_check, handlers::ALIGNMENT_CHECK),
+        (&mut idt.cp_protection_exception, handlers::CONTROL_PROTECTION),
+        (&mut idt.vmm_communication_exception, handlers::VMM_COMMUNICATION),
+        (&mut idt.security_exception, handlers::SECURITY),
+    ];
+    for (entry, vector) in exceptions {
+        entry.set_handler_addr(handlers::stub(vector));
+    }
+    idt.machine_check
+        .set_handler_addr(handlers::stub(handlers::MACHINE_CHECK));
     unsafe {
         idt.double_fault
-            .set_handler_fn(handlers::double_fault)
+            .set_handler_addr(handlers::stub(handlers::DOUBLE_FAULT))
             .set_stack_index(Selectors::DOUBLE_FAULT_IST_INDEX as u16);
     };
-    idt.page_fault.set_handler_fn(handlers::page_fault);
 
-    idt.load();
+    for (entry, stub) in idt.interrupts.iter_mut().zip(stubs::IRQ_STUBS.iter()) {
+        entry.set_handler_fn(*stub);
+    }
+
+    guard.load();
 }
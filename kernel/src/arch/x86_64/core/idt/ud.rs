@@ -0,0 +1,229 @@
+//! Diagnostics for invalid opcode exceptions
+//!
+//! Most invalid opcodes in the kernel are deliberate traps rather than bad instructions: LLVM emits
+//! `ud2` for `core::intrinsics::abort`, immediate-abort panics and code it considers unreachable,
+//! such as after `unreachable_unchecked` or after a call to a function that never returns, and
+//! `ud1` for sanitizer checks. The bytes around the faulting instruction tell these apart.
+
+use core::fmt;
+
+use crate::{
+    arch::{VirtAddr, registers::control::Cr3},
+    mm::page_table::KernelPageTable,
+    profile::symbols::{Demangled, SymbolTable},
+};
+
+/// The bytes shown on each side of the faulting instruction
+pub const CONTEXT_BYTES: usize = 16;
+
+const OPCODE_UD2: [u8; 2] = [0x0F, 0x0B];
+const OPCODE_UD1: [u8; 2] = [0x0F, 0xB9];
+const OPCODE_UD0: [u8; 2] = [0x0F, 0xFF];
+const PREFIX_ADDR_SIZE: u8 = 0x67;
+/// `call rel32`, which is 5 bytes long
+const OPCODE_CALL_REL32: u8 = 0xE8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdKind {
+    /// A `ud2` trap, directly after a call if `after_call` is set
+    Ud2 {
+        after_call: bool,
+    },
+    /// A `ud1` sanitizer trap, with the check kind LLVM encodes in its displacement
+    Ud1 {
+        check: Option<u8>,
+    },
+    Ud0,
+    /// Not a trap instruction, or the code couldn't be read
+    Invalid,
+}
+
+impl UdKind {
+    /// Classifies the instruction at the start of `code`, with `before` the bytes preceding it
+    pub fn classify(before: &[u8], code: &[u8]) -> Self {
+        let (prefixed, code) = match code.split_first() {
+            Some((&PREFIX_ADDR_SIZE, rest)) => (true, rest),
+            _ => (false, code),
+        };
+        match code.get(..2) {
+            Some(opcode) if opcode == OPCODE_UD2 => {
+                let after_call = before.len() >= 5 && before[before.len() - 5] == OPCODE_CALL_REL32;
+                Self::Ud2 { after_call }
+            }
+            // `ud1l N(%eax), %eax`: ModRM 0x40 with an 8-bit displacement holding the check kind
+            Some(opcode) if opcode == OPCODE_UD1 => Self::Ud1 {
+                check: match code.get(2..4) {
+                    Some(&[0x40, check]) if prefixed => Some(check),
+                    _ => None,
+                },
+            },
+            Some(opcode) if opcode == OPCODE_UD0 => Self::Ud0,
+            _ => Self::Invalid,
+        }
+    }
+
+    /// Returns a likely cause of the exception
+    pub fn cause(&self) -> &'static str {
+        match self {
+            Self::Ud2 { after_call: true } => {
+                "reached unreachable code after a call, a function that never returns has returned"
+            }
+            Self::Ud2 { after_call: false } => {
+                "trap: core::intrinsics::abort, an immediate-abort panic or unreachable code (unreachable_unchecked)"
+            }
+            Self::Ud1 { .. } => "sanitizer trap",
+            Self::Ud0 => "ud0 trap",
+            Self::Invalid => "invalid instruction: an unsupported CPU feature, a jump into data or miscompiled code",
+        }
+    }
+}
+
+impl fmt::Display for UdKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ud2 { .. } => f.write_str("ud2")?,
+            Self::Ud1 { check: Some(check) } => write!(f, "ud1 (check {:#04x})", check)?,
+            Self::Ud1 { check: None } => f.write_str("ud1")?,
+            Self::Ud0 => f.write_str("ud0")?,
+            Self::Invalid => f.write_str("invalid opcode")?,
+        }
+        write!(f, ": {}", self.cause())
+    }
+}
+
+/// Code around a faulting instruction, only including the mapped bytes
+pub struct CodeContext {
+    rip: usize,
+    bytes: [u8; CONTEXT_BYTES * 2],
+    /// The readable range of `bytes`
+    start: usize,
+    end: usize,
+}
+
+impl CodeContext {
+    /// Reads the code around `rip`, stopping at unmapped pages
+    pub fn read(rip: VirtAddr) -> Self {
+        let page_table = KernelPageTable::new(Cr3::addr());
+        let mapped = |offset: usize| {
+            (rip.as_usize().checked_add(offset))
+                .and_then(|addr| addr.checked_sub(CONTEXT_BYTES))
+                .and_then(|addr| VirtAddr::try_new(addr).ok())
+                .is_some_and(|addr| page_table.translate(addr).is_some())
+        };
+        // Pages are mapped as a whole, so the bytes are read outwards from the instruction
+        let mut context = Self {
+            rip: rip.as_usize(),
+            bytes: [0; CONTEXT_BYTES * 2],
+            start: CONTEXT_BYTES,
+            end: CONTEXT_BYTES,
+        };
+        while context.end < context.bytes.len() && mapped(context.end) {
+            context.end += 1;
+        }
+        while context.start > 0 && mapped(context.start - 1) {
+            context.start -= 1;
+        }
+        for offset in context.start..context.end {
+            let addr = context.rip + offset - CONTEXT_BYTES;
+            // SAFETY: The byte is mapped
+            context.bytes[offset] = unsafe { core::ptr::read_volatile(addr as *const u8) };
+        }
+        context
+    }
+
+    pub fn before(&self) -> &[u8] {
+        &self.bytes[self.start..CONTEXT_BYTES]
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.bytes[CONTEXT_BYTES..self.end]
+    }
+}
+
+impl fmt::Display for CodeContext {
+    /// Formats the bytes like a hex dump, with the faulting instruction in angle brackets
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}:", self.rip - self.before().len())?;
+        for byte in self.before() {
+            write!(f, " {:02x}", byte)?;
+        }
+        for (idx, byte) in self.code().iter().enumerate() {
+            match idx {
+                0 => write!(f, " <{:02x}", byte)?,
+                _ => write!(f, " {:02x}", byte)?,
+            }
+        }
+        if !self.code().is_empty() {
+            f.write_str(">")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns where the kernel text is, which the linker script marks
+#[cfg(not(feature = "test"))]
+fn kernel_text() -> core::ops::Range<usize> {
+    unsafe extern "C" {
+        static _kernel_text_start: u8;
+        static _kernel_rodata_start: u8;
+    }
+    (&raw const _kernel_text_start) as usize..(&raw const _kernel_rodata_start) as usize
+}
+
+/// Host builds aren't linked with the linker script
+#[cfg(feature = "test")]
+fn kernel_text() -> core::ops::Range<usize> {
+    0..0
+}
+
+/// Where an address is: the kernel function or module it is in
+///
+/// Formatting neither blocks nor allocates, as it is used from exception and panic context. A
+/// module being loaded or unloaded is missed, and so are kernel functions if the symbols weren't
+/// read at boot, which leaves the offset into the kernel text.
+pub struct Location(pub usize);
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = kernel_text();
+        write!(f, "{:#x}", self.0)?;
+        if let Some(module) = crate::module::try_owner(self.0) {
+            return write!(f, " ({}+{:#x})", module.name(), self.0 - module.start());
+        }
+        if !text.contains(&self.0) {
+            return f.write_str(" (outside of the kernel text)");
+        }
+        match SymbolTable::loaded().and_then(|symbols| symbols.resolve(self.0)) {
+            Some(func) => write!(f, " ({}+{:#x})", Demangled(func.name), self.0 - func.start),
+            None => write!(f, " (kernel text+{:#x})", self.0 - text.start),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ud_classify() {
+        // `call` then `ud2`, as emitted after a call to a function returning `!`
+        let before = [0x48, 0x89, 0xC7, 0xE8, 0x10, 0x20, 0x30, 0x40];
+        assert_eq!(
+            UdKind::classify(&before, &[0x0F, 0x0B, 0xCC]),
+            UdKind::Ud2 { after_call: true }
+        );
+        assert_eq!(
+            UdKind::classify(&[0x5D, 0xC3], &[0x0F, 0x0B]),
+            UdKind::Ud2 { after_call: false }
+        );
+        // `ud1l 0x13(%eax), %eax` from a sanitizer check
+        assert_eq!(
+            UdKind::classify(&[], &[0x67, 0x0F, 0xB9, 0x40, 0x13]),
+            UdKind::Ud1 { check: Some(0x13) }
+        );
+        assert_eq!(UdKind::classify(&[], &[0x0F, 0xFF, 0x00]), UdKind::Ud0);
+        // An AVX-512 instruction, with an EVEX prefix
+        assert_eq!(UdKind::classify(&[], &[0x62, 0xF1, 0x7C, 0x48]), UdKind::Invalid);
+        assert_eq!(UdKind::classify(&[], &[]), UdKind::Invalid);
+    }
+}
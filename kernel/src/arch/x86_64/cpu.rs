@@ -1,132 +1,440 @@
-use core::arch::x86_64::__cpuid;
+//! CPU identification and features
+//!
+//! The CPUID leaves the kernel cares about are read once at boot, and again after a microcode
+//! update, and every [`Feature`] is a bit of one of their registers. Code that has more than one way to do
+//! something picks it with [`has`], such as the paging code leaving out the no-execute bit on CPUs
+//! without NX. [`log_summary`] logs the CPU and its features at boot.
 
-use crate::sync::cell::RacyCell;
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
+    fmt,
+};
 
-// TODO: Replace with a OnceCell or something?
-static CPU_INFO: RacyCell<CpuInfo> = RacyCell::new(CpuInfo::default());
+use alloc::{string::String, vec::Vec};
 
-/// Initializies the CPU Info using the cpuid
+use crate::{kprintln, sync::cell::RacyCell};
+
+static CPU_INFO: RacyCell<Option<CpuInfo>> = RacyCell::new(None);
+
+/// Reads the CPU information, so features can be asked for without running CPUID again
+///
+/// Called again after a microcode update, which can change the features.
+///
+/// # Safety
+/// Must be called on the BSP, while no other CPU is running.
 pub unsafe fn init() {
-    *CPU_INFO.get_mut() = CpuInfo::get();
+    *CPU_INFO.get_mut() = Some(CpuInfo::get());
 }
 
+/// Returns the CPU information read by [`init`]
+///
+/// # Panics
+/// If [`init`] wasn't called yet.
 pub fn cpu_info() -> &'static CpuInfo {
-    CPU_INFO.get()
+    CPU_INFO
+        .get()
+        .as_ref()
+        .expect("cpu: features asked for before cpu::init")
+}
+
+/// Returns whether the CPU has a feature
+///
+/// Before [`init`], CPUID is run every time.
+pub fn has(feature: Feature) -> bool {
+    match CPU_INFO.get() {
+        Some(info) => info.has(feature),
+        None => CpuInfo::get().has(feature),
+    }
+}
+
+/// Returns the APIC ID of the current CPU, as assigned at reset
+pub fn initial_apic_id() -> u32 {
+    let max_leaf = __cpuid(0).eax;
+    // Leaf 0xB reports the full 32 bit x2APIC ID
+    if max_leaf >= 0xB {
+        let topology = __cpuid_count(0xB, 0);
+        if topology.ebx != 0 {
+            return topology.edx;
+        }
+    }
+    __cpuid(1).ebx >> 24
 }
 
+/// A CPUID register that features are read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Word {
+    /// Leaf 1
+    Leaf1Ecx,
+    Leaf1Edx,
+    /// Leaf 7, subleaf 0
+    Leaf7Ebx,
+    Leaf7Ecx,
+    Leaf7Edx,
+    /// Leaf 0xD, subleaf 1
+    XsaveEax,
+    /// Leaf 0x80000001
+    Ext1Ecx,
+    Ext1Edx,
+    /// Leaf 0x80000007
+    Ext7Edx,
+}
+
+const WORDS: usize = 9;
+
+macro_rules! features {
+    ($($(#[$doc:meta])* $variant:ident = ($word:ident, $bit:literal, $name:literal),)*) => {
+        /// A feature a CPU can have, see [`has`]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Feature {
+            $($(#[$doc])* $variant,)*
+        }
+
+        impl Feature {
+            /// Every feature, in the order they are listed in
+            pub const ALL: &[Feature] = &[$(Feature::$variant,)*];
+
+            const fn location(self) -> (Word, u32) {
+                match self {
+                    $(Feature::$variant => (Word::$word, $bit),)*
+                }
+            }
+
+            /// The name of the feature, the one Linux uses in `/proc/cpuinfo`
+            pub const fn name(self) -> &'static str {
+                match self {
+                    $(Feature::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+features! {
+    Fpu = (Leaf1Edx, 0, "fpu"),
+    Tsc = (Leaf1Edx, 4, "tsc"),
+    Msr = (Leaf1Edx, 5, "msr"),
+    Pae = (Leaf1Edx, 6, "pae"),
+    Apic = (Leaf1Edx, 9, "apic"),
+    Pge = (Leaf1Edx, 13, "pge"),
+    Pat = (Leaf1Edx, 16, "pat"),
+    Clflush = (Leaf1Edx, 19, "clflush"),
+    Mmx = (Leaf1Edx, 23, "mmx"),
+    Fxsr = (Leaf1Edx, 24, "fxsr"),
+    Sse = (Leaf1Edx, 25, "sse"),
+    Sse2 = (Leaf1Edx, 26, "sse2"),
+    Htt = (Leaf1Edx, 28, "ht"),
+    Sse3 = (Leaf1Ecx, 0, "pni"),
+    Pclmulqdq = (Leaf1Ecx, 1, "pclmulqdq"),
+    Monitor = (Leaf1Ecx, 3, "monitor"),
+    Vmx = (Leaf1Ecx, 5, "vmx"),
+    Ssse3 = (Leaf1Ecx, 9, "ssse3"),
+    Fma = (Leaf1Ecx, 12, "fma"),
+    Cx16 = (Leaf1Ecx, 13, "cx16"),
+    Pcid = (Leaf1Ecx, 17, "pcid"),
+    Sse4_1 = (Leaf1Ecx, 19, "sse4_1"),
+    Sse4_2 = (Leaf1Ecx, 20, "sse4_2"),
+    X2Apic = (Leaf1Ecx, 21, "x2apic"),
+    Movbe = (Leaf1Ecx, 22, "movbe"),
+    Popcnt = (Leaf1Ecx, 23, "popcnt"),
+    /// The local APIC timer has a TSC deadline mode
+    TscDeadline = (Leaf1Ecx, 24, "tsc_deadline_timer"),
+    Aes = (Leaf1Ecx, 25, "aes"),
+    Xsave = (Leaf1Ecx, 26, "xsave"),
+    /// The OS enabled XSAVE, so XGETBV works
+    Osxsave = (Leaf1Ecx, 27, "osxsave"),
+    Avx = (Leaf1Ecx, 28, "avx"),
+    F16c = (Leaf1Ecx, 29, "f16c"),
+    Rdrand = (Leaf1Ecx, 30, "rdrand"),
+    Hypervisor = (Leaf1Ecx, 31, "hypervisor"),
+    Fsgsbase = (Leaf7Ebx, 0, "fsgsbase"),
+    /// The `IA32_TSC_ADJUST` MSR
+    TscAdjust = (Leaf7Ebx, 1, "tsc_adjust"),
+    Bmi1 = (Leaf7Ebx, 3, "bmi1"),
+    Avx2 = (Leaf7Ebx, 5, "avx2"),
+    Smep = (Leaf7Ebx, 7, "smep"),
+    Bmi2 = (Leaf7Ebx, 8, "bmi2"),
+    Erms = (Leaf7Ebx, 9, "erms"),
+    Invpcid = (Leaf7Ebx, 10, "invpcid"),
+    Avx512f = (Leaf7Ebx, 16, "avx512f"),
+    Rdseed = (Leaf7Ebx, 18, "rdseed"),
+    Smap = (Leaf7Ebx, 20, "smap"),
+    Clflushopt = (Leaf7Ebx, 23, "clflushopt"),
+    Umip = (Leaf7Ecx, 2, "umip"),
+    Pku = (Leaf7Ecx, 3, "pku"),
+    /// 5-level paging
+    La57 = (Leaf7Ecx, 16, "la57"),
+    Fsrm = (Leaf7Edx, 4, "fsrm"),
+    Xsaveopt = (XsaveEax, 0, "xsaveopt"),
+    Xsavec = (XsaveEax, 1, "xsavec"),
+    Xsaves = (XsaveEax, 3, "xsaves"),
+    Lahf = (Ext1Ecx, 0, "lahf_lm"),
+    Syscall = (Ext1Edx, 11, "syscall"),
+    /// The no-execute bit of page table entries
+    Nx = (Ext1Edx, 20, "nx"),
+    /// 1GiB pages
+    Page1Gb = (Ext1Edx, 26, "pdpe1gb"),
+    Rdtscp = (Ext1Edx, 27, "rdtscp"),
+    LongMode = (Ext1Edx, 29, "lm"),
+    /// The TSC runs at a constant rate across P-, C- and T-states
+    InvariantTsc = (Ext7Edx, 8, "constant_tsc"),
+}
+
+/// The widest SIMD instructions the CPU has
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SimdLevel {
+    None,
+    Sse,
+    Sse2,
+    Sse3,
+    Ssse3,
+    Sse4_1,
+    Sse4_2,
+    Avx,
+    Avx2,
+    Avx512,
+}
+
+impl fmt::Display for SimdLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Sse => "SSE",
+            Self::Sse2 => "SSE2",
+            Self::Sse3 => "SSE3",
+            Self::Ssse3 => "SSSE3",
+            Self::Sse4_1 => "SSE4.1",
+            Self::Sse4_2 => "SSE4.2",
+            Self::Avx => "AVX",
+            Self::Avx2 => "AVX2",
+            Self::Avx512 => "AVX-512",
+        })
+    }
+}
+
+/// The raw CPUID values that the CPU information is decoded from
 #[derive(Debug, Clone)]
-pub struct CpuInfo {
-    features: CpuFeatures,
-    extended_feat: ExtendedCpuFeatures,
+struct Leaves {
+    vendor: [u8; 12],
+    /// Leaf 1 EAX
+    signature: u32,
+    words: [u32; WORDS],
+    /// Leaf 0xD, subleaf 0: the XCR0 bits supported, and the size of the area they need
+    xsave_components: u64,
+    xsave_size: u32,
+    brand: [u8; 48],
 }
 
-impl const Default for CpuInfo {
+impl Default for Leaves {
     fn default() -> Self {
         Self {
-            features: CpuFeatures::empty(),
-            extended_feat: ExtendedCpuFeatures::empty(),
+            vendor: [0; 12],
+            signature: 0,
+            words: [0; WORDS],
+            xsave_components: 0,
+            xsave_size: 0,
+            brand: [0; 48],
         }
     }
 }
 
+impl Leaves {
+    fn read() -> Self {
+        let mut leaves = Self::default();
+        let res = __cpuid(0);
+        let max_leaf = res.eax;
+        leaves.vendor[..4].copy_from_slice(&res.ebx.to_le_bytes());
+        leaves.vendor[4..8].copy_from_slice(&res.edx.to_le_bytes());
+        leaves.vendor[8..].copy_from_slice(&res.ecx.to_le_bytes());
+
+        let res = __cpuid(1);
+        leaves.signature = res.eax;
+        leaves.words[Word::Leaf1Ecx as usize] = res.ecx;
+        leaves.words[Word::Leaf1Edx as usize] = res.edx;
+        if max_leaf >= 7 {
+            let res = __cpuid_count(7, 0);
+            leaves.words[Word::Leaf7Ebx as usize] = res.ebx;
+            leaves.words[Word::Leaf7Ecx as usize] = res.ecx;
+            leaves.words[Word::Leaf7Edx as usize] = res.edx;
+        }
+        if max_leaf >= 0xD && leaves.words[Word::Leaf1Ecx as usize] & (1 << 26) != 0 {
+            let res = __cpuid_count(0xD, 0);
+            leaves.xsave_components = res.eax as u64 | (res.edx as u64) << 32;
+            leaves.xsave_size = res.ecx;
+            leaves.words[Word::XsaveEax as usize] = __cpuid_count(0xD, 1).eax;
+        }
+
+        let max_extended = __cpuid(0x8000_0000).eax;
+        if max_extended >= 0x8000_0001 {
+            let res = __cpuid(0x8000_0001);
+            leaves.words[Word::Ext1Ecx as usize] = res.ecx;
+            leaves.words[Word::Ext1Edx as usize] = res.edx;
+        }
+        if max_extended >= 0x8000_0004 {
+            for (idx, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+                let res = __cpuid(leaf);
+                for (reg, value) in [res.eax, res.ebx, res.ecx, res.edx].into_iter().enumerate() {
+                    let offset = idx * 16 + reg * 4;
+                    leaves.brand[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+        if max_extended >= 0x8000_0007 {
+            leaves.words[Word::Ext7Edx as usize] = __cpuid(0x8000_0007).edx;
+        }
+        leaves
+    }
+}
+
+/// What the CPU is, and the features it has
+#[derive(Debug, Clone)]
+pub struct CpuInfo {
+    leaves: Leaves,
+}
+
 impl CpuInfo {
+    /// Reads the CPU information of the current CPU
     pub fn get() -> Self {
-        Self {
-            features: CpuFeatures::get(),
-            extended_feat: ExtendedCpuFeatures::get(),
+        Self { leaves: Leaves::read() }
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        let (word, bit) = feature.location();
+        self.leaves.words[word as usize] & (1 << bit) != 0
+    }
+
+    /// Returns the features the CPU has
+    pub fn features(&self) -> impl Iterator<Item = Feature> + '_ {
+        Feature::ALL.iter().copied().filter(|feature| self.has(*feature))
+    }
+
+    /// Returns the vendor string, such as `GenuineIntel` or `AuthenticAMD`
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.leaves.vendor).unwrap_or("unknown")
+    }
+
+    /// Returns the brand string, such as `Intel(R) Core(TM) i7-8700 CPU @ 3.20GHz`
+    pub fn brand(&self) -> &str {
+        let brand = &self.leaves.brand;
+        let len = brand.iter().position(|byte| *byte == 0).unwrap_or(brand.len());
+        core::str::from_utf8(&brand[..len]).unwrap_or("").trim()
+    }
+
+    /// Returns the family, model and stepping, with the extended family and model folded in
+    pub fn signature(&self) -> (u32, u32, u32) {
+        let signature = self.leaves.signature;
+        let base_family = (signature >> 8) & 0xF;
+        let mut family = base_family;
+        let mut model = (signature >> 4) & 0xF;
+        if base_family == 0xF {
+            family += (signature >> 20) & 0xFF;
         }
+        if base_family == 0x6 || base_family == 0xF {
+            model |= ((signature >> 16) & 0xF) << 4;
+        }
+        (family, model, signature & 0xF)
+    }
+
+    pub fn simd_level(&self) -> SimdLevel {
+        [
+            (Feature::Avx512f, SimdLevel::Avx512),
+            (Feature::Avx2, SimdLevel::Avx2),
+            (Feature::Avx, SimdLevel::Avx),
+            (Feature::Sse4_2, SimdLevel::Sse4_2),
+            (Feature::Sse4_1, SimdLevel::Sse4_1),
+            (Feature::Ssse3, SimdLevel::Ssse3),
+            (Feature::Sse3, SimdLevel::Sse3),
+            (Feature::Sse2, SimdLevel::Sse2),
+            (Feature::Sse, SimdLevel::Sse),
+        ]
+        .into_iter()
+        .find(|(feature, _)| self.has(*feature))
+        .map_or(SimdLevel::None, |(_, level)| level)
+    }
+
+    /// Returns the XCR0 bits the CPU supports, 0 without XSAVE
+    pub fn xsave_components(&self) -> u64 {
+        self.leaves.xsave_components
+    }
+
+    /// Returns the size of an XSAVE area holding every supported component, 0 without XSAVE
+    pub fn xsave_size(&self) -> usize {
+        self.leaves.xsave_size as usize
     }
 }
 
-bitflags::bitflags! {
-    /// CPU Features that the CPU supports
-    #[derive(Debug, Clone, Copy)]
-    pub struct CpuFeatures: u64 {
-        /// Supports SSE3 (bit 0 on ECX)
-        const SSE3         = 1 << 0;
-        /// Supports PCLMUL (bit 1 on ECX)
-        const PCLMUL       = 1 << 1;
-        /// Supports DTES64 (bit 2 on ECX)
-        const DTES64       = 1 << 2;
-        const MONITOR      = 1 << 3;
-        const DS_CPL       = 1 << 4;
-        const VMX          = 1 << 5;
-        const SMX          = 1 << 6;
-        const EST          = 1 << 7;
-        const TM2          = 1 << 8;
-        const SSSE3        = 1 << 9;
-        const CID          = 1 << 10;
-        const SDBG         = 1 << 11;
-        const FMA          = 1 << 12;
-        const CX16         = 1 << 13;
-        const XTPR         = 1 << 14;
-        const PDCM         = 1 << 15;
-        const PCID         = 1 << 17;
-        const DCA          = 1 << 18;
-        const SSE4_1       = 1 << 19;
-        const SSE4_2       = 1 << 20;
-        const X2APIC       = 1 << 21;
-        const MOVBE        = 1 << 22;
-        const POPCNT       = 1 << 23;
-        const TSC_1          = 1 << 24;
-        const AES          = 1 << 25;
-        const XSAVE        = 1 << 26;
-        const OSXSAVE      = 1 << 27;
-        const AVX          = 1 << 28;
-        const F16C         = 1 << 29;
-        const RDRAND       = 1 << 30;
-        const HYPERVISOR   = 1 << 31;
-
-        /// The CPU has a FPU (bit 0 on EDX)
-        const FPU          = 1 << 32;
-        const VME          = 1 << 33;
-        const DE           = 1 << 34;
-        const PSE          = 1 << 35;
-        const TSC_2          = 1 << 36;
-        const MSR          = 1 << 37;
-        const PAE          = 1 << 38;
-        const MCE          = 1 << 39;
-        const CX8          = 1 << 40;
-        const APIC         = 1 << 41;
-        const SEP          = 1 << 43;
-        const MTRR         = 1 << 44;
-        const PGE          = 1 << 45;
-        const MCA          = 1 << 46;
-        const CMOV         = 1 << 47;
-        const PAT          = 1 << 48;
-        const PSE36        = 1 << 49;
-        const PSN          = 1 << 50;
-        const CLFLUSH      = 1 << 51;
-        const DS           = 1 << 53;
-        const ACPI         = 1 << 54;
-        const MMX          = 1 << 55;
-        const FXSR         = 1 << 56;
-        const SSE          = 1 << 57;
-        const SSE2         = 1 << 58;
-        const SS           = 1 << 59;
-        const HTT          = 1 << 60;
-        const TM           = 1 << 61;
-        const IA64         = 1 << 62;
-        const PBE          = 1 << 63;
-    }
-
-    #[derive(Debug, Clone, Copy)]
-    pub struct ExtendedCpuFeatures: u32 {
-        const PAGE_1GB = 1 << 26;
-    }
-}
-
-impl CpuFeatures {
-    fn get() -> Self {
-        let res = unsafe { __cpuid(1) };
-        // FIXME: We cannot trust EDX for some old CPUs
-        Self::from_bits_truncate((res.ecx as u64) | ((res.edx as u64) << 32))
-    }
-}
-
-impl ExtendedCpuFeatures {
-    fn get() -> Self {
-        let res = unsafe { __cpuid(0x80000001) };
-        Self::from_bits_truncate(res.edx)
+/// Logs the CPU and its features
+pub fn log_summary() {
+    let info = cpu_info();
+    let (family, model, stepping) = info.signature();
+    kprintln!(
+        Info,
+        "cpu: {} {} (family {:#x}, model {:#x}, stepping {})",
+        info.vendor(),
+        info.brand(),
+        family,
+        model,
+        stepping
+    );
+    kprintln!(
+        Info,
+        "cpu: SIMD up to {}, XSAVE area of {} bytes for components {:#x}",
+        info.simd_level(),
+        info.xsave_size(),
+        info.xsave_components()
+    );
+    let names: Vec<&str> = info.features().map(Feature::name).collect();
+    // Wrapped, the whole list doesn't fit a line
+    let mut line = String::new();
+    for name in names {
+        if line.len() + name.len() > 80 {
+            kprintln!(Info, "cpu: flags: {}", line);
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(name);
+    }
+    if !line.is_empty() {
+        kprintln!(Info, "cpu: flags: {}", line);
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_decode_features() {
+        let mut leaves = Leaves {
+            vendor: *b"GenuineIntel",
+            // Family 6, model 0x9E, stepping 10
+            signature: 0x000906EA,
+            ..Default::default()
+        };
+        leaves.words[Word::Leaf1Edx as usize] = 1 << 25 | 1 << 26;
+        leaves.words[Word::Leaf1Ecx as usize] = 1 << 28;
+        leaves.words[Word::Ext1Edx as usize] = 1 << 20;
+        leaves.brand[..5].copy_from_slice(b" Fake");
+        let info = CpuInfo { leaves };
+
+        assert!(info.has(Feature::Nx) && info.has(Feature::Avx) && info.has(Feature::Sse2));
+        assert!(!info.has(Feature::Page1Gb) && !info.has(Feature::Avx2));
+        assert_eq!(
+            info.features().map(Feature::name).collect::<Vec<_>>(),
+            ["sse", "sse2", "avx", "nx"]
+        );
+        assert_eq!(info.simd_level(), SimdLevel::Avx);
+        assert_eq!(info.signature(), (6, 0x9E, 10));
+        assert_eq!((info.vendor(), info.brand()), ("GenuineIntel", "Fake"));
+
+        // Every feature is its own bit
+        for (idx, feature) in Feature::ALL.iter().enumerate() {
+            assert!(
+                Feature::ALL[..idx]
+                    .iter()
+                    .all(|other| other.location() != feature.location())
+            );
+        }
     }
 }
@@ -0,0 +1,303 @@
+//! x87, SSE and AVX state
+//!
+//! The kernel is built with soft-float, so the compiler never touches the x87 or SIMD registers
+//! on its own, and they hold the state of the ring 3 task running on the CPU, even during syscalls
+//! and interrupts. [`init`] enables SSE, and AVX and AVX-512 through XCR0 when the CPU has them.
+//! Every task keeps its registers in an [`FpuState`], saved with XSAVE, or FXSAVE on CPUs without
+//! it, when it is switched away from. Kernel code that wants SIMD anyway has to wrap it in
+//! [`kernel_fpu_begin`], which saves the registers of the task first and restores them after.
+
+use core::{
+    alloc::Layout,
+    arch::{asm, x86_64::__cpuid_count},
+    fmt,
+    marker::PhantomData,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+    arch::{
+        instructions::interrupts,
+        registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        x86_64::cpu::{self, Feature},
+    },
+    percpu::{MAX_CPUS, PerCpu},
+    sync::cell::RacyCell,
+};
+
+/// The components of XCR0, which XSAVE and XRSTOR work on
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+/// The opmask registers, the upper halves of ZMM0-15, and ZMM16-31, only enabled together
+const XCR0_AVX512: u64 = 0b111 << 5;
+
+/// The size of the FXSAVE area, and of the legacy region at the start of the XSAVE area
+const FXSAVE_SIZE: usize = 512;
+/// XSAVE needs 64 byte alignment, FXSAVE only 16
+const AREA_ALIGN: usize = 64;
+/// Offsets of the control words in the legacy region
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+/// The x87 control word after FNINIT: every exception masked, extended precision
+const FCW_DEFAULT: u16 = 0x037F;
+/// Every SIMD exception masked, round to nearest
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+static XCR0: AtomicU64 = AtomicU64::new(XCR0_X87 | XCR0_SSE);
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+
+/// Where [`kernel_fpu_begin`] saves the registers of the interrupted task
+static KERNEL_AREAS: PerCpu<RacyCell<Option<FpuState>>> = PerCpu::new([const { RacyCell::new(None) }; MAX_CPUS]);
+static KERNEL_FPU_ACTIVE: PerCpu<AtomicBool> = PerCpu::new([const { AtomicBool::new(false) }; MAX_CPUS]);
+
+/// Enables the x87 unit, SSE, and the AVX state the CPU supports, and resets the registers
+///
+/// # Safety
+/// Must be called on every CPU during bring-up, after [`cpu::init`], before any ring 3 code runs
+/// on it.
+pub unsafe fn init() {
+    let info = cpu::cpu_info();
+    let mut cr0 = Cr0::read();
+    cr0.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+    cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+    // Every x86_64 CPU has SSE2 and FXSAVE
+    let mut cr4 = Cr4::read() | Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT;
+    if info.has(Feature::Xsave) {
+        cr4 |= Cr4Flags::OSXSAVE;
+    }
+    unsafe {
+        Cr0::write(cr0);
+        Cr4::write(cr4);
+    }
+
+    if info.has(Feature::Xsave) {
+        let mut xcr0 = XCR0_X87 | XCR0_SSE;
+        if info.has(Feature::Avx) {
+            xcr0 |= XCR0_AVX;
+        }
+        if info.has(Feature::Avx512f) {
+            xcr0 |= XCR0_AVX512;
+        }
+        xcr0 &= info.xsave_components();
+        unsafe { xsetbv(xcr0) };
+        XCR0.store(xcr0, Ordering::Relaxed);
+        // EBX is the size for the components enabled in XCR0, unlike the maximum in the registry
+        AREA_SIZE.store(__cpuid_count(0xD, 0).ebx as usize, Ordering::Relaxed);
+        USE_XSAVE.store(true, Ordering::Relaxed);
+    }
+    unsafe { reset() };
+}
+
+/// Allocates the area [`kernel_fpu_begin`] saves to on a CPU
+///
+/// # Safety
+/// Must be called once for every CPU while it is brought up, after [`init`].
+pub unsafe fn init_kernel_area(cpu_id: usize) {
+    *KERNEL_AREAS.get_for(cpu_id).get_mut() = Some(FpuState::new());
+}
+
+/// Returns the components enabled in XCR0, only x87 and SSE without XSAVE
+pub fn enabled_components() -> u64 {
+    XCR0.load(Ordering::Relaxed)
+}
+
+/// Returns the size of the area the registers are saved to
+pub fn area_size() -> usize {
+    AREA_SIZE.load(Ordering::Relaxed)
+}
+
+/// Returns MXCSR, which says which SIMD exception was raised
+pub fn mxcsr() -> u32 {
+    let mut mxcsr = 0u32;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack, preserves_flags)) };
+    mxcsr
+}
+
+unsafe fn xsetbv(value: u64) {
+    unsafe {
+        asm!(
+            "xsetbv",
+            in("ecx") 0,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nomem, nostack, preserves_flags)
+        )
+    };
+}
+
+/// Puts the x87 and SIMD registers in their default state
+unsafe fn reset() {
+    unsafe {
+        asm!("fninit", options(nomem, nostack, preserves_flags));
+        asm!("ldmxcsr [{}]", in(reg) &MXCSR_DEFAULT, options(readonly, nostack, preserves_flags));
+    }
+}
+
+/// The x87, SSE and AVX registers of a task
+pub struct FpuState {
+    area: NonNull<u8>,
+    layout: Layout,
+}
+
+// SAFETY: The area is owned, like a `Box`
+unsafe impl Send for FpuState {}
+unsafe impl Sync for FpuState {}
+
+impl FpuState {
+    /// Creates the state a task starts with, every register cleared and every exception masked
+    pub fn new() -> Self {
+        let layout = Layout::from_size_align(area_size(), AREA_ALIGN).unwrap();
+        // SAFETY: The layout isn't empty
+        let Some(area) = NonNull::new(unsafe { alloc::alloc::alloc_zeroed(layout) }) else {
+            alloc::alloc::handle_alloc_error(layout);
+        };
+        // With XSAVE the zeroed header marks every component as in its initial state, only MXCSR
+        // is always loaded from the legacy region
+        unsafe {
+            area.add(FCW_OFFSET).cast::<u16>().write(FCW_DEFAULT);
+            area.add(MXCSR_OFFSET).cast::<u32>().write(MXCSR_DEFAULT);
+        }
+        Self { area, layout }
+    }
+
+    /// Saves the registers of the current CPU
+    ///
+    /// # Safety
+    /// [`init`] must have been called on the current CPU.
+    pub unsafe fn save(&mut self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+            } else {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+            }
+        }
+    }
+
+    /// Loads the registers of the current CPU
+    ///
+    /// # Safety
+    /// [`init`] must have been called on the current CPU, and the registers it held are lost.
+    pub unsafe fn restore(&self) {
+        let area = self.area.as_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(readonly, nostack));
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) area, options(readonly, nostack));
+            }
+        }
+    }
+
+    /// Returns the saved MXCSR
+    pub fn mxcsr(&self) -> u32 {
+        unsafe { self.area.add(MXCSR_OFFSET).cast::<u32>().read() }
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        unsafe { alloc::alloc::dealloc(self.area.as_ptr(), self.layout) };
+    }
+}
+
+impl fmt::Debug for FpuState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FpuState")
+            .field("size", &self.layout.size())
+            .field("mxcsr", &format_args!("{:#x}", self.mxcsr()))
+            .finish()
+    }
+}
+
+/// Kernel use of the x87 and SIMD registers, which ends when it is dropped
+///
+/// Interrupts stay disabled meanwhile, so the registers can't be used by anything else on the CPU.
+pub struct KernelFpu {
+    interrupts: bool,
+    /// Bound to the CPU it was started on
+    _cpu: PhantomData<*const ()>,
+}
+
+/// Lets the kernel use the x87 and SIMD registers, outside of NMIs, until [`kernel_fpu_end`]
+///
+/// The registers of the current task are saved, and put back at the end, and the kernel starts
+/// with them in their default state. The kernel is built with soft-float, so only functions with
+/// `#[target_feature(enable = "...")]` or inline assembly make use of them.
+///
+/// Disabling interrupts doesn't hold off NMIs, so an NMI handler could land inside of a section,
+/// and panic below.
+///
+/// # Panics
+/// If the CPU is already in a kernel FPU section, they don't nest, or its save area isn't
+/// allocated yet, see [`init_kernel_area`].
+pub fn kernel_fpu_begin() -> KernelFpu {
+    let enabled = interrupts::are_enabled();
+    unsafe { interrupts::disable() };
+    assert!(
+        !KERNEL_FPU_ACTIVE.get().swap(true, Ordering::Acquire),
+        "fpu: kernel_fpu_begin called inside of a kernel FPU section"
+    );
+    // Interrupts are disabled, and an NMI that tries to use the area panics on the flag above
+    let area = KERNEL_AREAS
+        .get()
+        .get_mut()
+        .as_mut()
+        .expect("fpu: kernel_fpu_begin called before fpu::init_kernel_area");
+    unsafe {
+        area.save();
+        reset();
+    }
+    KernelFpu {
+        interrupts: enabled,
+        _cpu: PhantomData,
+    }
+}
+
+/// Ends the kernel FPU section, the same as dropping it
+pub fn kernel_fpu_end(section: KernelFpu) {
+    drop(section);
+}
+
+impl Drop for KernelFpu {
+    fn drop(&mut self) {
+        if let Some(area) = KERNEL_AREAS.get().get() {
+            unsafe { area.restore() };
+        }
+        KERNEL_FPU_ACTIVE.get().store(false, Ordering::Release);
+        if self.interrupts {
+            unsafe { interrupts::enable() };
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fpu_state_save_restore() {
+        let mut saved = FpuState::new();
+        assert_eq!(saved.mxcsr(), MXCSR_DEFAULT);
+        unsafe { saved.save() };
+
+        // Round toward zero
+        let state = FpuState::new();
+        unsafe { state.area.add(MXCSR_OFFSET).cast::<u32>().write(MXCSR_DEFAULT | 0x6000) };
+        unsafe { state.restore() };
+        assert_eq!(mxcsr(), MXCSR_DEFAULT | 0x6000);
+        unsafe { saved.restore() };
+        assert_eq!(mxcsr(), saved.mxcsr());
+    }
+}
@@ -0,0 +1,320 @@
+//! High Precision Event Timer
+//!
+//! The main counter is registered as a clock source, and the first comparator that supports
+//! FSB (MSI) delivery is registered as a clock event device.
+
+use core::fmt;
+
+use crate::{
+    acpi::{self, GenericAddress, hpet::HpetTable},
+    arch::{PhysAddr, VirtAddr, x86_64::apic},
+    dev::resource::{self, ResourceKind},
+    irq::{self, IrqError},
+    mm::mmio::{self, MmioSpaceExhausted},
+    sync::cell::RacyCell,
+    time::{self, ClockEventDevice, ClockEventFeatures, ClockSource},
+};
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_INTERRUPT_STATUS: usize = 0x020;
+const REG_MAIN_COUNTER: usize = 0x0F0;
+
+const fn timer_config(n: u8) -> usize {
+    0x100 + 0x20 * n as usize
+}
+
+const fn timer_comparator(n: u8) -> usize {
+    0x108 + 0x20 * n as usize
+}
+
+const fn timer_fsb_route(n: u8) -> usize {
+    0x110 + 0x20 * n as usize
+}
+
+/// Femtoseconds in a nanosecond
+const FS_PER_NS: u64 = 1_000_000;
+/// The maximum period allowed by the specification (100ns)
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    struct GeneralConfig: u64 {
+        const ENABLE = 1 << 0;
+        const LEGACY_REPLACEMENT = 1 << 1;
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct TimerConfig: u64 {
+        const LEVEL_TRIGGERED = 1 << 1;
+        const INTERRUPT_ENABLE = 1 << 2;
+        const PERIODIC = 1 << 3;
+        const PERIODIC_CAPABLE = 1 << 4;
+        const SIZE_64BIT = 1 << 5;
+        const SET_ACCUMULATOR = 1 << 6;
+        const FORCE_32BIT = 1 << 8;
+        const FSB_ENABLE = 1 << 14;
+        const FSB_CAPABLE = 1 << 15;
+        const _ = !0;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HpetError {
+    /// The firmware doesn't describe an HPET
+    NotPresent,
+    /// The HPET is not memory mapped, or reports an invalid period
+    Unsupported,
+    Mmio(MmioSpaceExhausted),
+    Irq(IrqError),
+}
+
+impl fmt::Display for HpetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotPresent => f.write_str("no HPET table found"),
+            Self::Unsupported => f.write_str("HPET is not supported"),
+            Self::Mmio(err) => write!(f, "failed to map HPET: {}", err),
+            Self::Irq(err) => write!(f, "failed to request HPET interrupt: {}", err),
+        }
+    }
+}
+
+impl core::error::Error for HpetError {}
+
+#[derive(Debug)]
+pub struct Hpet {
+    base: VirtAddr,
+    /// The period of the main counter in femtoseconds
+    period_fs: u64,
+    comparators: u8,
+    /// The comparator used as a clock event device, if any
+    event_timer: Option<u8>,
+}
+
+impl Hpet {
+    fn read(&self, reg: usize) -> u64 {
+        unsafe { mmio::read(self.base, reg) }
+    }
+
+    fn write(&self, reg: usize, value: u64) {
+        unsafe { mmio::write(self.base, reg, value) }
+    }
+
+    /// Returns the frequency of the main counter in Hz
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    pub fn comparators(&self) -> u8 {
+        self.comparators
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(REG_MAIN_COUNTER)
+    }
+
+    pub fn counter_ns(&self) -> u64 {
+        ((self.counter() as u128 * self.period_fs as u128) / FS_PER_NS as u128) as u64
+    }
+
+    fn ns_to_ticks(&self, ns: u64) -> u64 {
+        ((ns as u128 * FS_PER_NS as u128) / self.period_fs as u128).max(1) as u64
+    }
+
+    fn set_enabled(&self, enabled: bool) {
+        let mut config = GeneralConfig::from_bits_retain(self.read(REG_CONFIG));
+        config.set(GeneralConfig::ENABLE, enabled);
+        config.remove(GeneralConfig::LEGACY_REPLACEMENT);
+        self.write(REG_CONFIG, config.bits());
+    }
+
+    pub fn timer_config(&self, n: u8) -> TimerConfig {
+        TimerConfig::from_bits_retain(self.read(timer_config(n)))
+    }
+
+    fn set_timer_config(&self, n: u8, config: TimerConfig) {
+        self.write(timer_config(n), config.bits());
+    }
+
+    /// Routes the interrupts of comparator `n` as an MSI delivering `vector` to the current CPU
+    pub fn route_fsb(&self, n: u8, vector: u8) -> bool {
+        let Some(lapic) = apic::local_apic() else {
+            return false;
+        };
+        if !self.timer_config(n).contains(TimerConfig::FSB_CAPABLE) {
+            return false;
+        }
+        let (address, data) = lapic.msi_message(vector);
+        self.write(timer_fsb_route(n), ((address as u64) << 32) | data as u64);
+        let config = self.timer_config(n) | TimerConfig::FSB_ENABLE;
+        self.set_timer_config(n, config);
+        true
+    }
+
+    /// Programs comparator `n` to fire every `period_ns`
+    pub fn set_periodic(&self, n: u8, period_ns: u64) -> bool {
+        let config = self.timer_config(n);
+        if !config.contains(TimerConfig::PERIODIC_CAPABLE) {
+            return false;
+        }
+        let ticks = self.ns_to_ticks(period_ns);
+        // The counter must be stopped so the accumulator and comparator are set consistently
+        self.set_enabled(false);
+        self.set_timer_config(
+            n,
+            (config - TimerConfig::LEVEL_TRIGGERED)
+                | TimerConfig::INTERRUPT_ENABLE
+                | TimerConfig::PERIODIC
+                | TimerConfig::SET_ACCUMULATOR,
+        );
+        self.write(timer_comparator(n), self.counter() + ticks);
+        // With SET_ACCUMULATOR, the second write sets the period
+        self.write(timer_comparator(n), ticks);
+        self.set_enabled(true);
+        true
+    }
+
+    /// Programs comparator `n` to fire once after `delta_ns`
+    pub fn set_oneshot(&self, n: u8, delta_ns: u64) {
+        let config = self.timer_config(n);
+        self.set_timer_config(
+            n,
+            (config - TimerConfig::LEVEL_TRIGGERED - TimerConfig::PERIODIC) | TimerConfig::INTERRUPT_ENABLE,
+        );
+        self.write(timer_comparator(n), self.counter() + self.ns_to_ticks(delta_ns));
+    }
+
+    /// Stops comparator `n` from raising interrupts
+    pub fn stop(&self, n: u8) {
+        let config = self.timer_config(n);
+        self.set_timer_config(n, config - TimerConfig::INTERRUPT_ENABLE - TimerConfig::PERIODIC);
+    }
+
+    /// Clears the interrupt status of comparator `n`
+    pub fn ack(&self, n: u8) {
+        self.write(REG_INTERRUPT_STATUS, 1 << n);
+    }
+}
+
+static HPET: RacyCell<Option<Hpet>> = RacyCell::new(None);
+
+/// Returns the HPET, if it has been initialized
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.get().as_ref()
+}
+
+fn read_ns() -> u64 {
+    hpet().map(Hpet::counter_ns).unwrap_or(0)
+}
+
+static HPET_CLOCKSOURCE: ClockSource = ClockSource {
+    name: "hpet",
+    rating: 250,
+    read_ns,
+};
+
+/// Returns the HPET clock source
+pub fn clocksource() -> &'static ClockSource {
+    &HPET_CLOCKSOURCE
+}
+
+fn event_timer() -> Option<(&'static Hpet, u8)> {
+    let hpet = hpet()?;
+    Some((hpet, hpet.event_timer?))
+}
+
+fn event_set_periodic(period_ns: u64) {
+    if let Some((hpet, n)) = event_timer() {
+        hpet.set_periodic(n, period_ns);
+    }
+}
+
+fn event_set_oneshot(delta_ns: u64) {
+    if let Some((hpet, n)) = event_timer() {
+        hpet.set_oneshot(n, delta_ns);
+    }
+}
+
+fn event_shutdown() {
+    if let Some((hpet, n)) = event_timer() {
+        hpet.stop(n);
+    }
+}
+
+fn event_irq(_vector: u8, comparator: usize) {
+    if let Some(hpet) = hpet() {
+        hpet.ack(comparator as u8);
+    }
+    time::tick::handle();
+}
+
+static HPET_CLOCK_EVENT: RacyCell<ClockEventDevice> = RacyCell::new(ClockEventDevice {
+    name: "hpet",
+    rating: 50,
+    features: ClockEventFeatures::ONESHOT,
+    vector: 0,
+    set_periodic: event_set_periodic,
+    set_oneshot: event_set_oneshot,
+    shutdown: event_shutdown,
+});
+
+/// Finds the HPET in the ACPI tables, starts its main counter and registers it with the timekeeping code
+///
+/// # Safety
+/// Must only be called once, after ACPI and the local APIC are initialized.
+pub unsafe fn init() -> Result<(), HpetError> {
+    let table = acpi::find_table::<HpetTable>().ok_or(HpetError::NotPresent)?;
+    let address = table.base_address;
+    if address.address_space != GenericAddress::SPACE_SYSTEM_MEMORY {
+        return Err(HpetError::Unsupported);
+    }
+
+    let region = unsafe { mmio::map(PhysAddr::new(address.address as usize), 0x400) }.map_err(HpetError::Mmio)?;
+    resource::reserve(ResourceKind::Mmio, address.address..=address.address + 0x3FF, "hpet");
+    let mut hpet = Hpet {
+        base: region.virt(),
+        period_fs: 0,
+        comparators: 0,
+        event_timer: None,
+    };
+    let caps = hpet.read(REG_CAPABILITIES);
+    hpet.period_fs = caps >> 32;
+    hpet.comparators = ((caps >> 8) & 0x1F) as u8 + 1;
+    if hpet.period_fs == 0 || hpet.period_fs > MAX_PERIOD_FS {
+        return Err(HpetError::Unsupported);
+    }
+
+    // Start from a known state, with all comparators stopped
+    hpet.set_enabled(false);
+    for n in 0..hpet.comparators {
+        hpet.stop(n);
+        hpet.ack(n);
+    }
+    hpet.write(REG_MAIN_COUNTER, 0);
+    hpet.set_enabled(true);
+
+    let event_timer = (0..hpet.comparators).find(|&n| hpet.timer_config(n).contains(TimerConfig::FSB_CAPABLE));
+    HPET.replace(Some(hpet));
+    time::register_clocksource(&HPET_CLOCKSOURCE);
+
+    // Without an I/O APIC, only comparators capable of FSB delivery can raise interrupts
+    if let Some(n) = event_timer {
+        let vector = irq::request_any_irq("hpet", event_irq, n as usize).map_err(HpetError::Irq)?;
+        let hpet = HPET.get_mut().as_mut().unwrap();
+        if hpet.route_fsb(n, vector) {
+            // FSB delivery is an MSI write
+            _ = irq::set_source(vector, irq::IrqSource::Msi);
+            hpet.event_timer = Some(n);
+            let event = HPET_CLOCK_EVENT.get_mut();
+            event.vector = vector;
+            if hpet.timer_config(n).contains(TimerConfig::PERIODIC_CAPABLE) {
+                event.features |= ClockEventFeatures::PERIODIC;
+            }
+            time::register_clock_event(HPET_CLOCK_EVENT.get());
+        } else {
+            irq::free_irq(vector);
+        }
+    }
+    Ok(())
+}
@@ -4,9 +4,12 @@ pub mod uart;
 
 /// # Safety
 ///
-/// This function is unsafe because it directory interacts with hardware and does not check if the port is valid.
+/// This function is unsafe because it directly interacts with hardware and does not check if the port is valid.
+/// `io_audit` builds check that the port was claimed, see [`claim_ports`](crate::dev::io_audit::claim_ports).
 #[inline]
-unsafe fn outb(port: u16, data: u8) {
+#[track_caller]
+pub(crate) unsafe fn outb(port: u16, data: u8) {
+    crate::dev::io_audit::check_port(port, 1);
     unsafe {
         asm!("out dx, al", in("dx") port, in("al") data);
     }
@@ -14,12 +17,56 @@ unsafe fn outb(port: u16, data: u8) {
 
 /// # Safety
 ///
-/// This function is unsafe because it directory interacts with hardware and does not check if the port is valid.
+/// This function is unsafe because it directly interacts with hardware and does not check if the port is valid.
+/// `io_audit` builds check that the port was claimed, see [`claim_ports`](crate::dev::io_audit::claim_ports).
 #[inline]
-unsafe fn inb(port: u16) -> u8 {
+#[track_caller]
+pub(crate) unsafe fn inb(port: u16) -> u8 {
+    crate::dev::io_audit::check_port(port, 1);
     unsafe {
         let mut data: u8;
         asm!("in al, dx", out("al") data, in("dx") port);
         data
     }
 }
+
+/// # Safety
+///
+/// This function is unsafe because it directly interacts with hardware and does not check if the port is valid.
+/// `io_audit` builds check that the port was claimed, see [`claim_ports`](crate::dev::io_audit::claim_ports).
+#[inline]
+#[track_caller]
+pub(crate) unsafe fn outl(port: u16, data: u32) {
+    crate::dev::io_audit::check_port(port, 4);
+    unsafe {
+        asm!("out dx, eax", in("dx") port, in("eax") data);
+    }
+}
+
+/// # Safety
+///
+/// This function is unsafe because it directly interacts with hardware and does not check if the port is valid.
+/// `io_audit` builds check that the port was claimed, see [`claim_ports`](crate::dev::io_audit::claim_ports).
+#[inline]
+#[track_caller]
+pub(crate) unsafe fn inl(port: u16) -> u32 {
+    crate::dev::io_audit::check_port(port, 4);
+    unsafe {
+        let mut data: u32;
+        asm!("in eax, dx", out("eax") data, in("dx") port);
+        data
+    }
+}
+
+/// # Safety
+///
+/// This function is unsafe because it directly interacts with hardware and does not check if the port is valid.
+/// `io_audit` builds check that the port was claimed, see [`claim_ports`](crate::dev::io_audit::claim_ports).
+#[inline]
+#[track_caller]
+pub(crate) unsafe fn outw(port: u16, data: u16) {
+    crate::dev::io_audit::check_port(port, 2);
+    unsafe {
+        asm!("out dx, ax", in("dx") port, in("ax") data);
+    }
+}
@@ -12,9 +12,53 @@ const LINE_STATUS_REG: u16 = 5; // Line Status Register (LSR) (R)
 const MODEM_STATUS_REG: u16 = 6; // Modem Status Register (MSR) (R)
 const SCRATCHPAD_REG: u16 = 7; // Scratchpad Register (SR) (RW)
 
+/// IER bits
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+
+/// LCR bits
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 1 << 7;
+
+/// MCR bits, OUT2 gates the interrupt line on PC compatible boards
+const MCR_DTR_RTS: u8 = 0x03;
+const MCR_OUT2: u8 = 1 << 3;
+
+/// LSR bits
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+
+/// Both IIR bits are set when the FIFOs are enabled and working, which only the 16550A does
+const IIR_FIFO_ENABLED: u8 = 0b11 << 6;
+
+/// Enable the FIFOs and clear both, raising the receive interrupt at 14 bytes
+const FCR_ENABLE_CLEAR_14: u8 = 0xC7;
+
+/// The bytes the transmit FIFO of a 16550A holds
+const TX_FIFO_SIZE: usize = 16;
+
+/// The rate with a divisor of 1, a 1.8432 MHz clock divided by 16
+pub const BASE_BAUD: u32 = 115_200;
+/// The rate used unless the command line asks for another one
+///
+/// 38400 rather than 115200, as it is often more stable on QEMU.
+pub const DEFAULT_BAUD: u32 = 38_400;
+
+/// Returns the divisor latch value for a baud rate, if the UART can generate it exactly
+pub const fn divisor(baud: u32) -> Option<u16> {
+    if baud == 0 || !BASE_BAUD.is_multiple_of(baud) {
+        return None;
+    }
+    Some((BASE_BAUD / baud) as u16)
+}
+
 #[derive(Debug, Clone)]
 pub struct Uart16550 {
     port_base: u16,
+    baud: u32,
+    /// The bytes that can be written each time the transmitter is empty
+    tx_fifo: usize,
 }
 
 impl Uart16550 {
@@ -24,63 +68,119 @@ impl Uart16550 {
     /// This function is unsafe because it involves direct hardware access and
     /// assumes the given port is a valid UART base address.
     pub const unsafe fn new(port_base: u16) -> Self {
-        Uart16550 { port_base }
+        Uart16550 {
+            port_base,
+            baud: DEFAULT_BAUD,
+            tx_fifo: 1,
+        }
     }
 
-    /// Initializes the UART for basic polling mode.
+    /// Initializes the UART for polling mode at the given baud rate, 8N1, with FIFOs if it has them
+    ///
+    /// Rates that can't be generated exactly fall back to [`DEFAULT_BAUD`].
     ///
     /// # Safety
     /// This function is unsafe because it performs direct I/O port writes
     /// and should only be called once during early kernel initialization.
-    pub unsafe fn init(&mut self) {
+    pub unsafe fn init(&mut self, baud: u32) {
+        crate::dev::io_audit::claim_ports(self.port_base, 8, "uart");
         unsafe {
-            // 1. Disable all interrupts
+            // Disable all interrupts
             outb(self.port_base + INT_ENABLE_REG, 0x00);
+            if !self.set_baud(baud) {
+                self.set_baud(DEFAULT_BAUD);
+            }
+            outb(self.port_base + FIFO_CONTROL_REG, FCR_ENABLE_CLEAR_14);
+            self.tx_fifo = if inb(self.port_base + FIFO_CONTROL_REG) & IIR_FIFO_ENABLED == IIR_FIFO_ENABLED {
+                TX_FIFO_SIZE
+            } else {
+                1
+            };
+            outb(self.port_base + MODEM_CONTROL_REG, MCR_DTR_RTS);
+            // Read LSR to clear any pending interrupts/errors
+            inb(self.port_base + LINE_STATUS_REG);
+        }
+    }
 
-            // 2. Enable DLAB (Divisor Latch Access Bit) to set baud rate
-            //    LCR bit 7 = 1
-            outb(self.port_base + LINE_CONTROL_REG, 0x80);
-
-            // 3. Set Baud Rate (e.g., 115200 bps for a 1.8432 MHz crystal)
-            //    Divisor = 115200 / (16 * Baud Rate) = 115200 / (16 * 115200) = 1
-            //    For 38400 bps, Divisor = 3
-            //    For 9600 bps, Divisor = 12
-            //    Let's use 38400 for qemu, it's often more stable than 115200
-            let baud_rate_divisor: u16 = 3; // For 38400 baud
-            outb(self.port_base + DATA_REG, (baud_rate_divisor & 0xFF) as u8); // Divisor Low
-            outb(self.port_base + INT_ENABLE_REG, (baud_rate_divisor >> 8) as u8); // Divisor High
-
-            // 4. Disable DLAB (LCR bit 7 = 0) and set 8 data bits, no parity, 1 stop bit (8N1)
-            //    LCR = 0x03 (8 data bits, 1 stop bit, no parity)
-            outb(self.port_base + LINE_CONTROL_REG, 0x03);
+    /// Changes the baud rate, keeping 8 data bits, no parity and 1 stop bit
+    ///
+    /// Returns `false` and keeps the current rate if the rate can't be generated exactly.
+    pub fn set_baud(&mut self, baud: u32) -> bool {
+        let Some(divisor) = divisor(baud) else {
+            return false;
+        };
+        // Wait for the bytes in flight, they would be garbled otherwise
+        while !self.is_transmit_empty() {
+            core::hint::spin_loop();
+        }
+        unsafe {
+            outb(self.port_base + LINE_CONTROL_REG, LCR_DLAB);
+            outb(self.port_base + DATA_REG, divisor as u8);
+            outb(self.port_base + INT_ENABLE_REG, (divisor >> 8) as u8);
+            outb(self.port_base + LINE_CONTROL_REG, LCR_8N1);
+        }
+        self.baud = baud;
+        true
+    }
 
-            // 5. Enable FIFOs, clear them, and set interrupt threshold
-            //    FCR = 0x01 (enable FIFOs) | 0x02 (clear Rx FIFO) | 0x04 (clear Tx FIFO) | 0x08 (use DMA mode, but not needed here) | 0xC0 (Rx trigger level 14 bytes)
-            outb(self.port_base + FIFO_CONTROL_REG, 0xC7); // Enable, Clear Rx/Tx FIFO, 14-byte threshold
+    pub fn baud(&self) -> u32 {
+        self.baud
+    }
 
-            // 6. Set Modem Control Register (MCR)
-            //    MCR = 0x03 (DTR=1, RTS=1), or 0x0B for loopback mode (for testing)
-            outb(self.port_base + MODEM_CONTROL_REG, 0x03);
+    /// Returns the bytes that can be written with [`Self::send`] once the transmitter is empty
+    pub fn tx_fifo_size(&self) -> usize {
+        self.tx_fifo
+    }
 
-            // 7. Read LSR to clear any pending interrupts/errors
-            inb(self.port_base + LINE_STATUS_REG);
+    /// Enables the receive and transmitter empty interrupts, and the interrupt line
+    pub fn set_interrupts(&mut self, rx: bool, tx_empty: bool) {
+        let mut ier = 0;
+        if rx {
+            ier |= IER_RX_AVAILABLE;
+        }
+        if tx_empty {
+            ier |= IER_TX_EMPTY;
+        }
+        let mcr = if ier != 0 { MCR_DTR_RTS | MCR_OUT2 } else { MCR_DTR_RTS };
+        unsafe {
+            outb(self.port_base + MODEM_CONTROL_REG, mcr);
+            outb(self.port_base + INT_ENABLE_REG, ier);
         }
     }
 
     /// Checks if the transmit holding register is empty.
     /// This means the UART is ready to accept a new byte for transmission.
-    fn is_transmit_empty(&self) -> bool {
-        unsafe { (inb(self.port_base + LINE_STATUS_REG) & 0x20) != 0 } // LSR bit 5: THRE
+    pub fn is_transmit_empty(&self) -> bool {
+        unsafe { (inb(self.port_base + LINE_STATUS_REG) & LSR_TX_EMPTY) != 0 }
+    }
+
+    /// Writes a byte without checking that there is room for it
+    pub fn send(&mut self, byte: u8) {
+        unsafe { outb(self.port_base + DATA_REG, byte) };
     }
 
     /// Writes a single byte to the serial port, blocking until it can be sent.
     pub fn write_byte(&mut self, byte: u8) {
         // Wait until the transmit buffer is empty
         while !self.is_transmit_empty() {
-            // spin loop
+            core::hint::spin_loop();
         }
-        // Send the byte
-        unsafe { outb(self.port_base + DATA_REG, byte) };
+        self.send(byte);
+    }
+
+    /// Reads a byte from the serial port, if one has been received.
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        self.read_status().0
+    }
+
+    /// Reads a byte if one has been received, and whether bytes were lost before it
+    pub fn read_status(&mut self) -> (Option<u8>, bool) {
+        let status = unsafe { inb(self.port_base + LINE_STATUS_REG) };
+        let overrun = status & LSR_OVERRUN != 0;
+        if status & LSR_DATA_READY == 0 {
+            return (None, overrun);
+        }
+        (Some(unsafe { inb(self.port_base + DATA_REG) }), overrun)
     }
 
     pub fn port(&self) -> u16 {
@@ -0,0 +1,168 @@
+//! I/O APIC
+//!
+//! Only routing ISA interrupts to the BSP is implemented for now, which is what the legacy devices
+//! like the serial port need. PCI devices use MSI instead. Every redirection entry starts masked,
+//! and the 8259 PICs are masked for good when the MADT says the system has them.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use crate::{
+    acpi::madt::{Polarity, TriggerMode},
+    arch::{VirtAddr, x86_64::apic, x86_64::io::outb},
+    dev::resource::{self, ResourceKind},
+    irq::IrqSource,
+    mm::mmio::{self, MmioSpaceExhausted},
+    sync::{Mutex, Once},
+};
+
+const REG_SELECT: usize = 0x00;
+const REG_WINDOW: usize = 0x10;
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+const PIC_MASTER_DATA: u16 = 0x21;
+const PIC_SLAVE_DATA: u16 = 0xA1;
+
+#[derive(Debug, Clone, Copy)]
+pub enum IoApicError {
+    /// ACPI describes no I/O APIC
+    NotPresent,
+    /// No I/O APIC handles the global system interrupt
+    NoEntry(u32),
+    Mmio(MmioSpaceExhausted),
+}
+
+impl fmt::Display for IoApicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotPresent => f.write_str("no I/O APIC found"),
+            Self::NoEntry(gsi) => write!(f, "no I/O APIC handles GSI {}", gsi),
+            Self::Mmio(err) => write!(f, "failed to map I/O APIC: {}", err),
+        }
+    }
+}
+
+impl core::error::Error for IoApicError {}
+
+#[derive(Debug)]
+struct IoApic {
+    /// The select and window registers have to be used together
+    base: Mutex<VirtAddr>,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(base: VirtAddr, reg: u32) -> u32 {
+        unsafe {
+            mmio::write(base, REG_SELECT, reg);
+            mmio::read(base, REG_WINDOW)
+        }
+    }
+
+    fn write(base: VirtAddr, reg: u32, value: u32) {
+        unsafe {
+            mmio::write(base, REG_SELECT, reg);
+            mmio::write(base, REG_WINDOW, value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    /// Writes the redirection entry of a pin, the destination first so it is never half set up
+    fn set_entry(&self, pin: u32, low: u32, destination: u8) {
+        let reg = REG_REDIRECTION + pin * 2;
+        crate::arch::instructions::interrupts::without_interrupts(|| {
+            let base = self.base.lock();
+            Self::write(*base, reg, REDIRECTION_MASKED);
+            Self::write(*base, reg + 1, (destination as u32) << 24);
+            Self::write(*base, reg, low);
+        });
+    }
+}
+
+static IO_APICS: Once<Vec<IoApic>> = Once::new();
+
+/// Maps every I/O APIC, and masks all of their pins and the legacy PICs
+///
+/// # Safety
+/// Must only be called once, after ACPI and the local APIC are initialized.
+pub unsafe fn init() -> Result<(), IoApicError> {
+    let topology = crate::acpi::cpu_topology().ok_or(IoApicError::NotPresent)?;
+    if topology.io_apics.is_empty() {
+        return Err(IoApicError::NotPresent);
+    }
+    if topology.has_legacy_pics {
+        crate::dev::io_audit::claim_ports(PIC_MASTER_DATA - 1, 2, "pic");
+        crate::dev::io_audit::claim_ports(PIC_SLAVE_DATA - 1, 2, "pic");
+        unsafe {
+            outb(PIC_MASTER_DATA, 0xFF);
+            outb(PIC_SLAVE_DATA, 0xFF);
+        }
+    }
+    let mut io_apics = Vec::new();
+    for io_apic in &topology.io_apics {
+        let region = unsafe { mmio::map(io_apic.address, 0x1000).map_err(IoApicError::Mmio)? };
+        let start = io_apic.address.as_usize() as u64;
+        resource::reserve(ResourceKind::Mmio, start..=start + 0xFFF, "io apic");
+        let base = region.virt();
+        let entries = (IoApic::read(base, REG_VERSION) >> 16 & 0xFF) + 1;
+        let io_apic = IoApic {
+            base: Mutex::new(base),
+            gsi_base: io_apic.gsi_base,
+            entries,
+        };
+        for pin in 0..entries {
+            io_apic.set_entry(pin, REDIRECTION_MASKED, 0);
+        }
+        io_apics.push(io_apic);
+    }
+    IO_APICS.call_once(|| io_apics);
+    Ok(())
+}
+
+/// Returns the number of pins of every I/O APIC
+pub fn pins() -> u32 {
+    IO_APICS
+        .get()
+        .map_or(0, |io_apics| io_apics.iter().map(|io_apic| io_apic.entries).sum())
+}
+
+/// Delivers an ISA interrupt to the BSP as `vector`, returning its source
+///
+/// The MADT interrupt source overrides say which global system interrupt the ISA interrupt is,
+/// and how it is triggered, ISA interrupts are edge triggered and active high otherwise.
+pub fn route_isa(isa_irq: u8, vector: u8) -> Result<IrqSource, IoApicError> {
+    let io_apics = IO_APICS.get().ok_or(IoApicError::NotPresent)?;
+    let over =
+        crate::acpi::cpu_topology().and_then(|topology| topology.overrides.iter().find(|over| over.isa_irq == isa_irq));
+    let (gsi, polarity, trigger) = match over {
+        Some(over) => (over.gsi, over.polarity, over.trigger),
+        None => (isa_irq as u32, Polarity::BusDefault, TriggerMode::BusDefault),
+    };
+    let io_apic = io_apics
+        .iter()
+        .find(|io_apic| io_apic.handles(gsi))
+        .ok_or(IoApicError::NoEntry(gsi))?;
+
+    let mut low = vector as u32;
+    if polarity == Polarity::ActiveLow {
+        low |= REDIRECTION_ACTIVE_LOW;
+    }
+    if trigger == TriggerMode::Level {
+        low |= REDIRECTION_LEVEL;
+    }
+    // The I/O APIC only addresses 8 bit APIC IDs without interrupt remapping
+    let destination = apic::local_apic().map_or(0, |lapic| lapic.id() as u8);
+    io_apic.set_entry(gsi - io_apic.gsi_base, low, destination);
+    Ok(IrqSource::IoApic { pin: gsi as u8 })
+}
@@ -0,0 +1,388 @@
+//! CPU microcode updates
+//!
+//! Firmware often ships old microcode, and some errata workarounds depend on a current revision.
+//! Updates are read from the initramfs, at the same paths Linux uses for its early loader, so the
+//! files distributions ship can be copied in unchanged:
+//!  - `kernel/x86/microcode/GenuineIntel.bin`: Intel updates, concatenated
+//!  - `kernel/x86/microcode/AuthenticAMD.bin`: AMD containers, concatenated
+//!
+//! The BSP picks the newest update for its signature in [`init`], and every other CPU applies the
+//! same one with [`apply`] before it runs anything else. Updates don't survive a reset, so this
+//! happens on every boot. Under a hypervisor the host owns the microcode, so nothing is loaded.
+
+use core::{arch::x86_64::__cpuid, fmt};
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{
+        registers::msr::Msr,
+        x86_64::cpu::{self, Feature},
+    },
+    sync::Once,
+};
+
+const INTEL_PATH: &str = "kernel/x86/microcode/GenuineIntel.bin";
+const AMD_PATH: &str = "kernel/x86/microcode/AuthenticAMD.bin";
+
+/// The size of the header before the data of an Intel update
+const INTEL_HEADER_SIZE: usize = 48;
+/// The data size of an Intel update whose header leaves it zero
+const INTEL_DEFAULT_DATA_SIZE: usize = 2000;
+/// The size of the header of the extended signature table of an Intel update
+const INTEL_EXT_HEADER_SIZE: usize = 20;
+const INTEL_EXT_SIGNATURE_SIZE: usize = 12;
+
+/// "DMA\0", the magic number of an AMD container
+const AMD_MAGIC: u32 = 0x0041_4D44;
+const AMD_SECTION_EQUIV_TABLE: u32 = 0;
+const AMD_SECTION_PATCH: u32 = 1;
+const AMD_EQUIV_ENTRY_SIZE: usize = 16;
+/// The smallest patch that still has the whole header
+const AMD_PATCH_HEADER_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vendor {
+    Intel,
+    Amd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MicrocodeError {
+    /// The host owns the microcode
+    Hypervisor,
+    UnsupportedVendor,
+    NoInitramfs,
+    NotFound(&'static str),
+    /// The update file is cut short or has a bad checksum
+    Malformed,
+    /// The CPU didn't take the update, and still runs the given revision
+    Rejected {
+        revision: u32,
+    },
+}
+
+impl fmt::Display for MicrocodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Hypervisor => f.write_str("running under a hypervisor"),
+            Self::UnsupportedVendor => f.write_str("no microcode loader for this CPU vendor"),
+            Self::NoInitramfs => f.write_str("no initramfs"),
+            Self::NotFound(path) => write!(f, "no {} in the initramfs", path),
+            Self::Malformed => f.write_str("malformed microcode file"),
+            Self::Rejected { revision } => write!(f, "update rejected, still at revision {:#x}", revision),
+        }
+    }
+}
+
+impl core::error::Error for MicrocodeError {}
+
+/// An update, copied out of the initramfs so it is 16 byte aligned as Intel requires
+struct Update {
+    vendor: Vendor,
+    revision: u32,
+    data: Vec<u128>,
+}
+
+/// The update picked by the BSP, if there is a newer one than the firmware loaded
+static UPDATE: Once<Option<Update>> = Once::new();
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+/// Returns whether an Intel signature and platform flags match those of the CPU
+fn intel_matches(signature: u32, flags: u32, cpu_signature: u32, cpu_flags: u32) -> bool {
+    signature == cpu_signature && (flags & cpu_flags != 0 || (flags == 0 && cpu_flags == 0))
+}
+
+/// Returns whether an Intel update, including its extended signatures, is for the CPU
+fn intel_update_matches(update: &[u8], data_size: usize, cpu_signature: u32, cpu_flags: u32) -> Option<bool> {
+    if intel_matches(read_u32(update, 12)?, read_u32(update, 24)?, cpu_signature, cpu_flags) {
+        return Some(true);
+    }
+    let ext = update.get(INTEL_HEADER_SIZE + data_size..)?;
+    if ext.len() < INTEL_EXT_HEADER_SIZE {
+        return Some(false);
+    }
+    let count = read_u32(ext, 0)? as usize;
+    for idx in 0..count {
+        let offset = INTEL_EXT_HEADER_SIZE + idx * INTEL_EXT_SIGNATURE_SIZE;
+        if intel_matches(
+            read_u32(ext, offset)?,
+            read_u32(ext, offset + 4)?,
+            cpu_signature,
+            cpu_flags,
+        ) {
+            return Some(true);
+        }
+    }
+    Some(false)
+}
+
+/// Finds the newest Intel update for a CPU that is newer than `current`
+///
+/// Returns the whole update, header included, and its revision.
+fn find_intel(
+    file: &[u8],
+    cpu_signature: u32,
+    cpu_flags: u32,
+    current: u32,
+) -> Result<Option<(&[u8], u32)>, MicrocodeError> {
+    let mut best: Option<(&[u8], u32)> = None;
+    let mut rest = file;
+    while !rest.is_empty() {
+        let header = rest.get(..INTEL_HEADER_SIZE).ok_or(MicrocodeError::Malformed)?;
+        let data_size = match read_u32(header, 28).unwrap() as usize {
+            0 => INTEL_DEFAULT_DATA_SIZE,
+            size => size,
+        };
+        let total_size = match read_u32(header, 32).unwrap() as usize {
+            0 => INTEL_DEFAULT_DATA_SIZE + INTEL_HEADER_SIZE,
+            size => size,
+        };
+        if read_u32(header, 0) != Some(1) || total_size < data_size + INTEL_HEADER_SIZE || !total_size.is_multiple_of(4)
+        {
+            return Err(MicrocodeError::Malformed);
+        }
+        let update = rest.get(..total_size).ok_or(MicrocodeError::Malformed)?;
+        rest = &rest[total_size..];
+
+        let checksum = update
+            .as_chunks::<4>()
+            .0
+            .iter()
+            .fold(0u32, |sum, dword| sum.wrapping_add(u32::from_le_bytes(*dword)));
+        if checksum != 0 {
+            return Err(MicrocodeError::Malformed);
+        }
+
+        let revision = read_u32(update, 4).unwrap();
+        let matches =
+            intel_update_matches(update, data_size, cpu_signature, cpu_flags).ok_or(MicrocodeError::Malformed)?;
+        // Revisions are signed on Intel, pre-production ones are negative
+        if matches && (revision as i32) > (best.map_or(current, |(_, rev)| rev) as i32) {
+            best = Some((update, revision));
+        }
+    }
+    Ok(best)
+}
+
+/// Finds the newest AMD patch for a CPU that is newer than `current`
+///
+/// The equivalence table of each container maps the CPUID signature to the processor revision
+/// id the patches are tagged with.
+fn find_amd(file: &[u8], cpu_signature: u32, current: u32) -> Result<Option<(&[u8], u32)>, MicrocodeError> {
+    let mut best: Option<(&[u8], u32)> = None;
+    let mut rest = file;
+    while !rest.is_empty() {
+        if read_u32(rest, 0) != Some(AMD_MAGIC) || read_u32(rest, 4) != Some(AMD_SECTION_EQUIV_TABLE) {
+            return Err(MicrocodeError::Malformed);
+        }
+        let table_size = read_u32(rest, 8).ok_or(MicrocodeError::Malformed)? as usize;
+        let table = rest.get(12..12 + table_size).ok_or(MicrocodeError::Malformed)?;
+        let equiv_id = table
+            .as_chunks::<AMD_EQUIV_ENTRY_SIZE>()
+            .0
+            .iter()
+            .take_while(|entry| read_u32(*entry, 0) != Some(0))
+            .find(|entry| read_u32(*entry, 0) == Some(cpu_signature))
+            .and_then(|entry| read_u16(entry, 12));
+        rest = &rest[12 + table_size..];
+
+        // Patch sections follow until the next container or the end
+        while read_u32(rest, 0) == Some(AMD_SECTION_PATCH) {
+            let size = read_u32(rest, 4).ok_or(MicrocodeError::Malformed)? as usize;
+            let patch = rest.get(8..8 + size).ok_or(MicrocodeError::Malformed)?;
+            rest = &rest[8 + size..];
+            if patch.len() < AMD_PATCH_HEADER_SIZE {
+                return Err(MicrocodeError::Malformed);
+            }
+
+            let revision = read_u32(patch, 4).unwrap();
+            if equiv_id.is_some_and(|id| read_u16(patch, 24) == Some(id))
+                && revision > best.map_or(current, |(_, rev)| rev)
+            {
+                best = Some((patch, revision));
+            }
+        }
+    }
+    Ok(best)
+}
+
+fn vendor() -> Option<Vendor> {
+    let res = __cpuid(0);
+    let mut name = [0u8; 12];
+    name[..4].copy_from_slice(&res.ebx.to_le_bytes());
+    name[4..8].copy_from_slice(&res.edx.to_le_bytes());
+    name[8..].copy_from_slice(&res.ecx.to_le_bytes());
+    match &name {
+        b"GenuineIntel" => Some(Vendor::Intel),
+        b"AuthenticAMD" => Some(Vendor::Amd),
+        _ => None,
+    }
+}
+
+/// Returns the revision of the microcode running on the current CPU
+pub fn revision() -> u32 {
+    match vendor() {
+        Some(Vendor::Intel) => unsafe {
+            // The register is only updated by CPUID after it was cleared
+            let mut sign_id = Msr::IA32_BIOS_SIGN_ID;
+            sign_id.write(0);
+            __cpuid(1);
+            (sign_id.read() >> 32) as u32
+        },
+        Some(Vendor::Amd) => unsafe { Msr::IA32_BIOS_SIGN_ID.read() as u32 },
+        None => 0,
+    }
+}
+
+/// Finds the newest update for the CPU in the initramfs
+fn find_update() -> Result<Option<Update>, MicrocodeError> {
+    if cpu::has(Feature::Hypervisor) {
+        return Err(MicrocodeError::Hypervisor);
+    }
+    let vendor = vendor().ok_or(MicrocodeError::UnsupportedVendor)?;
+    let initramfs = crate::boot::initramfs().ok_or(MicrocodeError::NoInitramfs)?;
+    let path = match vendor {
+        Vendor::Intel => INTEL_PATH,
+        Vendor::Amd => AMD_PATH,
+    };
+    let file = crate::util::tar::find(initramfs, path).ok_or(MicrocodeError::NotFound(path))?;
+
+    let signature = __cpuid(1).eax;
+    let found = match vendor {
+        Vendor::Intel => {
+            let platform = unsafe { Msr::IA32_PLATFORM_ID.read() } >> 50 & 0x7;
+            find_intel(file, signature, 1 << platform, revision())?
+        }
+        Vendor::Amd => find_amd(file, signature, revision())?,
+    };
+    Ok(found.map(|(bytes, revision)| {
+        let mut data = alloc::vec![0u128; bytes.len().div_ceil(16)];
+        unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), data.as_mut_ptr().cast::<u8>(), bytes.len()) };
+        Update { vendor, revision, data }
+    }))
+}
+
+/// Loads the update found by the BSP into the current CPU, returning its new revision
+///
+/// Returns `None` if there is no update, or the CPU already runs it.
+///
+/// # Safety
+/// Must be called on each CPU other than the BSP, with interrupts disabled, before it runs
+/// anything that depends on the microcode.
+pub unsafe fn apply() -> Result<Option<u32>, MicrocodeError> {
+    let Some(Some(update)) = UPDATE.get() else {
+        return Ok(None);
+    };
+    if revision() == update.revision {
+        return Ok(None);
+    }
+
+    let addr = update.data.as_ptr() as u64;
+    let (mut trigger, addr) = match update.vendor {
+        Vendor::Intel => (Msr::IA32_BIOS_UPDT_TRIG, addr + INTEL_HEADER_SIZE as u64),
+        Vendor::Amd => (Msr::AMD64_PATCH_LOADER, addr),
+    };
+    unsafe { trigger.write(addr) };
+
+    match revision() {
+        revision if revision == update.revision => Ok(Some(revision)),
+        revision => Err(MicrocodeError::Rejected { revision }),
+    }
+}
+
+/// Finds the newest update for the CPU in the initramfs, and loads it into the BSP
+///
+/// Returns the revisions before and after, or `None` if the firmware already loaded the newest.
+/// The CPU features are read again afterwards, as an update can change them.
+///
+/// # Safety
+/// Must be called once, on the BSP with interrupts disabled, before other CPUs are started.
+pub unsafe fn init() -> Result<Option<(u32, u32)>, MicrocodeError> {
+    let before = revision();
+    let update = find_update()?;
+    UPDATE.call_once(|| update);
+    let Some(after) = (unsafe { apply() })? else {
+        return Ok(None);
+    };
+    unsafe { cpu::init() };
+    Ok(Some((before, after)))
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    /// Builds an Intel update with the given revision, signature and platform flags
+    fn intel_update(revision: u32, signature: u32, flags: u32) -> Vec<u8> {
+        let total_size = INTEL_HEADER_SIZE + 16;
+        let mut update = alloc::vec![0u8; total_size];
+        for (offset, value) in [
+            (0, 1),
+            (4, revision),
+            (12, signature),
+            (24, flags),
+            (28, 16),
+            (32, total_size as u32),
+        ] {
+            update[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        let sum = update.chunks_exact(4).fold(0u32, |sum, dword| {
+            sum.wrapping_add(u32::from_le_bytes(dword.try_into().unwrap()))
+        });
+        update[16..20].copy_from_slice(&sum.wrapping_neg().to_le_bytes());
+        update
+    }
+
+    fn amd_container(signature: u32, equiv_id: u16, patches: &[(u32, u16)]) -> Vec<u8> {
+        let mut file = Vec::new();
+        for value in [
+            AMD_MAGIC,
+            AMD_SECTION_EQUIV_TABLE,
+            2 * AMD_EQUIV_ENTRY_SIZE as u32,
+            signature,
+            0,
+            0,
+        ] {
+            file.extend_from_slice(&value.to_le_bytes());
+        }
+        file.extend_from_slice(&(equiv_id as u32).to_le_bytes());
+        file.extend_from_slice(&[0; AMD_EQUIV_ENTRY_SIZE]);
+        for (revision, rev_id) in patches {
+            let mut patch = [0u8; AMD_PATCH_HEADER_SIZE];
+            patch[4..8].copy_from_slice(&revision.to_le_bytes());
+            patch[24..26].copy_from_slice(&rev_id.to_le_bytes());
+            file.extend_from_slice(&AMD_SECTION_PATCH.to_le_bytes());
+            file.extend_from_slice(&(patch.len() as u32).to_le_bytes());
+            file.extend_from_slice(&patch);
+        }
+        file
+    }
+
+    #[test]
+    fn microcode_find() {
+        let mut intel = intel_update(0x10, 0x906EA, 0b10);
+        intel.extend(intel_update(0x20, 0x906EA, 0b01));
+        intel.extend(intel_update(0x30, 0x806C1, 0b10));
+        intel.extend(intel_update(0x18, 0x906EA, 0b10));
+        let (_, revision) = find_intel(&intel, 0x906EA, 0b10, 0x0).unwrap().unwrap();
+        assert_eq!(revision, 0x18);
+        assert!(find_intel(&intel, 0x906EA, 0b10, 0x18).unwrap().is_none());
+        intel[20] ^= 1;
+        assert_eq!(find_intel(&intel, 0x906EA, 0b10, 0), Err(MicrocodeError::Malformed));
+
+        let mut amd = amd_container(0x00A20F12, 0xA201, &[(0x0A20_1016, 0xA201), (0x0A20_1020, 0xA200)]);
+        amd.extend(amd_container(0x00870F10, 0x8710, &[(0x0870_1033, 0x8710)]));
+        let (patch, revision) = find_amd(&amd, 0x00A20F12, 0x0A20_1000).unwrap().unwrap();
+        assert_eq!((patch.len(), revision), (AMD_PATCH_HEADER_SIZE, 0x0A20_1016));
+        assert!(find_amd(&amd, 0x00A20F12, 0x0A20_1016).unwrap().is_none());
+        assert!(find_amd(&amd[..20], 0x00A20F12, 0).is_err());
+    }
+}
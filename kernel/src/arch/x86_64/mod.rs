@@ -1,3 +1,17 @@
+pub mod apic;
 pub mod core;
 pub mod cpu;
+pub mod fpu;
+pub mod hpet;
 pub mod io;
+pub mod ioapic;
+pub mod microcode;
+pub mod panic_stop;
+pub mod pit;
+pub mod pmtimer;
+pub mod random;
+pub mod reset;
+pub mod rtc;
+pub mod speaker;
+pub mod syscall;
+pub mod watchdog;
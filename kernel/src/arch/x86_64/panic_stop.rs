@@ -0,0 +1,128 @@
+//! Stopping the other CPUs on panic
+//!
+//! The CPU running the panic pipeline sends every other CPU an NMI with [`stop_others`], which
+//! gets through even if they have interrupts disabled or spin on a lock. The NMI handler of a CPU
+//! that is asked to stop saves its registers and a backtrace, and halts for good without returning,
+//! so the CPU stays where it was interrupted. [`write_report`] then shows all of them after the
+//! panic itself, and CPUs that didn't answer within [`STOP_TIMEOUT_NS`] as still running.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use crate::{
+    arch::{
+        instructions::{hlt, interrupts},
+        x86_64::{
+            apic,
+            core::idt::{Backtrace, ExceptionFrame, Report},
+        },
+    },
+    percpu::{self, MAX_CPUS, PerCpu},
+    sync::cell::RacyCell,
+    time::tsc,
+    util::machine_state::MachineState,
+};
+
+/// How long the other CPUs have to stop
+pub const STOP_TIMEOUT_NS: u64 = 1_000_000_000;
+/// How often to check for the other CPUs if the TSC isn't calibrated, about as long on most CPUs
+const STOP_TIMEOUT_SPINS: u64 = 100_000_000;
+
+const RUNNING: u8 = 0;
+/// Sent the NMI, but not stopped yet
+const ASKED: u8 = 1;
+const STOPPED: u8 = 2;
+
+/// What a CPU was doing when it stopped
+struct Stopped {
+    state: AtomicU8,
+    registers: RacyCell<Option<MachineState>>,
+    backtrace: RacyCell<Option<Backtrace>>,
+}
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+static CPUS: PerCpu<Stopped> = PerCpu::new(
+    [const {
+        Stopped {
+            state: AtomicU8::new(RUNNING),
+            registers: RacyCell::new(None),
+            backtrace: RacyCell::new(None),
+        }
+    }; MAX_CPUS],
+);
+
+/// Sends every other CPU an NMI and waits for them to stop, returning how many didn't
+///
+/// Only called once, by the CPU running the panic pipeline.
+pub fn stop_others() -> usize {
+    let Some(lapic) = apic::local_apic() else {
+        // Without the local APIC the other CPUs were never started
+        return 0;
+    };
+    let current = percpu::cpu_id();
+    STOPPING.store(true, Ordering::Release);
+    for cpu in percpu::cpus().filter(|cpu| cpu.cpu_id as usize != current) {
+        CPUS.get_for(cpu.cpu_id as usize).state.store(ASKED, Ordering::Release);
+        lapic.send_nmi(cpu.apic_id);
+    }
+
+    let start = tsc::monotonic_ns();
+    let mut spins = 0;
+    loop {
+        let running = percpu::cpus()
+            .filter(|cpu| CPUS.get_for(cpu.cpu_id as usize).state.load(Ordering::Acquire) == ASKED)
+            .count();
+        let timed_out = match tsc::frequency_khz() {
+            0 => spins >= STOP_TIMEOUT_SPINS,
+            _ => tsc::monotonic_ns() - start >= STOP_TIMEOUT_NS,
+        };
+        if running == 0 || timed_out {
+            return running;
+        }
+        spins += 1;
+        core::hint::spin_loop();
+    }
+}
+
+/// Stops the current CPU if it was asked to by [`stop_others`], otherwise returns
+pub fn handle_nmi(frame: &ExceptionFrame) {
+    if !STOPPING.load(Ordering::Acquire) {
+        return;
+    }
+    let stopped = CPUS.get();
+    if stopped.state.load(Ordering::Acquire) != ASKED {
+        return;
+    }
+    // Only this CPU writes them, and the panicking CPU only reads them once they are marked
+    *stopped.registers.get_mut() = Some(Report::new(frame).machine_state());
+    *stopped.backtrace.get_mut() = Some(Backtrace::capture(frame.rip as usize, frame.rbp as usize));
+    stopped.state.store(STOPPED, Ordering::Release);
+    // Further NMIs are blocked, as the handler never returns
+    unsafe { interrupts::disable() };
+    loop {
+        hlt();
+    }
+}
+
+/// Writes what every CPU asked to stop was doing
+pub fn write_report(out: &mut dyn fmt::Write) -> fmt::Result {
+    for cpu in percpu::cpus() {
+        let stopped = CPUS.get_for(cpu.cpu_id as usize);
+        match stopped.state.load(Ordering::Acquire) {
+            RUNNING => {}
+            ASKED => writeln!(out, "cpu{}: did not stop, and may still be running", cpu.cpu_id)?,
+            _ => {
+                writeln!(out, "cpu{}: stopped", cpu.cpu_id)?;
+                if let Some(registers) = stopped.registers.get() {
+                    write!(out, "{}", registers)?;
+                }
+                if let Some(backtrace) = stopped.backtrace.get() {
+                    write!(out, "backtrace:\n{}", backtrace)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,121 @@
+//! 8253/8254 programmable interval timer
+//!
+//! Every PC has one, counting down at 1.193182 MHz whatever the CPU runs at. Only channel 2 is
+//! used: its output can be read back through port B of the keyboard controller, which makes it a
+//! reference to measure other counters against with [`calibrate_against`], and it drives the PC
+//! speaker (see [`super::speaker`]). Channel 0 is left alone, the HPET raises timer interrupts.
+
+use core::fmt;
+
+use crate::arch::x86_64::io::{inb, outb};
+
+/// The frequency of the input clock in Hz
+pub const FREQUENCY: u64 = 1_193_182;
+/// The longest calibration window, channel 2 only counts 16 bits
+pub const MAX_WINDOW_MS: u64 = 0xFFFF * 1000 / FREQUENCY;
+
+const CHANNEL2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Keyboard controller port B, which gates channel 2 and connects it to the speaker
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+/// Mirrors the output of channel 2
+const PORT_B_OUTPUT: u8 = 1 << 5;
+
+/// Channel 2, lobyte/hibyte access, binary counting, to be ORed with a mode
+const COMMAND_CHANNEL2: u8 = 0b1011_0000;
+/// The output goes high once the count reaches 0
+const MODE_ONESHOT: u8 = 0 << 1;
+const MODE_SQUARE_WAVE: u8 = 3 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PitError {
+    /// The window is longer than channel 2 can count
+    WindowTooLong,
+    /// The output went high right away, there most likely is no PIT
+    NotPresent,
+    /// The counter didn't move during the window
+    Stuck,
+}
+
+impl fmt::Display for PitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WindowTooLong => write!(f, "calibration window longer than {} ms", MAX_WINDOW_MS),
+            Self::NotPresent => f.write_str("no PIT"),
+            Self::Stuck => f.write_str("the counter didn't move against the PIT"),
+        }
+    }
+}
+
+impl core::error::Error for PitError {}
+
+/// Claims the ports of channel 2 and port B
+pub fn init() {
+    crate::dev::io_audit::claim_ports(CHANNEL2, 2, "pit");
+    crate::dev::io_audit::claim_ports(PORT_B, 1, "pit");
+}
+
+/// Measures the frequency of a counter in Hz, by reading it before and after a channel 2
+/// one-shot countdown of `window_ms` milliseconds
+///
+/// The counter must count up and not wrap during the window. Longer windows are more precise, up
+/// to [`MAX_WINDOW_MS`]. The speaker is silenced meanwhile.
+///
+/// # Safety
+/// Reprograms channel 2, so nothing else may be using it, and interrupts should be disabled for
+/// the window to be measured right.
+pub unsafe fn calibrate_against(mut read: impl FnMut() -> u64, window_ms: u64) -> Result<u64, PitError> {
+    if window_ms > MAX_WINDOW_MS {
+        return Err(PitError::WindowTooLong);
+    }
+    init();
+    let latch = FREQUENCY * window_ms / 1000;
+    unsafe {
+        outb(PORT_B, (inb(PORT_B) & !PORT_B_SPEAKER) | PORT_B_GATE);
+        program(MODE_ONESHOT, latch as u16);
+
+        let start = read();
+        let mut loops = 0u64;
+        while inb(PORT_B) & PORT_B_OUTPUT == 0 {
+            loops += 1;
+            core::hint::spin_loop();
+        }
+        let end = read();
+
+        if loops < 50 {
+            return Err(PitError::NotPresent);
+        }
+        match end.wrapping_sub(start) {
+            0 => Err(PitError::Stuck),
+            counted => Ok(counted * 1000 / window_ms),
+        }
+    }
+}
+
+/// Starts a square wave of `frequency` Hz on channel 2, and connects it to the speaker
+///
+/// # Safety
+/// Reprograms channel 2, so nothing else may be using it.
+pub(super) unsafe fn start_tone(frequency: u32) {
+    let divisor = (FREQUENCY / frequency.max(1) as u64).clamp(1, 0xFFFF);
+    unsafe {
+        program(MODE_SQUARE_WAVE, divisor as u16);
+        outb(PORT_B, inb(PORT_B) | PORT_B_GATE | PORT_B_SPEAKER);
+    }
+}
+
+/// Disconnects channel 2 from the speaker and stops it
+pub(super) fn stop_tone() {
+    unsafe { outb(PORT_B, inb(PORT_B) & !(PORT_B_GATE | PORT_B_SPEAKER)) };
+}
+
+/// Sets the mode of channel 2, and starts it counting down from `count`
+unsafe fn program(mode: u8, count: u16) {
+    unsafe {
+        outb(COMMAND, COMMAND_CHANNEL2 | mode);
+        outb(CHANNEL2, count as u8);
+        outb(CHANNEL2, (count >> 8) as u8);
+    }
+}
@@ -0,0 +1,113 @@
+//! ACPI PM timer clock source
+//!
+//! A 3.579545 MHz counter in I/O space that every ACPI system has. It is slow to read and only
+//! 24 bits wide on many chipsets, wrapping every 4.7 seconds, so it is rated below the HPET and
+//! the TSC, and mostly serves as an independent reference for [`crate::time::selftest`].
+//!
+//! The counter is extended to 64 bits in software, which only works if it is read at least once
+//! per wrap. That is the case while it is the current clock source, other readers should only
+//! rely on short intervals.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use crate::{
+    acpi::{self, fadt},
+    arch::x86_64::io::inl,
+    time::{self, ClockSource},
+};
+
+/// The frequency of the counter in Hz
+pub const FREQUENCY: u64 = 3_579_545;
+
+#[derive(Debug, Clone, Copy)]
+pub enum PmTimerError {
+    /// The FADT describes no timer in I/O space
+    NotPresent,
+    /// The counter doesn't count
+    Stuck,
+}
+
+impl fmt::Display for PmTimerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotPresent => f.write_str("no PM timer in the FADT"),
+            Self::Stuck => f.write_str("the PM timer doesn't count"),
+        }
+    }
+}
+
+impl core::error::Error for PmTimerError {}
+
+static PORT: AtomicU32 = AtomicU32::new(0);
+static MASK: AtomicU32 = AtomicU32::new(0);
+/// The extended count at the last read
+static LAST: AtomicU64 = AtomicU64::new(0);
+
+fn read_raw(port: u16) -> u32 {
+    unsafe { inl(port) & MASK.load(Ordering::Relaxed) }
+}
+
+/// Returns the ticks since the timer was initialized, extended to 64 bits
+pub fn ticks() -> u64 {
+    let port = PORT.load(Ordering::Relaxed) as u16;
+    if port == 0 {
+        return 0;
+    }
+    let mask = MASK.load(Ordering::Relaxed) as u64;
+    let raw = read_raw(port) as u64;
+    let mut last = LAST.load(Ordering::Relaxed);
+    loop {
+        let next = last + (raw.wrapping_sub(last) & mask);
+        match LAST.compare_exchange_weak(last, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return next,
+            // Another reader got a later value, which is just as good
+            Err(current) if current >= next => return current,
+            Err(current) => last = current,
+        }
+    }
+}
+
+fn read_ns() -> u64 {
+    (ticks() as u128 * 1_000_000_000 / FREQUENCY as u128) as u64
+}
+
+static PM_TIMER_CLOCKSOURCE: ClockSource = ClockSource {
+    name: "acpi_pm",
+    rating: 200,
+    read_ns,
+};
+
+/// Returns the PM timer clock source
+pub fn clocksource() -> &'static ClockSource {
+    &PM_TIMER_CLOCKSOURCE
+}
+
+/// Finds the PM timer in the FADT, and registers it as a clock source
+///
+/// # Safety
+/// Must only be called once, after ACPI is initialized.
+pub unsafe fn init() -> Result<(), PmTimerError> {
+    let info = acpi::with_tables(|tables| {
+        tables
+            .find_raw(fadt::SIGNATURE)
+            .and_then(|fadt| fadt::pm_timer(fadt.bytes()))
+    })
+    .flatten()
+    .ok_or(PmTimerError::NotPresent)?;
+    crate::dev::io_audit::claim_ports(info.port, 4, "pm timer");
+    let mask = if info.extended { u32::MAX } else { 0xFF_FFFF };
+    MASK.store(mask, Ordering::Relaxed);
+
+    // An absent timer reads as all ones, or never changes
+    let start = read_raw(info.port);
+    if (0..100_000).all(|_| read_raw(info.port) == start) {
+        return Err(PmTimerError::Stuck);
+    }
+    LAST.store(read_raw(info.port) as u64, Ordering::Relaxed);
+    PORT.store(info.port as u32, Ordering::Relaxed);
+    time::register_clocksource(&PM_TIMER_CLOCKSOURCE);
+    Ok(())
+}
@@ -0,0 +1,74 @@
+//! Early sources of randomness
+//!
+//! These work before anything else is set up, for randomizing the kernel layout at boot. They are
+//! not an entropy pool, and without RDRAND the values are only as good as the TSC jitter. Once the
+//! heap is up, [`crate::random`] takes over.
+
+use core::{
+    arch::x86_64::{_rdrand64_step, _rdtsc},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::arch::x86_64::cpu::{self, Feature};
+
+/// Returns a random value from RDRAND, if the CPU supports it and it didn't keep failing
+pub fn rdrand() -> Option<u64> {
+    if !cpu::has(Feature::Rdrand) {
+        return None;
+    }
+    // SAFETY: The CPU supports RDRAND
+    unsafe { rdrand_unchecked() }
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand_unchecked() -> Option<u64> {
+    // Intel recommends 10 retries, RDRAND only fails when its entropy is momentarily exhausted
+    for _ in 0..10 {
+        let mut value = 0;
+        if _rdrand64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Returns a value collected from the jitter of timing a short loop with the TSC
+///
+/// The low bits of each measurement vary with caches, interrupts and the hypervisor, which is
+/// weak but better than nothing on CPUs without RDRAND.
+pub fn tsc_jitter() -> u64 {
+    let mut value = 0u64;
+    let mut scratch = 0u64;
+    for i in 0..64 {
+        let start = unsafe { _rdtsc() };
+        for j in 0..(i % 7) + 1 {
+            scratch = core::hint::black_box(scratch.wrapping_mul(31).wrapping_add(j));
+        }
+        let delta = unsafe { _rdtsc() }.wrapping_sub(start);
+        value = value.rotate_left(5) ^ delta;
+    }
+    mix(value ^ unsafe { _rdtsc() })
+}
+
+/// Set once [`early_random`] had to fall back to TSC jitter
+static EARLY_WEAK: AtomicBool = AtomicBool::new(false);
+
+/// Returns a random value, from RDRAND if possible, otherwise from TSC jitter
+pub fn early_random() -> u64 {
+    rdrand().unwrap_or_else(|| {
+        EARLY_WEAK.store(true, Ordering::Relaxed);
+        tsc_jitter()
+    })
+}
+
+/// Returns whether any value from [`early_random`] came from TSC jitter
+pub fn early_was_weak() -> bool {
+    EARLY_WEAK.load(Ordering::Relaxed)
+}
+
+/// The splitmix64 finalizer, which spreads every input bit over the output
+fn mix(mut value: u64) -> u64 {
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
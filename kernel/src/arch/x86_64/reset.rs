@@ -0,0 +1,45 @@
+//! Resetting the machine
+//!
+//! The reset control register at port 0xCF9 resets every chipset since the PIIX, QEMU's included.
+//! If that doesn't work the keyboard controller pulses the reset line, and as a last resort a
+//! triple fault resets the CPU.
+
+use core::arch::asm;
+
+use crate::arch::x86_64::io::outb;
+
+const RESET_CONTROL: u16 = 0xCF9;
+/// Asks for a reset, with the CPU and the rest of the system both reset
+const RESET_CONTROL_HARD: u8 = 0x06;
+const RESET_CONTROL_SYS: u8 = 0x02;
+
+const KBC_COMMAND: u16 = 0x64;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+/// Claims the reset ports, before anything could reboot
+pub fn init() {
+    crate::dev::io_audit::claim_ports(RESET_CONTROL, 1, "reset");
+    crate::dev::io_audit::claim_ports(KBC_COMMAND, 1, "reset");
+}
+
+/// Resets the machine, with interrupts disabled
+///
+/// The displays are handed back to the firmware first, which expects the mode it set.
+pub fn reboot() -> ! {
+    crate::display::shutdown();
+    unsafe {
+        crate::arch::instructions::interrupts::disable();
+        outb(RESET_CONTROL, RESET_CONTROL_SYS);
+        outb(RESET_CONTROL, RESET_CONTROL_HARD);
+    }
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+    unsafe { outb(KBC_COMMAND, KBC_PULSE_RESET) };
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+    // An empty IDT turns the breakpoint into a triple fault
+    let idtr = [0u64; 2];
+    unsafe { asm!("lidt [{}]", "int3", in(reg) &idtr, options(noreturn)) }
+}
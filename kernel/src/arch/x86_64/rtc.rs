@@ -0,0 +1,142 @@
+//! CMOS Real Time Clock
+
+use crate::{
+    arch::x86_64::io::{inb, outb},
+    sync::Mutex,
+    time::date::DateTime,
+};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Setting this bit in the index port disables NMIs
+const NMI_DISABLE: u8 = 1 << 7;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Set in status register A while the RTC is updating its registers
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Set in status register B if the hours are in 24 hour format
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Set in status register B if the values are binary, rather than BCD
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Set in the hours register for PM times in 12 hour format
+const HOUR_PM: u8 = 1 << 7;
+
+/// Serializes access to the index/data port pair
+static CMOS: Mutex<()> = Mutex::new(());
+
+/// The raw register values of the RTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+unsafe fn read_register(reg: u8) -> u8 {
+    unsafe {
+        outb(CMOS_INDEX, NMI_DISABLE | reg);
+        inb(CMOS_DATA)
+    }
+}
+
+unsafe fn update_in_progress() -> bool {
+    unsafe { read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 }
+}
+
+unsafe fn read_raw(century_reg: Option<u8>) -> RawTime {
+    unsafe {
+        while update_in_progress() {
+            core::hint::spin_loop();
+        }
+        RawTime {
+            second: read_register(REG_SECONDS),
+            minute: read_register(REG_MINUTES),
+            hour: read_register(REG_HOURS),
+            day: read_register(REG_DAY),
+            month: read_register(REG_MONTH),
+            year: read_register(REG_YEAR),
+            century: century_reg.map(|reg| read_register(reg)).unwrap_or(0),
+        }
+    }
+}
+
+const fn from_bcd(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Returns the CMOS register holding the century, as reported by the FADT
+fn century_register() -> Option<u8> {
+    crate::acpi::with_tables(|tables| {
+        let fadt = tables.find_raw(b"FACP")?;
+        // The century field is at offset 108, and is zero when not supported
+        fadt.bytes().get(108).copied().filter(|&reg| reg != 0)
+    })
+    .flatten()
+}
+
+/// Reads the current date and time from the RTC
+///
+/// The registers are read until two consecutive reads agree, so a read can't straddle an update.
+pub fn read() -> DateTime {
+    let century_reg = century_register();
+    let _guard = CMOS.lock();
+    crate::dev::io_audit::claim_ports(CMOS_INDEX, 2, "rtc");
+
+    let (raw, status_b) = unsafe {
+        let mut raw = read_raw(century_reg);
+        loop {
+            let next = read_raw(century_reg);
+            if next == raw {
+                break;
+            }
+            raw = next;
+        }
+        (raw, read_register(REG_STATUS_B))
+    };
+
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut time = raw;
+    time.hour &= !HOUR_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        time.second = from_bcd(time.second);
+        time.minute = from_bcd(time.minute);
+        time.hour = from_bcd(time.hour);
+        time.day = from_bcd(time.day);
+        time.month = from_bcd(time.month);
+        time.year = from_bcd(time.year);
+        time.century = from_bcd(time.century);
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight, and 12 PM is noon
+        time.hour %= 12;
+        if pm {
+            time.hour += 12;
+        }
+    }
+
+    let year = match time.century {
+        0 => 2000 + time.year as u16,
+        century => century as u16 * 100 + time.year as u16,
+    };
+
+    DateTime {
+        year,
+        month: time.month,
+        day: time.day,
+        hour: time.hour,
+        minute: time.minute,
+        second: time.second,
+    }
+}
@@ -0,0 +1,32 @@
+//! PC speaker
+//!
+//! The speaker plays the square wave of channel 2 of the [`pit`](super::pit). It works without any
+//! console or driver, which makes it the last resort to tell that the kernel panicked.
+
+use core::time::Duration;
+
+use crate::{arch::x86_64::pit, time::tsc};
+
+/// Spin loops per millisecond when there is no TSC to time a beep with, a rough guess
+const SPINS_PER_MS: u64 = 100_000;
+
+/// Plays a tone of `frequency` Hz for `duration`, busy waiting until it is over
+///
+/// Without a calibrated TSC the duration is only approximate.
+pub fn beep(frequency: u32, duration: Duration) {
+    // SAFETY: Channel 2 is only used otherwise by calibrations during boot
+    unsafe { pit::start_tone(frequency) };
+    let khz = tsc::frequency_khz();
+    if khz != 0 {
+        let cycles = (duration.as_nanos() * khz as u128 / 1_000_000) as u64;
+        let start = tsc::read();
+        while tsc::read() - start < cycles {
+            core::hint::spin_loop();
+        }
+    } else {
+        for _ in 0..duration.as_millis() as u64 * SPINS_PER_MS {
+            core::hint::spin_loop();
+        }
+    }
+    pit::stop_tone();
+}
@@ -0,0 +1,193 @@
+//! SYSCALL/SYSRET entry
+//!
+//! SYSCALL enters the kernel with the user stack still loaded, so the entry swaps GSBASE to reach
+//! the per-CPU area, switches to the per-CPU syscall stack, and hands a [`SyscallFrame`] to the
+//! generic dispatcher.
+//!
+//! Interrupt entry doesn't swap GSBASE yet, so ring 3 code runs with interrupts disabled for now.
+
+use core::{arch::naked_asm, mem::offset_of};
+
+use crate::{
+    arch::{VirtAddr, registers::msr::Msr, x86_64::core::gdt::GDT},
+    percpu::CpuLocal,
+};
+
+const IA32_EFER: Msr = Msr::new(0xC000_0080);
+const IA32_STAR: Msr = Msr::new(0xC000_0081);
+const IA32_LSTAR: Msr = Msr::new(0xC000_0082);
+const IA32_FMASK: Msr = Msr::new(0xC000_0084);
+
+/// System Call Extensions, which enables SYSCALL/SYSRET
+const EFER_SCE: u64 = 1 << 0;
+
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_DF: u64 = 1 << 10;
+const RFLAGS_AC: u64 = 1 << 18;
+/// Bit 1 of RFLAGS is reserved and always set
+const RFLAGS_RESERVED: u64 = 1 << 1;
+
+/// The registers saved by the SYSCALL entry
+///
+/// The layout matches the order the entry pushes them in.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallFrame {
+    /// The syscall number, replaced by the return value
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    /// The user instruction pointer
+    pub rcx: u64,
+    /// The user RFLAGS
+    pub r11: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    pub fn number(&self) -> usize {
+        self.rax as usize
+    }
+
+    /// Returns the arguments in the order of the syscall ABI
+    pub fn args(&self) -> [usize; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9].map(|arg| arg as usize)
+    }
+}
+
+/// Enables SYSCALL/SYSRET on the current CPU
+///
+/// # Safety
+/// The GDT and the per-CPU area of the current CPU must be initialized.
+pub unsafe fn init() {
+    let (kernel_code, user_data) = {
+        let gdt = GDT.lock();
+        (gdt.1.kernel_code.0 as u64, gdt.1.user_data.0 as u64)
+    };
+    // SYSCALL loads CS from STAR[47:32] and SS 8 bytes above it, SYSRET loads SS from
+    // STAR[63:48] + 8 and CS from STAR[63:48] + 16
+    let sysret_base = (user_data & !0b11) - 8;
+
+    let (mut efer, mut star, mut lstar, mut fmask) = (IA32_EFER, IA32_STAR, IA32_LSTAR, IA32_FMASK);
+    unsafe {
+        efer.write(efer.read() | EFER_SCE);
+        star.write((sysret_base << 48) | (kernel_code << 32));
+        lstar.write(syscall_entry as *const () as u64);
+        fmask.write(RFLAGS_TF | RFLAGS_IF | RFLAGS_DF | RFLAGS_AC);
+    }
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn syscall_entry() {
+    naked_asm!(
+        "swapgs",
+        "mov gs:[{user_rsp}], rsp",
+        "mov rsp, gs:[{syscall_stack}]",
+        // Build the `SyscallFrame`, in reverse
+        "push qword ptr gs:[{user_rsp}]",
+        "push r11",
+        "push rcx",
+        "push r9",
+        "push r8",
+        "push r10",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rax",
+        "mov rdi, rsp",
+        "call {dispatch}",
+        "pop rax",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop r10",
+        "pop r8",
+        "pop r9",
+        "pop rcx",
+        "pop r11",
+        "swapgs",
+        "pop rsp",
+        "sysretq",
+        user_rsp = const offset_of!(CpuLocal, user_rsp),
+        syscall_stack = const offset_of!(CpuLocal, syscall_stack),
+        dispatch = sym dispatch,
+    );
+}
+
+extern "C" fn dispatch(frame: &mut SyscallFrame) {
+    frame.rax = crate::syscall::dispatch(frame.number(), frame.args()) as u64;
+}
+
+/// Runs code in ring 3 until it makes the exit syscall, returning the exit code
+///
+/// # Safety
+/// The code and stack must be mapped user accessible in the current address space, and
+/// SYSCALL must be initialized on the current CPU.
+pub unsafe fn enter_user(entry: VirtAddr, stack: VirtAddr) -> i64 {
+    unsafe { enter_user_inner(entry.as_u64(), stack.as_u64(), RFLAGS_RESERVED) }
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn enter_user_inner(entry: u64, stack: u64, rflags: u64) -> i64 {
+    naked_asm!(
+        // Save the callee saved registers and flags, `exit_user` restores them when the payload exits
+        "pushfq",
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov gs:[{user_exit_rsp}], rsp",
+        "mov rcx, rdi",
+        "mov r11, rdx",
+        "mov rsp, rsi",
+        // Don't leak kernel values to the payload
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "swapgs",
+        "sysretq",
+        user_exit_rsp = const offset_of!(CpuLocal, user_exit_rsp),
+    );
+}
+
+/// Returns from [`enter_user`] with the given exit code
+///
+/// # Safety
+/// Must be called from a syscall made by code started with [`enter_user`] on the current CPU.
+pub unsafe fn exit_user(code: i64) -> ! {
+    unsafe { exit_user_inner(code) }
+}
+
+#[unsafe(naked)]
+unsafe extern "C" fn exit_user_inner(code: i64) -> ! {
+    naked_asm!(
+        "mov rsp, gs:[{user_exit_rsp}]",
+        "mov rax, rdi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "popfq",
+        "ret",
+        user_exit_rsp = const offset_of!(CpuLocal, user_exit_rsp),
+    );
+}
@@ -0,0 +1,261 @@
+//! NMI watchdog
+//!
+//! The first performance counter of each CPU counts unhalted cycles, and the local APIC turns its
+//! overflow into an NMI about every [`PERIOD_MS`]. The NMI checks that the heartbeat of the CPU
+//! advanced since the last one. A CPU whose heartbeat hasn't moved for [`THRESHOLD_NS`] is
+//! spinning with interrupts disabled or deadlocked on a lock, and since NMIs can't be masked, the
+//! handler still runs and panics with the registers and backtrace of the stuck code. Every NMI
+//! also records where the CPU is for the [latency budgets](crate::sched::latency) it overran.
+//!
+//! The heartbeat is [`touch`]ed by the scheduler tick, so code that blocks for long with
+//! interrupts enabled, like waiting for a disk or the network, isn't reported, only code that
+//! keeps them disabled. The main loop and [`net::poll_until`](crate::net::poll_until) touch it as
+//! well, for CPUs without a tick. Halted CPUs don't count cycles, so idle CPUs never fire. Only the
+//! architectural performance monitoring of Intel CPUs is supported, which some hypervisors don't
+//! expose. Booting with `nowatchdog` disables the watchdog.
+
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{
+    arch::{
+        registers::msr::Msr,
+        x86_64::{apic, core::idt::ExceptionFrame, core::idt::Report},
+    },
+    percpu::{self, MAX_CPUS, PerCpu},
+    sched::latency,
+    sync::Once,
+    time::tsc,
+};
+
+/// The approximate time between NMIs
+pub const PERIOD_MS: u64 = 1000;
+/// How long a heartbeat may stall before the CPU is considered locked up
+pub const THRESHOLD_NS: u64 = 10_000_000_000;
+
+/// The architectural event counting unhalted core cycles
+const EVENT_CYCLES: u64 = 0x3C;
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogError {
+    /// The CPU has no architectural performance counter for unhalted cycles
+    NoPerfmon,
+    /// The local APIC isn't initialized, so overflows can't raise NMIs
+    NoApic,
+    /// The TSC isn't calibrated, so the period is unknown
+    NoTsc,
+}
+
+impl fmt::Display for WatchdogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NoPerfmon => "no performance counter for unhalted cycles",
+            Self::NoApic => "local APIC is not initialized",
+            Self::NoTsc => "TSC is not calibrated",
+        })
+    }
+}
+
+impl core::error::Error for WatchdogError {}
+
+#[derive(Debug, Clone, Copy)]
+struct Perfmon {
+    version: u8,
+    /// The width of the counters in bits
+    width: u8,
+    /// The cycles between overflows
+    period: u64,
+}
+
+impl Perfmon {
+    /// Reads CPUID leaf 0xA, which describes architectural performance monitoring
+    fn detect() -> Option<Self> {
+        if __cpuid(0).eax < 0xA {
+            return None;
+        }
+        let leaf = __cpuid_count(0xA, 0);
+        let version = leaf.eax as u8;
+        let counters = (leaf.eax >> 8) as u8;
+        let width = (leaf.eax >> 16) as u8;
+        let events = (leaf.eax >> 24) as u8;
+        // A set bit in EBX means the event is not available
+        if version == 0 || counters == 0 || events == 0 || leaf.ebx & 1 != 0 {
+            return None;
+        }
+        Some(Self {
+            version,
+            width,
+            period: 0,
+        })
+    }
+
+    /// Returns whether the counter overflowed since it was armed
+    fn overflowed(&self) -> bool {
+        // SAFETY: The counter exists if the watchdog is running
+        let count = unsafe { Msr::IA32_PMC0.read() };
+        // The counter starts negative, and wraps around to a small positive value
+        count & (1 << (self.width - 1)) == 0
+    }
+
+    /// Starts the counter, to overflow after the period
+    ///
+    /// # Safety
+    /// The CPU must support the architectural performance counters.
+    unsafe fn arm(&self) {
+        let (mut evtsel, mut pmc) = (Msr::IA32_PERFEVTSEL0, Msr::IA32_PMC0);
+        unsafe {
+            evtsel.write(0);
+            // Only the low 32 bits can be written, the rest is sign extended
+            pmc.write(self.period.wrapping_neg());
+            if self.version >= 2 {
+                let (mut ovf_ctrl, mut global_ctrl) = (Msr::IA32_PERF_GLOBAL_OVF_CTRL, Msr::IA32_PERF_GLOBAL_CTRL);
+                ovf_ctrl.write(1);
+                global_ctrl.write(global_ctrl.read() | 1);
+            }
+            evtsel.write(EVENT_CYCLES | EVTSEL_USR | EVTSEL_OS | EVTSEL_INT | EVTSEL_EN);
+        }
+    }
+}
+
+struct CpuWatchdog {
+    enabled: AtomicBool,
+    heartbeat: AtomicU64,
+    /// The heartbeat at the last NMI
+    seen: AtomicU64,
+    /// When the heartbeat last changed, in TSC nanoseconds
+    seen_at: AtomicU64,
+    nmis: AtomicU64,
+}
+
+impl CpuWatchdog {
+    const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            heartbeat: AtomicU64::new(0),
+            seen: AtomicU64::new(0),
+            seen_at: AtomicU64::new(0),
+            nmis: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns how long the heartbeat has stalled at `now`, if it is longer than [`THRESHOLD_NS`]
+    fn check(&self, now: u64) -> Option<u64> {
+        let heartbeat = self.heartbeat.load(Ordering::Relaxed);
+        if self.seen.swap(heartbeat, Ordering::Relaxed) != heartbeat {
+            self.seen_at.store(now, Ordering::Relaxed);
+            return None;
+        }
+        let stalled = now.saturating_sub(self.seen_at.load(Ordering::Relaxed));
+        (stalled >= THRESHOLD_NS).then_some(stalled)
+    }
+}
+
+static PERFMON: Once<Perfmon> = Once::new();
+static WATCHDOGS: PerCpu<CpuWatchdog> = PerCpu::new([const { CpuWatchdog::new() }; MAX_CPUS]);
+
+/// Signals that the current CPU is making progress
+pub fn touch() {
+    WATCHDOGS.get().heartbeat.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Starts the watchdog on the current CPU
+///
+/// # Safety
+/// Must be called once per CPU, after the local APIC is initialized and the TSC is calibrated.
+pub unsafe fn init() -> Result<(), WatchdogError> {
+    let lapic = apic::local_apic().ok_or(WatchdogError::NoApic)?;
+    let khz = tsc::frequency_khz();
+    if khz == 0 {
+        return Err(WatchdogError::NoTsc);
+    }
+    let mut perfmon = Perfmon::detect().ok_or(WatchdogError::NoPerfmon)?;
+    // The counter runs at the core clock rather than the TSC rate, which is close enough
+    perfmon.period = (khz * PERIOD_MS).min(i32::MAX as u64);
+    let perfmon = PERFMON.call_once(|| {
+        crate::stats::register("watchdog", dump_stats);
+        perfmon
+    });
+
+    let watchdog = WATCHDOGS.get();
+    watchdog
+        .seen
+        .store(watchdog.heartbeat.load(Ordering::Relaxed), Ordering::Relaxed);
+    watchdog.seen_at.store(tsc::monotonic_ns(), Ordering::Relaxed);
+    watchdog.enabled.store(true, Ordering::Release);
+    unsafe { perfmon.arm() };
+    lapic.set_perf_nmi();
+    Ok(())
+}
+
+/// Handles an NMI, returning whether it came from the watchdog
+///
+/// Panics if the heartbeat of the current CPU has stalled for longer than [`THRESHOLD_NS`].
+pub fn handle_nmi(frame: &ExceptionFrame) -> bool {
+    let (Some(perfmon), Some(lapic)) = (PERFMON.get(), apic::local_apic()) else {
+        return false;
+    };
+    let watchdog = WATCHDOGS.get();
+    if !watchdog.enabled.load(Ordering::Acquire) || !perfmon.overflowed() {
+        return false;
+    }
+    // SAFETY: The watchdog only runs with performance counters
+    unsafe { perfmon.arm() };
+    lapic.set_perf_nmi();
+    watchdog.nmis.fetch_add(1, Ordering::Relaxed);
+    latency::watchdog_check(frame);
+
+    let Some(stalled) = watchdog.check(tsc::monotonic_ns()) else {
+        return true;
+    };
+    // Only report once, the panic may take longer than a period
+    watchdog.enabled.store(false, Ordering::Relaxed);
+    panic!(
+        "watchdog: CPU {} locked up for {}s\n{}",
+        percpu::cpu_id(),
+        stalled / 1_000_000_000,
+        Report::new(frame)
+    )
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    for (cpu_id, watchdog) in WATCHDOGS.iter().enumerate() {
+        if !watchdog.enabled.load(Ordering::Relaxed) {
+            continue;
+        }
+        writeln!(
+            out,
+            "cpu{}: {} NMIs, heartbeat {}",
+            cpu_id,
+            watchdog.nmis.load(Ordering::Relaxed),
+            watchdog.heartbeat.load(Ordering::Relaxed)
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_long_poll_until() {
+        let watchdog = WATCHDOGS.get();
+        assert_eq!(watchdog.check(0), None);
+        // Every poll is half the threshold after the last one, the wait as a whole far longer
+        let mut polls = 0;
+        let done = crate::net::poll_until(u64::MAX, || {
+            polls += 1;
+            assert_eq!(watchdog.check(polls * THRESHOLD_NS / 2), None);
+            (polls == 10).then_some(())
+        });
+        assert_eq!(done, Some(()));
+        assert!(watchdog.check(polls * THRESHOLD_NS / 2 + THRESHOLD_NS).is_some());
+    }
+}
@@ -0,0 +1,301 @@
+//! In-kernel microbenchmarks
+//!
+//! Booting with `bench=1` runs every benchmark once the kernel is up, before the shell starts, and
+//! logs one line per result, so the same harness tracks performance across releases on QEMU and
+//! real hardware:
+//!
+//! ```text
+//! bench: begin version=0.0.1 commit=1a2b3c4 cpu="AMD Ryzen 7 5800X" clock=tsc
+//! bench: alloc_free/64 rounds=16 iterations=4096 min_ns=21 median_ns=23 max_ns=40
+//! bench: end
+//! ```
+//!
+//! Every round runs the operation `iterations` times, and the time per operation of each round is
+//! summarized, so a round that an interrupt lands in shows up in `max_ns` and not in `median_ns`.
+//! There is no scheduler or IPC yet, so context switches are measured between two work items of
+//! the [`workqueue`](crate::workqueue) that schedule each other, and messages through a locked
+//! queue between them stand in for channels.
+
+use core::{
+    alloc::Layout,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{collections::VecDeque, vec::Vec};
+
+use crate::{
+    arch::VirtAddr,
+    kprintln,
+    mm::{
+        address_space::{AddressSpace, AddressSpaceError},
+        page_table::PageTableFlags,
+    },
+    sync::Mutex,
+    time,
+    util::build_info,
+    workqueue::{self, Work},
+};
+
+/// The number of rounds every benchmark runs
+const ROUNDS: usize = 16;
+/// Where the page fault benchmark maps its pages, in an address space that is never activated
+const FAULT_BASE: VirtAddr = VirtAddr::new(0x1000_0000);
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchError {
+    OutOfMemory,
+    AddressSpace(AddressSpaceError),
+    /// A fault on a copy-on-write page wasn't resolved
+    Unresolved,
+    /// The work items stopped handing off to each other
+    Stalled,
+    /// A message came back different from how it was sent
+    Corrupted,
+}
+
+impl fmt::Display for BenchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfMemory => f.write_str("out of memory"),
+            Self::AddressSpace(err) => write!(f, "address space: {}", err),
+            Self::Unresolved => f.write_str("page fault not resolved"),
+            Self::Stalled => f.write_str("ping-pong stalled"),
+            Self::Corrupted => f.write_str("message corrupted"),
+        }
+    }
+}
+
+impl core::error::Error for BenchError {}
+
+impl From<AddressSpaceError> for BenchError {
+    fn from(err: AddressSpaceError) -> Self {
+        Self::AddressSpace(err)
+    }
+}
+
+/// Runs the operation `iterations` times, returning the nanoseconds it took, without any setup
+type BenchFn = fn(iterations: usize) -> Result<u64, BenchError>;
+
+struct Bench {
+    name: &'static str,
+    iterations: usize,
+    run: BenchFn,
+}
+
+const BENCHES: &[Bench] = &[
+    Bench::new("alloc_free/16", 4096, alloc_free::<16>),
+    Bench::new("alloc_free/64", 4096, alloc_free::<64>),
+    Bench::new("alloc_free/256", 4096, alloc_free::<256>),
+    Bench::new("alloc_free/1024", 4096, alloc_free::<1024>),
+    Bench::new("alloc_free/4096", 1024, alloc_free::<4096>),
+    Bench::new("alloc_free/16384", 256, alloc_free::<16384>),
+    Bench::new("alloc_free/65536", 64, alloc_free::<65536>),
+    Bench::new("switch/ping_pong", 1024, ping_pong),
+    Bench::new("ipc/round_trip", 1024, ipc_round_trip),
+    Bench::new("page_fault/cow_zero", 256, page_fault),
+];
+
+impl Bench {
+    const fn new(name: &'static str, iterations: usize, run: BenchFn) -> Self {
+        Self { name, iterations, run }
+    }
+}
+
+/// The time per operation over the rounds of a benchmark
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub name: &'static str,
+    pub rounds: usize,
+    pub iterations: usize,
+    pub min_ns: u64,
+    pub median_ns: u64,
+    pub max_ns: u64,
+}
+
+impl Summary {
+    /// Summarizes the nanoseconds per operation of every round, `samples` must not be empty
+    fn new(name: &'static str, iterations: usize, mut samples: Vec<u64>) -> Self {
+        samples.sort_unstable();
+        Self {
+            name,
+            rounds: samples.len(),
+            iterations,
+            min_ns: samples[0],
+            median_ns: samples[samples.len() / 2],
+            max_ns: samples[samples.len() - 1],
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} rounds={} iterations={} min_ns={} median_ns={} max_ns={}",
+            self.name, self.rounds, self.iterations, self.min_ns, self.median_ns, self.max_ns
+        )
+    }
+}
+
+/// Runs every benchmark if `bench=1` is on the command line, logging the results
+pub fn init(cmdline: crate::boot::Cmdline) {
+    if cmdline.get("bench") != Some("1") {
+        return;
+    }
+    #[cfg(target_arch = "x86_64")]
+    let cpu = crate::arch::x86_64::cpu::cpu_info().brand();
+    #[cfg(not(target_arch = "x86_64"))]
+    let cpu = "unknown";
+    kprintln!(
+        Info,
+        "bench: begin version={} commit={} cpu=\"{}\" clock={}",
+        build_info::VERSION,
+        build_info::COMMIT,
+        cpu,
+        time::current_clocksource().map_or("none", |source| source.name)
+    );
+    run_all(|name, result| match result {
+        Ok(summary) => kprintln!(Info, "bench: {}", summary),
+        Err(err) => kprintln!(Info, "bench: {} error=\"{}\"", name, err),
+    });
+    kprintln!(Info, "bench: end");
+}
+
+/// Runs every benchmark, reporting each one as it finishes
+pub fn run_all(mut report: impl FnMut(&'static str, Result<Summary, BenchError>)) {
+    for bench in BENCHES {
+        report(bench.name, run(bench));
+    }
+}
+
+fn run(bench: &Bench) -> Result<Summary, BenchError> {
+    // A round to warm up the caches and the heap
+    (bench.run)(bench.iterations)?;
+    let mut samples = Vec::with_capacity(ROUNDS);
+    for _ in 0..ROUNDS {
+        let elapsed = (bench.run)(bench.iterations)?;
+        samples.push(elapsed / bench.iterations as u64);
+    }
+    Ok(Summary::new(bench.name, bench.iterations, samples))
+}
+
+fn alloc_free<const SIZE: usize>(iterations: usize) -> Result<u64, BenchError> {
+    let layout = Layout::from_size_align(SIZE, 16).unwrap();
+    let start = time::monotonic_ns();
+    for _ in 0..iterations {
+        // SAFETY: The layout isn't empty
+        let ptr = core::hint::black_box(unsafe { alloc::alloc::alloc(layout) });
+        if ptr.is_null() {
+            return Err(BenchError::OutOfMemory);
+        }
+        unsafe { alloc::alloc::dealloc(ptr, layout) };
+    }
+    Ok(time::monotonic_ns() - start)
+}
+
+/// Round trips left to the ping-pong work items
+static REMAINING: AtomicU64 = AtomicU64::new(0);
+static PING: Work = Work::new(|_| _ = workqueue::schedule_work(&PONG), 0);
+static PONG: Work = Work::new(
+    |_| {
+        if REMAINING.fetch_sub(1, Ordering::Relaxed) > 1 {
+            workqueue::schedule_work(&PING);
+        }
+    },
+    0,
+);
+
+/// Two work items scheduling each other, a round trip is two switches to a task and back
+fn ping_pong(iterations: usize) -> Result<u64, BenchError> {
+    REMAINING.store(iterations as u64, Ordering::Relaxed);
+    let start = time::monotonic_ns();
+    workqueue::schedule_work(&PING);
+    workqueue::poll();
+    let elapsed = time::monotonic_ns() - start;
+    match REMAINING.load(Ordering::Relaxed) {
+        0 => Ok(elapsed),
+        _ => Err(BenchError::Stalled),
+    }
+}
+
+static REQUESTS: Mutex<VecDeque<u64>> = Mutex::new(VecDeque::new());
+static REPLIES: Mutex<VecDeque<u64>> = Mutex::new(VecDeque::new());
+static CORRUPTED: AtomicU64 = AtomicU64::new(0);
+static CLIENT: Work = Work::new(client, 0);
+static SERVER: Work = Work::new(server, 0);
+
+/// Checks the reply to the last request, and sends the next one
+fn client(_: usize) {
+    let reply = REPLIES.lock().pop_front();
+    let sent = REMAINING.load(Ordering::Relaxed);
+    if reply.is_some_and(|reply| reply != sent + 1) {
+        CORRUPTED.fetch_add(1, Ordering::Relaxed);
+    }
+    if REMAINING.fetch_sub(1, Ordering::Relaxed) > 0 {
+        REQUESTS.lock().push_back(sent - 1);
+        workqueue::schedule_work(&SERVER);
+    }
+}
+
+/// Answers every request with the message plus one
+fn server(_: usize) {
+    while let Some(message) = REQUESTS.lock().pop_front() {
+        REPLIES.lock().push_back(message + 1);
+    }
+    workqueue::schedule_work(&CLIENT);
+}
+
+/// A message sent from one work item to another and answered
+fn ipc_round_trip(iterations: usize) -> Result<u64, BenchError> {
+    REMAINING.store(iterations as u64, Ordering::Relaxed);
+    CORRUPTED.store(0, Ordering::Relaxed);
+    REPLIES.lock().clear();
+    let start = time::monotonic_ns();
+    workqueue::schedule_work(&CLIENT);
+    workqueue::poll();
+    let elapsed = time::monotonic_ns() - start;
+    if CORRUPTED.load(Ordering::Relaxed) != 0 {
+        return Err(BenchError::Corrupted);
+    }
+    // The last call of the client took the counter below zero
+    match REMAINING.load(Ordering::Relaxed) {
+        u64::MAX => Ok(elapsed),
+        _ => Err(BenchError::Stalled),
+    }
+}
+
+/// Writes to anonymous memory, each resolved by giving the page a zeroed frame
+///
+/// The fault handler of the address space is called directly, the time doesn't include the
+/// exception itself.
+fn page_fault(iterations: usize) -> Result<u64, BenchError> {
+    let mut space = AddressSpace::new()?;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    space.map_anonymous(FAULT_BASE, iterations * PAGE_SIZE, flags)?;
+    let start = time::monotonic_ns();
+    for page in 0..iterations {
+        if !space.handle_fault(FAULT_BASE + page * PAGE_SIZE)? {
+            return Err(BenchError::Unresolved);
+        }
+    }
+    Ok(time::monotonic_ns() - start)
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::{string::ToString, vec};
+
+    use super::*;
+
+    #[test]
+    fn bench_summary() {
+        let summary = Summary::new("alloc_free/64", 4096, vec![30, 21, 90, 23, 22]);
+        assert_eq!((summary.min_ns, summary.median_ns, summary.max_ns), (21, 23, 90));
+        assert_eq!(
+            summary.to_string(),
+            "alloc_free/64 rounds=5 iterations=4096 min_ns=21 median_ns=23 max_ns=90"
+        );
+    }
+}
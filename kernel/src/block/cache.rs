@@ -0,0 +1,505 @@
+//! Block cache
+//!
+//! Sits between file systems and a [`Disk`], so metadata that is read on every path lookup stays
+//! in memory instead of being read from the device again. The cache works in pages of
+//! [`PAGE_SIZE`] bytes, each a frame reached through the direct map, as the kernel heap is far
+//! too small to hold them:
+//! - A read that misses also reads up to [`READAHEAD_PAGES`] following pages, in one request.
+//! - Writes only go to the cache and mark pages dirty. [`poll`] writes them back every
+//!   [`WRITEBACK_INTERVAL_NS`], and writers do it themselves once half the cache is dirty.
+//!   [`sync`] writes everything back and flushes the devices.
+//! - Once a cache holds its capacity, the least recently used pages are evicted, and under memory
+//!   pressure clean pages are given back through [`crate::mm::shrink`].
+//!
+//! There is no scheduler, so the main loop stands in for the flush task.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+
+use crate::{
+    block::{BlockError, Disk},
+    kprintln,
+    mm::{
+        FRAME_ALLOCATOR,
+        page_table::KernelPageTable,
+        paging::{FrameDeallocator, PageSize, PhysFrame, Size4KiB},
+        shrink,
+    },
+    sync::{Mutex, RwLock},
+    time,
+};
+
+pub const PAGE_SIZE: usize = Size4KiB::SIZE;
+/// The pages a cache holds before it evicts, 4 MiB
+pub const DEFAULT_CAPACITY: usize = 1024;
+/// The pages read past a miss
+pub const READAHEAD_PAGES: usize = 8;
+/// How often [`poll`] writes back dirty pages
+pub const WRITEBACK_INTERVAL_NS: u64 = 5_000_000_000;
+
+/// Values kept in least recently used order
+struct Lru<T> {
+    /// The values and the tick they were last used at
+    entries: BTreeMap<u64, (u64, T)>,
+    /// The keys by the tick they were last used at
+    order: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl<T> Lru<T> {
+    const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn contains(&self, key: u64) -> bool {
+        self.entries.contains_key(&key)
+    }
+
+    /// Returns a value and marks it as the most recently used
+    fn get_mut(&mut self, key: u64) -> Option<&mut T> {
+        let (tick, value) = self.entries.get_mut(&key)?;
+        self.order.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.order.insert(self.tick, key);
+        Some(value)
+    }
+
+    fn insert(&mut self, key: u64, value: T) -> Option<T> {
+        let old = self.remove(key);
+        self.tick += 1;
+        self.entries.insert(key, (self.tick, value));
+        self.order.insert(self.tick, key);
+        old
+    }
+
+    fn remove(&mut self, key: u64) -> Option<T> {
+        let (tick, value) = self.entries.remove(&key)?;
+        self.order.remove(&tick);
+        Some(value)
+    }
+
+    /// Returns the least recently used key whose value matches
+    fn oldest(&self, mut f: impl FnMut(&T) -> bool) -> Option<u64> {
+        self.order.values().copied().find(|key| f(&self.entries[key].1))
+    }
+
+    fn values_mut(&mut self) -> impl Iterator<Item = (u64, &mut T)> {
+        self.entries.iter_mut().map(|(key, (_, value))| (*key, value))
+    }
+}
+
+/// A cached page, which owns its frame
+struct CachedPage {
+    frame: PhysFrame,
+    dirty: bool,
+}
+
+impl CachedPage {
+    fn bytes(&mut self) -> &mut [u8] {
+        let virt = KernelPageTable::direct_map_start() + self.frame.start_address().as_usize();
+        // SAFETY: The frame belongs to the page
+        unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u8>(), PAGE_SIZE) }
+    }
+}
+
+impl Drop for CachedPage {
+    fn drop(&mut self) {
+        unsafe { FRAME_ALLOCATOR.lock().deallocate_frame(self.frame) };
+    }
+}
+
+#[derive(Debug)]
+pub struct CacheStats {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    /// Pages read ahead of a miss
+    pub readahead: AtomicU64,
+    pub writebacks: AtomicU64,
+    pub evictions: AtomicU64,
+}
+
+impl CacheStats {
+    const fn new() -> Self {
+        Self {
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            readahead: AtomicU64::new(0),
+            writebacks: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+}
+
+struct Pages {
+    lru: Lru<CachedPage>,
+    dirty: usize,
+}
+
+/// The cache of a disk
+pub struct BlockCache {
+    disk: Arc<Disk>,
+    blocks_per_page: u64,
+    capacity: AtomicUsize,
+    pages: Mutex<Pages>,
+    stats: CacheStats,
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("disk", &self.disk.name())
+            .field("capacity", &self.capacity())
+            .finish()
+    }
+}
+
+impl BlockCache {
+    fn new(disk: Arc<Disk>, capacity: usize) -> Result<Self, BlockError> {
+        let block_size = disk.block_size();
+        if block_size > PAGE_SIZE || !PAGE_SIZE.is_multiple_of(block_size) {
+            return Err(BlockError::Unsupported);
+        }
+        Ok(Self {
+            blocks_per_page: (PAGE_SIZE / block_size) as u64,
+            disk,
+            capacity: AtomicUsize::new(capacity.max(READAHEAD_PAGES)),
+            pages: Mutex::new(Pages {
+                lru: Lru::new(),
+                dirty: 0,
+            }),
+            stats: CacheStats::new(),
+        })
+    }
+
+    pub fn disk(&self) -> &Arc<Disk> {
+        &self.disk
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    /// Changes how many pages the cache holds, evicting any beyond it at the next miss
+    pub fn set_capacity(&self, pages: usize) {
+        self.capacity.store(pages.max(READAHEAD_PAGES), Ordering::Relaxed);
+    }
+
+    /// Returns the number of cached and of dirty pages
+    pub fn usage(&self) -> (usize, usize) {
+        let pages = self.pages.lock();
+        (pages.lru.len(), pages.dirty)
+    }
+
+    /// Returns the number of device blocks in a page, less than a full page at the end of the disk
+    fn page_blocks(&self, page: u64) -> usize {
+        let start = page * self.blocks_per_page;
+        (self.disk.num_blocks() - start).min(self.blocks_per_page) as usize
+    }
+
+    fn page_count(&self) -> u64 {
+        self.disk.num_blocks().div_ceil(self.blocks_per_page)
+    }
+
+    fn write_back_page(&self, index: u64, page: &mut CachedPage) -> Result<(), BlockError> {
+        let len = self.page_blocks(index) * self.disk.block_size();
+        self.disk.write(index * self.blocks_per_page, &page.bytes()[..len])?;
+        page.dirty = false;
+        self.stats.writebacks.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Writes back every dirty page, in disk order
+    fn write_back(&self, pages: &mut Pages) -> Result<(), BlockError> {
+        let mut result = Ok(());
+        for (index, page) in pages.lru.values_mut() {
+            if !page.dirty {
+                continue;
+            }
+            match self.write_back_page(index, page) {
+                Ok(()) => pages.dirty -= 1,
+                Err(err) => result = Err(err),
+            }
+        }
+        result
+    }
+
+    /// Evicts the least recently used pages until `needed` more fit, writing back dirty ones
+    fn make_room(&self, pages: &mut Pages, needed: usize) -> Result<(), BlockError> {
+        while pages.lru.len() + needed > self.capacity() {
+            let Some(index) = pages.lru.oldest(|_| true) else {
+                break;
+            };
+            let mut page = pages.lru.remove(index).unwrap();
+            if page.dirty {
+                if let Err(err) = self.write_back_page(index, &mut page) {
+                    pages.lru.insert(index, page);
+                    return Err(err);
+                }
+                pages.dirty -= 1;
+            }
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Allocates frames for `count` consecutive pages, fewer if memory is short
+    fn allocate(&self, count: usize) -> Option<Vec<PhysFrame>> {
+        if count > 1
+            && let Some(start) = FRAME_ALLOCATOR.lock().allocate_contiguous(count)
+        {
+            let base = start.start_address();
+            return Some(
+                (0..count)
+                    .map(|idx| PhysFrame::from_start_address(base + idx * PAGE_SIZE))
+                    .collect(),
+            );
+        }
+        shrink::allocate_frame().map(|frame| alloc::vec![frame])
+    }
+
+    /// Makes sure a page is cached, reading it and the uncached pages after it
+    fn load<'a>(&self, pages: &'a mut Pages, index: u64) -> Result<&'a mut CachedPage, BlockError> {
+        if pages.lru.contains(index) {
+            self.stats.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(pages.lru.get_mut(index).unwrap());
+        }
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+        let wanted = (index..self.page_count())
+            .take(READAHEAD_PAGES)
+            .take_while(|page| *page == index || !pages.lru.contains(*page))
+            .count();
+        self.make_room(pages, wanted)?;
+        let frames = self.allocate(wanted).ok_or(BlockError::NoMemory)?;
+        let virt = KernelPageTable::direct_map_start() + frames[0].start_address().as_usize();
+        // Freed again if the read fails
+        let new: Vec<CachedPage> = frames
+            .into_iter()
+            .map(|frame| CachedPage { frame, dirty: false })
+            .collect();
+        let count = new.len();
+
+        // The frames are contiguous, so the pages are read with one request
+        let blocks: usize = (index..index + count as u64).map(|page| self.page_blocks(page)).sum();
+        // SAFETY: The frames were just allocated
+        let buf = unsafe { core::slice::from_raw_parts_mut(virt.as_mut_ptr::<u8>(), blocks * self.disk.block_size()) };
+        self.disk.read(index * self.blocks_per_page, buf)?;
+
+        // Inserted backwards, so the page that was asked for is the most recently used
+        for (idx, page) in new.into_iter().enumerate().rev() {
+            pages.lru.insert(index + idx as u64, page);
+        }
+        self.stats.readahead.fetch_add(count as u64 - 1, Ordering::Relaxed);
+        Ok(pages.lru.get_mut(index).unwrap())
+    }
+
+    /// Calls `f` with the part of each page that overlaps `len` bytes at `lba`
+    ///
+    /// `f` gets the page, the range of it, and the offset of that range in the request.
+    fn for_each_page(
+        &self,
+        pages: &mut Pages,
+        lba: u64,
+        len: usize,
+        mut f: impl FnMut(&mut Pages, u64, core::ops::Range<usize>, usize) -> Result<(), BlockError>,
+    ) -> Result<(), BlockError> {
+        let block_size = self.disk.block_size() as u64;
+        let mut offset = 0;
+        while offset < len {
+            let byte = lba * block_size + offset as u64;
+            let index = byte / PAGE_SIZE as u64;
+            let start = (byte % PAGE_SIZE as u64) as usize;
+            let end = (start + len - offset).min(PAGE_SIZE);
+            f(pages, index, start..end, offset)?;
+            offset += end - start;
+        }
+        Ok(())
+    }
+
+    /// Reads whole blocks starting at `lba`, from the cache where possible
+    pub fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.disk.check(lba, buf.len())?;
+        let mut pages = self.pages.lock();
+        self.for_each_page(&mut pages, lba, buf.len(), |pages, index, range, offset| {
+            let page = self.load(pages, index)?;
+            buf[offset..offset + range.len()].copy_from_slice(&page.bytes()[range]);
+            Ok(())
+        })
+    }
+
+    /// Writes whole blocks starting at `lba` to the cache, to be written back later
+    pub fn write(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.disk.check(lba, buf.len())?;
+        let mut pages = self.pages.lock();
+        self.for_each_page(&mut pages, lba, buf.len(), |pages, index, range, offset| {
+            let whole = range.start == 0 && range.end >= self.page_blocks(index) * self.disk.block_size();
+            // A page that is overwritten completely doesn't have to be read first
+            if whole && !pages.lru.contains(index) {
+                self.make_room(pages, 1)?;
+                let frame = shrink::allocate_frame().ok_or(BlockError::NoMemory)?;
+                pages.lru.insert(index, CachedPage { frame, dirty: false });
+            }
+            let page = self.load(pages, index)?;
+            page.bytes()[range.clone()].copy_from_slice(&buf[offset..offset + range.len()]);
+            if !page.dirty {
+                page.dirty = true;
+                pages.dirty += 1;
+            }
+            Ok(())
+        })?;
+        if pages.dirty > self.capacity() / 2 {
+            self.write_back(&mut pages)?;
+        }
+        Ok(())
+    }
+
+    /// Writes back every dirty page and flushes the disk
+    pub fn sync(&self) -> Result<(), BlockError> {
+        self.write_back(&mut self.pages.lock())?;
+        self.disk.flush()
+    }
+
+    /// Evicts up to `count` clean pages, returning how many
+    ///
+    /// Gives up right away if the cache is in use, as this runs under memory pressure.
+    pub fn shrink(&self, count: usize) -> usize {
+        let Some(mut pages) = self.pages.try_lock() else {
+            return 0;
+        };
+        let mut evicted = 0;
+        while evicted < count {
+            let Some(index) = pages.lru.oldest(|page| !page.dirty) else {
+                break;
+            };
+            drop(pages.lru.remove(index));
+            evicted += 1;
+        }
+        self.stats.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+        evicted
+    }
+}
+
+static CACHES: RwLock<Vec<Arc<BlockCache>>> = RwLock::new(Vec::new());
+
+/// Returns the cache of a disk, creating it if there is none
+///
+/// Fails if the block size of the disk doesn't divide the page size.
+pub fn cache(disk: &Arc<Disk>) -> Result<Arc<BlockCache>, BlockError> {
+    let mut caches = CACHES.write();
+    if let Some(cache) = caches.iter().find(|cache| Arc::ptr_eq(&cache.disk, disk)) {
+        return Ok(cache.clone());
+    }
+    let cache = Arc::new(BlockCache::new(disk.clone(), DEFAULT_CAPACITY)?);
+    if caches.is_empty() {
+        crate::stats::register("bcache", dump_stats);
+        shrink::register("bcache", shrink_all);
+    }
+    caches.push(cache.clone());
+    Ok(cache)
+}
+
+/// Returns all caches
+pub fn caches() -> Vec<Arc<BlockCache>> {
+    CACHES.read().clone()
+}
+
+/// Writes back every cache and flushes the disks, returning the first error
+pub fn sync() -> Result<(), BlockError> {
+    caches().iter().map(|cache| cache.sync()).fold(Ok(()), Result::and)
+}
+
+/// Writes back dirty pages every [`WRITEBACK_INTERVAL_NS`], from the main loop
+pub fn poll() {
+    static NEXT_WRITEBACK: AtomicU64 = AtomicU64::new(0);
+    let now = time::monotonic_ns();
+    let next = NEXT_WRITEBACK.load(Ordering::Relaxed);
+    if now < next
+        || NEXT_WRITEBACK
+            .compare_exchange(next, now + WRITEBACK_INTERVAL_NS, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return;
+    }
+    for cache in caches() {
+        if let Err(err) = cache.write_back(&mut cache.pages.lock()) {
+            kprintln!(Warn, "bcache: {}: writeback failed: {}", cache.disk.name(), err);
+        }
+    }
+}
+
+fn shrink_all(frames: usize) -> usize {
+    let Some(caches) = CACHES.try_read() else {
+        return 0;
+    };
+    let mut freed = 0;
+    for cache in caches.iter() {
+        freed += cache.shrink(frames - freed);
+        if freed >= frames {
+            break;
+        }
+    }
+    freed
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    for cache in caches() {
+        let (cached, dirty) = cache.usage();
+        let stats = &cache.stats;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "{}: {}/{} pages, {} dirty, hits={} misses={} readahead={} writebacks={} evictions={}",
+            cache.disk.name(),
+            cached,
+            cache.capacity(),
+            dirty,
+            load(&stats.hits),
+            load(&stats.misses),
+            load(&stats.readahead),
+            load(&stats.writebacks),
+            load(&stats.evictions)
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bcache_lru() {
+        let mut lru = Lru::new();
+        for key in 0..4 {
+            assert!(lru.insert(key, key * 10).is_none());
+        }
+        assert_eq!(lru.oldest(|_| true), Some(0));
+        assert_eq!(lru.get_mut(0), Some(&mut 0));
+        assert_eq!(lru.oldest(|_| true), Some(1));
+        assert_eq!(lru.oldest(|value| *value >= 20), Some(2));
+
+        assert_eq!(lru.insert(1, 11), Some(10));
+        assert_eq!(lru.oldest(|_| true), Some(2));
+        assert_eq!(lru.remove(2), Some(20));
+        assert_eq!(lru.remove(2), None);
+        assert_eq!((lru.len(), lru.oldest(|_| true)), (3, Some(3)));
+        let values: Vec<(u64, u64)> = lru.values_mut().map(|(key, value)| (key, *value)).collect();
+        assert_eq!(values, [(0, 0), (1, 11), (3, 30)]);
+    }
+}
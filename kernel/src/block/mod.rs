@@ -0,0 +1,308 @@
+//! Block device layer
+//!
+//! Drivers implement [`BlockDevice`] and register it with [`register`]. All I/O goes through the
+//! returned [`Disk`], which keeps per-device request counters and latency histograms. File
+//! systems read through the [`cache`] of a disk instead. Drivers learn that a request is done from
+//! a [`Completion`](crate::sync::Completion) their interrupt handler signals.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    stats::{histogram::LatencyHistogram, snapshot::Counters},
+    sync::RwLock,
+    time,
+};
+
+pub mod cache;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request is outside of the device
+    OutOfRange,
+    /// The buffer is not a multiple of the block size
+    InvalidBuffer,
+    /// The device reported an error
+    Io,
+    ReadOnly,
+    /// The device has a block size the layer above doesn't support
+    Unsupported,
+    /// There is no memory to cache the blocks
+    NoMemory,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::OutOfRange => "request out of range",
+            Self::InvalidBuffer => "buffer is not a multiple of the block size",
+            Self::Io => "I/O error",
+            Self::ReadOnly => "device is read only",
+            Self::Unsupported => "block size not supported",
+            Self::NoMemory => "out of memory",
+        })
+    }
+}
+
+impl core::error::Error for BlockError {}
+
+/// A device that stores data in fixed size blocks
+pub trait BlockDevice: Send + Sync {
+    fn block_size(&self) -> usize;
+    fn num_blocks(&self) -> u64;
+    /// Reads `buf.len() / block_size` blocks starting at `lba`
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+    /// Writes `buf.len() / block_size` blocks starting at `lba`
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOp {
+    Read,
+    Write,
+    Flush,
+}
+
+/// Counters for a single kind of request
+#[derive(Debug)]
+pub struct OpStats {
+    pub requests: AtomicU64,
+    pub bytes: AtomicU64,
+    pub errors: AtomicU64,
+    pub latency: LatencyHistogram,
+}
+
+impl OpStats {
+    const fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            latency: LatencyHistogram::new(),
+        }
+    }
+}
+
+/// I/O statistics of a block device
+#[derive(Debug)]
+pub struct BlockStats {
+    pub read: OpStats,
+    pub write: OpStats,
+    pub flush: OpStats,
+    /// Requests currently being processed
+    pub in_flight: AtomicU64,
+    /// The highest number of requests that were in flight at once
+    pub max_in_flight: AtomicU64,
+}
+
+impl BlockStats {
+    pub const fn new() -> Self {
+        Self {
+            read: OpStats::new(),
+            write: OpStats::new(),
+            flush: OpStats::new(),
+            in_flight: AtomicU64::new(0),
+            max_in_flight: AtomicU64::new(0),
+        }
+    }
+
+    pub fn op(&self, op: BlockOp) -> &OpStats {
+        match op {
+            BlockOp::Read => &self.read,
+            BlockOp::Write => &self.write,
+            BlockOp::Flush => &self.flush,
+        }
+    }
+
+    fn start(&self) -> u64 {
+        let depth = self.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_in_flight.fetch_max(depth, Ordering::Relaxed);
+        time::monotonic_ns()
+    }
+
+    fn complete(&self, op: BlockOp, start_ns: u64, bytes: usize, result: Result<(), BlockError>) {
+        let latency = time::monotonic_ns().saturating_sub(start_ns);
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+
+        let stats = self.op(op);
+        stats.requests.fetch_add(1, Ordering::Relaxed);
+        match result {
+            Ok(()) => _ = stats.bytes.fetch_add(bytes as u64, Ordering::Relaxed),
+            Err(_) => _ = stats.errors.fetch_add(1, Ordering::Relaxed),
+        }
+        stats.latency.record(latency);
+    }
+}
+
+impl Default for BlockStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for BlockStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "in_flight={} max_in_flight={}",
+            self.in_flight.load(Ordering::Relaxed),
+            self.max_in_flight.load(Ordering::Relaxed)
+        )?;
+        for (name, stats) in [("read", &self.read), ("write", &self.write), ("flush", &self.flush)] {
+            write!(
+                f,
+                "{}: requests={} bytes={} errors={} ",
+                name,
+                stats.requests.load(Ordering::Relaxed),
+                stats.bytes.load(Ordering::Relaxed),
+                stats.errors.load(Ordering::Relaxed)
+            )?;
+            write!(f, "{}", stats.latency)?;
+        }
+        Ok(())
+    }
+}
+
+/// A registered block device
+pub struct Disk {
+    name: &'static str,
+    dev: Arc<dyn BlockDevice>,
+    stats: BlockStats,
+}
+
+impl fmt::Debug for Disk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Disk")
+            .field("name", &self.name)
+            .field("block_size", &self.dev.block_size())
+            .field("num_blocks", &self.dev.num_blocks())
+            .finish()
+    }
+}
+
+impl Disk {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.dev.block_size()
+    }
+
+    pub fn num_blocks(&self) -> u64 {
+        self.dev.num_blocks()
+    }
+
+    pub fn stats(&self) -> &BlockStats {
+        &self.stats
+    }
+
+    fn check(&self, lba: u64, len: usize) -> Result<(), BlockError> {
+        let block_size = self.block_size();
+        if !len.is_multiple_of(block_size) {
+            return Err(BlockError::InvalidBuffer);
+        }
+        let end = lba
+            .checked_add((len / block_size) as u64)
+            .ok_or(BlockError::OutOfRange)?;
+        if end > self.num_blocks() {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    pub fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check(lba, buf.len())?;
+        let start = self.stats.start();
+        let result = self.dev.read_blocks(lba, buf);
+        self.stats.complete(BlockOp::Read, start, buf.len(), result);
+        result
+    }
+
+    pub fn write(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.check(lba, buf.len())?;
+        let start = self.stats.start();
+        let result = self.dev.write_blocks(lba, buf);
+        self.stats.complete(BlockOp::Write, start, buf.len(), result);
+        result
+    }
+
+    pub fn flush(&self) -> Result<(), BlockError> {
+        let start = self.stats.start();
+        let result = self.dev.flush();
+        self.stats.complete(BlockOp::Flush, start, 0, result);
+        result
+    }
+}
+
+static DISKS: RwLock<Vec<Arc<Disk>>> = RwLock::new(Vec::new());
+
+/// Registers a block device under the given name
+pub fn register(name: &'static str, dev: Arc<dyn BlockDevice>) -> Arc<Disk> {
+    let disk = Arc::new(Disk {
+        name,
+        dev,
+        stats: BlockStats::new(),
+    });
+    let mut disks = DISKS.write();
+    if disks.is_empty() {
+        crate::stats::register("block", dump_stats);
+        crate::stats::snapshot::register("block", counters);
+    }
+    disks.push(disk.clone());
+    disk
+}
+
+/// Removes a block device, returning it if it was registered
+pub fn unregister(name: &str) -> Option<Arc<Disk>> {
+    let mut disks = DISKS.write();
+    let idx = disks.iter().position(|disk| disk.name == name)?;
+    Some(disks.remove(idx))
+}
+
+/// Returns the block device with the given name
+pub fn get(name: &str) -> Option<Arc<Disk>> {
+    DISKS.read().iter().find(|disk| disk.name == name).cloned()
+}
+
+/// Returns all registered block devices
+pub fn disks() -> Vec<Arc<Disk>> {
+    DISKS.read().clone()
+}
+
+fn counters(counters: &mut Counters<'_>) {
+    for disk in disks() {
+        let stats = &disk.stats;
+        for (name, op) in [("read", &stats.read), ("write", &stats.write), ("flush", &stats.flush)] {
+            let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+            counters.counter(format_args!("{}.{}_requests", disk.name, name), load(&op.requests));
+            counters.counter(format_args!("{}.{}_bytes", disk.name, name), load(&op.bytes));
+            counters.counter(format_args!("{}.{}_errors", disk.name, name), load(&op.errors));
+        }
+        counters.gauge(
+            format_args!("{}.in_flight", disk.name),
+            stats.in_flight.load(Ordering::Relaxed),
+        );
+    }
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    for disk in disks() {
+        writeln!(
+            out,
+            "{}: {} blocks of {} bytes",
+            disk.name,
+            disk.num_blocks(),
+            disk.block_size()
+        )?;
+        write!(out, "{}", disk.stats)?;
+    }
+    Ok(())
+}
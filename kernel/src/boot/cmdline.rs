@@ -0,0 +1,66 @@
+//! Parsing of the kernel command line
+//!
+//! The command line is a whitespace separated list of flags like `nokaslr`, and options like
+//! `acpi=off`. When an option is given more than once, the last value wins, unless the option
+//! is meant to be repeated and read with [`Cmdline::get_all`].
+
+use core::fmt;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cmdline<'a>(&'a str);
+
+impl<'a> Cmdline<'a> {
+    pub const fn new(cmdline: &'a str) -> Self {
+        Self(cmdline)
+    }
+
+    /// Returns the arguments, as the name and the value for options
+    pub fn args(&self) -> impl Iterator<Item = (&'a str, Option<&'a str>)> + use<'a> {
+        self.0.split_whitespace().map(|arg| match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg, None),
+        })
+    }
+
+    /// Returns whether a flag is given
+    pub fn flag(&self, name: &str) -> bool {
+        self.args().any(|arg| arg == (name, None))
+    }
+
+    /// Returns the value of an option
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.get_all(name).last()
+    }
+
+    /// Returns every value of an option, for options that may be given more than once
+    pub fn get_all<'n>(&self, name: &'n str) -> impl Iterator<Item = &'a str> + use<'a, 'n> {
+        self.args()
+            .filter(move |(arg, _)| *arg == name)
+            .filter_map(|(_, value)| value)
+    }
+}
+
+impl fmt::Display for Cmdline<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cmdline_args() {
+        let cmdline = Cmdline::new("  nokaslr acpi=off root=/dev/a root=/dev/b empty=  headless=1");
+        assert!(cmdline.flag("nokaslr"));
+        assert!(!cmdline.flag("headless"));
+        assert!(!cmdline.flag("acpi"));
+        assert_eq!(cmdline.get("acpi"), Some("off"));
+        assert_eq!(cmdline.get("root"), Some("/dev/b"));
+        assert_eq!(cmdline.get("empty"), Some(""));
+        assert_eq!(cmdline.get("nokaslr"), None);
+        assert!(cmdline.get_all("root").eq(["/dev/a", "/dev/b"]));
+        assert_eq!(cmdline.args().count(), 6);
+    }
+}
@@ -1,16 +1,44 @@
+use core::fmt;
+
 use crate::{
     arch::PhysAddr,
     boot::memory_map::{BootstrapMemoryMap, MemoryMapEntry, MemoryRegionType},
     mm::paging::{FrameAllocator, PageSize, PhysFrame, Size4KiB},
 };
 
+/// The end of the memory the bootloader maps in the HHDM, which the mapped allocations come from
+const MAPPED_END: usize = 0x1_0000_0000;
+
 pub struct BootstrapFrameAllocator<'a> {
     memory_map: &'a mut BootstrapMemoryMap,
+    /// What the frames are currently allocated for, reported if memory runs out
+    phase: &'static str,
 }
 
 impl<'a> BootstrapFrameAllocator<'a> {
     pub fn new(memory_map: &'a mut BootstrapMemoryMap) -> Self {
-        Self { memory_map }
+        Self {
+            memory_map,
+            phase: "boot",
+        }
+    }
+
+    /// Sets what the following allocations are for
+    pub fn set_phase(&mut self, phase: &'static str) {
+        self.phase = phase;
+    }
+
+    /// Panics with what failed to be allocated, and the state of the memory map
+    #[cold]
+    pub fn exhausted(&self, request: Request) -> ! {
+        panic!(
+            "{}",
+            Exhausted {
+                phase: self.phase,
+                request,
+                entries: &self.memory_map.entries,
+            }
+        )
     }
 
     pub fn deallocate_region(&mut self, start: PhysAddr, length: usize) {
@@ -24,6 +52,74 @@ impl<'a> BootstrapFrameAllocator<'a> {
     }
 }
 
+/// An allocation made while building the kernel page table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    /// Frames anywhere in memory
+    Frames(usize),
+    /// Frames below 4GiB, which the bootloader maps in the HHDM
+    MappedFrames(usize),
+    /// Physically contiguous frames below 4GiB
+    Contiguous(usize),
+    /// Page table bookkeeping, from a bump allocator of the given size in bytes
+    Bookkeeping(usize),
+}
+
+/// A failed bootstrap allocation, formatted with a summary of the memory map
+pub struct Exhausted<'a> {
+    pub phase: &'static str,
+    pub request: Request,
+    pub entries: &'a [MemoryMapEntry],
+}
+
+impl fmt::Display for Exhausted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let usable = || {
+            self.entries
+                .iter()
+                .filter(|entry| entry.ty() == MemoryRegionType::Usable)
+        };
+        let frames = |entry: &MemoryMapEntry| entry.length() / Size4KiB::SIZE;
+        let mapped = || usable().filter(|entry| entry.base().as_usize() < MAPPED_END);
+
+        write!(f, "boot: out of memory while mapping {}: ", self.phase)?;
+        match self.request {
+            Request::Frames(count) => writeln!(
+                f,
+                "requested {} frames, {} available",
+                count,
+                usable().map(frames).sum::<usize>()
+            )?,
+            Request::MappedFrames(count) => writeln!(
+                f,
+                "requested {} frames below 4GiB, {} available",
+                count,
+                mapped().map(frames).sum::<usize>()
+            )?,
+            Request::Contiguous(count) => writeln!(
+                f,
+                "requested {} contiguous frames below 4GiB, the largest run is {}",
+                count,
+                mapped().map(frames).max().unwrap_or(0)
+            )?,
+            // The bump allocator is sized from an estimate, so this is a bug rather than a lack of memory
+            Request::Bookkeeping(size) => writeln!(f, "the {} byte bump allocator for the page tables is full", size)?,
+        }
+        writeln!(f, "memory map:")?;
+        for entry in self.entries {
+            writeln!(
+                f,
+                "  {:#014x}-{:#014x} {:?} ({} frames)",
+                entry.base(),
+                entry.end(),
+                entry.ty(),
+                frames(entry)
+            )?;
+        }
+        Ok(())
+    }
+}
+
 unsafe impl FrameAllocator<Size4KiB> for BootstrapFrameAllocator<'_> {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
         let mut i = 0;
@@ -60,7 +156,7 @@ impl BootstrapFrameAllocator<'_> {
             let region = &mut self.memory_map[i];
 
             // Skip regions above 4GiB
-            if region.base().as_u64() >= 0x0001_0000_0000 {
+            if region.base().as_usize() >= MAPPED_END {
                 break;
             }
 
@@ -92,7 +188,7 @@ impl BootstrapFrameAllocator<'_> {
             let region = &mut self.memory_map[i];
 
             // Skip regions above 4GiB
-            if region.base().as_u64() >= 0x0001_0000_0000 {
+            if region.base().as_usize() >= MAPPED_END {
                 break;
             }
 
@@ -116,3 +212,35 @@ impl BootstrapFrameAllocator<'_> {
         None
     }
 }
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn frame_allocator_exhausted() {
+        let entries = [
+            MemoryMapEntry::new(PhysAddr::new(0x1000), 0x9_E000, MemoryRegionType::Usable),
+            MemoryMapEntry::new(PhysAddr::new(0x10_0000), 0x10_0000, MemoryRegionType::Reserved),
+            MemoryMapEntry::new(PhysAddr::new(0x20_0000), 0x4_0000, MemoryRegionType::Usable),
+            MemoryMapEntry::new(PhysAddr::new(0x1_0000_0000), 0x10_0000, MemoryRegionType::Usable),
+        ];
+        let message = |request| {
+            Exhausted {
+                phase: "heap",
+                request,
+                entries: &entries,
+            }
+            .to_string()
+        };
+        let contiguous = message(Request::Contiguous(200));
+        assert!(contiguous.starts_with("boot: out of memory while mapping heap: requested 200 contiguous frames"));
+        assert!(contiguous.contains("below 4GiB, the largest run is 158\n"));
+        assert!(contiguous.contains("\n  0x000000100000-0x000000200000 Reserved (256 frames)\n"));
+        assert_eq!(contiguous.lines().count(), 6);
+        assert!(message(Request::MappedFrames(1)).contains("requested 1 frames below 4GiB, 222 available"));
+        assert!(message(Request::Frames(1)).contains("requested 1 frames, 478 available"));
+    }
+}
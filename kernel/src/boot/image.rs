@@ -0,0 +1,274 @@
+//! Kernel image self-verification
+//!
+//! After linking, the buildscript seals the image: it hashes every read only segment, the text
+//! and read only data, and writes the hashes into [`IMAGE_SEAL`], which lives in the data segment
+//! so writing it doesn't change what was hashed. [`verify`] hashes the loaded segments again at
+//! boot, which catches a bootloader loading a different or damaged kernel, or something writing
+//! over it before the kernel mappings were set up. With `image.verify=<seconds>` on the command
+//! line, [`poll`] verifies again at that interval.
+//!
+//! The bootloader applies the relocations of the image, which changes the words it relocates by
+//! the distance between the address the image was linked at and the one it was loaded at. Those
+//! words are hashed as if it was loaded at its link address, so the hashes match wherever it is.
+//! Images built without the buildscript aren't sealed, and aren't verified.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::vec::Vec;
+
+use crate::{boot::Cmdline, kprintln, time};
+
+/// The most segments that can be sealed
+const MAX_RANGES: usize = 4;
+/// The type of the relocations of a position independent kernel
+const R_X86_64_RELATIVE: u64 = 8;
+/// Set in the flags of executable segments
+const SEGMENT_EXECUTABLE: u64 = 1 << 0;
+
+/// A sealed segment of the image
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct SealedRange {
+    /// The link time address
+    start: u64,
+    len: u64,
+    /// The flags of the segment
+    flags: u64,
+    hash: u64,
+}
+
+/// The hashes of the image, written by the buildscript after linking, which finds it by its magic
+///
+/// The layout is shared with `crates/buildscript/src/seal.rs`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ImageSeal {
+    magic: [u8; 16],
+    /// The link time address and size of the relocations
+    rela: u64,
+    rela_size: u64,
+    /// The sealed ranges, 0 if the image isn't sealed, the first is the start of the image
+    count: u64,
+    ranges: [SealedRange; MAX_RANGES],
+}
+
+/// Only ever read volatile, as its contents change after compiling, and in the data segment
+/// rather than the read only data it would be in otherwise
+#[used]
+#[unsafe(link_section = ".data.image_seal")]
+static IMAGE_SEAL: ImageSeal = ImageSeal {
+    magic: *b"hadron-imageseal",
+    rela: 0,
+    rela_size: 0,
+    count: 0,
+    ranges: [SealedRange {
+        start: 0,
+        len: 0,
+        flags: 0,
+        hash: 0,
+    }; MAX_RANGES],
+};
+
+/// Framing the report of a mismatch, so it stands out of the log
+const BANNER: &str = "************************************************************";
+
+static INTERVAL_NS: AtomicU64 = AtomicU64::new(0);
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01B3))
+}
+
+/// Hashes the bytes of a segment linked at `start`, with the words at the link addresses in
+/// `relocs` moved back by `slide`
+///
+/// `relocs` must be sorted, the ones outside of the segment are skipped.
+fn hash(bytes: &[u8], start: u64, relocs: &[u64], slide: u64) -> u64 {
+    let mut hash = 0xCBF2_9CE4_8422_2325;
+    let mut pos = 0;
+    let first = relocs.partition_point(|reloc| *reloc < start);
+    for reloc in &relocs[first..] {
+        let offset = (reloc - start) as usize;
+        if offset + 8 > bytes.len() {
+            break;
+        }
+        if offset < pos {
+            continue;
+        }
+        hash = fnv1a(hash, &bytes[pos..offset]);
+        let word = u64::from_le_bytes(*bytes[offset..].first_chunk().unwrap()).wrapping_sub(slide);
+        hash = fnv1a(hash, &word.to_le_bytes());
+        pos = offset + 8;
+    }
+    fnv1a(hash, &bytes[pos..])
+}
+
+/// A segment whose hash doesn't match the seal
+#[derive(Debug, Clone, Copy)]
+pub struct Mismatch {
+    /// The address the segment is loaded at
+    pub start: usize,
+    pub len: usize,
+    pub executable: bool,
+    pub expected: u64,
+    pub found: u64,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} segment {:#x}..{:#x}: hash is {:016x}, sealed {:016x}",
+            if self.executable { "text" } else { "read only" },
+            self.start,
+            self.start + self.len,
+            self.found,
+            self.expected
+        )
+    }
+}
+
+/// Hashes the loaded image, returning the segments that don't match the seal
+///
+/// Returns `None` if the image isn't sealed.
+pub fn verify() -> Option<Vec<Mismatch>> {
+    unsafe extern "C" {
+        static _kernel_text_start: u8;
+    }
+    // SAFETY: The seal is a plain static
+    let seal = unsafe { core::ptr::read_volatile(&raw const IMAGE_SEAL) };
+    let count = (seal.count as usize).min(MAX_RANGES);
+    if count == 0 {
+        return None;
+    }
+    let slide = ((&raw const _kernel_text_start) as u64).wrapping_sub(seal.ranges[0].start);
+    // SAFETY: The relocations are in the read only data of the image
+    let rela = unsafe {
+        core::slice::from_raw_parts(
+            seal.rela.wrapping_add(slide) as *const [u64; 3],
+            seal.rela_size as usize / size_of::<[u64; 3]>(),
+        )
+    };
+    let mut relocs: Vec<u64> = rela
+        .iter()
+        .filter(|[_, info, _]| info & 0xFFFF_FFFF == R_X86_64_RELATIVE)
+        .map(|[offset, _, _]| *offset)
+        .collect();
+    relocs.sort_unstable();
+
+    let mut mismatches = Vec::new();
+    for range in &seal.ranges[..count] {
+        let start = range.start.wrapping_add(slide) as usize;
+        // SAFETY: The sealed ranges are the read only segments of the image, which stay mapped
+        let bytes = unsafe { core::slice::from_raw_parts(start as *const u8, range.len as usize) };
+        let found = hash(bytes, range.start, &relocs, slide);
+        if found != range.hash {
+            mismatches.push(Mismatch {
+                start,
+                len: range.len as usize,
+                executable: range.flags & SEGMENT_EXECUTABLE != 0,
+                expected: range.hash,
+                found,
+            });
+        }
+    }
+    Some(mismatches)
+}
+
+/// Verifies the image, logging loudly if it doesn't match the seal
+///
+/// Returns `false` on a mismatch.
+pub fn check() -> bool {
+    let Some(mismatches) = verify() else {
+        kprintln!(Info, "image: not sealed, skipping verification");
+        return true;
+    };
+    if mismatches.is_empty() {
+        kprintln!(Debug, "image: matches the seal");
+        return true;
+    }
+    kprintln!(Error, "image: {}", BANNER);
+    kprintln!(Error, "image: THE LOADED KERNEL DOESN'T MATCH THE IMAGE THAT WAS BUILT");
+    for mismatch in &mismatches {
+        kprintln!(Error, "image: {}", mismatch);
+    }
+    kprintln!(
+        Error,
+        "image: the bootloader loaded a different or damaged kernel, or something"
+    );
+    kprintln!(Error, "image: wrote over it, don't trust anything this kernel does");
+    kprintln!(Error, "image: {}", BANNER);
+    false
+}
+
+/// Verifies the image, and keeps verifying it at the interval given by `image.verify=<seconds>`
+pub fn init(cmdline: Cmdline) {
+    let sealed = check();
+    let Some(seconds) = cmdline.get("image.verify") else {
+        return;
+    };
+    match seconds.parse::<u64>() {
+        Ok(seconds) if sealed && seconds > 0 => {
+            INTERVAL_NS.store(seconds * 1_000_000_000, Ordering::Relaxed);
+        }
+        Ok(_) => {}
+        Err(_) => kprintln!(Warn, "image: invalid interval '{}'", seconds),
+    }
+}
+
+/// Verifies the image again once the interval has passed, from the main loop
+pub fn poll() {
+    static NEXT_CHECK: AtomicU64 = AtomicU64::new(0);
+    let interval = INTERVAL_NS.load(Ordering::Relaxed);
+    if interval == 0 {
+        return;
+    }
+    let now = time::monotonic_ns();
+    let next = NEXT_CHECK.load(Ordering::Relaxed);
+    if next == 0 {
+        NEXT_CHECK.store(now + interval, Ordering::Relaxed);
+        return;
+    }
+    if now < next
+        || NEXT_CHECK
+            .compare_exchange(next, now + interval, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return;
+    }
+    // Only report the first mismatch, the image won't heal
+    if !check() {
+        INTERVAL_NS.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_hash_relocations() {
+        // A segment linked at 0x1000 with relocated words at offsets 8 and 24
+        let mut linked = [0u8; 40];
+        linked[..8].copy_from_slice(b"hadron!!");
+        linked[8..16].copy_from_slice(&0x1020u64.to_le_bytes());
+        linked[24..32].copy_from_slice(&0x2000u64.to_le_bytes());
+        let relocs = [0x10, 0x1008, 0x1018, 0x5000];
+        let expected = fnv1a(0xCBF2_9CE4_8422_2325, &linked);
+        assert_eq!(hash(&linked, 0x1000, &relocs, 0), expected);
+
+        let slide = 0xFFFF_FFFF_8000_0000u64;
+        let mut loaded = linked;
+        for offset in [8, 24] {
+            let word = u64::from_le_bytes(*loaded[offset..].first_chunk().unwrap());
+            loaded[offset..offset + 8].copy_from_slice(&word.wrapping_add(slide).to_le_bytes());
+        }
+        assert_eq!(hash(&loaded, 0x1000, &relocs, slide), expected);
+        loaded[2] ^= 1;
+        assert_ne!(hash(&loaded, 0x1000, &relocs, slide), expected);
+    }
+}
@@ -1,18 +1,249 @@
+//! The boot information
+//!
+//! Every boot path fills in the same [`BootInfo`] from whatever its bootloader passes on, so the
+//! rest of the kernel never sees bootloader types. Everything is copied into fixed size tables,
+//! as bootloader memory isn't mapped once the kernel runs and there is no heap yet.
+
 use crate::dev::drivers::platform::fb::FramebufferInfoAddr;
 use crate::{
     arch::{PhysAddr, VirtAddr},
-    boot::memory_map::BootstrapMemoryMap,
+    boot::memory_map::{BootstrapMemoryMap, MemoryMapEntry, MemoryRegionType},
     sync::cell::RacyCell,
 };
 
+/// The most modules the bootloader can pass to the kernel
+const MAX_BOOT_MODULES: usize = 16;
+/// The longest path or command line of a boot module, longer ones are truncated
+const MAX_MODULE_STR: usize = 128;
+/// The longest kernel command line, longer ones are truncated
+const MAX_CMDLINE: usize = 1024;
+/// The most memory map entries kept, firmware rarely reports more than a few dozen
+const MAX_MEMORY_MAP_ENTRIES: usize = 256;
+/// The most framebuffers kept
+const MAX_FRAMEBUFFERS: usize = 4;
+/// The longest EDID kept, the base block and three extension blocks
+pub(super) const MAX_EDID: usize = 512;
+
+/// A string copied out of bootloader memory, which isn't mapped once the kernel runs
+#[derive(Clone, Copy)]
+pub struct BootStr<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> BootStr<N> {
+    pub(super) const fn empty() -> Self {
+        Self { bytes: [0; N], len: 0 }
+    }
+
+    pub(super) fn new(s: &str) -> Self {
+        let mut len = s.len().min(N);
+        // Don't truncate in the middle of a character
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0; N];
+        bytes[..len].copy_from_slice(&s.as_bytes()[..len]);
+        Self { bytes, len }
+    }
+
+    pub(super) fn as_str(&self) -> &str {
+        // Only ever created from a `str`, truncated at a character boundary
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap()
+    }
+}
+
+/// A file loaded by the bootloader, such as an initramfs or a driver
+#[derive(Clone, Copy)]
+pub struct BootModule {
+    path: BootStr<MAX_MODULE_STR>,
+    cmdline: BootStr<MAX_MODULE_STR>,
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: usize,
+}
+
+impl BootModule {
+    pub(super) const fn empty() -> Self {
+        Self {
+            path: BootStr::empty(),
+            cmdline: BootStr::empty(),
+            phys: PhysAddr::NULL,
+            virt: VirtAddr::NULL,
+            size: 0,
+        }
+    }
+
+    pub(super) fn new(path: &str, cmdline: &str, phys: PhysAddr, size: usize) -> Self {
+        Self {
+            path: BootStr::new(path),
+            cmdline: BootStr::new(cmdline),
+            phys,
+            virt: VirtAddr::NULL,
+            size,
+        }
+    }
+
+    /// Returns the path of the module on the boot volume, with a leading `/`
+    pub fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    /// Returns the name of the module, the last component of its path
+    pub fn name(&self) -> &str {
+        self.path().rsplit('/').next().unwrap_or_default()
+    }
+
+    /// Returns the command line given to the module in the bootloader configuration
+    pub fn cmdline(&self) -> &str {
+        self.cmdline.as_str()
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the contents of the module
+    pub fn data(&self) -> &'static [u8] {
+        if self.size == 0 {
+            return &[];
+        }
+        // SAFETY: The image was mapped read only during boot and is never unmapped
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.size) }
+    }
+
+    pub(super) fn set_virt(&mut self, virt: VirtAddr) {
+        self.virt = virt;
+    }
+}
+
+impl core::fmt::Debug for BootModule {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("BootModule")
+            .field("path", &self.path())
+            .field("cmdline", &self.cmdline())
+            .field("phys", &self.phys)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+/// A table of at most `N` items, filled in during boot
+pub struct BootTable<T: Copy, const N: usize> {
+    items: [T; N],
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> BootTable<T, N> {
+    pub const fn new(empty: T) -> Self {
+        Self {
+            items: [empty; N],
+            len: 0,
+        }
+    }
+
+    /// Adds an item, returning false if the table is full
+    pub(super) fn push(&mut self, item: T) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.items[self.len] = item;
+        self.len += 1;
+        true
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.items[..self.len]
+    }
+
+    pub(super) fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.items[..self.len]
+    }
+}
+
+pub type BootModules = BootTable<BootModule, MAX_BOOT_MODULES>;
+/// The memory map as reported by the bootloader
+pub type BootMemoryMap = BootTable<MemoryMapEntry, MAX_MEMORY_MAP_ENTRIES>;
+/// The framebuffers set up by the bootloader, the first one is used for the console
+pub type Framebuffers = BootTable<FramebufferInfoAddr, MAX_FRAMEBUFFERS>;
+/// The EDID of the display of the first framebuffer, truncated to whole blocks
+pub type BootEdid = BootTable<u8, MAX_EDID>;
+
+/// The firmware the bootloader was started from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareKind {
+    /// The bootloader didn't say
+    Unknown,
+    Bios,
+    Uefi32,
+    Uefi64,
+    Sbi,
+}
+
+impl core::fmt::Display for FirmwareKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Self::Unknown => "unknown",
+            Self::Bios => "BIOS",
+            Self::Uefi32 => "UEFI (32-bit)",
+            Self::Uefi64 => "UEFI (64-bit)",
+            Self::Sbi => "SBI",
+        })
+    }
+}
+
+/// Firmware tables passed on by the bootloader, kept for services used after boot
+///
+/// Only the addresses are kept, the tables themselves are in firmware memory which is never
+/// reclaimed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareInfo {
+    pub kind: FirmwareKind,
+    /// The EFI system table, when booted from UEFI
+    pub efi_system_table: Option<PhysAddr>,
+    /// The 32-bit SMBIOS 2.x entry point
+    pub smbios_32: Option<PhysAddr>,
+    /// The 64-bit SMBIOS 3.x entry point
+    pub smbios_64: Option<PhysAddr>,
+}
+
+impl FirmwareInfo {
+    pub const fn empty() -> Self {
+        Self {
+            kind: FirmwareKind::Unknown,
+            efi_system_table: None,
+            smbios_32: None,
+            smbios_64: None,
+        }
+    }
+}
+
+/// What the kernel needs to know from the bootloader
 pub struct BootInfo {
+    /// The offset of the bootloader's mapping of all physical memory, only used during boot
     pub hhdm_offset: u64,
     pub kernel_phys: PhysAddr,
     pub kernel_virt: VirtAddr,
-    pub memory_map: BootstrapMemoryMap,
-    pub rsdp_addr: PhysAddr,
+    pub memory_map: BootMemoryMap,
+    /// The memory map the bootstrap frame allocator allocates from, built from `memory_map`
+    pub bootstrap_map: BootstrapMemoryMap,
+    /// Missing without ACPI, or with `acpi=off`
+    pub rsdp_addr: Option<PhysAddr>,
     pub heap: (VirtAddr, usize),
-    pub framebuffer: FramebufferInfoAddr,
+    /// Framebuffer addresses are in the HHDM until the first one is mapped for the kernel, and
+    /// there are none when booting `headless`
+    pub framebuffers: Framebuffers,
+    /// Empty if the firmware couldn't read the EDID of the display
+    pub edid: BootEdid,
+    pub cmdline: BootStr<MAX_CMDLINE>,
+    pub modules: BootModules,
+    /// The ELF file of the kernel itself, mapped like the modules for its symbols
+    pub kernel_file: BootModule,
+    pub firmware: FirmwareInfo,
 }
 
 impl BootInfo {
@@ -21,10 +252,16 @@ impl BootInfo {
             hhdm_offset: 0,
             kernel_phys: PhysAddr::NULL,
             kernel_virt: VirtAddr::NULL,
-            memory_map: BootstrapMemoryMap::empty(),
-            rsdp_addr: PhysAddr::NULL,
+            memory_map: BootTable::new(MemoryMapEntry::new(PhysAddr::NULL, 0, MemoryRegionType::Reserved)),
+            bootstrap_map: BootstrapMemoryMap::empty(),
+            rsdp_addr: None,
             heap: (VirtAddr::NULL, 0),
-            framebuffer: FramebufferInfoAddr::default(),
+            framebuffers: BootTable::new(FramebufferInfoAddr::default()),
+            edid: BootTable::new(0),
+            cmdline: BootStr::empty(),
+            modules: BootTable::new(BootModule::empty()),
+            kernel_file: BootModule::empty(),
+            firmware: FirmwareInfo::empty(),
         }
     }
 }
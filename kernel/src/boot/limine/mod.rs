@@ -1,28 +1,34 @@
 use core::panic::PanicInfo;
 
 use alloc::boxed::Box;
+use limine::response::{FirmwareType, PagingMode};
 
 use crate::{
     arch::{
         PhysAddr, VirtAddr,
         instructions::interrupts,
-        registers::control::Cr3,
-        x86_64::{cpu::cpu_info, io::uart::Uart16550},
+        registers::control::{Cr3, Cr4, Cr4Flags},
+        x86_64::{io::uart::Uart16550, random},
     },
     boot::{
-        frame_allocator::BootstrapFrameAllocator,
-        info::BOOT_INFO,
+        Cmdline,
+        frame_allocator::{BootstrapFrameAllocator, Request},
+        info::{BOOT_INFO, BootModule, BootStr, FirmwareKind, MAX_EDID},
         memory_map::{MainMemoryMap, UsableRegion},
         page_table::BootstrapPageTable,
     },
     dev::drivers::platform::fb::FramebufferInfoAddr,
     kprintln,
     mm::{
+        FRAME_ALLOCATOR,
         allocator::{Locked, bump::BumpAllocator},
+        frame_allocator::KernelFrameAllocator,
+        layout::{self, KernelLayout},
         mappings,
         memory_map::MemoryMap,
         page_table::{KernelPageTable, PageTableFlags},
         paging::{FrameAllocator, PageSize, PhysFrame, Size2MiB, Size4KiB},
+        stack,
     },
     sync::cell::RacyCell,
     util::panicking::set_alternate_panic_handler,
@@ -66,11 +72,11 @@ macro_rules! boot_println {
 fn panic(info: &PanicInfo) -> ! {
     use core::fmt::Write;
     let mut serial = unsafe { Uart16550::new(0x3F8) };
-    unsafe { serial.init() };
+    unsafe { serial.init(crate::arch::x86_64::io::uart::DEFAULT_BAUD) };
     _ = writeln!(serial, "\n--- BOOT PANIC ---");
     _ = writeln!(serial, "message: {}", info);
     _ = writeln!(serial, "\n--- END PANIC ---");
-    loop {}
+    crate::util::panicking::resume(info)
 }
 
 pub unsafe fn entry() -> ! {
@@ -95,10 +101,25 @@ unsafe fn init_core() {
         );
     }
 
+    boot_println!("info: {}", crate::util::build_info::Banner);
     if let Some(info) = request::BOOTLOADER_INFO.response() {
         boot_println!("info: kernel booted from {} {}", info.name(), info.version());
     }
 
+    // The paging mode can't change, and addresses like the HHDM depend on it
+    let five_level = Cr4::read().contains(Cr4Flags::LA57);
+    VirtAddr::set_five_level(five_level);
+    if let Some(paging) = request::PAGING_MODE.response()
+        && (paging.paging_mode() == PagingMode::FIVE_LEVEL) != five_level
+    {
+        boot_println!("warn: paging mode response doesn't match CR4");
+    }
+    boot_println!(
+        "info: {}-level paging, {}-bit virtual addresses",
+        if five_level { 5 } else { 4 },
+        VirtAddr::bits()
+    );
+
     unsafe {
         boot_println!("info: initializing GDT...");
         crate::arch::x86_64::core::gdt::init();
@@ -106,12 +127,23 @@ unsafe fn init_core() {
         crate::arch::x86_64::core::idt::init();
         boot_println!("info: getting CPU info...");
         crate::arch::x86_64::cpu::init();
+        boot_println!("info: enabling FPU and SIMD...");
+        crate::arch::x86_64::fpu::init();
+        boot_println!("info: calibrating TSC...");
+        match crate::time::tsc::init() {
+            Ok(()) => boot_println!(
+                "info: TSC running at {} kHz (invariant: {})",
+                crate::time::tsc::frequency_khz(),
+                crate::time::tsc::is_invariant()
+            ),
+            Err(err) => boot_println!("warn: {}", err),
+        }
     }
 }
 
 unsafe fn init_serial() {
     let mut writer = unsafe { Uart16550::new(0x3F8) };
-    unsafe { writer.init() };
+    unsafe { writer.init(crate::arch::x86_64::io::uart::DEFAULT_BAUD) };
     SERIAL.replace(Some(writer));
     boot_println!("info: initialized serial COMM1");
 }
@@ -133,20 +165,52 @@ unsafe fn populate_boot_info() {
 
     match request::MEMORY_MAP.response() {
         Some(memory_map) => {
+            for entry in memory_map.entries() {
+                if !boot_info.memory_map.push(entry.into()) {
+                    panic!("memory map: too many entries");
+                }
+            }
             boot_info
-                .memory_map
-                .parse_from_limine(&memory_map, boot_info.hhdm_offset as usize);
-            boot_println!("memory_map: {:#?}", boot_info.memory_map);
+                .bootstrap_map
+                .init(boot_info.memory_map.as_slice(), boot_info.hhdm_offset as usize);
+            boot_println!("memory_map: {:#?}", boot_info.bootstrap_map);
         }
         None => panic!("bootloader did not send memory map response"),
     }
 
-    if let Some(framebuffers) = request::FRAMEBUFFER.response() {
+    if let Some(file) = request::EXECUTABLE_FILE.response() {
+        let file = file.executable_file();
+        boot_info.cmdline = BootStr::new(file.cmdline());
+        // Loaded into memory that is never reclaimed, like the modules
+        let phys = PhysAddr::new(file.address as usize - boot_info.hhdm_offset as usize);
+        boot_info.kernel_file = BootModule::new(file.path(), "", phys, file.size as usize);
+    }
+    let cmdline = Cmdline::new(boot_info.cmdline.as_str());
+
+    let baud = crate::dev::drivers::platform::serial::baud(cmdline);
+    if let Some(serial) = SERIAL.get_mut()
+        && serial.baud() != baud
+    {
+        boot_println!("info: switching serial to {} baud", baud);
+        serial.set_baud(baud);
+    }
+
+    if cmdline.flag("headless") {
+        boot_println!("info: booting headless, ignoring framebuffers");
+    } else if let Some(framebuffers) = request::FRAMEBUFFER.response() {
+        use crate::dev::drivers::platform::fb::PixelFormat;
         let framebuffers = framebuffers.framebuffers();
         boot_println!("info: found {} framebuffers", framebuffers.len());
-        if let Some(fb) = framebuffers.first() {
-            use crate::dev::drivers::platform::fb::{FramebufferInfoAddr, PixelFormat};
-            boot_info.framebuffer = FramebufferInfoAddr {
+        // SAFETY: Bootloader reclaimable memory isn't reclaimed before the boot info is filled in
+        if let Some(edid) = framebuffers.first().and_then(|fb| unsafe { fb.edid() }) {
+            // Only whole 128 byte blocks are of any use
+            let blocks = edid.len().min(MAX_EDID) / 128;
+            for byte in &edid[..blocks * 128] {
+                boot_info.edid.push(*byte);
+            }
+        }
+        for fb in framebuffers {
+            let fb = FramebufferInfoAddr {
                 width: fb.width() as u32,
                 height: fb.height() as u32,
                 pixel_format: PixelFormat::RGB,
@@ -154,22 +218,85 @@ unsafe fn populate_boot_info() {
                 bpp: (fb.bpp() / 8) as u32,
                 addr: fb.address() as *mut u8,
             };
+            if !boot_info.framebuffers.push(fb) {
+                boot_println!("warn: ignoring framebuffer, too many framebuffers");
+            }
         }
     } else {
         boot_println!("warn: bootloader did not send any framebuffers");
     }
 
-    match request::RSDP.response() {
-        Some(rsdp) => boot_info.rsdp_addr = PhysAddr::new(rsdp.address as usize),
-        None => panic!("bootloader did not send rsdp response"),
+    if cmdline.get("acpi") == Some("off") {
+        boot_println!("info: ACPI disabled on the command line");
+    } else if let Some(rsdp) = request::RSDP.response() {
+        boot_info.rsdp_addr = Some(PhysAddr::new(rsdp.address as usize));
+    } else {
+        boot_println!("warn: bootloader did not send an RSDP, booting without ACPI");
+    }
+
+    // With base revision 3, the firmware table addresses are physical
+    let firmware = &mut boot_info.firmware;
+    if let Some(firmware_type) = request::FIRMWARE_TYPE.response() {
+        firmware.kind = match firmware_type.firmware_type() {
+            FirmwareType::X86BIOS => FirmwareKind::Bios,
+            FirmwareType::UEFI32 => FirmwareKind::Uefi32,
+            FirmwareType::UEFI64 => FirmwareKind::Uefi64,
+            FirmwareType::SBI => FirmwareKind::Sbi,
+        };
+    }
+    if let Some(system_table) = request::EFI_SYSTEM_TABLE.response() {
+        firmware.efi_system_table = Some(PhysAddr::new(system_table.address as usize));
+    }
+    if let Some(smbios) = request::SMBIOS.response() {
+        firmware.smbios_32 = smbios.entry_32().map(|addr| PhysAddr::new(addr as usize));
+        firmware.smbios_64 = smbios.entry_64().map(|addr| PhysAddr::new(addr as usize));
+    }
+
+    if let Some(modules) = request::MODULE.response() {
+        for file in modules.modules() {
+            // Module addresses are in the HHDM, which the kernel page table doesn't keep
+            let phys = PhysAddr::new(file.address as usize - boot_info.hhdm_offset as usize);
+            let module = BootModule::new(file.path(), file.cmdline(), phys, file.size as usize);
+            if !boot_info.modules.push(module) {
+                boot_println!("warn: ignoring module {}, too many modules", file.path());
+            }
+        }
     }
 
     boot_println!("info: Boot Info");
     boot_println!(" - HHDM offset: {:#x}", boot_info.hhdm_offset);
     boot_println!(" - kernel virt: {:#x}", boot_info.kernel_virt);
     boot_println!(" - kernel phys: {:#x}", boot_info.kernel_phys);
-    boot_println!(" - memory map: {}b available", boot_info.memory_map.total_size());
-    boot_println!(" - RSDP address: {:#x}", boot_info.rsdp_addr);
+    boot_println!(" - memory map: {}b available", boot_info.bootstrap_map.total_size());
+    boot_println!(" - cmdline: '{}'", boot_info.cmdline.as_str());
+    if let Some(addr) = boot_info.rsdp_addr {
+        boot_println!(" - RSDP address: {:#x}", addr);
+    }
+    boot_println!(" - firmware: {}", boot_info.firmware.kind);
+    if let Some(addr) = boot_info.firmware.efi_system_table {
+        boot_println!(" - EFI system table: {:#x}", addr);
+    }
+    if let Some(addr) = boot_info.firmware.smbios_32 {
+        boot_println!(" - SMBIOS 2 entry point: {:#x}", addr);
+    }
+    if let Some(addr) = boot_info.firmware.smbios_64 {
+        boot_println!(" - SMBIOS 3 entry point: {:#x}", addr);
+    }
+    boot_println!(
+        " - kernel file: {} ({:#x}, {} bytes)",
+        boot_info.kernel_file.path(),
+        boot_info.kernel_file.phys_addr(),
+        boot_info.kernel_file.size()
+    );
+    for module in boot_info.modules.as_slice() {
+        boot_println!(
+            " - module: {} ({:#x}, {} bytes) '{}'",
+            module.path(),
+            module.phys_addr(),
+            module.size(),
+            module.cmdline()
+        );
+    }
 }
 
 /// Calculates the number of pages needed for the page table
@@ -184,49 +311,71 @@ fn calculate_pages_needed(pages: usize) -> usize {
 
 fn allocate_pages() -> ! {
     let boot_info = BOOT_INFO.get_mut();
-    let (mm_start, mm_len) = boot_info.memory_map.mapped_range();
-    let mut frame_allocator = BootstrapFrameAllocator::new(&mut boot_info.memory_map);
+    let (mm_start, mm_len) = boot_info.bootstrap_map.mapped_range();
+    let mut frame_allocator = BootstrapFrameAllocator::new(&mut boot_info.bootstrap_map);
+
+    // The regions have to move before anything is mapped at them
+    if !Cmdline::new(boot_info.cmdline.as_str()).flag("nokaslr") {
+        let phys_end = boot_info
+            .memory_map
+            .as_slice()
+            .iter()
+            .map(|entry| entry.base.as_usize() + entry.length)
+            .max()
+            .unwrap_or(0);
+        unsafe { layout::set_layout(KernelLayout::randomized(phys_end, random::early_random)) };
+    }
+    let layout = *layout::layout();
 
     let kernel_size = get_kernel_size();
     let mut pages_to_allocate = 0;
 
-    pages_to_allocate += calculate_pages_needed(kernel_size.0 / Size4KiB::SIZE as usize);
-    pages_to_allocate += calculate_pages_needed(kernel_size.1 / Size4KiB::SIZE as usize);
-    let stack_frames = request::KERNEL_STACK_SIZE / Size4KiB::SIZE as usize;
+    pages_to_allocate += calculate_pages_needed(kernel_size.0 / Size4KiB::SIZE);
+    pages_to_allocate += calculate_pages_needed(kernel_size.1 / Size4KiB::SIZE);
+    pages_to_allocate += calculate_pages_needed(kernel_size.2 / Size4KiB::SIZE);
+    let stack_frames = request::KERNEL_STACK_SIZE / Size4KiB::SIZE;
     pages_to_allocate += calculate_pages_needed(stack_frames);
-    const HEAP_SIZE: usize = 512 * 1024;
+    const HEAP_SIZE: usize = crate::config::HEAP_SIZE.next_multiple_of(Size4KiB::SIZE);
     let heap_frames = HEAP_SIZE / Size4KiB::SIZE;
-    pages_to_allocate += calculate_pages_needed(heap_frames as usize);
+    pages_to_allocate += calculate_pages_needed(heap_frames);
     let mmap_frames = mm_len.div_ceil(Size4KiB::SIZE);
-    pages_to_allocate += calculate_pages_needed(mmap_frames as usize);
-    {
-        let size = (boot_info.framebuffer.stride as usize) * (boot_info.framebuffer.height as usize);
-        pages_to_allocate += calculate_pages_needed(size.div_ceil(Size4KiB::SIZE as usize));
+    pages_to_allocate += calculate_pages_needed(mmap_frames);
+    if let Some(framebuffer) = boot_info.framebuffers.as_slice().first() {
+        let size = (framebuffer.stride as usize) * (framebuffer.height as usize);
+        pages_to_allocate += calculate_pages_needed(size.div_ceil(Size4KiB::SIZE));
+    }
+    for module in boot_info.modules.as_slice().iter().chain([&boot_info.kernel_file]) {
+        pages_to_allocate += calculate_pages_needed(module.size().div_ceil(Size4KiB::SIZE));
     }
 
-    for region in request::MEMORY_MAP.response().unwrap().entries() {
+    for region in boot_info.memory_map.as_slice() {
         if let Some(region) = UsableRegion::from_region(region) {
             pages_to_allocate += region.pages_needed();
         }
     }
 
-    let frame = frame_allocator.allocate_mapped_contiguous(pages_to_allocate).unwrap();
+    frame_allocator.set_phase("page table bookkeeping");
+    let frame = frame_allocator
+        .allocate_mapped_contiguous(pages_to_allocate)
+        .unwrap_or_else(|| frame_allocator.exhausted(Request::Contiguous(pages_to_allocate)));
     let allocator = Locked::new(unsafe {
         BumpAllocator::new(
             VirtAddr::new(frame.start_address().as_usize() + boot_info.hhdm_offset as usize),
             pages_to_allocate * Size4KiB::SIZE,
         )
     });
+    frame_allocator.set_phase("page table root");
     let mut page_table = BootstrapPageTable::new(boot_info.hhdm_offset as usize, &mut frame_allocator, &allocator);
 
     let start_phys = boot_info.kernel_phys;
     let kernel_virt = boot_info.kernel_virt;
     assert!(
-        (kernel_size.0 + kernel_size.1) < mappings::KERNEL_TEXT_SIZE,
+        (kernel_size.0 + kernel_size.1 + kernel_size.2) < mappings::KERNEL_TEXT_SIZE,
         "Kernel is too large\n"
     );
 
     // Map text section with execute permissions
+    frame_allocator.set_phase("kernel text");
     for i in 0..kernel_size.0 / Size4KiB::SIZE {
         let offset = i * Size4KiB::SIZE;
         page_table.map(
@@ -237,42 +386,65 @@ fn allocate_pages() -> ! {
         );
     }
 
-    // Map data section with writable permissions but no execute permissions
+    // Map rodata section without write or execute permissions
+    frame_allocator.set_phase("kernel rodata");
     for i in 0..kernel_size.1 / Size4KiB::SIZE {
         let offset = i * Size4KiB::SIZE + kernel_size.0;
         page_table.map(
             kernel_virt + offset,
             PhysFrame::from_start_address(start_phys + offset),
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
             &mut frame_allocator,
         );
     }
 
-    let stack_virt = mappings::KERNEL_STACK_START + mappings::TOTAL_KERNEL_STACK_SIZE - request::KERNEL_STACK_SIZE;
+    // Map data section with writable permissions but no execute permissions
+    frame_allocator.set_phase("kernel data");
+    for i in 0..kernel_size.2 / Size4KiB::SIZE {
+        let offset = i * Size4KiB::SIZE + kernel_size.0 + kernel_size.1;
+        page_table.map(
+            kernel_virt + offset,
+            PhysFrame::from_start_address(start_phys + offset),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            &mut frame_allocator,
+        );
+    }
+
+    frame_allocator.set_phase("boot stack");
+    let stack_virt = layout.stacks_end() - request::KERNEL_STACK_SIZE;
     for i in 0..stack_frames {
         let offset = i * Size4KiB::SIZE;
+        let frame = frame_allocator
+            .allocate_frame()
+            .unwrap_or_else(|| frame_allocator.exhausted(Request::Frames(stack_frames - i)));
+        let hhdm = VirtAddr::new(frame.start_address().as_usize() + boot_info.hhdm_offset as usize);
+        unsafe { stack::poison(hhdm, Size4KiB::SIZE) };
         page_table.map(
             stack_virt + offset,
-            frame_allocator.allocate_frame().unwrap(),
+            frame,
             PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
             &mut frame_allocator,
         );
     }
 
+    frame_allocator.set_phase("heap");
     for i in 0..heap_frames {
         let offset = i * Size4KiB::SIZE;
-        let frame = frame_allocator.allocate_frame().unwrap();
+        let frame = frame_allocator
+            .allocate_frame()
+            .unwrap_or_else(|| frame_allocator.exhausted(Request::Frames(heap_frames - i)));
         page_table.map(
-            mappings::KERNEL_HEAP_START + offset,
+            layout.heap + offset,
             frame,
             PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
             &mut frame_allocator,
         );
     }
-    boot_info.heap = (mappings::KERNEL_HEAP_START, HEAP_SIZE);
+    boot_info.heap = (layout.heap, HEAP_SIZE);
 
-    {
-        let framebuffer = &mut boot_info.framebuffer;
+    // Only the first framebuffer is used for now
+    frame_allocator.set_phase("framebuffer");
+    if let Some(framebuffer) = boot_info.framebuffers.as_mut_slice().first_mut() {
         let fb_virt = VirtAddr::new(framebuffer.addr as usize);
         let fb_phys = PhysAddr::new(fb_virt.as_usize() - boot_info.hhdm_offset as usize);
         let fb_pages = ((framebuffer.stride as usize) * (framebuffer.height as usize)).div_ceil(Size4KiB::SIZE);
@@ -292,7 +464,31 @@ fn allocate_pages() -> ! {
 
         framebuffer.addr = mappings::FRAMEBUFFER_START.as_mut_ptr();
     }
+    // Modules are packed one after another, each starting on a page boundary, followed by the kernel
+    frame_allocator.set_phase("boot modules");
+    let mut module_virt = mappings::BOOT_MODULES_START;
+    let modules = boot_info.modules.as_mut_slice().iter_mut();
+    for module in modules.chain([&mut boot_info.kernel_file]) {
+        let pages = module.size().div_ceil(Size4KiB::SIZE);
+        assert!(
+            module_virt + pages * Size4KiB::SIZE <= mappings::BOOT_MODULES_END,
+            "Boot modules are too large\n"
+        );
+        for i in 0..pages {
+            let offset = i * Size4KiB::SIZE;
+            page_table.map(
+                module_virt + offset,
+                PhysFrame::from_start_address(module.phys_addr() + offset),
+                PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+                &mut frame_allocator,
+            );
+        }
+        module.set_virt(module_virt);
+        module_virt += pages * Size4KiB::SIZE;
+    }
+
     // Allocate memory map
+    frame_allocator.set_phase("memory map");
     for i in 0..mmap_frames {
         let offset = i * Size4KiB::SIZE;
         page_table.map(
@@ -305,19 +501,20 @@ fn allocate_pages() -> ! {
         )
     }
 
+    frame_allocator.set_phase("direct map");
     let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
-    for region in request::MEMORY_MAP.response().unwrap().entries() {
+    for region in boot_info.memory_map.as_slice() {
         if let Some(region) = UsableRegion::from_region(region) {
             let mut start = region.base;
             for i in 0..region.f_pad_4kib {
-                let virt = KernelPageTable::DIRECT_MAP_START + start.as_usize();
+                let virt = KernelPageTable::direct_map_start() + start.as_usize();
                 page_table.map(virt, PhysFrame::from_start_address(start), flags, &mut frame_allocator);
                 start += i * Size4KiB::SIZE;
             }
 
             /*
             for i in 0..region.f_pad_2mib {
-                let virt = KernelPageTable::DIRECT_MAP_START + start.as_usize();
+                let virt = KernelPageTable::direct_map_start() + start.as_usize();
                 page_table.map(
                     virt,
                     PhysFrame::<Size2MiB>::from_start_address(start),
@@ -330,6 +527,7 @@ fn allocate_pages() -> ! {
         }
     }
 
+    frame_allocator.set_phase("direct map of the page tables");
     page_table.direct_map(&mut frame_allocator);
 
     let page_table_ptr = page_table.as_phys_addr().as_u64();
@@ -343,7 +541,7 @@ fn allocate_pages() -> ! {
             "push 0",
             "jmp {entry}",
             ptr = in(reg) page_table_ptr,
-            stack = in(reg) mappings::KERNEL_STACK_END.as_u64(),
+            stack = in(reg) layout.stacks_end().as_u64(),
             entry = sym stage_2,
             options(noreturn, preserves_flags)
         )
@@ -353,6 +551,7 @@ fn allocate_pages() -> ! {
 fn setup_platform_dev() {
     use crate::dev::{
         DEVICES,
+        devres::devres_release_all,
         platform::{PlatformDev, PlatformDevAddr, PlatformDevType},
     };
 
@@ -366,12 +565,13 @@ fn setup_platform_dev() {
         ));
     }
 
-    let fb = &BOOT_INFO.get_mut().framebuffer;
-    platform_devs.add_device(PlatformDev::new(
-        "efi_fb",
-        PlatformDevType::Framebuffer,
-        PlatformDevAddr::addr((fb as *const FramebufferInfoAddr) as usize),
-    ));
+    if let Some(fb) = BOOT_INFO.get().framebuffers.as_slice().first() {
+        platform_devs.add_device(PlatformDev::new(
+            "efi_fb",
+            PlatformDevType::Framebuffer,
+            PlatformDevAddr::addr((fb as *const FramebufferInfoAddr) as usize),
+        ));
+    }
 
     let drivers = crate::dev::drivers::platform::available_drivers();
 
@@ -380,6 +580,8 @@ fn setup_platform_dev() {
         for drv in drivers {
             if drv.matches(device) {
                 if !drv.probe(device) {
+                    // SAFETY: The driver failed to probe, so it holds no references to its resources
+                    unsafe { devres_release_all(&device.dev) };
                     continue;
                 }
                 drv.attach(device);
@@ -409,22 +611,178 @@ fn setup_logger() {
     set_alternate_panic_handler(None);
 }
 
+fn setup_timers() {
+    use crate::arch::x86_64::{apic, hpet, ioapic, pmtimer};
+
+    // Without ACPI there are no tables describing the APIC or the HPET, so the TSC has to do
+    let Some(rsdp_addr) = BOOT_INFO.get().rsdp_addr else {
+        kprintln!(Warn, "acpi: no RSDP, booting without ACPI, APIC and HPET");
+        return;
+    };
+    if let Err(err) = unsafe { crate::acpi::init(rsdp_addr) } {
+        kprintln!(Error, "acpi: {}", err);
+        return;
+    }
+    if let Some(topology) = crate::acpi::cpu_topology() {
+        kprintln!(
+            Info,
+            "acpi: {} processors, {} IO APICs, {} interrupt overrides",
+            topology.processors.len(),
+            topology.io_apics.len(),
+            topology.overrides.len()
+        );
+    }
+    match unsafe { apic::init() } {
+        Ok(mode) => kprintln!(
+            Info,
+            "apic: {} mode, timer at {} kHz",
+            mode,
+            apic::timer_frequency() / 1000
+        ),
+        Err(err) => kprintln!(Error, "apic: {}", err),
+    }
+    match unsafe { ioapic::init() } {
+        Ok(()) => kprintln!(Info, "ioapic: {} pins", ioapic::pins()),
+        Err(err) => kprintln!(Warn, "ioapic: {}", err),
+    }
+
+    match unsafe { hpet::init() } {
+        Ok(()) => {
+            let hpet = hpet::hpet().unwrap();
+            kprintln!(
+                Info,
+                "hpet: {} Hz, {} comparators",
+                hpet.frequency(),
+                hpet.comparators()
+            );
+            unsafe { crate::time::tsc::recalibrate(hpet::clocksource()) };
+        }
+        Err(err) => kprintln!(Warn, "hpet: {}", err),
+    }
+
+    match unsafe { pmtimer::init() } {
+        Ok(()) => kprintln!(Info, "acpi_pm: {} Hz", pmtimer::FREQUENCY),
+        Err(err) => kprintln!(Warn, "acpi_pm: {}", err),
+    }
+
+    if let Some(source) = crate::time::current_clocksource() {
+        kprintln!(Info, "time: using clock source {}", source.name);
+    }
+    crate::time::selftest::boot_check();
+    crate::time::tsc_sync::sync_all();
+    match crate::time::tick::init() {
+        Ok(device) => kprintln!(Info, "tick: {} Hz from {}", crate::time::tick::HZ, device.name),
+        Err(err) => kprintln!(Warn, "tick: {}", err),
+    }
+    crate::profile::init(crate::boot::cmdline());
+    crate::tracepoint::init(crate::boot::cmdline());
+
+    if crate::boot::cmdline().flag("nowatchdog") {
+        kprintln!(Info, "watchdog: disabled on the command line");
+    } else {
+        match unsafe { crate::arch::x86_64::watchdog::init() } {
+            Ok(()) => kprintln!(
+                Info,
+                "watchdog: NMI lockup detection after {}s",
+                crate::arch::x86_64::watchdog::THRESHOLD_NS / 1_000_000_000
+            ),
+            Err(err) => kprintln!(Warn, "watchdog: {}", err),
+        }
+    }
+}
+
+fn load_microcode() {
+    use crate::arch::x86_64::microcode::{self, MicrocodeError};
+
+    match unsafe { microcode::init() } {
+        Ok(Some((before, after))) => kprintln!(Info, "microcode: updated from {:#x} to {:#x}", before, after),
+        Ok(None) => kprintln!(Info, "microcode: revision {:#x} is current", microcode::revision()),
+        Err(err @ (MicrocodeError::Hypervisor | MicrocodeError::NoInitramfs | MicrocodeError::NotFound(_))) => {
+            kprintln!(Info, "microcode: not loaded, {}", err)
+        }
+        Err(err) => kprintln!(Warn, "microcode: {}", err),
+    }
+}
+
 fn stage_2() -> ! {
+    use crate::display::splash::{self, Milestone};
+
     let boot_info = BOOT_INFO.get_mut();
     // Initialize the heap
     unsafe { crate::mm::allocator::ALLOCATOR.init(boot_info.heap.0.as_mut_ptr(), boot_info.heap.1) };
+    unsafe {
+        crate::percpu::init_bsp();
+        crate::arch::x86_64::syscall::init();
+    }
+
+    // Hand over the memory map to the frame allocator, so drivers can map device memory
+    {
+        let mut page_table = KernelPageTable::new(Cr3::addr());
+        let memory_map = MemoryMap::from_bootstrap(&mut boot_info.bootstrap_map, &mut page_table);
+        unsafe { FRAME_ALLOCATOR.replace_uninit(KernelFrameAllocator::new(memory_map)) };
+    }
+    crate::mm::stats::init();
+    crate::dev::resource::init();
+    crate::sched::latency::init();
+    if let Err(err) = crate::util::logring::init() {
+        kprintln!(Warn, "log: {}", err);
+    }
+    crate::workqueue::init();
+    crate::time::timer::init();
+    crate::sched::balance::init();
+    crate::sched::trace::init(crate::boot::cmdline());
+    crate::mm::scrub::init(crate::boot::cmdline());
+    crate::mm::dedup::init();
+    stack::init();
+    let boot_stack = layout::layout().stacks_end() - request::KERNEL_STACK_SIZE;
+    stack::register("boot", boot_stack, request::KERNEL_STACK_SIZE);
 
     // We setup devices to our proper device system
     setup_platform_dev();
     setup_logger();
+    crate::util::panicking::init(crate::boot::cmdline());
+    crate::boot::image::init(crate::boot::cmdline());
+    splash::init();
+    load_microcode();
 
     kprintln!(Debug, "Hello World!");
-    kprintln!(Debug, "CPU Info: {:#?}", cpu_info());
+    crate::arch::x86_64::cpu::log_summary();
+    kprintln!(Debug, "Firmware: {:#?}", crate::boot::firmware());
+    kprintln!(Info, "boot: command line '{}'", crate::boot::cmdline());
+    let layout = layout::layout();
+    kprintln!(
+        Debug,
+        "boot: direct map at {:#x}, heap at {:#x}, stacks at {:#x}, mmio at {:#x}",
+        layout.direct_map,
+        layout.heap,
+        layout.stacks,
+        layout.mmio
+    );
 
-    {
-        let boot_info = BOOT_INFO.get_mut();
-        let mut page_table = KernelPageTable::new(Cr3::addr());
-        let mut memory_map = MemoryMap::from_bootstrap(&mut boot_info.memory_map, &mut page_table);
+    setup_timers();
+    crate::percpu::limit_cpus(crate::boot::cmdline());
+    crate::random::init();
+    crate::dev::drivers::platform::serial::enable_irq();
+    crate::time::init_wall_clock();
+    kprintln!(Info, "time: wall clock is {}", crate::time::now());
+    splash::milestone(Milestone::Timers);
+
+    crate::dev::pci::init();
+    splash::milestone(Milestone::Pci);
+    #[cfg(feature = "pci_golden")]
+    crate::dev::pci::golden::run();
+    crate::dev::drivers::pci::probe_all();
+    crate::display::set_native_mode();
+    crate::dev::virtio::mmio::init(crate::boot::cmdline());
+    crate::dev::virtio::console::init();
+    crate::dev::virtio::net::init();
+    splash::milestone(Milestone::Drivers);
+    crate::net::init(crate::boot::cmdline());
+    crate::net::tftp::init(crate::boot::cmdline());
+    splash::milestone(Milestone::Network);
+    crate::mm::wx::audit();
+    if let Err(err) = crate::fs::root::mount(crate::boot::cmdline()) {
+        kprintln!(Error, "fs: can't mount the root file system: {}", err);
     }
 
     unsafe extern "Rust" {
@@ -433,26 +791,33 @@ fn stage_2() -> ! {
     unsafe { kernel_main() };
 }
 
+/// Returns the sizes of the text, read only data and data sections of the kernel image
 #[inline]
-fn get_kernel_size() -> (usize, usize) {
+fn get_kernel_size() -> (usize, usize, usize) {
     unsafe extern "C" {
         unsafe static _kernel_text_start: u8;
+        unsafe static _kernel_rodata_start: u8;
         unsafe static _kernel_data_start: u8;
         unsafe static _kernel_end: u8;
     }
 
     unsafe {
         let start = &_kernel_text_start as *const u8 as usize;
-        let end = &_kernel_end as *const u8 as usize;
+        let rodata_start = &_kernel_rodata_start as *const u8 as usize;
         let data_start = &_kernel_data_start as *const u8 as usize;
+        let end = &_kernel_end as *const u8 as usize;
         assert!(
-            (data_start - start) % 0x1000 == 0,
+            (rodata_start - start) % 0x1000 == 0,
             "Kernel text section is not page aligned"
         );
+        assert!(
+            (data_start - rodata_start) % 0x1000 == 0,
+            "Kernel rodata section is not page aligned"
+        );
         assert!(
             (end - data_start) % 0x1000 == 0,
             "Kernel data section is not page aligned"
         );
-        (data_start - start, end - data_start)
+        (rodata_start - start, data_start - rodata_start, end - data_start)
     }
 }
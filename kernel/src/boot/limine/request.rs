@@ -1,7 +1,11 @@
-use limine::request::{
-    BootloaderInfoRequest, ExecutableAddressRequest, ExecutableFileRequest, FirmwareTypeRequest, FramebufferRequest,
-    HhdmRequest, MemoryMapRequest, ModuleRequest, RequestsEndMarker, RequestsStartMarker, RsdpRequest,
-    StackSizeRequest,
+use limine::{
+    module::{InternalModule, ModuleFlags},
+    request::{
+        BootloaderInfoRequest, EfiSystemTableRequest, ExecutableAddressRequest, ExecutableFileRequest,
+        FirmwareTypeRequest, FramebufferRequest, HhdmRequest, MemoryMapRequest, ModuleRequest, PagingModeRequest,
+        RequestsEndMarker, RequestsStartMarker, RsdpRequest, SmBiosRequest, StackSizeRequest,
+    },
+    response::PagingMode,
 };
 
 #[used]
@@ -30,6 +34,12 @@ pub static _STACK_SIZE: StackSizeRequest = StackSizeRequest::new(KERNEL_STACK_SI
 #[unsafe(link_section = ".requests")]
 pub static HHDM: HhdmRequest = HhdmRequest::new();
 
+/// 5-level paging if the CPU supports it, the kernel handles both
+#[used]
+#[unsafe(link_section = ".requests")]
+pub static PAGING_MODE: PagingModeRequest =
+    PagingModeRequest::new(PagingMode::FIVE_LEVEL, PagingMode::FIVE_LEVEL, PagingMode::FOUR_LEVEL);
+
 #[used]
 #[unsafe(link_section = ".requests")]
 pub static FRAMEBUFFER: FramebufferRequest = FramebufferRequest::new();
@@ -52,7 +62,18 @@ pub static RSDP: RsdpRequest = RsdpRequest::new();
 
 #[used]
 #[unsafe(link_section = ".requests")]
-pub static MODULES: ModuleRequest = ModuleRequest::new();
+pub static EFI_SYSTEM_TABLE: EfiSystemTableRequest = EfiSystemTableRequest::new();
+
+#[used]
+#[unsafe(link_section = ".requests")]
+pub static SMBIOS: SmBiosRequest = SmBiosRequest::new();
+
+/// An initramfs next to the kernel, loaded if it exists
+static INITRAMFS: InternalModule = InternalModule::new(c"initramfs.tar", c"initramfs", ModuleFlags::EMPTY);
+
+#[used]
+#[unsafe(link_section = ".requests")]
+pub static MODULE: ModuleRequest = ModuleRequest::with_internal_modules(&[&INITRAMFS]);
 
 #[used]
 #[unsafe(link_section = ".requests_end_marker")]
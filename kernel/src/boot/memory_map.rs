@@ -62,14 +62,13 @@ impl Default for UsableRegion {
 }
 
 impl UsableRegion {
-    pub fn from_region(region: &limine::memory_map::MemoryMapEntry) -> Option<Self> {
-        use limine::memory_map::MemoryMapEntryType;
-        if region.ty != MemoryMapEntryType::Usable {
+    pub fn from_region(region: &MemoryMapEntry) -> Option<Self> {
+        if region.ty() != MemoryRegionType::Usable {
             return None;
         }
 
-        let start = PhysAddr::new(region.base as usize);
-        let end = start + (region.length as usize);
+        let start = region.base();
+        let end = region.end();
         debug_assert!(start.is_aligned(Size4KiB::SIZE), "memory regions are not aligned!");
         let aligned_start = start.align_up(Size2MiB::SIZE);
         if end < (aligned_start + Size2MiB::SIZE) {
@@ -140,6 +139,47 @@ impl BootstrapMemoryMap {
         }
     }
 
+    /// Creates the memory map from the entries reported by the bootloader
+    ///
+    /// This function does several things:
+    /// 1. It finds a HHDM mapped region, which is long enough to hold the entire memory map.
+    /// 2. It creates a frame based allocator with that frame.
+    /// 3. It creates a vector of the memory map entries using the allocator
+    /// 4. It marks that frame as used int he memory map.
+    pub fn init(&mut self, boot_entries: &[MemoryMapEntry], hhdm_offset: usize) {
+        /// The number of entries we need to reserve for the memory map, for deallocation
+        /// We copletely control this in the kernel, so this can be a constant
+        const RESERVED_ENTRIES: usize = 8;
+        let required_size = size_of::<MemoryMapEntry>() * (boot_entries.len() + RESERVED_ENTRIES);
+        // Now we find a hhdm region (phys addr <= 4 GiB) that is long enough to hold the memory map
+        const HHDM_END: usize = 0x100000000;
+        let region = boot_entries
+            .iter()
+            .find(|e| {
+                e.ty() == MemoryRegionType::Usable && e.base().as_usize() <= HHDM_END && e.length() >= required_size
+            })
+            .expect("memory map: requires a memory region that is long enough to hold the memory map");
+        self.entries
+            .allocator()
+            .call(|alloc| alloc.init(VirtAddr::new(region.base().as_usize() + hhdm_offset), region.length()));
+        self.entries.reserve(boot_entries.len());
+
+        for entry in boot_entries {
+            let mut entry = *entry;
+            if entry.base() == region.base() {
+                // Align the length to a page size, because everything else in the kernel assumes that
+                // the memory map regions are page aligned
+                let length = region.length().next_multiple_of(Size4KiB::SIZE);
+                entry.base += length;
+                entry.length -= length;
+                if entry.length == 0 {
+                    continue;
+                }
+            }
+            self.entries.push(entry);
+        }
+    }
+
     pub fn total_size(&self) -> usize {
         self.entries
             .iter()
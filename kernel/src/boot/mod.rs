@@ -1,11 +1,71 @@
 #[cfg(target_arch = "x86_64")]
 pub mod limine;
 
+pub mod cmdline;
 mod frame_allocator;
+pub mod image;
 mod info;
 mod memory_map;
 mod page_table;
 
+pub use cmdline::Cmdline;
+pub use info::{BootModule, FirmwareInfo};
+
+/// Returns the modules loaded by the bootloader
+///
+/// Empty until the bootloader entry has parsed its responses.
+pub fn modules() -> &'static [BootModule] {
+    info::BOOT_INFO.get().modules.as_slice()
+}
+
+/// Returns the ELF file of the kernel, if the bootloader passed it on
+pub fn kernel_file() -> Option<&'static [u8]> {
+    Some(info::BOOT_INFO.get().kernel_file.data()).filter(|data| !data.is_empty())
+}
+
+/// Returns the initramfs archive: the network boot payload if there is one, or the one the
+/// bootloader loaded
+pub fn initramfs() -> Option<&'static [u8]> {
+    if let Some(payload) = crate::net::tftp::boot_payload() {
+        return Some(payload);
+    }
+    modules()
+        .iter()
+        .find(|module| module.cmdline() == "initramfs")
+        .map(BootModule::data)
+        .filter(|data| !data.is_empty())
+}
+
+/// Returns the kernel command line given by the bootloader
+pub fn cmdline() -> Cmdline<'static> {
+    Cmdline::new(info::BOOT_INFO.get().cmdline.as_str())
+}
+
+/// Returns the firmware tables passed on by the bootloader
+pub fn firmware() -> FirmwareInfo {
+    info::BOOT_INFO.get().firmware
+}
+
+/// Returns the EDID of the display the bootloader set the first framebuffer up on
+///
+/// Empty if the firmware didn't pass one on.
+pub fn edid() -> &'static [u8] {
+    info::BOOT_INFO.get().edid.as_slice()
+}
+
+/// Returns the physical ranges of the bootloader memory map that devices must not decode
+///
+/// This is every entry except the framebuffers, which are device memory themselves.
+pub fn reserved_memory() -> impl Iterator<Item = core::ops::Range<usize>> {
+    info::BOOT_INFO
+        .get()
+        .memory_map
+        .as_slice()
+        .iter()
+        .filter(|entry| entry.ty() != memory_map::MemoryRegionType::Framebuffer && entry.length() > 0)
+        .map(|entry| entry.base().as_usize()..entry.end().as_usize())
+}
+
 /// The Main Kernel Entry Function
 /// This macro has to be expanded in the main.rs file so that the `kernel_info` symbol is exported
 #[macro_export]
@@ -2,7 +2,7 @@ use alloc::vec::Vec;
 
 use crate::{
     arch::{PhysAddr, VirtAddr, registers::control::Cr3Flags},
-    boot::frame_allocator::BootstrapFrameAllocator,
+    boot::frame_allocator::{BootstrapFrameAllocator, Request},
     mm::{
         allocator::{Locked, bump::BumpAllocator},
         mappings,
@@ -73,6 +73,8 @@ impl PageSubTable for PtTable {
 #[derive(Debug)]
 pub struct BootstrapPageTable<'a> {
     pml4_phys: PhysFrame,
+    /// The root table with 5-level paging, whose last entry is the PML4
+    pml5_phys: Option<PhysFrame>,
     pdpts: Vec<PdptTable, &'a Locked<BumpAllocator>>,
     pds: Vec<PdTable, &'a Locked<BumpAllocator>>,
     pts: Vec<PtTable, &'a Locked<BumpAllocator>>,
@@ -87,11 +89,25 @@ impl<'a> BootstrapPageTable<'a> {
     ) -> Self {
         let pml4_phys = frame_allocator
             .allocate_mapped_frame()
-            .expect("Failed to allocate frame");
+            .unwrap_or_else(|| frame_allocator.exhausted(Request::MappedFrames(1)));
         let pml4_addr = VirtAddr::new(pml4_phys.start_address().as_usize() + hhdm_offset);
         unsafe { pml4_addr.as_mut_ptr::<PageTable>().write(PageTable::new()) };
+        // The kernel only uses the upper half, so with 5-level paging everything is under the last
+        // PML5 entry, and the other levels are the same as with 4-level paging
+        let pml5_phys = VirtAddr::is_five_level().then(|| {
+            let pml5_phys = frame_allocator
+                .allocate_mapped_frame()
+                .unwrap_or_else(|| frame_allocator.exhausted(Request::MappedFrames(1)));
+            let pml5_addr = VirtAddr::new(pml5_phys.start_address().as_usize() + hhdm_offset);
+            let mut pml5 = PageTable::new();
+            pml5[KernelPageTable::KERNEL_PML5_INDEX]
+                .set_frame(pml4_phys, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            unsafe { pml5_addr.as_mut_ptr::<PageTable>().write(pml5) };
+            pml5_phys
+        });
         let table = Self {
             pml4_phys,
+            pml5_phys,
             pdpts: Vec::new_in(allocator),
             pds: Vec::new_in(allocator),
             pts: Vec::new_in(allocator),
@@ -107,18 +123,28 @@ impl<'a> BootstrapPageTable<'a> {
         table
     }
 
+    /// Returns the size of the bump allocator holding the lists of tables
+    fn bookkeeping_size(&self) -> usize {
+        self.pts.allocator().lock().mapped_range().1
+    }
+
     #[inline]
     fn get_table(&mut self, table: &impl PageSubTable) -> &mut PageTable {
         unsafe { &mut *(table.get_addr().as_mut_ptr()) }
     }
 
+    /// Returns the top level table, the PML5 with 5-level paging and the PML4 otherwise
+    fn root(&self) -> PhysFrame {
+        self.pml5_phys.unwrap_or(self.pml4_phys)
+    }
+
     pub fn load(&self) {
-        unsafe { crate::arch::registers::control::Cr3::write(self.pml4_phys, Cr3Flags::empty()) };
+        unsafe { crate::arch::registers::control::Cr3::write(self.root(), Cr3Flags::empty()) };
     }
 
     /// Consuming self, returning the physical address of the page table
     pub fn as_phys_addr(self) -> PhysAddr {
-        self.pml4_phys.start_address()
+        self.root().start_address()
     }
 
     fn get_pml4(&mut self) -> &mut PageTable {
@@ -137,7 +163,9 @@ impl<'a> BootstrapPageTable<'a> {
 
     fn get_or_create_pdpt(&mut self, pml4_index: usize, frame_allocator: &mut BootstrapFrameAllocator) -> PdptTable {
         self.try_get_pdpt(pml4_index).unwrap_or_else(|| {
-            let frame = frame_allocator.allocate_mapped_frame().unwrap();
+            let frame = frame_allocator
+                .allocate_mapped_frame()
+                .unwrap_or_else(|| frame_allocator.exhausted(Request::MappedFrames(1)));
             let addr = VirtAddr::new(frame.start_address().as_usize() + self.hhdm_offset);
             unsafe { addr.as_mut_ptr::<PageTable>().write(PageTable::new()) };
             let table = PdptTable {
@@ -145,6 +173,9 @@ impl<'a> BootstrapPageTable<'a> {
                 addr,
                 pml4_index,
             };
+            if self.pdpts.try_reserve(1).is_err() {
+                frame_allocator.exhausted(Request::Bookkeeping(self.bookkeeping_size()));
+            }
             self.pdpts.push(table);
             let page_table = self.get_pml4();
             let mut entry = PageTableEntry::new();
@@ -172,7 +203,9 @@ impl<'a> BootstrapPageTable<'a> {
         frame_allocator: &mut BootstrapFrameAllocator,
     ) -> PdTable {
         self.try_get_pd(pml4_index, pdpt_index).unwrap_or_else(|| {
-            let frame = frame_allocator.allocate_mapped_frame().unwrap();
+            let frame = frame_allocator
+                .allocate_mapped_frame()
+                .unwrap_or_else(|| frame_allocator.exhausted(Request::MappedFrames(1)));
             let addr = VirtAddr::new(frame.start_address().as_usize() + self.hhdm_offset);
             unsafe { addr.as_mut_ptr::<PageTable>().write(PageTable::new()) };
             let table = PdTable {
@@ -182,6 +215,9 @@ impl<'a> BootstrapPageTable<'a> {
                 pdpt_index,
             };
 
+            if self.pds.try_reserve(1).is_err() {
+                frame_allocator.exhausted(Request::Bookkeeping(self.bookkeeping_size()));
+            }
             self.pds.push(table);
             let pdpt = self.try_get_pdpt(pml4_index).unwrap();
             let pdpt_table = self.get_table(&pdpt);
@@ -212,7 +248,9 @@ impl<'a> BootstrapPageTable<'a> {
         frame_allocator: &mut BootstrapFrameAllocator,
     ) -> PtTable {
         self.try_get_pt(pml4_index, pdpt_index, pd_index).unwrap_or_else(|| {
-            let frame = frame_allocator.allocate_mapped_frame().unwrap();
+            let frame = frame_allocator
+                .allocate_mapped_frame()
+                .unwrap_or_else(|| frame_allocator.exhausted(Request::MappedFrames(1)));
             let addr = VirtAddr::new(frame.start_address().as_usize() + self.hhdm_offset);
             unsafe { addr.as_mut_ptr::<PageTable>().write(PageTable::new()) };
             let table = PtTable {
@@ -223,6 +261,9 @@ impl<'a> BootstrapPageTable<'a> {
                 pd_index,
             };
 
+            if self.pts.try_reserve(1).is_err() {
+                frame_allocator.exhausted(Request::Bookkeeping(self.bookkeeping_size()));
+            }
             self.pts.push(table);
             let pd = self.try_get_pd(pml4_index, pdpt_index).unwrap();
             let pd_table = self.get_table(&pd);
@@ -285,23 +326,28 @@ impl<'a> BootstrapPageTable<'a> {
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
         for idx in 0..self.pts.len() {
             let page = &self.pts[idx];
-            let addr = KernelPageTable::DIRECT_MAP_START + page.frame.start_address().as_usize();
+            let addr = KernelPageTable::direct_map_start() + page.frame.start_address().as_usize();
             self.map(addr, page.frame, flags, frame_allocator);
         }
 
         for idx in 0..self.pds.len() {
             let page = &self.pds[idx];
-            let addr = KernelPageTable::DIRECT_MAP_START + page.frame.start_address().as_usize();
+            let addr = KernelPageTable::direct_map_start() + page.frame.start_address().as_usize();
             self.map(addr, page.frame, flags, frame_allocator);
         }
 
         for idx in 0..self.pdpts.len() {
             let page = &self.pdpts[idx];
-            let addr = KernelPageTable::DIRECT_MAP_START + page.frame.start_address().as_usize();
+            let addr = KernelPageTable::direct_map_start() + page.frame.start_address().as_usize();
             self.map(addr, page.frame, flags, frame_allocator);
         }
 
-        let addr = KernelPageTable::DIRECT_MAP_START + self.pml4_phys.start_address().as_usize();
+        let addr = KernelPageTable::direct_map_start() + self.pml4_phys.start_address().as_usize();
         self.map(addr, self.pml4_phys, flags, frame_allocator);
+
+        if let Some(pml5_phys) = self.pml5_phys {
+            let addr = KernelPageTable::direct_map_start() + pml5_phys.start_address().as_usize();
+            self.map(addr, pml5_phys, flags, frame_allocator);
+        }
     }
 }
@@ -0,0 +1,8 @@
+//! The kernel config
+//!
+//! Generated by the build script from the config `make menuconfig` writes, with the defaults of the
+//! `kconfig.toml` files for options it doesn't set, or only the defaults if there is none yet. Every
+//! option is a constant named after it, and the bool options that are on also set
+//! `cfg(kconfig = "<option>")`, which leaves out the drivers that are off.
+
+include!(concat!(env!("OUT_DIR"), "/config.rs"));
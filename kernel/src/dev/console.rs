@@ -2,12 +2,15 @@ use crate::dev::Device;
 
 pub struct ConsoleDevVTable {
     pub write: fn(dev: &Device, char: u8),
+    /// Reads a pending input byte, for consoles that support input
+    pub read: Option<fn(dev: &Device) -> Option<u8>>,
 }
 
 impl core::fmt::Debug for ConsoleDevVTable {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("ConsoleDevVTable")
             .field("write", &format_args!("{:#x}", self.write as usize))
+            .field("read", &self.read.map(|read| read as usize))
             .finish()
     }
 }
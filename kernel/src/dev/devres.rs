@@ -0,0 +1,171 @@
+//! Device managed resources
+//!
+//! Resources acquired through the `devm_*` helpers are tied to a [`Device`], and are released
+//! in reverse order of acquisition when probing fails or the driver is unbound.
+
+use core::{any::Any, fmt, ops::RangeInclusive, ptr::NonNull};
+
+use crate::sync::Mutex;
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::{
+    arch::{PhysAddr, VirtAddr},
+    dev::{
+        Device,
+        resource::{self, Resource, ResourceError, ResourceKind},
+    },
+    irq::{self, IrqError, IrqHandler},
+    mm::mmio::{self, MmioRegion, MmioSpaceExhausted},
+};
+
+enum DevRes {
+    Alloc(Box<dyn Any + Send + Sync>),
+    Mmio(MmioRegion),
+    Irq(u8),
+    Resource(Resource),
+}
+
+impl DevRes {
+    fn release(self) {
+        match self {
+            Self::Alloc(data) => drop(data),
+            // SAFETY: The mapping is owned by the device, which is being torn down
+            Self::Mmio(region) => unsafe { mmio::unmap(region) },
+            Self::Irq(vector) => _ = irq::free_irq(vector),
+            Self::Resource(resource) => drop(resource),
+        }
+    }
+}
+
+impl fmt::Debug for DevRes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Alloc(_) => f.write_str("Alloc"),
+            Self::Mmio(region) => f.debug_tuple("Mmio").field(region).finish(),
+            Self::Irq(vector) => f.debug_tuple("Irq").field(vector).finish(),
+            Self::Resource(resource) => f.debug_tuple("Resource").field(resource).finish(),
+        }
+    }
+}
+
+/// The list of managed resources held by a device
+#[derive(Debug)]
+pub struct DevResList {
+    resources: Mutex<Vec<DevRes>>,
+}
+
+impl DevResList {
+    pub const fn new() -> Self {
+        Self {
+            resources: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn push(&self, res: DevRes) {
+        self.resources.lock().push(res);
+    }
+
+    /// Returns the number of resources currently held
+    pub fn len(&self) -> usize {
+        self.resources.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for DevResList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DevResError {
+    Mmio(MmioSpaceExhausted),
+    Irq(IrqError),
+    Resource(ResourceError),
+}
+
+impl fmt::Display for DevResError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mmio(err) => write!(f, "failed to map BAR: {}", err),
+            Self::Irq(err) => write!(f, "failed to request IRQ: {}", err),
+            Self::Resource(err) => write!(f, "failed to claim resource: {}", err),
+        }
+    }
+}
+
+impl core::error::Error for DevResError {}
+
+/// Allocates a value owned by the device
+///
+/// The returned pointer is valid until the resources of the device are released.
+pub fn devm_alloc<T: Send + Sync + 'static>(dev: &Device, value: T) -> NonNull<T> {
+    let mut data = Box::new(value);
+    let ptr = NonNull::from(&mut *data);
+    dev.resources.push(DevRes::Alloc(data));
+    ptr
+}
+
+/// Maps a BAR of the device into the MMIO space, returning its virtual address
+///
+/// # Safety
+/// The physical region must belong to the device.
+pub unsafe fn devm_map_bar(dev: &Device, base: PhysAddr, size: usize) -> Result<VirtAddr, DevResError> {
+    let region = unsafe { mmio::map(base, size) }.map_err(DevResError::Mmio)?;
+    let virt = region.virt();
+    dev.resources.push(DevRes::Mmio(region));
+    Ok(virt)
+}
+
+/// Registers an interrupt handler that is freed along with the device
+pub fn devm_request_irq(
+    dev: &Device,
+    vector: u8,
+    name: &'static str,
+    handler: IrqHandler,
+    data: usize,
+) -> Result<(), DevResError> {
+    irq::request_irq(vector, name, handler, data).map_err(DevResError::Irq)?;
+    dev.resources.push(DevRes::Irq(vector));
+    Ok(())
+}
+
+/// Registers an interrupt handler on any free vector, returning the vector
+pub fn devm_request_any_irq(
+    dev: &Device,
+    name: &'static str,
+    handler: IrqHandler,
+    data: usize,
+) -> Result<u8, DevResError> {
+    let vector = irq::request_any_irq(name, handler, data).map_err(DevResError::Irq)?;
+    dev.resources.push(DevRes::Irq(vector));
+    Ok(vector)
+}
+
+/// Claims a range of a resource for the device, see [`resource::claim`]
+#[track_caller]
+pub fn devm_claim(
+    dev: &Device,
+    kind: ResourceKind,
+    range: RangeInclusive<u64>,
+    owner: &'static str,
+) -> Result<(), DevResError> {
+    let claim = resource::claim(kind, range, owner).map_err(DevResError::Resource)?;
+    dev.resources.push(DevRes::Resource(claim));
+    Ok(())
+}
+
+/// Releases all managed resources of the device, in reverse order of acquisition
+///
+/// # Safety
+/// Nothing may still reference the released allocations or mappings.
+pub unsafe fn devres_release_all(dev: &Device) {
+    let resources = core::mem::take(&mut *dev.resources.resources.lock());
+    for res in resources.into_iter().rev() {
+        res.release();
+    }
+}
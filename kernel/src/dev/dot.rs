@@ -0,0 +1,404 @@
+//! Graphviz export of the device tree
+//!
+//! Enumeration bugs are easier to report as a picture than as a textual dump of a deep PCIe
+//! hierarchy. The `devtree` shell command sends every bus and device, the driver bound to it and
+//! the claimed resources over the serial console as a DOT graph, between marker lines and after a
+//! comment saying how to render it:
+//!
+//! ```text
+//! # hadron device tree: 14 nodes
+//! # render: sed -n '/BEGIN HADRON DEVTREE/,/END HADRON DEVTREE/{//!p}' serial.log | dot -Tsvg > devtree.svg
+//! -----BEGIN HADRON DEVTREE-----
+//! digraph devices {
+//!   ...
+//! }
+//! -----END HADRON DEVTREE-----
+//! ```
+//!
+//! PCI functions hang off the bus they are on, and the bus behind a bridge off the bridge. The
+//! resource tree is drawn next to the devices, and a dashed edge links a device to the claims made
+//! under the name of its driver, so every device with the same driver links to all of its claims.
+
+use core::fmt::{self, Write};
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::dev::{
+    pci::{Bar, PciDevice},
+    resource::ClaimInfo,
+};
+
+const BEGIN_MARKER: &str = "-----BEGIN HADRON DEVTREE-----";
+const END_MARKER: &str = "-----END HADRON DEVTREE-----";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DotError {
+    NoSerial,
+}
+
+impl fmt::Display for DotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NoSerial => "no serial console",
+        })
+    }
+}
+
+impl core::error::Error for DotError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shape {
+    /// A bus or a group of devices
+    Bus,
+    Device,
+    /// A claim in the resource tree
+    Resource,
+}
+
+impl Shape {
+    fn attributes(self) -> &'static str {
+        match self {
+            Self::Bus => "shape=folder",
+            Self::Device => "shape=box",
+            Self::Resource => "shape=note, fontsize=9",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    label: String,
+    shape: Shape,
+    parent: Option<usize>,
+    /// The driver of a device, or the owner of a claim
+    owner: Option<&'static str>,
+}
+
+/// A PCI function, with what [`DeviceGraph::add_pci`] needs to place and describe it
+#[derive(Debug, Clone)]
+pub struct PciFunction {
+    pub dev: PciDevice,
+    /// The bus behind it, if it is a bridge
+    pub secondary_bus: Option<u8>,
+    pub driver: Option<&'static str>,
+    pub bars: Vec<(usize, Bar)>,
+}
+
+/// The devices and resources of the machine, as a tree of labelled nodes
+#[derive(Debug, Clone, Default)]
+pub struct DeviceGraph {
+    nodes: Vec<Node>,
+}
+
+impl DeviceGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a node, returning its index
+    fn add(&mut self, parent: Option<usize>, shape: Shape, label: String, owner: Option<&'static str>) -> usize {
+        self.nodes.push(Node {
+            label,
+            shape,
+            parent,
+            owner,
+        });
+        self.nodes.len() - 1
+    }
+
+    /// Adds a bus, or another group of devices, returning its index
+    pub fn add_bus(&mut self, parent: Option<usize>, label: String) -> usize {
+        self.add(parent, Shape::Bus, label, None)
+    }
+
+    /// Adds a device bound to `driver`, returning its index
+    pub fn add_device(&mut self, parent: usize, label: String, driver: Option<&'static str>) -> usize {
+        let label = match driver {
+            Some(driver) => format!("{}\ndriver: {}", label, driver),
+            None => label,
+        };
+        self.add(Some(parent), Shape::Device, label, driver)
+    }
+
+    /// Adds the PCI functions, each under its bus, and each bus under its bridge
+    pub fn add_pci(&mut self, functions: &[PciFunction]) {
+        let root = self.add_bus(None, "pci".into());
+        let first = self.nodes.len();
+        for function in functions {
+            let mut label = format!("{}\n{}", function.dev, function.dev.device_class());
+            for (idx, bar) in &function.bars {
+                _ = write!(label, "\nBAR{}: {}", idx, bar);
+            }
+            self.add_device(root, label, function.driver);
+        }
+
+        let mut buses: Vec<(u8, usize)> = Vec::new();
+        for (idx, function) in functions.iter().enumerate() {
+            let bus = function.dev.addr.bus;
+            let parent = match buses.iter().find(|(number, _)| *number == bus) {
+                Some((_, node)) => *node,
+                None => {
+                    // The bus behind a bridge hangs off the bridge, any other off the root
+                    let bridge = functions
+                        .iter()
+                        .position(|bridge| bridge.secondary_bus == Some(bus))
+                        .map(|bridge| first + bridge);
+                    let node = self.add_bus(Some(bridge.unwrap_or(root)), format!("bus {:02x}", bus));
+                    buses.push((bus, node));
+                    node
+                }
+            };
+            self.nodes[first + idx].parent = Some(parent);
+        }
+    }
+
+    /// Adds the claims of the resource tree, nested like the tree
+    pub fn add_claims(&mut self, claims: &[ClaimInfo]) {
+        let root = self.add_bus(None, "resources".into());
+        let mut kinds: Vec<(&'static str, usize)> = Vec::new();
+        let first = self.nodes.len();
+        for claim in claims {
+            let kind = claim.kind.name();
+            let parent = match kinds.iter().find(|(name, _)| *name == kind) {
+                Some((_, node)) => *node,
+                None => {
+                    let node = self.add_bus(Some(root), kind.into());
+                    kinds.push((kind, node));
+                    node
+                }
+            };
+            let label = format!("{:#x}-{:#x}\n{}", claim.range.start(), claim.range.end(), claim.owner);
+            self.add(Some(parent), Shape::Resource, label, Some(claim.owner));
+        }
+        // The kind nodes were added in between, so the claims aren't contiguous
+        let claim_nodes: Vec<usize> = (first..self.nodes.len())
+            .filter(|idx| self.nodes[*idx].shape == Shape::Resource)
+            .collect();
+        for (claim, node) in claims.iter().zip(&claim_nodes) {
+            let window = claim
+                .parent
+                .and_then(|parent| claims.iter().position(|other| other.id == parent));
+            if let Some(window) = window {
+                self.nodes[*node].parent = Some(claim_nodes[window]);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Writes the graph in the DOT language
+    pub fn write_dot(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        writeln!(out, "digraph devices {{")?;
+        writeln!(out, "  rankdir=LR;")?;
+        writeln!(out, "  node [fontname=\"monospace\", fontsize=10];")?;
+        for (idx, node) in self.nodes.iter().enumerate() {
+            writeln!(
+                out,
+                "  n{} [label=\"{}\", {}];",
+                idx,
+                Escaped(&node.label),
+                node.shape.attributes()
+            )?;
+        }
+        for (idx, node) in self.nodes.iter().enumerate() {
+            if let Some(parent) = node.parent {
+                writeln!(out, "  n{} -> n{};", parent, idx)?;
+            }
+        }
+        let devices = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.shape == Shape::Device);
+        for (device, driver) in devices.filter_map(|(idx, node)| Some((idx, node.owner?))) {
+            let claims = self
+                .nodes
+                .iter()
+                .enumerate()
+                .filter(|(_, node)| node.shape == Shape::Resource);
+            for (claim, _) in claims.filter(|(_, node)| node.owner == Some(driver)) {
+                writeln!(out, "  n{} -> n{} [style=dashed, arrowhead=none];", device, claim)?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+}
+
+/// A label with quotes and backslashes escaped, and line breaks as `\n`
+struct Escaped<'a>(&'a str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds the graph of the running machine
+pub fn collect() -> DeviceGraph {
+    use crate::dev::{DEVICES, drivers, pci, resource, virtio};
+
+    let mut graph = DeviceGraph::new();
+    if let Some(topology) = crate::acpi::cpu_topology() {
+        let acpi = graph.add_bus(None, "acpi".into());
+        for cpu in &topology.processors {
+            let state = match (cpu.enabled, cpu.online_capable) {
+                (true, _) => "enabled",
+                (false, true) => "hot pluggable",
+                (false, false) => "disabled",
+            };
+            let bsp = if cpu.is_bsp { ", BSP" } else { "" };
+            let label = format!("processor {}\nAPIC ID {}, {}{}", cpu.uid, cpu.apic_id, state, bsp);
+            graph.add_device(acpi, label, None);
+        }
+        for io_apic in &topology.io_apics {
+            let label = format!(
+                "I/O APIC {}\nat {:#x}, GSI {}",
+                io_apic.id, io_apic.address, io_apic.gsi_base
+            );
+            graph.add_device(acpi, label, Some("ioapic"));
+        }
+    }
+
+    let functions: Vec<PciFunction> = pci::devices()
+        .into_iter()
+        .map(|dev| PciFunction {
+            dev,
+            secondary_bus: dev.secondary_bus(),
+            driver: drivers::pci::bound_driver(dev.addr),
+            bars: (0..dev.bar_count())
+                .filter_map(|idx| Some((idx, dev.bar(idx)?)))
+                .collect(),
+        })
+        .collect();
+    graph.add_pci(&functions);
+
+    let platform = graph.add_bus(None, "platform".into());
+    for dev in DEVICES.platform().iter() {
+        // The driver isn't recorded, but it is the first one that matches
+        let driver = dev.dev.drv.as_ref().and_then(|_| {
+            drivers::platform::available_drivers()
+                .iter()
+                .find(|drv| drv.matches(dev))
+                .map(|drv| drv.name)
+        });
+        graph.add_device(platform, format!("{}\n{:?}", dev.name, dev.class), driver);
+    }
+
+    let mmio = virtio::mmio::devices();
+    if !mmio.is_empty() {
+        let bus = graph.add_bus(None, "virtio-mmio".into());
+        for dev in mmio {
+            graph.add_device(bus, format!("{}\n{}", dev.desc(), dev.device_type()), None);
+        }
+    }
+
+    graph.add_claims(&resource::claims());
+    graph
+}
+
+/// Sends the graph of the running machine over the serial console, returning its size
+pub fn dump_serial() -> Result<usize, DotError> {
+    use crate::dev::drivers::platform::serial;
+
+    let graph = collect();
+    let mut serial = serial::console().ok_or(DotError::NoSerial)?;
+    // Writes to the serial port can't fail
+    _ = writeln!(serial, "# hadron device tree: {} nodes", graph.len());
+    _ = writeln!(
+        serial,
+        "# render: sed -n '/BEGIN HADRON DEVTREE/,/END HADRON DEVTREE/{{//!p}}' serial.log | dot -Tsvg > devtree.svg"
+    );
+    _ = writeln!(serial, "{}", BEGIN_MARKER);
+    _ = graph.write_dot(&mut serial);
+    _ = writeln!(serial, "{}", END_MARKER);
+    Ok(graph.len())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use core::ops::RangeInclusive;
+
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::dev::{pci::PciAddress, resource::ResourceKind};
+
+    fn function(
+        bus: u8,
+        device: u8,
+        class: u8,
+        secondary_bus: Option<u8>,
+        driver: Option<&'static str>,
+    ) -> PciFunction {
+        PciFunction {
+            dev: PciDevice {
+                addr: PciAddress::new(bus, device, 0),
+                vendor_id: 0x8086,
+                device_id: 0x1234,
+                subsystem_vendor_id: 0,
+                subsystem_id: 0,
+                class,
+                subclass: 0x08,
+                prog_if: 0x02,
+                revision: 0,
+                header_type: if secondary_bus.is_some() { 0x01 } else { 0x00 },
+                interrupt_line: 0,
+            },
+            secondary_bus,
+            driver,
+            bars: Vec::new(),
+        }
+    }
+
+    fn claim(id: usize, range: RangeInclusive<u64>, owner: &'static str, parent: Option<usize>) -> ClaimInfo {
+        ClaimInfo {
+            id,
+            kind: ResourceKind::Mmio,
+            range,
+            owner,
+            parent,
+        }
+    }
+
+    #[test]
+    fn dot_device_graph() {
+        let mut graph = DeviceGraph::new();
+        // A root port at 00:1c.0 with an NVMe controller behind it, on bus 01
+        graph.add_pci(&[
+            function(0, 0x1c, 0x06, Some(1), None),
+            function(1, 0, 0x01, None, Some("nvme")),
+        ]);
+        graph.add_claims(&[
+            claim(7, 0xfe00_0000..=0xfe0f_ffff, "pci bridge", None),
+            claim(3, 0xfe00_0000..=0xfe00_3fff, "nvme", Some(7)),
+        ]);
+        // pci, the two functions, bus 00 and bus 01, resources, iomem and the two claims
+        assert_eq!(graph.len(), 9);
+
+        let mut dot = String::new();
+        graph.write_dot(&mut dot).unwrap();
+        assert!(dot.starts_with("digraph devices {\n") && dot.ends_with("}\n"));
+        // Bus 00 hangs off the root, bus 01 off the bridge, and the controller off bus 01
+        assert!(dot.contains("  n0 -> n3;\n") && dot.contains("  n1 -> n4;\n") && dot.contains("  n4 -> n2;\n"));
+        assert!(dot.contains("n2 [label=\"01:00.0 [0108] 8086:1234 (rev 00)\\n"));
+        assert!(dot.contains("\\ndriver: nvme\", shape=box];"));
+        // The controller's claim nests in the window, and links to the controller
+        assert!(dot.contains("  n7 -> n8;\n"));
+        assert!(dot.contains("  n2 -> n8 [style=dashed, arrowhead=none];\n"));
+        assert_eq!(Escaped("a \"b\"\\").to_string(), "a \\\"b\\\"\\\\");
+    }
+}
@@ -0,0 +1,348 @@
+//! The Bochs display interface of QEMU's standard VGA and `bochs-display`
+//!
+//! Both devices have their video memory in BAR0, and the Bochs "dispi" registers at offset 0x500
+//! of the MMIO BAR2, 16 bits each. Programming the resolution and depth there switches the linear
+//! framebuffer to a new mode at the start of video memory, which is how OVMF sets up the mode
+//! Limine hands us. The driver takes over that mode, and puts the firmware's registers back on
+//! shutdown.
+//!
+//! With `edid=on`, which is the default, QEMU also puts an EDID for the display at the start of
+//! BAR2, listing the modes it offers.
+//!
+//! QEMU rounds invalid values to something it can scan out rather than refusing them, so every
+//! mode is read back after it is programmed, and the previous one is restored if it didn't stick.
+
+use core::{fmt, ptr::NonNull};
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    arch::VirtAddr,
+    dev::{
+        drivers::pci::{PciDevMatcher, PciDrv},
+        pci::{Bar, CLASS_DISPLAY, PciCommand, PciDevice},
+    },
+    display::{self, DisplayDevice, DisplayError, DisplayMode, Scanout, edid},
+    module::{
+        abi::{AbiSlice, AbiStr},
+        api::{self, KernelApi},
+    },
+    sync::Mutex,
+    util::kprint::LogLevel,
+};
+
+pub const VENDOR_QEMU: u16 = 0x1234;
+pub const DEVICE_BOCHS: u16 = 0x1111;
+
+/// The dispi registers in BAR2, the bytes before are the VGA ports and the ones after QEMU's own
+const DISPI_OFFSET: usize = 0x500;
+const MMIO_SIZE: usize = 0x1000;
+
+const DISPI_ID: usize = 0x0;
+const DISPI_XRES: usize = 0x1;
+const DISPI_YRES: usize = 0x2;
+const DISPI_BPP: usize = 0x3;
+const DISPI_ENABLE: usize = 0x4;
+const DISPI_BANK: usize = 0x5;
+const DISPI_VIRT_WIDTH: usize = 0x6;
+const DISPI_VIRT_HEIGHT: usize = 0x7;
+const DISPI_X_OFFSET: usize = 0x8;
+const DISPI_Y_OFFSET: usize = 0x9;
+
+/// The first interface version with 32 bpp and a linear framebuffer
+const DISPI_ID2: u16 = 0xB0C2;
+const DISPI_ID_MAX: u16 = 0xB0CF;
+
+const ENABLE_ENABLED: u16 = 1 << 0;
+const ENABLE_LFB: u16 = 1 << 6;
+/// Keeps video memory on a mode change, which is cleared otherwise
+const ENABLE_NO_CLEAR: u16 = 1 << 7;
+
+#[derive(Debug, Clone, Copy)]
+pub enum BochsError {
+    /// BAR0 or BAR2 is missing or not a memory BAR, BAR2 is missing on old QEMU machine types
+    NoMmio,
+    /// The registers or video memory couldn't be mapped
+    Mmio,
+    /// The device doesn't implement a dispi version we can use
+    UnsupportedVersion(u16),
+}
+
+impl fmt::Display for BochsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoMmio => f.write_str("no MMIO BAR"),
+            Self::Mmio => f.write_str("failed to map registers"),
+            Self::UnsupportedVersion(id) => write!(f, "unsupported dispi version {:#x}", id),
+        }
+    }
+}
+
+impl core::error::Error for BochsError {}
+
+/// The mode registers, as the firmware left them or as a mode is programmed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DispiState {
+    xres: u16,
+    yres: u16,
+    bpp: u16,
+    enable: u16,
+    virt_width: u16,
+    virt_height: u16,
+    x_offset: u16,
+    y_offset: u16,
+}
+
+impl DispiState {
+    /// Returns the registers for a mode, scanned out from the start of video memory
+    fn for_mode(width: u32, height: u32, bpp: u32) -> Self {
+        Self {
+            xres: width as u16,
+            yres: height as u16,
+            bpp: bpp as u16,
+            enable: ENABLE_ENABLED | ENABLE_LFB,
+            virt_width: width as u16,
+            virt_height: height as u16,
+            x_offset: 0,
+            y_offset: 0,
+        }
+    }
+
+    fn mode(&self) -> DisplayMode {
+        DisplayMode {
+            width: self.xres as u32,
+            height: self.yres as u32,
+            bpp: self.bpp as u32,
+            stride: stride(self.virt_width as u32, self.bpp as u32),
+            timings: None,
+        }
+    }
+}
+
+/// Returns the bytes per line of a mode, which QEMU derives from the virtual width
+fn stride(virt_width: u32, bpp: u32) -> u32 {
+    virt_width * bpp.div_ceil(8)
+}
+
+/// Checks that a mode fits into the registers and video memory, returning its stride
+fn check_mode(width: u32, height: u32, bpp: u32, vram_size: usize) -> Result<u32, DisplayError> {
+    if width > u16::MAX as u32 || height > u16::MAX as u32 || !matches!(bpp, 8 | 15 | 16 | 24 | 32) {
+        return Err(DisplayError::InvalidMode { width, height, bpp });
+    }
+    let stride = stride(width, bpp);
+    if stride as usize * height as usize > vram_size {
+        return Err(DisplayError::OutOfMemory);
+    }
+    Ok(stride)
+}
+
+#[derive(Debug)]
+struct Registers {
+    api: &'static KernelApi,
+    /// The first [`MMIO_SIZE`] bytes of BAR2
+    base: NonNull<u8>,
+}
+
+// SAFETY: The registers are only accessed one at a time through the API
+unsafe impl Send for Registers {}
+unsafe impl Sync for Registers {}
+
+impl Registers {
+    fn read(&self, idx: usize) -> u16 {
+        unsafe { self.api.read_u16(self.base, DISPI_OFFSET + idx * 2) }
+    }
+
+    /// Reads the base block of the EDID at the start of BAR2, if QEMU put one there
+    fn edid(&self) -> Option<Vec<u8>> {
+        let mut edid = Vec::with_capacity(edid::BLOCK_SIZE);
+        for offset in (0..edid::BLOCK_SIZE).step_by(2) {
+            let word = unsafe { self.api.read_u16(self.base, offset) };
+            edid.extend_from_slice(&word.to_le_bytes());
+        }
+        edid::parse(&edid).is_ok().then_some(edid)
+    }
+
+    fn write(&self, idx: usize, value: u16) {
+        unsafe { self.api.write_u16(self.base, DISPI_OFFSET + idx * 2, value) }
+    }
+
+    fn state(&self) -> DispiState {
+        DispiState {
+            xres: self.read(DISPI_XRES),
+            yres: self.read(DISPI_YRES),
+            bpp: self.read(DISPI_BPP),
+            enable: self.read(DISPI_ENABLE),
+            virt_width: self.read(DISPI_VIRT_WIDTH),
+            virt_height: self.read(DISPI_VIRT_HEIGHT),
+            x_offset: self.read(DISPI_X_OFFSET),
+            y_offset: self.read(DISPI_Y_OFFSET),
+        }
+    }
+
+    /// Programs a mode, which only takes effect once the display is enabled again
+    fn program(&self, state: DispiState) {
+        self.write(DISPI_ENABLE, 0);
+        self.write(DISPI_BPP, state.bpp);
+        self.write(DISPI_XRES, state.xres);
+        self.write(DISPI_YRES, state.yres);
+        self.write(DISPI_BANK, 0);
+        self.write(DISPI_VIRT_WIDTH, state.virt_width);
+        self.write(DISPI_VIRT_HEIGHT, state.virt_height);
+        self.write(DISPI_X_OFFSET, state.x_offset);
+        self.write(DISPI_Y_OFFSET, state.y_offset);
+        self.write(DISPI_ENABLE, state.enable);
+    }
+}
+
+#[derive(Debug)]
+pub struct BochsDisplay {
+    name: String,
+    regs: Registers,
+    /// All of video memory, mapped for as long as the driver is bound
+    vram: NonNull<u8>,
+    vram_size: usize,
+    mode: Mutex<DisplayMode>,
+    saved: DispiState,
+}
+
+// SAFETY: Video memory is only written by the owner of the scanout, the console
+unsafe impl Send for BochsDisplay {}
+unsafe impl Sync for BochsDisplay {}
+
+impl DisplayDevice for BochsDisplay {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mode(&self) -> DisplayMode {
+        *self.mode.lock()
+    }
+
+    fn set_mode(&self, width: u32, height: u32, bpp: u32) -> Result<Scanout, DisplayError> {
+        check_mode(width, height, bpp, self.vram_size)?;
+        let mut mode = self.mode.lock();
+        let old = self.regs.state();
+        let new = DispiState::for_mode(width, height, bpp);
+        self.regs.program(new);
+        let state = self.regs.state();
+        if (state.xres, state.yres, state.bpp) != (new.xres, new.yres, new.bpp) {
+            self.regs.program(DispiState {
+                enable: old.enable | ENABLE_NO_CLEAR,
+                ..old
+            });
+            return Err(DisplayError::InvalidMode { width, height, bpp });
+        }
+        *mode = state.mode();
+        Ok(Scanout {
+            mode: *mode,
+            addr: VirtAddr::new(self.vram.as_ptr() as usize),
+        })
+    }
+
+    fn edid(&self) -> Option<Vec<u8>> {
+        self.regs.edid()
+    }
+
+    fn restore(&self) {
+        let mode = self.mode.lock();
+        if *mode != self.saved.mode() {
+            self.regs.program(DispiState {
+                enable: self.saved.enable | ENABLE_NO_CLEAR,
+                ..self.saved
+            });
+        }
+    }
+}
+
+#[used]
+#[cfg_attr(target_arch = "x86_64", unsafe(link_section = ".pci_drivers"))]
+static BOCHS_DRV: PciDrv = PciDrv {
+    name: AbiStr::new("bochs"),
+    matchers: AbiSlice::new(&[PciDevMatcher {
+        vendor_id: VENDOR_QEMU as u32,
+        device_id: DEVICE_BOCHS as u32,
+        class: CLASS_DISPLAY as u32,
+    }]),
+    probe: probe_drv,
+};
+
+extern "C" fn probe_drv(dev: &PciDevice) -> bool {
+    match probe(dev) {
+        Ok(()) => true,
+        Err(err) => {
+            api::kernel().log(LogLevel::Warn, format_args!("bochs: {}: {}", dev.addr, err));
+            false
+        }
+    }
+}
+
+/// Takes over the display of a QEMU standard VGA or `bochs-display`
+pub fn probe(dev: &PciDevice) -> Result<(), BochsError> {
+    let (
+        Some(Bar::Memory {
+            addr: vram_addr,
+            size: vram_size,
+            ..
+        }),
+        Some(Bar::Memory { addr, size, .. }),
+    ) = (dev.bar(0), dev.bar(2))
+    else {
+        return Err(BochsError::NoMmio);
+    };
+    dev.enable(PciCommand::MEMORY_SPACE);
+    let api = api::kernel();
+    let base = unsafe { api.map_mmio(addr, size.min(MMIO_SIZE)) }.ok_or(BochsError::Mmio)?;
+    let regs = Registers { api, base };
+
+    let id = regs.read(DISPI_ID);
+    if !(DISPI_ID2..=DISPI_ID_MAX).contains(&id) {
+        unsafe { (api.mmio_unmap)(base.as_ptr().cast(), size.min(MMIO_SIZE)) };
+        return Err(BochsError::UnsupportedVersion(id));
+    }
+    let Some(vram) = (unsafe { api.map_mmio(vram_addr, vram_size) }) else {
+        unsafe { (api.mmio_unmap)(base.as_ptr().cast(), size.min(MMIO_SIZE)) };
+        return Err(BochsError::Mmio);
+    };
+
+    let saved = regs.state();
+    if saved.enable & ENABLE_ENABLED == 0 {
+        api.log(
+            LogLevel::Info,
+            format_args!("bochs: {}: display is off, in VGA mode", dev.addr),
+        );
+    }
+    display::register(Arc::new(BochsDisplay {
+        name: format!("bochs {}", dev.addr),
+        regs,
+        vram,
+        vram_size,
+        mode: Mutex::new(saved.mode()),
+        saved,
+    }));
+    Ok(())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bochs_check_mode() {
+        const VRAM: usize = 16 * 1024 * 1024;
+        assert_eq!(check_mode(1024, 768, 32, VRAM).ok(), Some(4096));
+        assert_eq!(check_mode(800, 600, 24, VRAM).ok(), Some(2400));
+        assert_eq!(check_mode(640, 480, 15, VRAM).ok(), Some(1280));
+        assert!(matches!(
+            check_mode(1024, 768, 12, VRAM),
+            Err(DisplayError::InvalidMode { bpp: 12, .. })
+        ));
+        assert!(matches!(
+            check_mode(3840, 2160, 32, VRAM),
+            Err(DisplayError::OutOfMemory)
+        ));
+        assert!(matches!(
+            check_mode(70000, 10, 32, VRAM),
+            Err(DisplayError::InvalidMode { .. })
+        ));
+    }
+}
@@ -0,0 +1,281 @@
+//! Intel integrated graphics, display readout only
+//!
+//! The driver doesn't program modes. It reads back the pipe timings and primary plane the firmware
+//! configured, keeps scanning out of the firmware framebuffer, and restores the plane registers on
+//! shutdown so the firmware (or the next kernel) finds the display the way it left it.
+//!
+//! The pipe and plane registers used here sit at the same offsets from gen4 up to gen9+, where the
+//! plane control register became `PLANE_CTL`. The pixel format field moved between generations,
+//! so the depth is derived from the stride instead.
+
+use core::{fmt, ptr::NonNull};
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    dev::{
+        drivers::pci::{PciDevMatcher, PciDrv},
+        pci::{Bar, CLASS_DISPLAY, PciCommand, PciDevice},
+    },
+    display::{self, DisplayDevice, DisplayMode, DisplayTimings},
+    module::{
+        abi::{AbiSlice, AbiStr},
+        api::{self, KernelApi},
+    },
+    util::kprint::LogLevel,
+};
+
+pub const VENDOR_INTEL: u16 = 0x8086;
+
+/// Covers the display engine registers, the rest of BAR0 is the GTT
+const MMIO_SIZE: usize = 0x80000;
+const NUM_PIPES: usize = 3;
+const PIPE_STRIDE: usize = 0x1000;
+
+const HTOTAL: usize = 0x60000;
+const HSYNC: usize = 0x60008;
+const VTOTAL: usize = 0x6000C;
+const VSYNC: usize = 0x60014;
+const PIPESRC: usize = 0x6001C;
+const PIPECONF: usize = 0x70008;
+const DSPCNTR: usize = 0x70180;
+const DSPLINOFF: usize = 0x70184;
+const DSPSTRIDE: usize = 0x70188;
+const DSPSURF: usize = 0x7019C;
+const DSPTILEOFF: usize = 0x701A4;
+
+const PIPECONF_ENABLE: u32 = 1 << 31;
+const DSPCNTR_ENABLE: u32 = 1 << 31;
+const DSPCNTR_TILED: u32 = 1 << 10;
+
+#[derive(Debug, Clone, Copy)]
+pub enum I915Error {
+    /// BAR0 is missing or not a memory BAR
+    NoMmio,
+    /// The registers couldn't be mapped
+    Mmio,
+    /// The firmware left no pipe scanning out
+    NoActivePipe,
+}
+
+impl fmt::Display for I915Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoMmio => f.write_str("no MMIO BAR"),
+            Self::Mmio => f.write_str("failed to map registers"),
+            Self::NoActivePipe => f.write_str("no active pipe"),
+        }
+    }
+}
+
+impl core::error::Error for I915Error {}
+
+/// The primary plane registers, as the firmware left them
+#[derive(Debug, Clone, Copy)]
+struct PlaneState {
+    control: u32,
+    linear_offset: u32,
+    stride: u32,
+    surface: u32,
+    tile_offset: u32,
+}
+
+#[derive(Debug)]
+struct Registers {
+    api: &'static KernelApi,
+    /// The first [`MMIO_SIZE`] bytes of BAR0
+    base: NonNull<u8>,
+}
+
+// SAFETY: The registers are only accessed one at a time through the API
+unsafe impl Send for Registers {}
+unsafe impl Sync for Registers {}
+
+impl Registers {
+    fn read(&self, reg: usize) -> u32 {
+        debug_assert!(reg < MMIO_SIZE);
+        unsafe { self.api.read_u32(self.base, reg) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        debug_assert!(reg < MMIO_SIZE);
+        unsafe { self.api.write_u32(self.base, reg, value) }
+    }
+}
+
+/// A pipe the firmware left enabled, together with its primary plane
+#[derive(Debug)]
+pub struct I915Display {
+    name: String,
+    regs: Arc<Registers>,
+    pipe: usize,
+    mode: DisplayMode,
+    saved: PlaneState,
+}
+
+impl I915Display {
+    fn pipe_reg(&self, reg: usize) -> usize {
+        reg + self.pipe * PIPE_STRIDE
+    }
+
+    /// Returns whether the plane scans out of a tiled buffer, which the console can't draw into
+    pub fn is_tiled(&self) -> bool {
+        self.saved.control & DSPCNTR_TILED != 0
+    }
+
+    /// Returns the graphics address of the scanout buffer
+    pub fn surface(&self) -> u32 {
+        self.saved.surface
+    }
+}
+
+impl DisplayDevice for I915Display {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mode(&self) -> DisplayMode {
+        self.mode
+    }
+
+    fn restore(&self) {
+        let saved = self.saved;
+        self.regs.write(self.pipe_reg(DSPCNTR), saved.control);
+        self.regs.write(self.pipe_reg(DSPLINOFF), saved.linear_offset);
+        self.regs.write(self.pipe_reg(DSPSTRIDE), saved.stride);
+        self.regs.write(self.pipe_reg(DSPTILEOFF), saved.tile_offset);
+        // Writing the surface address arms the update of the other plane registers
+        self.regs.write(self.pipe_reg(DSPSURF), saved.surface);
+    }
+}
+
+/// Splits a timing register into its `(low, high)` fields, which hold the value minus one
+fn timing_pair(value: u32) -> (u32, u32) {
+    ((value & 0x1FFF) + 1, ((value >> 16) & 0x1FFF) + 1)
+}
+
+/// Derives the depth from the stride, which is at least `width * bpp / 8` bytes
+fn depth_from_stride(width: u32, stride: u32) -> u32 {
+    let bits = (stride as u64 * 8) / width.max(1) as u64;
+    [32, 16, 8].into_iter().find(|&bpp| bits >= bpp as u64).unwrap_or(8)
+}
+
+fn read_mode(regs: &Registers, pipe: usize) -> Option<(DisplayMode, PlaneState)> {
+    let reg = |reg: usize| regs.read(reg + pipe * PIPE_STRIDE);
+    if reg(PIPECONF) & PIPECONF_ENABLE == 0 || reg(DSPCNTR) & DSPCNTR_ENABLE == 0 {
+        return None;
+    }
+
+    let saved = PlaneState {
+        control: reg(DSPCNTR),
+        linear_offset: reg(DSPLINOFF),
+        stride: reg(DSPSTRIDE),
+        surface: reg(DSPSURF),
+        tile_offset: reg(DSPTILEOFF),
+    };
+    // The source size is stored as (width - 1) << 16 | (height - 1), 13 bits each on gen9+
+    let src = reg(PIPESRC);
+    let width = ((src >> 16) & 0x1FFF) + 1;
+    let height = (src & 0x1FFF) + 1;
+
+    let (hactive, htotal) = timing_pair(reg(HTOTAL));
+    let (hsync_start, hsync_end) = timing_pair(reg(HSYNC));
+    let (vactive, vtotal) = timing_pair(reg(VTOTAL));
+    let (vsync_start, vsync_end) = timing_pair(reg(VSYNC));
+
+    let mode = DisplayMode {
+        width,
+        height,
+        bpp: depth_from_stride(width, saved.stride),
+        stride: saved.stride,
+        timings: Some(DisplayTimings {
+            hactive,
+            hsync_start,
+            hsync_end,
+            htotal,
+            vactive,
+            vsync_start,
+            vsync_end,
+            vtotal,
+            // Reading the clock needs the DPLL layout of each generation
+            pixel_clock_khz: None,
+        }),
+    };
+    Some((mode, saved))
+}
+
+#[used]
+#[cfg_attr(target_arch = "x86_64", unsafe(link_section = ".pci_drivers"))]
+static I915_DRV: PciDrv = PciDrv {
+    name: AbiStr::new("i915"),
+    matchers: AbiSlice::new(&[PciDevMatcher {
+        vendor_id: VENDOR_INTEL as u32,
+        device_id: PciDevMatcher::ANY,
+        class: CLASS_DISPLAY as u32,
+    }]),
+    probe: probe_drv,
+};
+
+extern "C" fn probe_drv(dev: &PciDevice) -> bool {
+    match probe(dev) {
+        Ok(()) => true,
+        Err(err) => {
+            api::kernel().log(LogLevel::Warn, format_args!("i915: {}: {}", dev.addr, err));
+            false
+        }
+    }
+}
+
+/// Takes over the displays the firmware left enabled on an Intel GPU
+pub fn probe(dev: &PciDevice) -> Result<(), I915Error> {
+    let Some(Bar::Memory { addr, size, .. }) = dev.bar(0) else {
+        return Err(I915Error::NoMmio);
+    };
+    dev.enable(PciCommand::MEMORY_SPACE);
+    let api = api::kernel();
+    let base = unsafe { api.map_mmio(addr, size.min(MMIO_SIZE)) }.ok_or(I915Error::Mmio)?;
+    let regs = Arc::new(Registers { api, base });
+
+    let displays: Vec<_> = (0..NUM_PIPES)
+        .filter_map(|pipe| {
+            let (mode, saved) = read_mode(&regs, pipe)?;
+            Some(I915Display {
+                name: format!("i915 {} pipe {}", dev.addr, (b'A' + pipe as u8) as char),
+                regs: regs.clone(),
+                pipe,
+                mode,
+                saved,
+            })
+        })
+        .collect();
+    if displays.is_empty() {
+        return Err(I915Error::NoActivePipe);
+    }
+
+    for display in displays {
+        if display.is_tiled() {
+            api::kernel().log(
+                LogLevel::Warn,
+                format_args!("{}: firmware framebuffer is tiled", display.name),
+            );
+        }
+        display::register(Arc::new(display));
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn i915_mode_decoding() {
+        // 1920x1080 with a 2200 pixel line, as the registers store it
+        assert_eq!(timing_pair((2199 << 16) | 1919), (1920, 2200));
+        assert_eq!(depth_from_stride(1920, 7680), 32);
+        // Strides are padded to 64 bytes
+        assert_eq!(depth_from_stride(1366, 5504), 32);
+        assert_eq!(depth_from_stride(1366, 2752), 16);
+        assert_eq!(depth_from_stride(0, 0), 8);
+    }
+}
@@ -0,0 +1,4 @@
+//! GPU drivers
+
+pub mod bochs;
+pub mod i915;
@@ -1,6 +1,11 @@
 use crate::dev::console::ConsoleDevVTable;
 
+#[cfg(target_arch = "x86_64")]
+pub mod gpu;
+pub mod pci;
 pub mod platform;
+#[cfg(target_arch = "x86_64")]
+pub mod storage;
 
 #[derive(Debug)]
 pub struct DriverCapabilities {
@@ -0,0 +1,223 @@
+//! PCI drivers
+//!
+//! Built-in drivers are collected from the `.pci_drivers` linker section, drivers in loaded
+//! modules are added with [`register_driver`]. A device is bound to the first driver that matches
+//! it and probes successfully.
+//!
+//! The descriptors are part of the module ABI, see [`crate::module::abi`].
+
+use alloc::vec::Vec;
+
+use crate::{
+    dev::pci::{self, DeviceClass, PciAddress, PciDevice},
+    module::{
+        self, ModuleRef,
+        abi::{AbiSlice, AbiStr},
+    },
+    sync::RwLock,
+};
+
+/// Matches devices by ID and class, fields set to [`PciDevMatcher::ANY`] match anything
+///
+/// A plain `class` matches the base class. [`PciDevMatcher::subclass`] and
+/// [`PciDevMatcher::prog_if`] build values that also match the subclass and programming
+/// interface, which needs ABI 1.3.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevMatcher {
+    pub vendor_id: u32,
+    pub device_id: u32,
+    pub class: u32,
+}
+
+impl PciDevMatcher {
+    pub const ANY: u32 = u32::MAX;
+    /// Set in `class` when it holds a full class code whose subclass must match
+    pub const MATCH_SUBCLASS: u32 = 1 << 24;
+    /// Set in `class` when it holds a full class code whose programming interface must match
+    pub const MATCH_PROG_IF: u32 = 1 << 25;
+
+    /// Returns a `class` matching a subclass, like SATA controllers of any interface
+    pub const fn subclass(class: u8, subclass: u8) -> u32 {
+        Self::MATCH_SUBCLASS | DeviceClass::new(class, subclass, 0).code()
+    }
+
+    /// Returns a `class` matching a programming interface, like AHCI SATA controllers
+    pub const fn prog_if(class: u8, subclass: u8, prog_if: u8) -> u32 {
+        Self::MATCH_SUBCLASS | Self::MATCH_PROG_IF | DeviceClass::new(class, subclass, prog_if).code()
+    }
+
+    fn matches_class(&self, class: DeviceClass) -> bool {
+        if self.class == Self::ANY {
+            return true;
+        }
+        if self.class & (Self::MATCH_SUBCLASS | Self::MATCH_PROG_IF) == 0 {
+            return self.class == class.class as u32;
+        }
+        let mut mask = 0xFF_0000;
+        if self.class & Self::MATCH_SUBCLASS != 0 {
+            mask |= 0xFF00;
+        }
+        if self.class & Self::MATCH_PROG_IF != 0 {
+            mask |= 0xFF;
+        }
+        self.class & mask == class.code() & mask
+    }
+
+    pub fn matches(&self, dev: &PciDevice) -> bool {
+        let field = |matcher: u32, value: u32| matcher == Self::ANY || matcher == value;
+        field(self.vendor_id, dev.vendor_id as u32)
+            && field(self.device_id, dev.device_id as u32)
+            && self.matches_class(dev.device_class())
+    }
+}
+
+#[repr(C)]
+pub struct PciDrv {
+    pub name: AbiStr,
+    pub matchers: AbiSlice<PciDevMatcher>,
+    pub probe: extern "C" fn(dev: &PciDevice) -> bool,
+}
+
+impl PciDrv {
+    pub fn name(&self) -> &str {
+        // SAFETY: Drivers are built-in, or unregistered before their module is freed
+        unsafe { self.name.as_str() }
+    }
+
+    pub fn matches(&self, dev: &PciDevice) -> bool {
+        unsafe { self.matchers.as_slice() }.iter().any(|m| m.matches(dev))
+    }
+
+    pub fn probe(&self, dev: &PciDevice) -> bool {
+        let _probing = crate::dev::io_audit::probing(self.name(), dev.addr);
+        (self.probe)(dev)
+    }
+}
+
+impl core::fmt::Debug for PciDrv {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PciDrv")
+            .field("name", &self.name())
+            .field("probe", &format_args!("{:#x}", self.probe as usize))
+            .finish()
+    }
+}
+
+struct Binding {
+    addr: PciAddress,
+    drv: &'static PciDrv,
+    /// Keeps the module providing the driver loaded while the device is bound
+    _owner: Option<ModuleRef>,
+}
+
+static MODULE_DRIVERS: RwLock<Vec<&'static PciDrv>> = RwLock::new(Vec::new());
+static BINDINGS: RwLock<Vec<Binding>> = RwLock::new(Vec::new());
+
+/// List the Built-in PCI Drivers
+pub fn available_drivers() -> &'static [PciDrv] {
+    unsafe extern "C" {
+        static _pci_drv_start: u8;
+        static _pci_drv_end: u8;
+    }
+    let size = (&raw const _pci_drv_end) as usize - (&raw const _pci_drv_start) as usize;
+    unsafe { core::slice::from_raw_parts((&raw const _pci_drv_start).cast::<PciDrv>(), size / size_of::<PciDrv>()) }
+}
+
+/// Returns the built-in drivers followed by the ones registered by modules
+pub fn drivers() -> Vec<&'static PciDrv> {
+    available_drivers()
+        .iter()
+        .chain(MODULE_DRIVERS.read().iter().copied())
+        .collect()
+}
+
+/// Returns the name of the driver bound to a device
+pub fn bound_driver(addr: PciAddress) -> Option<&'static str> {
+    BINDINGS
+        .read()
+        .iter()
+        .find(|binding| binding.addr == addr)
+        .map(|binding| binding.drv.name())
+}
+
+fn bind(drv: &'static PciDrv, dev: &PciDevice) -> bool {
+    if bound_driver(dev.addr).is_some() || !drv.matches(dev) || !drv.probe(dev) {
+        return false;
+    }
+    let owner = module::owner(drv as *const PciDrv as usize);
+    BINDINGS.write().push(Binding {
+        addr: dev.addr,
+        drv,
+        _owner: owner,
+    });
+    true
+}
+
+/// Binds every unbound device to the first driver which accepts it
+pub fn probe_all() {
+    let drivers = drivers();
+    for dev in pci::devices() {
+        for drv in drivers.iter().copied() {
+            if bind(drv, &dev) {
+                break;
+            }
+        }
+    }
+}
+
+/// Adds a driver from a module, and binds it to the unbound devices it matches
+///
+/// # Safety
+/// The driver must stay valid until it is removed with [`unregister_driver`].
+pub unsafe fn register_driver(drv: &'static PciDrv) {
+    MODULE_DRIVERS.write().push(drv);
+    for dev in pci::devices() {
+        bind(drv, &dev);
+    }
+}
+
+/// Removes a driver registered by a module
+///
+/// Devices can't be unbound yet, the module loader refuses to unload a module with bound drivers.
+pub fn unregister_driver(drv: &'static PciDrv) {
+    MODULE_DRIVERS.write().retain(|other| !core::ptr::eq(*other, drv));
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pci_driver_class_match() {
+        let mut ahci = PciDevice {
+            addr: PciAddress {
+                bus: 0,
+                device: 0x1F,
+                function: 2,
+            },
+            vendor_id: 0x8086,
+            device_id: 0x2922,
+            subsystem_vendor_id: 0,
+            subsystem_id: 0,
+            class: 0x01,
+            subclass: 0x06,
+            prog_if: 0x01,
+            revision: 0,
+            header_type: 0,
+            interrupt_line: 0,
+        };
+        let matcher = |class| PciDevMatcher {
+            vendor_id: PciDevMatcher::ANY,
+            device_id: PciDevMatcher::ANY,
+            class,
+        };
+        assert!(matcher(pci::CLASS_MASS_STORAGE as u32).matches(&ahci));
+        assert!(matcher(PciDevMatcher::subclass(0x01, 0x06)).matches(&ahci));
+        assert!(matcher(PciDevMatcher::prog_if(0x01, 0x06, 0x01)).matches(&ahci));
+        assert!(!matcher(PciDevMatcher::subclass(0x01, 0x08)).matches(&ahci));
+        ahci.prog_if = 0x00;
+        assert!(matcher(PciDevMatcher::subclass(0x01, 0x06)).matches(&ahci));
+        assert!(!matcher(PciDevMatcher::prog_if(0x01, 0x06, 0x01)).matches(&ahci));
+    }
+}
@@ -1,15 +1,16 @@
-use alloc::{boxed::Box, sync::Arc};
-use core::ptr::NonNull;
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+use core::{alloc::Layout, ptr::NonNull};
 
 use crate::dev::{
-    Device, DeviceDriver,
+    DEVICES, Device, DeviceDriver,
     drivers::{
         ConsoleDevVTable, DriverCapabilities,
         platform::{PlatformDrv, PlatformDrvVTable},
     },
     platform::{PlatformDev, PlatformDevMatcher},
 };
-use spin::Mutex;
+use crate::sync::Mutex;
+use crate::util::ring::ByteRing;
 
 #[derive(Debug, Clone, Copy)]
 pub enum PixelFormat {
@@ -70,7 +71,7 @@ static FB_DRV: PlatformDrv = PlatformDrv {
         addr: None,
     }],
     caps: DriverCapabilities {
-        console: Some(&ConsoleDevVTable { write }),
+        console: Some(&ConsoleDevVTable { write, read: None }),
         ..Default::default()
     },
 };
@@ -103,15 +104,58 @@ fn write(dev: &Device, byte: u8) {
             .cast::<FramebufferWriter>()
             .as_mut()
     };
-    fb.inner.write_char(&mut fb.fb, byte as char);
+    fb.put(byte);
+}
+
+/// Runs `f` on the framebuffer console, if there is one
+fn with_console<R>(f: impl FnOnce(&mut FramebufferWriter) -> R) -> Option<R> {
+    let mut platform_devs = DEVICES.platform();
+    let dev = platform_devs.iter().find(|dev| dev.name == "efi_fb")?;
+    let data = dev.dev.drv.as_ref()?.data.lock();
+    Some(f(unsafe { data.cast::<FramebufferWriter>().as_mut() }))
+}
+
+/// Draws directly into the framebuffer of the console, meant for while it is suspended
+///
+/// Returns `false` if there is no framebuffer console.
+pub fn draw(f: impl FnOnce(&mut Framebuffer)) -> bool {
+    with_console(|writer| f(&mut writer.fb)).is_some()
+}
+
+/// Stops the console from drawing, while the display driver changes the buffer under it
+///
+/// Text written meanwhile only goes to the scrollback, and is drawn by [`resume_console`], except
+/// when panicking. Returns `false` if there is no framebuffer console.
+pub fn suspend_console() -> bool {
+    with_console(|writer| writer.suspended = true).is_some()
+}
+
+/// Lets the console draw again, into a new framebuffer if the mode changed
+///
+/// The screen is redrawn from the scrollback, wrapped to the width of the framebuffer.
+pub fn resume_console(fb: Option<Framebuffer>) {
+    with_console(|writer| {
+        if let Some(fb) = fb {
+            writer.fb = fb;
+        }
+        writer.suspended = false;
+        writer.redraw();
+    });
 }
 
 use noto_sans_mono_bitmap::{FontWeight, RasterHeight, RasterizedChar, get_raster, get_raster_width};
 use volatile::slice::VolatileSlice;
 
+/// The bytes of console output kept to redraw the screen, enough to fill a 1080p display
+const SCROLLBACK_SIZE: usize = 32 * 1024;
+
 pub struct FramebufferWriter {
     fb: Framebuffer,
     inner: FramebufferWriterInner,
+    /// The most recent output, in a box as it is too large for the stack
+    scrollback: Box<ByteRing<SCROLLBACK_SIZE>>,
+    /// Output is only recorded while the display changes modes
+    suspended: bool,
 }
 
 impl FramebufferWriter {
@@ -119,9 +163,40 @@ impl FramebufferWriter {
         Self {
             fb,
             inner: FramebufferWriterInner::new(),
+            scrollback: new_scrollback(),
+            suspended: false,
         }
     }
 
+    /// Draws a byte, and records it in the scrollback
+    fn put(&mut self, byte: u8) {
+        if byte != b'\r' {
+            self.scrollback.push_overwrite(byte);
+        }
+        if !self.suspended || crate::util::panicking::is_panicking() {
+            self.inner.write_char(&mut self.fb, byte as char);
+        }
+    }
+
+    /// Clears the screen, and draws as much of the scrollback as fits
+    fn redraw(&mut self) {
+        let text: Vec<u8> = self.scrollback.iter().collect();
+        let info = &self.fb.info;
+        let columns = (info.width as usize).saturating_sub(2 * BORDER_PADDING) / font_constants::CHAR_RASTER_WIDTH;
+        let rows = (info.height as usize).saturating_sub(BORDER_PADDING)
+            / (font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING);
+        self.fb.shadow.fill(0);
+        self.fb.buffer.fill(0);
+        self.inner = FramebufferWriterInner::new();
+        for byte in &text[replay_start(&text, columns, rows)..] {
+            self.inner.write_char(&mut self.fb, *byte as char);
+        }
+    }
+
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.fb
+    }
+
     pub fn fb_addr(&self) -> usize {
         self.fb.buffer.as_ptr() as usize
     }
@@ -140,10 +215,26 @@ impl FramebufferWriter {
     }
 }
 
+/// Allocates an empty scrollback directly on the heap, as it is too large for the stack
+fn new_scrollback() -> Box<ByteRing<SCROLLBACK_SIZE>> {
+    let layout = Layout::new::<ByteRing<SCROLLBACK_SIZE>>();
+    let ptr = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    if ptr.is_null() {
+        alloc::alloc::handle_alloc_error(layout);
+    }
+    // SAFETY: An empty ring is all zeroes
+    unsafe { Box::from_raw(ptr.cast()) }
+}
+
 impl core::fmt::Write for FramebufferWriter {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         for c in s.chars() {
-            self.inner.write_char(&mut self.fb, c);
+            for byte in c.encode_utf8(&mut [0; 4]).bytes().filter(|byte| *byte != b'\r') {
+                self.scrollback.push_overwrite(byte);
+            }
+            if !self.suspended || crate::util::panicking::is_panicking() {
+                self.inner.write_char(&mut self.fb, c);
+            }
         }
         Ok(())
     }
@@ -180,17 +271,11 @@ impl FramebufferWriterInner {
         let height = fb.info.height as usize;
         let line_height = font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
 
-        // Copy each line upwards
-        for y in line_height..height {
-            let dest_start = (y - line_height) * row_size;
-            let src_start = y * row_size;
-
-            fb.buffer.copy_within(src_start..(src_start + row_size), dest_start);
-        }
-
-        // Clear the last line
+        // Move the lines up in the shadow buffer, reading video memory is slow
         let last_line_start = (height - line_height) * row_size;
-        fb.buffer[last_line_start..].fill(0);
+        fb.shadow.copy_within(line_height * row_size..height * row_size, 0);
+        fb.shadow[last_line_start..].fill(0);
+        fb.buffer.copy_from_slice(&fb.shadow);
 
         // Reset y_pos to stay at the last line
         self.y_pos = height - line_height;
@@ -242,10 +327,33 @@ impl FramebufferWriterInner {
         let bpp = fb.info.bpp as usize;
         let byte_offset = y * fb.info.stride as usize + (x * bpp);
         let color = [intensity; 4];
+        fb.shadow[byte_offset..(byte_offset + bpp)].copy_from_slice(&color[..bpp]);
         fb.buffer[byte_offset..(byte_offset + bpp)].copy_from_slice(&color[..bpp]);
     }
 }
 
+/// Returns the offset in `text` from which drawing it fills at most `rows` rows of `columns`
+///
+/// Only whole lines are drawn, the oldest ones that don't fit are skipped.
+fn replay_start(text: &[u8], columns: usize, rows: usize) -> usize {
+    let mut used = 0;
+    let mut end = text.len();
+    loop {
+        let start = text[..end]
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |newline| newline + 1);
+        used += (end - start).div_ceil(columns.max(1)).max(1);
+        if used > rows {
+            return (end + 1).min(text.len());
+        }
+        if start == 0 {
+            return 0;
+        }
+        end = start - 1;
+    }
+}
+
 /// Additional vertical space between lines
 pub const LINE_SPACING: usize = 1;
 /// Additional horizontal space between characters.
@@ -294,12 +402,15 @@ pub struct FramebufferInfo {
 pub struct Framebuffer {
     pub info: FramebufferInfo,
     pub buffer: &'static mut VolatileSlice<u8>,
+    /// A copy of the buffer in RAM, so scrolling never reads video memory
+    pub shadow: Vec<u8>,
 }
 
 impl Framebuffer {
     pub fn new(info: FramebufferInfo, buffer: &'static mut [u8]) -> Self {
         Self {
             info,
+            shadow: vec![0; buffer.len()],
             buffer: VolatileSlice::from_slice_mut(buffer),
         }
     }
@@ -308,9 +419,20 @@ impl Framebuffer {
         let offset = (y * self.info.stride + x * self.info.bpp) as usize;
         match self.info.pixel_format {
             PixelFormat::RGB => {
-                self.buffer[offset].set((color & 0xFF) as u8);
-                self.buffer[offset + 1].set(((color >> 8) & 0xFF) as u8);
-                self.buffer[offset + 2].set(((color >> 16) & 0xFF) as u8);
+                let bytes = [color as u8, (color >> 8) as u8, (color >> 16) as u8];
+                self.shadow[offset..offset + 3].copy_from_slice(&bytes);
+                self.buffer[offset..offset + 3].copy_from_slice(&bytes);
+            }
+        }
+    }
+
+    /// Returns the color of a pixel, read from the shadow rather than video memory
+    pub fn read_pixel(&self, x: u32, y: u32) -> u32 {
+        let offset = (y * self.info.stride + x * self.info.bpp) as usize;
+        match self.info.pixel_format {
+            PixelFormat::RGB => {
+                let bytes = &self.shadow[offset..offset + 3];
+                (bytes[2] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[0] as u32
             }
         }
     }
@@ -342,3 +464,19 @@ impl Drop for FramebufferWriter {
         */
     }
 }
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fb_replay_start() {
+        let text = b"first\nsecond line\nthird";
+        assert_eq!(replay_start(text, 80, 25), 0);
+        // The second line wraps to two rows
+        assert_eq!(replay_start(text, 6, 3), 6);
+        assert_eq!(replay_start(text, 6, 2), 18);
+        assert_eq!(replay_start(b"done\n", 80, 1), 5);
+        assert_eq!(replay_start(b"", 80, 1), 0);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::dev::{
     drivers::DriverCapabilities,
     platform::{PlatformDev, PlatformDevMatcher},
+    uevent::{self, UeventAction},
 };
 
 pub mod fb;
@@ -26,7 +27,8 @@ impl PlatformDrv {
     }
 
     pub fn attach(&self, dev: &mut PlatformDev) {
-        (self.vtable.attach)(dev)
+        (self.vtable.attach)(dev);
+        uevent::emit(UeventAction::Bind, "platform", dev.name, Some(self.name));
     }
 }
 
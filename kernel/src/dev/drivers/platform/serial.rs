@@ -1,16 +1,47 @@
-use crate::arch::x86_64::io::uart::Uart16550;
+//! 16550 UART driver
+//!
+//! Writes go through a transmit ring, which the transmitter empty interrupt drains a FIFO's worth
+//! at a time, and received bytes are moved into a receive ring by the interrupt handler, where the
+//! console reads them. The interrupt is an ISA interrupt routed through the I/O APIC, which only
+//! exists once ACPI is initialized, so the port works polled until [`enable_irq`] succeeds.
+//!
+//! Output written with interrupts disabled, like from interrupt handlers, NMIs and panics, flushes
+//! the ring and is written polled, so it is never stuck behind an interrupt that can't arrive.
+//! When the ring is full, writers wait for room instead of dropping output.
+
+use crate::arch::{
+    instructions::interrupts,
+    x86_64::{
+        io::uart::{self, Uart16550},
+        ioapic,
+    },
+};
 use alloc::{boxed::Box, sync::Arc};
-use core::{ffi::c_void, ptr::NonNull};
+use core::{
+    ffi::c_void,
+    fmt,
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
+use crate::boot::Cmdline;
 use crate::dev::{
-    Device, DeviceDriver,
+    DEVICES, Device, DeviceDriver,
     drivers::{
         ConsoleDevVTable, DriverCapabilities,
         platform::{PlatformDrv, PlatformDrvVTable},
     },
     platform::{PlatformDev, PlatformDevAddr, PlatformDevMatcher},
 };
-use spin::Mutex;
+use crate::irq;
+use crate::kprintln;
+use crate::sync::Mutex;
+use crate::util::ring::ByteRing;
+
+const RX_RING_SIZE: usize = 1024;
+const TX_RING_SIZE: usize = 4096;
+/// The longest draining and filling the FIFOs in an interrupt should take
+const IRQ_BUDGET_NS: u64 = 100_000;
 
 #[used]
 #[unsafe(link_section = ".platform_drivers")]
@@ -22,27 +53,310 @@ static SERIAL_DRV: PlatformDrv = PlatformDrv {
         addr: Some(PlatformDevAddr::io_port(0x3F8)),
     }],
     caps: DriverCapabilities {
-        console: Some(&ConsoleDevVTable { write }),
+        console: Some(&ConsoleDevVTable {
+            write,
+            read: Some(read),
+        }),
         ..Default::default()
     },
 };
 
+/// Returns the baud rate given with `serial.baud=<rate>`, or the default
+pub fn baud(cmdline: Cmdline) -> u32 {
+    match cmdline.get("serial.baud").map(str::parse::<u32>) {
+        Some(Ok(baud)) if uart::divisor(baud).is_some() => baud,
+        _ => uart::DEFAULT_BAUD,
+    }
+}
+
+/// Returns the ISA interrupt of the standard COM ports
+fn isa_irq(port: u16) -> Option<u8> {
+    match port {
+        0x3F8 | 0x3E8 => Some(4),
+        0x2F8 | 0x2E8 => Some(3),
+        _ => None,
+    }
+}
+
+struct Port {
+    uart: Uart16550,
+    rx: ByteRing<RX_RING_SIZE>,
+    tx: ByteRing<TX_RING_SIZE>,
+    /// The vector, once the port is interrupt driven
+    vector: Option<u8>,
+    /// Whether the transmitter empty interrupt is enabled, which it only is while there is output
+    tx_irq: bool,
+}
+
+impl Port {
+    /// Moves received bytes into the receive ring
+    fn receive(&mut self, stats: &Stats) {
+        loop {
+            let (byte, overrun) = self.uart.read_status();
+            if overrun {
+                stats.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+            let Some(byte) = byte else { return };
+            stats.rx.fetch_add(1, Ordering::Relaxed);
+            if !self.rx.push(byte) {
+                stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Fills the transmit FIFO from the ring, if the transmitter is empty
+    fn transmit(&mut self, stats: &Stats) {
+        if !self.uart.is_transmit_empty() {
+            return;
+        }
+        for _ in 0..self.uart.tx_fifo_size() {
+            let Some(byte) = self.tx.pop() else { break };
+            self.uart.send(byte);
+            stats.tx.fetch_add(1, Ordering::Relaxed);
+        }
+        let tx_irq = self.vector.is_some() && !self.tx.is_empty();
+        if tx_irq != self.tx_irq {
+            self.tx_irq = tx_irq;
+            self.uart.set_interrupts(self.vector.is_some(), tx_irq);
+        }
+    }
+
+    /// Writes everything in the ring, polled
+    fn flush(&mut self, stats: &Stats) {
+        while !self.tx.is_empty() {
+            self.transmit(stats);
+            core::hint::spin_loop();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    rx: AtomicU64,
+    tx: AtomicU64,
+    /// Bytes the UART lost because nobody read them in time
+    overruns: AtomicU64,
+    /// Bytes lost because the receive ring was full
+    dropped: AtomicU64,
+}
+
+struct Serial {
+    io_port: u16,
+    port: Mutex<Port>,
+    stats: Stats,
+}
+
+impl Serial {
+    fn write(&self, byte: u8) {
+        let irqs_enabled = interrupts::are_enabled();
+        interrupts::without_interrupts(|| {
+            let mut port = match self.port.try_lock() {
+                Some(port) => port,
+                None if irqs_enabled => self.port.lock(),
+                // Interrupted in the middle of a write, by an NMI or a panic, waiting would deadlock
+                None => {
+                    // SAFETY: The port was initialized by the driver, and stays valid
+                    let mut uart = unsafe { Uart16550::new(self.io_port) };
+                    uart.write_byte(byte);
+                    return;
+                }
+            };
+            if port.vector.is_none() || !irqs_enabled {
+                port.flush(&self.stats);
+                port.uart.write_byte(byte);
+                self.stats.tx.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            while port.tx.is_full() {
+                port.transmit(&self.stats);
+                core::hint::spin_loop();
+            }
+            port.tx.push(byte);
+            port.transmit(&self.stats);
+        });
+    }
+
+    fn read(&self) -> Option<u8> {
+        interrupts::without_interrupts(|| {
+            let mut port = self.port.lock();
+            // Also catches bytes when the port is polled, or an interrupt was missed
+            port.receive(&self.stats);
+            port.rx.pop()
+        })
+    }
+}
+
+fn serial_irq(_vector: u8, data: usize) {
+    // SAFETY: The data is the leaked driver state, see `enable_irq`
+    let serial = unsafe { &*(data as *const Serial) };
+    let mut port = serial.port.lock();
+    port.receive(&serial.stats);
+    port.transmit(&serial.stats);
+}
+
 fn probe(_dev: &PlatformDev) -> bool {
     true
 }
 
 fn attach(dev: &mut PlatformDev) {
-    let mut serial = unsafe { Uart16550::new(dev.addr.io_port) };
-    unsafe { serial.init() };
-    let drv_data = Box::leak(Box::new(serial));
+    let io_port = unsafe { dev.addr.io_port };
+    let mut uart = unsafe { Uart16550::new(io_port) };
+    unsafe { uart.init(baud(crate::boot::cmdline())) };
+    let drv_data = Box::leak(Box::new(Serial {
+        io_port,
+        port: Mutex::new(Port {
+            uart,
+            rx: ByteRing::new(),
+            tx: ByteRing::new(),
+            vector: None,
+            tx_irq: false,
+        }),
+        stats: Stats::default(),
+    }));
     let dev = Arc::get_mut(&mut dev.dev).expect("a driver can only be attached when the device is not referenced");
-    dev.drv = NonNull::new(drv_data as *mut Uart16550 as *mut c_void).map(|data| DeviceDriver {
+    dev.drv = NonNull::new(drv_data as *mut Serial as *mut c_void).map(|data| DeviceDriver {
         data: Mutex::new(data),
         caps: &SERIAL_DRV.caps,
     });
 }
 
+fn serial(dev: &Device) -> &'static Serial {
+    // SAFETY: Set to the leaked state by `attach`, which is never freed
+    unsafe { dev.drv.as_ref().unwrap().data.lock().cast::<Serial>().as_ref() }
+}
+
 fn write(dev: &Device, byte: u8) {
-    let serial = unsafe { dev.drv.as_ref().unwrap().data.lock().cast::<Uart16550>().as_mut() };
-    serial.write_byte(byte);
+    serial(dev).write(byte);
+}
+
+fn read(dev: &Device) -> Option<u8> {
+    serial(dev).read()
+}
+
+fn serial_devices() -> impl Iterator<Item = (u16, Arc<Device>)> {
+    let mut platform_devs = DEVICES.platform();
+    let devices: alloc::vec::Vec<_> = platform_devs
+        .iter()
+        .filter(|dev| {
+            dev.dev
+                .drv
+                .as_ref()
+                .is_some_and(|drv| core::ptr::eq(drv.caps, &SERIAL_DRV.caps))
+        })
+        .map(|dev| (unsafe { dev.addr.io_port }, dev.dev.clone()))
+        .collect();
+    devices.into_iter()
+}
+
+/// Switches the serial ports to interrupt driven I/O, once the I/O APIC is initialized
+pub fn enable_irq() {
+    for (io_port, dev) in serial_devices() {
+        let serial = serial(&dev);
+        let Some(isa_irq) = isa_irq(io_port) else {
+            continue;
+        };
+        let vector = match irq::request_any_irq("serial", serial_irq, serial as *const Serial as usize) {
+            Ok(vector) => vector,
+            Err(err) => {
+                kprintln!(Warn, "serial: {:#x} stays polled: {}", io_port, err);
+                continue;
+            }
+        };
+        interrupts::without_interrupts(|| {
+            let mut port = serial.port.lock();
+            port.vector = Some(vector);
+            port.uart.set_interrupts(true, false);
+        });
+        match ioapic::route_isa(isa_irq, vector) {
+            Ok(source) => {
+                _ = irq::set_source(vector, source);
+                _ = irq::set_budget(vector, IRQ_BUDGET_NS);
+                kprintln!(Info, "serial: {:#x} on IRQ {}, vector {}", io_port, isa_irq, vector);
+            }
+            Err(err) => {
+                interrupts::without_interrupts(|| {
+                    let mut port = serial.port.lock();
+                    port.vector = None;
+                    port.uart.set_interrupts(false, false);
+                });
+                irq::free_irq(vector);
+                kprintln!(Warn, "serial: {:#x} stays polled: {}", io_port, err);
+            }
+        }
+    }
+    crate::stats::register("serial", dump_stats);
+}
+
+/// Sends everything queued for the serial ports, without waiting for ports in use
+pub fn flush() {
+    let Some(mut platform_devs) = DEVICES.try_platform() else {
+        return;
+    };
+    for dev in platform_devs.iter() {
+        let Some(drv) = dev
+            .dev
+            .drv
+            .as_ref()
+            .filter(|drv| core::ptr::eq(drv.caps, &SERIAL_DRV.caps))
+        else {
+            continue;
+        };
+        let Some(data) = drv.data.try_lock() else {
+            continue;
+        };
+        // SAFETY: Set to the leaked state by `attach`, which is never freed
+        let serial = unsafe { data.cast::<Serial>().as_ref() };
+        if let Some(mut port) = serial.port.try_lock() {
+            port.flush(&serial.stats);
+        }
+    }
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    for (io_port, dev) in serial_devices() {
+        let serial = serial(&dev);
+        let (baud, fifo, vector) = interrupts::without_interrupts(|| {
+            let port = serial.port.lock();
+            (port.uart.baud(), port.uart.tx_fifo_size(), port.vector)
+        });
+        let stats = &serial.stats;
+        writeln!(
+            out,
+            "{:#x}: {} baud, {} byte FIFO, {}, rx={} tx={} overruns={} dropped={}",
+            io_port,
+            baud,
+            fifo,
+            if vector.is_some() { "interrupt driven" } else { "polled" },
+            stats.rx.load(Ordering::Relaxed),
+            stats.tx.load(Ordering::Relaxed),
+            stats.overruns.load(Ordering::Relaxed),
+            stats.dropped.load(Ordering::Relaxed)
+        )?;
+    }
+    Ok(())
+}
+
+/// The serial console, for sending data meant for the host rather than for every console
+pub struct SerialConsole(Arc<Device>);
+
+/// Returns the serial console, if its driver is attached
+pub fn console() -> Option<SerialConsole> {
+    let mut platform_devs = DEVICES.platform();
+    platform_devs
+        .iter()
+        .find(|dev| dev.name == "io_dev" && dev.dev.drv.as_ref().is_some_and(|drv| drv.caps.console.is_some()))
+        .map(|dev| SerialConsole(dev.dev.clone()))
+}
+
+impl fmt::Write for SerialConsole {
+    /// Writes a string, sending line feeds as `\r\n`
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                write(&self.0, b'\r');
+            }
+            write(&self.0, byte);
+        }
+        Ok(())
+    }
 }
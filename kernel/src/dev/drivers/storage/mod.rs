@@ -0,0 +1,4 @@
+//! Storage drivers
+
+#[cfg(kconfig = "drivers.nvme")]
+pub mod nvme;
@@ -0,0 +1,734 @@
+//! NVMe controllers
+//!
+//! The controller gets an admin queue pair and a single I/O queue pair, in one block of DMA
+//! memory together with a bounce buffer. Block requests are copied through the bounce buffer, as
+//! the buffers passed to [`BlockDevice`] live in the heap and aren't physically contiguous. Every
+//! active namespace becomes a block device named `nvme<controller>n<namespace>`.
+//!
+//! Completions of the I/O queue raise an MSI-X interrupt, whose handler reaps the completion queue
+//! and signals the [`Completion`] of each command. Without MSI-X, or while interrupts are disabled,
+//! the submitter reaps it instead. Only one command
+//! is in flight per queue, there is no scheduler to run anything else while waiting.
+//!
+//! In QEMU that is `-drive file=disk.img,if=none,id=nvm -device nvme,serial=hadron,drive=nvm`.
+
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    arch::{PhysAddr, VirtAddr, instructions::interrupts},
+    block::{self, BlockDevice, BlockError},
+    dev::{
+        drivers::pci::{PciDevMatcher, PciDrv},
+        pci::{Bar, PciCommand, PciDevice, msix::MsixTable},
+    },
+    irq::{self, IrqSource},
+    kprintln,
+    mm::{
+        FRAME_ALLOCATOR,
+        mmio::{self, MmioRegion, MmioSpaceExhausted},
+        page_table::KernelPageTable,
+        paging::{PageSize, Size4KiB},
+    },
+    module::abi::{AbiSlice, AbiStr},
+    sync::{Completion, Mutex, Once},
+    time,
+};
+
+const REG_CAP: usize = 0x00;
+const REG_VS: usize = 0x08;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1C;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const REG_DOORBELLS: usize = 0x1000;
+
+/// The controller supports the NVM command set
+const CAP_CSS_NVM: u64 = 1 << 37;
+
+const CC_ENABLE: u32 = 1 << 0;
+/// Submission queue entries are 2^6 bytes
+const CC_IOSQES: u32 = 6 << 16;
+/// Completion queue entries are 2^4 bytes
+const CC_IOCQES: u32 = 4 << 20;
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+
+const ADMIN_OPCODE_CREATE_SQ: u8 = 0x01;
+const ADMIN_OPCODE_CREATE_CQ: u8 = 0x05;
+const ADMIN_OPCODE_IDENTIFY: u8 = 0x06;
+const ADMIN_OPCODE_SET_FEATURES: u8 = 0x09;
+const OPCODE_FLUSH: u8 = 0x00;
+const OPCODE_WRITE: u8 = 0x01;
+const OPCODE_READ: u8 = 0x02;
+
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+const FEATURE_NUM_QUEUES: u32 = 0x07;
+
+/// The queue is physically contiguous
+const QUEUE_CONTIGUOUS: u32 = 1 << 0;
+const CQ_INTERRUPTS: u32 = 1 << 1;
+
+const ADMIN_QUEUE: u16 = 0;
+const IO_QUEUE: u16 = 1;
+const ADMIN_QUEUE_SIZE: u16 = 16;
+const IO_QUEUE_SIZE: u16 = 64;
+
+const PAGE_SIZE: usize = Size4KiB::SIZE;
+// The layout of the DMA memory, in pages
+const PAGE_ADMIN_SQ: usize = 0;
+const PAGE_ADMIN_CQ: usize = 1;
+const PAGE_IO_SQ: usize = 2;
+const PAGE_IO_CQ: usize = 3;
+const PAGE_IDENTIFY: usize = 4;
+/// The PRP list describing the bounce buffer after its first page
+const PAGE_PRP_LIST: usize = 5;
+const PAGE_BOUNCE: usize = 6;
+const BOUNCE_PAGES: usize = 16;
+const DMA_PAGES: usize = PAGE_BOUNCE + BOUNCE_PAGES;
+
+/// How long a command may take
+const COMMAND_TIMEOUT_NS: u64 = 5_000_000_000;
+/// How long to wait for the interrupt before reaping the completion queue anyway
+const IRQ_GRACE_NS: u64 = 1_000_000;
+
+/// The longest reaping the completions of an interrupt should take
+const IRQ_BUDGET_NS: u64 = 50_000;
+
+static CONTROLLERS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub enum NvmeError {
+    /// BAR0 is missing or not a memory BAR
+    NoMmio,
+    Mmio(MmioSpaceExhausted),
+    /// The controller doesn't support the NVM command set or 4 KiB pages
+    Unsupported,
+    OutOfMemory,
+    /// The controller didn't become ready or complete a command in time
+    Timeout,
+    /// The controller reported a fatal error
+    Fatal,
+    /// A command failed with the given status
+    Command {
+        status: u16,
+    },
+}
+
+impl fmt::Display for NvmeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoMmio => f.write_str("no MMIO BAR"),
+            Self::Mmio(err) => write!(f, "registers: {}", err),
+            Self::Unsupported => f.write_str("controller doesn't support the NVM command set with 4 KiB pages"),
+            Self::OutOfMemory => f.write_str("out of memory for the queues"),
+            Self::Timeout => f.write_str("controller timed out"),
+            Self::Fatal => f.write_str("controller fatal status"),
+            Self::Command { status } => write!(f, "command failed with status {:#x}", status),
+        }
+    }
+}
+
+impl core::error::Error for NvmeError {}
+
+/// A submission queue entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Command {
+    opcode: u8,
+    flags: u8,
+    cid: u16,
+    nsid: u32,
+    _reserved: u64,
+    metadata: u64,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+}
+
+/// A completion queue entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct CompletionEntry {
+    result: u32,
+    _reserved: u32,
+    sq_head: u16,
+    sq_id: u16,
+    cid: u16,
+    /// The phase tag in bit 0, and the status above it
+    status: u16,
+}
+
+const _: () = assert!(size_of::<Command>() == 64 && size_of::<CompletionEntry>() == 16);
+
+/// The geometry of a namespace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct NamespaceInfo {
+    blocks: u64,
+    block_size: usize,
+}
+
+/// Reads the size and the LBA format in use from an Identify Namespace structure
+///
+/// Formats with metadata interleaved with the data aren't supported.
+fn parse_namespace(data: &[u8]) -> Option<NamespaceInfo> {
+    let blocks = u64::from_le_bytes(data.get(0..8)?.try_into().ok()?);
+    let flbas = *data.get(26)?;
+    let format = 128 + (flbas & 0xF) as usize * 4;
+    let lbaf = u32::from_le_bytes(data.get(format..format + 4)?.try_into().ok()?);
+    let lba_shift = (lbaf >> 16) & 0xFF;
+    let extended = flbas & (1 << 4) != 0 && lbaf & 0xFFFF != 0;
+    if blocks == 0 || !(9..=12).contains(&lba_shift) || extended {
+        return None;
+    }
+    Some(NamespaceInfo {
+        blocks,
+        block_size: 1 << lba_shift,
+    })
+}
+
+/// Returns the two PRP entries for a transfer of `len` bytes from the start of the bounce buffer
+///
+/// Up to two pages are described directly, beyond that the second entry points to the PRP list,
+/// which holds the addresses of the bounce pages after the first.
+fn bounce_prps(len: usize, bounce: u64, prp_list: u64) -> (u64, u64) {
+    match len.div_ceil(PAGE_SIZE) {
+        0 | 1 => (bounce, 0),
+        2 => (bounce, bounce + PAGE_SIZE as u64),
+        _ => (bounce, prp_list),
+    }
+}
+
+/// Trims an ASCII field of an Identify structure, which is padded with spaces
+fn identify_string(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).trim().into()
+}
+
+struct Registers {
+    region: MmioRegion,
+    /// The distance between doorbells, in bytes
+    doorbell_stride: usize,
+}
+
+impl Registers {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { self.region.read(reg) }
+    }
+
+    fn read_u64(&self, reg: usize) -> u64 {
+        self.read(reg) as u64 | (self.read(reg + 4) as u64) << 32
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { self.region.write(reg, value) }
+    }
+
+    fn write_u64(&self, reg: usize, value: u64) {
+        self.write(reg, value as u32);
+        self.write(reg + 4, (value >> 32) as u32);
+    }
+
+    fn sq_doorbell(&self, queue: u16) -> usize {
+        REG_DOORBELLS + 2 * queue as usize * self.doorbell_stride
+    }
+
+    fn cq_doorbell(&self, queue: u16) -> usize {
+        REG_DOORBELLS + (2 * queue as usize + 1) * self.doorbell_stride
+    }
+
+    /// Waits for the ready bit to reach `ready`, for at most `timeout_ns`
+    fn wait_ready(&self, ready: bool, timeout_ns: u64) -> Result<(), NvmeError> {
+        let deadline = time::monotonic_ns() + timeout_ns;
+        loop {
+            let status = self.read(REG_CSTS);
+            if status & CSTS_FATAL != 0 && ready {
+                return Err(NvmeError::Fatal);
+            }
+            if (status & CSTS_READY != 0) == ready {
+                return Ok(());
+            }
+            if time::monotonic_ns() > deadline {
+                return Err(NvmeError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+struct SubmissionQueue {
+    entries: VirtAddr,
+    tail: u16,
+}
+
+struct CompletionQueue {
+    entries: VirtAddr,
+    head: u16,
+    /// The phase tag of new entries, which flips every time the queue wraps
+    phase: bool,
+}
+
+/// A submission queue and the completion queue it completes into
+struct QueuePair {
+    id: u16,
+    size: u16,
+    /// Held for the whole command, so there is one in flight at a time
+    sq: Mutex<SubmissionQueue>,
+    cq: Mutex<CompletionQueue>,
+    /// Signalled for every command id when its completion is reaped, with its result or status
+    slots: Vec<Completion<Result<u32, u16>>>,
+}
+
+impl QueuePair {
+    fn new(id: u16, size: u16, sq: VirtAddr, cq: VirtAddr) -> Self {
+        Self {
+            id,
+            size,
+            sq: Mutex::new(SubmissionQueue { entries: sq, tail: 0 }),
+            cq: Mutex::new(CompletionQueue {
+                entries: cq,
+                head: 0,
+                phase: true,
+            }),
+            slots: (0..size).map(|_| Completion::new()).collect(),
+        }
+    }
+
+    /// Signals the commands of the new completions, returning whether there were any
+    fn reap(&self, regs: &Registers) -> bool {
+        let mut cq = self.cq.lock();
+        let mut reaped = false;
+        loop {
+            let entry = unsafe {
+                cq.entries
+                    .as_ptr::<CompletionEntry>()
+                    .add(cq.head as usize)
+                    .read_volatile()
+            };
+            if (entry.status & 1 != 0) != cq.phase {
+                break;
+            }
+            if let Some(slot) = self.slots.get(entry.cid as usize) {
+                slot.complete(match entry.status >> 1 {
+                    0 => Ok(entry.result),
+                    status => Err(status),
+                });
+            }
+            cq.head += 1;
+            if cq.head == self.size {
+                cq.head = 0;
+                cq.phase = !cq.phase;
+            }
+            reaped = true;
+        }
+        if reaped {
+            regs.write(regs.cq_doorbell(self.id), cq.head as u32);
+        }
+        reaped
+    }
+
+    /// Submits a command and waits for it to complete, returning its result
+    ///
+    /// With `irq` set, the interrupt handler is expected to reap the completion.
+    fn execute(&self, regs: &Registers, mut command: Command, irq: bool) -> Result<u32, NvmeError> {
+        let mut sq = self.sq.lock();
+        let cid = sq.tail;
+        let slot = &self.slots[cid as usize];
+        // Drops the result of the previous command with this id if it completed after timing out
+        slot.reset();
+        command.cid = cid;
+        unsafe {
+            sq.entries
+                .as_mut_ptr::<Command>()
+                .add(cid as usize)
+                .write_volatile(command)
+        };
+        sq.tail = (sq.tail + 1) % self.size;
+        regs.write(regs.sq_doorbell(self.id), sq.tail as u32);
+
+        let start = time::monotonic_ns();
+        loop {
+            if let Some(result) = slot.try_take() {
+                return result.map_err(|status| NvmeError::Command { status });
+            }
+            let elapsed = time::monotonic_ns() - start;
+            if !irq || !interrupts::are_enabled() || elapsed > IRQ_GRACE_NS {
+                interrupts::without_interrupts(|| self.reap(regs));
+            }
+            if elapsed > COMMAND_TIMEOUT_NS {
+                return Err(NvmeError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+pub struct NvmeController {
+    name: String,
+    regs: Registers,
+    dma: PhysAddr,
+    admin: QueuePair,
+    io: QueuePair,
+    /// Whether completions of the I/O queue raise an interrupt
+    irq: AtomicBool,
+    msix: Once<MsixTable>,
+    /// Held while the bounce buffer is in use
+    bounce: Mutex<()>,
+}
+
+impl NvmeController {
+    fn page(&self, page: usize) -> (u64, VirtAddr) {
+        let phys = self.dma + page * PAGE_SIZE;
+        (
+            phys.as_usize() as u64,
+            KernelPageTable::direct_map_start() + phys.as_usize(),
+        )
+    }
+
+    fn admin(&self, command: Command) -> Result<u32, NvmeError> {
+        self.admin.execute(&self.regs, command, false)
+    }
+
+    /// Runs an Identify command, returning the structure it wrote
+    fn identify(&self, cns: u32, nsid: u32) -> Result<&[u8], NvmeError> {
+        let (phys, virt) = self.page(PAGE_IDENTIFY);
+        self.admin(Command {
+            opcode: ADMIN_OPCODE_IDENTIFY,
+            nsid,
+            prp1: phys,
+            cdw10: cns,
+            ..Default::default()
+        })?;
+        Ok(unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), PAGE_SIZE) })
+    }
+
+    /// Creates the I/O queue pair, interrupting on `msix_entry` if given
+    fn create_io_queues(&self, msix_entry: Option<u16>) -> Result<(), NvmeError> {
+        let size_and_id = (IO_QUEUE_SIZE as u32 - 1) << 16 | IO_QUEUE as u32;
+        let interrupts = match msix_entry {
+            Some(entry) => (entry as u32) << 16 | CQ_INTERRUPTS,
+            None => 0,
+        };
+        self.admin(Command {
+            opcode: ADMIN_OPCODE_CREATE_CQ,
+            prp1: self.page(PAGE_IO_CQ).0,
+            cdw10: size_and_id,
+            cdw11: interrupts | QUEUE_CONTIGUOUS,
+            ..Default::default()
+        })?;
+        self.admin(Command {
+            opcode: ADMIN_OPCODE_CREATE_SQ,
+            prp1: self.page(PAGE_IO_SQ).0,
+            cdw10: size_and_id,
+            cdw11: (IO_QUEUE as u32) << 16 | QUEUE_CONTIGUOUS,
+            ..Default::default()
+        })?;
+        Ok(())
+    }
+
+    /// Returns the ids of the active namespaces
+    fn namespaces(&self, count: u32) -> Vec<u32> {
+        // NVMe 1.0 controllers don't have the active namespace list
+        match self.identify(IDENTIFY_ACTIVE_NAMESPACES, 0) {
+            Ok(list) => list
+                .as_chunks::<4>()
+                .0
+                .iter()
+                .map(|id| u32::from_le_bytes(*id))
+                .take_while(|id| *id != 0)
+                .collect(),
+            Err(_) => (1..=count.min(16)).collect(),
+        }
+    }
+
+    /// Runs a read, write or flush on the I/O queue, through the bounce buffer
+    fn io(&self, opcode: u8, nsid: u32, lba: u64, blocks: usize, block_size: usize) -> Result<(), NvmeError> {
+        let (bounce, _) = self.page(PAGE_BOUNCE);
+        let (prp1, prp2) = bounce_prps(blocks * block_size, bounce, self.page(PAGE_PRP_LIST).0);
+        let command = Command {
+            opcode,
+            nsid,
+            prp1: if blocks == 0 { 0 } else { prp1 },
+            prp2: if blocks == 0 { 0 } else { prp2 },
+            cdw10: lba as u32,
+            cdw11: (lba >> 32) as u32,
+            cdw12: blocks.saturating_sub(1) as u32,
+            ..Default::default()
+        };
+        self.io
+            .execute(&self.regs, command, self.irq.load(Ordering::Relaxed))
+            .map(drop)
+    }
+}
+
+/// Reaps the I/O completion queue, `data` points to the controller
+fn nvme_irq(_vector: u8, data: usize) {
+    let ctrl = unsafe { &*(data as *const NvmeController) };
+    ctrl.io.reap(&ctrl.regs);
+}
+
+/// A namespace of a controller, as a block device
+pub struct NvmeNamespace {
+    ctrl: Arc<NvmeController>,
+    nsid: u32,
+    info: NamespaceInfo,
+    /// The most blocks moved by one command
+    max_blocks: usize,
+}
+
+impl BlockDevice for NvmeNamespace {
+    fn block_size(&self) -> usize {
+        self.info.block_size
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.info.blocks
+    }
+
+    fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let block_size = self.info.block_size;
+        let (_, bounce) = self.ctrl.page(PAGE_BOUNCE);
+        for (idx, chunk) in buf.chunks_mut(self.max_blocks * block_size).enumerate() {
+            let _bounce = self.ctrl.bounce.lock();
+            let lba = lba + (idx * self.max_blocks) as u64;
+            self.ctrl
+                .io(OPCODE_READ, self.nsid, lba, chunk.len() / block_size, block_size)
+                .map_err(|_| BlockError::Io)?;
+            unsafe { ptr::copy_nonoverlapping(bounce.as_ptr::<u8>(), chunk.as_mut_ptr(), chunk.len()) };
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let block_size = self.info.block_size;
+        let (_, bounce) = self.ctrl.page(PAGE_BOUNCE);
+        for (idx, chunk) in buf.chunks(self.max_blocks * block_size).enumerate() {
+            let _bounce = self.ctrl.bounce.lock();
+            unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), bounce.as_mut_ptr::<u8>(), chunk.len()) };
+            let lba = lba + (idx * self.max_blocks) as u64;
+            self.ctrl
+                .io(OPCODE_WRITE, self.nsid, lba, chunk.len() / block_size, block_size)
+                .map_err(|_| BlockError::Io)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.ctrl
+            .io(OPCODE_FLUSH, self.nsid, 0, 0, self.info.block_size)
+            .map_err(|_| BlockError::Io)
+    }
+}
+
+/// Routes the I/O completions to an MSI-X vector, returning the table entry used
+fn setup_msix(ctrl: &Arc<NvmeController>, dev: &PciDevice) -> Option<u16> {
+    let table = match MsixTable::new(dev) {
+        Ok(table) => table,
+        Err(err) => {
+            kprintln!(Info, "{}: {}, polling for completions", ctrl.name, err);
+            return None;
+        }
+    };
+    // Entry 0 belongs to the admin queue, which is polled
+    let entry = 1.min(table.entries() - 1);
+    // The controller is never freed, devices can't be unbound
+    let data = Arc::into_raw(ctrl.clone()) as usize;
+    let vector = match irq::request_any_irq("nvme", nvme_irq, data) {
+        Ok(vector) => vector,
+        Err(err) => {
+            kprintln!(Warn, "{}: {}, polling for completions", ctrl.name, err);
+            return None;
+        }
+    };
+    _ = irq::set_source(vector, IrqSource::Msi);
+    _ = irq::set_budget(vector, IRQ_BUDGET_NS);
+    if let Err(err) = table.set_vector(entry, vector) {
+        kprintln!(Warn, "{}: {}, polling for completions", ctrl.name, err);
+        irq::free_irq(vector);
+        return None;
+    }
+    table.enable();
+    ctrl.msix.call_once(|| table);
+    Some(entry)
+}
+
+/// Resets and enables a controller, and registers its namespaces as block devices
+pub fn probe(dev: &PciDevice) -> Result<(), NvmeError> {
+    let Some(Bar::Memory { addr, size, .. }) = dev.bar(0) else {
+        return Err(NvmeError::NoMmio);
+    };
+    dev.enable(PciCommand::MEMORY_SPACE | PciCommand::BUS_MASTER);
+    // SAFETY: The BAR belongs to the device
+    let region = unsafe { mmio::map(addr, size) }.map_err(NvmeError::Mmio)?;
+    let mut regs = Registers {
+        region,
+        doorbell_stride: 4,
+    };
+    let cap = regs.read_u64(REG_CAP);
+    regs.doorbell_stride = 4 << ((cap >> 32) & 0xF);
+    // Only 4 KiB pages are used, which the controller must support as its smallest
+    if cap & CAP_CSS_NVM == 0 || (cap >> 48) & 0xF != 0 {
+        return Err(NvmeError::Unsupported);
+    }
+    let max_entries = (cap & 0xFFFF) as u16 + 1;
+    // The worst case time to change the ready bit, in units of 500 ms
+    let ready_timeout_ns = ((cap >> 24) & 0xFF).max(1) * 500_000_000;
+
+    regs.write(REG_CC, 0);
+    regs.wait_ready(false, ready_timeout_ns)?;
+
+    let dma = FRAME_ALLOCATOR
+        .lock()
+        .allocate_contiguous(DMA_PAGES)
+        .ok_or(NvmeError::OutOfMemory)?
+        .start_address();
+    let virt = |page: usize| KernelPageTable::direct_map_start() + (dma + page * PAGE_SIZE).as_usize();
+    unsafe { ptr::write_bytes(virt(0).as_mut_ptr::<u8>(), 0, DMA_PAGES * PAGE_SIZE) };
+    // The PRP list covers the bounce buffer after its first page
+    for page in 1..BOUNCE_PAGES {
+        let entry = (dma + (PAGE_BOUNCE + page) * PAGE_SIZE).as_usize() as u64;
+        unsafe { virt(PAGE_PRP_LIST).as_mut_ptr::<u64>().add(page - 1).write(entry) };
+    }
+
+    let admin_size = ADMIN_QUEUE_SIZE.min(max_entries);
+    let io_size = IO_QUEUE_SIZE.min(max_entries);
+    let ctrl = Arc::new(NvmeController {
+        name: format!("nvme{}", CONTROLLERS.fetch_add(1, Ordering::Relaxed)),
+        dma,
+        admin: QueuePair::new(ADMIN_QUEUE, admin_size, virt(PAGE_ADMIN_SQ), virt(PAGE_ADMIN_CQ)),
+        io: QueuePair::new(IO_QUEUE, io_size, virt(PAGE_IO_SQ), virt(PAGE_IO_CQ)),
+        irq: AtomicBool::new(false),
+        msix: Once::new(),
+        bounce: Mutex::new(()),
+        regs,
+    });
+    let regs = &ctrl.regs;
+    regs.write(REG_AQA, (admin_size as u32 - 1) << 16 | (admin_size as u32 - 1));
+    regs.write_u64(REG_ASQ, ctrl.page(PAGE_ADMIN_SQ).0);
+    regs.write_u64(REG_ACQ, ctrl.page(PAGE_ADMIN_CQ).0);
+    regs.write(REG_CC, CC_ENABLE | CC_IOSQES | CC_IOCQES);
+    regs.wait_ready(true, ready_timeout_ns)?;
+
+    let identify = ctrl.identify(IDENTIFY_CONTROLLER, 0)?;
+    let model = identify_string(&identify[24..64]);
+    let serial = identify_string(&identify[4..24]);
+    // In units of the smallest page size, which is 4 KiB here
+    let mdts = identify[77];
+    let namespace_count = u32::from_le_bytes(identify[516..520].try_into().unwrap());
+    let max_transfer = match mdts {
+        0 => BOUNCE_PAGES * PAGE_SIZE,
+        mdts => (PAGE_SIZE << mdts.min(20)).min(BOUNCE_PAGES * PAGE_SIZE),
+    };
+    let version = regs.read(REG_VS);
+    kprintln!(
+        Info,
+        "{}: {} {} (serial {}), NVMe {}.{}",
+        ctrl.name,
+        dev.addr,
+        model,
+        serial,
+        version >> 16,
+        (version >> 8) & 0xFF
+    );
+
+    // One I/O submission and completion queue, both counts are zero based
+    ctrl.admin(Command {
+        opcode: ADMIN_OPCODE_SET_FEATURES,
+        cdw10: FEATURE_NUM_QUEUES,
+        cdw11: 0,
+        ..Default::default()
+    })?;
+    let msix_entry = setup_msix(&ctrl, dev);
+    ctrl.create_io_queues(msix_entry)?;
+    ctrl.irq.store(msix_entry.is_some(), Ordering::Relaxed);
+
+    for nsid in ctrl.namespaces(namespace_count) {
+        let Some(info) = ctrl.identify(IDENTIFY_NAMESPACE, nsid).ok().and_then(parse_namespace) else {
+            kprintln!(Warn, "{}: namespace {} has an unsupported format", ctrl.name, nsid);
+            continue;
+        };
+        if max_transfer < info.block_size {
+            kprintln!(
+                Warn,
+                "{}: namespace {} has blocks larger than a transfer",
+                ctrl.name,
+                nsid
+            );
+            continue;
+        }
+        let name = format!("{}n{}", ctrl.name, nsid).leak();
+        kprintln!(Info, "{}: {} blocks of {} bytes", name, info.blocks, info.block_size);
+        let namespace = NvmeNamespace {
+            ctrl: ctrl.clone(),
+            nsid,
+            info,
+            max_blocks: max_transfer / info.block_size,
+        };
+        block::register(name, Arc::new(namespace));
+    }
+    Ok(())
+}
+
+#[used]
+#[cfg_attr(target_arch = "x86_64", unsafe(link_section = ".pci_drivers"))]
+static NVME_DRV: PciDrv = PciDrv {
+    name: AbiStr::new("nvme"),
+    matchers: AbiSlice::new(&[PciDevMatcher {
+        vendor_id: PciDevMatcher::ANY,
+        device_id: PciDevMatcher::ANY,
+        class: PciDevMatcher::prog_if(0x01, 0x08, 0x02),
+    }]),
+    probe: probe_drv,
+};
+
+extern "C" fn probe_drv(dev: &PciDevice) -> bool {
+    match probe(dev) {
+        Ok(()) => true,
+        Err(err) => {
+            kprintln!(Warn, "nvme: {}: {}", dev.addr, err);
+            false
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nvme_identify() {
+        let mut data = [0u8; 4096];
+        data[0..8].copy_from_slice(&2048u64.to_le_bytes());
+        // Format 1 is in use, with 4 KiB blocks
+        data[26] = 1;
+        data[128..132].copy_from_slice(&(9u32 << 16).to_le_bytes());
+        data[132..136].copy_from_slice(&(12u32 << 16).to_le_bytes());
+        assert_eq!(
+            parse_namespace(&data),
+            Some(NamespaceInfo {
+                blocks: 2048,
+                block_size: 4096
+            })
+        );
+        // Metadata interleaved with the data
+        data[26] = 1 | 1 << 4;
+        data[132..136].copy_from_slice(&(12u32 << 16 | 8).to_le_bytes());
+        assert_eq!(parse_namespace(&data), None);
+
+        assert_eq!(bounce_prps(512, 0x10000, 0x5000), (0x10000, 0));
+        assert_eq!(bounce_prps(8192, 0x10000, 0x5000), (0x10000, 0x11000));
+        assert_eq!(bounce_prps(8193, 0x10000, 0x5000), (0x10000, 0x5000));
+        assert_eq!(identify_string(b"QEMU NVMe Ctrl    "), "QEMU NVMe Ctrl");
+    }
+}
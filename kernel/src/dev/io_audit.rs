@@ -0,0 +1,282 @@
+//! Checking device memory and I/O port accesses, enabled with the `io_audit` option
+//!
+//! Every region mapped with [`mmio::map`](crate::mm::mmio::map) is recorded with its owner: the
+//! call site that mapped it, and the PCI driver being probed at the time, if any. Accesses through
+//! [`mmio::read`](crate::mm::mmio::read), [`mmio::write`](crate::mm::mmio::write) or the driver API
+//! pass the base of the region they mean to access, so an offset that runs past the end of the
+//! region is caught before it reaches whatever is mapped next, usually the registers of another
+//! device. I/O ports are claimed with [`claim_ports`], and
+//! accesses to ports nobody claimed, or straddling two claims, are caught the same way.
+//!
+//! Reports are panics naming the owner and the caller. Without the option nothing is recorded,
+//! besides port claims going to the [`resource`] tree, and the checks compile to nothing.
+
+use core::{
+    fmt,
+    panic::Location,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{
+    arch::{PhysAddr, VirtAddr, instructions::interrupts},
+    dev::{
+        pci::PciAddress,
+        resource::{self, ResourceError, ResourceKind},
+    },
+    percpu::{MAX_CPUS, PerCpu},
+};
+
+/// The most I/O port ranges that can be claimed, ports are claimed before the heap exists
+const MAX_PORT_CLAIMS: usize = 32;
+
+/// A mapped region of device memory
+#[derive(Debug, Clone)]
+struct Region {
+    virt: usize,
+    size: usize,
+    phys: PhysAddr,
+    site: &'static Location<'static>,
+    /// The PCI driver and function being probed when the region was mapped
+    driver: Option<String>,
+}
+
+impl Region {
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.virt && addr < self.virt + self.size
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}+{:#x}", self.phys.as_usize(), self.size)?;
+        if let Some(driver) = &self.driver {
+            write!(f, " of {}", driver)?;
+        }
+        write!(f, " mapped at {}", self.site)
+    }
+}
+
+/// A range of I/O ports claimed by a driver
+#[derive(Debug, Clone, Copy)]
+struct PortClaim {
+    first: u16,
+    count: u16,
+    owner: &'static str,
+    site: &'static Location<'static>,
+}
+
+impl PortClaim {
+    fn contains(&self, port: u16) -> bool {
+        port >= self.first && (port - self.first) < self.count
+    }
+}
+
+impl fmt::Display for PortClaim {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#x}..{:#x} of {} claimed at {}",
+            self.first,
+            self.first as u32 + self.count as u32,
+            self.owner,
+            self.site
+        )
+    }
+}
+
+// The registries use spin locks directly, as lockdep reports through the serial port, whose
+// accesses are checked here
+static REGIONS: spin::RwLock<Vec<Region>> = spin::RwLock::new(Vec::new());
+static PORTS: spin::RwLock<[Option<PortClaim>; MAX_PORT_CLAIMS]> = spin::RwLock::new([None; MAX_PORT_CLAIMS]);
+static PROBING: PerCpu<spin::Mutex<Option<String>>> = PerCpu::new([const { spin::Mutex::new(None) }; MAX_CPUS]);
+/// Set once a report is being made, so printing it doesn't report again
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// Attributes regions mapped on this CPU to a PCI driver until dropped
+#[derive(Debug)]
+pub struct Probing(());
+
+impl Drop for Probing {
+    fn drop(&mut self) {
+        if crate::config::IO_AUDIT {
+            *PROBING.get().lock() = None;
+        }
+    }
+}
+
+/// Attributes the regions mapped while the guard is held to a driver probing a PCI function
+pub fn probing(driver: &str, addr: PciAddress) -> Probing {
+    if crate::config::IO_AUDIT {
+        *PROBING.get().lock() = Some(format!("{} {}", driver, addr));
+    }
+    Probing(())
+}
+
+/// Records a region mapped by [`map`](crate::mm::mmio::map)
+pub(crate) fn mapped(virt: VirtAddr, phys: PhysAddr, size: usize, site: &'static Location<'static>) {
+    if !crate::config::IO_AUDIT {
+        return;
+    }
+    let region = Region {
+        virt: virt.as_usize(),
+        size,
+        phys,
+        site,
+        driver: PROBING.get().lock().clone(),
+    };
+    interrupts::without_interrupts(|| REGIONS.write().push(region));
+}
+
+/// Forgets a region that was unmapped
+pub(crate) fn unmapped(virt: VirtAddr) {
+    if !crate::config::IO_AUDIT {
+        return;
+    }
+    interrupts::without_interrupts(|| REGIONS.write().retain(|region| region.virt != virt.as_usize()));
+}
+
+#[track_caller]
+fn report(args: fmt::Arguments<'_>) {
+    if !REPORTING.swap(true, Ordering::Relaxed) {
+        panic!("io audit: {}", args);
+    }
+}
+
+/// Checks that `size` bytes at `offset` into the region mapped at `base` are inside it
+#[track_caller]
+pub fn check_mmio(base: VirtAddr, offset: usize, size: usize) {
+    if !crate::config::IO_AUDIT || REPORTING.load(Ordering::Relaxed) {
+        return;
+    }
+    let regions = REGIONS.read();
+    let Some(region) = regions.iter().find(|region| region.contains(base.as_usize())) else {
+        drop(regions);
+        return report(format_args!(
+            "access through {:#x}, which isn't mapped device memory",
+            base.as_usize()
+        ));
+    };
+    let start = base.as_usize() - region.virt + offset;
+    if start.checked_add(size).is_some_and(|end| end <= region.size) {
+        return;
+    }
+    let target = region.virt.wrapping_add(start);
+    let region = region.clone();
+    let neighbor = regions.iter().find(|other| other.contains(target)).cloned();
+    drop(regions);
+    match neighbor {
+        Some(neighbor) => report(format_args!(
+            "{} byte access at offset {:#x} of {} lands in {}",
+            size, start, region, neighbor
+        )),
+        None => report(format_args!(
+            "{} byte access at offset {:#x} of {}",
+            size, start, region
+        )),
+    }
+}
+
+/// Claims `count` I/O ports from `first` on for `owner`, claiming the same ports again is allowed
+///
+/// The claim is recorded in the [`resource`] tree whether the option is enabled or not, and lasts
+/// until reboot.
+///
+/// # Panics
+/// If the ports overlap ports claimed by someone else, or there are too many claims.
+#[track_caller]
+pub fn claim_ports(first: u16, count: u16, owner: &'static str) {
+    let range = first as u64..=first as u64 + (count as u64 - 1);
+    match resource::claim(ResourceKind::Port, range.clone(), owner) {
+        Ok(claim) => claim.forget(),
+        Err(ResourceError::Conflict {
+            owner: other,
+            start,
+            end,
+            ..
+        }) if (other, start, end) == (owner, *range.start(), *range.end()) => {}
+        Err(err) => crate::kprintln!(Warn, "{}: ports {:#x}-{:#x} {}", owner, range.start(), range.end(), err),
+    }
+    if !crate::config::IO_AUDIT {
+        return;
+    }
+    let claim = PortClaim {
+        first,
+        count,
+        owner,
+        site: Location::caller(),
+    };
+    let last = first + (count - 1);
+    let result = interrupts::without_interrupts(|| {
+        let mut ports = PORTS.write();
+        let existing = ports.iter().flatten();
+        if let Some(other) = existing
+            .clone()
+            .find(|other| other.contains(first) || other.contains(last) || claim.contains(other.first))
+        {
+            return match (other.first, other.count, other.owner) == (first, count, owner) {
+                true => Ok(()),
+                false => Err(Some(*other)),
+            };
+        }
+        let slot = ports.iter_mut().find(|slot| slot.is_none()).ok_or(None)?;
+        *slot = Some(claim);
+        Ok(())
+    });
+    match result {
+        Ok(()) => {}
+        Err(Some(other)) => report(format_args!("{} overlaps {}", claim, other)),
+        Err(None) => report(format_args!("more than {} port claims", MAX_PORT_CLAIMS)),
+    }
+}
+
+/// Checks that the `size` ports from `port` on were claimed, by the same owner
+#[track_caller]
+pub fn check_port(port: u16, size: u16) {
+    if !crate::config::IO_AUDIT || REPORTING.load(Ordering::Relaxed) {
+        return;
+    }
+    let ports = PORTS.read();
+    let claim = ports.iter().flatten().find(|claim| claim.contains(port)).copied();
+    let last = ports
+        .iter()
+        .flatten()
+        .find(|claim| claim.contains(port + (size - 1)))
+        .copied();
+    drop(ports);
+    match (claim, last) {
+        (Some(claim), Some(last)) if claim.first == last.first => {}
+        (Some(claim), _) => report(format_args!(
+            "{} byte access at port {:#x} runs past {}",
+            size, port, claim
+        )),
+        (None, _) => report(format_args!(
+            "{} byte access at port {:#x}, which isn't claimed",
+            size, port
+        )),
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_audit_port_claim_bounds() {
+        let claim = PortClaim {
+            first: 0x3F8,
+            count: 8,
+            owner: "uart",
+            site: Location::caller(),
+        };
+        assert!(claim.contains(0x3F8) && claim.contains(0x3FF));
+        assert!(!claim.contains(0x3F7) && !claim.contains(0x400));
+        let top = PortClaim {
+            first: 0xFFFF,
+            count: 1,
+            ..claim
+        };
+        assert!(top.contains(0xFFFF) && !top.contains(0xFFFE));
+    }
+}
@@ -2,13 +2,20 @@
 
 use core::{ffi::c_void, ptr::NonNull};
 
-use spin::{Mutex, MutexGuard};
+use crate::sync::{Mutex, MutexGuard};
 
-use crate::dev::drivers::DriverCapabilities;
+use crate::dev::{devres::DevResList, drivers::DriverCapabilities};
 
 pub mod console;
+pub mod devres;
+pub mod dot;
 pub mod drivers;
+pub mod io_audit;
+pub mod pci;
 pub mod platform;
+pub mod resource;
+pub mod uevent;
+pub mod virtio;
 
 pub struct DeviceTree {
     platform: Mutex<platform::PlatformDeviceTree>,
@@ -24,11 +31,18 @@ impl DeviceTree {
     pub fn platform(&self) -> MutexGuard<'_, platform::PlatformDeviceTree> {
         self.platform.lock()
     }
+
+    /// Returns the platform devices unless they are locked, for when waiting could deadlock
+    pub fn try_platform(&self) -> Option<MutexGuard<'_, platform::PlatformDeviceTree>> {
+        self.platform.try_lock()
+    }
 }
 
 #[derive(Debug)]
 pub struct Device {
     pub drv: Option<DeviceDriver>,
+    /// Resources acquired through the `devm_*` helpers
+    pub resources: DevResList,
 }
 
 #[derive(Debug)]
@@ -44,7 +58,10 @@ unsafe impl Sync for DeviceDriver {}
 
 impl Device {
     pub fn new() -> Self {
-        Self { drv: None }
+        Self {
+            drv: None,
+            resources: DevResList::new(),
+        }
     }
 }
 
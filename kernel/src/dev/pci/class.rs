@@ -0,0 +1,243 @@
+//! PCI class codes
+//!
+//! The class, subclass and programming interface of a function describe what kind of device it
+//! is. The names follow the PCI ID database that `lspci` uses, for the classes a kernel is likely
+//! to meet.
+
+use core::fmt;
+
+use crate::dev::pci::PciDevice;
+
+const CLASSES: &[(u8, &str)] = &[
+    (0x00, "Unclassified device"),
+    (0x01, "Mass storage controller"),
+    (0x02, "Network controller"),
+    (0x03, "Display controller"),
+    (0x04, "Multimedia controller"),
+    (0x05, "Memory controller"),
+    (0x06, "Bridge"),
+    (0x07, "Communication controller"),
+    (0x08, "Generic system peripheral"),
+    (0x09, "Input device controller"),
+    (0x0A, "Docking station"),
+    (0x0B, "Processor"),
+    (0x0C, "Serial bus controller"),
+    (0x0D, "Wireless controller"),
+    (0x0E, "Intelligent controller"),
+    (0x0F, "Satellite communications controller"),
+    (0x10, "Encryption controller"),
+    (0x11, "Signal processing controller"),
+    (0x12, "Processing accelerators"),
+    (0x13, "Non-Essential Instrumentation"),
+    (0x40, "Coprocessor"),
+    (0xFF, "Unassigned class"),
+];
+
+const SUBCLASSES: &[(u8, u8, &str)] = &[
+    (0x00, 0x00, "Non-VGA unclassified device"),
+    (0x00, 0x01, "VGA compatible unclassified device"),
+    (0x01, 0x00, "SCSI storage controller"),
+    (0x01, 0x01, "IDE interface"),
+    (0x01, 0x02, "Floppy disk controller"),
+    (0x01, 0x03, "IPI bus controller"),
+    (0x01, 0x04, "RAID bus controller"),
+    (0x01, 0x05, "ATA controller"),
+    (0x01, 0x06, "SATA controller"),
+    (0x01, 0x07, "Serial Attached SCSI controller"),
+    (0x01, 0x08, "Non-Volatile memory controller"),
+    (0x01, 0x09, "Universal Flash Storage controller"),
+    (0x02, 0x00, "Ethernet controller"),
+    (0x02, 0x01, "Token ring network controller"),
+    (0x02, 0x02, "FDDI network controller"),
+    (0x02, 0x03, "ATM network controller"),
+    (0x02, 0x04, "ISDN controller"),
+    (0x02, 0x07, "Infiniband controller"),
+    (0x02, 0x08, "Fabric controller"),
+    (0x03, 0x00, "VGA compatible controller"),
+    (0x03, 0x01, "XGA compatible controller"),
+    (0x03, 0x02, "3D controller"),
+    (0x04, 0x00, "Multimedia video controller"),
+    (0x04, 0x01, "Multimedia audio controller"),
+    (0x04, 0x02, "Computer telephony device"),
+    (0x04, 0x03, "Audio device"),
+    (0x05, 0x00, "RAM memory"),
+    (0x05, 0x01, "FLASH memory"),
+    (0x05, 0x02, "CXL"),
+    (0x06, 0x00, "Host bridge"),
+    (0x06, 0x01, "ISA bridge"),
+    (0x06, 0x02, "EISA bridge"),
+    (0x06, 0x03, "MicroChannel bridge"),
+    (0x06, 0x04, "PCI bridge"),
+    (0x06, 0x05, "PCMCIA bridge"),
+    (0x06, 0x06, "NuBus bridge"),
+    (0x06, 0x07, "CardBus bridge"),
+    (0x06, 0x08, "RACEway bridge"),
+    (0x06, 0x09, "Semi-transparent PCI-to-PCI bridge"),
+    (0x06, 0x0A, "InfiniBand to PCI host bridge"),
+    (0x07, 0x00, "Serial controller"),
+    (0x07, 0x01, "Parallel controller"),
+    (0x07, 0x02, "Multiport serial controller"),
+    (0x07, 0x03, "Modem"),
+    (0x07, 0x04, "GPIB controller"),
+    (0x07, 0x05, "Smart Card controller"),
+    (0x08, 0x00, "PIC"),
+    (0x08, 0x01, "DMA controller"),
+    (0x08, 0x02, "Timer"),
+    (0x08, 0x03, "RTC"),
+    (0x08, 0x04, "PCI Hot-plug controller"),
+    (0x08, 0x05, "SD Host controller"),
+    (0x08, 0x06, "IOMMU"),
+    (0x09, 0x00, "Keyboard controller"),
+    (0x09, 0x01, "Digitizer Pen"),
+    (0x09, 0x02, "Mouse controller"),
+    (0x09, 0x03, "Scanner controller"),
+    (0x09, 0x04, "Gameport controller"),
+    (0x0C, 0x00, "FireWire (IEEE 1394)"),
+    (0x0C, 0x01, "ACCESS Bus"),
+    (0x0C, 0x02, "SSA"),
+    (0x0C, 0x03, "USB controller"),
+    (0x0C, 0x04, "Fibre Channel"),
+    (0x0C, 0x05, "SMBus"),
+    (0x0C, 0x06, "InfiniBand"),
+    (0x0C, 0x07, "IPMI Interface"),
+    (0x0C, 0x08, "SERCOS interface"),
+    (0x0C, 0x09, "CANBUS"),
+    (0x0D, 0x00, "IRDA controller"),
+    (0x0D, 0x01, "Consumer IR controller"),
+    (0x0D, 0x10, "RF controller"),
+    (0x0D, 0x11, "Bluetooth"),
+    (0x0D, 0x12, "Broadband"),
+    (0x0D, 0x20, "802.1a controller"),
+    (0x0D, 0x21, "802.1b controller"),
+];
+
+const PROG_IFS: &[(u8, u8, u8, &str)] = &[
+    (0x01, 0x05, 0x20, "ADMA single stepping"),
+    (0x01, 0x05, 0x30, "ADMA continuous operation"),
+    (0x01, 0x06, 0x00, "Vendor specific"),
+    (0x01, 0x06, 0x01, "AHCI 1.0"),
+    (0x01, 0x06, 0x02, "Serial Storage Bus"),
+    (0x01, 0x07, 0x01, "Serial Storage Bus"),
+    (0x01, 0x08, 0x01, "NVMHCI"),
+    (0x01, 0x08, 0x02, "NVM Express"),
+    (0x03, 0x00, 0x00, "VGA controller"),
+    (0x03, 0x00, 0x01, "8514 controller"),
+    (0x06, 0x04, 0x00, "Normal decode"),
+    (0x06, 0x04, 0x01, "Subtractive decode"),
+    (0x07, 0x00, 0x00, "8250"),
+    (0x07, 0x00, 0x01, "16450"),
+    (0x07, 0x00, 0x02, "16550"),
+    (0x07, 0x00, 0x03, "16650"),
+    (0x07, 0x00, 0x04, "16750"),
+    (0x07, 0x00, 0x05, "16850"),
+    (0x07, 0x00, 0x06, "16950"),
+    (0x08, 0x00, 0x00, "8259"),
+    (0x08, 0x00, 0x01, "ISA PIC"),
+    (0x08, 0x00, 0x02, "EISA PIC"),
+    (0x08, 0x00, 0x10, "IO-APIC"),
+    (0x08, 0x00, 0x20, "IO(X)-APIC"),
+    (0x0C, 0x00, 0x10, "OHCI"),
+    (0x0C, 0x03, 0x00, "UHCI"),
+    (0x0C, 0x03, 0x10, "OHCI"),
+    (0x0C, 0x03, 0x20, "EHCI"),
+    (0x0C, 0x03, 0x30, "XHCI"),
+    (0x0C, 0x03, 0x40, "USB4 Host Interface"),
+    (0x0C, 0x03, 0xFE, "USB Device"),
+    (0x0C, 0x07, 0x00, "SMIC"),
+    (0x0C, 0x07, 0x01, "KCS"),
+    (0x0C, 0x07, 0x02, "BT (Block Transfer)"),
+];
+
+/// Subclass 0x80 of every class is "other"
+const SUBCLASS_OTHER: u8 = 0x80;
+
+/// The class code of a PCI function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceClass {
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+impl DeviceClass {
+    pub const fn new(class: u8, subclass: u8, prog_if: u8) -> Self {
+        Self {
+            class,
+            subclass,
+            prog_if,
+        }
+    }
+
+    pub const fn of(dev: &PciDevice) -> Self {
+        Self::new(dev.class, dev.subclass, dev.prog_if)
+    }
+
+    /// Returns the 24 bit class code, as in the configuration space
+    pub const fn code(&self) -> u32 {
+        (self.class as u32) << 16 | (self.subclass as u32) << 8 | self.prog_if as u32
+    }
+
+    pub fn class_name(&self) -> Option<&'static str> {
+        CLASSES
+            .iter()
+            .find(|(class, _)| *class == self.class)
+            .map(|(_, name)| *name)
+    }
+
+    pub fn subclass_name(&self) -> Option<&'static str> {
+        SUBCLASSES
+            .iter()
+            .find(|(class, subclass, _)| (*class, *subclass) == (self.class, self.subclass))
+            .map(|(_, _, name)| *name)
+    }
+
+    pub fn prog_if_name(&self) -> Option<&'static str> {
+        PROG_IFS
+            .iter()
+            .find(|(class, subclass, prog_if, _)| {
+                (*class, *subclass, *prog_if) == (self.class, self.subclass, self.prog_if)
+            })
+            .map(|(_, _, _, name)| *name)
+    }
+}
+
+impl fmt::Display for DeviceClass {
+    /// Writes the most specific name known, like `Non-Volatile memory controller (NVM Express)`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.subclass_name(), self.class_name()) {
+            (Some(name), _) => f.write_str(name)?,
+            (None, Some(name)) if self.subclass == SUBCLASS_OTHER => f.write_str(name)?,
+            (None, Some(name)) => write!(f, "{} (subclass {:#04x})", name, self.subclass)?,
+            (None, None) => write!(f, "Unknown class {:#04x}", self.class)?,
+        }
+        if let Some(name) = self.prog_if_name() {
+            write!(f, " ({})", name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn pci_class_names() {
+        let nvme = DeviceClass::new(0x01, 0x08, 0x02);
+        assert_eq!(nvme.code(), 0x010802);
+        assert_eq!(nvme.to_string(), "Non-Volatile memory controller (NVM Express)");
+        assert_eq!(
+            DeviceClass::new(0x01, 0x06, 0x01).to_string(),
+            "SATA controller (AHCI 1.0)"
+        );
+        assert_eq!(DeviceClass::new(0x03, 0x02, 0x00).to_string(), "3D controller");
+        assert_eq!(DeviceClass::new(0x03, 0x80, 0x00).to_string(), "Display controller");
+        assert_eq!(
+            DeviceClass::new(0x02, 0x42, 0x00).to_string(),
+            "Network controller (subclass 0x42)"
+        );
+        assert_eq!(DeviceClass::new(0x99, 0x00, 0x00).to_string(), "Unknown class 0x99");
+    }
+}
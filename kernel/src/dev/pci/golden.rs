@@ -0,0 +1,247 @@
+//! Golden test of PCI enumeration
+//!
+//! With the `pci_golden` feature, the kernel compares the functions found by [`init`](super::init)
+//! against [`MANIFEST`] right after enumeration, then exits QEMU with the result. The buildscript
+//! `golden` task boots QEMU with exactly the devices the manifest lists, including a PCI-to-PCI
+//! bridge with a device behind it, so a regression in parsing configuration space or in scanning
+//! buses behind bridges shows up as a failed run.
+//!
+//! Manifest lines are `<bus>:<device>.<function> <vendor>:<device id> <class><subclass><prog if>`
+//! in hex like `lspci -n`, with `#` starting a comment.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use super::{PciAddress, PciDevice};
+
+/// The functions expected with the devices of the buildscript `golden` task
+pub const MANIFEST: &str = include_str!("golden.manifest");
+
+/// A function listed in a manifest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expected {
+    pub addr: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+impl Expected {
+    fn matches(&self, dev: &PciDevice) -> bool {
+        (self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if)
+            == (dev.vendor_id, dev.device_id, dev.class, dev.subclass, dev.prog_if)
+    }
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} {:02x}{:02x}{:02x}",
+            self.addr, self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ManifestError {
+    /// The line number, starting at 1
+    pub line: usize,
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid manifest entry on line {}", self.line)
+    }
+}
+
+impl core::error::Error for ManifestError {}
+
+fn parse_line(line: &str) -> Option<Expected> {
+    let mut fields = line.split_whitespace();
+    let (bus, rest) = fields.next()?.split_once(':')?;
+    let (device, function) = rest.split_once('.')?;
+    let (vendor_id, device_id) = fields.next()?.split_once(':')?;
+    let class = fields.next()?;
+    if fields.next().is_some() || class.len() != 6 {
+        return None;
+    }
+    let class = u32::from_str_radix(class, 16).ok()?;
+    Some(Expected {
+        addr: PciAddress {
+            bus: u8::from_str_radix(bus, 16).ok()?,
+            device: u8::from_str_radix(device, 16).ok().filter(|device| *device < 32)?,
+            function: function.parse().ok().filter(|function| *function < 8)?,
+        },
+        vendor_id: u16::from_str_radix(vendor_id, 16).ok()?,
+        device_id: u16::from_str_radix(device_id, 16).ok()?,
+        class: (class >> 16) as u8,
+        subclass: (class >> 8) as u8,
+        prog_if: class as u8,
+    })
+}
+
+/// Parses a manifest, sorted by address
+pub fn parse(manifest: &str) -> Result<Vec<Expected>, ManifestError> {
+    let mut expected = Vec::new();
+    for (idx, line) in manifest.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        expected.push(parse_line(line).ok_or(ManifestError { line: idx + 1 })?);
+    }
+    expected.sort_unstable_by_key(|entry| entry.addr);
+    Ok(expected)
+}
+
+/// A difference between the enumerated functions and the manifest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mismatch {
+    /// A function in the manifest wasn't found
+    Missing(Expected),
+    /// A function was found that isn't in the manifest
+    Unexpected(PciDevice),
+    /// A function was found at the address, but reads back differently
+    Different { expected: Expected, found: PciDevice },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing(expected) => write!(f, "missing {}", expected),
+            Self::Unexpected(dev) => write!(f, "unexpected {}", dev),
+            Self::Different { expected, found } => write!(f, "expected {}, found {}", expected, found),
+        }
+    }
+}
+
+/// Compares enumerated functions against a parsed manifest, in address order
+pub fn compare(devices: &[PciDevice], expected: &[Expected]) -> Vec<Mismatch> {
+    let mut devices: Vec<PciDevice> = devices.to_vec();
+    devices.sort_unstable_by_key(|dev| dev.addr);
+    let mut mismatches = Vec::new();
+    let (mut devices, mut expected) = (devices.iter().peekable(), expected.iter().peekable());
+    loop {
+        let mismatch = match (devices.peek(), expected.peek()) {
+            (None, None) => break,
+            (Some(dev), Some(entry)) if dev.addr == entry.addr => {
+                let (dev, entry) = (devices.next().unwrap(), expected.next().unwrap());
+                if entry.matches(dev) {
+                    continue;
+                }
+                Mismatch::Different {
+                    expected: *entry,
+                    found: *dev,
+                }
+            }
+            (Some(dev), Some(entry)) if dev.addr > entry.addr => Mismatch::Missing(*expected.next().unwrap()),
+            (None, Some(_)) => Mismatch::Missing(*expected.next().unwrap()),
+            (Some(_), _) => Mismatch::Unexpected(*devices.next().unwrap()),
+        };
+        mismatches.push(mismatch);
+    }
+    mismatches
+}
+
+/// Checks the enumerated functions against [`MANIFEST`], and exits QEMU with the result
+#[cfg(target_arch = "x86_64")]
+pub fn run() -> ! {
+    use crate::{arch::x86_64::io::outl, kprintln};
+
+    /// The `isa-debug-exit` device, QEMU exits with `(value << 1) | 1`
+    const DEBUG_EXIT_PORT: u16 = 0xF4;
+    const EXIT_SUCCESS: u32 = 0x10;
+    const EXIT_FAILURE: u32 = 0x11;
+
+    let passed = match parse(MANIFEST) {
+        Ok(expected) => {
+            let mismatches = compare(&super::devices(), &expected);
+            for mismatch in &mismatches {
+                kprintln!(Error, "pci golden: {}", mismatch);
+            }
+            mismatches.is_empty()
+        }
+        Err(err) => {
+            kprintln!(Error, "pci golden: {}", err);
+            false
+        }
+    };
+    kprintln!(Info, "pci golden: {}", if passed { "passed" } else { "FAILED" });
+    crate::dev::io_audit::claim_ports(DEBUG_EXIT_PORT, 4, "isa-debug-exit");
+    unsafe { outl(DEBUG_EXIT_PORT, if passed { EXIT_SUCCESS } else { EXIT_FAILURE }) };
+    // Without the exit device there is nothing left to do
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    fn device(bus: u8, device: u8, vendor_id: u16, device_id: u16, class: u32) -> PciDevice {
+        PciDevice {
+            addr: PciAddress {
+                bus,
+                device,
+                function: 0,
+            },
+            vendor_id,
+            device_id,
+            subsystem_vendor_id: 0,
+            subsystem_id: 0,
+            class: (class >> 16) as u8,
+            subclass: (class >> 8) as u8,
+            prog_if: class as u8,
+            revision: 0,
+            header_type: 0,
+            interrupt_line: 0,
+        }
+    }
+
+    #[test]
+    fn golden_manifest() {
+        let expected = parse(MANIFEST).unwrap();
+        assert!(expected.windows(2).all(|pair| pair[0].addr < pair[1].addr));
+        assert_eq!(parse("00:01.0 1234:1111\n"), Err(ManifestError { line: 1 }));
+        assert_eq!(
+            parse("# comment\n00:20.0 1234:1111 030000"),
+            Err(ManifestError { line: 2 })
+        );
+
+        let expected = parse(
+            "00:00.0 8086:29c0 060000\n\
+             00:04.0 1b36:0001 060400 # bridge\n\
+             01:01.0 8086:100e 020000\n",
+        )
+        .unwrap();
+        let devices = [
+            device(0, 0, 0x8086, 0x29C0, 0x06_00_00),
+            device(0, 4, 0x1B36, 0x0001, 0x06_04_00),
+            device(1, 1, 0x8086, 0x100E, 0x02_00_00),
+        ];
+        assert!(compare(&devices, &expected).is_empty());
+
+        // The device behind the bridge wasn't found, and a function reads back the wrong class
+        let devices = [
+            device(0, 4, 0x1B36, 0x0001, 0x06_00_00),
+            device(0, 0, 0x8086, 0x29C0, 0x06_00_00),
+            device(0, 5, 0x1B36, 0x000D, 0x0C_03_30),
+        ];
+        assert_eq!(
+            compare(&devices, &expected),
+            [
+                Mismatch::Different {
+                    expected: expected[1],
+                    found: devices[0]
+                },
+                Mismatch::Unexpected(devices[2]),
+                Mismatch::Missing(expected[2]),
+            ]
+        );
+    }
+}
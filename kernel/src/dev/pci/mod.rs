@@ -0,0 +1,411 @@
+//! PCI bus enumeration
+//!
+//! Devices are found by scanning configuration space through the legacy `0xCF8`/`0xCFC` port
+//! mechanism, which every x86 chipset supports. The scan result is cached, drivers look up their
+//! devices with [`devices`] or [`find`].
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::PhysAddr,
+    kprintln,
+    sync::{Mutex, RwLock},
+};
+
+pub mod class;
+pub mod golden;
+#[cfg(target_arch = "x86_64")]
+pub mod msix;
+pub mod resource;
+
+pub use class::DeviceClass;
+
+pub const REG_VENDOR_ID: u8 = 0x00;
+pub const REG_DEVICE_ID: u8 = 0x02;
+pub const REG_COMMAND: u8 = 0x04;
+pub const REG_STATUS: u8 = 0x06;
+pub const REG_REVISION: u8 = 0x08;
+pub const REG_HEADER_TYPE: u8 = 0x0E;
+pub const REG_BAR0: u8 = 0x10;
+pub const REG_SUBSYSTEM_VENDOR_ID: u8 = 0x2C;
+pub const REG_SUBSYSTEM_ID: u8 = 0x2E;
+/// The offset of the first capability, if the status register has [`STATUS_CAPABILITIES`]
+pub const REG_CAPABILITIES: u8 = 0x34;
+pub const REG_INTERRUPT_LINE: u8 = 0x3C;
+
+/// Set in the header type for devices with more than one function
+const HEADER_MULTIFUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const HEADER_TYPE_DEVICE: u8 = 0x00;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+/// The bus behind a bridge, in the header of PCI-to-PCI bridges
+const REG_SECONDARY_BUS: u8 = 0x19;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+/// A bound on the capabilities walked, so a looping list can't hang enumeration
+const MAX_CAPABILITIES: usize = 48;
+
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_MSIX: u8 = 0x11;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+pub const CLASS_MASS_STORAGE: u8 = 0x01;
+pub const CLASS_NETWORK: u8 = 0x02;
+pub const CLASS_DISPLAY: u8 = 0x03;
+pub const CLASS_BRIDGE: u8 = 0x06;
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PciCommand: u16 {
+        const IO_SPACE = 1 << 0;
+        const MEMORY_SPACE = 1 << 1;
+        const BUS_MASTER = 1 << 2;
+        const INTERRUPT_DISABLE = 1 << 10;
+    }
+}
+
+/// The location of a function in configuration space
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// Serializes access to the address/data port pair
+static CONFIG: Mutex<()> = Mutex::new(());
+
+#[cfg(target_arch = "x86_64")]
+impl PciAddress {
+    const CONFIG_ADDRESS: u16 = 0xCF8;
+    const CONFIG_DATA: u16 = 0xCFC;
+
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self { bus, device, function }
+    }
+
+    fn config_address(self, offset: u8) -> u32 {
+        (1 << 31)
+            | ((self.bus as u32) << 16)
+            | ((self.device as u32) << 11)
+            | ((self.function as u32) << 8)
+            | (offset as u32 & 0xFC)
+    }
+
+    pub fn read_u32(self, offset: u8) -> u32 {
+        use crate::arch::x86_64::io::{inl, outl};
+
+        let _guard = CONFIG.lock();
+        unsafe {
+            outl(Self::CONFIG_ADDRESS, self.config_address(offset));
+            inl(Self::CONFIG_DATA)
+        }
+    }
+
+    pub fn write_u32(self, offset: u8, value: u32) {
+        use crate::arch::x86_64::io::outl;
+
+        let _guard = CONFIG.lock();
+        unsafe {
+            outl(Self::CONFIG_ADDRESS, self.config_address(offset));
+            outl(Self::CONFIG_DATA, value);
+        }
+    }
+
+    pub fn read_u16(self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    /// Writes a 16 bit register, without touching its neighbour in the same dword
+    pub fn write_u16(self, offset: u8, value: u16) {
+        use crate::arch::x86_64::io::{outl, outw};
+
+        let _guard = CONFIG.lock();
+        unsafe {
+            outl(Self::CONFIG_ADDRESS, self.config_address(offset));
+            outw(Self::CONFIG_DATA + (offset & 2) as u16, value);
+        }
+    }
+}
+
+/// A decoded base address register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        addr: PhysAddr,
+        size: usize,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Memory {
+                addr,
+                size,
+                prefetchable,
+            } => write!(
+                f,
+                "memory at {:#x} ({} KiB{})",
+                addr.as_usize(),
+                size / 1024,
+                if *prefetchable { ", prefetchable" } else { "" }
+            ),
+            Self::Io { port, size } => write!(f, "I/O ports at {:#x} ({} ports)", port, size),
+        }
+    }
+}
+
+/// A PCI function found during enumeration
+///
+/// This is part of the module ABI, so fields may only be added at the end.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub addr: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub interrupt_line: u8,
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} [{:02x}{:02x}] {:04x}:{:04x} (rev {:02x})",
+            self.addr, self.class, self.subclass, self.vendor_id, self.device_id, self.revision
+        )
+    }
+}
+
+impl PciDevice {
+    pub const fn device_class(&self) -> DeviceClass {
+        DeviceClass::of(self)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl PciDevice {
+    /// Reads the header of a function, returning `None` if there is nothing at the address
+    pub fn probe(addr: PciAddress) -> Option<Self> {
+        let vendor_id = addr.read_u16(REG_VENDOR_ID);
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+        let [revision, prog_if, subclass, class] = addr.read_u32(REG_REVISION).to_le_bytes();
+        let header_type = addr.read_u8(REG_HEADER_TYPE);
+        let is_device = header_type & HEADER_TYPE_MASK == HEADER_TYPE_DEVICE;
+        Some(Self {
+            addr,
+            vendor_id,
+            device_id: addr.read_u16(REG_DEVICE_ID),
+            subsystem_vendor_id: if is_device {
+                addr.read_u16(REG_SUBSYSTEM_VENDOR_ID)
+            } else {
+                0
+            },
+            subsystem_id: if is_device { addr.read_u16(REG_SUBSYSTEM_ID) } else { 0 },
+            class,
+            subclass,
+            prog_if,
+            revision,
+            header_type,
+            interrupt_line: addr.read_u8(REG_INTERRUPT_LINE),
+        })
+    }
+
+    pub fn is_multifunction(&self) -> bool {
+        self.header_type & HEADER_MULTIFUNCTION != 0
+    }
+
+    /// Returns the bus behind a PCI-to-PCI bridge
+    pub fn secondary_bus(&self) -> Option<u8> {
+        (self.header_type & HEADER_TYPE_MASK == HEADER_TYPE_BRIDGE).then(|| self.addr.read_u8(REG_SECONDARY_BUS))
+    }
+
+    /// Returns the number of BARs in the header
+    pub fn bar_count(&self) -> usize {
+        match self.header_type & HEADER_TYPE_MASK {
+            HEADER_TYPE_DEVICE => 6,
+            // PCI-to-PCI bridges
+            0x01 => 2,
+            _ => 0,
+        }
+    }
+
+    pub fn command(&self) -> PciCommand {
+        PciCommand::from_bits_retain(self.addr.read_u16(REG_COMMAND))
+    }
+
+    pub fn set_command(&self, command: PciCommand) {
+        self.addr.write_u16(REG_COMMAND, command.bits());
+    }
+
+    /// Returns the capabilities of the function, as pairs of their id and offset
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        let mut next = match self.addr.read_u16(REG_STATUS) & STATUS_CAPABILITIES {
+            0 => 0,
+            _ => self.addr.read_u8(REG_CAPABILITIES) & !0b11,
+        };
+        core::iter::from_fn(move || {
+            if next == 0 {
+                return None;
+            }
+            let offset = next;
+            let header = self.addr.read_u16(offset);
+            next = (header >> 8) as u8 & !0b11;
+            Some((header as u8, offset))
+        })
+        .take(MAX_CAPABILITIES)
+    }
+
+    /// Returns the offset of the first capability with the given id
+    pub fn capability(&self, id: u8) -> Option<u8> {
+        self.capabilities()
+            .find(|(cap, _)| *cap == id)
+            .map(|(_, offset)| offset)
+    }
+
+    /// Enables decoding and bus mastering, as needed by the driver
+    pub fn enable(&self, flags: PciCommand) {
+        self.set_command(self.command() | flags);
+    }
+
+    /// Decodes a BAR, sizing it by writing all ones while decoding is disabled
+    ///
+    /// Returns `None` for unimplemented BARs, and for the upper half of a 64 bit BAR.
+    pub fn bar(&self, idx: usize) -> Option<Bar> {
+        if idx >= self.bar_count() {
+            return None;
+        }
+        let is_64 = |raw: u32| raw & BAR_IO == 0 && raw & 0b110 == BAR_TYPE_64;
+        let mut first = 0;
+        while first < idx {
+            first += if is_64(self.addr.read_u32(REG_BAR0 + first as u8 * 4)) {
+                2
+            } else {
+                1
+            };
+        }
+        if first != idx {
+            return None;
+        }
+        let offset = REG_BAR0 + idx as u8 * 4;
+        let raw = self.addr.read_u32(offset);
+
+        let command = self.command();
+        self.set_command(command - (PciCommand::IO_SPACE | PciCommand::MEMORY_SPACE));
+        let bar = if raw & BAR_IO != 0 {
+            self.addr.write_u32(offset, u32::MAX);
+            let mask = self.addr.read_u32(offset) & !0b11;
+            self.addr.write_u32(offset, raw);
+            let size = (!mask).wrapping_add(1) as u16;
+            (mask != 0).then_some(Bar::Io {
+                port: (raw & !0b11) as u16,
+                size,
+            })
+        } else {
+            let is_64 = is_64(raw);
+            let high = if is_64 { self.addr.read_u32(offset + 4) } else { 0 };
+            self.addr.write_u32(offset, u32::MAX);
+            let mut mask = (self.addr.read_u32(offset) & !0xF) as u64;
+            self.addr.write_u32(offset, raw);
+            if is_64 {
+                self.addr.write_u32(offset + 4, u32::MAX);
+                mask |= (self.addr.read_u32(offset + 4) as u64) << 32;
+                self.addr.write_u32(offset + 4, high);
+            } else {
+                mask |= 0xFFFF_FFFF << 32;
+            }
+            let addr = ((high as u64) << 32) | (raw & !0xF) as u64;
+            (mask != 0xFFFF_FFFF << 32 && mask != 0).then_some(Bar::Memory {
+                addr: PhysAddr::new(addr as usize),
+                size: (!mask).wrapping_add(1) as usize,
+                prefetchable: raw & BAR_PREFETCHABLE != 0,
+            })
+        };
+        self.set_command(command);
+        bar
+    }
+
+    /// Moves a BAR to a new address, with decoding disabled while it is written
+    ///
+    /// The address must be aligned to the size of the BAR, and fit in 32 bits unless it is a 64 bit
+    /// memory BAR.
+    pub fn set_bar_address(&self, idx: usize, addr: u64) {
+        let offset = REG_BAR0 + idx as u8 * 4;
+        let raw = self.addr.read_u32(offset);
+        let command = self.command();
+        self.set_command(command - (PciCommand::IO_SPACE | PciCommand::MEMORY_SPACE));
+        if raw & BAR_IO != 0 {
+            self.addr.write_u32(offset, addr as u32 | (raw & 0b11));
+        } else {
+            self.addr.write_u32(offset, addr as u32 | (raw & 0xF));
+            if raw & 0b110 == BAR_TYPE_64 {
+                self.addr.write_u32(offset + 4, (addr >> 32) as u32);
+            }
+        }
+        self.set_command(command);
+    }
+}
+
+static DEVICES: RwLock<Vec<PciDevice>> = RwLock::new(Vec::new());
+
+/// Scans every bus for devices
+#[cfg(target_arch = "x86_64")]
+pub fn init() {
+    crate::dev::io_audit::claim_ports(PciAddress::CONFIG_ADDRESS, 8, "pci");
+    let mut devices = Vec::new();
+    for bus in 0..=255u8 {
+        for device in 0..32 {
+            let Some(dev) = PciDevice::probe(PciAddress::new(bus, device, 0)) else {
+                continue;
+            };
+            devices.push(dev);
+            if dev.is_multifunction() {
+                devices.extend((1..8).filter_map(|function| PciDevice::probe(PciAddress::new(bus, device, function))));
+            }
+        }
+    }
+    kprintln!(Info, "pci: found {} functions", devices.len());
+    resource::assign(&devices);
+    *DEVICES.write() = devices;
+}
+
+/// Returns every function found on the bus
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.read().clone()
+}
+
+/// Returns the functions matching a predicate
+pub fn find(mut f: impl FnMut(&PciDevice) -> bool) -> Vec<PciDevice> {
+    DEVICES.read().iter().filter(|dev| f(dev)).copied().collect()
+}
@@ -0,0 +1,140 @@
+//! MSI-X
+//!
+//! A function with the MSI-X capability has a table of interrupt messages in one of its memory
+//! BARs, one entry for each interrupt it can raise. An entry holds the address and data the
+//! function writes to raise it, which for x86 names a local APIC and a vector. All device
+//! interrupts go to the BSP for now, like those of the I/O APIC.
+
+use core::fmt;
+
+use crate::{
+    arch::x86_64::apic,
+    dev::pci::{Bar, CAP_MSIX, PciAddress, PciCommand, PciDevice},
+    mm::mmio::{self, MmioRegion, MmioSpaceExhausted},
+};
+
+const CONTROL_ENABLE: u16 = 1 << 15;
+const CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const CONTROL_TABLE_SIZE: u16 = 0x7FF;
+
+const ENTRY_SIZE: usize = 16;
+const ENTRY_ADDR_LOW: usize = 0;
+const ENTRY_ADDR_HIGH: usize = 4;
+const ENTRY_DATA: usize = 8;
+const ENTRY_CONTROL: usize = 12;
+const ENTRY_MASKED: u32 = 1 << 0;
+
+#[derive(Debug, Clone, Copy)]
+pub enum MsixError {
+    /// The function has no MSI-X capability
+    NotSupported,
+    /// The table is in a BAR that isn't a memory BAR
+    InvalidBar,
+    Mmio(MmioSpaceExhausted),
+    /// The local APIC isn't initialized, so there is nothing to send messages to
+    NoApic,
+    /// The entry is beyond the end of the table
+    InvalidEntry,
+}
+
+impl fmt::Display for MsixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotSupported => f.write_str("no MSI-X capability"),
+            Self::InvalidBar => f.write_str("MSI-X table is not in a memory BAR"),
+            Self::Mmio(err) => write!(f, "MSI-X table: {}", err),
+            Self::NoApic => f.write_str("local APIC is not initialized"),
+            Self::InvalidEntry => f.write_str("no such MSI-X entry"),
+        }
+    }
+}
+
+impl core::error::Error for MsixError {}
+
+/// The MSI-X table of a function
+#[derive(Debug)]
+pub struct MsixTable {
+    addr: PciAddress,
+    /// The offset of the capability in configuration space
+    cap: u8,
+    entries: u16,
+    table: MmioRegion,
+}
+
+impl MsixTable {
+    /// Maps the table of a function, with every entry masked
+    ///
+    /// MSI-X stays disabled until [`MsixTable::enable`].
+    pub fn new(dev: &PciDevice) -> Result<Self, MsixError> {
+        let cap = dev.capability(CAP_MSIX).ok_or(MsixError::NotSupported)?;
+        let entries = (dev.addr.read_u16(cap + 2) & CONTROL_TABLE_SIZE) + 1;
+        let location = dev.addr.read_u32(cap + 4);
+        let Some(Bar::Memory { addr, .. }) = dev.bar((location & 0b111) as usize) else {
+            return Err(MsixError::InvalidBar);
+        };
+        let phys = addr + (location & !0b111) as usize;
+        // SAFETY: The BAR belongs to the device
+        let table = unsafe { mmio::map(phys, entries as usize * ENTRY_SIZE) }.map_err(MsixError::Mmio)?;
+        let table = Self {
+            addr: dev.addr,
+            cap,
+            entries,
+            table,
+        };
+        for entry in 0..entries {
+            table.write(entry, ENTRY_CONTROL, ENTRY_MASKED);
+        }
+        Ok(table)
+    }
+
+    pub fn entries(&self) -> u16 {
+        self.entries
+    }
+
+    fn write(&self, entry: u16, reg: usize, value: u32) {
+        let offset = entry as usize * ENTRY_SIZE + reg;
+        unsafe { self.table.write(offset, value) }
+    }
+
+    fn read(&self, entry: u16, reg: usize) -> u32 {
+        let offset = entry as usize * ENTRY_SIZE + reg;
+        unsafe { self.table.read(offset) }
+    }
+
+    /// Points an entry at a vector and unmasks it
+    pub fn set_vector(&self, entry: u16, vector: u8) -> Result<(), MsixError> {
+        if entry >= self.entries {
+            return Err(MsixError::InvalidEntry);
+        }
+        let (address, data) = apic::local_apic().ok_or(MsixError::NoApic)?.msi_message(vector);
+        self.write(entry, ENTRY_CONTROL, ENTRY_MASKED);
+        self.write(entry, ENTRY_ADDR_LOW, address);
+        self.write(entry, ENTRY_ADDR_HIGH, 0);
+        self.write(entry, ENTRY_DATA, data);
+        self.write(entry, ENTRY_CONTROL, 0);
+        Ok(())
+    }
+
+    /// Masks or unmasks an entry
+    pub fn set_masked(&self, entry: u16, masked: bool) {
+        if entry < self.entries {
+            let control = self.read(entry, ENTRY_CONTROL) & !ENTRY_MASKED;
+            self.write(entry, ENTRY_CONTROL, control | if masked { ENTRY_MASKED } else { 0 });
+        }
+    }
+
+    /// Switches the function from legacy interrupts to MSI-X
+    pub fn enable(&self) {
+        let control = self.addr.read_u16(self.cap + 2);
+        self.addr
+            .write_u16(self.cap + 2, (control | CONTROL_ENABLE) & !CONTROL_FUNCTION_MASK);
+        let command = PciCommand::from_bits_retain(self.addr.read_u16(super::REG_COMMAND));
+        self.addr
+            .write_u16(super::REG_COMMAND, (command | PciCommand::INTERRUPT_DISABLE).bits());
+    }
+
+    pub fn disable(&self) {
+        let control = self.addr.read_u16(self.cap + 2);
+        self.addr.write_u16(self.cap + 2, control & !CONTROL_ENABLE);
+    }
+}
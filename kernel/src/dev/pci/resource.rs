@@ -0,0 +1,413 @@
+//! BAR resource assignment
+//!
+//! Firmware is supposed to give every BAR an address of its own, but stripped down firmware leaves
+//! BARs unassigned, or assigns overlapping ones, and mapping two BARs that overlap corrupts both
+//! devices. [`assign`] runs right after enumeration, before any driver maps a BAR. It checks every
+//! BAR against RAM and firmware reserved memory, against the window of the bridge the device is
+//! behind, and against the BARs before it, then moves the ones that conflict into a free range of
+//! their window and logs the final resource map.
+//!
+//! The windows of PCI-to-PCI bridges are read from their headers and never resized. The root bus
+//! forwards whatever the host bridge decodes, which only ACPI `_CRS` describes, so BARs on it are
+//! only checked for overlaps, and moved into the 32 bit hole between the end of the memory map and
+//! the I/O APIC, or the I/O ports above the legacy ones.
+
+use core::{fmt, ops::Range};
+
+use alloc::vec::Vec;
+
+use super::{Bar, PciAddress, PciDevice};
+#[cfg(target_arch = "x86_64")]
+use crate::kprintln;
+
+/// Devices on the root bus are moved below the I/O APIC and local APIC
+const ROOT_MEMORY_END: u64 = 0xFEC0_0000;
+/// The I/O ports below this are used by legacy devices
+const ROOT_IO: Range<u64> = 0x1000..0x1_0000;
+
+const REG_SECONDARY_BUS: u8 = 0x19;
+const REG_IO_BASE: u8 = 0x1C;
+const REG_MEMORY_BASE: u8 = 0x20;
+const REG_PREFETCH_BASE: u8 = 0x24;
+const REG_PREFETCH_BASE_UPPER: u8 = 0x28;
+const REG_PREFETCH_LIMIT_UPPER: u8 = 0x2C;
+const REG_IO_BASE_UPPER: u8 = 0x30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    Memory { prefetchable: bool },
+    Io,
+}
+
+impl ResourceKind {
+    fn space_matches(self, other: Self) -> bool {
+        matches!(
+            (self, other),
+            (Self::Memory { .. }, Self::Memory { .. }) | (Self::Io, Self::Io)
+        )
+    }
+}
+
+/// The address range a BAR decodes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resource {
+    pub dev: PciAddress,
+    pub bar: usize,
+    pub kind: ResourceKind,
+    pub range: Range<u64>,
+}
+
+impl Resource {
+    pub fn from_bar(dev: PciAddress, bar: usize, decoded: Bar) -> Self {
+        let (kind, start, size) = match decoded {
+            Bar::Memory {
+                addr,
+                size,
+                prefetchable,
+            } => (ResourceKind::Memory { prefetchable }, addr.as_u64(), size as u64),
+            Bar::Io { port, size } => (ResourceKind::Io, port as u64, size as u64),
+        };
+        Self {
+            dev,
+            bar,
+            kind,
+            range: start..start + size,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+
+    fn overlaps(&self, range: &Range<u64>) -> bool {
+        self.range.start < range.end && range.start < self.range.end
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            ResourceKind::Memory { prefetchable: true } => "mem pref",
+            ResourceKind::Memory { prefetchable: false } => "mem",
+            ResourceKind::Io => "io",
+        };
+        write!(
+            f,
+            "{} BAR{} {:<8} {:#011x}-{:#011x}",
+            self.dev,
+            self.bar,
+            kind,
+            self.range.start,
+            self.range.end - 1
+        )
+    }
+}
+
+/// Why a BAR has to be moved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conflict {
+    /// The firmware left it at zero
+    Unassigned,
+    /// It isn't aligned to its size, which the hardware can't decode
+    Misaligned,
+    /// It overlaps RAM or memory the firmware reserved
+    Reserved,
+    /// It is outside the window of the bridge the device is behind
+    OutsideWindow,
+    /// It overlaps an earlier BAR
+    Overlaps(PciAddress, usize),
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unassigned => f.write_str("unassigned"),
+            Self::Misaligned => f.write_str("misaligned"),
+            Self::Reserved => f.write_str("overlaps reserved memory"),
+            Self::OutsideWindow => f.write_str("outside the bridge window"),
+            Self::Overlaps(dev, bar) => write!(f, "overlaps {} BAR{}", dev, bar),
+        }
+    }
+}
+
+/// The ranges a bus forwards, `None` for the root bus whose windows aren't known
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Windows {
+    pub memory: Option<Range<u64>>,
+    pub prefetchable: Option<Range<u64>>,
+    pub io: Option<Range<u64>>,
+}
+
+impl Windows {
+    /// Returns the window a BAR of the kind must be in, `None` if any address is allowed
+    ///
+    /// Prefetchable BARs may use either memory window, the others only the non-prefetchable one.
+    fn bounds(&self, kind: ResourceKind) -> Option<Vec<Range<u64>>> {
+        let windows = match kind {
+            ResourceKind::Memory { prefetchable: true } => [self.prefetchable.clone(), self.memory.clone()],
+            ResourceKind::Memory { prefetchable: false } => [self.memory.clone(), None],
+            ResourceKind::Io => [self.io.clone(), None],
+        };
+        let windows: Vec<_> = windows.into_iter().flatten().collect();
+        (!windows.is_empty()).then_some(windows)
+    }
+}
+
+/// Checks every resource in order, returning the index and conflict of those that must move
+///
+/// `windows[i]` belongs to the bus of `resources[i]`. A BAR that overlaps an earlier one is the
+/// one that moves, so the earlier BARs keep their addresses.
+pub fn conflicts(resources: &[Resource], windows: &[Windows], reserved: &[Range<u64>]) -> Vec<(usize, Conflict)> {
+    let mut found: Vec<(usize, Conflict)> = Vec::new();
+    for (idx, resource) in resources.iter().enumerate() {
+        let is_memory = resource.kind != ResourceKind::Io;
+        let earlier = resources[..idx]
+            .iter()
+            .enumerate()
+            .filter(|(other, _)| !found.iter().any(|(moved, _)| moved == other))
+            .map(|(_, other)| other)
+            .find(|other| other.kind.space_matches(resource.kind) && other.overlaps(&resource.range));
+        let conflict = if resource.range.start == 0 {
+            Some(Conflict::Unassigned)
+        } else if !resource.range.start.is_multiple_of(resource.size()) {
+            Some(Conflict::Misaligned)
+        } else if let Some(bounds) = windows[idx].bounds(resource.kind)
+            && !bounds
+                .iter()
+                .any(|window| window.start <= resource.range.start && resource.range.end <= window.end)
+        {
+            Some(Conflict::OutsideWindow)
+        } else if is_memory && reserved.iter().any(|range| resource.overlaps(range)) {
+            Some(Conflict::Reserved)
+        } else {
+            earlier.map(|other| Conflict::Overlaps(other.dev, other.bar))
+        };
+        if let Some(conflict) = conflict {
+            found.push((idx, conflict));
+        }
+    }
+    found
+}
+
+/// Finds the lowest address in a window where `size` bytes, aligned to `size`, overlap nothing
+/// that is used
+pub fn allocate(window: &Range<u64>, size: u64, used: &[Range<u64>]) -> Option<u64> {
+    let mut start = window.start.next_multiple_of(size);
+    loop {
+        let end = start.checked_add(size)?;
+        if end > window.end {
+            return None;
+        }
+        match used.iter().find(|range| range.start < end && start < range.end) {
+            Some(range) => start = range.end.next_multiple_of(size),
+            None => return Some(start),
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn read_windows(bridge: &PciDevice) -> Windows {
+    let addr = bridge.addr;
+    let range = |base: u64, limit: u64| (base <= limit).then_some(base..limit + 1);
+
+    let memory = addr.read_u32(REG_MEMORY_BASE);
+    let memory = range(
+        ((memory & 0xFFF0) as u64) << 16,
+        ((memory >> 16 & 0xFFF0) as u64) << 16 | 0xF_FFFF,
+    );
+
+    let prefetch = addr.read_u32(REG_PREFETCH_BASE);
+    let (mut base, mut limit) = (
+        ((prefetch & 0xFFF0) as u64) << 16,
+        ((prefetch >> 16 & 0xFFF0) as u64) << 16 | 0xF_FFFF,
+    );
+    // The low bits are 1 when the window decodes 64 bit addresses
+    if prefetch & 0xF == 1 {
+        base |= (addr.read_u32(REG_PREFETCH_BASE_UPPER) as u64) << 32;
+        limit |= (addr.read_u32(REG_PREFETCH_LIMIT_UPPER) as u64) << 32;
+    }
+    let prefetchable = range(base, limit);
+
+    let io = addr.read_u16(REG_IO_BASE);
+    let (mut base, mut limit) = (((io & 0xF0) as u64) << 8, ((io >> 8 & 0xF0) as u64) << 8 | 0xFFF);
+    if io & 0xF == 1 {
+        let upper = addr.read_u32(REG_IO_BASE_UPPER);
+        base |= ((upper & 0xFFFF) as u64) << 16;
+        limit |= ((upper >> 16) as u64) << 16;
+    }
+    let io = range(base, limit);
+
+    Windows {
+        memory,
+        prefetchable,
+        io,
+    }
+}
+
+/// Returns the window to move a BAR into, the one the bus forwards, or the root bus default
+///
+/// Memory BARs go in the non-prefetchable window where there is one, because it is always below
+/// 4 GiB, where 32 bit BARs can decode it.
+#[cfg(target_arch = "x86_64")]
+fn pool(windows: &Windows, resource: &Resource, root_memory: &Range<u64>) -> Option<Range<u64>> {
+    match resource.kind {
+        ResourceKind::Io => windows.io.clone().or(Some(ROOT_IO)),
+        ResourceKind::Memory { prefetchable: false } => windows.memory.clone().or(Some(root_memory.clone())),
+        ResourceKind::Memory { prefetchable: true } => match (&windows.memory, &windows.prefetchable) {
+            (None, None) => Some(root_memory.clone()),
+            (memory, prefetchable) => memory.clone().or(prefetchable.clone()),
+        },
+    }
+}
+
+/// Checks the BARs of every function, and moves the ones that conflict
+#[cfg(target_arch = "x86_64")]
+pub fn assign(devices: &[PciDevice]) {
+    // Bridges by their secondary bus
+    let bridges: Vec<(u8, Windows)> = devices
+        .iter()
+        .filter(|dev| dev.header_type & super::HEADER_TYPE_MASK == 0x01)
+        .map(|bridge| (bridge.addr.read_u8(REG_SECONDARY_BUS), read_windows(bridge)))
+        .collect();
+    let mut resources = Vec::new();
+    let mut windows = Vec::new();
+    for dev in devices {
+        let bus = bridges
+            .iter()
+            .find(|(bus, _)| *bus == dev.addr.bus)
+            .map(|(_, windows)| windows.clone())
+            .unwrap_or_default();
+        for bar in 0..dev.bar_count() {
+            if let Some(decoded) = dev.bar(bar) {
+                resources.push(Resource::from_bar(dev.addr, bar, decoded));
+                windows.push(bus.clone());
+            }
+        }
+    }
+
+    let reserved: Vec<Range<u64>> = crate::boot::reserved_memory()
+        .map(|range| range.start as u64..range.end as u64)
+        .collect();
+    let below_4g = reserved.iter().map(|range| range.end).filter(|end| *end <= 1 << 32);
+    let root_memory = below_4g.max().unwrap_or(0).next_multiple_of(0x10_0000)..ROOT_MEMORY_END;
+
+    let mut conflicts = conflicts(&resources, &windows, &reserved);
+    // The largest BARs first, so the smaller ones fill the gaps they leave
+    conflicts.sort_by_key(|(idx, _)| core::cmp::Reverse(resources[*idx].size()));
+    let mut used: Vec<Range<u64>> = resources
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| !conflicts.iter().any(|(moved, _)| moved == idx))
+        .map(|(_, resource)| resource.range.clone())
+        .collect();
+    for (idx, conflict) in &conflicts {
+        let resource = &resources[*idx];
+        let Some(dev) = devices.iter().find(|dev| dev.addr == resource.dev) else {
+            continue;
+        };
+        let reserved = if resource.kind == ResourceKind::Io {
+            &[][..]
+        } else {
+            &reserved[..]
+        };
+        let blocked: Vec<Range<u64>> = used.iter().chain(reserved).cloned().collect();
+        let start = pool(&windows[*idx], resource, &root_memory)
+            .and_then(|window| allocate(&window, resource.size(), &blocked));
+        match start {
+            Some(start) => {
+                kprintln!(
+                    Warn,
+                    "pci: {} BAR{} {}, moving it from {:#x} to {:#x}",
+                    resource.dev,
+                    resource.bar,
+                    conflict,
+                    resource.range.start,
+                    start
+                );
+                dev.set_bar_address(resource.bar, start);
+                let range = start..start + resource.size();
+                used.push(range.clone());
+                resources[*idx].range = range;
+            }
+            None => {
+                // Leaving it decoding would corrupt whatever it overlaps
+                kprintln!(
+                    Error,
+                    "pci: {} BAR{} {}, and there is no room to move it, disabling the device",
+                    resource.dev,
+                    resource.bar,
+                    conflict
+                );
+                dev.set_command(dev.command() - (super::PciCommand::IO_SPACE | super::PciCommand::MEMORY_SPACE));
+            }
+        }
+    }
+
+    for resource in &resources {
+        kprintln!(Debug, "pci: {}", resource);
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    fn memory(device: u8, bar: usize, range: Range<u64>) -> Resource {
+        Resource {
+            dev: PciAddress::new(0, device, 0),
+            bar,
+            kind: ResourceKind::Memory { prefetchable: false },
+            range,
+        }
+    }
+
+    #[test]
+    fn pci_resource_conflicts() {
+        let resources = [
+            memory(1, 0, 0xC000_0000..0xC000_1000),
+            // Overlaps the first
+            memory(2, 0, 0xC000_0000..0xC000_2000),
+            memory(3, 0, 0..0x1000),
+            memory(3, 1, 0xC000_0400..0xC000_0C00),
+            // In RAM
+            memory(4, 0, 0x10_0000..0x10_1000),
+            // An I/O BAR doesn't conflict with memory at the same address
+            Resource {
+                dev: PciAddress::new(0, 5, 0),
+                bar: 0,
+                kind: ResourceKind::Io,
+                range: 0xC000_0000..0xC000_0100,
+            },
+            // Outside the window of its bridge
+            memory(6, 0, 0xD000_0000..0xD000_1000),
+        ];
+        let mut windows = [const {
+            Windows {
+                memory: None,
+                prefetchable: None,
+                io: None,
+            }
+        }; 7];
+        windows[6].memory = Some(0xE000_0000..0xE010_0000);
+        let reserved = [0..0x8000_0000];
+
+        let found = conflicts(&resources, &windows, &reserved);
+        assert_eq!(
+            found,
+            [
+                (1, Conflict::Overlaps(PciAddress::new(0, 1, 0), 0)),
+                (2, Conflict::Unassigned),
+                (3, Conflict::Misaligned),
+                (4, Conflict::Reserved),
+                (6, Conflict::OutsideWindow),
+            ]
+        );
+
+        let used = [0xE000_0000..0xE000_1000, 0xE000_3000..0xE000_4000];
+        let window = 0xE000_0000..0xE001_0000;
+        assert_eq!(allocate(&window, 0x1000, &used), Some(0xE000_1000));
+        assert_eq!(allocate(&window, 0x2000, &used), Some(0xE000_4000));
+        assert_eq!(allocate(&window, 0x10000, &used), None);
+        assert_eq!(allocate(&window, 0x8000, &[]), Some(0xE000_0000));
+    }
+}
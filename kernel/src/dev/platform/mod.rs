@@ -2,7 +2,10 @@ use core::{any::Any, fmt};
 
 use alloc::{boxed::Box, sync::Arc, vec::Vec};
 
-use crate::dev::Device;
+use crate::dev::{
+    Device,
+    uevent::{self, UeventAction},
+};
 
 #[derive(Debug)]
 pub struct PlatformDeviceTree {
@@ -15,9 +18,18 @@ impl PlatformDeviceTree {
     }
 
     pub fn add_device(&mut self, dev: PlatformDev) {
+        uevent::emit(UeventAction::Add, "platform", dev.name, None);
         self.devs.push(dev);
     }
 
+    /// Removes the first device with the given name
+    pub fn remove_device(&mut self, name: &str) -> Option<PlatformDev> {
+        let idx = self.devs.iter().position(|dev| dev.name == name)?;
+        let dev = self.devs.remove(idx);
+        uevent::emit(UeventAction::Remove, "platform", dev.name, None);
+        Some(dev)
+    }
+
     pub fn iter(&mut self) -> core::slice::Iter<'_, PlatformDev> {
         self.devs.iter()
     }
@@ -0,0 +1,494 @@
+//! Ownership of hardware resources
+//!
+//! Interrupt vectors, I/O ports, device memory and ISA DMA channels are claimed with [`claim`],
+//! which records the range and its owner in a tree and returns a [`Resource`] handle. Handles are
+//! reference counted, the claim is released when the last one is dropped, and claims that last
+//! until reboot are kept with [`Resource::forget`].
+//!
+//! Like `/proc/iomem` on Linux, claims nest: a bridge or a firmware table can claim a window with
+//! [`claim_window`], and the claims inside of it become its children. Exclusive claims can't have
+//! children, and a claim that partially overlaps another is a conflict. `stats resources` dumps
+//! the tree, see [`report`].
+//!
+//! The tree is a fixed array, so resources can be claimed before the heap exists.
+
+use core::{fmt, ops::RangeInclusive, panic::Location};
+
+use alloc::vec::Vec;
+
+use crate::arch::instructions::interrupts;
+
+/// The most claims that can be held at once
+const MAX_RESOURCES: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    /// Interrupt vectors
+    Irq,
+    /// I/O ports
+    Port,
+    /// Physical addresses of device memory
+    Mmio,
+    /// ISA DMA channels
+    Dma,
+}
+
+impl ResourceKind {
+    pub const ALL: [Self; 4] = [Self::Port, Self::Mmio, Self::Irq, Self::Dma];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Irq => "irq",
+            Self::Port => "ioport",
+            Self::Mmio => "iomem",
+            Self::Dma => "dma",
+        }
+    }
+
+    /// The hex digits of the addresses in reports, more if an address needs them
+    fn width(self) -> usize {
+        match self {
+            Self::Irq => 2,
+            Self::Port => 4,
+            Self::Mmio => 8,
+            Self::Dma => 1,
+        }
+    }
+}
+
+impl fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceError {
+    /// The range overlaps a claim it can't nest in, or that can't nest in it
+    Conflict {
+        owner: &'static str,
+        start: u64,
+        end: u64,
+        site: &'static Location<'static>,
+    },
+    /// The range ends before it starts
+    InvalidRange,
+    /// There are [`MAX_RESOURCES`] claims already
+    Full,
+}
+
+impl fmt::Display for ResourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Conflict {
+                owner,
+                start,
+                end,
+                site,
+            } => {
+                write!(
+                    f,
+                    "conflicts with {:#x}-{:#x} of {} claimed at {}",
+                    start, end, owner, site
+                )
+            }
+            Self::InvalidRange => f.write_str("invalid range"),
+            Self::Full => write!(f, "more than {} claimed resources", MAX_RESOURCES),
+        }
+    }
+}
+
+impl core::error::Error for ResourceError {}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    kind: ResourceKind,
+    start: u64,
+    end: u64,
+    owner: &'static str,
+    site: &'static Location<'static>,
+    /// Whether other claims can't nest in it
+    exclusive: bool,
+    parent: Option<usize>,
+    refs: usize,
+}
+
+impl Node {
+    fn contains(&self, start: u64, end: u64) -> bool {
+        self.start <= start && end <= self.end
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflict(&self) -> ResourceError {
+        ResourceError::Conflict {
+            owner: self.owner,
+            start: self.start,
+            end: self.end,
+            site: self.site,
+        }
+    }
+}
+
+/// The claims of every kind, each linked to the claim it nests in
+struct Tree {
+    nodes: [Option<Node>; MAX_RESOURCES],
+}
+
+impl Tree {
+    const fn new() -> Self {
+        Self {
+            nodes: [None; MAX_RESOURCES],
+        }
+    }
+
+    fn depth(&self, mut idx: usize) -> usize {
+        let mut depth = 0;
+        while let Some(parent) = self.nodes[idx].and_then(|node| node.parent) {
+            depth += 1;
+            idx = parent;
+        }
+        depth
+    }
+
+    fn children(&self, kind: ResourceKind, parent: Option<usize>) -> impl Iterator<Item = (usize, &Node)> + '_ {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, node)| Some((idx, node.as_ref()?)))
+            .filter(move |(_, node)| node.kind == kind && node.parent == parent)
+    }
+
+    fn insert(&mut self, mut node: Node) -> Result<usize, ResourceError> {
+        if node.end < node.start {
+            return Err(ResourceError::InvalidRange);
+        }
+        // The deepest claim containing the range is where it goes, as claims never partially
+        // overlap, the ones containing it form a chain
+        let parent = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, other)| Some((idx, other.as_ref()?)))
+            .filter(|(_, other)| other.kind == node.kind && other.contains(node.start, node.end))
+            .max_by_key(|(idx, _)| self.depth(*idx))
+            .map(|(idx, other)| (idx, *other));
+        if let Some((_, parent)) = parent.filter(|(_, parent)| parent.exclusive) {
+            return Err(parent.conflict());
+        }
+        let parent = parent.map(|(idx, _)| idx);
+
+        // Siblings inside the range become its children, if it is a window
+        let mut adopted = [false; MAX_RESOURCES];
+        for (idx, sibling) in self.children(node.kind, parent) {
+            if !sibling.overlaps(node.start, node.end) {
+                continue;
+            }
+            if node.exclusive || !node.contains(sibling.start, sibling.end) {
+                return Err(sibling.conflict());
+            }
+            adopted[idx] = true;
+        }
+
+        let idx = self.nodes.iter().position(Option::is_none).ok_or(ResourceError::Full)?;
+        node.parent = parent;
+        self.nodes[idx] = Some(node);
+        for (child, _) in adopted.iter().enumerate().filter(|(_, adopted)| **adopted) {
+            if let Some(child) = &mut self.nodes[child] {
+                child.parent = Some(idx);
+            }
+        }
+        Ok(idx)
+    }
+
+    /// Removes a claim, its children nest in its parent from then on
+    fn remove(&mut self, idx: usize) {
+        let Some(node) = self.nodes[idx].take() else {
+            return;
+        };
+        for child in self.nodes.iter_mut().flatten() {
+            if child.parent == Some(idx) {
+                child.parent = node.parent;
+            }
+        }
+    }
+
+    fn write(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        for kind in ResourceKind::ALL {
+            writeln!(out, "{}:", kind)?;
+            self.write_children(out, kind, None, 1)?;
+        }
+        Ok(())
+    }
+
+    fn write_children(
+        &self,
+        out: &mut dyn fmt::Write,
+        kind: ResourceKind,
+        parent: Option<usize>,
+        depth: usize,
+    ) -> fmt::Result {
+        let mut children: Vec<_> = self.children(kind, parent).collect();
+        children.sort_by_key(|(_, node)| node.start);
+        for (idx, node) in children {
+            writeln!(
+                out,
+                "{:indent$}{:0width$x}-{:0width$x} : {}",
+                "",
+                node.start,
+                node.end,
+                node.owner,
+                indent = depth * 2,
+                width = kind.width()
+            )?;
+            self.write_children(out, kind, Some(idx), depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+// A spin lock, as ports are claimed through `io_audit`, whose checks lockdep would run into
+static TREE: spin::RwLock<Tree> = spin::RwLock::new(Tree::new());
+
+/// A claimed resource, released when the last handle to it is dropped
+#[must_use = "the claim is released right away, use `Resource::forget` to keep it"]
+#[derive(Debug)]
+pub struct Resource {
+    idx: usize,
+}
+
+impl Resource {
+    fn node(&self) -> Node {
+        interrupts::without_interrupts(|| TREE.read().nodes[self.idx].expect("resource: handle to a released claim"))
+    }
+
+    pub fn kind(&self) -> ResourceKind {
+        self.node().kind
+    }
+
+    pub fn range(&self) -> RangeInclusive<u64> {
+        let node = self.node();
+        node.start..=node.end
+    }
+
+    pub fn owner(&self) -> &'static str {
+        self.node().owner
+    }
+
+    /// Keeps the claim until reboot
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Clone for Resource {
+    fn clone(&self) -> Self {
+        interrupts::without_interrupts(|| {
+            if let Some(node) = &mut TREE.write().nodes[self.idx] {
+                node.refs += 1;
+            }
+        });
+        Self { idx: self.idx }
+    }
+}
+
+impl Drop for Resource {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut tree = TREE.write();
+            let Some(node) = &mut tree.nodes[self.idx] else {
+                return;
+            };
+            node.refs -= 1;
+            if node.refs == 0 {
+                tree.remove(self.idx);
+            }
+        });
+    }
+}
+
+fn insert(
+    kind: ResourceKind,
+    range: RangeInclusive<u64>,
+    owner: &'static str,
+    exclusive: bool,
+    site: &'static Location<'static>,
+) -> Result<Resource, ResourceError> {
+    let node = Node {
+        kind,
+        start: *range.start(),
+        end: *range.end(),
+        owner,
+        site,
+        exclusive,
+        parent: None,
+        refs: 1,
+    };
+    let idx = interrupts::without_interrupts(|| TREE.write().insert(node))?;
+    Ok(Resource { idx })
+}
+
+/// Claims a range for `owner` alone, nothing else can overlap it
+#[track_caller]
+pub fn claim(kind: ResourceKind, range: RangeInclusive<u64>, owner: &'static str) -> Result<Resource, ResourceError> {
+    insert(kind, range, owner, true, Location::caller())
+}
+
+/// Claims a range that other claims can nest in, like the window of a bridge
+#[track_caller]
+pub fn claim_window(
+    kind: ResourceKind,
+    range: RangeInclusive<u64>,
+    owner: &'static str,
+) -> Result<Resource, ResourceError> {
+    insert(kind, range, owner, false, Location::caller())
+}
+
+/// Claims a range of the platform until reboot, conflicts are only logged
+///
+/// For the interrupt controllers and timers, which are set up once and never given back.
+#[track_caller]
+pub fn reserve(kind: ResourceKind, range: RangeInclusive<u64>, owner: &'static str) {
+    let (start, end) = (*range.start(), *range.end());
+    match claim(kind, range, owner) {
+        Ok(claim) => claim.forget(),
+        Err(err) => crate::kprintln!(Warn, "{}: {} {:#x}-{:#x} {}", owner, kind, start, end, err),
+    }
+}
+
+/// Returns the owner of the innermost claim containing `addr`
+pub fn owner(kind: ResourceKind, addr: u64) -> Option<&'static str> {
+    interrupts::without_interrupts(|| {
+        let tree = TREE.read();
+        tree.nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, node)| Some((idx, node.as_ref()?)))
+            .filter(|(_, node)| node.kind == kind && node.contains(addr, addr))
+            .max_by_key(|(idx, _)| tree.depth(*idx))
+            .map(|(_, node)| node.owner)
+    })
+}
+
+/// A claim, as returned by [`claims`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClaimInfo {
+    /// Identifies the claim among the others returned with it
+    pub id: usize,
+    pub kind: ResourceKind,
+    pub range: RangeInclusive<u64>,
+    pub owner: &'static str,
+    /// The id of the claim it nests in
+    pub parent: Option<usize>,
+}
+
+/// Returns every claim, sorted by kind and address
+pub fn claims() -> Vec<ClaimInfo> {
+    let nodes = interrupts::without_interrupts(|| TREE.read().nodes);
+    let mut claims: Vec<ClaimInfo> = nodes
+        .iter()
+        .enumerate()
+        .filter_map(|(id, node)| {
+            let node = node.as_ref()?;
+            Some(ClaimInfo {
+                id,
+                kind: node.kind,
+                range: node.start..=node.end,
+                owner: node.owner,
+                parent: node.parent,
+            })
+        })
+        .collect();
+    claims.sort_by_key(|claim| {
+        (
+            ResourceKind::ALL.iter().position(|kind| *kind == claim.kind),
+            *claim.range.start(),
+        )
+    });
+    claims
+}
+
+/// Writes every claim, nested in the claims containing it, like `/proc/iomem`
+///
+/// ```text
+/// ioport:
+///   0040-0043 : pit
+/// iomem:
+///   fee00000-fee00fff : local apic
+/// ```
+pub fn report(out: &mut dyn fmt::Write) -> fmt::Result {
+    let tree = interrupts::without_interrupts(|| TREE.read().nodes);
+    Tree { nodes: tree }.write(out)
+}
+
+pub fn init() {
+    crate::stats::register("resources", report);
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::string::String;
+
+    use super::*;
+
+    fn node(kind: ResourceKind, range: RangeInclusive<u64>, owner: &'static str, exclusive: bool) -> Node {
+        Node {
+            kind,
+            start: *range.start(),
+            end: *range.end(),
+            owner,
+            site: Location::caller(),
+            exclusive,
+            parent: None,
+            refs: 1,
+        }
+    }
+
+    #[test]
+    fn resource_nesting_and_conflicts() {
+        use ResourceKind::*;
+
+        let mut tree = Tree::new();
+        let nvme = tree
+            .insert(node(Mmio, 0xfe00_0000..=0xfe00_3fff, "nvme", true))
+            .unwrap();
+        // Claimed after its child, the bridge window adopts it
+        let bridge = tree
+            .insert(node(Mmio, 0xfe00_0000..=0xfeff_ffff, "pci bridge", false))
+            .unwrap();
+        tree.insert(node(Mmio, 0xfe10_0000..=0xfe10_0fff, "virtio", true))
+            .unwrap();
+        tree.insert(node(Port, 0x40..=0x43, "pit", true)).unwrap();
+
+        assert!(matches!(
+            tree.insert(node(Mmio, 0xfe00_1000..=0xfe00_1fff, "other", true)),
+            Err(ResourceError::Conflict { owner: "nvme", .. })
+        ));
+        assert!(matches!(
+            tree.insert(node(Mmio, 0xfeff_0000..=0xff00_0fff, "straddling", false)),
+            Err(ResourceError::Conflict {
+                owner: "pci bridge",
+                ..
+            })
+        ));
+        assert_eq!(
+            tree.insert(node(Irq, 0x30..=0x20, "backwards", true)),
+            Err(ResourceError::InvalidRange)
+        );
+        assert_eq!(tree.nodes[nvme].unwrap().parent, Some(bridge));
+
+        let mut out = String::new();
+        tree.write(&mut out).unwrap();
+        assert_eq!(
+            out,
+            "ioport:\n  0040-0043 : pit\niomem:\n  fe000000-feffffff : pci bridge\n    fe000000-fe003fff : nvme\n    \
+             fe100000-fe100fff : virtio\nirq:\ndma:\n"
+        );
+
+        // The children of a released window move up
+        tree.remove(bridge);
+        assert_eq!(tree.nodes[nvme].unwrap().parent, None);
+    }
+}
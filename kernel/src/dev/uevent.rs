@@ -0,0 +1,159 @@
+//! Device event notifications
+//!
+//! Device add/remove/bind/unbind events are recorded in a fixed size log from boot,
+//! so a reader attached later (such as a userspace device manager) still sees early devices.
+//! Each reader keeps its own cursor, and is told how many events it missed if it falls behind.
+
+use core::fmt::{self, Write};
+
+use crate::{sync::Mutex, time};
+
+/// The number of events kept in the log
+const UEVENT_LOG_SIZE: usize = 256;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UeventAction {
+    Add,
+    Remove,
+    Bind,
+    Unbind,
+}
+
+impl fmt::Display for UeventAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Add => "add",
+            Self::Remove => "remove",
+            Self::Bind => "bind",
+            Self::Unbind => "unbind",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Uevent {
+    pub seqnum: u64,
+    pub timestamp_ns: u64,
+    pub action: UeventAction,
+    pub subsystem: &'static str,
+    pub devname: &'static str,
+    pub driver: Option<&'static str>,
+}
+
+impl fmt::Display for Uevent {
+    /// Formats the event as NUL separated `KEY=VALUE` pairs, following an `action@devpath` line
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@/devices/{}/{}\0", self.action, self.subsystem, self.devname)?;
+        write!(f, "ACTION={}\0", self.action)?;
+        write!(f, "DEVPATH=/devices/{}/{}\0", self.subsystem, self.devname)?;
+        write!(f, "SUBSYSTEM={}\0", self.subsystem)?;
+        if let Some(driver) = self.driver {
+            write!(f, "DRIVER={}\0", driver)?;
+        }
+        write!(f, "SEQNUM={}\0", self.seqnum)
+    }
+}
+
+struct UeventLog {
+    events: [Option<Uevent>; UEVENT_LOG_SIZE],
+    /// The sequence number of the next event
+    next_seq: u64,
+}
+
+static UEVENTS: Mutex<UeventLog> = Mutex::new(UeventLog {
+    events: [None; UEVENT_LOG_SIZE],
+    next_seq: 0,
+});
+
+/// Records a device event
+pub fn emit(action: UeventAction, subsystem: &'static str, devname: &'static str, driver: Option<&'static str>) {
+    let mut log = UEVENTS.lock();
+    let seqnum = log.next_seq;
+    log.events[seqnum as usize % UEVENT_LOG_SIZE] = Some(Uevent {
+        seqnum,
+        timestamp_ns: time::monotonic_ns(),
+        action,
+        subsystem,
+        devname,
+        driver,
+    });
+    log.next_seq += 1;
+}
+
+/// The result of reading from a [`UeventReader`]
+#[derive(Debug, Clone, Copy)]
+pub enum UeventRead {
+    Event(Uevent),
+    /// The reader fell behind, and this many events were overwritten
+    Lost(u64),
+}
+
+/// A cursor over the event log
+#[derive(Debug)]
+pub struct UeventReader {
+    next_seq: u64,
+}
+
+impl UeventReader {
+    /// Creates a reader starting at the oldest event still in the log
+    pub fn from_start() -> Self {
+        let log = UEVENTS.lock();
+        Self {
+            next_seq: log.next_seq.saturating_sub(UEVENT_LOG_SIZE as u64),
+        }
+    }
+
+    /// Creates a reader that only sees events emitted after this call
+    pub fn from_now() -> Self {
+        Self {
+            next_seq: UEVENTS.lock().next_seq,
+        }
+    }
+
+    /// Returns the next event, if there is one
+    pub fn next_event(&mut self) -> Option<UeventRead> {
+        let log = UEVENTS.lock();
+        if self.next_seq >= log.next_seq {
+            return None;
+        }
+
+        let oldest = log.next_seq.saturating_sub(UEVENT_LOG_SIZE as u64);
+        if self.next_seq < oldest {
+            let lost = oldest - self.next_seq;
+            self.next_seq = oldest;
+            return Some(UeventRead::Lost(lost));
+        }
+
+        let event = log.events[self.next_seq as usize % UEVENT_LOG_SIZE]?;
+        self.next_seq += 1;
+        Some(UeventRead::Event(event))
+    }
+
+    /// Reads the next event into `buf` in its textual form, returning the number of bytes written
+    ///
+    /// Returns zero if there are no pending events. Events that don't fit are truncated.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut writer = SliceWriter { buf, len: 0 };
+        match self.next_event() {
+            Some(UeventRead::Event(event)) => _ = write!(writer, "{}", event),
+            Some(UeventRead::Lost(lost)) => _ = write!(writer, "LOST={}\0", lost),
+            None => {}
+        }
+        writer.len
+    }
+}
+
+struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        if n < s.len() { Err(fmt::Error) } else { Ok(()) }
+    }
+}
@@ -0,0 +1,165 @@
+//! The virtio console driver
+//!
+//! A virtio-serial device has a port 0 that works without negotiating multiple ports, which is
+//! the only one the driver uses. In QEMU that is a `virtconsole` on a `virtio-serial-device`,
+//! whose other end can be a socket or a pipe on the host. The driver polls, it doesn't use the
+//! interrupt.
+
+use core::ptr;
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    arch::{PhysAddr, VirtAddr},
+    dev::virtio::{DeviceType, Transport, VirtioError, mmio, queue::VirtQueue},
+    kprintln,
+    mm::{
+        FRAME_ALLOCATOR,
+        page_table::KernelPageTable,
+        paging::{PageSize, Size4KiB},
+    },
+    sync::{Mutex, RwLock},
+    time,
+};
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+/// The receive buffers fill the first half of the buffer page, and the transmit buffer the rest
+const RX_BUFFERS: usize = 8;
+const RX_BUFFER_SIZE: usize = 256;
+const TX_BUFFER_SIZE: usize = Size4KiB::SIZE - RX_BUFFERS * RX_BUFFER_SIZE;
+const TRANSMIT_TIMEOUT_NS: u64 = 1_000_000_000;
+
+struct Queues {
+    rx: VirtQueue,
+    tx: VirtQueue,
+    /// The receive buffer of each descriptor of the receive queue
+    rx_slots: Vec<Option<usize>>,
+    /// Set once a transmit timed out, the device may still read the transmit buffer
+    broken: bool,
+}
+
+pub struct VirtioConsole {
+    transport: Arc<dyn Transport>,
+    buffers: PhysAddr,
+    queues: Mutex<Queues>,
+}
+
+impl VirtioConsole {
+    /// Sets up port 0 of a console device
+    pub fn new(transport: Arc<dyn Transport>) -> Result<Self, VirtioError> {
+        transport.negotiate(0)?;
+        let rx = VirtQueue::new(&*transport, RECEIVE_QUEUE, RX_BUFFERS as u16)?;
+        let tx = VirtQueue::new(&*transport, TRANSMIT_QUEUE, 1)?;
+        let buffers = FRAME_ALLOCATOR
+            .lock()
+            .allocate_contiguous(1)
+            .ok_or(VirtioError::OutOfMemory)?
+            .start_address();
+        let console = Self {
+            transport,
+            buffers,
+            queues: Mutex::new(Queues {
+                rx_slots: alloc::vec![None; rx.size() as usize],
+                rx,
+                tx,
+                broken: false,
+            }),
+        };
+        {
+            let mut queues = console.queues.lock();
+            for buffer in 0..RX_BUFFERS.min(queues.rx.size() as usize) {
+                console.offer_rx(&mut queues, buffer);
+            }
+        }
+        console.transport.set_driver_ok();
+        console.transport.notify(RECEIVE_QUEUE);
+        Ok(console)
+    }
+
+    fn buffer(&self, offset: usize) -> (PhysAddr, VirtAddr) {
+        let phys = self.buffers + offset;
+        (phys, KernelPageTable::direct_map_start() + phys.as_usize())
+    }
+
+    fn offer_rx(&self, queues: &mut Queues, buffer: usize) {
+        let (phys, _) = self.buffer(buffer * RX_BUFFER_SIZE);
+        // SAFETY: The buffers live as long as the device
+        if let Some(id) = unsafe { queues.rx.push(phys, RX_BUFFER_SIZE as u32, true) } {
+            queues.rx_slots[id as usize] = Some(buffer);
+        }
+    }
+
+    /// Appends the bytes received since the last call to `out`
+    pub fn read(&self, out: &mut Vec<u8>) {
+        self.transport.ack_interrupt();
+        let mut queues = self.queues.lock();
+        let mut offered = false;
+        while let Some((id, len)) = queues.rx.pop_used() {
+            let Some(buffer) = queues.rx_slots[id as usize].take() else {
+                continue;
+            };
+            let (_, virt) = self.buffer(buffer * RX_BUFFER_SIZE);
+            let len = (len as usize).min(RX_BUFFER_SIZE);
+            // SAFETY: The device is done with the buffer
+            out.extend_from_slice(unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), len) });
+            self.offer_rx(&mut queues, buffer);
+            offered = true;
+        }
+        if offered {
+            self.transport.notify(RECEIVE_QUEUE);
+        }
+    }
+
+    /// Sends bytes to the host, waiting until the device has taken them
+    pub fn write(&self, data: &[u8]) -> Result<(), VirtioError> {
+        let mut queues = self.queues.lock();
+        if queues.broken {
+            return Err(VirtioError::Timeout);
+        }
+        let (phys, virt) = self.buffer(RX_BUFFERS * RX_BUFFER_SIZE);
+        for chunk in data.chunks(TX_BUFFER_SIZE) {
+            // SAFETY: Only one transmit is in flight, and it is waited for below
+            unsafe {
+                ptr::copy_nonoverlapping(chunk.as_ptr(), virt.as_mut_ptr::<u8>(), chunk.len());
+                if queues.tx.push(phys, chunk.len() as u32, false).is_none() {
+                    return Err(VirtioError::QueueUnavailable(TRANSMIT_QUEUE));
+                }
+            }
+            self.transport.notify(TRANSMIT_QUEUE);
+            let deadline = time::monotonic_ns() + TRANSMIT_TIMEOUT_NS;
+            while queues.tx.pop_used().is_none() {
+                if time::monotonic_ns() > deadline {
+                    queues.broken = true;
+                    return Err(VirtioError::Timeout);
+                }
+                core::hint::spin_loop();
+            }
+        }
+        Ok(())
+    }
+}
+
+static DEVICES: RwLock<Vec<Arc<VirtioConsole>>> = RwLock::new(Vec::new());
+
+/// Returns the consoles that were set up
+pub fn devices() -> Vec<Arc<VirtioConsole>> {
+    DEVICES.read().clone()
+}
+
+/// Sets up the console devices found by the transports
+pub fn init() {
+    for dev in mmio::devices() {
+        if dev.device_type() != DeviceType::Console {
+            continue;
+        }
+        let base = dev.desc().base;
+        match VirtioConsole::new(dev) {
+            Ok(console) => {
+                kprintln!(Info, "virtio-console: port 0 at {:#x}", base);
+                DEVICES.write().push(Arc::new(console));
+            }
+            Err(err) => kprintln!(Warn, "virtio-console: {:#x}: {}", base, err),
+        }
+    }
+}
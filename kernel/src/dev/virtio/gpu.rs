@@ -0,0 +1,608 @@
+//! The virtio-gpu driver
+//!
+//! A virtio-gpu device scans out of resources that live on the host. The driver creates a 2D
+//! resource of the requested size, backs it with guest memory the console draws into, and points
+//! the scanout at it. What is drawn only reaches the host when it is transferred into the resource
+//! and flushed, which [`display::poll`] does periodically. Commands go through the control queue
+//! one at a time, and are polled for.
+//!
+//! With the EDID feature, the device also describes the display of the scanout with an EDID,
+//! listing the modes the host offers.
+//!
+//! Until the first mode change the display shows what the firmware left: VGA mode on `virtio-vga`,
+//! which is also where the boot framebuffer comes from, and nothing on `virtio-gpu-pci`. Resetting
+//! the device on shutdown puts `virtio-vga` back into VGA mode. In QEMU that is `-vga virtio` or
+//! `-device virtio-gpu-pci`, and any resolution up to the size of the window can be set.
+
+use core::{fmt, ptr};
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+
+use crate::{
+    arch::{PhysAddr, VirtAddr},
+    dev::{
+        drivers::pci::{PciDevMatcher, PciDrv},
+        pci::PciDevice,
+        virtio::{
+            Transport, VirtioError,
+            pci::{DEVICE_MODERN_BASE, VENDOR_VIRTIO, VirtioPci, VirtioPciError},
+            queue::VirtQueue,
+        },
+    },
+    display::{self, DisplayDevice, DisplayError, DisplayMode, Scanout},
+    kprintln,
+    mm::{
+        FRAME_ALLOCATOR,
+        page_table::KernelPageTable,
+        paging::{PageSize, PhysFrame, Size4KiB},
+    },
+    module::abi::{AbiSlice, AbiStr},
+    sync::Mutex,
+    time,
+};
+
+pub const DEVICE_GPU: u16 = DEVICE_MODERN_BASE + 16;
+
+const CONTROL_QUEUE: u16 = 0;
+
+/// The device can read the EDID of a scanout
+const FEATURE_EDID: u64 = 1 << 1;
+
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_RESOURCE_UNREF: u32 = 0x0102;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+const CMD_GET_EDID: u32 = 0x010A;
+
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+const RESP_OK_EDID: u32 = 0x1104;
+/// Replies from here on are errors
+const RESP_ERR_UNSPEC: u32 = 0x1200;
+const RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+
+/// The byte order the framebuffer console draws in
+const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+const MAX_SCANOUTS: usize = 16;
+/// The largest EDID a reply holds
+const MAX_EDID: usize = 1024;
+/// The words of the header of every command and reply
+const HEADER_WORDS: usize = 6;
+/// Requests go in the first half of the command page, replies in the second
+const REPLY_OFFSET: usize = Size4KiB::SIZE / 2;
+const COMMAND_TIMEOUT_NS: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub const fn of_size(width: u32, height: u32) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    fn words(&self) -> [u32; 4] {
+        [self.x, self.y, self.width, self.height]
+    }
+}
+
+/// A command of the control queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    GetDisplayInfo,
+    CreateResource {
+        id: u32,
+        width: u32,
+        height: u32,
+    },
+    Unref {
+        id: u32,
+    },
+    /// Resource 0 disables the scanout
+    SetScanout {
+        scanout: u32,
+        id: u32,
+        rect: Rect,
+    },
+    Flush {
+        id: u32,
+        rect: Rect,
+    },
+    /// Copies the rectangle from guest memory, `offset` bytes into the backing
+    Transfer {
+        id: u32,
+        rect: Rect,
+        offset: u64,
+    },
+    /// Backs a resource with one contiguous range of guest memory
+    AttachBacking {
+        id: u32,
+        addr: PhysAddr,
+        len: u32,
+    },
+    DetachBacking {
+        id: u32,
+    },
+    GetEdid {
+        scanout: u32,
+    },
+}
+
+impl Command {
+    /// Encodes the command into `out`, returning the number of words
+    fn encode(&self, out: &mut [u32; 16]) -> usize {
+        let mut len = HEADER_WORDS;
+        let mut push = |words: &[u32]| {
+            out[len..len + words.len()].copy_from_slice(words);
+            len += words.len();
+        };
+        let ty = match *self {
+            Self::GetDisplayInfo => CMD_GET_DISPLAY_INFO,
+            Self::CreateResource { id, width, height } => {
+                push(&[id, FORMAT_B8G8R8X8_UNORM, width, height]);
+                CMD_RESOURCE_CREATE_2D
+            }
+            Self::Unref { id } => {
+                push(&[id, 0]);
+                CMD_RESOURCE_UNREF
+            }
+            Self::SetScanout { scanout, id, rect } => {
+                push(&rect.words());
+                push(&[scanout, id]);
+                CMD_SET_SCANOUT
+            }
+            Self::Flush { id, rect } => {
+                push(&rect.words());
+                push(&[id, 0]);
+                CMD_RESOURCE_FLUSH
+            }
+            Self::Transfer { id, rect, offset } => {
+                push(&rect.words());
+                push(&[offset as u32, (offset >> 32) as u32, id, 0]);
+                CMD_TRANSFER_TO_HOST_2D
+            }
+            Self::AttachBacking { id, addr, len } => {
+                let addr = addr.as_u64();
+                push(&[id, 1, addr as u32, (addr >> 32) as u32, len, 0]);
+                CMD_RESOURCE_ATTACH_BACKING
+            }
+            Self::DetachBacking { id } => {
+                push(&[id, 0]);
+                CMD_RESOURCE_DETACH_BACKING
+            }
+            Self::GetEdid { scanout } => {
+                push(&[scanout, 0]);
+                CMD_GET_EDID
+            }
+        };
+        // The flags, fence, context and ring index are unused
+        out[..HEADER_WORDS].copy_from_slice(&[ty, 0, 0, 0, 0, 0]);
+        len
+    }
+}
+
+/// Returns the first enabled scanout of a display info reply, and its preferred size
+fn first_scanout(reply: &[u32]) -> Option<(u32, Rect)> {
+    let modes = reply.get(HEADER_WORDS..)?;
+    let (modes, _) = modes.as_chunks::<6>();
+    modes
+        .iter()
+        .take(MAX_SCANOUTS)
+        .enumerate()
+        .find(|(_, mode)| mode[4] != 0)
+        .map(|(scanout, mode)| {
+            (
+                scanout as u32,
+                Rect {
+                    x: mode[0],
+                    y: mode[1],
+                    width: mode[2],
+                    height: mode[3],
+                },
+            )
+        })
+}
+
+/// Returns the EDID of an EDID reply
+fn edid_of(reply: &[u32]) -> Option<Vec<u8>> {
+    let size = (*reply.get(HEADER_WORDS)? as usize).min(MAX_EDID);
+    let words = reply.get(HEADER_WORDS + 2..HEADER_WORDS + 2 + size.div_ceil(4))?;
+    let mut edid: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    edid.truncate(size);
+    (reply[0] == RESP_OK_EDID && size > 0).then_some(edid)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum GpuError {
+    Pci(VirtioPciError),
+    Virtio(VirtioError),
+    /// The device replied to a command with an error
+    Command {
+        command: u32,
+        reply: u32,
+    },
+    /// The device didn't answer a command, it isn't used anymore
+    Timeout,
+    /// No scanout is enabled
+    NoScanout,
+}
+
+impl fmt::Display for GpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pci(err) => write!(f, "{}", err),
+            Self::Virtio(err) => write!(f, "{}", err),
+            Self::Command { command, reply } => write!(f, "command {:#x} failed with {:#x}", command, reply),
+            Self::Timeout => f.write_str("command timed out"),
+            Self::NoScanout => f.write_str("no scanout is enabled"),
+        }
+    }
+}
+
+impl core::error::Error for GpuError {}
+
+impl From<VirtioError> for GpuError {
+    fn from(err: VirtioError) -> Self {
+        Self::Virtio(err)
+    }
+}
+
+/// A resource the scanout shows, backed by contiguous guest memory
+#[derive(Debug)]
+struct Resource {
+    id: u32,
+    backing: PhysFrame,
+    pages: usize,
+    mode: DisplayMode,
+}
+
+impl Resource {
+    fn rect(&self) -> Rect {
+        Rect::of_size(self.mode.width, self.mode.height)
+    }
+}
+
+struct Control {
+    queue: VirtQueue,
+    next_id: u32,
+    resource: Option<Resource>,
+    /// Set once a command timed out, the device may still write into the command page
+    broken: bool,
+}
+
+pub struct VirtioGpu {
+    name: String,
+    transport: VirtioPci,
+    /// The page commands and replies go through
+    commands: PhysAddr,
+    scanout: u32,
+    /// The size the host prefers, usually that of its window
+    preferred: Rect,
+    /// The EDID of the scanout, if the device supports reading it
+    edid: Option<Vec<u8>>,
+    control: Mutex<Control>,
+}
+
+impl VirtioGpu {
+    pub fn new(dev: &PciDevice) -> Result<Self, GpuError> {
+        let transport = VirtioPci::new(dev).map_err(GpuError::Pci)?;
+        let features = transport.negotiate(FEATURE_EDID)?;
+        let queue = VirtQueue::new(&transport, CONTROL_QUEUE, 2)?;
+        let commands = FRAME_ALLOCATOR
+            .lock()
+            .allocate_contiguous(1)
+            .ok_or(VirtioError::OutOfMemory)?
+            .start_address();
+        transport.set_driver_ok();
+        let mut gpu = Self {
+            name: format!("virtio-gpu {}", dev.addr),
+            transport,
+            commands,
+            scanout: 0,
+            preferred: Rect::of_size(0, 0),
+            edid: None,
+            control: Mutex::new(Control {
+                queue,
+                next_id: 1,
+                resource: None,
+                broken: false,
+            }),
+        };
+        let reply = gpu.command(&mut gpu.control.lock(), Command::GetDisplayInfo)?;
+        (gpu.scanout, gpu.preferred) = first_scanout(&reply).ok_or(GpuError::NoScanout)?;
+        if features & FEATURE_EDID != 0 {
+            let command = Command::GetEdid { scanout: gpu.scanout };
+            match gpu.command(&mut gpu.control.lock(), command) {
+                Ok(reply) => gpu.edid = edid_of(&reply),
+                Err(err) => kprintln!(Warn, "{}: EDID: {}", gpu.name, err),
+            }
+        }
+        Ok(gpu)
+    }
+
+    fn page(&self) -> VirtAddr {
+        KernelPageTable::direct_map_start() + self.commands.as_usize()
+    }
+
+    /// Sends a command and waits for the reply, returning its words
+    fn command(&self, control: &mut Control, command: Command) -> Result<[u32; REPLY_OFFSET / 4], GpuError> {
+        if control.broken {
+            return Err(GpuError::Timeout);
+        }
+        let mut words = [0; 16];
+        let len = command.encode(&mut words);
+        let page = self.page();
+        // SAFETY: The page belongs to the device, and only one command is in flight
+        unsafe {
+            ptr::copy_nonoverlapping(words.as_ptr(), page.as_mut_ptr::<u32>(), len);
+            ptr::write_bytes((page + REPLY_OFFSET).as_mut_ptr::<u8>(), 0, REPLY_OFFSET);
+        }
+        let buffers = [
+            (self.commands, (len * 4) as u32, false),
+            (self.commands + REPLY_OFFSET, REPLY_OFFSET as u32, true),
+        ];
+        // SAFETY: The command is waited for below
+        if unsafe { control.queue.push_chain(&buffers) }.is_none() {
+            return Err(VirtioError::QueueUnavailable(CONTROL_QUEUE).into());
+        }
+        self.transport.notify(CONTROL_QUEUE);
+        let deadline = time::monotonic_ns() + COMMAND_TIMEOUT_NS;
+        while control.queue.pop_used().is_none() {
+            if time::monotonic_ns() > deadline {
+                control.broken = true;
+                return Err(GpuError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        let mut reply = [0; REPLY_OFFSET / 4];
+        // SAFETY: The device is done with the reply
+        unsafe {
+            ptr::copy_nonoverlapping((page + REPLY_OFFSET).as_ptr::<u32>(), reply.as_mut_ptr(), reply.len());
+        }
+        if reply[0] >= RESP_ERR_UNSPEC {
+            return Err(GpuError::Command {
+                command: words[0],
+                reply: reply[0],
+            });
+        }
+        if command == Command::GetDisplayInfo && reply[0] != RESP_OK_DISPLAY_INFO {
+            return Err(GpuError::NoScanout);
+        }
+        Ok(reply)
+    }
+
+    /// Creates a resource for a mode, and shows it on the scanout
+    fn create(&self, control: &mut Control, width: u32, height: u32) -> Result<Resource, GpuError> {
+        let stride = width * 4;
+        let size = stride as usize * height as usize;
+        let pages = size.div_ceil(Size4KiB::SIZE);
+        let backing = FRAME_ALLOCATOR
+            .lock()
+            .allocate_contiguous(pages)
+            .ok_or(VirtioError::OutOfMemory)?;
+        let virt = KernelPageTable::direct_map_start() + backing.start_address().as_usize();
+        // SAFETY: The frames were just allocated, and are mapped by the direct map
+        unsafe { ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, pages * Size4KiB::SIZE) };
+
+        let id = control.next_id;
+        control.next_id += 1;
+        let resource = Resource {
+            id,
+            backing,
+            pages,
+            mode: DisplayMode {
+                width,
+                height,
+                bpp: 32,
+                stride,
+                timings: None,
+            },
+        };
+        let rect = resource.rect();
+        let result = [
+            Command::CreateResource { id, width, height },
+            Command::AttachBacking {
+                id,
+                addr: backing.start_address(),
+                len: size as u32,
+            },
+            Command::SetScanout {
+                scanout: self.scanout,
+                id,
+                rect,
+            },
+        ]
+        .into_iter()
+        .try_for_each(|command| self.command(control, command).map(|_| ()));
+        if let Err(err) = result {
+            self.destroy(control, resource);
+            return Err(err);
+        }
+        Ok(resource)
+    }
+
+    /// Frees a resource the scanout doesn't show anymore
+    fn destroy(&self, control: &mut Control, resource: Resource) {
+        let detached = self.command(control, Command::DetachBacking { id: resource.id });
+        _ = self.command(control, Command::Unref { id: resource.id });
+        // Memory the device may still use is leaked rather than reused
+        if !control.broken && detached.is_ok() {
+            // SAFETY: The device doesn't use the backing anymore
+            unsafe {
+                FRAME_ALLOCATOR
+                    .lock()
+                    .deallocate_contiguous(resource.backing, resource.pages)
+            };
+        }
+    }
+
+    /// Copies the resource from guest memory to the host, and shows it
+    fn present(&self, control: &mut Control) -> Result<(), GpuError> {
+        let Some(resource) = &control.resource else {
+            return Ok(());
+        };
+        let (id, rect) = (resource.id, resource.rect());
+        self.command(control, Command::Transfer { id, rect, offset: 0 })?;
+        self.command(control, Command::Flush { id, rect })?;
+        Ok(())
+    }
+}
+
+// SAFETY: The command page is only accessed with the control queue locked
+unsafe impl Send for VirtioGpu {}
+unsafe impl Sync for VirtioGpu {}
+
+impl DisplayDevice for VirtioGpu {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mode(&self) -> DisplayMode {
+        let control = self.control.lock();
+        match &control.resource {
+            Some(resource) => resource.mode,
+            None => DisplayMode {
+                width: self.preferred.width,
+                height: self.preferred.height,
+                bpp: 32,
+                stride: self.preferred.width * 4,
+                timings: None,
+            },
+        }
+    }
+
+    fn set_mode(&self, width: u32, height: u32, bpp: u32) -> Result<Scanout, DisplayError> {
+        if bpp != 32 || width.checked_mul(4).is_none() {
+            return Err(DisplayError::InvalidMode { width, height, bpp });
+        }
+        let mut control = self.control.lock();
+        let resource = self.create(&mut control, width, height).map_err(|err| {
+            kprintln!(Warn, "{}: {}x{}: {}", self.name, width, height, err);
+            match err {
+                GpuError::Virtio(VirtioError::OutOfMemory)
+                | GpuError::Command {
+                    reply: RESP_ERR_OUT_OF_MEMORY,
+                    ..
+                } => DisplayError::OutOfMemory,
+                _ => DisplayError::InvalidMode { width, height, bpp },
+            }
+        })?;
+        let scanout = Scanout {
+            mode: resource.mode,
+            addr: KernelPageTable::direct_map_start() + resource.backing.start_address().as_usize(),
+        };
+        if let Some(old) = control.resource.replace(resource) {
+            self.destroy(&mut control, old);
+        }
+        Ok(scanout)
+    }
+
+    fn edid(&self) -> Option<Vec<u8>> {
+        self.edid.clone()
+    }
+
+    fn restore(&self) {
+        let mut control = self.control.lock();
+        if let Some(resource) = control.resource.take() {
+            let rect = Rect::of_size(0, 0);
+            _ = self.command(
+                &mut control,
+                Command::SetScanout {
+                    scanout: self.scanout,
+                    id: 0,
+                    rect,
+                },
+            );
+            self.destroy(&mut control, resource);
+            self.transport.reset();
+        }
+    }
+
+    fn flush(&self) {
+        let Some(mut control) = self.control.try_lock() else {
+            return;
+        };
+        if let Err(err) = self.present(&mut control) {
+            kprintln!(Warn, "{}: flush: {}", self.name, err);
+        }
+    }
+}
+
+#[used]
+#[cfg_attr(target_arch = "x86_64", unsafe(link_section = ".pci_drivers"))]
+static VIRTIO_GPU_DRV: PciDrv = PciDrv {
+    name: AbiStr::new("virtio-gpu"),
+    matchers: AbiSlice::new(&[PciDevMatcher {
+        vendor_id: VENDOR_VIRTIO as u32,
+        device_id: DEVICE_GPU as u32,
+        class: PciDevMatcher::ANY,
+    }]),
+    probe: probe_drv,
+};
+
+extern "C" fn probe_drv(dev: &PciDevice) -> bool {
+    match VirtioGpu::new(dev) {
+        Ok(gpu) => {
+            display::register(Arc::new(gpu));
+            true
+        }
+        Err(err) => {
+            kprintln!(Warn, "virtio-gpu: {}: {}", dev.addr, err);
+            false
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtio_gpu_commands() {
+        let mut words = [0; 16];
+        let rect = Rect::of_size(1280, 800);
+        let len = Command::Transfer {
+            id: 3,
+            rect,
+            offset: 1 << 32,
+        }
+        .encode(&mut words);
+        assert_eq!(
+            words[..len],
+            [CMD_TRANSFER_TO_HOST_2D, 0, 0, 0, 0, 0, 0, 0, 1280, 800, 0, 1, 3, 0]
+        );
+        let len = Command::GetDisplayInfo.encode(&mut words);
+        assert_eq!(words[..len], [CMD_GET_DISPLAY_INFO, 0, 0, 0, 0, 0]);
+
+        let mut reply = [0; 128];
+        reply[0] = RESP_OK_DISPLAY_INFO;
+        assert_eq!(first_scanout(&reply), None);
+        // Scanout 1 is enabled, with a preferred size of 1024x768
+        reply[HEADER_WORDS + 6..HEADER_WORDS + 12].copy_from_slice(&[0, 0, 1024, 768, 1, 0]);
+        assert_eq!(first_scanout(&reply), Some((1, Rect::of_size(1024, 768))));
+
+        let len = Command::GetEdid { scanout: 1 }.encode(&mut words);
+        assert_eq!(words[..len], [CMD_GET_EDID, 0, 0, 0, 0, 0, 1, 0]);
+        reply[0] = RESP_OK_EDID;
+        reply[HEADER_WORDS..HEADER_WORDS + 4].copy_from_slice(&[6, 0, 0xFFFF_FF00, 0x00FF_FFFF]);
+        assert_eq!(
+            edid_of(&reply).as_deref(),
+            Some(&[0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF][..])
+        );
+        reply[0] = RESP_OK_DISPLAY_INFO;
+        assert_eq!(edid_of(&reply), None);
+    }
+}
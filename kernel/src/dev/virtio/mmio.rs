@@ -0,0 +1,453 @@
+//! The virtio-mmio transport
+//!
+//! A virtio-mmio device is a block of registers at a fixed physical address with a fixed
+//! interrupt, and nothing on the bus describes it. Machines that use it pass the devices on the
+//! command line instead, as `virtio_mmio.device=<size>@<base>:<irq>[:<id>]`, the format of Linux
+//! which QEMU's `microvm` machine generates. Sizes accept the `K`, `M` and `G` suffixes, and
+//! numbers with a `0x` prefix are hexadecimal. Other ways of finding devices, like a device tree,
+//! hand their descriptions to [`register`].
+//!
+//! Both the legacy (version 1) and the modern (version 2) register layouts are supported. QEMU
+//! still defaults to the legacy one, unless `-global virtio-mmio.force-legacy=false` is given.
+
+use core::fmt;
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    arch::PhysAddr,
+    boot::Cmdline,
+    dev::virtio::{DeviceStatus, DeviceType, FEATURE_VERSION_1, QueueLayout, Transport, VirtioError},
+    kprintln,
+    mm::mmio::{self, MmioRegion, MmioSpaceExhausted},
+    sync::RwLock,
+};
+
+/// "virt" in little endian
+const MAGIC: u32 = 0x7472_6976;
+
+const REG_MAGIC: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_VENDOR_ID: usize = 0x00C;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC: usize = 0x080;
+const REG_QUEUE_DRIVER: usize = 0x090;
+const REG_QUEUE_DEVICE: usize = 0x0A0;
+const REG_CONFIG_GENERATION: usize = 0x0FC;
+const REG_CONFIG: usize = 0x100;
+// Only in the legacy layout
+const REG_LEGACY_GUEST_PAGE_SIZE: usize = 0x028;
+const REG_LEGACY_QUEUE_ALIGN: usize = 0x03C;
+const REG_LEGACY_QUEUE_PFN: usize = 0x040;
+
+/// The option describing a device on the command line
+pub const CMDLINE_OPTION: &str = "virtio_mmio.device";
+
+/// A virtio-mmio device, as described on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmioDeviceDesc {
+    pub base: PhysAddr,
+    pub size: usize,
+    pub irq: u32,
+    /// The platform device id, only used to name the device
+    pub id: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescError {
+    /// The description is not `<size>@<base>:<irq>[:<id>]`
+    Syntax,
+    InvalidNumber,
+    /// The size is too small for the registers
+    TooSmall,
+}
+
+impl fmt::Display for DescError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax => f.write_str("expected <size>@<base>:<irq>[:<id>]"),
+            Self::InvalidNumber => f.write_str("invalid number"),
+            Self::TooSmall => write!(f, "the registers need at least {:#x} bytes", REG_CONFIG),
+        }
+    }
+}
+
+impl core::error::Error for DescError {}
+
+/// Parses a decimal number, or a hexadecimal one with a `0x` prefix
+fn parse_number(s: &str) -> Result<u64, DescError> {
+    let result = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    result.map_err(|_| DescError::InvalidNumber)
+}
+
+/// Parses a size with an optional `K`, `M` or `G` suffix
+fn parse_size(s: &str) -> Result<u64, DescError> {
+    let (number, shift) = match s.as_bytes().last() {
+        Some(b'k' | b'K') => (&s[..s.len() - 1], 10),
+        Some(b'm' | b'M') => (&s[..s.len() - 1], 20),
+        Some(b'g' | b'G') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    parse_number(number)?
+        .checked_mul(1 << shift)
+        .ok_or(DescError::InvalidNumber)
+}
+
+impl MmioDeviceDesc {
+    pub fn parse(desc: &str) -> Result<Self, DescError> {
+        let (size, rest) = desc.split_once('@').ok_or(DescError::Syntax)?;
+        let mut fields = rest.split(':');
+        let base = fields.next().ok_or(DescError::Syntax)?;
+        let irq = fields.next().ok_or(DescError::Syntax)?;
+        let id = fields.next();
+        if fields.next().is_some() {
+            return Err(DescError::Syntax);
+        }
+
+        let size = parse_size(size)? as usize;
+        if size < REG_CONFIG {
+            return Err(DescError::TooSmall);
+        }
+        let base = usize::try_from(parse_number(base)?).map_err(|_| DescError::InvalidNumber)?;
+        let number = |s| u32::try_from(parse_number(s)?).map_err(|_| DescError::InvalidNumber);
+        Ok(Self {
+            base: PhysAddr::new(base),
+            size,
+            irq: number(irq)?,
+            id: id.map(number).transpose()?,
+        })
+    }
+}
+
+impl fmt::Display for MmioDeviceDesc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}@{:#x}:{}", self.size, self.base, self.irq)?;
+        if let Some(id) = self.id {
+            write!(f, ":{}", id)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum VirtioMmioError {
+    Mmio(MmioSpaceExhausted),
+    /// The registers don't start with the virtio magic value
+    BadMagic(u32),
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for VirtioMmioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mmio(err) => write!(f, "failed to map registers: {}", err),
+            Self::BadMagic(magic) => write!(f, "bad magic value {:#x}", magic),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+        }
+    }
+}
+
+impl core::error::Error for VirtioMmioError {}
+
+/// The register layout of a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// Version 1, from before virtio 1.0, with page frame numbers for the queues
+    Legacy,
+    /// Version 2, with separate addresses for the rings of each queue
+    Modern,
+}
+
+/// A probed virtio-mmio device
+#[derive(Debug)]
+pub struct VirtioMmio {
+    desc: MmioDeviceDesc,
+    version: Version,
+    device_type: DeviceType,
+    regs: MmioRegion,
+}
+
+impl VirtioMmio {
+    /// Maps the registers of a device and identifies it
+    ///
+    /// Returns `Ok(None)` if nothing is plugged into the slot, which is unmapped again.
+    ///
+    /// # Safety
+    /// The description must be of a virtio-mmio device.
+    pub unsafe fn probe(desc: MmioDeviceDesc) -> Result<Option<Self>, VirtioMmioError> {
+        let regs = unsafe { mmio::map(desc.base, desc.size) }.map_err(VirtioMmioError::Mmio)?;
+        let read = |reg: usize| unsafe { regs.read::<u32>(reg) };
+        let check = || {
+            let magic = read(REG_MAGIC);
+            if magic != MAGIC {
+                return Err(VirtioMmioError::BadMagic(magic));
+            }
+            let version = match read(REG_VERSION) {
+                1 => Version::Legacy,
+                2 => Version::Modern,
+                version => return Err(VirtioMmioError::UnsupportedVersion(version)),
+            };
+            Ok(DeviceType::from_id(read(REG_DEVICE_ID)).map(|device_type| (version, device_type)))
+        };
+        match check() {
+            Ok(Some((version, device_type))) => Ok(Some(Self {
+                desc,
+                version,
+                device_type,
+                regs,
+            })),
+            result => {
+                // SAFETY: Nothing uses the registers of a device that isn't there
+                unsafe { mmio::unmap(regs) };
+                result.map(|_| None)
+            }
+        }
+    }
+
+    pub fn desc(&self) -> &MmioDeviceDesc {
+        &self.desc
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    pub fn vendor_id(&self) -> u32 {
+        self.read(REG_VENDOR_ID)
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        debug_assert!(reg < self.desc.size);
+        unsafe { self.regs.read(reg) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        debug_assert!(reg < self.desc.size);
+        unsafe { self.regs.write(reg, value) }
+    }
+
+    fn write_u64(&self, reg: usize, value: u64) {
+        self.write(reg, value as u32);
+        self.write(reg + 4, (value >> 32) as u32);
+    }
+
+    pub fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_retain(self.read(REG_STATUS))
+    }
+
+    pub fn set_status(&self, status: DeviceStatus) {
+        self.write(REG_STATUS, status.bits());
+    }
+
+    pub fn device_features(&self) -> u64 {
+        self.write(REG_DEVICE_FEATURES_SEL, 0);
+        let low = self.read(REG_DEVICE_FEATURES);
+        self.write(REG_DEVICE_FEATURES_SEL, 1);
+        let high = self.read(REG_DEVICE_FEATURES);
+        ((high as u64) << 32) | low as u64
+    }
+}
+
+impl Transport for VirtioMmio {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn reset(&self) {
+        self.write(REG_STATUS, 0);
+        while self.read(REG_STATUS) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Negotiates the features, always including [`FEATURE_VERSION_1`] for modern devices
+    ///
+    /// Legacy devices only have 32 feature bits, and don't confirm the features.
+    fn negotiate(&self, supported: u64) -> Result<u64, VirtioError> {
+        self.reset();
+        self.set_status(DeviceStatus::ACKNOWLEDGE);
+        self.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+        let features = match self.version {
+            Version::Legacy => self.device_features() & supported & u32::MAX as u64,
+            Version::Modern => self.device_features() & (supported | FEATURE_VERSION_1),
+        };
+        self.write(REG_DRIVER_FEATURES_SEL, 0);
+        self.write(REG_DRIVER_FEATURES, features as u32);
+        self.write(REG_DRIVER_FEATURES_SEL, 1);
+        self.write(REG_DRIVER_FEATURES, (features >> 32) as u32);
+
+        if self.version == Version::Legacy {
+            self.write(REG_LEGACY_GUEST_PAGE_SIZE, QueueLayout::ALIGN as u32);
+            return Ok(features);
+        }
+        let status = DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK;
+        self.set_status(status);
+        if !self.status().contains(DeviceStatus::FEATURES_OK) {
+            self.set_status(status | DeviceStatus::FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(features)
+    }
+
+    fn queue_max_size(&self, queue: u16) -> u16 {
+        self.write(REG_QUEUE_SEL, queue as u32);
+        self.read(REG_QUEUE_NUM_MAX) as u16
+    }
+
+    fn setup_queue(&self, queue: u16, size: u16, rings: PhysAddr) -> Result<(), VirtioError> {
+        if !rings.as_usize().is_multiple_of(QueueLayout::ALIGN) {
+            return Err(VirtioError::MisalignedQueue(queue));
+        }
+        self.write(REG_QUEUE_SEL, queue as u32);
+        let max_size = self.read(REG_QUEUE_NUM_MAX);
+        let in_use = match self.version {
+            Version::Legacy => self.read(REG_LEGACY_QUEUE_PFN) != 0,
+            Version::Modern => self.read(REG_QUEUE_READY) != 0,
+        };
+        if max_size == 0 || size as u32 > max_size || in_use {
+            return Err(VirtioError::QueueUnavailable(queue));
+        }
+        self.write(REG_QUEUE_NUM, size as u32);
+
+        let layout = QueueLayout::new(size);
+        match self.version {
+            Version::Legacy => {
+                self.write(REG_LEGACY_QUEUE_ALIGN, QueueLayout::ALIGN as u32);
+                self.write(REG_LEGACY_QUEUE_PFN, (rings.as_usize() / QueueLayout::ALIGN) as u32);
+            }
+            Version::Modern => {
+                self.write_u64(REG_QUEUE_DESC, rings.as_u64());
+                self.write_u64(REG_QUEUE_DRIVER, (rings + layout.driver).as_u64());
+                self.write_u64(REG_QUEUE_DEVICE, (rings + layout.device).as_u64());
+                self.write(REG_QUEUE_READY, 1);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_driver_ok(&self) {
+        self.set_status(self.status() | DeviceStatus::DRIVER_OK);
+    }
+
+    fn notify(&self, queue: u16) {
+        self.write(REG_QUEUE_NOTIFY, queue as u32);
+    }
+
+    fn ack_interrupt(&self) -> u32 {
+        let status = self.read(REG_INTERRUPT_STATUS);
+        self.write(REG_INTERRUPT_ACK, status);
+        status
+    }
+
+    /// Reads the configuration, retrying until the device didn't change it while reading
+    ///
+    /// Legacy devices have no generation counter, so their configuration can tear.
+    fn read_config(&self, offset: usize, buf: &mut [u8]) {
+        let generation = || match self.version {
+            Version::Legacy => 0,
+            Version::Modern => self.read(REG_CONFIG_GENERATION),
+        };
+        loop {
+            let before = generation();
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = unsafe { self.regs.read(REG_CONFIG + offset + i) };
+            }
+            if generation() == before {
+                return;
+            }
+        }
+    }
+}
+
+static DEVICES: RwLock<Vec<Arc<VirtioMmio>>> = RwLock::new(Vec::new());
+
+/// Returns the probed devices
+pub fn devices() -> Vec<Arc<VirtioMmio>> {
+    DEVICES.read().clone()
+}
+
+/// Probes a device found by firmware, a device tree or the command line, and adds it to
+/// [`devices`]
+///
+/// # Safety
+/// The description must be of a virtio-mmio device.
+pub unsafe fn register(desc: MmioDeviceDesc) -> Result<Option<Arc<VirtioMmio>>, VirtioMmioError> {
+    let Some(dev) = (unsafe { VirtioMmio::probe(desc) })? else {
+        return Ok(None);
+    };
+    let dev = Arc::new(dev);
+    DEVICES.write().push(dev.clone());
+    Ok(Some(dev))
+}
+
+/// Probes the devices described on the command line
+pub fn init(cmdline: Cmdline) {
+    for arg in cmdline.get_all(CMDLINE_OPTION) {
+        let desc = match MmioDeviceDesc::parse(arg) {
+            Ok(desc) => desc,
+            Err(err) => {
+                kprintln!(Warn, "virtio-mmio: ignoring '{}': {}", arg, err);
+                continue;
+            }
+        };
+        // SAFETY: The command line says there is a virtio-mmio device
+        match unsafe { register(desc) } {
+            Ok(Some(dev)) => kprintln!(
+                Info,
+                "virtio-mmio: {} device at {:#x}, irq {}, {:?} registers",
+                dev.device_type(),
+                desc.base,
+                desc.irq,
+                dev.version()
+            ),
+            Ok(None) => kprintln!(Debug, "virtio-mmio: no device at {:#x}", desc.base),
+            Err(err) => kprintln!(Warn, "virtio-mmio: {}: {}", desc, err),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn virtio_mmio_desc() {
+        let desc = MmioDeviceDesc::parse("512@0xfeb00e00:12").unwrap();
+        assert_eq!(desc.base, PhysAddr::new(0xFEB0_0E00));
+        assert_eq!(desc.size, 512);
+        assert_eq!(desc.irq, 12);
+        assert_eq!(desc.id, None);
+        assert_eq!(desc.to_string(), "0x200@0xfeb00e00:12");
+
+        let desc = MmioDeviceDesc::parse("4K@0x10001000:0x21:3").unwrap();
+        assert_eq!((desc.size, desc.irq, desc.id), (4096, 33, Some(3)));
+
+        assert_eq!(MmioDeviceDesc::parse("0x1000"), Err(DescError::Syntax));
+        assert_eq!(MmioDeviceDesc::parse("4K@0x1000"), Err(DescError::Syntax));
+        assert_eq!(MmioDeviceDesc::parse("4K@0x1000:1:2:3"), Err(DescError::Syntax));
+        assert_eq!(MmioDeviceDesc::parse("4X@0x1000:1"), Err(DescError::InvalidNumber));
+        assert_eq!(MmioDeviceDesc::parse("4K@0x1000:-1"), Err(DescError::InvalidNumber));
+        assert_eq!(MmioDeviceDesc::parse("0x80@0x1000:1"), Err(DescError::TooSmall));
+    }
+}
@@ -0,0 +1,191 @@
+//! Virtio devices
+//!
+//! The definitions shared by every virtio transport, and the [`Transport`] trait that drivers
+//! use, so they work the same whichever way the device is attached. Devices are attached through
+//! [`pci`], or through [`mmio`], which is how virtio devices appear on machines without PCI, like
+//! QEMU's `microvm`, and on most non-x86 machines. Drivers talk to devices through [`queue`]s.
+
+use core::fmt;
+
+use crate::arch::PhysAddr;
+
+pub mod console;
+#[cfg(all(target_arch = "x86_64", kconfig = "drivers.virtio_gpu"))]
+pub mod gpu;
+pub mod mmio;
+pub mod net;
+#[cfg(target_arch = "x86_64")]
+pub mod pci;
+pub mod queue;
+
+/// The device feature bit set by devices following version 1.0 of the specification or later
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct DeviceStatus: u32 {
+        const ACKNOWLEDGE = 1 << 0;
+        const DRIVER = 1 << 1;
+        const DRIVER_OK = 1 << 2;
+        const FEATURES_OK = 1 << 3;
+        const DEVICE_NEEDS_RESET = 1 << 6;
+        const FAILED = 1 << 7;
+    }
+}
+
+/// The kind of a virtio device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    Net,
+    Block,
+    Console,
+    Entropy,
+    Balloon,
+    Scsi,
+    Gpu,
+    Input,
+    Vsock,
+    Unknown(u32),
+}
+
+impl DeviceType {
+    /// Returns the type of a device id, or `None` for the placeholder id 0
+    pub const fn from_id(id: u32) -> Option<Self> {
+        Some(match id {
+            0 => return None,
+            1 => Self::Net,
+            2 => Self::Block,
+            3 => Self::Console,
+            4 => Self::Entropy,
+            5 => Self::Balloon,
+            8 => Self::Scsi,
+            16 => Self::Gpu,
+            18 => Self::Input,
+            19 => Self::Vsock,
+            id => Self::Unknown(id),
+        })
+    }
+}
+
+impl fmt::Display for DeviceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Net => f.write_str("network"),
+            Self::Block => f.write_str("block"),
+            Self::Console => f.write_str("console"),
+            Self::Entropy => f.write_str("entropy"),
+            Self::Balloon => f.write_str("balloon"),
+            Self::Scsi => f.write_str("SCSI host"),
+            Self::Gpu => f.write_str("GPU"),
+            Self::Input => f.write_str("input"),
+            Self::Vsock => f.write_str("socket"),
+            Self::Unknown(id) => write!(f, "unknown ({})", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The device didn't accept the features of the driver
+    FeaturesRejected,
+    /// The queue doesn't exist, is already in use, or is smaller than requested
+    QueueUnavailable(u16),
+    /// The rings of a queue are not aligned to [`QueueLayout::ALIGN`]
+    MisalignedQueue(u16),
+    OutOfMemory,
+    /// The device didn't use a buffer in time, it isn't used anymore
+    Timeout,
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FeaturesRejected => f.write_str("the device rejected the features"),
+            Self::QueueUnavailable(queue) => write!(f, "queue {} is unavailable", queue),
+            Self::MisalignedQueue(queue) => write!(f, "the rings of queue {} are misaligned", queue),
+            Self::OutOfMemory => f.write_str("out of memory"),
+            Self::Timeout => f.write_str("the device timed out"),
+        }
+    }
+}
+
+impl core::error::Error for VirtioError {}
+
+/// The offsets of the rings of a split virtqueue in one contiguous allocation
+///
+/// This is the layout legacy devices require, modern devices accept it as well, so drivers
+/// always allocate their queues like this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLayout {
+    /// The offset of the driver (available) ring, the descriptor table is at the start
+    pub driver: usize,
+    /// The offset of the device (used) ring
+    pub device: usize,
+    /// The size of the allocation
+    pub size: usize,
+}
+
+impl QueueLayout {
+    /// The alignment of the allocation and of the device ring
+    pub const ALIGN: usize = 4096;
+
+    pub const fn new(queue_size: u16) -> Self {
+        let entries = queue_size as usize;
+        let driver = 16 * entries;
+        // flags, idx, the ring, and the used event
+        let device = (driver + 6 + 2 * entries).next_multiple_of(Self::ALIGN);
+        // flags, idx, the ring of id and length pairs, and the available event
+        let size = device + 6 + 8 * entries;
+        Self { driver, device, size }
+    }
+}
+
+/// The way a virtio device is attached, which drivers use to set it up
+pub trait Transport: Send + Sync {
+    fn device_type(&self) -> DeviceType;
+
+    /// Resets the device, which forgets the features and queues of the previous driver
+    fn reset(&self);
+
+    /// Resets the device and negotiates the features the driver supports, returning them
+    fn negotiate(&self, supported: u64) -> Result<u64, VirtioError>;
+
+    /// Returns the maximum size of a queue, or 0 if the queue doesn't exist
+    fn queue_max_size(&self, queue: u16) -> u16;
+
+    /// Hands the rings of a queue to the device, and enables it
+    ///
+    /// The rings are one allocation with the [`QueueLayout`] of `size`.
+    fn setup_queue(&self, queue: u16, size: u16, rings: PhysAddr) -> Result<(), VirtioError>;
+
+    /// Tells the device the driver is ready, after its queues are set up
+    fn set_driver_ok(&self);
+
+    /// Tells the device there are new buffers in a queue
+    fn notify(&self, queue: u16);
+
+    /// Returns and acknowledges the pending interrupts
+    fn ack_interrupt(&self) -> u32;
+
+    /// Reads the device specific configuration
+    fn read_config(&self, offset: usize, buf: &mut [u8]);
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn virtio_queue_layout() {
+        assert_eq!(
+            QueueLayout::new(256),
+            QueueLayout {
+                driver: 4096,
+                device: 8192,
+                size: 8192 + 6 + 8 * 256,
+            }
+        );
+        let layout = QueueLayout::new(8);
+        assert_eq!((layout.driver, layout.device), (128, 4096));
+    }
+}
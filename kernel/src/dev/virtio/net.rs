@@ -0,0 +1,283 @@
+//! The virtio-net driver
+//!
+//! Every frame is preceded by a [`VirtioNetHdr`] in the same buffer, which carries the checksum
+//! offload state, so the driver negotiates `VIRTIO_F_ANY_LAYOUT` for legacy devices, which
+//! otherwise want the header in a separate descriptor. Checksum offload is used in both
+//! directions when the device offers it, segmentation offload isn't, as the buffers are only large
+//! enough for a single frame. The driver is polled by [`net::poll`], it doesn't use the interrupt,
+//! and signals the TX completion of a packet when it takes back its buffer.
+//!
+//! In QEMU that is `-device virtio-net-device,netdev=net0 -netdev user,id=net0` on `microvm`.
+
+use core::ptr;
+
+use alloc::{format, sync::Arc, vec::Vec};
+
+use crate::{
+    arch::{PhysAddr, VirtAddr},
+    dev::virtio::{DeviceType, FEATURE_VERSION_1, Transport, VirtioError, mmio, queue::VirtQueue},
+    kprintln,
+    mm::{
+        FRAME_ALLOCATOR,
+        page_table::KernelPageTable,
+        paging::{PageSize, Size4KiB},
+    },
+    net::{
+        self, InterfaceFlags, MacAddr, NetDevice, NetError,
+        buf::{PacketBuf, TxCompletion},
+        offload::{
+            Offloads,
+            virtio::{self as offload, VirtioNetHdr},
+        },
+    },
+    sync::Mutex,
+};
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+const F_MAC: u64 = 1 << 5;
+const F_ANY_LAYOUT: u64 = 1 << 27;
+
+/// Each buffer holds the header and a whole frame
+const BUFFER_SIZE: usize = 2048;
+const RX_BUFFERS: usize = 16;
+const TX_BUFFERS: usize = 16;
+const BUFFER_FRAMES: usize = (RX_BUFFERS + TX_BUFFERS) * BUFFER_SIZE / Size4KiB::SIZE;
+
+/// The MAC address used if the device doesn't have one, locally administered
+const FALLBACK_MAC: MacAddr = MacAddr([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+
+/// Returns the size of the header, which legacy devices send without `num_buffers`
+fn header_len(features: u64) -> usize {
+    match features & FEATURE_VERSION_1 {
+        0 => size_of::<VirtioNetHdr>() - 2,
+        _ => size_of::<VirtioNetHdr>(),
+    }
+}
+
+/// Splits a received buffer into the frame and its checksum state
+fn parse_rx(buf: &[u8], header_len: usize) -> Option<PacketBuf> {
+    let frame = buf.get(header_len..).filter(|frame| !frame.is_empty())?;
+    // Only the flags matter on receive, the other fields are about segmentation
+    let hdr = VirtioNetHdr {
+        flags: buf[0],
+        ..Default::default()
+    };
+    let mut packet = PacketBuf::new(frame.to_vec());
+    packet.csum = hdr.rx_csum();
+    Some(packet)
+}
+
+struct Queues {
+    rx: VirtQueue,
+    tx: VirtQueue,
+    /// The buffer of each descriptor of the queues
+    rx_slots: Vec<Option<usize>>,
+    /// Along with the completion of the packet in it
+    tx_slots: Vec<Option<(usize, Option<Arc<TxCompletion>>)>>,
+    /// The transmit buffers the device isn't using
+    tx_free: Vec<usize>,
+}
+
+impl Queues {
+    /// Takes back the transmit buffers the device is done with, completing their packets
+    fn reclaim_tx(&mut self) {
+        while let Some((id, _)) = self.tx.pop_used() {
+            if let Some((buffer, done)) = self.tx_slots[id as usize].take() {
+                self.tx_free.push(buffer);
+                if let Some(done) = done {
+                    done.complete(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+pub struct VirtioNet {
+    transport: Arc<dyn Transport>,
+    mac: MacAddr,
+    offloads: Offloads,
+    header_len: usize,
+    /// The receive buffers, followed by the transmit buffers
+    buffers: PhysAddr,
+    queues: Mutex<Queues>,
+}
+
+impl VirtioNet {
+    pub fn new(transport: Arc<dyn Transport>) -> Result<Self, VirtioError> {
+        let features = transport.negotiate(F_MAC | F_ANY_LAYOUT | offload::F_CSUM | offload::F_GUEST_CSUM)?;
+        let mut mac = FALLBACK_MAC;
+        if features & F_MAC != 0 {
+            transport.read_config(0, &mut mac.0);
+        }
+        let rx = VirtQueue::new(&*transport, RECEIVE_QUEUE, RX_BUFFERS as u16)?;
+        let tx = VirtQueue::new(&*transport, TRANSMIT_QUEUE, TX_BUFFERS as u16)?;
+        let buffers = FRAME_ALLOCATOR
+            .lock()
+            .allocate_contiguous(BUFFER_FRAMES)
+            .ok_or(VirtioError::OutOfMemory)?
+            .start_address();
+        let tx_count = TX_BUFFERS.min(tx.size() as usize);
+        let net = Self {
+            transport,
+            mac,
+            offloads: Offloads::from_virtio_net_features(features),
+            header_len: header_len(features),
+            buffers,
+            queues: Mutex::new(Queues {
+                rx_slots: alloc::vec![None; rx.size() as usize],
+                tx_slots: alloc::vec![None; tx.size() as usize],
+                tx_free: (RX_BUFFERS..RX_BUFFERS + tx_count).collect(),
+                rx,
+                tx,
+            }),
+        };
+        {
+            let mut queues = net.queues.lock();
+            for buffer in 0..RX_BUFFERS.min(queues.rx.size() as usize) {
+                net.offer_rx(&mut queues, buffer);
+            }
+        }
+        net.transport.set_driver_ok();
+        net.transport.notify(RECEIVE_QUEUE);
+        Ok(net)
+    }
+
+    fn buffer(&self, buffer: usize) -> (PhysAddr, VirtAddr) {
+        let phys = self.buffers + buffer * BUFFER_SIZE;
+        (phys, KernelPageTable::direct_map_start() + phys.as_usize())
+    }
+
+    fn offer_rx(&self, queues: &mut Queues, buffer: usize) {
+        let (phys, _) = self.buffer(buffer);
+        // SAFETY: The buffers live as long as the device
+        if let Some(id) = unsafe { queues.rx.push(phys, BUFFER_SIZE as u32, true) } {
+            queues.rx_slots[id as usize] = Some(buffer);
+        }
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn offloads(&self) -> Offloads {
+        // The buffers only fit one frame
+        self.offloads - Offloads::TSO4
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        self.transmit_buf(&PacketBuf::new(frame.to_vec()))
+    }
+
+    fn transmit_buf(&self, buf: &PacketBuf) -> Result<(), NetError> {
+        let len = self.header_len + buf.len();
+        if len > BUFFER_SIZE || buf.gso.is_some() {
+            return Err(NetError::TooLarge);
+        }
+        let mut queues = self.queues.lock();
+        queues.reclaim_tx();
+        let buffer = queues.tx_free.pop().ok_or(NetError::Busy)?;
+        let (phys, virt) = self.buffer(buffer);
+        let hdr = VirtioNetHdr::for_tx(buf);
+        // SAFETY: The device gave the buffer back, and keeps it until it is reclaimed
+        unsafe {
+            let dst = virt.as_mut_ptr::<u8>();
+            ptr::copy_nonoverlapping((&raw const hdr).cast::<u8>(), dst, self.header_len);
+            ptr::copy_nonoverlapping(buf.data.as_ptr(), dst.add(self.header_len), buf.len());
+            match queues.tx.push(phys, len as u32, false) {
+                Some(id) => queues.tx_slots[id as usize] = Some((buffer, buf.tx_done.clone())),
+                None => {
+                    queues.tx_free.push(buffer);
+                    return Err(NetError::Busy);
+                }
+            }
+        }
+        self.transport.notify(TRANSMIT_QUEUE);
+        Ok(())
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> Option<usize> {
+        let packet = self.receive_buf()?;
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet.data[..len]);
+        Some(len)
+    }
+
+    fn receive_buf(&self) -> Option<PacketBuf> {
+        self.transport.ack_interrupt();
+        let mut queues = self.queues.lock();
+        // Polled anyway, so packets don't wait for the next one to be sent to complete
+        queues.reclaim_tx();
+        loop {
+            let (id, len) = queues.rx.pop_used()?;
+            let Some(buffer) = queues.rx_slots[id as usize].take() else {
+                continue;
+            };
+            let (_, virt) = self.buffer(buffer);
+            let len = (len as usize).min(BUFFER_SIZE);
+            // SAFETY: The device is done with the buffer
+            let packet = parse_rx(
+                unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), len) },
+                self.header_len,
+            );
+            self.offer_rx(&mut queues, buffer);
+            self.transport.notify(RECEIVE_QUEUE);
+            if packet.is_some() {
+                return packet;
+            }
+        }
+    }
+}
+
+/// Sets up the network devices found by the transports, as `eth0`, `eth1` and so on
+pub fn init() {
+    let mut count = 0;
+    for dev in mmio::devices() {
+        if dev.device_type() != DeviceType::Net {
+            continue;
+        }
+        let base = dev.desc().base;
+        match VirtioNet::new(dev) {
+            Ok(nic) => {
+                let name = format!("eth{}", count).leak();
+                count += 1;
+                kprintln!(
+                    Info,
+                    "virtio-net: {} at {:#x}, MAC {}, offloads {}",
+                    name,
+                    base,
+                    nic.mac(),
+                    nic.offloads()
+                );
+                net::register(name, Arc::new(nic), InterfaceFlags::BROADCAST);
+            }
+            Err(err) => kprintln!(Warn, "virtio-net: {:#x}: {}", base, err),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+    use crate::net::buf::ChecksumState;
+
+    #[test]
+    fn virtio_net_rx() {
+        assert_eq!(header_len(0), 10);
+        assert_eq!(header_len(FEATURE_VERSION_1), 12);
+
+        let mut buf = [0u8; 12 + 60];
+        buf[0] = offload::HDR_F_DATA_VALID;
+        buf[12] = 0xFF;
+        let packet = parse_rx(&buf, 12).unwrap();
+        assert_eq!((packet.len(), packet.data[0]), (60, 0xFF));
+        assert_eq!(packet.csum, ChecksumState::Unnecessary);
+
+        buf[0] = 0;
+        assert_eq!(parse_rx(&buf, 10).unwrap().csum, ChecksumState::None);
+        assert!(parse_rx(&buf[..10], 10).is_none());
+    }
+}
@@ -0,0 +1,307 @@
+//! The virtio-pci transport
+//!
+//! A modern virtio-pci device describes where its registers are with vendor specific capabilities
+//! in configuration space, each naming a BAR and a range in it: the common configuration, the
+//! queue notification doorbells, the interrupt status, and the device specific configuration.
+//! Only those ranges are mapped. Transitional devices have the same capabilities next to their
+//! legacy I/O ports, which aren't used.
+
+use core::fmt;
+
+use crate::{
+    arch::PhysAddr,
+    dev::{
+        pci::{Bar, PciCommand, PciDevice},
+        virtio::{DeviceStatus, DeviceType, FEATURE_VERSION_1, QueueLayout, Transport, VirtioError},
+    },
+    mm::mmio::{self, MmioRegion, MmioSpaceExhausted},
+};
+
+pub const VENDOR_VIRTIO: u16 = 0x1AF4;
+/// Modern devices have the device type added to this id
+pub const DEVICE_MODERN_BASE: u16 = 0x1040;
+/// Transitional devices have ids from here on, with the device type in the subsystem id
+pub const DEVICE_TRANSITIONAL_BASE: u16 = 0x1000;
+
+const CAP_VENDOR: u8 = 0x09;
+const CAP_TYPE: u8 = 3;
+const CAP_BAR: u8 = 4;
+const CAP_OFFSET: u8 = 8;
+const CAP_LENGTH: u8 = 12;
+/// Only in the notification capability
+const CAP_NOTIFY_MULTIPLIER: u8 = 16;
+
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_ISR: u8 = 3;
+const CFG_DEVICE: u8 = 4;
+
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_CONFIG_GENERATION: usize = 0x15;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+const COMMON_SIZE: usize = 0x38;
+
+/// Returns the type of a virtio-pci device from its ids, or `None` if it isn't one
+pub fn device_type(dev: &PciDevice) -> Option<DeviceType> {
+    if dev.vendor_id != VENDOR_VIRTIO {
+        return None;
+    }
+    let id = match dev.device_id {
+        id @ DEVICE_MODERN_BASE..=0x107F => (id - DEVICE_MODERN_BASE) as u32,
+        DEVICE_TRANSITIONAL_BASE..=0x103F => dev.subsystem_id as u32,
+        _ => return None,
+    };
+    DeviceType::from_id(id)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum VirtioPciError {
+    /// Not a virtio device, or one without the capabilities of modern devices
+    NotModern,
+    /// A capability points at a missing or I/O BAR, or past the end of its BAR
+    BadCapability(u8),
+    Mmio(MmioSpaceExhausted),
+}
+
+impl fmt::Display for VirtioPciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotModern => f.write_str("not a modern virtio device"),
+            Self::BadCapability(offset) => write!(f, "bad capability at {:#x}", offset),
+            Self::Mmio(err) => write!(f, "failed to map registers: {}", err),
+        }
+    }
+}
+
+impl core::error::Error for VirtioPciError {}
+
+/// A modern virtio-pci device
+#[derive(Debug)]
+pub struct VirtioPci {
+    dev: PciDevice,
+    device_type: DeviceType,
+    common: MmioRegion,
+    notify: MmioRegion,
+    notify_multiplier: u32,
+    isr: MmioRegion,
+    /// Devices without configuration have none
+    config: Option<MmioRegion>,
+}
+
+impl VirtioPci {
+    /// Finds and maps the registers of a device
+    pub fn new(dev: &PciDevice) -> Result<Self, VirtioPciError> {
+        let device_type = device_type(dev).ok_or(VirtioPciError::NotModern)?;
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut config = None;
+        for (id, cap) in dev.capabilities() {
+            if id != CAP_VENDOR {
+                continue;
+            }
+            let slot = match dev.addr.read_u8(cap + CAP_TYPE) {
+                CFG_COMMON => &mut common,
+                CFG_NOTIFY => &mut notify,
+                CFG_ISR => &mut isr,
+                CFG_DEVICE => &mut config,
+                _ => continue,
+            };
+            // Devices may offer several of a kind, the first is the preferred one
+            if slot.is_none() {
+                *slot = Some(cap);
+            }
+        }
+        let (Some(common), Some(notify), Some(isr)) = (common, notify, isr) else {
+            return Err(VirtioPciError::NotModern);
+        };
+        dev.enable(PciCommand::MEMORY_SPACE | PciCommand::BUS_MASTER);
+        let common = map_capability(dev, common)?;
+        if common.size() < COMMON_SIZE {
+            return Err(VirtioPciError::BadCapability(COMMON_SIZE as u8));
+        }
+        Ok(Self {
+            dev: *dev,
+            device_type,
+            notify_multiplier: dev.addr.read_u32(notify + CAP_NOTIFY_MULTIPLIER),
+            notify: map_capability(dev, notify)?,
+            isr: map_capability(dev, isr)?,
+            config: config.map(|cap| map_capability(dev, cap)).transpose()?,
+            common,
+        })
+    }
+
+    pub fn pci(&self) -> &PciDevice {
+        &self.dev
+    }
+
+    fn read<T: Copy>(&self, reg: usize) -> T {
+        unsafe { self.common.read(reg) }
+    }
+
+    fn write<T: Copy>(&self, reg: usize, value: T) {
+        unsafe { self.common.write(reg, value) }
+    }
+
+    fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_retain(self.read::<u8>(COMMON_DEVICE_STATUS) as u32)
+    }
+
+    fn set_status(&self, status: DeviceStatus) {
+        self.write(COMMON_DEVICE_STATUS, status.bits() as u8);
+    }
+
+    fn device_features(&self) -> u64 {
+        self.write(COMMON_DEVICE_FEATURE_SELECT, 0u32);
+        let low = self.read::<u32>(COMMON_DEVICE_FEATURE);
+        self.write(COMMON_DEVICE_FEATURE_SELECT, 1u32);
+        let high = self.read::<u32>(COMMON_DEVICE_FEATURE);
+        ((high as u64) << 32) | low as u64
+    }
+}
+
+/// Maps the range of a BAR a capability points at
+fn map_capability(dev: &PciDevice, cap: u8) -> Result<MmioRegion, VirtioPciError> {
+    let bar = dev.addr.read_u8(cap + CAP_BAR) as usize;
+    let offset = dev.addr.read_u32(cap + CAP_OFFSET) as usize;
+    let length = dev.addr.read_u32(cap + CAP_LENGTH) as usize;
+    let Some(Bar::Memory { addr, size, .. }) = dev.bar(bar) else {
+        return Err(VirtioPciError::BadCapability(cap));
+    };
+    if length == 0 || offset.checked_add(length).is_none_or(|end| end > size) {
+        return Err(VirtioPciError::BadCapability(cap));
+    }
+    // SAFETY: The range is inside a memory BAR of the device
+    unsafe { mmio::map(addr + offset, length) }.map_err(VirtioPciError::Mmio)
+}
+
+impl Transport for VirtioPci {
+    fn device_type(&self) -> DeviceType {
+        self.device_type
+    }
+
+    fn reset(&self) {
+        self.set_status(DeviceStatus::empty());
+        while !self.status().is_empty() {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Negotiates the features, always including [`FEATURE_VERSION_1`]
+    fn negotiate(&self, supported: u64) -> Result<u64, VirtioError> {
+        self.reset();
+        self.set_status(DeviceStatus::ACKNOWLEDGE);
+        self.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+        let features = self.device_features() & (supported | FEATURE_VERSION_1);
+        self.write(COMMON_DRIVER_FEATURE_SELECT, 0u32);
+        self.write(COMMON_DRIVER_FEATURE, features as u32);
+        self.write(COMMON_DRIVER_FEATURE_SELECT, 1u32);
+        self.write(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+
+        let status = DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK;
+        self.set_status(status);
+        if !self.status().contains(DeviceStatus::FEATURES_OK) {
+            self.set_status(status | DeviceStatus::FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(features)
+    }
+
+    fn queue_max_size(&self, queue: u16) -> u16 {
+        self.write(COMMON_QUEUE_SELECT, queue);
+        self.read(COMMON_QUEUE_SIZE)
+    }
+
+    fn setup_queue(&self, queue: u16, size: u16, rings: PhysAddr) -> Result<(), VirtioError> {
+        if !rings.as_usize().is_multiple_of(QueueLayout::ALIGN) {
+            return Err(VirtioError::MisalignedQueue(queue));
+        }
+        self.write(COMMON_QUEUE_SELECT, queue);
+        let max_size = self.read::<u16>(COMMON_QUEUE_SIZE);
+        if max_size == 0 || size > max_size || self.read::<u16>(COMMON_QUEUE_ENABLE) != 0 {
+            return Err(VirtioError::QueueUnavailable(queue));
+        }
+        self.write(COMMON_QUEUE_SIZE, size);
+        let layout = QueueLayout::new(size);
+        self.write(COMMON_QUEUE_DESC, rings.as_u64());
+        self.write(COMMON_QUEUE_DRIVER, (rings + layout.driver).as_u64());
+        self.write(COMMON_QUEUE_DEVICE, (rings + layout.device).as_u64());
+        self.write(COMMON_QUEUE_ENABLE, 1u16);
+        Ok(())
+    }
+
+    fn set_driver_ok(&self) {
+        self.set_status(self.status() | DeviceStatus::DRIVER_OK);
+    }
+
+    fn notify(&self, queue: u16) {
+        self.write(COMMON_QUEUE_SELECT, queue);
+        let offset = self.read::<u16>(COMMON_QUEUE_NOTIFY_OFF) as usize * self.notify_multiplier as usize;
+        if offset + 2 <= self.notify.size() {
+            unsafe { self.notify.write(offset, queue) };
+        }
+    }
+
+    /// Returns the interrupt status, which reading acknowledges
+    fn ack_interrupt(&self) -> u32 {
+        unsafe { self.isr.read::<u8>(0) as u32 }
+    }
+
+    /// Reads the configuration, retrying until the device didn't change it while reading
+    fn read_config(&self, offset: usize, buf: &mut [u8]) {
+        let Some(config) = &self.config else {
+            buf.fill(0);
+            return;
+        };
+        loop {
+            let before = self.read::<u8>(COMMON_CONFIG_GENERATION);
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = match offset + i < config.size() {
+                    true => unsafe { config.read(offset + i) },
+                    false => 0,
+                };
+            }
+            if self.read::<u8>(COMMON_CONFIG_GENERATION) == before {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+    use crate::dev::pci::PciAddress;
+
+    #[test]
+    fn virtio_pci_device_type() {
+        let dev = |vendor_id, device_id, subsystem_id| PciDevice {
+            addr: PciAddress::new(0, 3, 0),
+            vendor_id,
+            device_id,
+            subsystem_vendor_id: VENDOR_VIRTIO,
+            subsystem_id,
+            class: 0,
+            subclass: 0,
+            prog_if: 0,
+            revision: 1,
+            header_type: 0,
+            interrupt_line: 0,
+        };
+        assert_eq!(device_type(&dev(VENDOR_VIRTIO, 0x1050, 0)), Some(DeviceType::Gpu));
+        assert_eq!(device_type(&dev(VENDOR_VIRTIO, 0x1000, 1)), Some(DeviceType::Net));
+        assert_eq!(device_type(&dev(VENDOR_VIRTIO, 0x1040, 0)), None);
+        assert_eq!(device_type(&dev(VENDOR_VIRTIO, 0x1100, 0)), None);
+        assert_eq!(device_type(&dev(0x8086, 0x1050, 0)), None);
+    }
+}
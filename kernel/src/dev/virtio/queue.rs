@@ -0,0 +1,183 @@
+//! Split virtqueues
+//!
+//! A queue is a descriptor table, a driver ring where the driver offers descriptors, and a device
+//! ring where the device hands them back, in one allocation with the [`QueueLayout`]. A buffer is
+//! a single descriptor, or a chain of them for requests with a separate buffer for the reply.
+//! Devices are never removed, so neither are their queues.
+
+use core::{
+    ptr,
+    sync::atomic::{Ordering, fence},
+};
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{PhysAddr, VirtAddr},
+    dev::virtio::{QueueLayout, Transport, VirtioError},
+    mm::{
+        FRAME_ALLOCATOR,
+        page_table::KernelPageTable,
+        paging::{PageSize, Size4KiB},
+    },
+};
+
+/// The descriptor continues in `next`
+const DESC_F_NEXT: u16 = 1 << 0;
+/// The buffer is written by the device
+const DESC_F_WRITE: u16 = 1 << 1;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    rings: VirtAddr,
+    layout: QueueLayout,
+    free: Vec<u16>,
+    /// The next index of the driver ring
+    next_avail: u16,
+    /// The index of the device ring up to which buffers have been returned
+    last_used: u16,
+}
+
+impl VirtQueue {
+    /// Allocates a queue of at most `size` entries, and hands it to the device
+    pub fn new(transport: &dyn Transport, index: u16, size: u16) -> Result<Self, VirtioError> {
+        let size = transport.queue_max_size(index).min(size);
+        if size == 0 {
+            return Err(VirtioError::QueueUnavailable(index));
+        }
+        let layout = QueueLayout::new(size);
+        let frame = FRAME_ALLOCATOR
+            .lock()
+            .allocate_contiguous(layout.size.div_ceil(Size4KiB::SIZE))
+            .ok_or(VirtioError::OutOfMemory)?;
+        let phys = frame.start_address();
+        let rings = KernelPageTable::direct_map_start() + phys.as_usize();
+        // SAFETY: The frames were just allocated, and are mapped by the direct map
+        unsafe { ptr::write_bytes(rings.as_mut_ptr::<u8>(), 0, layout.size) };
+        transport.setup_queue(index, size, phys)?;
+        Ok(Self {
+            index,
+            size,
+            rings,
+            layout,
+            free: (0..size).rev().collect(),
+            next_avail: 0,
+            last_used: 0,
+        })
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn descriptor(&self, id: u16) -> *mut Descriptor {
+        unsafe { self.rings.as_mut_ptr::<Descriptor>().add(id as usize) }
+    }
+
+    /// Returns a pointer to the `idx` field of a ring, which is followed by the entries
+    fn ring_idx(&self, offset: usize) -> *mut u16 {
+        (self.rings + (offset + 2)).as_mut_ptr()
+    }
+
+    /// Offers a buffer to the device, returning its descriptor id
+    ///
+    /// The device only sees the buffer after the queue is notified. Returns `None` if every
+    /// descriptor is in use.
+    ///
+    /// # Safety
+    /// The buffer must stay valid until the device returns it from [`Self::pop_used`].
+    pub unsafe fn push(&mut self, addr: PhysAddr, len: u32, device_writable: bool) -> Option<u16> {
+        unsafe { self.push_chain(&[(addr, len, device_writable)]) }
+    }
+
+    /// Offers a chain of buffers to the device as one, given as their address, length and whether
+    /// the device writes them, returning the descriptor id of the first
+    ///
+    /// The buffers the device reads must come before the ones it writes. Returns `None` if there
+    /// aren't enough free descriptors.
+    ///
+    /// # Safety
+    /// The buffers must stay valid until the device returns the chain from [`Self::pop_used`].
+    pub unsafe fn push_chain(&mut self, buffers: &[(PhysAddr, u32, bool)]) -> Option<u16> {
+        if buffers.is_empty() || self.free.len() < buffers.len() {
+            return None;
+        }
+        let ids = self.free.split_off(self.free.len() - buffers.len());
+        let id = ids[buffers.len() - 1];
+        unsafe {
+            for (i, &(addr, len, device_writable)) in buffers.iter().enumerate() {
+                let mut flags = if device_writable { DESC_F_WRITE } else { 0 };
+                // The ids were popped from the end of the free list, so the chain runs backwards
+                let this = ids[buffers.len() - 1 - i];
+                let next = match i + 1 < buffers.len() {
+                    true => {
+                        flags |= DESC_F_NEXT;
+                        ids[buffers.len() - 2 - i]
+                    }
+                    false => 0,
+                };
+                self.descriptor(this).write_volatile(Descriptor {
+                    addr: addr.as_u64(),
+                    len,
+                    flags,
+                    next,
+                });
+            }
+            let idx = self.ring_idx(self.layout.driver);
+            let slot = self.next_avail % self.size;
+            idx.add(1 + slot as usize).write_volatile(id);
+            // The device must see the entry before the index that publishes it
+            fence(Ordering::Release);
+            self.next_avail = self.next_avail.wrapping_add(1);
+            idx.write_volatile(self.next_avail);
+        }
+        Some(id)
+    }
+
+    /// Returns the next buffer the device is done with, as its descriptor id and the bytes written
+    ///
+    /// The descriptors of a chain are all freed, the id is that of the first.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let idx = self.ring_idx(self.layout.device);
+        if unsafe { idx.read_volatile() } == self.last_used {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        let slot = self.last_used % self.size;
+        let elem = unsafe { idx.add(1).cast::<UsedElem>().add(slot as usize).read_volatile() };
+        self.last_used = self.last_used.wrapping_add(1);
+        let id = elem.id as u16;
+        let mut next = id;
+        loop {
+            self.free.push(next);
+            let desc = unsafe { self.descriptor(next).read_volatile() };
+            if desc.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            next = desc.next;
+        }
+        Some((id, elem.len))
+    }
+}
+
+// SAFETY: The rings are only accessed through `&mut self`
+unsafe impl Send for VirtQueue {}
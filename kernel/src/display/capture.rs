@@ -0,0 +1,141 @@
+//! Screen capture of the framebuffer console
+//!
+//! The framebuffer is converted to a binary PPM (`P6`) image and sent over the serial console as
+//! base64, between marker lines, so it can be cut out of a serial log and attached to a bug report:
+//!
+//! ```text
+//! -----BEGIN HADRON SCREENSHOT 1280x800 PPM-----
+//! UDYKMTI4MCA4MDAKMjU1Cg...
+//! -----END HADRON SCREENSHOT-----
+//! ```
+//!
+//! On the host, decode it with
+//! `sed -n '/BEGIN HADRON SCREENSHOT/,/END HADRON SCREENSHOT/{//!p}' serial.log | base64 -d > screen.ppm`.
+
+use core::fmt::{self, Write};
+
+use alloc::{format, vec::Vec};
+
+use crate::{
+    dev::{
+        DEVICES,
+        drivers::platform::{
+            fb::{FramebufferInfo, FramebufferWriter, PixelFormat},
+            serial,
+        },
+    },
+    util::base64,
+};
+
+const BEGIN_MARKER: &str = "-----BEGIN HADRON SCREENSHOT";
+const END_MARKER: &str = "-----END HADRON SCREENSHOT-----";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureError {
+    NoFramebuffer,
+    /// The framebuffer has fewer than 3 bytes per pixel
+    UnsupportedFormat,
+    NoSerial,
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NoFramebuffer => "no framebuffer console",
+            Self::UnsupportedFormat => "unsupported pixel format",
+            Self::NoSerial => "no serial console",
+        })
+    }
+}
+
+impl core::error::Error for CaptureError {}
+
+/// A captured image, in PPM format
+#[derive(Debug)]
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+    pub ppm: Vec<u8>,
+}
+
+/// Converts framebuffer contents to a PPM image
+///
+/// Pixels are stored with blue in the lowest byte, as written by the framebuffer console.
+fn encode_ppm(info: &FramebufferInfo, pixels: &[u8]) -> Result<Vec<u8>, CaptureError> {
+    let bpp = info.bpp as usize;
+    match info.pixel_format {
+        PixelFormat::RGB if bpp >= 3 => {}
+        _ => return Err(CaptureError::UnsupportedFormat),
+    }
+    let header = format!("P6\n{} {}\n255\n", info.width, info.height);
+    let mut ppm = Vec::with_capacity(header.len() + info.width as usize * info.height as usize * 3);
+    ppm.extend_from_slice(header.as_bytes());
+    for row in pixels.chunks(info.stride as usize).take(info.height as usize) {
+        for pixel in row.chunks(bpp).take(info.width as usize) {
+            ppm.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+        }
+    }
+    Ok(ppm)
+}
+
+/// Captures the framebuffer console
+pub fn capture() -> Result<Screenshot, CaptureError> {
+    let mut platform_devs = DEVICES.platform();
+    let dev = platform_devs
+        .iter()
+        .find(|dev| dev.name == "efi_fb")
+        .ok_or(CaptureError::NoFramebuffer)?;
+    let drv = dev.dev.drv.as_ref().ok_or(CaptureError::NoFramebuffer)?;
+    // Holding the driver data keeps the console from drawing while the buffer is copied
+    let data = drv.data.lock();
+    let writer = unsafe { data.cast::<FramebufferWriter>().as_ref() };
+    let fb = writer.framebuffer();
+    // The shadow buffer holds the same pixels, and is much faster to read than video memory
+    let pixels = fb.shadow.clone();
+    drop(data);
+
+    Ok(Screenshot {
+        width: fb.info.width,
+        height: fb.info.height,
+        ppm: encode_ppm(&fb.info, &pixels)?,
+    })
+}
+
+/// Sends a screenshot over the serial console as base64
+pub fn dump_serial(shot: &Screenshot) -> Result<(), CaptureError> {
+    let mut serial = serial::console().ok_or(CaptureError::NoSerial)?;
+    // Writes to the serial port can't fail
+    _ = writeln!(serial, "{} {}x{} PPM-----", BEGIN_MARKER, shot.width, shot.height);
+    base64::encode_lines(&shot.ppm, |line| _ = writeln!(serial, "{}", line));
+    _ = writeln!(serial, "{}", END_MARKER);
+    Ok(())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_ppm_encoding() {
+        let info = FramebufferInfo {
+            width: 2,
+            height: 2,
+            pixel_format: PixelFormat::RGB,
+            stride: 12,
+            bpp: 4,
+        };
+        // Rows are padded to the stride, the padding isn't part of the image
+        #[rustfmt::skip]
+        let pixels = [
+            0x00, 0x00, 0xFF, 0, 0x00, 0xFF, 0x00, 0, 0xEE, 0xEE, 0xEE, 0xEE,
+            0xFF, 0x00, 0x00, 0, 0x10, 0x20, 0x30, 0, 0xEE, 0xEE, 0xEE, 0xEE,
+        ];
+        let ppm = encode_ppm(&info, &pixels).unwrap();
+        let (header, data) = ppm.split_at(11);
+        assert_eq!(header, b"P6\n2 2\n255\n");
+        assert_eq!(data, [0xFF, 0, 0, 0, 0xFF, 0, 0, 0, 0xFF, 0x30, 0x20, 0x10]);
+
+        let info = FramebufferInfo { bpp: 2, ..info };
+        assert_eq!(encode_ppm(&info, &pixels).unwrap_err(), CaptureError::UnsupportedFormat);
+    }
+}
@@ -0,0 +1,280 @@
+//! EDID parsing
+//!
+//! Displays describe themselves with a 128 byte EDID block: who made them, the modes they support
+//! and, in the first detailed timing descriptor, the mode they prefer, usually their native
+//! resolution. GPU drivers read it from the display, and the bootloader passes on the one the
+//! firmware read. Only the base block is parsed, extension blocks are ignored.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use super::DisplayTimings;
+
+/// The size of an EDID block
+pub const BLOCK_SIZE: usize = 128;
+
+const HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+/// The established timings, one bit each, most significant first, starting at byte 35
+const ESTABLISHED: [(u32, u32, u32); 17] = [
+    (720, 400, 70),
+    (720, 400, 88),
+    (640, 480, 60),
+    (640, 480, 67),
+    (640, 480, 72),
+    (640, 480, 75),
+    (800, 600, 56),
+    (800, 600, 60),
+    (800, 600, 72),
+    (800, 600, 75),
+    (832, 624, 75),
+    // Interlaced
+    (1024, 768, 87),
+    (1024, 768, 60),
+    (1024, 768, 70),
+    (1024, 768, 75),
+    (1280, 1024, 75),
+    (1152, 870, 75),
+];
+/// The interlaced established timing, which can't be scanned out
+const ESTABLISHED_INTERLACED: usize = 11;
+
+/// A mode a display supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EdidMode {
+    pub width: u32,
+    pub height: u32,
+    /// The refresh rate in Hz
+    pub refresh: u32,
+}
+
+impl fmt::Display for EdidMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{} @ {}Hz", self.width, self.height, self.refresh)
+    }
+}
+
+/// What a display says about itself
+#[derive(Debug, Clone)]
+pub struct Edid {
+    /// The three letter PNP id of the manufacturer
+    pub manufacturer: [u8; 3],
+    pub product: u16,
+    /// The EDID version and revision
+    pub version: (u8, u8),
+    /// The timings of the mode the display prefers, its native mode
+    pub preferred: Option<DisplayTimings>,
+    /// Every mode the display lists, without duplicates
+    pub modes: Vec<EdidMode>,
+}
+
+impl Edid {
+    pub fn manufacturer(&self) -> &str {
+        core::str::from_utf8(&self.manufacturer).unwrap_or("???")
+    }
+
+    /// Returns the mode to switch to by default: the preferred one, or the largest one listed
+    pub fn native_mode(&self) -> Option<(u32, u32)> {
+        if let Some(timings) = self.preferred {
+            return Some((timings.hactive, timings.vactive));
+        }
+        self.modes
+            .iter()
+            .max_by_key(|mode| (mode.width * mode.height, mode.refresh))
+            .map(|mode| (mode.width, mode.height))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdidError {
+    /// Shorter than a block
+    TooShort,
+    /// The block doesn't start with the EDID header
+    BadHeader,
+    /// The bytes of the block don't add up to 0
+    BadChecksum,
+}
+
+impl fmt::Display for EdidError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::TooShort => "EDID is shorter than a block",
+            Self::BadHeader => "not an EDID block",
+            Self::BadChecksum => "EDID checksum mismatch",
+        })
+    }
+}
+
+impl core::error::Error for EdidError {}
+
+/// Parses a detailed timing descriptor, returning `None` for display descriptors
+fn detailed_timing(desc: &[u8; 18]) -> Option<DisplayTimings> {
+    let clock = u16::from_le_bytes([desc[0], desc[1]]) as u32;
+    if clock == 0 {
+        return None;
+    }
+    let high = |byte: u8, shift: u32, mask: u8| (((byte >> shift) & mask) as u32) << 8;
+    let hactive = desc[2] as u32 | high(desc[4], 4, 0xF);
+    let hblank = desc[3] as u32 | high(desc[4], 0, 0xF);
+    let vactive = desc[5] as u32 | high(desc[7], 4, 0xF);
+    let vblank = desc[6] as u32 | high(desc[7], 0, 0xF);
+    let hsync_offset = desc[8] as u32 | high(desc[11], 6, 0x3);
+    let hsync_width = desc[9] as u32 | high(desc[11], 4, 0x3);
+    let vsync_offset = (desc[10] >> 4) as u32 | ((desc[11] as u32 >> 2) & 0x3) << 4;
+    let vsync_width = (desc[10] & 0xF) as u32 | (desc[11] as u32 & 0x3) << 4;
+    if hactive == 0 || vactive == 0 {
+        return None;
+    }
+    Some(DisplayTimings {
+        hactive,
+        hsync_start: hactive + hsync_offset,
+        hsync_end: hactive + hsync_offset + hsync_width,
+        htotal: hactive + hblank,
+        vactive,
+        vsync_start: vactive + vsync_offset,
+        vsync_end: vactive + vsync_offset + vsync_width,
+        vtotal: vactive + vblank,
+        // In units of 10kHz
+        pixel_clock_khz: Some(clock * 10),
+    })
+}
+
+/// Parses a standard timing, returning `None` for unused slots
+fn standard_timing(bytes: [u8; 2], version: (u8, u8)) -> Option<EdidMode> {
+    if bytes == [0x01, 0x01] || bytes[0] == 0 {
+        return None;
+    }
+    let width = (bytes[0] as u32 + 31) * 8;
+    let height = match bytes[1] >> 6 {
+        // 1:1 before EDID 1.3
+        0 if version < (1, 3) => width,
+        0 => width * 10 / 16,
+        1 => width * 3 / 4,
+        2 => width * 4 / 5,
+        _ => width * 9 / 16,
+    };
+    Some(EdidMode {
+        width,
+        height,
+        refresh: (bytes[1] & 0x3F) as u32 + 60,
+    })
+}
+
+/// Parses the base block of an EDID
+pub fn parse(blob: &[u8]) -> Result<Edid, EdidError> {
+    let block: &[u8; BLOCK_SIZE] = blob.first_chunk().ok_or(EdidError::TooShort)?;
+    if block[..8] != HEADER {
+        return Err(EdidError::BadHeader);
+    }
+    if block.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        return Err(EdidError::BadChecksum);
+    }
+
+    // Three letters of five bits each, 1 is 'A'
+    let id = u16::from_be_bytes([block[8], block[9]]);
+    let manufacturer = [10, 5, 0].map(|shift| b'A' - 1 + ((id >> shift) & 0x1F) as u8);
+    let version = (block[18], block[19]);
+
+    let mut modes = Vec::new();
+    let mut add = |mode: EdidMode| {
+        if !modes.contains(&mode) {
+            modes.push(mode);
+        }
+    };
+    let established = u32::from_be_bytes([block[35], block[36], block[37], 0]);
+    for (i, (width, height, refresh)) in ESTABLISHED.iter().enumerate() {
+        if i != ESTABLISHED_INTERLACED && established & (1 << (31 - i)) != 0 {
+            add(EdidMode {
+                width: *width,
+                height: *height,
+                refresh: *refresh,
+            });
+        }
+    }
+    let (standard, _) = block[38..54].as_chunks::<2>();
+    for bytes in standard {
+        if let Some(mode) = standard_timing(*bytes, version) {
+            add(mode);
+        }
+    }
+
+    let (descriptors, _) = block[54..126].as_chunks::<18>();
+    let timings: Vec<DisplayTimings> = descriptors.iter().filter_map(detailed_timing).collect();
+    for timing in &timings {
+        add(EdidMode {
+            width: timing.hactive,
+            height: timing.vactive,
+            refresh: timing.refresh_mhz().map_or(0, |mhz| (mhz + 500) / 1000),
+        });
+    }
+
+    Ok(Edid {
+        manufacturer,
+        product: u16::from_le_bytes([block[10], block[11]]),
+        version,
+        // The first detailed timing is the preferred mode since EDID 1.3, and usually before it
+        preferred: timings.first().copied(),
+        modes,
+    })
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    /// The EDID QEMU reports for its display, with a preferred mode of 1024x768
+    fn qemu_edid() -> [u8; BLOCK_SIZE] {
+        let mut block = [0u8; BLOCK_SIZE];
+        block[..8].copy_from_slice(&HEADER);
+        // "RHT", product 0x1234
+        block[8..12].copy_from_slice(&[0x49, 0x14, 0x34, 0x12]);
+        block[18..20].copy_from_slice(&[1, 4]);
+        // 640x480@60 and 800x600@60
+        block[35] = 0x21;
+        // 1280x1024@60 (5:4), and unused slots
+        block[38..40].copy_from_slice(&[129, 0x80]);
+        block[40..54].copy_from_slice(&[0x01; 14]);
+        // 1024x768@60: 65MHz, 1344x806 total, sync at 1048..1184 and 771..777
+        block[54..72].copy_from_slice(&[
+            0x64, 0x19, 0x00, 0x40, 0x41, 0x00, 0x26, 0x30, 0x18, 0x88, 0x36, 0x00, 0, 0, 0, 0, 0, 0x18,
+        ]);
+        let sum = block.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        block[127] = sum.wrapping_neg();
+        block
+    }
+
+    #[test]
+    fn edid_parse() {
+        let block = qemu_edid();
+        let edid = parse(&block).unwrap();
+        assert_eq!(edid.manufacturer(), "RHT");
+        assert_eq!(edid.product, 0x1234);
+        assert_eq!(edid.version, (1, 4));
+        let timings = edid.preferred.unwrap();
+        assert_eq!((timings.hactive, timings.vactive), (1024, 768));
+        assert_eq!(
+            (timings.hsync_start, timings.hsync_end, timings.htotal),
+            (1048, 1184, 1344)
+        );
+        assert_eq!(
+            (timings.vsync_start, timings.vsync_end, timings.vtotal),
+            (771, 777, 806)
+        );
+        assert_eq!(timings.pixel_clock_khz, Some(65_000));
+        assert_eq!(
+            edid.modes
+                .iter()
+                .map(|mode| (mode.width, mode.height, mode.refresh))
+                .collect::<Vec<_>>(),
+            [(640, 480, 60), (800, 600, 60), (1280, 1024, 60), (1024, 768, 60)]
+        );
+        assert_eq!(edid.native_mode(), Some((1024, 768)));
+
+        let mut bad = block;
+        bad[20] ^= 1;
+        assert_eq!(parse(&bad).unwrap_err(), EdidError::BadChecksum);
+        assert_eq!(parse(&block[..64]).unwrap_err(), EdidError::TooShort);
+        bad[0] = 1;
+        assert_eq!(parse(&bad).unwrap_err(), EdidError::BadHeader);
+    }
+}
@@ -0,0 +1,245 @@
+//! 2D drawing primitives
+//!
+//! Enough to draw boot graphics into a [`Framebuffer`], or anything else implementing [`Canvas`]:
+//! filled rectangles, lines, text in the console font, and images copied as they are or blended
+//! by their alpha. Colors are `0xRRGGBB`, and positions are signed so shapes can hang off the
+//! edges, everything is clipped to the canvas.
+
+use crate::{
+    dev::drivers::platform::fb::{Framebuffer, font_constants, get_char_raster},
+    display::qoi::Image,
+};
+
+/// Something that can be drawn into, pixel by pixel
+pub trait Canvas {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    /// Returns the color of a pixel inside of the canvas
+    fn pixel(&self, x: u32, y: u32) -> u32;
+    /// Sets the color of a pixel inside of the canvas
+    fn set_pixel(&mut self, x: u32, y: u32, color: u32);
+
+    /// Sets a pixel if it is inside of the canvas
+    fn plot(&mut self, x: i32, y: i32, color: u32) {
+        if x >= 0 && y >= 0 && (x as u32) < self.width() && (y as u32) < self.height() {
+            self.set_pixel(x as u32, y as u32, color);
+        }
+    }
+
+    fn fill_rect(&mut self, x: i32, y: i32, width: u32, height: u32, color: u32) {
+        let Some((left, top, right, bottom)) = clip(self.width(), self.height(), x, y, width, height) else {
+            return;
+        };
+        for y in top..bottom {
+            for x in left..right {
+                self.set_pixel(x, y, color);
+            }
+        }
+    }
+
+    /// Draws a line from one point to another, both included
+    fn line(&mut self, from: (i32, i32), to: (i32, i32), color: u32) {
+        // Bresenham's, in all octants
+        let (dx, dy) = ((to.0 - from.0).abs(), -(to.1 - from.1).abs());
+        let (step_x, step_y) = ((to.0 - from.0).signum(), (to.1 - from.1).signum());
+        let (mut x, mut y) = from;
+        let mut error = dx + dy;
+        loop {
+            self.plot(x, y, color);
+            if (x, y) == to {
+                return;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Copies an image, ignoring its alpha
+    fn blit(&mut self, x: i32, y: i32, image: &Image) {
+        let Some((left, top, right, bottom)) = clip(self.width(), self.height(), x, y, image.width, image.height)
+        else {
+            return;
+        };
+        for py in top..bottom {
+            let row = (py as i32 - y) as usize * image.width as usize;
+            for px in left..right {
+                self.set_pixel(px, py, image.pixels[row + (px as i32 - x) as usize]);
+            }
+        }
+    }
+
+    /// Draws an image over what is there, by its alpha
+    fn blend(&mut self, x: i32, y: i32, image: &Image) {
+        let Some((left, top, right, bottom)) = clip(self.width(), self.height(), x, y, image.width, image.height)
+        else {
+            return;
+        };
+        for py in top..bottom {
+            let row = (py as i32 - y) as usize * image.width as usize;
+            for px in left..right {
+                let idx = row + (px as i32 - x) as usize;
+                let color = match image.alpha[idx] {
+                    255 => image.pixels[idx],
+                    0 => continue,
+                    // The image is blended onto black already
+                    alpha => add(image.pixels[idx], scale(self.pixel(px, py), 255 - alpha)),
+                };
+                self.set_pixel(px, py, color);
+            }
+        }
+    }
+
+    /// Draws text in the console font with its top left corner at a point, returning its width
+    ///
+    /// The text isn't wrapped, and is drawn over what is there.
+    fn text(&mut self, x: i32, y: i32, text: &str, color: u32) -> u32 {
+        let mut left = x;
+        for c in text.chars() {
+            let raster = get_char_raster(c);
+            for (row, line) in raster.raster().iter().enumerate() {
+                for (column, intensity) in line.iter().enumerate() {
+                    let (px, py) = (left + column as i32, y + row as i32);
+                    if *intensity == 0 || px < 0 || py < 0 || px as u32 >= self.width() || py as u32 >= self.height() {
+                        continue;
+                    }
+                    let background = scale(self.pixel(px as u32, py as u32), 255 - intensity);
+                    self.set_pixel(px as u32, py as u32, add(scale(color, *intensity), background));
+                }
+            }
+            left += raster.width() as i32;
+        }
+        (left - x) as u32
+    }
+}
+
+/// Returns the width of text drawn by [`Canvas::text`]
+pub fn text_width(text: &str) -> u32 {
+    (text.chars().count() * font_constants::CHAR_RASTER_WIDTH) as u32
+}
+
+/// The height of text drawn by [`Canvas::text`]
+pub fn text_height() -> u32 {
+    font_constants::CHAR_RASTER_HEIGHT.val() as u32
+}
+
+/// Clips a rectangle to a canvas, returning the left, top, right and bottom edges inside of it
+fn clip(
+    canvas_width: u32,
+    canvas_height: u32,
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+) -> Option<(u32, u32, u32, u32)> {
+    let (x, y) = (x as i64, y as i64);
+    let left = x.max(0);
+    let top = y.max(0);
+    let right = (x + width as i64).min(canvas_width as i64);
+    let bottom = (y + height as i64).min(canvas_height as i64);
+    (left < right && top < bottom).then_some((left as u32, top as u32, right as u32, bottom as u32))
+}
+
+/// Multiplies every channel of a color by `factor / 255`
+fn scale(color: u32, factor: u8) -> u32 {
+    let channel = |shift: u32| (((color >> shift) & 0xFF) * factor as u32 / 255) << shift;
+    channel(16) | channel(8) | channel(0)
+}
+
+/// Adds two colors channel by channel, saturating
+fn add(a: u32, b: u32) -> u32 {
+    let channel = |shift: u32| (((a >> shift) & 0xFF) + ((b >> shift) & 0xFF)).min(0xFF) << shift;
+    channel(16) | channel(8) | channel(0)
+}
+
+impl Canvas for Framebuffer {
+    fn width(&self) -> u32 {
+        self.info.width
+    }
+
+    fn height(&self) -> u32 {
+        self.info.height
+    }
+
+    fn pixel(&self, x: u32, y: u32) -> u32 {
+        self.read_pixel(x, y)
+    }
+
+    fn set_pixel(&mut self, x: u32, y: u32, color: u32) {
+        self.write_pixel(x, y, color);
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+
+    struct Pixels {
+        width: u32,
+        height: u32,
+        pixels: Vec<u32>,
+    }
+
+    impl Canvas for Pixels {
+        fn width(&self) -> u32 {
+            self.width
+        }
+
+        fn height(&self) -> u32 {
+            self.height
+        }
+
+        fn pixel(&self, x: u32, y: u32) -> u32 {
+            self.pixels[(y * self.width + x) as usize]
+        }
+
+        fn set_pixel(&mut self, x: u32, y: u32, color: u32) {
+            self.pixels[(y * self.width + x) as usize] = color;
+        }
+    }
+
+    #[test]
+    fn gfx_draw() {
+        let mut canvas = Pixels {
+            width: 4,
+            height: 3,
+            pixels: vec![0; 12],
+        };
+        // Hanging off the top left corner
+        canvas.fill_rect(-1, -1, 3, 2, 0xFF0000);
+        assert_eq!(canvas.pixels, [0xFF0000, 0xFF0000, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        canvas.fill_rect(10, 0, 3, 3, 0xFF0000);
+        canvas.fill_rect(0, 0, 0, 3, 0xFF0000);
+        assert_eq!(canvas.pixels.iter().filter(|pixel| **pixel != 0).count(), 2);
+
+        canvas.pixels.fill(0);
+        canvas.line((3, 2), (0, 0), 0xFFFFFF);
+        assert_eq!(
+            canvas.pixels,
+            [0xFFFFFF, 0, 0, 0, 0, 0xFFFFFF, 0xFFFFFF, 0, 0, 0, 0, 0xFFFFFF]
+        );
+
+        // Opaque, half transparent and transparent over white, and one pixel off the edge
+        canvas.pixels.fill(0xFFFFFF);
+        let image = Image {
+            width: 4,
+            height: 1,
+            pixels: vec![0x0000FF, 0x000080, 0xFF0000, 0x00FF00],
+            alpha: vec![255, 128, 0, 255],
+        };
+        canvas.blend(1, 2, &image);
+        assert_eq!(canvas.pixels[8..], [0xFFFFFF, 0x0000FF, 0x7F7FFF, 0xFFFFFF]);
+        canvas.blit(-3, 0, &image);
+        assert_eq!(canvas.pixels[0], 0x00FF00);
+
+        assert_eq!(text_width("boot"), 4 * font_constants::CHAR_RASTER_WIDTH as u32);
+    }
+}
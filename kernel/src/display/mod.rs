@@ -0,0 +1,298 @@
+//! Display core
+//!
+//! GPU drivers register the displays they drive here. A driver which takes over a display from the
+//! firmware keeps scanning out of the buffer the bootloader set up, so the console keeps working,
+//! and gives the display back in its firmware configuration on [`shutdown`], which the reboot runs,
+//! and a panic before the policy reboots.
+//!
+//! Changing the mode with [`set_mode`] moves the framebuffer console along with it, it redraws
+//! the text wrapped to the new width from its scrollback.
+//!
+//! Once the drivers are probed, [`set_native_mode`] switches the first display to the mode its
+//! EDID prefers, which is usually its native resolution rather than whatever the firmware picked.
+//!
+//! Displays of virtual GPUs like virtio-gpu don't scan out of the buffer directly, the host only
+//! sees what is drawn once it is flushed. [`poll`] flushes them at [`FLUSH_INTERVAL_NS`].
+
+use core::{
+    fmt,
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    arch::VirtAddr,
+    dev::drivers::platform::fb::{self, Framebuffer, FramebufferInfo, PixelFormat},
+    kprintln,
+    sync::{Once, RwLock},
+    time,
+    util::panicking::{self, PanicPolicy, PanicStage},
+};
+
+pub mod capture;
+pub mod edid;
+pub mod gfx;
+pub mod qoi;
+pub mod splash;
+
+/// How often [`poll`] flushes the displays, about 30 times a second
+pub const FLUSH_INTERVAL_NS: u64 = 33_000_000;
+
+/// The timings of a video mode, in pixels and lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayTimings {
+    pub hactive: u32,
+    pub hsync_start: u32,
+    pub hsync_end: u32,
+    pub htotal: u32,
+    pub vactive: u32,
+    pub vsync_start: u32,
+    pub vsync_end: u32,
+    pub vtotal: u32,
+    /// The pixel clock in kHz, if the driver knows it
+    pub pixel_clock_khz: Option<u32>,
+}
+
+impl DisplayTimings {
+    /// Returns the refresh rate in mHz, if the pixel clock is known
+    pub fn refresh_mhz(&self) -> Option<u32> {
+        let pixels = self.htotal as u64 * self.vtotal as u64;
+        let clock = self.pixel_clock_khz? as u64 * 1_000_000;
+        clock.checked_div(pixels).map(|mhz| mhz as u32)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayMode {
+    pub width: u32,
+    pub height: u32,
+    /// Bits per pixel
+    pub bpp: u32,
+    /// Bytes per line
+    pub stride: u32,
+    pub timings: Option<DisplayTimings>,
+}
+
+impl fmt::Display for DisplayMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} {}bpp stride {}",
+            self.width, self.height, self.bpp, self.stride
+        )?;
+        if let Some(mhz) = self.timings.and_then(|timings| timings.refresh_mhz()) {
+            write!(f, " @ {}.{:03}Hz", mhz / 1000, mhz % 1000)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DisplayError {
+    /// No display is registered
+    NoDisplay,
+    /// The driver can't program modes
+    Unsupported,
+    /// The display or the driver can't scan out in the mode
+    InvalidMode { width: u32, height: u32, bpp: u32 },
+    /// Video memory is too small for the mode
+    OutOfMemory,
+}
+
+impl fmt::Display for DisplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoDisplay => f.write_str("no display"),
+            Self::Unsupported => f.write_str("the driver can't change modes"),
+            Self::InvalidMode { width, height, bpp } => write!(f, "unsupported mode {}x{} {}bpp", width, height, bpp),
+            Self::OutOfMemory => f.write_str("not enough video memory for the mode"),
+        }
+    }
+}
+
+impl core::error::Error for DisplayError {}
+
+/// The buffer a display scans out of after a mode change, `mode.stride * mode.height` bytes long
+#[derive(Debug, Clone, Copy)]
+pub struct Scanout {
+    pub mode: DisplayMode,
+    /// The buffer, mapped by the driver for as long as the display uses it
+    pub addr: VirtAddr,
+}
+
+/// A display driven by a GPU driver
+pub trait DisplayDevice: Send + Sync {
+    fn name(&self) -> &str;
+    /// Returns the mode the display is scanning out in
+    fn mode(&self) -> DisplayMode;
+    /// Switches to a mode, returning the buffer the display scans out of from now on
+    ///
+    /// The framebuffer console is stopped meanwhile, so the old buffer can be reused or unmapped.
+    fn set_mode(&self, width: u32, height: u32, bpp: u32) -> Result<Scanout, DisplayError> {
+        _ = (width, height, bpp);
+        Err(DisplayError::Unsupported)
+    }
+    /// Returns the EDID of the display, if the driver can read it
+    fn edid(&self) -> Option<Vec<u8>> {
+        None
+    }
+    /// Returns the display to the state the firmware left it in
+    fn restore(&self);
+    /// Shows what was drawn into the buffer, for displays that don't scan out of it directly
+    fn flush(&self) {}
+}
+
+static DISPLAYS: RwLock<Vec<Arc<dyn DisplayDevice>>> = RwLock::new(Vec::new());
+
+pub fn register(display: Arc<dyn DisplayDevice>) {
+    static PANIC_STAGE: Once<()> = Once::new();
+    PANIC_STAGE.call_once(|| {
+        panicking::register_stage(PanicStage {
+            name: "display shutdown",
+            run: shutdown_on_panic,
+        });
+    });
+    kprintln!(Info, "display: {}: {}", display.name(), display.mode());
+    DISPLAYS.write().push(display);
+}
+
+pub fn displays() -> Vec<Arc<dyn DisplayDevice>> {
+    DISPLAYS.read().clone()
+}
+
+/// Switches the first display to a mode, moving the framebuffer console to the new buffer
+///
+/// The console keeps its old buffer if the driver fails.
+pub fn set_mode(width: u32, height: u32, bpp: u32) -> Result<DisplayMode, DisplayError> {
+    if width == 0 || height == 0 || !matches!(bpp, 24 | 32) {
+        return Err(DisplayError::InvalidMode { width, height, bpp });
+    }
+    let display = DISPLAYS.read().first().cloned().ok_or(DisplayError::NoDisplay)?;
+    let old = display.mode();
+    let console = fb::suspend_console();
+    let scanout = match display.set_mode(width, height, bpp) {
+        Ok(scanout) => scanout,
+        Err(err) => {
+            if console {
+                fb::resume_console(None);
+            }
+            return Err(err);
+        }
+    };
+    let mode = scanout.mode;
+    if console {
+        let info = FramebufferInfo {
+            width: mode.width,
+            height: mode.height,
+            pixel_format: PixelFormat::RGB,
+            stride: mode.stride,
+            bpp: mode.bpp / 8,
+        };
+        // SAFETY: The driver keeps the buffer mapped for as long as the display scans out of it
+        let buffer = unsafe {
+            core::slice::from_raw_parts_mut(
+                scanout.addr.as_mut_ptr::<u8>(),
+                mode.stride as usize * mode.height as usize,
+            )
+        };
+        fb::resume_console(Some(Framebuffer::new(info, buffer)));
+    }
+    display.flush();
+    kprintln!(Info, "display: {}: {} -> {}", display.name(), old, mode);
+    Ok(mode)
+}
+
+/// Returns the EDID of the first display, as its driver read it or else as the firmware did
+pub fn edid() -> Option<edid::Edid> {
+    let display = DISPLAYS.read().first().cloned()?;
+    let (source, blob) = match display.edid() {
+        Some(blob) => (display.name(), blob),
+        None if !crate::boot::edid().is_empty() => ("firmware", crate::boot::edid().to_vec()),
+        None => return None,
+    };
+    edid::parse(&blob)
+        .inspect_err(|err| kprintln!(Warn, "display: {}: {}", source, err))
+        .ok()
+}
+
+/// Switches the first display to the native mode of its EDID, unless booted with `nomodeset`
+pub fn set_native_mode() {
+    if crate::boot::cmdline().flag("nomodeset") {
+        kprintln!(Info, "display: nomodeset, keeping the firmware mode");
+        return;
+    }
+    let Some(display) = DISPLAYS.read().first().cloned() else {
+        return;
+    };
+    let Some(edid) = edid() else {
+        kprintln!(Debug, "display: {}: no EDID, keeping the firmware mode", display.name());
+        return;
+    };
+    let Some((width, height)) = edid.native_mode() else {
+        return;
+    };
+    kprintln!(
+        Info,
+        "display: {}: {} {:04x}, native mode {}x{}",
+        display.name(),
+        edid.manufacturer(),
+        edid.product,
+        width,
+        height
+    );
+    let mode = display.mode();
+    if (mode.width, mode.height, mode.bpp) == (width, height, 32) {
+        return;
+    }
+    // The splash would be drawn over by the console in the new mode
+    if splash::shown() {
+        kprintln!(Info, "display: keeping the firmware mode for the splash");
+        return;
+    }
+    match set_mode(width, height, 32) {
+        Ok(_) | Err(DisplayError::Unsupported) => {}
+        Err(err) => kprintln!(Warn, "display: can't switch to {}x{}: {}", width, height, err),
+    }
+}
+
+/// Hands every display back to its firmware configuration, most recently registered first
+///
+/// Does nothing if the displays are locked, which a CPU stopped by a panic may have left them.
+pub fn shutdown() {
+    let Some(mut displays) = DISPLAYS.try_write() else {
+        return;
+    };
+    let displays = core::mem::take(&mut *displays);
+    for display in displays.iter().rev() {
+        display.restore();
+    }
+}
+
+/// Shuts the displays down before the panic policy reboots, halting keeps the panic screen
+fn shutdown_on_panic(_info: &PanicInfo) {
+    if panicking::policy() != PanicPolicy::Halt {
+        shutdown();
+    }
+}
+
+/// Flushes every display every [`FLUSH_INTERVAL_NS`], from the main loop
+pub fn poll() {
+    static NEXT_FLUSH: AtomicU64 = AtomicU64::new(0);
+    let now = time::monotonic_ns();
+    let next = NEXT_FLUSH.load(Ordering::Relaxed);
+    if now < next
+        || NEXT_FLUSH
+            .compare_exchange(next, now + FLUSH_INTERVAL_NS, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return;
+    }
+    let Some(displays) = DISPLAYS.try_read() else {
+        return;
+    };
+    for display in displays.iter() {
+        display.flush();
+    }
+}
@@ -0,0 +1,177 @@
+//! QOI image decoder
+//!
+//! The "Quite OK Image" format is small to decode and compresses boot graphics well, see
+//! <https://qoiformat.org/qoi-specification.pdf>. Transparent pixels are blended onto black, and
+//! their alpha is kept so they can be blended onto anything else, see [`gfx`](super::gfx).
+
+use core::fmt;
+
+use alloc::{vec, vec::Vec};
+
+const MAGIC: &[u8; 4] = b"qoif";
+const HEADER_SIZE: usize = 14;
+/// Larger images are refused, rather than allocating most of the heap
+const MAX_PIXELS: usize = 4096 * 4096;
+
+const OP_RGB: u8 = 0xFE;
+const OP_RGBA: u8 = 0xFF;
+const OP_INDEX: u8 = 0b00;
+const OP_DIFF: u8 = 0b01;
+const OP_LUMA: u8 = 0b10;
+const OP_RUN: u8 = 0b11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QoiError {
+    BadMagic,
+    Truncated,
+    TooLarge { width: u32, height: u32 },
+}
+
+impl fmt::Display for QoiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => f.write_str("not a QOI image"),
+            Self::Truncated => f.write_str("truncated QOI image"),
+            Self::TooLarge { width, height } => write!(f, "QOI image too large ({}x{})", width, height),
+        }
+    }
+}
+
+impl core::error::Error for QoiError {}
+
+/// A decoded image, row by row
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels as `0xRRGGBB`, blended onto black
+    pub pixels: Vec<u32>,
+    /// The alpha of every pixel, 255 is opaque
+    pub alpha: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rgba([u8; 4]);
+
+impl Rgba {
+    fn hash(self) -> usize {
+        let [r, g, b, a] = self.0.map(usize::from);
+        (r * 3 + g * 5 + b * 7 + a * 11) % 64
+    }
+
+    fn blended(self) -> u32 {
+        let [r, g, b, a] = self.0.map(u32::from);
+        ((r * a / 255) << 16) | ((g * a / 255) << 8) | (b * a / 255)
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Image, QoiError> {
+    let header = data.get(..HEADER_SIZE).ok_or(QoiError::Truncated)?;
+    if &header[..4] != MAGIC {
+        return Err(QoiError::BadMagic);
+    }
+    let width = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    let height = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    let count = (width as usize)
+        .checked_mul(height as usize)
+        .filter(|count| *count <= MAX_PIXELS)
+        .ok_or(QoiError::TooLarge { width, height })?;
+
+    let mut pixels = vec![0; count];
+    let mut alpha = vec![0; count];
+    let mut index = [Rgba([0; 4]); 64];
+    let mut px = Rgba([0, 0, 0, 255]);
+    let mut bytes = data[HEADER_SIZE..].iter().copied();
+    let mut next = || bytes.next().ok_or(QoiError::Truncated);
+    let mut pos = 0;
+    while pos < count {
+        let tag = next()?;
+        let mut run = 1;
+        match tag {
+            OP_RGB => px = Rgba([next()?, next()?, next()?, px.0[3]]),
+            OP_RGBA => px = Rgba([next()?, next()?, next()?, next()?]),
+            _ => match tag >> 6 {
+                OP_INDEX => px = index[tag as usize],
+                OP_DIFF => {
+                    let [r, g, b, a] = px.0;
+                    px = Rgba([
+                        r.wrapping_add((tag >> 4 & 3).wrapping_sub(2)),
+                        g.wrapping_add((tag >> 2 & 3).wrapping_sub(2)),
+                        b.wrapping_add((tag & 3).wrapping_sub(2)),
+                        a,
+                    ]);
+                }
+                OP_LUMA => {
+                    let dg = (tag & 0x3F).wrapping_sub(32);
+                    let rb = next()?;
+                    let [r, g, b, a] = px.0;
+                    px = Rgba([
+                        r.wrapping_add(dg.wrapping_add(rb >> 4).wrapping_sub(8)),
+                        g.wrapping_add(dg),
+                        b.wrapping_add(dg.wrapping_add(rb & 0xF).wrapping_sub(8)),
+                        a,
+                    ]);
+                }
+                OP_RUN => run = (tag & 0x3F) as usize + 1,
+                _ => unreachable!(),
+            },
+        }
+        index[px.hash()] = px;
+        let end = (pos + run).min(count);
+        pixels[pos..end].fill(px.blended());
+        alpha[pos..end].fill(px.0[3]);
+        pos = end;
+    }
+
+    Ok(Image {
+        width,
+        height,
+        pixels,
+        alpha,
+    })
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qoi_decode() {
+        let mut data = alloc::vec::Vec::from(*b"qoif");
+        data.extend_from_slice(&3u32.to_be_bytes());
+        data.extend_from_slice(&2u32.to_be_bytes());
+        data.extend_from_slice(&[3, 0]);
+        data.extend_from_slice(&[
+            OP_RGB,
+            0x10,
+            0x20,
+            0x30,
+            // Repeat it once
+            OP_RUN << 6,
+            // +1 red, -1 blue
+            OP_DIFF << 6 | 3 << 4 | 2 << 2 | 1,
+            // +8 green, red and blue follow
+            OP_LUMA << 6 | 40,
+            0x88,
+            // The first pixel again
+            OP_INDEX << 6 | Rgba([0x10, 0x20, 0x30, 255]).hash() as u8,
+            OP_RGBA,
+            0xFF,
+            0xFF,
+            0xFF,
+            0,
+        ]);
+        data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+
+        let image = decode(&data).unwrap();
+        assert_eq!((image.width, image.height), (3, 2));
+        assert_eq!(image.pixels, [0x102030, 0x102030, 0x11202F, 0x192837, 0x102030, 0]);
+        assert_eq!(image.alpha, [255, 255, 255, 255, 255, 0]);
+        assert_eq!(decode(&data[..20]).unwrap_err(), QoiError::Truncated);
+        assert_eq!(decode(b"PNG\0").unwrap_err(), QoiError::Truncated);
+        assert_eq!(
+            decode(b"\x89PNG\r\n\x1a\n\0\0\0\0\0\0").unwrap_err(),
+            QoiError::BadMagic
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Boot splash
+//!
+//! With `splash` or `quiet` on the command line, and without `verbose`, the image `splash.qoi` from
+//! the initramfs is shown centered in place of the boot log, blended by its alpha, above a progress
+//! bar that boot milestones advance and a line saying what boot is at. The framebuffer console is
+//! suspended meanwhile but keeps recording, so the log so far shows up when the splash goes away.
+//! That is on the first key press, after boot too, or right away if anything goes wrong: the image
+//! is missing, broken or larger than the screen. A panic draws over the splash.
+
+use core::fmt;
+
+use crate::{
+    dev::drivers::platform::fb::{self, Framebuffer},
+    display::{
+        gfx::{self, Canvas},
+        qoi::{self, QoiError},
+    },
+    kprintln,
+    sync::Mutex,
+};
+
+const IMAGE_PATH: &str = "splash.qoi";
+
+const BAR_HEIGHT: u32 = 6;
+/// The space between the image and the bar
+const BAR_MARGIN: u32 = 24;
+const BAR_COLOR: u32 = 0xFFFFFF;
+const BAR_BACKGROUND: u32 = 0x404040;
+const BACKGROUND: u32 = 0x000000;
+/// The space between the bar and the message
+const MESSAGE_MARGIN: u32 = 12;
+const MESSAGE_COLOR: u32 = 0xA0A0A0;
+
+/// The points in boot the progress bar shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Milestone {
+    Timers,
+    Pci,
+    Drivers,
+    Network,
+    Done,
+}
+
+impl Milestone {
+    const COUNT: u32 = Self::Done as u32 + 1;
+
+    /// What the splash says once the milestone is reached
+    fn message(self) -> &'static str {
+        match self {
+            Self::Timers => "Scanning PCI devices",
+            Self::Pci => "Starting drivers",
+            Self::Drivers => "Bringing up the network",
+            Self::Network => "Mounting the root file system",
+            Self::Done => "Ready, press any key for the console",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SplashError {
+    NoInitramfs,
+    NotFound,
+    Image(QoiError),
+    NoFramebuffer,
+    TooLarge { width: u32, height: u32 },
+}
+
+impl fmt::Display for SplashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoInitramfs => f.write_str("no initramfs"),
+            Self::NotFound => write!(f, "no {} in the initramfs", IMAGE_PATH),
+            Self::Image(err) => write!(f, "{}: {}", IMAGE_PATH, err),
+            Self::NoFramebuffer => f.write_str("no framebuffer console"),
+            Self::TooLarge { width, height } => write!(f, "{}x{} image doesn't fit the screen", width, height),
+        }
+    }
+}
+
+impl core::error::Error for SplashError {}
+
+/// Where the progress bar is
+#[derive(Debug, Clone, Copy)]
+struct Bar {
+    x: u32,
+    y: u32,
+    width: u32,
+}
+
+static BAR: Mutex<Option<Bar>> = Mutex::new(None);
+
+/// Shows the splash, if the command line asks for it
+pub fn init() {
+    let cmdline = crate::boot::cmdline();
+    if !(cmdline.flag("splash") || cmdline.flag("quiet")) || cmdline.flag("verbose") {
+        return;
+    }
+    if let Err(err) = show() {
+        kprintln!(Warn, "splash: {}", err);
+    }
+}
+
+fn show() -> Result<(), SplashError> {
+    let initramfs = crate::boot::initramfs().ok_or(SplashError::NoInitramfs)?;
+    let data = crate::util::tar::find(initramfs, IMAGE_PATH).ok_or(SplashError::NotFound)?;
+    let image = qoi::decode(data).map_err(SplashError::Image)?;
+    if !fb::suspend_console() {
+        return Err(SplashError::NoFramebuffer);
+    }
+
+    let mut drawn = Ok(());
+    fb::draw(|fb| {
+        let (width, height) = (fb.info.width, fb.info.height);
+        let below = BAR_MARGIN + BAR_HEIGHT + MESSAGE_MARGIN + gfx::text_height();
+        if image.width > width || image.height + below > height {
+            drawn = Err(SplashError::TooLarge {
+                width: image.width,
+                height: image.height,
+            });
+            return;
+        }
+        fb.fill(BACKGROUND);
+        let left = (width - image.width) / 2;
+        let top = (height - image.height - below) / 2;
+        fb.blend(left as i32, top as i32, &image);
+        let bar = Bar {
+            x: width / 3,
+            y: top + image.height + BAR_MARGIN,
+            width: width / 3,
+        };
+        draw_bar(fb, bar, 0);
+        draw_message(fb, bar, "Starting timers");
+        *BAR.lock() = Some(bar);
+    });
+    if drawn.is_err() {
+        fb::resume_console(None);
+    }
+    drawn
+}
+
+fn draw_bar(fb: &mut Framebuffer, bar: Bar, steps: u32) {
+    let filled = bar.width * steps / Milestone::COUNT;
+    let (x, y) = (bar.x as i32, bar.y as i32);
+    fb.fill_rect(x, y, filled, BAR_HEIGHT, BAR_COLOR);
+    fb.fill_rect(x + filled as i32, y, bar.width - filled, BAR_HEIGHT, BAR_BACKGROUND);
+}
+
+/// Replaces the message below the bar, centered on it
+fn draw_message(fb: &mut Framebuffer, bar: Bar, message: &str) {
+    let y = (bar.y + BAR_HEIGHT + MESSAGE_MARGIN) as i32;
+    fb.fill_rect(0, y, fb.info.width, gfx::text_height(), BACKGROUND);
+    let x = (bar.x + bar.width / 2) as i32 - gfx::text_width(message) as i32 / 2;
+    fb.text(x, y, message, MESSAGE_COLOR);
+}
+
+/// Advances the progress bar to a milestone
+pub fn milestone(milestone: Milestone) {
+    let Some(bar) = *BAR.lock() else { return };
+    fb::draw(|fb| {
+        draw_bar(fb, bar, milestone as u32 + 1);
+        draw_message(fb, bar, milestone.message());
+    });
+}
+
+/// Returns whether the splash is shown instead of the console
+pub fn shown() -> bool {
+    BAR.lock().is_some()
+}
+
+/// Hides the splash and shows the console again, returning whether the splash was shown
+pub fn dismiss() -> bool {
+    if BAR.lock().take().is_none() {
+        return false;
+    }
+    fb::resume_console(None);
+    true
+}
@@ -0,0 +1,513 @@
+//! ELF64 loader
+//!
+//! Parses little endian x86_64 ELF images and maps their loadable segments into a [`LoadTarget`],
+//! which is a user [`AddressSpace`] for processes. Position independent images are loaded at a
+//! caller chosen base and have their `R_X86_64_RELATIVE` relocations applied.
+//!
+//! Relocatable objects can't be loaded here, the module loader links them from their sections,
+//! symbols and relocations, see [`section`].
+
+use core::fmt;
+
+pub mod section;
+
+use crate::{
+    arch::VirtAddr,
+    mm::{
+        address_space::{AddressSpace, AddressSpaceError},
+        page_table::PageTableFlags,
+    },
+};
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u16 = 62;
+
+const FILE_HEADER_LEN: usize = 64;
+const PROGRAM_HEADER_LEN: usize = 56;
+const SECTION_HEADER_LEN: usize = 64;
+const DYN_LEN: usize = 16;
+const RELA_LEN: usize = 24;
+const PAGE_SIZE: usize = 4096;
+
+pub const PT_NULL: u32 = 0;
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+
+const DT_NULL: i64 = 0;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_RELATIVE: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The image ends before a header or segment does
+    Truncated,
+    BadMagic,
+    /// The image is not a 64 bit little endian x86_64 executable, shared or relocatable object
+    Unsupported,
+    /// A segment has inconsistent sizes or addresses
+    BadSegment,
+    /// A section header, symbol or string table entry is out of bounds
+    BadSection,
+    /// The image needs a dynamic linker
+    Interpreter,
+    UnsupportedRelocation(u32),
+    Map(AddressSpaceError),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => f.write_str("image is truncated"),
+            Self::BadMagic => f.write_str("not an ELF image"),
+            Self::Unsupported => f.write_str("unsupported ELF class, endianness, type or machine"),
+            Self::BadSegment => f.write_str("malformed segment"),
+            Self::BadSection => f.write_str("malformed section"),
+            Self::Interpreter => f.write_str("dynamically linked images are not supported"),
+            Self::UnsupportedRelocation(kind) => write!(f, "unsupported relocation type {}", kind),
+            Self::Map(err) => write!(f, "failed to map segment: {}", err),
+        }
+    }
+}
+
+impl core::error::Error for ElfError {}
+
+impl From<AddressSpaceError> for ElfError {
+    fn from(err: AddressSpaceError) -> Self {
+        Self::Map(err)
+    }
+}
+
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfType {
+    /// An object file, linked by the module loader
+    Relocatable = 1,
+    /// Loaded at the addresses in its program headers
+    Executable = 2,
+    /// Position independent, loaded at any base
+    Shared = 3,
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct SegmentFlags: u32 {
+        const EXECUTE = 1 << 0;
+        const WRITE = 1 << 1;
+        const READ = 1 << 2;
+    }
+}
+
+impl SegmentFlags {
+    /// Returns the page flags enforcing these permissions
+    pub fn page_flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.contains(Self::WRITE) {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.contains(Self::EXECUTE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: SegmentFlags,
+    pub offset: usize,
+    pub vaddr: usize,
+    pub filesz: usize,
+    pub memsz: usize,
+    pub align: usize,
+}
+
+/// Memory that segments can be loaded into
+pub trait LoadTarget {
+    /// Maps zeroed memory covering `[start, start + len)` with the given permissions
+    fn map(&mut self, start: VirtAddr, len: usize, flags: PageTableFlags) -> Result<(), AddressSpaceError>;
+    /// Copies data into mapped memory, regardless of its permissions
+    fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), AddressSpaceError>;
+}
+
+impl LoadTarget for AddressSpace {
+    fn map(&mut self, start: VirtAddr, len: usize, flags: PageTableFlags) -> Result<(), AddressSpaceError> {
+        self.map_range(start, len, flags)
+    }
+
+    fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), AddressSpaceError> {
+        AddressSpace::write(self, addr, data)
+    }
+}
+
+/// Where an image was loaded
+#[derive(Debug, Clone, Copy)]
+pub struct LoadedImage {
+    pub entry: VirtAddr,
+    /// The offset added to every address in the image, zero for executables
+    pub base: usize,
+    /// The lowest address of a loaded segment
+    pub start: VirtAddr,
+    /// The end of the highest loaded segment
+    pub end: VirtAddr,
+}
+
+/// A parsed ELF64 image
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    kind: ElfType,
+    entry: usize,
+    phoff: usize,
+    phentsize: usize,
+    phnum: usize,
+    shoff: usize,
+    shentsize: usize,
+    shnum: usize,
+    shstrndx: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Parses and validates the file header
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ElfError> {
+        if bytes.len() < FILE_HEADER_LEN {
+            return Err(ElfError::Truncated);
+        }
+        if &bytes[..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if bytes[4] != ELFCLASS64 || bytes[5] != ELFDATA2LSB || read_u16(bytes, 18)? != EM_X86_64 {
+            return Err(ElfError::Unsupported);
+        }
+        let kind = match read_u16(bytes, 16)? {
+            1 => ElfType::Relocatable,
+            2 => ElfType::Executable,
+            3 => ElfType::Shared,
+            _ => return Err(ElfError::Unsupported),
+        };
+
+        let elf = Self {
+            bytes,
+            kind,
+            entry: read_u64(bytes, 24)?,
+            phoff: read_u64(bytes, 32)?,
+            phentsize: read_u16(bytes, 54)? as usize,
+            phnum: read_u16(bytes, 56)? as usize,
+            shoff: read_u64(bytes, 40)?,
+            shentsize: read_u16(bytes, 58)? as usize,
+            shnum: read_u16(bytes, 60)? as usize,
+            shstrndx: read_u16(bytes, 62)? as usize,
+        };
+        if elf.phnum != 0 && elf.phentsize < PROGRAM_HEADER_LEN {
+            return Err(ElfError::Unsupported);
+        }
+        if elf.shnum != 0 && elf.shentsize < SECTION_HEADER_LEN {
+            return Err(ElfError::Unsupported);
+        }
+        Ok(elf)
+    }
+
+    pub fn kind(&self) -> ElfType {
+        self.kind
+    }
+
+    /// Returns the entry point, relative to the load base for shared objects
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = Result<ProgramHeader, ElfError>> + '_ {
+        (0..self.phnum).map(|idx| {
+            let off = self
+                .phoff
+                .checked_add(idx * self.phentsize)
+                .ok_or(ElfError::Truncated)?;
+            let header = field(self.bytes, off, PROGRAM_HEADER_LEN)?;
+            Ok(ProgramHeader {
+                kind: read_u32(header, 0)?,
+                flags: SegmentFlags::from_bits_truncate(read_u32(header, 4)?),
+                offset: read_u64(header, 8)?,
+                vaddr: read_u64(header, 16)?,
+                filesz: read_u64(header, 32)?,
+                memsz: read_u64(header, 40)?,
+                align: read_u64(header, 48)?,
+            })
+        })
+    }
+
+    /// Returns the file contents of a segment
+    pub fn segment_data(&self, ph: &ProgramHeader) -> Result<&'a [u8], ElfError> {
+        let end = ph.offset.checked_add(ph.filesz).ok_or(ElfError::BadSegment)?;
+        self.bytes.get(ph.offset..end).ok_or(ElfError::Truncated)
+    }
+
+    /// Maps every loadable segment into `target` and applies relocations
+    ///
+    /// Shared objects are loaded at `base`, executables ignore it and load at their link address.
+    /// Memory past the file contents of a segment (the BSS) is zeroed.
+    pub fn load(&self, target: &mut impl LoadTarget, base: usize) -> Result<LoadedImage, ElfError> {
+        let base = match self.kind {
+            ElfType::Relocatable => return Err(ElfError::Unsupported),
+            ElfType::Executable => 0,
+            ElfType::Shared => base,
+        };
+
+        let (mut start, mut end) = (usize::MAX, 0);
+        for ph in self.program_headers() {
+            let ph = ph?;
+            match ph.kind {
+                PT_INTERP => return Err(ElfError::Interpreter),
+                PT_LOAD => {}
+                _ => continue,
+            }
+            if ph.filesz > ph.memsz {
+                return Err(ElfError::BadSegment);
+            }
+            let data = self.segment_data(&ph)?;
+            let vaddr = relocate(base, ph.vaddr)?;
+            let seg_end = vaddr.as_usize().checked_add(ph.memsz).ok_or(ElfError::BadSegment)?;
+
+            target.map(vaddr, ph.memsz, ph.flags.page_flags())?;
+            target.write(vaddr, data)?;
+            // Pages past the file contents are fresh, but the page holding the end of the file
+            // contents might be shared with a previous segment
+            let bss = vaddr + ph.filesz;
+            let bss_len = (ph.memsz - ph.filesz).min(bss.as_usize().next_multiple_of(PAGE_SIZE) - bss.as_usize());
+            target.write(bss, &[0; PAGE_SIZE][..bss_len])?;
+
+            start = start.min(vaddr.as_usize());
+            end = end.max(seg_end);
+        }
+        if start > end {
+            return Err(ElfError::BadSegment);
+        }
+
+        self.apply_relocations(target, base)?;
+
+        Ok(LoadedImage {
+            entry: relocate(base, self.entry)?,
+            base,
+            start: VirtAddr::new(start),
+            end: VirtAddr::new(end),
+        })
+    }
+
+    /// Translates a link time address to its offset in the file
+    fn vaddr_to_offset(&self, vaddr: usize) -> Result<usize, ElfError> {
+        for ph in self.program_headers() {
+            let ph = ph?;
+            if ph.kind == PT_LOAD && vaddr >= ph.vaddr && vaddr - ph.vaddr < ph.filesz {
+                return Ok(ph.offset + (vaddr - ph.vaddr));
+            }
+        }
+        Err(ElfError::BadSegment)
+    }
+
+    fn apply_relocations(&self, target: &mut impl LoadTarget, base: usize) -> Result<(), ElfError> {
+        let mut dynamic = None;
+        for ph in self.program_headers() {
+            let ph = ph?;
+            if ph.kind == PT_DYNAMIC {
+                dynamic = Some(ph);
+                break;
+            }
+        }
+        let Some(dynamic) = dynamic else {
+            return Ok(());
+        };
+        let dynamic = self.segment_data(&dynamic)?;
+
+        let (mut rela, mut rela_size, mut rela_ent) = (None, 0, RELA_LEN);
+        for entry in dynamic.as_chunks::<DYN_LEN>().0 {
+            let tag = read_u64(entry, 0)? as i64;
+            let value = read_u64(entry, 8)?;
+            match tag {
+                DT_NULL => break,
+                DT_RELA => rela = Some(value),
+                DT_RELASZ => rela_size = value,
+                DT_RELAENT => rela_ent = value,
+                _ => {}
+            }
+        }
+        let Some(rela) = rela else {
+            return Ok(());
+        };
+        if rela_ent < RELA_LEN {
+            return Err(ElfError::BadSegment);
+        }
+
+        let offset = self.vaddr_to_offset(rela)?;
+        let table = self
+            .bytes
+            .get(offset..offset.checked_add(rela_size).ok_or(ElfError::BadSegment)?)
+            .ok_or(ElfError::Truncated)?;
+        for entry in table.chunks_exact(rela_ent) {
+            let r_offset = read_u64(entry, 0)?;
+            let kind = read_u64(entry, 8)? as u32;
+            let addend = read_u64(entry, 16)?;
+            match kind {
+                R_X86_64_NONE => {}
+                R_X86_64_RELATIVE => {
+                    let value = base.wrapping_add(addend) as u64;
+                    target.write(relocate(base, r_offset)?, &value.to_le_bytes())?;
+                }
+                _ => return Err(ElfError::UnsupportedRelocation(kind)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn relocate(base: usize, addr: usize) -> Result<VirtAddr, ElfError> {
+    let addr = base.checked_add(addr).ok_or(ElfError::BadSegment)?;
+    VirtAddr::try_new(addr).map_err(|_| ElfError::BadSegment)
+}
+
+/// Returns the `len` bytes at `off`, which comes from the file and may be anything
+fn field(bytes: &[u8], off: usize, len: usize) -> Result<&[u8], ElfError> {
+    off.checked_add(len)
+        .and_then(|end| bytes.get(off..end))
+        .ok_or(ElfError::Truncated)
+}
+
+fn read_u16(bytes: &[u8], off: usize) -> Result<u16, ElfError> {
+    let bytes = field(bytes, off, 2)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(bytes: &[u8], off: usize) -> Result<u32, ElfError> {
+    let bytes = field(bytes, off, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(bytes: &[u8], off: usize) -> Result<usize, ElfError> {
+    let bytes = field(bytes, off, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+
+    /// A flat buffer standing in for the lower part of an address space
+    struct Memory {
+        data: Vec<u8>,
+        mapped: Vec<(usize, usize, PageTableFlags)>,
+    }
+
+    impl LoadTarget for Memory {
+        fn map(&mut self, start: VirtAddr, len: usize, flags: PageTableFlags) -> Result<(), AddressSpaceError> {
+            self.mapped.push((start.as_usize(), len, flags));
+            Ok(())
+        }
+
+        fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), AddressSpaceError> {
+            let start = addr.as_usize();
+            let dst = self
+                .data
+                .get_mut(start..start + data.len())
+                .ok_or(AddressSpaceError::NotMapped)?;
+            dst.copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    fn put(buf: &mut [u8], off: usize, bytes: &[u8]) {
+        buf[off..off + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Builds a shared object with a text segment, a writable segment holding a pointer to be
+    /// relocated, and a dynamic section pointing at a single RELATIVE relocation
+    fn build_image() -> Vec<u8> {
+        let mut image = vec![0u8; 0x300];
+        put(&mut image, 0, b"\x7fELF\x02\x01\x01");
+        put(&mut image, 16, &3u16.to_le_bytes());
+        put(&mut image, 18, &EM_X86_64.to_le_bytes());
+        put(&mut image, 24, &0x10u64.to_le_bytes());
+        put(&mut image, 32, &(FILE_HEADER_LEN as u64).to_le_bytes());
+        put(&mut image, 54, &(PROGRAM_HEADER_LEN as u16).to_le_bytes());
+        put(&mut image, 56, &3u16.to_le_bytes());
+
+        let phdr = |image: &mut Vec<u8>, idx: usize, kind: u32, flags: u32, offset: u64, filesz: u64, memsz: u64| {
+            let off = FILE_HEADER_LEN + idx * PROGRAM_HEADER_LEN;
+            put(image, off, &kind.to_le_bytes());
+            put(image, off + 4, &flags.to_le_bytes());
+            put(image, off + 8, &offset.to_le_bytes());
+            put(image, off + 16, &offset.to_le_bytes());
+            put(image, off + 32, &filesz.to_le_bytes());
+            put(image, off + 40, &memsz.to_le_bytes());
+        };
+        // Text at 0x0..0x100, data at 0x100..0x300 with 0x100 bytes of BSS
+        phdr(&mut image, 0, PT_LOAD, 0b101, 0, 0x100, 0x100);
+        phdr(&mut image, 1, PT_LOAD, 0b110, 0x100, 0x200, 0x300);
+        phdr(&mut image, 2, PT_DYNAMIC, 0b110, 0x200, 0x40, 0x40);
+
+        // The relocation table at 0x180, patching the pointer at 0x100 to point at the entry
+        put(&mut image, 0x180, &0x100u64.to_le_bytes());
+        put(&mut image, 0x188, &(R_X86_64_RELATIVE as u64).to_le_bytes());
+        put(&mut image, 0x190, &0x10u64.to_le_bytes());
+
+        let dynamic = [
+            (DT_RELA, 0x180u64),
+            (DT_RELASZ, RELA_LEN as u64),
+            (DT_RELAENT, RELA_LEN as u64),
+        ];
+        for (idx, (tag, value)) in dynamic.iter().enumerate() {
+            put(&mut image, 0x200 + idx * DYN_LEN, &tag.to_le_bytes());
+            put(&mut image, 0x208 + idx * DYN_LEN, &value.to_le_bytes());
+        }
+        image
+    }
+
+    #[test]
+    fn elf_rejects_bad_headers() {
+        assert_eq!(Elf::parse(&[0u8; 16]).unwrap_err(), ElfError::Truncated);
+        assert_eq!(Elf::parse(&[0u8; 64]).unwrap_err(), ElfError::BadMagic);
+        let mut image = build_image();
+        image[4] = 1;
+        assert_eq!(Elf::parse(&image).unwrap_err(), ElfError::Unsupported);
+
+        // Program headers that would end past the address space
+        let mut image = build_image();
+        put(&mut image, 32, &(u64::MAX - 1).to_le_bytes());
+        let elf = Elf::parse(&image).unwrap();
+        assert!(matches!(elf.program_headers().next(), Some(Err(ElfError::Truncated))));
+    }
+
+    #[test]
+    fn elf_load_relocates() {
+        let image = build_image();
+        let elf = Elf::parse(&image).unwrap();
+        assert_eq!(elf.kind(), ElfType::Shared);
+
+        let base = 0x1000;
+        let mut memory = Memory {
+            data: vec![0; 0x2000],
+            mapped: Vec::new(),
+        };
+        let loaded = elf.load(&mut memory, base).unwrap();
+        assert_eq!(loaded.entry, VirtAddr::new(base + 0x10));
+        assert_eq!(loaded.start, VirtAddr::new(base));
+        assert_eq!(loaded.end, VirtAddr::new(base + 0x400));
+
+        assert_eq!(memory.mapped.len(), 2);
+        assert!(!memory.mapped[0].2.contains(PageTableFlags::NO_EXECUTE));
+        assert!(
+            memory.mapped[1]
+                .2
+                .contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+        );
+
+        let pointer = u64::from_le_bytes(memory.data[base + 0x100..base + 0x108].try_into().unwrap());
+        assert_eq!(pointer, (base + 0x10) as u64);
+    }
+}
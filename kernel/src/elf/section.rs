@@ -0,0 +1,156 @@
+//! Sections, symbols and relocations of relocatable objects
+
+use super::{Elf, ElfError, ElfType, SECTION_HEADER_LEN, field, read_u16, read_u32, read_u64};
+
+pub const SHT_NULL: u32 = 0;
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_RELA: u32 = 4;
+pub const SHT_NOBITS: u32 = 8;
+
+pub const SHN_UNDEF: u16 = 0;
+pub const SHN_ABS: u16 = 0xFFF1;
+pub const SHN_COMMON: u16 = 0xFFF2;
+
+pub const STB_LOCAL: u8 = 0;
+pub const STB_GLOBAL: u8 = 1;
+pub const STB_WEAK: u8 = 2;
+
+pub const STT_FUNC: u8 = 2;
+pub const STT_SECTION: u8 = 3;
+
+const SYMBOL_LEN: usize = 24;
+const RELA_LEN: usize = 24;
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct SectionFlags: u64 {
+        const WRITE = 1 << 0;
+        const ALLOC = 1 << 1;
+        const EXECINSTR = 1 << 2;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SectionHeader {
+    /// Offset of the name in the section header string table
+    pub name: u32,
+    pub kind: u32,
+    pub flags: SectionFlags,
+    pub offset: usize,
+    pub size: usize,
+    pub link: u32,
+    pub info: u32,
+    pub align: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub bind: u8,
+    pub kind: u8,
+    /// The index of the section the symbol is defined in, or one of the `SHN_*` values
+    pub shndx: u16,
+    pub value: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rela {
+    pub offset: usize,
+    pub kind: u32,
+    pub symbol: usize,
+    pub addend: i64,
+}
+
+impl<'a> Elf<'a> {
+    pub fn section_count(&self) -> usize {
+        self.shnum
+    }
+
+    pub fn section_header(&self, idx: usize) -> Result<SectionHeader, ElfError> {
+        if idx >= self.shnum {
+            return Err(ElfError::BadSection);
+        }
+        let off = self
+            .shoff
+            .checked_add(idx * self.shentsize)
+            .ok_or(ElfError::Truncated)?;
+        let header = field(self.bytes, off, SECTION_HEADER_LEN)?;
+        Ok(SectionHeader {
+            name: read_u32(header, 0)?,
+            kind: read_u32(header, 4)?,
+            flags: SectionFlags::from_bits_retain(read_u64(header, 8)? as u64),
+            offset: read_u64(header, 24)?,
+            size: read_u64(header, 32)?,
+            link: read_u32(header, 40)?,
+            info: read_u32(header, 44)?,
+            align: read_u64(header, 48)?,
+        })
+    }
+
+    pub fn section_headers(&self) -> impl Iterator<Item = Result<SectionHeader, ElfError>> + '_ {
+        (0..self.shnum).map(|idx| self.section_header(idx))
+    }
+
+    /// Returns the file contents of a section, which are empty for `SHT_NOBITS`
+    pub fn section_data(&self, sh: &SectionHeader) -> Result<&'a [u8], ElfError> {
+        if sh.kind == SHT_NOBITS {
+            return Ok(&[]);
+        }
+        let end = sh.offset.checked_add(sh.size).ok_or(ElfError::BadSection)?;
+        self.bytes.get(sh.offset..end).ok_or(ElfError::Truncated)
+    }
+
+    pub fn section_name(&self, sh: &SectionHeader) -> Result<&'a str, ElfError> {
+        let strtab = self.section_header(self.shstrndx)?;
+        string(self.section_data(&strtab)?, sh.name as usize)
+    }
+
+    /// Returns the symbols of a `SHT_SYMTAB` section, including the null symbol at index 0
+    pub fn symbols(
+        &self,
+        symtab: &SectionHeader,
+    ) -> Result<impl Iterator<Item = Result<Symbol<'a>, ElfError>>, ElfError> {
+        if symtab.kind != SHT_SYMTAB {
+            return Err(ElfError::BadSection);
+        }
+        let data = self.section_data(symtab)?;
+        let strings = self.section_data(&self.section_header(symtab.link as usize)?)?;
+        Ok(data.as_chunks::<SYMBOL_LEN>().0.iter().map(move |entry| {
+            let info = entry[4];
+            Ok(Symbol {
+                name: string(strings, read_u32(entry, 0)? as usize)?,
+                bind: info >> 4,
+                kind: info & 0xF,
+                shndx: read_u16(entry, 6)?,
+                value: read_u64(entry, 8)?,
+                size: read_u64(entry, 16)?,
+            })
+        }))
+    }
+
+    /// Returns the entries of a `SHT_RELA` section
+    pub fn relocations(&self, sh: &SectionHeader) -> Result<impl Iterator<Item = Rela> + 'a, ElfError> {
+        if sh.kind != SHT_RELA || self.kind != ElfType::Relocatable {
+            return Err(ElfError::BadSection);
+        }
+        Ok(self.section_data(sh)?.as_chunks::<RELA_LEN>().0.iter().map(|entry| {
+            let info = u64::from_le_bytes(entry[8..16].try_into().unwrap());
+            Rela {
+                offset: u64::from_le_bytes(entry[0..8].try_into().unwrap()) as usize,
+                kind: info as u32,
+                symbol: (info >> 32) as usize,
+                addend: i64::from_le_bytes(entry[16..24].try_into().unwrap()),
+            }
+        }))
+    }
+}
+
+/// Reads a NUL terminated string from a string table
+fn string(table: &[u8], off: usize) -> Result<&str, ElfError> {
+    let bytes = table.get(off..).ok_or(ElfError::BadSection)?;
+    let len = bytes.iter().position(|&b| b == 0).ok_or(ElfError::BadSection)?;
+    core::str::from_utf8(&bytes[..len]).map_err(|_| ElfError::BadSection)
+}
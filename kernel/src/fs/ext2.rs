@@ -0,0 +1,465 @@
+//! The ext2 file system, read-only
+//!
+//! Reads the superblock and group descriptors at mount, then looks up paths from the root
+//! directory and reads files through their block pointers, including the single, double and
+//! triple indirect ones. Holes read as zeros. File systems using features that change the on-disk
+//! layout, like the extents of ext4 or a journal that needs replaying, are refused.
+//!
+//! Everything is read through a [`ReadAt`], which the [block cache](crate::block::cache) implements.
+
+use core::fmt;
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+
+use crate::block::{BlockError, cache::BlockCache};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+pub const ROOT_INODE: u32 = 2;
+/// The direct block pointers of an inode, followed by the single, double and triple indirect ones
+const DIRECT_BLOCKS: usize = 12;
+/// The inode size of revision 0 file systems
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GROUP_DESC_SIZE: usize = 32;
+
+/// Directory entries have a file type byte
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+
+const S_IFMT: u16 = 0xF000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xA000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ext2Error {
+    Block(BlockError),
+    /// The superblock doesn't have the ext2 magic
+    BadMagic,
+    /// The file system uses incompatible features we don't support
+    Unsupported(u32),
+    /// A structure points outside of the file system or is inconsistent
+    Corrupt,
+    NotFound,
+    NotDirectory,
+}
+
+impl fmt::Display for Ext2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Block(err) => write!(f, "{}", err),
+            Self::BadMagic => f.write_str("not an ext2 file system"),
+            Self::Unsupported(features) => write!(f, "unsupported incompatible features {:#x}", features),
+            Self::Corrupt => f.write_str("file system is corrupt"),
+            Self::NotFound => f.write_str("no such file or directory"),
+            Self::NotDirectory => f.write_str("not a directory"),
+        }
+    }
+}
+
+impl core::error::Error for Ext2Error {}
+
+impl From<BlockError> for Ext2Error {
+    fn from(err: BlockError) -> Self {
+        Self::Block(err)
+    }
+}
+
+/// Storage that can be read at any byte offset
+pub trait ReadAt {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+}
+
+impl ReadAt for BlockCache {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let block_size = self.disk().block_size() as u64;
+        let start = offset / block_size;
+        let end = (offset + buf.len() as u64).div_ceil(block_size);
+        let skip = (offset % block_size) as usize;
+        if skip == 0 && (buf.len() as u64).is_multiple_of(block_size) {
+            return self.read(start, buf);
+        }
+        let mut blocks = vec![0; ((end - start) * block_size) as usize];
+        self.read(start, &mut blocks)?;
+        buf.copy_from_slice(&blocks[skip..skip + buf.len()]);
+        Ok(())
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for &T {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        (**self).read_at(offset, buf)
+    }
+}
+
+impl<T: ReadAt + ?Sized> ReadAt for Arc<T> {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        (**self).read_at(offset, buf)
+    }
+}
+
+impl ReadAt for [u8] {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let start = usize::try_from(offset).map_err(|_| BlockError::OutOfRange)?;
+        let bytes = self.get(start..start + buf.len()).ok_or(BlockError::OutOfRange)?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Superblock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub first_data_block: u32,
+    pub block_size: usize,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub inode_size: usize,
+    pub feature_incompat: u32,
+    pub volume_name: [u8; 16],
+}
+
+impl Superblock {
+    fn parse(bytes: &[u8]) -> Result<Self, Ext2Error> {
+        if u16_at(bytes, 56) != MAGIC {
+            return Err(Ext2Error::BadMagic);
+        }
+        let revision = u32_at(bytes, 76);
+        let (inode_size, feature_incompat) = match revision {
+            0 => (GOOD_OLD_INODE_SIZE, 0),
+            _ => (u16_at(bytes, 88) as usize, u32_at(bytes, 96)),
+        };
+        if feature_incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(Ext2Error::Unsupported(feature_incompat & !INCOMPAT_SUPPORTED));
+        }
+        let log_block_size = u32_at(bytes, 24);
+        let sb = Self {
+            inodes_count: u32_at(bytes, 0),
+            blocks_count: u32_at(bytes, 4),
+            first_data_block: u32_at(bytes, 20),
+            block_size: 1024usize.checked_shl(log_block_size).ok_or(Ext2Error::Corrupt)?,
+            blocks_per_group: u32_at(bytes, 32),
+            inodes_per_group: u32_at(bytes, 40),
+            inode_size,
+            feature_incompat,
+            volume_name: bytes[120..136].try_into().unwrap(),
+        };
+        if sb.block_size > 64 * 1024
+            || sb.blocks_count <= sb.first_data_block
+            || sb.blocks_per_group == 0
+            || sb.inodes_per_group == 0
+            || !(GOOD_OLD_INODE_SIZE..=sb.block_size).contains(&sb.inode_size)
+            || sb.inodes_count.div_ceil(sb.inodes_per_group) as usize != sb.group_count()
+        {
+            return Err(Ext2Error::Corrupt);
+        }
+        Ok(sb)
+    }
+
+    fn group_count(&self) -> usize {
+        (self.blocks_count - self.first_data_block).div_ceil(self.blocks_per_group) as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    Other,
+}
+
+impl FileType {
+    fn from_mode(mode: u16) -> Self {
+        match mode & S_IFMT {
+            S_IFREG => Self::Regular,
+            S_IFDIR => Self::Directory,
+            S_IFLNK => Self::Symlink,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Inode {
+    pub ino: u32,
+    pub mode: u16,
+    pub size: u64,
+    pub links: u16,
+    block: [u32; 15],
+}
+
+impl Inode {
+    pub fn file_type(&self) -> FileType {
+        FileType::from_mode(self.mode)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub ino: u32,
+    pub name: String,
+}
+
+/// A mounted ext2 file system
+#[derive(Debug)]
+pub struct Ext2<D> {
+    dev: D,
+    sb: Superblock,
+    /// The first block of the inode table of each group
+    inode_tables: Vec<u32>,
+}
+
+impl<D: ReadAt> Ext2<D> {
+    pub fn mount(dev: D) -> Result<Self, Ext2Error> {
+        let mut bytes = [0; SUPERBLOCK_SIZE];
+        dev.read_at(SUPERBLOCK_OFFSET, &mut bytes)?;
+        let sb = Superblock::parse(&bytes)?;
+
+        // The group descriptors start in the block after the superblock
+        let mut descs = vec![0; sb.group_count() * GROUP_DESC_SIZE];
+        dev.read_at((sb.first_data_block as u64 + 1) * sb.block_size as u64, &mut descs)?;
+        let inode_tables = descs
+            .as_chunks::<GROUP_DESC_SIZE>()
+            .0
+            .iter()
+            .map(|desc| u32_at(desc, 8))
+            .collect();
+        Ok(Self { dev, sb, inode_tables })
+    }
+
+    pub fn device(&self) -> &D {
+        &self.dev
+    }
+
+    pub fn superblock(&self) -> &Superblock {
+        &self.sb
+    }
+
+    fn read_block(&self, block: u32, offset: usize, buf: &mut [u8]) -> Result<(), Ext2Error> {
+        if block >= self.sb.blocks_count || offset + buf.len() > self.sb.block_size {
+            return Err(Ext2Error::Corrupt);
+        }
+        let block_size = self.sb.block_size as u64;
+        Ok(self.dev.read_at(block as u64 * block_size + offset as u64, buf)?)
+    }
+
+    pub fn inode(&self, ino: u32) -> Result<Inode, Ext2Error> {
+        if ino == 0 || ino > self.sb.inodes_count {
+            return Err(Ext2Error::NotFound);
+        }
+        let group = ((ino - 1) / self.sb.inodes_per_group) as usize;
+        let index = ((ino - 1) % self.sb.inodes_per_group) as usize;
+        let table = *self.inode_tables.get(group).ok_or(Ext2Error::Corrupt)?;
+        let per_block = self.sb.block_size / self.sb.inode_size;
+        let block = table + (index / per_block) as u32;
+
+        let mut bytes = [0; GOOD_OLD_INODE_SIZE];
+        self.read_block(block, (index % per_block) * self.sb.inode_size, &mut bytes)?;
+        let mode = u16_at(&bytes, 0);
+        let mut size = u32_at(&bytes, 4) as u64;
+        // Regular files keep the high half of their size where directories keep their ACL
+        if FileType::from_mode(mode) == FileType::Regular {
+            size |= (u32_at(&bytes, 108) as u64) << 32;
+        }
+        Ok(Inode {
+            ino,
+            mode,
+            size,
+            links: u16_at(&bytes, 26),
+            block: core::array::from_fn(|idx| u32_at(&bytes, 40 + idx * 4)),
+        })
+    }
+
+    /// Returns the block holding the `index`th block of a file, or 0 for a hole
+    fn block_of(&self, inode: &Inode, index: u64) -> Result<u32, Ext2Error> {
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok(inode.block[index as usize]);
+        }
+        let per_block = (self.sb.block_size / 4) as u64;
+        let mut index = index - DIRECT_BLOCKS as u64;
+        let mut span = 1;
+        for (level, &root) in inode.block[DIRECT_BLOCKS..].iter().enumerate() {
+            span *= per_block;
+            if index >= span {
+                index -= span;
+                continue;
+            }
+            let mut block = root;
+            let mut span = span;
+            for _ in 0..=level {
+                if block == 0 {
+                    return Ok(0);
+                }
+                span /= per_block;
+                let mut entry = [0; 4];
+                self.read_block(block, (index / span) as usize * 4, &mut entry)?;
+                block = u32::from_le_bytes(entry);
+                index %= span;
+            }
+            return Ok(block);
+        }
+        Err(Ext2Error::Corrupt)
+    }
+
+    /// Reads from a file at `offset`, returning the number of bytes read, which is only short at
+    /// the end of the file
+    pub fn read(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, Ext2Error> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = buf.len().min((inode.size - offset) as usize);
+        let block_size = self.sb.block_size as u64;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let start = (pos % block_size) as usize;
+            let count = (self.sb.block_size - start).min(len - done);
+            match self.block_of(inode, pos / block_size)? {
+                0 => buf[done..done + count].fill(0),
+                block => self.read_block(block, start, &mut buf[done..done + count])?,
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+
+    /// Returns the entries of a directory, including `.` and `..`
+    pub fn read_dir(&self, dir: &Inode) -> Result<Vec<DirEntry>, Ext2Error> {
+        if dir.file_type() != FileType::Directory {
+            return Err(Ext2Error::NotDirectory);
+        }
+        let mut data = vec![0; usize::try_from(dir.size).map_err(|_| Ext2Error::Corrupt)?];
+        self.read(dir, 0, &mut data)?;
+
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let ino = u32_at(&data, offset);
+            let rec_len = u16_at(&data, offset + 4) as usize;
+            // Without the file type feature, the high byte belongs to the name length
+            let name_len = match self.sb.feature_incompat & INCOMPAT_FILETYPE {
+                0 => u16_at(&data, offset + 6) as usize,
+                _ => data[offset + 6] as usize,
+            };
+            if rec_len < 8 || offset + rec_len > data.len() || 8 + name_len > rec_len {
+                return Err(Ext2Error::Corrupt);
+            }
+            // Deleted entries are kept with inode 0
+            if ino != 0 {
+                let name = &data[offset + 8..offset + 8 + name_len];
+                entries.push(DirEntry {
+                    ino,
+                    name: String::from_utf8_lossy(name).into_owned(),
+                });
+            }
+            offset += rec_len;
+        }
+        Ok(entries)
+    }
+
+    /// Looks up an absolute path, without following symlinks
+    pub fn lookup(&self, path: &str) -> Result<Inode, Ext2Error> {
+        let mut inode = self.inode(ROOT_INODE)?;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let entry = self
+                .read_dir(&inode)?
+                .into_iter()
+                .find(|entry| entry.name == name)
+                .ok_or(Ext2Error::NotFound)?;
+            inode = self.inode(entry.ino)?;
+        }
+        Ok(inode)
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 1024;
+
+    fn put_u16(image: &mut [u8], offset: usize, value: u16) {
+        image[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_dirent(image: &mut [u8], offset: usize, ino: u32, rec_len: u16, name: &str) {
+        put_u32(image, offset, ino);
+        put_u16(image, offset + 4, rec_len);
+        image[offset + 6] = name.len() as u8;
+        image[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    /// Builds a file system of 8 blocks: the superblock in block 1, the group descriptor in 2,
+    /// the inode table in 3, the root directory in 4, and a sparse file in 5 to 6
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; 8 * BLOCK];
+        let sb = BLOCK;
+        put_u32(&mut image, sb, 8);
+        put_u32(&mut image, sb + 4, 8);
+        put_u32(&mut image, sb + 20, 1);
+        put_u32(&mut image, sb + 32, 8192);
+        put_u32(&mut image, sb + 40, 8);
+        put_u16(&mut image, sb + 56, MAGIC);
+        put_u32(&mut image, sb + 76, 1);
+        put_u16(&mut image, sb + 88, 128);
+        put_u32(&mut image, sb + 96, INCOMPAT_FILETYPE);
+        put_u32(&mut image, 2 * BLOCK + 8, 3);
+
+        let inode = |ino: usize| 3 * BLOCK + (ino - 1) * 128;
+        put_u16(&mut image, inode(2), S_IFDIR | 0o755);
+        put_u32(&mut image, inode(2) + 4, BLOCK as u32);
+        put_u32(&mut image, inode(2) + 40, 4);
+        put_dirent(&mut image, 4 * BLOCK, 2, 12, ".");
+        put_dirent(&mut image, 4 * BLOCK + 12, 2, 12, "..");
+        put_dirent(&mut image, 4 * BLOCK + 24, 3, BLOCK as u16 - 24, "hello.txt");
+
+        // The direct blocks are holes, the data is behind the single indirect block
+        put_u16(&mut image, inode(3), S_IFREG | 0o644);
+        put_u32(&mut image, inode(3) + 4, (DIRECT_BLOCKS * BLOCK + 5) as u32);
+        put_u32(&mut image, inode(3) + 40 + DIRECT_BLOCKS * 4, 5);
+        put_u32(&mut image, 5 * BLOCK, 6);
+        image[6 * BLOCK..6 * BLOCK + 5].copy_from_slice(b"hello");
+        image
+    }
+
+    #[test]
+    fn ext2_read() {
+        let image = image();
+        let fs = Ext2::mount(&image[..]).unwrap();
+        let names: Vec<String> = fs
+            .read_dir(&fs.inode(ROOT_INODE).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, [".", "..", "hello.txt"]);
+
+        let file = fs.lookup("/hello.txt").unwrap();
+        assert_eq!(file.file_type(), FileType::Regular);
+        let mut buf = [0xFF; 16];
+        assert_eq!(fs.read(&file, (DIRECT_BLOCKS * BLOCK - 2) as u64, &mut buf), Ok(7));
+        assert_eq!(&buf[..7], b"\0\0hello");
+        assert_eq!(fs.lookup("/hello.txt/x"), Err(Ext2Error::NotDirectory));
+        assert_eq!(fs.lookup("/missing"), Err(Ext2Error::NotFound));
+
+        // A journal that needs recovery
+        let mut image = image;
+        put_u32(&mut image, BLOCK + 96, INCOMPAT_FILETYPE | 0x4);
+        assert_eq!(Ext2::mount(&image[..]).map(|_| ()), Err(Ext2Error::Unsupported(0x4)));
+    }
+}
@@ -0,0 +1,141 @@
+//! File systems
+//!
+//! There is no VFS yet. A [`Mount`] wraps one of the file systems we can read, so the root file
+//! system picked with `root=` can be used without knowing which one it is, see [`root`].
+
+use core::fmt;
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+
+use crate::{
+    block::cache::BlockCache,
+    fs::ext2::{Ext2, Ext2Error, FileType},
+    util::tar,
+};
+
+pub mod ext2;
+pub mod root;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    Ext2(Ext2Error),
+    NotFound,
+    NotDirectory,
+    IsDirectory,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ext2(err) => write!(f, "{}", err),
+            Self::NotFound => f.write_str("no such file or directory"),
+            Self::NotDirectory => f.write_str("not a directory"),
+            Self::IsDirectory => f.write_str("is a directory"),
+        }
+    }
+}
+
+impl core::error::Error for FsError {}
+
+impl From<Ext2Error> for FsError {
+    fn from(err: Ext2Error) -> Self {
+        match err {
+            Ext2Error::NotFound => Self::NotFound,
+            Ext2Error::NotDirectory => Self::NotDirectory,
+            err => Self::Ext2(err),
+        }
+    }
+}
+
+/// A mounted file system
+pub enum Mount {
+    /// The initramfs, a tar archive in memory
+    Ramdisk(&'static [u8]),
+    Ext2(Ext2<Arc<BlockCache>>),
+}
+
+impl fmt::Debug for Mount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ramdisk(archive) => write!(f, "Ramdisk({} bytes)", archive.len()),
+            Self::Ext2(fs) => write!(f, "Ext2({})", fs.device().disk().name()),
+        }
+    }
+}
+
+impl Mount {
+    /// Returns the name of the file system type, as given to `root=`
+    pub fn fs_type(&self) -> &'static str {
+        match self {
+            Self::Ramdisk(_) => "ramdisk",
+            Self::Ext2(_) => "ext2",
+        }
+    }
+
+    /// Reads a whole file
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, FsError> {
+        match self {
+            Self::Ramdisk(archive) => match tar::find(archive, path) {
+                Some(data) => Ok(data.to_vec()),
+                None if !list_archive(archive, path).is_empty() => Err(FsError::IsDirectory),
+                None => Err(FsError::NotFound),
+            },
+            Self::Ext2(fs) => {
+                let inode = fs.lookup(path)?;
+                if inode.file_type() == FileType::Directory {
+                    return Err(FsError::IsDirectory);
+                }
+                let mut data = vec![0; usize::try_from(inode.size).map_err(|_| FsError::Ext2(Ext2Error::Corrupt))?];
+                let len = fs.read(&inode, 0, &mut data)?;
+                data.truncate(len);
+                Ok(data)
+            }
+        }
+    }
+
+    /// Returns the names in a directory, without `.` and `..`
+    pub fn read_dir(&self, path: &str) -> Result<Vec<String>, FsError> {
+        match self {
+            Self::Ramdisk(archive) => match list_archive(archive, path) {
+                names if !names.is_empty() => Ok(names),
+                _ if tar::find(archive, path).is_some() => Err(FsError::NotDirectory),
+                _ => Err(FsError::NotFound),
+            },
+            Self::Ext2(fs) => {
+                let dir = fs.lookup(path)?;
+                Ok(fs
+                    .read_dir(&dir)?
+                    .into_iter()
+                    .map(|entry| entry.name)
+                    .filter(|name| name != "." && name != "..")
+                    .collect())
+            }
+        }
+    }
+}
+
+/// Lists a directory of an archive, which only has files, so directories are the prefixes of their
+/// paths
+fn list_archive(archive: &[u8], path: &str) -> Vec<String> {
+    let dir = path.trim_matches('/');
+    let mut names: Vec<String> = Vec::new();
+    for entry in tar::entries(archive) {
+        let entry_path = entry.path();
+        let rest = match dir.is_empty() {
+            true => Some(entry_path.as_str()),
+            false => entry_path.strip_prefix(dir).and_then(|rest| rest.strip_prefix('/')),
+        };
+        let Some(name) = rest.and_then(|rest| rest.split('/').next()) else {
+            continue;
+        };
+        if !names.iter().any(|existing| existing == name) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
@@ -0,0 +1,151 @@
+//! Mounting the root file system
+//!
+//! `root=` on the command line names the root file system: `ramdisk` for the initramfs, or a disk
+//! and optionally its type, like `root=/dev/nvme0n1,ext2`. The type defaults to ext2, the only one
+//! we can read from a disk so far. [`mount`] runs at the end of boot, once drivers are probed, and
+//! waits up to [`WAIT_NS`] for the disk to show up, or forever with `rootwait`.
+
+use core::fmt;
+
+use alloc::sync::Arc;
+
+use crate::{
+    block::{self, BlockError, Disk},
+    boot::Cmdline,
+    fs::{FsError, Mount, ext2::Ext2},
+    kprintln,
+    sync::Once,
+    time, workqueue,
+};
+
+/// How long to wait for the root disk without `rootwait`
+pub const WAIT_NS: u64 = 5_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsType {
+    Ext2,
+}
+
+/// Where the root file system is, as given to `root=`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootSpec<'a> {
+    Ramdisk,
+    Disk { name: &'a str, fs_type: FsType },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootError {
+    /// `root=` has no disk name
+    Invalid,
+    /// A file system type we can't mount
+    UnknownFs,
+    NoInitramfs,
+    /// The disk didn't show up in time
+    NoDisk,
+    Block(BlockError),
+    Fs(FsError),
+}
+
+impl fmt::Display for RootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid => f.write_str("invalid root= option"),
+            Self::UnknownFs => f.write_str("unknown file system type"),
+            Self::NoInitramfs => f.write_str("no initramfs"),
+            Self::NoDisk => f.write_str("disk not found"),
+            Self::Block(err) => write!(f, "{}", err),
+            Self::Fs(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl core::error::Error for RootError {}
+
+impl From<BlockError> for RootError {
+    fn from(err: BlockError) -> Self {
+        Self::Block(err)
+    }
+}
+
+impl From<FsError> for RootError {
+    fn from(err: FsError) -> Self {
+        Self::Fs(err)
+    }
+}
+
+static ROOT: Once<Mount> = Once::new();
+
+/// Parses the value of `root=`
+pub fn parse(value: &str) -> Result<RootSpec<'_>, RootError> {
+    if value == "ramdisk" {
+        return Ok(RootSpec::Ramdisk);
+    }
+    let (device, fs_type) = value.split_once(',').unwrap_or((value, "ext2"));
+    let name = device.strip_prefix("/dev/").unwrap_or(device);
+    if name.is_empty() {
+        return Err(RootError::Invalid);
+    }
+    let fs_type = match fs_type {
+        "ext2" => FsType::Ext2,
+        _ => return Err(RootError::UnknownFs),
+    };
+    Ok(RootSpec::Disk { name, fs_type })
+}
+
+/// Waits for a disk to be registered, running deferred work meanwhile so late probes can finish
+fn wait_for_disk(name: &str, forever: bool) -> Result<Arc<Disk>, RootError> {
+    let deadline = time::monotonic_ns() + WAIT_NS;
+    loop {
+        if let Some(disk) = block::get(name) {
+            return Ok(disk);
+        }
+        if !forever && time::monotonic_ns() >= deadline {
+            return Err(RootError::NoDisk);
+        }
+        workqueue::poll();
+        core::hint::spin_loop();
+    }
+}
+
+/// Mounts the root file system given by `root=`, doing nothing without it
+pub fn mount(cmdline: Cmdline) -> Result<(), RootError> {
+    let Some(value) = cmdline.get("root") else {
+        return Ok(());
+    };
+    let mount = match parse(value)? {
+        RootSpec::Ramdisk => Mount::Ramdisk(crate::boot::initramfs().ok_or(RootError::NoInitramfs)?),
+        RootSpec::Disk { name, fs_type } => {
+            let disk = wait_for_disk(name, cmdline.flag("rootwait"))?;
+            let cache = block::cache::cache(&disk)?;
+            match fs_type {
+                FsType::Ext2 => Mount::Ext2(Ext2::mount(cache).map_err(FsError::from)?),
+            }
+        }
+    };
+    kprintln!(Info, "fs: mounted {} as root from {}", mount.fs_type(), value);
+    ROOT.call_once(|| mount);
+    Ok(())
+}
+
+/// Returns the root file system, if one was mounted
+pub fn root() -> Option<&'static Mount> {
+    ROOT.get()
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_parse() {
+        assert_eq!(parse("ramdisk"), Ok(RootSpec::Ramdisk));
+        let disk = RootSpec::Disk {
+            name: "nvme0n1",
+            fs_type: FsType::Ext2,
+        };
+        assert_eq!(parse("/dev/nvme0n1"), Ok(disk));
+        assert_eq!(parse("nvme0n1,ext2"), Ok(disk));
+        assert_eq!(parse("/dev/vda1,fat32"), Err(RootError::UnknownFs));
+        assert_eq!(parse("/dev/,ext2"), Err(RootError::Invalid));
+    }
+}
@@ -0,0 +1,349 @@
+//! Interrupt request handling
+//!
+//! Vectors 32..=255 are routed through [`dispatch`] to handlers registered with [`request_irq`].
+//! The handler table is read under RCU, so handlers can be replaced with [`replace_irq`] or
+//! removed while interrupts keep arriving, without disabling interrupts on other CPUs.
+//! Every vector is counted per CPU, whether it has a handler or not, see [`stats`].
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::{
+    arch::instructions::interrupts,
+    dev::resource::{self, Resource, ResourceKind},
+    kprintln,
+    percpu::{self, MAX_CPUS, PerCpu},
+    sched::latency::Budget,
+    sync::{
+        Mutex, RwLock,
+        rcu::{self, RcuBox},
+    },
+};
+
+/// The first vector available for device interrupts
+pub const IRQ_VECTOR_START: u8 = 32;
+/// The number of vectors available for device interrupts
+pub const IRQ_VECTOR_COUNT: usize = 256 - IRQ_VECTOR_START as usize;
+
+/// A handler for an interrupt vector, called with the vector and the registered data
+pub type IrqHandler = fn(vector: u8, data: usize);
+
+/// Where the interrupts of a vector come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqSource {
+    /// Not known, the default until the owner calls [`set_source`]
+    Unknown,
+    /// A pin of an I/O APIC
+    IoApic { pin: u8 },
+    /// A message signalled interrupt, written by the device
+    Msi,
+    /// An inter-processor interrupt
+    Ipi,
+    /// A source in the local APIC, like its timer
+    Local,
+}
+
+impl fmt::Display for IrqSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown => f.write_str("-"),
+            Self::IoApic { pin } => write!(f, "IO-APIC {}", pin),
+            Self::Msi => f.write_str("MSI"),
+            Self::Ipi => f.write_str("IPI"),
+            Self::Local => f.write_str("LAPIC"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IrqAction {
+    pub name: &'static str,
+    pub handler: IrqHandler,
+    pub data: usize,
+    pub source: IrqSource,
+    /// The longest the handler should take, or zero, see [`set_budget`]
+    pub budget_ns: u64,
+}
+
+/// The interrupt counts of a vector
+#[derive(Debug, Clone)]
+pub struct IrqStats {
+    pub vector: u8,
+    /// The handler, if one is registered
+    pub action: Option<IrqAction>,
+    /// The count of each CPU, indexed by CPU id
+    pub counts: Vec<u64>,
+}
+
+impl IrqStats {
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The vector is reserved for CPU exceptions
+    InvalidVector,
+    /// The vector already has a handler registered
+    Busy,
+    /// There are no free vectors left
+    NoVectors,
+    /// The vector has no handler registered
+    NotRegistered,
+}
+
+impl fmt::Display for IrqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidVector => "invalid interrupt vector",
+            Self::Busy => "interrupt vector already in use",
+            Self::NoVectors => "no free interrupt vectors",
+            Self::NotRegistered => "no handler registered on the interrupt vector",
+        })
+    }
+}
+
+impl core::error::Error for IrqError {}
+
+static COUNTS: PerCpu<[AtomicU64; IRQ_VECTOR_COUNT]> =
+    PerCpu::new([const { [const { AtomicU64::new(0) }; IRQ_VECTOR_COUNT] }; MAX_CPUS]);
+/// The handler of every vector, replaced under [`UPDATE`] and read without locks by [`dispatch`]
+static ACTIONS: [RcuBox<IrqAction>; IRQ_VECTOR_COUNT] = [const { RcuBox::empty() }; IRQ_VECTOR_COUNT];
+/// Serializes changes to [`ACTIONS`]
+static UPDATE: Mutex<()> = Mutex::new(());
+/// The claims of the vectors with a handler in the resource tree
+static CLAIMS: Mutex<[Option<Resource>; IRQ_VECTOR_COUNT]> = Mutex::new([const { None }; IRQ_VECTOR_COUNT]);
+/// Acknowledges an interrupt at the interrupt controller
+static EOI: RwLock<Option<fn(u8)>> = RwLock::new(None);
+/// Where the current CPU was when the interrupt being handled arrived, zero outside of handlers
+static INTERRUPTED_IP: PerCpu<AtomicUsize> = PerCpu::new([const { AtomicUsize::new(0) }; MAX_CPUS]);
+
+fn index(vector: u8) -> Result<usize, IrqError> {
+    vector
+        .checked_sub(IRQ_VECTOR_START)
+        .map(|idx| idx as usize)
+        .ok_or(IrqError::InvalidVector)
+}
+
+/// Records who owns a vector in the resource tree, which can only go wrong if it is full
+fn claim(idx: usize, name: &'static str) {
+    let vector = idx as u64 + IRQ_VECTOR_START as u64;
+    match resource::claim(ResourceKind::Irq, vector..=vector, name) {
+        Ok(claim) => CLAIMS.lock()[idx] = Some(claim),
+        Err(err) => kprintln!(Warn, "irq: vector {} of {}: {}", vector, name, err),
+    }
+}
+
+/// Registers a handler for the given vector
+pub fn request_irq(vector: u8, name: &'static str, handler: IrqHandler, data: usize) -> Result<(), IrqError> {
+    let idx = index(vector)?;
+    let action = Box::new(IrqAction {
+        name,
+        handler,
+        data,
+        source: IrqSource::Unknown,
+        budget_ns: 0,
+    });
+    interrupts::without_interrupts(|| {
+        let _update = UPDATE.lock();
+        if ACTIONS[idx].is_some() {
+            return Err(IrqError::Busy);
+        }
+        // The slot was empty, so there is nothing to wait for
+        _ = ACTIONS[idx].swap(Some(action));
+        claim(idx, name);
+        Ok(())
+    })
+}
+
+/// Registers a handler on the first free vector, returning the vector
+pub fn request_any_irq(name: &'static str, handler: IrqHandler, data: usize) -> Result<u8, IrqError> {
+    let action = Box::new(IrqAction {
+        name,
+        handler,
+        data,
+        source: IrqSource::Unknown,
+        budget_ns: 0,
+    });
+    interrupts::without_interrupts(|| {
+        let _update = UPDATE.lock();
+        let idx = ACTIONS
+            .iter()
+            .position(|slot| !slot.is_some())
+            .ok_or(IrqError::NoVectors)?;
+        _ = ACTIONS[idx].swap(Some(action));
+        claim(idx, name);
+        Ok(idx as u8 + IRQ_VECTOR_START)
+    })
+}
+
+/// Replaces the handler of a vector with a new version of it, returning the old one
+///
+/// Interrupts keep being delivered while the handler is swapped: each goes to either the old or
+/// the new handler. Once this returns, the old handler has finished running on every CPU.
+pub fn replace_irq(vector: u8, name: &'static str, handler: IrqHandler, data: usize) -> Result<IrqAction, IrqError> {
+    let idx = index(vector)?;
+    update(idx, |old| {
+        Some(IrqAction {
+            name,
+            handler,
+            data,
+            source: old.source,
+            budget_ns: old.budget_ns,
+        })
+    })
+    .ok_or(IrqError::NotRegistered)
+}
+
+/// Records where the interrupts of a vector with a handler come from
+pub fn set_source(vector: u8, source: IrqSource) -> Result<(), IrqError> {
+    let idx = index(vector)?;
+    update(idx, |old| Some(IrqAction { source, ..*old }))
+        .map(drop)
+        .ok_or(IrqError::NotRegistered)
+}
+
+/// Sets the longest the handler of a vector should take, a warning is logged when it takes longer
+///
+/// Zero removes the budget. See [`crate::sched::latency`].
+pub fn set_budget(vector: u8, budget_ns: u64) -> Result<(), IrqError> {
+    let idx = index(vector)?;
+    update(idx, |old| Some(IrqAction { budget_ns, ..*old }))
+        .map(drop)
+        .ok_or(IrqError::NotRegistered)
+}
+
+/// Removes the handler for the given vector, returning it if there was one
+///
+/// Once this returns, the handler has finished running on every CPU, so its data can be freed.
+pub fn free_irq(vector: u8) -> Option<IrqAction> {
+    let idx = index(vector).ok()?;
+    let action = update(idx, |_| None)?;
+    CLAIMS.lock()[idx] = None;
+    Some(action)
+}
+
+/// Replaces the action of a vector with one derived from it, if there was one, and waits until
+/// no CPU runs the old one anymore
+///
+/// Must not be called from an interrupt handler, as it waits for all of them to finish.
+fn update(idx: usize, new: impl FnOnce(&IrqAction) -> Option<IrqAction>) -> Option<IrqAction> {
+    let retired = interrupts::without_interrupts(|| {
+        let _update = UPDATE.lock();
+        let old = ACTIONS[idx].get(&rcu::read_lock()).copied()?;
+        Some(ACTIONS[idx].swap(new(&old).map(Box::new)))
+    });
+    retired?.into_inner().map(|old| *old)
+}
+
+/// Returns the action registered for the given vector
+pub fn irq_action(vector: u8) -> Option<IrqAction> {
+    let idx = index(vector).ok()?;
+    ACTIONS[idx].get(&rcu::read_lock()).copied()
+}
+
+/// Sets the function used to signal end of interrupt to the interrupt controller
+pub fn set_eoi_handler(eoi: Option<fn(u8)>) {
+    interrupts::without_interrupts(|| *EOI.write() = eoi);
+}
+
+/// Returns the instruction the current CPU was interrupted at, while an interrupt handler runs
+pub fn interrupted_ip() -> Option<usize> {
+    Some(INTERRUPTED_IP.get().load(Ordering::Relaxed)).filter(|ip| *ip != 0)
+}
+
+/// Dispatches an interrupt to its registered handler, `ip` is where it arrived
+///
+/// This is called from the IDT stubs with interrupts disabled. Tasklets the handler scheduled run
+/// after the end of interrupt, see [`crate::workqueue`].
+pub fn dispatch(vector: u8, ip: usize) {
+    crate::trace!(IrqEntry, vector, ip);
+    if let Some(cpu) = percpu::try_current() {
+        cpu.stats.interrupts.fetch_add(1, Ordering::Relaxed);
+    }
+    count(vector);
+    let interrupted = INTERRUPTED_IP.get();
+    let previous_ip = interrupted.swap(ip, Ordering::Relaxed);
+    if let Ok(idx) = index(vector) {
+        // Held while the handler runs, so it can't be freed from under it
+        let guard = rcu::read_lock();
+        if let Some(action) = ACTIONS[idx].get(&guard) {
+            let _section = (action.budget_ns != 0).then(|| Budget::new(action.name, action.budget_ns).enter());
+            (action.handler)(vector, action.data);
+        }
+    }
+    if let Some(eoi) = *EOI.read() {
+        eoi(vector);
+    }
+    // Tasklets aren't the handler, and may be interrupted themselves
+    interrupted.store(previous_ip, Ordering::Relaxed);
+    crate::trace!(IrqExit, vector);
+    crate::workqueue::run_tasklets();
+}
+
+fn count(vector: u8) {
+    if let Ok(idx) = index(vector) {
+        COUNTS.get()[idx].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the counts of every vector that has a handler or has been raised
+pub fn stats() -> Vec<IrqStats> {
+    let guard = rcu::read_lock();
+    let cpus = percpu::cpus().map(|cpu| cpu.cpu_id as usize + 1).max().unwrap_or(1);
+    (0..IRQ_VECTOR_COUNT)
+        .filter_map(|idx| {
+            let counts: Vec<u64> = (0..cpus)
+                .map(|cpu_id| COUNTS.get_for(cpu_id)[idx].load(Ordering::Relaxed))
+                .collect();
+            let action = ACTIONS[idx].get(&guard).copied();
+            if action.is_none() && counts.iter().all(|count| *count == 0) {
+                return None;
+            }
+            Some(IrqStats {
+                vector: idx as u8 + IRQ_VECTOR_START,
+                action,
+                counts,
+            })
+        })
+        .collect()
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn irq_stats() {
+        fn handler(_vector: u8, _data: usize) {}
+        // Registering disables interrupts, which the host doesn't allow
+        let vector = 200;
+        _ = ACTIONS[index(vector).unwrap()].swap(Some(Box::new(IrqAction {
+            name: "test",
+            handler,
+            data: 0,
+            source: IrqSource::Msi,
+            budget_ns: 0,
+        })));
+        count(vector);
+        count(vector);
+        count(255);
+        count(3);
+
+        let stats = stats();
+        let ours = stats.iter().find(|stats| stats.vector == vector).unwrap();
+        assert_eq!(ours.total(), 2);
+        assert_eq!(ours.action.unwrap().source, IrqSource::Msi);
+        let spurious = stats.iter().find(|stats| stats.vector == 255).unwrap();
+        assert!(spurious.action.is_none() && spurious.total() == 1);
+        assert!(stats.iter().all(|stats| stats.vector >= IRQ_VECTOR_START));
+        assert_eq!(IrqSource::IoApic { pin: 4 }.to_string(), "IO-APIC 4");
+    }
+}
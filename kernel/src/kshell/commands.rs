@@ -0,0 +1,1074 @@
+use core::{
+    fmt,
+    net::Ipv4Addr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use alloc::string::ToString;
+
+use crate::{
+    arch::{VirtAddr, registers::control::Cr3},
+    block,
+    dev::{dot, drivers, pci},
+    fs::{
+        self,
+        ext2::{Ext2, Ext2Error},
+    },
+    irq,
+    kshell::Command,
+    mm::{self, page_table::KernelPageTable},
+    module,
+    net::{self, arp, dns, http, icmp, ipv4::Ipv4Cidr, route, tftp},
+    percpu,
+    sched::trace,
+    stats, time,
+};
+
+pub(super) static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help",
+        help: "list the available commands",
+        run: help,
+    },
+    Command {
+        name: "stats",
+        usage: "stats [provider | watch [interval_ms] [count]]",
+        help: "dump kernel statistics, or watch how fast the counters change",
+        run: stats,
+    },
+    Command {
+        name: "mem",
+        usage: "mem",
+        help: "show physical memory and heap usage",
+        run: mem,
+    },
+    Command {
+        name: "leaks",
+        usage: "leaks [mark | <since>]",
+        help: "list live heap allocations, or those made since a mark (alloc_debug builds)",
+        run: leaks,
+    },
+    Command {
+        name: "pt",
+        usage: "pt <addr> | pt <start> <end>",
+        help: "translate a virtual address, or list the mappings of a range",
+        run: pt,
+    },
+    Command {
+        name: "memcheck",
+        usage: "memcheck [heap | pt]",
+        help: "check the heap free list and the kernel page tables for corruption",
+        run: memcheck,
+    },
+    Command {
+        name: "lspci",
+        usage: "lspci [-v]",
+        help: "list PCI functions, with their BARs if verbose",
+        run: lspci,
+    },
+    Command {
+        name: "devtree",
+        usage: "devtree",
+        help: "send the buses, devices, drivers and resources over serial as a Graphviz graph",
+        run: devtree,
+    },
+    Command {
+        name: "display",
+        usage: "display [mode <width>x<height>[x<bpp>] | edid]",
+        help: "list displays and their modes, switch the first one to a mode, or show its EDID",
+        run: display,
+    },
+    Command {
+        name: "screenshot",
+        usage: "screenshot",
+        help: "send the framebuffer console over serial as a base64 PPM image",
+        run: screenshot,
+    },
+    Command {
+        name: "memdump",
+        usage: "memdump <phys|virt> <address> <length>",
+        help: "send a memory range over serial as Intel HEX",
+        run: memdump,
+    },
+    Command {
+        name: "ifconfig",
+        usage: "ifconfig [interface [up|down|addr/prefix]]",
+        help: "show or configure network interfaces",
+        run: ifconfig,
+    },
+    Command {
+        name: "arp",
+        usage: "arp [-d <address> | flush]",
+        help: "show or remove ARP cache entries",
+        run: arp,
+    },
+    Command {
+        name: "route",
+        usage: "route [add|del <dest> [via <gw>] [dev <if>] | flush]",
+        help: "show or change the IPv4 routing table",
+        run: route,
+    },
+    Command {
+        name: "dns",
+        usage: "dns [server <address> | <name>]",
+        help: "show or set the DNS server, or resolve a name",
+        run: dns,
+    },
+    Command {
+        name: "fetch",
+        usage: "fetch <url>",
+        help: "fetch a URL over HTTP",
+        run: fetch,
+    },
+    Command {
+        name: "tftp",
+        usage: "tftp [server <address> | get <file> | boot [file]]",
+        help: "show or set the boot server, or download a file over TFTP",
+        run: tftp,
+    },
+    Command {
+        name: "lsmod",
+        usage: "lsmod",
+        help: "list loaded modules",
+        run: lsmod,
+    },
+    Command {
+        name: "insmod",
+        usage: "insmod <file>",
+        help: "load a module passed by the bootloader, or downloaded from the boot server",
+        run: insmod,
+    },
+    Command {
+        name: "rmmod",
+        usage: "rmmod <name>",
+        help: "unload a module",
+        run: rmmod,
+    },
+    Command {
+        name: "cpu",
+        usage: "cpu [offline|online <id>]",
+        help: "list CPUs, or park a secondary CPU and bring it back",
+        run: cpu,
+    },
+    Command {
+        name: "clocks",
+        usage: "clocks [interval_ms]",
+        help: "compare how long an interval takes on every clock source",
+        run: clocks,
+    },
+    Command {
+        name: "irq",
+        usage: "irq",
+        help: "show interrupt counts per vector and CPU, with their handler and source",
+        run: irq,
+    },
+    Command {
+        name: "ping",
+        usage: "ping <address> [count]",
+        help: "send ICMP echo requests and report the round trip time",
+        run: ping,
+    },
+    Command {
+        name: "schedtrace",
+        usage: "schedtrace [on|off|clear]",
+        help: "trace scheduling events, or dump them with their wakeup latency",
+        run: schedtrace,
+    },
+    Command {
+        name: "profile",
+        usage: "profile [start|stop|clear]",
+        help: "sample where the tick interrupts, or show the flat profile of the samples",
+        run: profile,
+    },
+    Command {
+        name: "trace",
+        usage: "trace [on [event...]|off|clear|list]",
+        help: "trace all or some events, or dump the trace of all CPUs",
+        run: trace,
+    },
+    Command {
+        name: "sync",
+        usage: "sync",
+        help: "write back the block caches and flush the disks",
+        run: sync,
+    },
+    Command {
+        name: "ls",
+        usage: "ls [path]",
+        help: "list a directory of the root file system",
+        run: ls,
+    },
+    Command {
+        name: "cat",
+        usage: "cat <path>",
+        help: "print a file of the root file system",
+        run: cat,
+    },
+    Command {
+        name: "ext2",
+        usage: "ext2 <disk> ls|cat <path>",
+        help: "list a directory or print a file on an ext2 disk",
+        run: ext2,
+    },
+    Command {
+        name: "sysrq",
+        usage: "sysrq <key>",
+        help: "run a SysRq action, 'sysrq h' lists them",
+        run: sysrq,
+    },
+];
+
+fn help(_args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    for cmd in COMMANDS {
+        writeln!(out, "{:<32} {}", cmd.usage, cmd.help)?;
+    }
+    Ok(())
+}
+
+fn stats(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    if let Some((&"watch", args)) = args.split_first() {
+        return stats_watch(args, out);
+    }
+    match args.first() {
+        Some(name) => match stats::dump(name, out) {
+            Some(result) => result,
+            None => writeln!(
+                out,
+                "stats: unknown provider '{}', available: {:?}",
+                name,
+                stats::providers()
+            ),
+        },
+        None => stats::dump_all(out),
+    }
+}
+
+fn schedtrace(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    match args {
+        [] => trace::dump(out),
+        ["on"] => {
+            trace::set_enabled(true);
+            Ok(())
+        }
+        ["off"] => {
+            trace::set_enabled(false);
+            Ok(())
+        }
+        ["clear"] => {
+            trace::clear();
+            Ok(())
+        }
+        _ => writeln!(out, "usage: schedtrace [on|off|clear]"),
+    }
+}
+
+fn profile(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    match args {
+        [] => crate::profile::report(out),
+        ["start"] => {
+            crate::profile::start();
+            Ok(())
+        }
+        ["stop"] => {
+            crate::profile::stop();
+            Ok(())
+        }
+        ["clear"] => {
+            crate::profile::clear();
+            Ok(())
+        }
+        _ => writeln!(out, "usage: profile [start|stop|clear]"),
+    }
+}
+
+fn trace(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    use crate::tracepoint::{self, Event};
+
+    match args {
+        [] => tracepoint::dump(out),
+        ["on"] => {
+            tracepoint::start(&Event::ALL);
+            Ok(())
+        }
+        ["on", names @ ..] => {
+            let mut events = alloc::vec::Vec::new();
+            for name in names {
+                match Event::from_name(name) {
+                    Some(event) => events.push(event),
+                    None => return writeln!(out, "trace: no event '{}', see 'trace list'", name),
+                }
+            }
+            tracepoint::start(&events);
+            Ok(())
+        }
+        ["off"] => {
+            tracepoint::stop();
+            Ok(())
+        }
+        ["clear"] => {
+            tracepoint::clear();
+            Ok(())
+        }
+        ["list"] => {
+            for event in Event::ALL {
+                let enabled = tracepoint::is_enabled(event);
+                writeln!(
+                    out,
+                    "{:<14} {:<3} {}",
+                    event,
+                    if enabled { "on" } else { "off" },
+                    event.args().join(" ")
+                )?;
+            }
+            Ok(())
+        }
+        _ => writeln!(out, "usage: trace [on [event...]|off|clear|list]"),
+    }
+}
+
+fn sysrq(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let mut key = args.first().map_or("", |arg| arg).chars();
+    match (key.next(), key.next()) {
+        (Some(key), None) => super::sysrq::handle(key, out),
+        _ => writeln!(out, "usage: sysrq <key>"),
+    }
+}
+
+fn irq(_args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let stats = irq::stats();
+    let cpus = stats.first().map_or(1, |stats| stats.counts.len());
+    write!(out, "VEC")?;
+    for cpu_id in 0..cpus {
+        write!(out, " {:>10}", alloc::format!("cpu{}", cpu_id))?;
+    }
+    writeln!(out, "  {:<10} HANDLER", "SOURCE")?;
+    for stats in stats {
+        write!(out, "{:>3}", stats.vector)?;
+        for count in &stats.counts {
+            write!(out, " {:>10}", count)?;
+        }
+        match stats.action {
+            Some(action) => writeln!(out, "  {:<10} {}", action.source.to_string(), action.name)?,
+            None => writeln!(out, "  {:<10} (none)", irq::IrqSource::Unknown.to_string())?,
+        }
+    }
+    Ok(())
+}
+
+fn clocks(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    use crate::time::selftest;
+
+    let interval_ms = match args {
+        [] => 100,
+        [interval] => match interval.parse::<u64>() {
+            Ok(interval @ 1..=1000) => interval,
+            _ => return writeln!(out, "clocks: the interval must be 1 to 1000 ms"),
+        },
+        _ => return writeln!(out, "usage: clocks [interval_ms]"),
+    };
+    let measurements = match selftest::run(interval_ms * 1_000_000) {
+        Ok(measurements) => measurements,
+        Err(err) => return writeln!(out, "clocks: {}", err),
+    };
+    let current = time::current_clocksource().map(|source| source.name);
+    writeln!(
+        out,
+        "{:<14} {:>6} {:>14} {:>10}",
+        "SOURCE", "RATING", "ELAPSED (ns)", "PPM"
+    )?;
+    for measurement in measurements {
+        let source = measurement.source;
+        writeln!(
+            out,
+            "{:<14} {:>6} {:>14} {:>10}{}{}",
+            source.name,
+            source.rating,
+            measurement.elapsed_ns,
+            measurement.deviation_ppm,
+            if current == Some(source.name) { "  current" } else { "" },
+            if measurement.diverges() { "  DIVERGES" } else { "" }
+        )?;
+    }
+    Ok(())
+}
+
+fn sync(_args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    match block::cache::sync() {
+        Ok(()) => Ok(()),
+        Err(err) => writeln!(out, "sync: {}", err),
+    }
+}
+
+fn ls(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let Some(root) = fs::root::root() else {
+        return writeln!(out, "ls: no root file system, see root=");
+    };
+    let path = args.first().copied().unwrap_or("/");
+    match root.read_dir(path) {
+        Ok(names) => names.iter().try_for_each(|name| writeln!(out, "{}", name)),
+        Err(err) => writeln!(out, "ls: {}: {}", path, err),
+    }
+}
+
+fn cat(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let Some(root) = fs::root::root() else {
+        return writeln!(out, "cat: no root file system, see root=");
+    };
+    let [path] = args else {
+        return writeln!(out, "usage: cat <path>");
+    };
+    match root.read(path) {
+        Ok(data) => write_lossy(&data, out),
+        Err(err) => writeln!(out, "cat: {}: {}", path, err),
+    }
+}
+
+/// Writes bytes as text, replacing invalid UTF-8
+fn write_lossy(bytes: &[u8], out: &mut dyn fmt::Write) -> fmt::Result {
+    for chunk in bytes.utf8_chunks() {
+        out.write_str(chunk.valid())?;
+        if !chunk.invalid().is_empty() {
+            out.write_char('\u{FFFD}')?;
+        }
+    }
+    Ok(())
+}
+
+fn ext2(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let &[disk, op @ ("ls" | "cat"), path] = args else {
+        return writeln!(out, "usage: ext2 <disk> ls|cat <path>");
+    };
+    let Some(disk) = block::get(disk) else {
+        return writeln!(out, "ext2: no disk '{}'", disk);
+    };
+    let fs = match block::cache::cache(&disk)
+        .map_err(Ext2Error::from)
+        .and_then(Ext2::mount)
+    {
+        Ok(fs) => fs,
+        Err(err) => return writeln!(out, "ext2: {}: {}", disk.name(), err),
+    };
+    let inode = match fs.lookup(path) {
+        Ok(inode) => inode,
+        Err(err) => return writeln!(out, "ext2: {}: {}", path, err),
+    };
+    if op == "ls" {
+        let entries = match fs.read_dir(&inode) {
+            Ok(entries) => entries,
+            Err(err) => return writeln!(out, "ext2: {}: {}", path, err),
+        };
+        for entry in entries {
+            match fs.inode(entry.ino) {
+                Ok(inode) => writeln!(out, "{:>8} {:>10} {}", entry.ino, inode.size, entry.name)?,
+                Err(err) => writeln!(out, "{:>8} {:>10} {} ({})", entry.ino, "?", entry.name, err)?,
+            }
+        }
+        return Ok(());
+    }
+
+    let mut buf = [0; 512];
+    let mut offset = 0;
+    loop {
+        let len = match fs.read(&inode, offset, &mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(err) => return writeln!(out, "\next2: {}: {}", path, err),
+        };
+        write_lossy(&buf[..len], out)?;
+        offset += len as u64;
+    }
+}
+
+fn mem(_args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(out, "{}", mm::stats())
+}
+
+/// Prints the rates of all counters every interval, driving the network stack in between
+fn stats_watch(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let (interval_ms, count) = match args {
+        [] => (Ok(1000), Ok(5)),
+        [interval] => (interval.parse::<u64>(), Ok(5)),
+        [interval, count] => (interval.parse(), count.parse::<u32>()),
+        _ => return writeln!(out, "usage: stats watch [interval_ms] [count]"),
+    };
+    let (Ok(interval_ms @ 1..=60_000), Ok(count)) = (interval_ms, count) else {
+        return writeln!(out, "stats: the interval must be 1 to 60000 ms, and the count a number");
+    };
+    let mut last = stats::snapshot::Snapshot::take();
+    for _ in 0..count {
+        net::poll_until(interval_ms * 1_000_000, || None::<()>);
+        let now = stats::snapshot::Snapshot::take();
+        write!(out, "{}", now.delta(&last))?;
+        last = now;
+    }
+    Ok(())
+}
+
+fn leaks(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let since = match args {
+        [] => 0,
+        ["mark"] => return writeln!(out, "mark: {}", mm::alloc_debug::mark()),
+        [since] => match since.parse() {
+            Ok(since) => since,
+            Err(_) => return writeln!(out, "leaks: invalid mark '{}'", since),
+        },
+        _ => return writeln!(out, "usage: leaks [mark | <since>]"),
+    };
+    mm::alloc_debug::dump_since(since, out)
+}
+
+fn pt(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let parse = |arg: &str| usize::from_str_radix(arg.trim_start_matches("0x"), 16).ok();
+    let page_table = KernelPageTable::new(Cr3::addr());
+    match args {
+        [addr] => {
+            let Some(addr) = parse(addr).and_then(|addr| VirtAddr::try_new(addr).ok()) else {
+                return writeln!(out, "pt: invalid address '{}'", addr);
+            };
+            match page_table.translate(addr) {
+                Some((phys, size, flags)) => writeln!(out, "{:?} -> {:?} ({} page, {:?})", addr, phys, size, flags),
+                None => writeln!(out, "{:?} is not mapped", addr),
+            }
+        }
+        [start, end] => {
+            let (Some(start), Some(end)) = (parse(start), parse(end)) else {
+                return writeln!(out, "pt: invalid range");
+            };
+            page_table.dump_range(VirtAddr::new_truncate(start), VirtAddr::new_truncate(end), out)
+        }
+        _ => writeln!(out, "usage: pt <addr> | pt <start> <end>"),
+    }
+}
+
+fn memcheck(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let (heap, pt) = match args {
+        [] => (true, true),
+        ["heap"] => (true, false),
+        ["pt"] => (false, true),
+        _ => return writeln!(out, "usage: memcheck [heap | pt]"),
+    };
+    let mut violations = 0;
+    if heap {
+        let (check, found) = mm::check::heap();
+        for violation in &found {
+            writeln!(out, "heap: {}", violation)?;
+        }
+        if check.violations > found.len() {
+            writeln!(out, "heap: {} more violations", check.violations - found.len())?;
+        }
+        writeln!(
+            out,
+            "heap: {} holes, {} KiB free, largest {} KiB",
+            check.holes,
+            check.free / 1024,
+            check.largest / 1024
+        )?;
+        violations += check.violations;
+    }
+    if pt {
+        let found = mm::check::page_tables(&KernelPageTable::new(Cr3::addr()));
+        for violation in &found {
+            writeln!(out, "pt: {}", violation)?;
+        }
+        violations += found.len();
+    }
+    match violations {
+        0 => writeln!(out, "memcheck: no violations"),
+        violations => writeln!(out, "memcheck: {} violations", violations),
+    }
+}
+
+fn lspci(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let verbose = match args {
+        [] => false,
+        ["-v"] => true,
+        _ => return writeln!(out, "usage: lspci [-v]"),
+    };
+    for dev in pci::devices() {
+        match drivers::pci::bound_driver(dev.addr) {
+            Some(drv) => writeln!(out, "{} {}, driver {}", dev, dev.device_class(), drv)?,
+            None => writeln!(out, "{} {}", dev, dev.device_class())?,
+        }
+        if verbose {
+            for bar in (0..dev.bar_count()).filter_map(|idx| dev.bar(idx).map(|bar| (idx, bar))) {
+                writeln!(out, "    BAR{}: {}", bar.0, bar.1)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn devtree(_args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    match dot::dump_serial() {
+        Ok(nodes) => writeln!(out, "devtree: sent {} nodes over serial", nodes),
+        Err(err) => writeln!(out, "devtree: {}", err),
+    }
+}
+
+fn display(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    if let ["mode", mode] = args {
+        let mut parts = mode.split('x').map(str::parse::<u32>);
+        let (Some(Ok(width)), Some(Ok(height)), bpp, None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return writeln!(out, "usage: display mode <width>x<height>[x<bpp>]");
+        };
+        let Ok(bpp) = bpp.unwrap_or(Ok(32)) else {
+            return writeln!(out, "usage: display mode <width>x<height>[x<bpp>]");
+        };
+        return match crate::display::set_mode(width, height, bpp) {
+            Ok(mode) => writeln!(out, "display: {}", mode),
+            Err(err) => writeln!(out, "display: {}", err),
+        };
+    }
+    if let ["edid"] = args {
+        let Some(edid) = crate::display::edid() else {
+            return writeln!(out, "display: no EDID");
+        };
+        writeln!(
+            out,
+            "{} {:04x}, EDID {}.{}",
+            edid.manufacturer(),
+            edid.product,
+            edid.version.0,
+            edid.version.1
+        )?;
+        if let Some((width, height)) = edid.native_mode() {
+            writeln!(out, "native: {}x{}", width, height)?;
+        }
+        for mode in &edid.modes {
+            writeln!(out, "  {}", mode)?;
+        }
+        return Ok(());
+    }
+    let displays = crate::display::displays();
+    if displays.is_empty() {
+        return writeln!(out, "no displays");
+    }
+    for display in displays {
+        writeln!(out, "{}: {}", display.name(), display.mode())?;
+    }
+    Ok(())
+}
+
+fn screenshot(_args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    use crate::display::capture;
+
+    let result = capture::capture().and_then(|shot| {
+        capture::dump_serial(&shot)?;
+        Ok(shot)
+    });
+    match result {
+        Ok(shot) => writeln!(
+            out,
+            "screenshot: sent {}x{} image ({} bytes) over serial",
+            shot.width,
+            shot.height,
+            shot.ppm.len()
+        ),
+        Err(err) => writeln!(out, "screenshot: {}", err),
+    }
+}
+
+/// Parses a number, in hex if prefixed with `0x`
+fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(&hex.replace('_', ""), 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn memdump(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    use crate::mm::memdump::{self, AddressKind};
+
+    let [kind, addr, len] = args else {
+        return writeln!(out, "usage: memdump <phys|virt> <address> <length>");
+    };
+    let kind = match *kind {
+        "phys" => AddressKind::Physical,
+        "virt" => AddressKind::Virtual,
+        _ => return writeln!(out, "memdump: unknown address kind '{}'", kind),
+    };
+    let (Some(addr), Some(len)) = (parse_number(addr), parse_number(len)) else {
+        return writeln!(out, "memdump: invalid number");
+    };
+    match memdump::dump_serial(kind, addr, len) {
+        Ok(summary) => writeln!(
+            out,
+            "memdump: sent {} bytes over serial, skipped {} unmapped bytes",
+            summary.dumped, summary.skipped
+        ),
+        Err(err) => writeln!(out, "memdump: {}", err),
+    }
+}
+
+fn ifconfig(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let Some(name) = args.first() else {
+        for iface in net::interfaces() {
+            net::write_interface(out, &iface)?;
+        }
+        return Ok(());
+    };
+
+    let Some(iface) = net::interface(name) else {
+        return writeln!(out, "ifconfig: no such interface '{}'", name);
+    };
+    match args.get(1).copied() {
+        None => net::write_interface(out, &iface),
+        Some("up") => {
+            iface.set_up(true);
+            Ok(())
+        }
+        Some("down") => {
+            iface.set_up(false);
+            Ok(())
+        }
+        Some(action) => match action.parse::<Ipv4Cidr>() {
+            Ok(addr) => {
+                iface.set_ipv4(Some(addr));
+                Ok(())
+            }
+            Err(_) => writeln!(out, "ifconfig: unknown action '{}'", action),
+        },
+    }
+}
+
+fn arp(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    match args {
+        [] => {
+            let now = time::monotonic_ns();
+            writeln!(out, "{:<16} {:<18} {:<8} Age", "Address", "HWaddress", "Iface")?;
+            for entry in arp::entries() {
+                let age_secs = now.saturating_sub(entry.updated_ns) / 1_000_000_000;
+                writeln!(
+                    out,
+                    "{:<16} {:<18} {:<8} {}s",
+                    entry.ip, entry.mac, entry.iface, age_secs
+                )?;
+            }
+            writeln!(out, "{} packets waiting for resolution", arp::pending())
+        }
+        ["-d", addr] => match addr.parse::<Ipv4Addr>() {
+            Ok(ip) if arp::remove(ip) => Ok(()),
+            Ok(ip) => writeln!(out, "arp: no entry for {}", ip),
+            Err(_) => writeln!(out, "arp: invalid address '{}'", addr),
+        },
+        ["flush"] => writeln!(out, "arp: removed {} entries", arp::flush()),
+        _ => writeln!(out, "usage: arp [-d <address> | flush]"),
+    }
+}
+
+/// Parses a route destination, which is either `default` or `a.b.c.d/prefix`
+fn parse_dest(dest: &str) -> Option<Ipv4Cidr> {
+    match dest {
+        "default" => Some(Ipv4Cidr::new(Ipv4Addr::UNSPECIFIED, 0)),
+        dest => dest.parse().ok(),
+    }
+}
+
+fn route(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    const USAGE: &str = "usage: route [add|del <dest> [via <gw>] [dev <if>] | flush]";
+
+    match args {
+        [] => {
+            for route in route::routes() {
+                writeln!(out, "{}", route)?;
+            }
+            Ok(())
+        }
+        ["flush"] => writeln!(out, "route: removed {} static routes", route::flush()),
+        ["del", dest] => match parse_dest(dest).map(route::remove) {
+            Some(Ok(_)) => Ok(()),
+            Some(Err(err)) => writeln!(out, "route: {}", err),
+            None => writeln!(out, "route: invalid destination '{}'", dest),
+        },
+        ["add", dest, options @ ..] => {
+            let Some(dest) = parse_dest(dest) else {
+                return writeln!(out, "route: invalid destination '{}'", dest);
+            };
+            let (mut gateway, mut iface) = (None, None);
+            for option in options.chunks(2) {
+                match option {
+                    ["via", addr] => match addr.parse::<Ipv4Addr>() {
+                        Ok(addr) => gateway = Some(addr),
+                        Err(_) => return writeln!(out, "route: invalid gateway '{}'", addr),
+                    },
+                    ["dev", name] => iface = Some(*name),
+                    _ => return writeln!(out, "{}", USAGE),
+                }
+            }
+            match route::add(dest, gateway, iface) {
+                Ok(route) => writeln!(out, "{}", route),
+                Err(err) => writeln!(out, "route: {}", err),
+            }
+        }
+        _ => writeln!(out, "{}", USAGE),
+    }
+}
+
+fn dns(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    match args {
+        [] => match dns::server() {
+            Some(server) => writeln!(out, "server {}", server),
+            None => writeln!(out, "no server configured"),
+        },
+        ["server", addr] => match addr.parse::<Ipv4Addr>() {
+            Ok(addr) => {
+                dns::set_server(Some(addr));
+                Ok(())
+            }
+            Err(_) => writeln!(out, "dns: invalid address '{}'", addr),
+        },
+        [name] => match dns::resolve(name) {
+            Ok(addr) => writeln!(out, "{} has address {}", name, addr),
+            Err(err) => writeln!(out, "dns: {}: {}", name, err),
+        },
+        _ => writeln!(out, "usage: dns [server <address> | <name>]"),
+    }
+}
+
+/// Bodies up to this size are printed if they are text
+const FETCH_PRINT_LIMIT: usize = 4096;
+
+fn fetch(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let [url] = args else {
+        return writeln!(out, "usage: fetch <url>");
+    };
+    let start = time::monotonic_ns();
+    let response = match http::get(url) {
+        Ok(response) => response,
+        Err(err) => return writeln!(out, "fetch: {}", err),
+    };
+    let elapsed_ms = (time::monotonic_ns() - start) / 1_000_000;
+
+    writeln!(out, "HTTP {} {}", response.status, response.reason)?;
+    for (key, value) in &response.headers {
+        writeln!(out, "{}: {}", key, value)?;
+    }
+    writeln!(out, "\n{} bytes in {} ms", response.body.len(), elapsed_ms)?;
+    if response.body.len() <= FETCH_PRINT_LIMIT
+        && let Ok(text) = core::str::from_utf8(&response.body)
+    {
+        writeln!(out, "{}", text)?;
+    }
+    Ok(())
+}
+
+fn tftp(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    match args {
+        [] => {
+            match tftp::server() {
+                Some(server) => writeln!(out, "server {}", server)?,
+                None => writeln!(out, "no server configured")?,
+            }
+            match tftp::boot_payload() {
+                Some(payload) => writeln!(out, "boot payload: {} bytes", payload.len()),
+                None => writeln!(out, "no boot payload"),
+            }
+        }
+        ["server", addr] => match addr.parse::<Ipv4Addr>() {
+            Ok(addr) => {
+                tftp::set_server(Some(addr));
+                Ok(())
+            }
+            Err(_) => writeln!(out, "tftp: invalid address '{}'", addr),
+        },
+        ["get", file] => {
+            let Some(server) = tftp::server() else {
+                return writeln!(out, "tftp: {}", tftp::TftpError::NoServer);
+            };
+            let start = time::monotonic_ns();
+            match tftp::fetch(server, file) {
+                Ok(data) => writeln!(
+                    out,
+                    "{}: {} bytes in {} ms",
+                    file,
+                    data.len(),
+                    (time::monotonic_ns() - start) / 1_000_000
+                ),
+                Err(err) => writeln!(out, "tftp: {}: {}", file, err),
+            }
+        }
+        ["boot", file @ ..] if file.len() <= 1 => match tftp::netboot(file.first().copied()) {
+            Ok(Some(payload)) => writeln!(out, "boot payload: {} bytes", payload.len()),
+            Ok(None) => writeln!(out, "tftp: {}", tftp::TftpError::NoServer),
+            Err(err) => writeln!(out, "tftp: {}", err),
+        },
+        _ => writeln!(out, "usage: tftp [server <address> | get <file> | boot [file]]"),
+    }
+}
+
+fn lsmod(_args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(
+        out,
+        "{:<24} {:>8} {:>8} {:>4} {:>5} Caps",
+        "Module", "Size", "Drivers", "Used", "ABI"
+    )?;
+    for module in module::modules() {
+        writeln!(
+            out,
+            "{:<24} {:>8} {:>8} {:>4} {:>5} {:?}",
+            module.name(),
+            module.size(),
+            module.driver_count(),
+            module.refcount(),
+            module.abi().to_string(),
+            module.caps()
+        )?;
+    }
+    Ok(())
+}
+
+fn insmod(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let [file] = args else {
+        return writeln!(out, "usage: insmod <file>");
+    };
+    // Modules loaded by the bootloader are used before fetching over TFTP
+    let boot_module = crate::boot::modules()
+        .iter()
+        .find(|module| module.path() == *file || module.name() == *file);
+    let result = match boot_module {
+        Some(boot_module) => module::load(boot_module.data()),
+        None => {
+            let Some(server) = tftp::server() else {
+                return writeln!(out, "insmod: {}", tftp::TftpError::NoServer);
+            };
+            let bytes = match tftp::fetch(server, file) {
+                Ok(bytes) => bytes,
+                Err(err) => return writeln!(out, "insmod: {}: {}", file, err),
+            };
+            module::load(&bytes)
+        }
+    };
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) => writeln!(out, "insmod: {}: {}", file, err),
+    }
+}
+
+fn rmmod(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    match args {
+        [name] => match module::unload(name) {
+            Ok(()) => Ok(()),
+            Err(err) => writeln!(out, "rmmod: {}: {}", name, err),
+        },
+        _ => writeln!(out, "usage: rmmod <name>"),
+    }
+}
+
+fn cpu(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    let (online, id) = match args {
+        [] => {
+            for cpu in percpu::cpus() {
+                writeln!(out, "cpu{}: apic_id={} {}", cpu.cpu_id, cpu.apic_id, cpu.state())?;
+            }
+            if percpu::cpu_limit() < percpu::MAX_CPUS {
+                return writeln!(
+                    out,
+                    "{} online, limited to {}",
+                    percpu::online_cpus(),
+                    percpu::cpu_limit()
+                );
+            }
+            return writeln!(out, "{} online", percpu::online_cpus());
+        }
+        ["offline", id] => (false, id),
+        ["online", id] => (true, id),
+        _ => return writeln!(out, "usage: cpu [offline|online <id>]"),
+    };
+    let Ok(id) = id.parse() else {
+        return writeln!(out, "cpu: invalid CPU id '{}'", id);
+    };
+    let result = if online {
+        percpu::online(id)
+    } else {
+        percpu::offline(id)
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => writeln!(out, "cpu: cpu{}: {}", id, err),
+    }
+}
+
+/// The number of data bytes in each echo request, matching the usual `ping` default
+const PING_DATA_LEN: usize = 56;
+const PING_INTERVAL_NS: u64 = 1_000_000_000;
+
+fn ping(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result {
+    static NEXT_IDENT: AtomicU16 = AtomicU16::new(1);
+
+    let Some(Ok(dst)) = args.first().map(|arg| arg.parse::<Ipv4Addr>()) else {
+        return writeln!(out, "usage: ping <address> [count]");
+    };
+    let count = match args.get(1).map(|arg| arg.parse::<u16>()) {
+        None => 4,
+        Some(Ok(count)) => count,
+        Some(Err(_)) => return writeln!(out, "ping: invalid count"),
+    };
+
+    let ident = NEXT_IDENT.fetch_add(1, Ordering::Relaxed);
+    let data: [u8; PING_DATA_LEN] = core::array::from_fn(|idx| idx as u8);
+    writeln!(out, "PING {} {} data bytes", dst, PING_DATA_LEN)?;
+
+    let mut received = 0;
+    let (mut min_ns, mut max_ns, mut total_ns) = (u64::MAX, 0, 0);
+    for seq in 1..=count {
+        let sent_ns = time::monotonic_ns();
+        if let Err(err) = icmp::send_echo_request(dst, ident, seq, &data) {
+            return writeln!(out, "ping: {}", err);
+        }
+
+        // Wait out the whole interval even after a reply, so we don't flood the target
+        let mut reply = None;
+        net::poll_until(PING_INTERVAL_NS, || {
+            if reply.is_none() {
+                reply = icmp::take_echo_reply(ident, seq);
+            }
+            (reply.is_some() && seq == count).then_some(())
+        });
+
+        match reply {
+            Some(reply) => {
+                let rtt_ns = reply.received_ns - sent_ns;
+                received += 1;
+                min_ns = min_ns.min(rtt_ns);
+                max_ns = max_ns.max(rtt_ns);
+                total_ns += rtt_ns;
+                writeln!(
+                    out,
+                    "{} bytes from {}: icmp_seq={} ttl={} time={}.{:03} ms",
+                    reply.len,
+                    reply.src,
+                    seq,
+                    reply.ttl,
+                    rtt_ns / 1_000_000,
+                    rtt_ns / 1_000 % 1_000
+                )?;
+            }
+            None => writeln!(out, "request timeout for icmp_seq={}", seq)?,
+        }
+    }
+
+    writeln!(out, "--- {} ping statistics ---", dst)?;
+    writeln!(
+        out,
+        "{} packets transmitted, {} received, {}% packet loss",
+        count,
+        received,
+        (count - received) as u32 * 100 / count.max(1) as u32
+    )?;
+    if received > 0 {
+        let avg_ns = total_ns / received as u64;
+        writeln!(
+            out,
+            "rtt min/avg/max = {}.{:03}/{}.{:03}/{}.{:03} ms",
+            min_ns / 1_000_000,
+            min_ns / 1_000 % 1_000,
+            avg_ns / 1_000_000,
+            avg_ns / 1_000 % 1_000,
+            max_ns / 1_000_000,
+            max_ns / 1_000 % 1_000
+        )?;
+    }
+    Ok(())
+}
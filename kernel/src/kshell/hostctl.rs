@@ -0,0 +1,231 @@
+//! Host control channel
+//!
+//! A line based protocol on the first virtio console, for test scripts on the host that need more
+//! than the log. Every request is one line, and every reply is any number of output lines
+//! prefixed with `| `, followed by `OK` or `ERR <reason>`.
+//!
+//! ```text
+//! version          the kernel version, commit and build date, and the module ABI version
+//! selftest         runs quick checks of the heap, frame allocator, clock, mappings and stacks
+//! stats [provider] dumps kernel statistics
+//! panic [message]  replies OK and panics
+//! sysrq <key>      runs a SysRq action, the ones that break the kernel never reply
+//! ```
+//!
+//! With QEMU, the channel is a `virtconsole` whose chardev is a socket, see `docs/src/Features.md`.
+
+use core::fmt::{self, Write};
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{
+    arch::registers::control::Cr3,
+    dev::virtio::console::{self, VirtioConsole},
+    kshell::sysrq,
+    mm::{self, FRAME_ALLOCATOR, page_table::KernelPageTable, wx},
+    module::abi::ABI_VERSION,
+    sync::Mutex,
+    time,
+    util::build_info,
+};
+
+/// The longest request that is accepted
+const MAX_LINE: usize = 256;
+
+static LINE: Mutex<String> = Mutex::new(String::new());
+
+/// Prefixes every line written through it
+struct Output<'a> {
+    out: &'a mut dyn Write,
+    line_start: bool,
+}
+
+impl Write for Output<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for line in s.split_inclusive('\n') {
+            if self.line_start {
+                self.out.write_str("| ")?;
+            }
+            self.out.write_str(line)?;
+            self.line_start = line.ends_with('\n');
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Request<'a> {
+    Version,
+    Selftest,
+    Stats(Option<&'a str>),
+    Panic(String),
+    SysRq(char),
+}
+
+impl<'a> Request<'a> {
+    fn parse(line: &'a str) -> Result<Self, &'static str> {
+        let mut args = line.split_whitespace();
+        match args.next() {
+            Some("version") => Ok(Self::Version),
+            Some("selftest") => Ok(Self::Selftest),
+            Some("stats") => Ok(Self::Stats(args.next())),
+            Some("panic") => Ok(Self::Panic(args.collect::<Vec<_>>().join(" "))),
+            Some("sysrq") => {
+                let mut key = args.next().unwrap_or("").chars();
+                match (key.next(), key.next()) {
+                    (Some(key), None) => Ok(Self::SysRq(key)),
+                    _ => Err("expected a single SysRq key"),
+                }
+            }
+            Some(_) => Err("unknown command"),
+            None => Err("empty request"),
+        }
+    }
+}
+
+/// Runs a request, writing the reply including the final status line
+pub fn execute(line: &str, out: &mut dyn Write) -> fmt::Result {
+    let request = match Request::parse(line) {
+        Ok(request) => request,
+        Err(reason) => return writeln!(out, "ERR {}", reason),
+    };
+    let mut output = Output { out, line_start: true };
+    let result = match request {
+        Request::Version => writeln!(output, "{} (module ABI {})", build_info::Banner, ABI_VERSION).map(|()| Ok(())),
+        Request::Selftest => selftest(&mut output),
+        Request::Stats(Some(name)) => crate::stats::dump(name, &mut output)
+            .map(|result| result.map(|()| Ok(())))
+            .unwrap_or(Ok(Err("unknown provider"))),
+        Request::Stats(None) => crate::stats::dump_all(&mut output).map(|()| Ok(())),
+        Request::Panic(message) => {
+            writeln!(output.out, "OK")?;
+            panic!("hostctl: {}", message);
+        }
+        Request::SysRq(key) => sysrq::handle(key, &mut output).map(|()| Ok(())),
+    };
+    if !output.line_start {
+        output.out.write_char('\n')?;
+    }
+    match result? {
+        Ok(()) => writeln!(out, "OK"),
+        Err(reason) => writeln!(out, "ERR {}", reason),
+    }
+}
+
+type Check = fn() -> Result<(), String>;
+
+/// Runs every check, returning an error if any of them failed
+fn selftest(out: &mut dyn Write) -> Result<Result<(), &'static str>, fmt::Error> {
+    let checks: [(&str, Check); 5] = [
+        ("heap", || {
+            let values: Vec<u64> = (0..4096).collect();
+            match values.iter().sum::<u64>() {
+                8_386_560 => Ok(()),
+                sum => Err(alloc::format!("sum is {}", sum)),
+            }
+        }),
+        ("frames", || {
+            let mut frames = FRAME_ALLOCATOR.lock();
+            let frame = frames
+                .allocate_contiguous(1)
+                .ok_or_else(|| String::from("out of frames"))?;
+            // SAFETY: The frame was just allocated and never used
+            unsafe { frames.deallocate_contiguous(frame, 1) };
+            Ok(())
+        }),
+        ("clock", || {
+            let start = time::monotonic_ns();
+            let advanced = (0..10_000_000).any(|_| {
+                core::hint::spin_loop();
+                time::monotonic_ns() > start
+            });
+            if advanced {
+                Ok(())
+            } else {
+                Err("the monotonic clock is stuck".into())
+            }
+        }),
+        ("wx", || {
+            let page_table = KernelPageTable::new(Cr3::addr());
+            match wx::violations(&page_table, &wx::Regions::kernel()).len() {
+                0 => Ok(()),
+                count => Err(alloc::format!("{} mappings violate W^X", count)),
+            }
+        }),
+        ("stacks", || {
+            // Panics if a canary was overwritten
+            mm::stack::check();
+            Ok(())
+        }),
+    ];
+    let mut failed = false;
+    for (name, check) in checks {
+        match check() {
+            Ok(()) => writeln!(out, "PASS {}", name)?,
+            Err(err) => {
+                writeln!(out, "FAIL {}: {}", name, err)?;
+                failed = true;
+            }
+        }
+    }
+    Ok(if failed { Err("selftest failed") } else { Ok(()) })
+}
+
+/// Sends a reply as it is written, so it reaches the host even if the request never returns
+struct Reply<'a>(&'a VirtioConsole);
+
+impl Write for Reply<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// Reads requests from the first virtio console, and answers complete ones
+pub fn poll() {
+    let Some(console) = console::devices().into_iter().next() else {
+        return;
+    };
+    let mut input = Vec::new();
+    console.read(&mut input);
+    for byte in input {
+        let mut line = LINE.lock();
+        match byte {
+            b'\n' => {
+                let request = core::mem::take(&mut *line);
+                drop(line);
+                _ = execute(request.trim_end_matches('\r'), &mut Reply(&console));
+            }
+            _ if line.len() < MAX_LINE => line.push(byte as char),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostctl_requests() {
+        assert_eq!(Request::parse("version"), Ok(Request::Version));
+        assert_eq!(Request::parse(" stats  heap "), Ok(Request::Stats(Some("heap"))));
+        assert_eq!(Request::parse("stats"), Ok(Request::Stats(None)));
+        assert_eq!(
+            Request::parse("panic out of  tea"),
+            Ok(Request::Panic("out of tea".into()))
+        );
+        assert_eq!(Request::parse("sysrq M"), Ok(Request::SysRq('M')));
+        assert!(Request::parse("sysrq ab").is_err());
+        assert_eq!(Request::parse("reboot now"), Err("unknown command"));
+        assert_eq!(Request::parse("  "), Err("empty request"));
+
+        let mut reply = String::new();
+        let mut output = Output {
+            out: &mut reply,
+            line_start: true,
+        };
+        write!(output, "a\nb").unwrap();
+        write!(output, "c\n\nd\n").unwrap();
+        assert_eq!(reply, "| a\n| bc\n| \n| d\n");
+    }
+}
@@ -0,0 +1,114 @@
+//! Kernel debug shell
+//!
+//! A line based shell on the console terminal, polled from the kernel main loop. The shell pumps
+//! the input of the console devices into the terminal, as none of them deliver it on their own.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use crate::{dev::DEVICES, sync::Mutex, tty};
+
+mod commands;
+pub mod hostctl;
+pub mod sysrq;
+
+const PROMPT: &str = "hadron> ";
+
+/// A shell command
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: fn(args: &[&str], out: &mut dyn fmt::Write) -> fmt::Result,
+}
+
+/// Writes shell output to the kernel consoles, without the log prefix
+pub struct ConsoleOut;
+
+impl fmt::Write for ConsoleOut {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::util::kprint::kprint_internal(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+struct Shell {
+    /// Whether the next byte is a SysRq key
+    sysrq: bool,
+}
+
+static SHELL: Mutex<Shell> = Mutex::new(Shell { sysrq: false });
+
+/// Prints the prompt
+pub fn init() {
+    use fmt::Write;
+    _ = write!(
+        ConsoleOut,
+        "\nkernel debug shell, type 'help' for a list of commands\n{}",
+        PROMPT
+    );
+}
+
+/// Reads pending input from the consoles, running a command once a full line has been entered
+pub fn poll() {
+    let mut input = Vec::new();
+    {
+        let mut platform_devs = DEVICES.platform();
+        for dev in platform_devs.iter() {
+            let Some(drv) = &dev.dev.drv else { continue };
+            let Some(read) = drv.caps.console.and_then(|console| console.read) else {
+                continue;
+            };
+            while let Some(byte) = read(&dev.dev) {
+                input.push(byte);
+            }
+        }
+    }
+
+    for byte in input {
+        handle_byte(byte);
+    }
+}
+
+fn handle_byte(byte: u8) {
+    use fmt::Write;
+    // The key that brings back the console is not meant for the shell
+    if crate::display::splash::dismiss() {
+        return;
+    }
+    let mut out = ConsoleOut;
+    let mut shell = SHELL.lock();
+    if core::mem::take(&mut shell.sysrq) {
+        drop(shell);
+        _ = out.write_str("\n");
+        _ = sysrq::handle(byte as char, &mut out);
+        _ = out.write_str(PROMPT);
+        return;
+    }
+    if byte == sysrq::SERIAL_PREFIX {
+        shell.sysrq = true;
+        return;
+    }
+    // Commands may take a while, and shouldn't block other users of the shell state
+    drop(shell);
+    let tty = tty::console();
+    tty.receive(byte);
+    while let Some(line) = tty.try_read_line() {
+        execute(&line, &mut out);
+        _ = out.write_str(PROMPT);
+    }
+}
+
+/// Runs a single command line
+pub fn execute(line: &str, out: &mut dyn fmt::Write) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, args)) = args.split_first() else {
+        return;
+    };
+
+    match commands::COMMANDS.iter().find(|cmd| cmd.name == name) {
+        Some(cmd) => _ = (cmd.run)(args, out),
+        None => _ = writeln!(out, "{}: command not found", name),
+    }
+}
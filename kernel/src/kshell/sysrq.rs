@@ -0,0 +1,133 @@
+//! Magic SysRq keys
+//!
+//! Single key actions that work even when the shell can't run a command line. On the serial
+//! console, Ctrl-O followed by the key triggers one, the `sysrq <key>` command and the
+//! `sysrq <key>` host control request do the same. There is no keyboard driver yet, which would
+//! call [`handle`] for Alt-SysRq-<key>.
+//!
+//! Besides the informational actions, some deliberately break the kernel in a controlled way, so
+//! the failure handling can be demonstrated and regression tested on demand:
+//! - `M` allocates until the heap runs out, ending in the allocation failure panic, as there is
+//!   no OOM handler that could recover.
+//! - `d` takes a lock twice, which `lock_debug` builds report, and other builds deadlock on until
+//!   the NMI watchdog fires.
+//! - `D` takes two locks in both orders, which `lock_debug` builds report as an ABBA inversion.
+//!   There are no tasks to deadlock on each other, so other builds just carry on.
+//! - `l` spins forever with interrupts disabled, until the NMI watchdog fires.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use crate::{arch::instructions::interrupts, kprintln, mm, sync::Mutex};
+
+/// The byte that arms SysRq on the serial console, Ctrl-O
+pub const SERIAL_PREFIX: u8 = 0x0F;
+
+pub struct SysRqAction {
+    pub key: char,
+    pub help: &'static str,
+    pub run: fn(out: &mut dyn fmt::Write) -> fmt::Result,
+}
+
+pub static ACTIONS: &[SysRqAction] = &[
+    SysRqAction {
+        key: 'h',
+        help: "list the SysRq actions",
+        run: help,
+    },
+    SysRqAction {
+        key: 'm',
+        help: "show memory usage",
+        run: |out| writeln!(out, "{}", mm::stats()),
+    },
+    SysRqAction {
+        key: 'c',
+        help: "panic",
+        run: |_| panic!("sysrq: triggered crash"),
+    },
+    SysRqAction {
+        key: 'M',
+        help: "allocate until the heap runs out",
+        run: exhaust_heap,
+    },
+    SysRqAction {
+        key: 'd',
+        help: "take a lock twice",
+        run: double_lock,
+    },
+    SysRqAction {
+        key: 'D',
+        help: "take two locks in both orders",
+        run: lock_inversion,
+    },
+    SysRqAction {
+        key: 'l',
+        help: "spin forever with interrupts disabled",
+        run: spin_irqs_off,
+    },
+];
+
+/// Runs the action of a key
+pub fn handle(key: char, out: &mut dyn fmt::Write) -> fmt::Result {
+    match ACTIONS.iter().find(|action| action.key == key) {
+        Some(action) => {
+            kprintln!(Warn, "sysrq: {}", action.help);
+            (action.run)(out)
+        }
+        None => help(out),
+    }
+}
+
+fn help(out: &mut dyn fmt::Write) -> fmt::Result {
+    for action in ACTIONS {
+        writeln!(out, "{}  {}", action.key, action.help)?;
+    }
+    Ok(())
+}
+
+fn exhaust_heap(_out: &mut dyn fmt::Write) -> fmt::Result {
+    const CHUNK: usize = 1024 * 1024;
+    let mut total = 0usize;
+    loop {
+        let chunk: Vec<u8> = Vec::with_capacity(CHUNK);
+        // Leaked through black_box, so the allocation can't be optimized out as unused
+        core::mem::forget(core::hint::black_box(chunk));
+        total += CHUNK;
+        if total.is_multiple_of(64 * CHUNK) {
+            kprintln!(Info, "sysrq: allocated {} MiB", total / CHUNK);
+        }
+    }
+}
+
+static LOCK_A: Mutex<()> = Mutex::new(());
+static LOCK_B: Mutex<()> = Mutex::new(());
+
+fn double_lock(_out: &mut dyn fmt::Write) -> fmt::Result {
+    let _first = LOCK_A.lock();
+    let _second = LOCK_A.lock();
+    unreachable!("sysrq: took a lock twice");
+}
+
+fn lock_inversion(out: &mut dyn fmt::Write) -> fmt::Result {
+    {
+        let _a = LOCK_A.lock();
+        let _b = LOCK_B.lock();
+    }
+    {
+        let _b = LOCK_B.lock();
+        let _a = LOCK_A.lock();
+    }
+    writeln!(
+        out,
+        "sysrq: no inversion reported, the kernel is built without lock_debug"
+    )
+}
+
+fn spin_irqs_off(_out: &mut dyn fmt::Write) -> fmt::Result {
+    // SAFETY: Nothing runs on this CPU afterwards, the hang is for the watchdog to catch
+    unsafe { interrupts::disable() };
+    loop {
+        core::hint::spin_loop();
+    }
+}
@@ -10,6 +10,7 @@
     const_default,
     vec_push_within_capacity
 )]
+#![cfg_attr(kconfig = "alloc_debug", feature(return_address))]
 #![cfg_attr(not(feature = "test"), reexport_test_harness_main = "test_main")]
 #![cfg_attr(not(feature = "test"), test_runner(crate::tests::test_runner))]
 
@@ -27,11 +28,33 @@ pub fn kernel_entry() -> ! {
 #[cfg(feature = "test")]
 extern crate std;
 
+pub mod acpi;
 pub mod arch;
+pub mod bench;
+pub mod block;
+pub mod config;
 pub mod dev;
+pub mod display;
+pub mod elf;
+pub mod fs;
+pub mod irq;
+pub mod kshell;
 pub mod mm;
+pub mod module;
+pub mod net;
+pub mod percpu;
+pub mod process;
+pub mod profile;
+pub mod random;
+pub mod sched;
+pub mod stats;
 pub mod sync;
+pub mod syscall;
+pub mod time;
+pub mod tracepoint;
+pub mod tty;
 pub mod util;
+pub mod workqueue;
 
 #[unsafe(no_mangle)]
 pub extern "Rust" fn kernel_main() -> ! {
@@ -40,7 +63,26 @@ pub extern "Rust" fn kernel_main() -> ! {
         test_main();
         hadron_test::exit_qemu(hadron_test::ExitCode::Success);
     }
-    loop {}
+
+    bench::init(boot::cmdline());
+    display::splash::milestone(display::splash::Milestone::Done);
+    kshell::init();
+    loop {
+        net::poll();
+        kshell::poll();
+        kshell::hostctl::poll();
+        workqueue::poll();
+        mm::stats::poll();
+        mm::shrink::poll();
+        block::cache::poll();
+        mm::stack::poll();
+        mm::scrub::poll();
+        display::poll();
+        boot::image::poll();
+        #[cfg(target_arch = "x86_64")]
+        arch::x86_64::watchdog::touch();
+        core::hint::spin_loop();
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,381 @@
+//! Per-process address spaces
+//!
+//! Each [`AddressSpace`] has its own PML4. The lower half belongs to the process and is torn down
+//! with it, while the upper half entries are copied from the kernel page table, so the kernel is
+//! mapped identically in every address space. With 5-level paging, each address space also has a
+//! PML5 whose first entry is its PML4, and whose last entry is shared with the kernel.
+//!
+//! Anonymous memory starts out mapped read-only to a single zero page, and only gets a frame of
+//! its own when it is first written, through [`AddressSpace::handle_fault`]. Read-only pages of
+//! loaded images are shared between address spaces with identical contents, see [`dedup`].
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{
+        PhysAddr, VirtAddr,
+        instructions::invlpg,
+        registers::control::{Cr3, Cr3Flags},
+    },
+    mm::{
+        FRAME_ALLOCATOR, dedup, mappings,
+        page_table::{KernelPageTable, PageTable, PageTableEntry, PageTableFlags},
+        paging::{FrameDeallocator, PageSize, PhysFrame, Size4KiB},
+        scrub,
+    },
+    sync::Once,
+};
+
+/// The first PML4 entry of the kernel half
+const KERNEL_PML4_START: usize = 256;
+
+/// Flags of the intermediate tables, permissions are only restricted in the leaf entries
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::PRESENT.bits() | PageTableFlags::WRITABLE.bits() | PageTableFlags::USER.bits(),
+);
+
+/// Marks leaf entries of frames the address space doesn't own, which aren't freed with it
+const SHARED: PageTableFlags = PageTableFlags::BIT_9;
+/// Marks writable pages still mapped to the zero page, which get a frame on the first write
+const COW: PageTableFlags = PageTableFlags::BIT_10;
+/// Marks read-only pages shared through [`dedup`], which are released rather than freed
+const DEDUP: PageTableFlags = PageTableFlags::BIT_11;
+
+/// The frame untouched anonymous pages are mapped to, which is never written
+static ZERO_PAGE: Once<PhysFrame> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpaceError {
+    OutOfMemory,
+    /// The address is outside of user memory
+    NotUser,
+    /// The address is not mapped
+    NotMapped,
+    /// The address is already mapped
+    AlreadyMapped,
+}
+
+impl fmt::Display for AddressSpaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::OutOfMemory => "out of memory",
+            Self::NotUser => "address is outside of user memory",
+            Self::NotMapped => "address is not mapped",
+            Self::AlreadyMapped => "address is already mapped",
+        })
+    }
+}
+
+impl core::error::Error for AddressSpaceError {}
+
+/// A user address space, sharing the kernel half with every other address space
+///
+/// Kernel mappings are copied when the address space is created, so the kernel must not add new
+/// top level entries to its own page table afterwards.
+#[derive(Debug)]
+pub struct AddressSpace {
+    pml4: PhysFrame,
+    pml5: Option<PhysFrame>,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty lower half
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let kernel = table(Cr3::addr());
+        if VirtAddr::is_five_level() {
+            let pml5 = allocate_zeroed()?;
+            let pml4 = allocate_zeroed().inspect_err(|_| unsafe { FRAME_ALLOCATOR.lock().deallocate_frame(pml5) })?;
+            let root = table(pml5.start_address());
+            root[0].set_frame(pml4, TABLE_FLAGS);
+            let entry = &kernel[KernelPageTable::KERNEL_PML5_INDEX];
+            root[KernelPageTable::KERNEL_PML5_INDEX].set_addr(entry.addr(), entry.flags());
+            return Ok(Self { pml4, pml5: Some(pml5) });
+        }
+
+        let pml4 = allocate_zeroed()?;
+        let table = table(pml4.start_address());
+        for idx in KERNEL_PML4_START..512 {
+            let entry = &kernel[idx];
+            table[idx].set_addr(entry.addr(), entry.flags());
+        }
+        Ok(Self { pml4, pml5: None })
+    }
+
+    /// Returns the physical address of the PML4 of the lower half
+    pub fn pml4(&self) -> PhysAddr {
+        self.pml4.start_address()
+    }
+
+    /// Returns the physical address of the top level table, which is loaded into CR3
+    pub fn root(&self) -> PhysAddr {
+        self.pml5.unwrap_or(self.pml4).start_address()
+    }
+
+    /// Returns whether this address space is loaded on the current CPU
+    pub fn is_active(&self) -> bool {
+        Cr3::addr() == self.root()
+    }
+
+    /// Switches the current CPU to this address space
+    ///
+    /// # Safety
+    /// The address space must outlive its use on this CPU, and the caller must switch away from it
+    /// before it is dropped.
+    pub unsafe fn activate(&self) {
+        unsafe { Cr3::write(self.pml5.unwrap_or(self.pml4), Cr3Flags::empty()) };
+    }
+
+    /// Maps fresh zeroed memory covering `[start, start + len)`
+    ///
+    /// Pages which are already mapped are kept, with their permissions widened to include `flags`,
+    /// so segments sharing a page don't clobber each other.
+    pub fn map_range(&mut self, start: VirtAddr, len: usize, flags: PageTableFlags) -> Result<(), AddressSpaceError> {
+        let end = start.as_usize().checked_add(len).ok_or(AddressSpaceError::NotUser)?;
+        if end > mappings::USER_MEM_SIZE {
+            return Err(AddressSpaceError::NotUser);
+        }
+
+        let first = start.as_usize() / Size4KiB::SIZE;
+        let last = end.div_ceil(Size4KiB::SIZE);
+        for page in first..last {
+            self.map_page(VirtAddr::new(page * Size4KiB::SIZE), flags)?;
+        }
+        Ok(())
+    }
+
+    /// Maps zeroed memory covering `[start, start + len)`, which only gets frames once written
+    ///
+    /// Every page starts out as the zero page, mapped read-only. None of the pages may be mapped
+    /// yet.
+    pub fn map_anonymous(
+        &mut self,
+        start: VirtAddr,
+        len: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        let end = start.as_usize().checked_add(len).ok_or(AddressSpaceError::NotUser)?;
+        if end > mappings::USER_MEM_SIZE {
+            return Err(AddressSpaceError::NotUser);
+        }
+        let first = start.as_usize() / Size4KiB::SIZE;
+        let last = end.div_ceil(Size4KiB::SIZE);
+        if (first..last).any(|page| self.translate(VirtAddr::new(page * Size4KiB::SIZE)).is_some()) {
+            return Err(AddressSpaceError::AlreadyMapped);
+        }
+
+        let zero_page = *ZERO_PAGE.try_call_once(allocate_zeroed)?;
+        let mut page_flags =
+            (flags - PageTableFlags::WRITABLE) | PageTableFlags::PRESENT | PageTableFlags::USER | SHARED;
+        if flags.contains(PageTableFlags::WRITABLE) {
+            page_flags |= COW;
+        }
+        for page in first..last {
+            self.leaf(VirtAddr::new(page * Size4KiB::SIZE))?
+                .set_frame(zero_page, page_flags);
+        }
+        Ok(())
+    }
+
+    /// Maps `count` frames from `frame` on at `start`, without taking ownership of them
+    ///
+    /// The frames are left alone when the address space is dropped, so they can be shared with the
+    /// kernel or other processes. None of the pages may be mapped yet.
+    pub fn map_shared(
+        &mut self,
+        start: VirtAddr,
+        frame: PhysAddr,
+        count: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        let len = count.checked_mul(Size4KiB::SIZE).ok_or(AddressSpaceError::NotUser)?;
+        let end = start.as_usize().checked_add(len).ok_or(AddressSpaceError::NotUser)?;
+        if end > mappings::USER_MEM_SIZE || !start.as_usize().is_multiple_of(Size4KiB::SIZE) {
+            return Err(AddressSpaceError::NotUser);
+        }
+        if (0..count).any(|page| self.translate(start + page * Size4KiB::SIZE).is_some()) {
+            return Err(AddressSpaceError::AlreadyMapped);
+        }
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER | SHARED;
+        for page in 0..count {
+            let entry = self.leaf(start + page * Size4KiB::SIZE)?;
+            entry.set_addr(frame + page * Size4KiB::SIZE, flags);
+        }
+        Ok(())
+    }
+
+    /// Returns the last level entry of an address, creating the tables leading to it
+    fn leaf(&mut self, addr: VirtAddr) -> Result<&mut PageTableEntry, AddressSpaceError> {
+        let mut table_addr = self.pml4();
+        for idx in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+            let entry = &mut table(table_addr)[idx];
+            if !entry.is_present() {
+                let frame = allocate_zeroed()?;
+                entry.set_frame(frame, TABLE_FLAGS);
+            }
+            table_addr = entry.addr();
+        }
+        Ok(&mut table(table_addr)[addr.p1_index()])
+    }
+
+    fn map_page(&mut self, addr: VirtAddr, flags: PageTableFlags) -> Result<(), AddressSpaceError> {
+        let entry = self.leaf(addr)?;
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER;
+        if entry.flags().contains(SHARED) {
+            // Widening the permissions of shared frames would leak to their other users
+            return Err(AddressSpaceError::AlreadyMapped);
+        } else if entry.is_present() {
+            let mut merged = entry.flags() | (flags & PageTableFlags::WRITABLE);
+            if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                merged.remove(PageTableFlags::NO_EXECUTE);
+            }
+            entry.set_flags(merged);
+        } else {
+            let frame = allocate_zeroed()?;
+            entry.set_frame(frame, flags);
+        }
+        Ok(())
+    }
+
+    /// Returns the last level entry of an address, if the tables leading to it exist
+    fn existing_leaf(&self, addr: VirtAddr) -> Option<&mut PageTableEntry> {
+        if addr.as_usize() >= mappings::USER_MEM_SIZE {
+            return None;
+        }
+        let mut table_addr = self.pml4();
+        for idx in [addr.p4_index(), addr.p3_index(), addr.p2_index()] {
+            let entry = &table(table_addr)[idx];
+            if !entry.is_present() {
+                return None;
+            }
+            table_addr = entry.addr();
+        }
+        Some(&mut table(table_addr)[addr.p1_index()])
+    }
+
+    /// Resolves a write fault at a user address, returning whether the write can be retried
+    ///
+    /// Only writes to writable pages that are still the zero page are resolved, by giving them a
+    /// zeroed frame of their own.
+    pub fn handle_fault(&mut self, addr: VirtAddr) -> Result<bool, AddressSpaceError> {
+        let active = self.is_active();
+        let Some(entry) = self.existing_leaf(addr) else {
+            return Ok(false);
+        };
+        if !entry.flags().contains(COW) {
+            return Ok(false);
+        }
+        let frame = allocate_zeroed()?;
+        entry.set_frame(frame, (entry.flags() - COW - SHARED) | PageTableFlags::WRITABLE);
+        if active {
+            unsafe { invlpg(addr) };
+        }
+        Ok(true)
+    }
+
+    /// Replaces the read-only pages with shared ones of identical contents where there are any
+    ///
+    /// Called once an image is loaded and relocated, before the address space is activated.
+    pub fn dedup_read_only(&mut self) {
+        assert!(!self.is_active(), "deduplicating the active address space");
+        for pml4_entry in table(self.pml4()).entries[..KERNEL_PML4_START].iter() {
+            if !pml4_entry.is_present() {
+                continue;
+            }
+            for pdpt_entry in table(pml4_entry.addr()).entries.iter().filter(|e| e.is_present()) {
+                for pd_entry in table(pdpt_entry.addr()).entries.iter().filter(|e| e.is_present()) {
+                    for pt_entry in table(pd_entry.addr()).entries.iter_mut() {
+                        let flags = pt_entry.flags();
+                        if !pt_entry.is_present() || flags.intersects(PageTableFlags::WRITABLE | SHARED | COW | DEDUP) {
+                            continue;
+                        }
+                        let frame = dedup::share(PhysFrame::from_start_address(pt_entry.addr()));
+                        pt_entry.set_frame(frame, flags | DEDUP);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the physical address a user address is mapped to
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        if addr.as_usize() >= mappings::USER_MEM_SIZE {
+            return None;
+        }
+        let mut table_addr = self.pml4();
+        for idx in [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()] {
+            let entry = &table(table_addr)[idx];
+            if !entry.is_present() {
+                return None;
+            }
+            table_addr = entry.addr();
+        }
+        Some(table_addr + addr.as_usize() % Size4KiB::SIZE)
+    }
+
+    /// Copies `data` into mapped user memory at `addr`, without switching to the address space
+    pub fn write(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), AddressSpaceError> {
+        let mut offset = 0;
+        while offset < data.len() {
+            let virt = addr + offset;
+            // The zero page must not be written through the direct map
+            self.handle_fault(virt)?;
+            let phys = self.translate(virt).ok_or(AddressSpaceError::NotMapped)?;
+            let len = (Size4KiB::SIZE - virt.as_usize() % Size4KiB::SIZE).min(data.len() - offset);
+            let dst = (KernelPageTable::direct_map_start() + phys.as_usize()).as_mut_ptr::<u8>();
+            unsafe { core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), dst, len) };
+            offset += len;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees the user pages, and the page tables of the lower half
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        let mut deduped = Vec::new();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let mut free =
+            |addr: PhysAddr| unsafe { frame_allocator.deallocate_frame(PhysFrame::from_start_address(addr)) };
+
+        for pml4_entry in table(self.pml4()).entries[..KERNEL_PML4_START].iter() {
+            if !pml4_entry.is_present() {
+                continue;
+            }
+            for pdpt_entry in table(pml4_entry.addr()).entries.iter().filter(|e| e.is_present()) {
+                for pd_entry in table(pdpt_entry.addr()).entries.iter().filter(|e| e.is_present()) {
+                    for pt_entry in table(pd_entry.addr()).entries.iter().filter(|e| e.is_present()) {
+                        if pt_entry.flags().contains(DEDUP) {
+                            deduped.push(PhysFrame::from_start_address(pt_entry.addr()));
+                        } else if !pt_entry.flags().contains(SHARED) {
+                            free(pt_entry.addr());
+                        }
+                    }
+                    free(pd_entry.addr());
+                }
+                free(pdpt_entry.addr());
+            }
+            free(pml4_entry.addr());
+        }
+        free(self.pml4());
+        if let Some(pml5) = self.pml5 {
+            free(pml5.start_address());
+        }
+        drop(frame_allocator);
+        deduped.into_iter().for_each(dedup::release);
+    }
+}
+
+/// Returns a page table through the direct map
+fn table<'a>(addr: PhysAddr) -> &'a mut PageTable {
+    let virt = KernelPageTable::direct_map_start() + addr.as_usize();
+    unsafe { &mut *virt.as_mut_ptr::<PageTable>() }
+}
+
+/// Allocates a zeroed frame, for both page tables and user pages
+fn allocate_zeroed() -> Result<PhysFrame, AddressSpaceError> {
+    scrub::allocate_zeroed().ok_or(AddressSpaceError::OutOfMemory)
+}
@@ -0,0 +1,243 @@
+//! Heap allocation tracking, for finding leaks
+//!
+//! With the `alloc_debug` option, every live allocation of the kernel heap is recorded with its
+//! size, the address the allocator was called from and a sequence number. Taking a [`mark`]
+//! before probing a device and dumping the allocations made since then after removing it shows
+//! what the driver forgot to free.
+//!
+//! The allocations are kept in a fixed size table, as the tracker can't use the heap it tracks.
+//! Allocations made while it is full are counted, but not tracked.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::sync::Mutex;
+use alloc::vec::Vec;
+
+/// The most live allocations that are tracked
+pub const MAX_TRACKED: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub ptr: usize,
+    pub size: usize,
+    /// The return address of the call into the allocator
+    pub caller: usize,
+    pub seq: u64,
+}
+
+impl fmt::Display for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "#{} {} bytes at {:#x}, allocated from {:#x}",
+            self.seq, self.size, self.ptr, self.caller
+        )?;
+        if let Some(module) = crate::module::owner(self.caller) {
+            write!(f, " ({}+{:#x})", module.name(), self.caller - module.start())?;
+        }
+        Ok(())
+    }
+}
+
+/// A hash table of allocations by address, with linear probing
+pub struct Tracker<const N: usize> {
+    slots: [Option<Allocation>; N],
+    len: usize,
+}
+
+impl<const N: usize> Tracker<N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [None; N],
+            len: 0,
+        }
+    }
+
+    fn slot(ptr: usize) -> usize {
+        // Allocations are at least 8 byte aligned, so the low bits carry no information
+        (ptr >> 3).wrapping_mul(0x9E37_79B9_7F4A_7C15) % N
+    }
+
+    /// Records an allocation, returning `false` if the table is full
+    pub fn insert(&mut self, allocation: Allocation) -> bool {
+        if self.len == N {
+            return false;
+        }
+        let mut idx = Self::slot(allocation.ptr);
+        while self.slots[idx].is_some() {
+            idx = (idx + 1) % N;
+        }
+        self.slots[idx] = Some(allocation);
+        self.len += 1;
+        true
+    }
+
+    /// Removes the allocation at `ptr`, if it is tracked
+    pub fn remove(&mut self, ptr: usize) -> Option<Allocation> {
+        let mut idx = Self::slot(ptr);
+        let mut probes = 0;
+        loop {
+            let allocation = self.slots[idx]?;
+            if allocation.ptr == ptr {
+                break;
+            }
+            probes += 1;
+            if probes == N {
+                return None;
+            }
+            idx = (idx + 1) % N;
+        }
+        let removed = self.slots[idx].take();
+        self.len -= 1;
+
+        // Moves later entries of the probe sequence back, so lookups never stop at the hole
+        let mut hole = idx;
+        let mut next = (idx + 1) % N;
+        while let Some(allocation) = self.slots[next] {
+            let home = Self::slot(allocation.ptr);
+            // The entry can fill the hole if its home slot is not in (hole, next]
+            let reachable = if hole <= next {
+                home <= hole || home > next
+            } else {
+                home <= hole && home > next
+            };
+            if reachable {
+                self.slots[hole] = self.slots[next].take();
+                hole = next;
+            }
+            next = (next + 1) % N;
+        }
+        removed
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Allocation> {
+        self.slots.iter().flatten()
+    }
+}
+
+impl<const N: usize> Default for Tracker<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static TRACKER: Mutex<Tracker<MAX_TRACKED>> = Mutex::new(Tracker::new());
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+/// Allocations that weren't tracked because the table was full
+static UNTRACKED: AtomicU64 = AtomicU64::new(0);
+
+/// Records an allocation of the kernel heap
+pub fn track(ptr: *mut u8, size: usize, caller: *const ()) {
+    let allocation = Allocation {
+        ptr: ptr as usize,
+        size,
+        caller: caller as usize,
+        seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+    };
+    if !TRACKER.lock().insert(allocation) {
+        UNTRACKED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Forgets an allocation of the kernel heap when it is freed
+pub fn untrack(ptr: *mut u8) {
+    TRACKER.lock().remove(ptr as usize);
+}
+
+/// Returns the sequence number the next allocation gets
+pub fn mark() -> u64 {
+    NEXT_SEQ.load(Ordering::Relaxed)
+}
+
+/// Returns the live allocations made since `mark`, oldest first
+pub fn live_since(mark: u64) -> Vec<Allocation> {
+    // Allocated up front, as the heap can't be used while the tracker is locked
+    let mut live: Vec<Allocation> = Vec::with_capacity(MAX_TRACKED);
+    live.extend(TRACKER.lock().iter().filter(|allocation| allocation.seq >= mark));
+    live.sort_unstable_by_key(|allocation| allocation.seq);
+    live
+}
+
+/// Writes the live allocations made since `mark`
+pub fn dump_since(mark: u64, out: &mut dyn fmt::Write) -> fmt::Result {
+    if !crate::config::ALLOC_DEBUG {
+        return writeln!(
+            out,
+            "allocation tracking is disabled, build with the alloc_debug option"
+        );
+    }
+    let live = live_since(mark);
+    let bytes: usize = live.iter().map(|allocation| allocation.size).sum();
+    for allocation in &live {
+        writeln!(out, "{}", allocation)?;
+    }
+    writeln!(out, "{} live allocations since #{}, {} bytes", live.len(), mark, bytes)?;
+    match UNTRACKED.load(Ordering::Relaxed) {
+        0 => Ok(()),
+        untracked => writeln!(out, "{} allocations were not tracked, the table was full", untracked),
+    }
+}
+
+struct LogOut;
+
+impl fmt::Write for LogOut {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for line in s.lines() {
+            crate::kprintln!(Info, "{}", line);
+        }
+        Ok(())
+    }
+}
+
+/// Logs every live allocation of the kernel heap
+pub fn dump_leaks() {
+    _ = dump_since(0, &mut LogOut);
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    fn allocation(ptr: usize) -> Allocation {
+        Allocation {
+            ptr,
+            size: 16,
+            caller: 0,
+            seq: ptr as u64,
+        }
+    }
+
+    #[test]
+    fn alloc_debug_tracker() {
+        let mut tracker = Tracker::<8>::new();
+        let ptrs = [0x1000, 0x1040, 0x2000, 0x2008, 0x3000, 0x8000, 0x8040, 0x9000];
+        for ptr in ptrs {
+            assert!(tracker.insert(allocation(ptr)));
+        }
+        assert!(!tracker.insert(allocation(0xA000)));
+
+        // Removing entries never hides the others, wherever they were probed to
+        for (idx, ptr) in ptrs.iter().enumerate() {
+            assert_eq!(tracker.remove(*ptr), Some(allocation(*ptr)));
+            assert_eq!(tracker.remove(*ptr), None);
+            assert_eq!(tracker.len(), ptrs.len() - idx - 1);
+            for other in &ptrs[idx + 1..] {
+                assert!(tracker.iter().any(|allocation| allocation.ptr == *other));
+                assert!(tracker.remove(*other).is_some());
+                assert!(tracker.insert(allocation(*other)));
+            }
+        }
+        assert!(tracker.is_empty());
+    }
+}
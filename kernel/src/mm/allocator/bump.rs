@@ -37,8 +37,10 @@ impl BumpAllocator {
 unsafe impl MutAllocator for BumpAllocator {
     fn allocate(&mut self, layout: core::alloc::Layout) -> Result<NonNull<[u8]>, alloc::alloc::AllocError> {
         let index = self.index;
+        if layout.size() > self.len - index {
+            return Err(alloc::alloc::AllocError);
+        }
         self.index += layout.size();
-        assert!(self.index <= self.len, "bump allocator is full!");
         // SAFETY: We checked that the index is valid, and the pointer should be valid
         let ptr = unsafe { core::slice::from_raw_parts_mut((self.base + index).as_mut_ptr(), layout.size()) };
 
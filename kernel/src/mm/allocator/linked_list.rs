@@ -1,35 +1,323 @@
-use core::ptr::NonNull;
+//! A first fit heap allocator
+//!
+//! The free blocks, the holes, form a list sorted by address that is stored in the holes
+//! themselves. Allocations take the first hole they fit in, splitting off what is left in front of
+//! and behind them as holes of their own, and freed blocks are merged with the holes around them,
+//! so two holes are never next to each other. Every block is a multiple of [`ALIGN`] and at least
+//! [`MIN_SIZE`] bytes, so it can hold a hole once it is freed.
+//!
+//! [`LinkedListAllocator::check`] walks the holes and reports every one that breaks these rules,
+//! which is usually the first sign of a buffer overflow or a double free.
 
-// FIXME: We should not use dependencies as a core design goal for the kernel
-use linked_list_allocator::Heap;
+use core::{fmt, ptr};
 
 use crate::mm::allocator::MutGlobalAlloc;
 
+/// The alignment of every block
+pub const ALIGN: usize = align_of::<Hole>();
+/// The smallest block, big enough to hold a hole
+pub const MIN_SIZE: usize = size_of::<Hole>();
+
+#[repr(C)]
+struct Hole {
+    size: usize,
+    /// The next hole, which is at a higher address, null for the last one
+    next: *mut Hole,
+}
+
+/// A hole that breaks the rules of the free list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapViolation {
+    /// The hole is misaligned or outside of the heap, so the walk stops at it
+    OutOfBounds { addr: usize },
+    /// The hole is too small, misaligned or runs past the end of the heap
+    BadSize { addr: usize, size: usize },
+    /// The hole starts before the previous one ends, or the list isn't sorted
+    Overlap { addr: usize, prev_end: usize },
+    /// The hole starts right where the previous one ends, and should have been merged with it
+    Unmerged { addr: usize },
+    /// There are more holes than fit in the heap, so the list has a loop
+    Loop,
+    /// The holes don't add up to the memory that isn't allocated
+    FreeMismatch { free: usize, expected: usize },
+}
+
+impl fmt::Display for HeapViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfBounds { addr } => write!(f, "hole at {:#x} is outside of the heap", addr),
+            Self::BadSize { addr, size } => write!(f, "hole at {:#x} has a bad size of {:#x}", addr, size),
+            Self::Overlap { addr, prev_end } => write!(
+                f,
+                "hole at {:#x} starts before the previous hole ends at {:#x}",
+                addr, prev_end
+            ),
+            Self::Unmerged { addr } => write!(f, "hole at {:#x} is not merged with the previous hole", addr),
+            Self::Loop => f.write_str("the free list has a loop"),
+            Self::FreeMismatch { free, expected } => {
+                write!(f, "the holes add up to {} bytes, but {} are free", free, expected)
+            }
+        }
+    }
+}
+
+impl core::error::Error for HeapViolation {}
+
+/// What a walk of the free list found
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapCheck {
+    pub holes: usize,
+    pub free: usize,
+    pub largest: usize,
+    pub violations: usize,
+}
+
 pub struct LinkedListAllocator {
-    inner: Heap,
+    /// The first hole, null if the heap is full
+    head: *mut Hole,
+    bottom: usize,
+    top: usize,
+    /// The bytes in allocated blocks
+    used: usize,
+}
+
+// SAFETY: The holes are only reached through the allocator
+unsafe impl Send for LinkedListAllocator {}
+
+/// Returns where a block of `size` bytes aligned to `align` fits in the hole `[start, end)`
+///
+/// What is left in front of and behind the block has to be big enough for a hole.
+fn fit(start: usize, end: usize, size: usize, align: usize) -> Option<usize> {
+    let mut addr = start.next_multiple_of(align);
+    if addr != start && addr - start < MIN_SIZE {
+        addr = (start + MIN_SIZE).next_multiple_of(align);
+    }
+    let back = end.checked_sub(addr.checked_add(size)?)?;
+    (back == 0 || back >= MIN_SIZE).then_some(addr)
 }
 
 impl LinkedListAllocator {
     pub const fn empty() -> Self {
-        Self { inner: Heap::empty() }
+        Self {
+            head: ptr::null_mut(),
+            bottom: 0,
+            top: 0,
+            used: 0,
+        }
     }
 
+    /// Hands the memory to the allocator as a single hole
+    ///
+    /// # Safety
+    /// The memory must be valid, unused and not given to anything else for as long as the
+    /// allocator is used.
     pub unsafe fn init(&mut self, heap_bottom: *mut u8, heap_size: usize) {
-        unsafe {
-            self.inner.init(heap_bottom, heap_size);
+        let bottom = (heap_bottom as usize).next_multiple_of(ALIGN);
+        let top = ((heap_bottom as usize + heap_size) & !(ALIGN - 1)).max(bottom);
+        *self = Self {
+            head: ptr::null_mut(),
+            bottom,
+            top,
+            used: 0,
+        };
+        if top - bottom >= MIN_SIZE {
+            self.head = bottom as *mut Hole;
+            unsafe {
+                self.head.write(Hole {
+                    size: top - bottom,
+                    next: ptr::null_mut(),
+                })
+            };
+        }
+    }
+
+    /// Returns the size of the heap
+    pub fn size(&self) -> usize {
+        self.top - self.bottom
+    }
+
+    /// Returns the bytes in allocated blocks, including what they were rounded up by
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    fn block_size(layout: core::alloc::Layout) -> usize {
+        layout.size().max(MIN_SIZE).next_multiple_of(ALIGN)
+    }
+
+    /// Walks the free list, calling `report` with every hole that breaks its rules
+    pub fn check(&self, mut report: impl FnMut(HeapViolation)) -> HeapCheck {
+        let mut check = HeapCheck::default();
+        let mut report = |violation| {
+            check.violations += 1;
+            report(violation)
+        };
+        let (mut holes, mut free, mut largest) = (0, 0, 0);
+        let mut prev_end = None;
+        let mut hole = self.head;
+        while !hole.is_null() {
+            let addr = hole as usize;
+            if holes > self.size() / MIN_SIZE {
+                report(HeapViolation::Loop);
+                break;
+            }
+            if !addr.is_multiple_of(ALIGN) || addr < self.bottom || addr + MIN_SIZE > self.top {
+                report(HeapViolation::OutOfBounds { addr });
+                break;
+            }
+            // SAFETY: The hole is inside of the heap
+            let (size, next) = unsafe { ((*hole).size, (*hole).next) };
+            let end = addr.saturating_add(size);
+            if size < MIN_SIZE || !size.is_multiple_of(ALIGN) || end > self.top {
+                report(HeapViolation::BadSize { addr, size });
+            }
+            match prev_end {
+                Some(prev_end) if addr < prev_end => report(HeapViolation::Overlap { addr, prev_end }),
+                Some(prev_end) if addr == prev_end => report(HeapViolation::Unmerged { addr }),
+                _ => {}
+            }
+            holes += 1;
+            free += size;
+            largest = largest.max(size);
+            prev_end = Some(end);
+            hole = next;
+        }
+        let expected = self.size() - self.used;
+        if free != expected {
+            report(HeapViolation::FreeMismatch { free, expected });
+        }
+        HeapCheck {
+            holes,
+            free,
+            largest,
+            ..check
         }
     }
 }
 
 unsafe impl MutGlobalAlloc for LinkedListAllocator {
     unsafe fn alloc(&mut self, layout: core::alloc::Layout) -> *mut u8 {
-        match self.inner.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => core::ptr::null_mut(),
+        let size = Self::block_size(layout);
+        let align = layout.align().max(ALIGN);
+        // The link that points to the hole being looked at
+        let mut link: *mut *mut Hole = &raw mut self.head;
+        unsafe {
+            while !(*link).is_null() {
+                let hole = *link;
+                let start = hole as usize;
+                let end = start + (*hole).size;
+                let Some(addr) = fit(start, end, size, align) else {
+                    link = &raw mut (*hole).next;
+                    continue;
+                };
+                let mut rest = (*hole).next;
+                if end > addr + size {
+                    let back = (addr + size) as *mut Hole;
+                    back.write(Hole {
+                        size: end - (addr + size),
+                        next: rest,
+                    });
+                    rest = back;
+                }
+                if addr > start {
+                    (*hole).size = addr - start;
+                    (*hole).next = rest;
+                } else {
+                    *link = rest;
+                }
+                self.used += size;
+                return addr as *mut u8;
+            }
         }
+        ptr::null_mut()
     }
 
     unsafe fn dealloc(&mut self, ptr: *mut u8, layout: core::alloc::Layout) {
-        unsafe { self.inner.deallocate(NonNull::new(ptr).unwrap(), layout) };
+        let size = Self::block_size(layout);
+        let addr = ptr as usize;
+        self.used -= size;
+        unsafe {
+            let mut prev: *mut Hole = ptr::null_mut();
+            let mut next = self.head;
+            while !next.is_null() && (next as usize) < addr {
+                prev = next;
+                next = (*next).next;
+            }
+            let block = ptr.cast::<Hole>();
+            block.write(Hole { size, next });
+            if !next.is_null() && addr + size == next as usize {
+                (*block).size += (*next).size;
+                (*block).next = (*next).next;
+            }
+            if prev.is_null() {
+                self.head = block;
+            } else if prev as usize + (*prev).size == addr {
+                (*prev).size += (*block).size;
+                (*prev).next = (*block).next;
+            } else {
+                (*prev).next = block;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use core::alloc::Layout;
+
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    #[repr(align(64))]
+    struct Memory([u8; 4096]);
+
+    #[test]
+    fn linked_list_alloc_check() {
+        let mut memory = Memory([0; 4096]);
+        let mut heap = LinkedListAllocator::empty();
+        unsafe { heap.init(memory.0.as_mut_ptr(), memory.0.len()) };
+        let layouts = [
+            Layout::from_size_align(1, 1).unwrap(),
+            Layout::from_size_align(100, 8).unwrap(),
+            Layout::from_size_align(24, 64).unwrap(),
+            Layout::from_size_align(512, 16).unwrap(),
+        ];
+        let blocks: Vec<_> = layouts
+            .iter()
+            .map(|layout| (unsafe { heap.alloc(*layout) }, *layout))
+            .collect();
+        for (ptr, layout) in &blocks {
+            assert!(!ptr.is_null());
+            assert!((*ptr as usize).is_multiple_of(layout.align()));
+        }
+        let mut violations = Vec::new();
+        assert_eq!(heap.check(|violation| violations.push(violation)).violations, 0);
+        // Freed out of order, every hole is merged back into one
+        for idx in [1, 3, 0, 2] {
+            unsafe { heap.dealloc(blocks[idx].0, blocks[idx].1) };
+        }
+        let check = heap.check(|violation| violations.push(violation));
+        assert_eq!((check.holes, check.free, check.violations), (1, 4096, 0));
+        assert!(unsafe { heap.alloc(Layout::from_size_align(4097, 8).unwrap()) }.is_null());
+
+        // A buffer overflow into the hole after a block
+        let ptr = unsafe { heap.alloc(Layout::from_size_align(64, 8).unwrap()) };
+        unsafe { ptr.cast::<usize>().add(8).write(0x11) };
+        let check = heap.check(|violation| violations.push(violation));
+        assert_eq!(check.violations, 2);
+        assert_eq!(
+            violations,
+            [
+                HeapViolation::BadSize {
+                    addr: ptr as usize + 64,
+                    size: 0x11
+                },
+                HeapViolation::FreeMismatch {
+                    free: 0x11,
+                    expected: 4096 - 64
+                }
+            ]
+        );
     }
 }
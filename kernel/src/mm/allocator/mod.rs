@@ -1,8 +1,14 @@
+use crate::sync::{Mutex, MutexGuard};
 use alloc::{alloc::Allocator, sync::Arc};
-use core::{alloc::GlobalAlloc, fmt::Debug, ops::DerefMut, ptr::NonNull};
-use spin::{Mutex, MutexGuard};
+use core::{
+    alloc::GlobalAlloc,
+    fmt::Debug,
+    ops::DerefMut,
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
 
-use crate::mm::allocator::linked_list::LinkedListAllocator;
+use crate::mm::allocator::linked_list::{HeapCheck, HeapViolation, LinkedListAllocator};
 
 pub mod bump;
 pub mod linked_list;
@@ -21,6 +27,7 @@ impl<T: Debug> Debug for Locked<T> {
 }
 
 impl<T> Locked<T> {
+    #[track_caller]
     pub const fn new(alloc: T) -> Self {
         Self {
             alloc: Mutex::new(alloc),
@@ -119,28 +126,137 @@ where
 #[cfg_attr(not(feature = "test"), global_allocator)]
 pub static ALLOCATOR: KernelAllocator = KernelAllocator::new();
 
+#[cfg(not(kconfig = "kasan"))]
+type HeapAllocator = LinkedListAllocator;
+#[cfg(kconfig = "kasan")]
+type HeapAllocator = crate::mm::kasan::KasanAllocator<LinkedListAllocator>;
+
+/// Usage of the kernel heap, as requested by its users
+///
+/// Sizes are those of the requested layouts, without the overhead of the allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The size of the heap
+    pub size: usize,
+    /// The bytes currently allocated
+    pub allocated: usize,
+    /// The most bytes ever allocated at once
+    pub peak: usize,
+    /// The number of live allocations
+    pub allocations: usize,
+    /// The number of allocations made since boot
+    pub total_allocations: u64,
+    /// The number of allocations that failed
+    pub failures: u64,
+}
+
+struct HeapCounters {
+    size: AtomicUsize,
+    allocated: AtomicUsize,
+    peak: AtomicUsize,
+    allocations: AtomicUsize,
+    total_allocations: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl HeapCounters {
+    const fn new() -> Self {
+        Self {
+            size: AtomicUsize::new(0),
+            allocated: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            total_allocations: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        }
+    }
+}
+
 pub struct KernelAllocator {
-    generic: Locked<LinkedListAllocator>,
+    generic: Locked<HeapAllocator>,
+    stats: HeapCounters,
 }
 
 impl KernelAllocator {
+    #[cfg(not(kconfig = "kasan"))]
     pub const fn new() -> Self {
         Self {
             generic: Locked::new(LinkedListAllocator::empty()),
+            stats: HeapCounters::new(),
+        }
+    }
+
+    #[cfg(kconfig = "kasan")]
+    pub const fn new() -> Self {
+        Self {
+            generic: Locked::new(HeapAllocator::new(LinkedListAllocator::empty())),
+            stats: HeapCounters::new(),
         }
     }
 
+    #[cfg(not(kconfig = "kasan"))]
     pub unsafe fn init(&self, addr: *mut u8, size: usize) {
         unsafe { self.generic.lock().init(addr, size) };
+        self.stats.size.store(size, Ordering::Relaxed);
+    }
+
+    /// Initializes the heap, with the shadow taking up the start of the memory
+    #[cfg(kconfig = "kasan")]
+    pub unsafe fn init(&self, addr: *mut u8, size: usize) {
+        let mut heap = self.generic.lock();
+        let (addr, size) = unsafe { heap.init_shadow(addr, size) };
+        unsafe { heap.inner_mut().init(addr, size) };
+        crate::mm::kasan::register(*heap.shadow().unwrap());
+        self.stats.size.store(size, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let stats = &self.stats;
+        HeapStats {
+            size: stats.size.load(Ordering::Relaxed),
+            allocated: stats.allocated.load(Ordering::Relaxed),
+            peak: stats.peak.load(Ordering::Relaxed),
+            allocations: stats.allocations.load(Ordering::Relaxed),
+            total_allocations: stats.total_allocations.load(Ordering::Relaxed),
+            failures: stats.failures.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Walks the free list of the heap, see [`LinkedListAllocator::check`]
+    ///
+    /// `report` is called with the heap locked, so it must not allocate.
+    pub fn check(&self, report: impl FnMut(HeapViolation)) -> HeapCheck {
+        let heap = self.generic.lock();
+        #[cfg(kconfig = "kasan")]
+        let heap = heap.inner();
+        heap.check(report)
     }
 }
 
 unsafe impl GlobalAlloc for KernelAllocator {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        unsafe { GlobalAlloc::alloc(&self.generic, layout) }
+        let ptr = unsafe { GlobalAlloc::alloc(&self.generic, layout) };
+        crate::trace!(Alloc, ptr, layout.size(), layout.align());
+        let stats = &self.stats;
+        if ptr.is_null() {
+            stats.failures.fetch_add(1, Ordering::Relaxed);
+            return ptr;
+        }
+        let allocated = stats.allocated.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        stats.peak.fetch_max(allocated, Ordering::Relaxed);
+        stats.allocations.fetch_add(1, Ordering::Relaxed);
+        stats.total_allocations.fetch_add(1, Ordering::Relaxed);
+        #[cfg(kconfig = "alloc_debug")]
+        crate::mm::alloc_debug::track(ptr, layout.size(), core::arch::return_address!());
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        unsafe { GlobalAlloc::dealloc(&self.generic, ptr, layout) }
+        #[cfg(kconfig = "alloc_debug")]
+        crate::mm::alloc_debug::untrack(ptr);
+        crate::trace!(Free, ptr, layout.size());
+        unsafe { GlobalAlloc::dealloc(&self.generic, ptr, layout) };
+        self.stats.allocated.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.stats.allocations.fetch_sub(1, Ordering::Relaxed);
     }
 }
@@ -0,0 +1,246 @@
+//! On-demand consistency checks of the kernel heap and page tables
+//!
+//! Corrupted memory usually crashes far away from whatever corrupted it. The `memcheck` shell
+//! command runs these checks to narrow it down while the damage is still fresh: [`heap`] walks the
+//! free list of the heap, and [`page_tables`] checks that every mapping in the kernel half lies in
+//! one of the [`REGIONS`] of [`mappings`], with the flags that region needs. Neither changes
+//! anything, so they are safe to run at any time.
+
+use core::{fmt, ops::Range};
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::VirtAddr,
+    mm::{
+        allocator::{
+            ALLOCATOR,
+            linked_list::{HeapCheck, HeapViolation},
+        },
+        mappings,
+        page_table::{KernelPageTable, MappingRun, PageTableFlags},
+    },
+};
+
+/// The most heap violations kept, the walk runs with the heap locked so they can't be allocated
+pub const MAX_HEAP_VIOLATIONS: usize = 16;
+
+/// A window of the kernel half, and the flags every mapping in it must have or not have
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub name: &'static str,
+    pub range: Range<usize>,
+    pub required: PageTableFlags,
+    pub forbidden: PageTableFlags,
+}
+
+impl Region {
+    const fn new(name: &'static str, start: VirtAddr, end: VirtAddr, required: PageTableFlags) -> Self {
+        Self {
+            name,
+            range: start.as_usize()..end.as_usize(),
+            required,
+            forbidden: PageTableFlags::USER,
+        }
+    }
+
+    const fn forbid(mut self, flags: PageTableFlags) -> Self {
+        self.forbidden = self.forbidden.union(flags);
+        self
+    }
+}
+
+const DATA: PageTableFlags = PageTableFlags::WRITABLE.union(PageTableFlags::NO_EXECUTE);
+
+/// The regions of the kernel half, whatever the KASLR offsets inside of their windows
+pub const REGIONS: &[Region] = &[
+    Region::new(
+        "direct map",
+        mappings::PAGE_TABLE_START,
+        mappings::KERNEL_HEAP_START,
+        PageTableFlags::NO_EXECUTE,
+    ),
+    Region::new("heap", mappings::KERNEL_HEAP_START, mappings::KERNEL_STACK_START, DATA),
+    Region::new(
+        "stacks",
+        mappings::KERNEL_STACK_START,
+        mappings::FRAMEBUFFER_START,
+        DATA,
+    ),
+    Region::new(
+        "framebuffer",
+        mappings::FRAMEBUFFER_START,
+        mappings::FRAMEBUFFER_END,
+        PageTableFlags::NO_EXECUTE,
+    ),
+    Region::new(
+        "mmio",
+        mappings::MMIO_SPACE_START,
+        mappings::MEMORY_MAPPINGS,
+        PageTableFlags::NO_EXECUTE.union(PageTableFlags::NO_CACHE),
+    ),
+    Region::new(
+        "memory map",
+        mappings::MEMORY_MAPPINGS,
+        VirtAddr::new(mappings::MEMORY_MAPPINGS.as_usize() + mappings::MEMORY_MAPPINGS_SIZE),
+        PageTableFlags::NO_EXECUTE,
+    ),
+    Region::new(
+        "boot modules",
+        mappings::BOOT_MODULES_START,
+        mappings::BOOT_MODULES_END,
+        PageTableFlags::NO_EXECUTE,
+    )
+    .forbid(PageTableFlags::WRITABLE),
+    // Split into text, rodata and data, which the W^X audit checks
+    Region::new(
+        "kernel image",
+        mappings::KERNEL_TEXT_START,
+        mappings::MODULE_SPACE_START,
+        PageTableFlags::empty(),
+    ),
+    Region::new(
+        "modules",
+        mappings::MODULE_SPACE_START,
+        mappings::MODULE_SPACE_END,
+        PageTableFlags::empty(),
+    ),
+];
+
+/// A kernel mapping that doesn't fit the region it is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingViolation {
+    /// Part of the mapping is outside of every region
+    Uncovered(MappingRun),
+    Flags {
+        run: MappingRun,
+        region: &'static str,
+        missing: PageTableFlags,
+        forbidden: PageTableFlags,
+    },
+}
+
+impl fmt::Display for MappingViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Uncovered(run) => write!(f, "outside of every region: {}", run),
+            Self::Flags {
+                run,
+                region,
+                missing,
+                forbidden,
+            } => {
+                write!(f, "{}: {}", region, run)?;
+                if !missing.is_empty() {
+                    write!(f, ", missing {:?}", missing)?;
+                }
+                if !forbidden.is_empty() {
+                    write!(f, ", forbidden {:?}", forbidden)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Checks a mapping against every region it overlaps, and that the regions cover all of it
+pub fn check_mapping(regions: &[Region], run: &MappingRun, mut report: impl FnMut(MappingViolation)) {
+    let mut covered = 0;
+    for region in regions
+        .iter()
+        .filter(|region| run.overlaps(region.range.start, region.range.end))
+    {
+        covered += run
+            .virt
+            .max(region.range.start)
+            .abs_diff((run.virt + run.len).min(region.range.end));
+        let missing = region.required.difference(run.flags);
+        let forbidden = region.forbidden.intersection(run.flags);
+        if !missing.is_empty() || !forbidden.is_empty() {
+            report(MappingViolation::Flags {
+                run: *run,
+                region: region.name,
+                missing,
+                forbidden,
+            });
+        }
+    }
+    if covered < run.len {
+        report(MappingViolation::Uncovered(*run));
+    }
+}
+
+/// Returns the violations in the kernel half of a page table
+pub fn page_tables(page_table: &KernelPageTable) -> Vec<MappingViolation> {
+    let mut violations = Vec::new();
+    page_table.for_each_mapping(mappings::KERNEL_MEM_START, VirtAddr::new(usize::MAX), |run| {
+        check_mapping(REGIONS, run, |violation| violations.push(violation));
+    });
+    violations
+}
+
+/// Walks the free list of the heap, returning the first [`MAX_HEAP_VIOLATIONS`] violations
+pub fn heap() -> (HeapCheck, Vec<HeapViolation>) {
+    let mut found = [None; MAX_HEAP_VIOLATIONS];
+    let mut idx = 0;
+    let check = ALLOCATOR.check(|violation| {
+        if let Some(slot) = found.get_mut(idx) {
+            *slot = Some(violation);
+        }
+        idx += 1;
+    });
+    (check, found.into_iter().flatten().collect())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+    use crate::mm::paging::PageSizeKind;
+
+    fn run(virt: VirtAddr, len: usize, flags: PageTableFlags) -> MappingRun {
+        MappingRun {
+            virt: virt.as_usize(),
+            phys: 0,
+            len,
+            size: PageSizeKind::Size4KiB,
+            flags: flags | PageTableFlags::PRESENT,
+        }
+    }
+
+    fn violations(run: &MappingRun) -> Vec<MappingViolation> {
+        let mut violations = Vec::new();
+        check_mapping(REGIONS, run, |violation| violations.push(violation));
+        violations
+    }
+
+    #[test]
+    fn check_mappings() {
+        let heap = run(mappings::KERNEL_HEAP_START, 0x2000, DATA);
+        assert_eq!(violations(&heap), []);
+        let mmio = run(mappings::MMIO_SPACE_START, 0x1000, DATA);
+        assert_eq!(
+            violations(&mmio),
+            [MappingViolation::Flags {
+                run: mmio,
+                region: "mmio",
+                missing: PageTableFlags::NO_CACHE,
+                forbidden: PageTableFlags::empty(),
+            }]
+        );
+        let user = run(mappings::KERNEL_STACK_START, 0x1000, DATA | PageTableFlags::USER);
+        assert_eq!(
+            violations(&user),
+            [MappingViolation::Flags {
+                run: user,
+                region: "stacks",
+                missing: PageTableFlags::empty(),
+                forbidden: PageTableFlags::USER,
+            }]
+        );
+        // Between the stacks and the framebuffer windows, and across the end of the module space
+        let stray = run(mappings::FRAMEBUFFER_END, 0x1000, DATA);
+        assert_eq!(violations(&stray), [MappingViolation::Uncovered(stray)]);
+        let across = run(mappings::MODULE_SPACE_END - 0x1000usize, 0x2000, DATA);
+        assert_eq!(violations(&across), [MappingViolation::Uncovered(across)]);
+    }
+}
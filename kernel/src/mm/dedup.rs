@@ -0,0 +1,106 @@
+//! Sharing identical read-only user pages
+//!
+//! Once an image is loaded, [`AddressSpace::dedup_read_only`](super::address_space::AddressSpace)
+//! hands its read-only pages to [`share`], which looks for a page with the same contents that is
+//! already mapped somewhere and returns that instead. Running the same program twice then costs
+//! its text and read only data once. Shared pages are counted, and freed with their last user.
+//!
+//! Pages are looked up by a hash of their contents, and compared in full on a match. Their
+//! contents can't change while they are shared, so the hash is recomputed when they are released.
+
+use core::{fmt, slice};
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::{
+    mm::{
+        FRAME_ALLOCATOR,
+        page_table::KernelPageTable,
+        paging::{FrameDeallocator, PageSize, PhysFrame, Size4KiB},
+    },
+    sync::Mutex,
+};
+
+#[derive(Debug)]
+struct SharedPage {
+    frame: PhysFrame,
+    refs: usize,
+}
+
+/// The shared pages by the hash of their contents
+static PAGES: Mutex<BTreeMap<u64, Vec<SharedPage>>> = Mutex::new(BTreeMap::new());
+
+fn bytes<'a>(frame: PhysFrame) -> &'a [u8] {
+    let virt = KernelPageTable::direct_map_start() + frame.start_address().as_usize();
+    unsafe { slice::from_raw_parts(virt.as_ptr::<u8>(), Size4KiB::SIZE) }
+}
+
+/// FNV-1a, which is plenty to tell pages apart before comparing them
+fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+/// Returns a shared frame with the same contents as `frame`, which is freed if there already is
+/// one, or becomes the shared frame otherwise
+pub fn share(frame: PhysFrame) -> PhysFrame {
+    let contents = bytes(frame);
+    let mut pages = PAGES.lock();
+    let candidates = pages.entry(hash(contents)).or_default();
+    if let Some(page) = candidates.iter_mut().find(|page| bytes(page.frame) == contents) {
+        page.refs += 1;
+        let shared = page.frame;
+        drop(pages);
+        unsafe { FRAME_ALLOCATOR.lock().deallocate_frame(frame) };
+        return shared;
+    }
+    candidates.push(SharedPage { frame, refs: 1 });
+    frame
+}
+
+/// Drops a reference to a frame returned by [`share`], freeing it with the last one
+pub fn release(frame: PhysFrame) {
+    let key = hash(bytes(frame));
+    let mut pages = PAGES.lock();
+    let candidates = pages.get_mut(&key).expect("releasing a frame that isn't shared");
+    let idx = candidates
+        .iter()
+        .position(|page| page.frame.start_address() == frame.start_address())
+        .expect("releasing a frame that isn't shared");
+    candidates[idx].refs -= 1;
+    if candidates[idx].refs > 0 {
+        return;
+    }
+    candidates.swap_remove(idx);
+    if candidates.is_empty() {
+        pages.remove(&key);
+    }
+    drop(pages);
+    unsafe { FRAME_ALLOCATOR.lock().deallocate_frame(frame) };
+}
+
+pub fn init() {
+    crate::stats::register("dedup", dump_stats);
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    let pages = PAGES.lock();
+    let (shared, refs) = pages
+        .values()
+        .flatten()
+        .fold((0, 0), |(shared, refs), page| (shared + 1, refs + page.refs));
+    writeln!(out, "shared={} mappings={} saved={}", shared, refs, refs - shared)
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedup_hash() {
+        assert_eq!(hash(b""), 0xCBF2_9CE4_8422_2325);
+        assert_eq!(hash(b"a"), 0xAF63_DC4C_8601_EC8C);
+        assert_ne!(hash(&[0; 4096]), hash(&[[0; 4095].as_slice(), &[1]].concat()));
+    }
+}
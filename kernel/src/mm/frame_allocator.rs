@@ -33,6 +33,25 @@ impl KernelFrameAllocator {
         self.memory_map.entries.iter().map(|entry| entry.pages()).sum()
     }
 
+    /// Allocates `count` physically contiguous frames, returning the first one
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        self.memory_map.entries.iter_mut().find_map(|entry| {
+            let idx = entry.allocate_contiguous(count)?;
+            Some(PhysFrame::from_start_address(entry.base + idx * Size4KiB::SIZE))
+        })
+    }
+
+    /// Frees frames returned by [`Self::allocate_contiguous`]
+    ///
+    /// # Safety
+    /// The frames must not be used afterwards.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        for idx in 0..count {
+            let frame = PhysFrame::from_start_address(start.start_address() + idx * Size4KiB::SIZE);
+            unsafe { self.deallocate_frame(frame) };
+        }
+    }
+
     pub fn memory_map(&self) -> &MemoryMap {
         &self.memory_map
     }
@@ -67,6 +86,7 @@ impl FrameDeallocator<Size4KiB> for KernelFrameAllocator {
 
             let idx = (frame.start_address().as_usize() - entry.base.as_usize()) / Size4KiB::SIZE;
             entry.deallocate(idx);
+            return;
         }
         panic!("Deallocating frame that is not allocated");
     }
@@ -0,0 +1,340 @@
+//! Kernel address sanitizer for the heap
+//!
+//! With the `kasan` option, every 8 byte granule of the heap has a shadow byte saying how much of
+//! it may be accessed: `0` for all of it, `1..=7` for only that many leading bytes, and one of the
+//! poison tags otherwise. Allocations are surrounded by poisoned redzones, and freed blocks are
+//! poisoned and kept in a quarantine for a while before they are reused, so overflows and uses
+//! after free hit poisoned memory.
+//!
+//! There is no compiler instrumentation, so only accesses that go through [`read`], [`write`] or
+//! [`check`], and frees, are checked. Without the option, these are plain accesses.
+
+use core::{
+    alloc::Layout,
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::{mm::allocator::MutGlobalAlloc, sync::Once};
+
+/// The bytes covered by a shadow byte
+pub const GRANULE: usize = 8;
+/// The minimum redzone on each side of an allocation
+pub const REDZONE: usize = 16;
+/// The most freed blocks held back from reuse
+const QUARANTINE_LEN: usize = 256;
+/// The most freed bytes held back from reuse
+const QUARANTINE_BYTES: usize = 64 * 1024;
+
+/// Shadow tag of the redzones around allocations
+const TAG_REDZONE: u8 = 0xFA;
+/// Shadow tag of freed memory in the quarantine
+const TAG_FREED: u8 = 0xFB;
+/// Shadow tag of memory that has never been allocated or left the quarantine
+const TAG_UNALLOCATED: u8 = 0xFC;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Free,
+}
+
+/// The first poisoned byte of a checked access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadAccess {
+    pub addr: usize,
+    tag: u8,
+}
+
+impl BadAccess {
+    fn reason(&self) -> &'static str {
+        match self.tag {
+            TAG_FREED => "use after free",
+            TAG_UNALLOCATED => "unallocated memory",
+            // Partially accessible granules also mean an out of bounds access
+            _ => "out of bounds",
+        }
+    }
+}
+
+impl fmt::Display for BadAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {:#x} (shadow {:#04x})", self.reason(), self.addr, self.tag)
+    }
+}
+
+/// The shadow of a heap
+#[derive(Debug, Clone, Copy)]
+pub struct Shadow {
+    start: usize,
+    size: usize,
+    bytes: &'static [AtomicU8],
+}
+
+impl Shadow {
+    fn contains(&self, addr: usize) -> bool {
+        (self.start..self.start + self.size).contains(&addr)
+    }
+
+    fn byte(&self, addr: usize) -> &AtomicU8 {
+        &self.bytes[(addr - self.start) / GRANULE]
+    }
+
+    /// Poisons the granules covering `[addr, addr + len)`, which must start on a granule
+    fn poison(&self, addr: usize, len: usize, tag: u8) {
+        debug_assert!(addr.is_multiple_of(GRANULE));
+        for granule in (addr..addr + len).step_by(GRANULE) {
+            self.byte(granule).store(tag, Ordering::Relaxed);
+        }
+    }
+
+    /// Makes `[addr, addr + len)` accessible, which must start on a granule
+    fn unpoison(&self, addr: usize, len: usize) {
+        debug_assert!(addr.is_multiple_of(GRANULE));
+        for granule in (addr..addr + len).step_by(GRANULE) {
+            let accessible = (addr + len - granule).min(GRANULE);
+            self.byte(granule)
+                .store((accessible % GRANULE) as u8, Ordering::Relaxed);
+        }
+    }
+
+    /// Checks that `[addr, addr + len)` may be accessed, ignoring memory outside of the heap
+    pub fn check(&self, addr: usize, len: usize) -> Result<(), BadAccess> {
+        let end = addr.saturating_add(len);
+        let mut addr = addr;
+        while addr < end {
+            let granule = addr - addr % GRANULE;
+            let next = (granule + GRANULE).min(end);
+            if self.contains(addr) {
+                let tag = self.byte(addr).load(Ordering::Relaxed);
+                let accessible = match tag {
+                    0 => GRANULE,
+                    1..8 => tag as usize,
+                    _ => 0,
+                };
+                if next - granule > accessible {
+                    return Err(BadAccess {
+                        addr: addr.max(granule + accessible),
+                        tag,
+                    });
+                }
+            }
+            addr = next;
+        }
+        Ok(())
+    }
+}
+
+/// The shadow of the kernel heap, once it is initialized
+static SHADOW: Once<Shadow> = Once::new();
+
+/// Reports a bad access, which is fatal
+#[track_caller]
+fn report(bad: BadAccess, kind: AccessKind, len: usize) -> ! {
+    panic!("kasan: {:?} of {} bytes: {}", kind, len, bad);
+}
+
+/// Checks that `len` bytes at `addr` may be accessed, reporting a bad access otherwise
+#[track_caller]
+pub fn check(addr: usize, len: usize, kind: AccessKind) {
+    if !crate::config::KASAN {
+        return;
+    }
+    if let Some(shadow) = SHADOW.get()
+        && let Err(bad) = shadow.check(addr, len)
+    {
+        report(bad, kind, len);
+    }
+}
+
+/// Reads a value after checking that it may be accessed
+///
+/// # Safety
+/// Same as [`core::ptr::read`].
+#[track_caller]
+pub unsafe fn read<T>(ptr: *const T) -> T {
+    check(ptr as usize, size_of::<T>(), AccessKind::Read);
+    unsafe { ptr.read() }
+}
+
+/// Writes a value after checking that it may be accessed
+///
+/// # Safety
+/// Same as [`core::ptr::write`].
+#[track_caller]
+pub unsafe fn write<T>(ptr: *mut T, value: T) {
+    check(ptr as usize, size_of::<T>(), AccessKind::Write);
+    unsafe { ptr.write(value) };
+}
+
+/// Returns the layout of an allocation with its redzones, and the offset of the allocation in it
+fn padded(layout: Layout) -> (Layout, usize) {
+    let left = layout.align().max(REDZONE);
+    let size = left + layout.size().next_multiple_of(GRANULE) + REDZONE;
+    // The left redzone is a multiple of the alignment, so the allocation stays aligned
+    let padded = Layout::from_size_align(size, layout.align().max(GRANULE)).unwrap();
+    (padded, left)
+}
+
+/// A heap allocator with redzones and a quarantine, keeping the shadow at the start of its memory
+pub struct KasanAllocator<A> {
+    inner: A,
+    shadow: Option<Shadow>,
+    /// Freed blocks as their address and user layout, oldest first from `head`
+    quarantine: [(usize, Layout); QUARANTINE_LEN],
+    head: usize,
+    len: usize,
+    bytes: usize,
+}
+
+impl<A: MutGlobalAlloc> KasanAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            shadow: None,
+            quarantine: [(0, Layout::new::<u8>()); QUARANTINE_LEN],
+            head: 0,
+            len: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Splits `[addr, addr + size)` into the shadow and the heap, returning the heap memory
+    ///
+    /// # Safety
+    /// The memory must be valid and unused, and `addr` aligned to [`GRANULE`].
+    pub unsafe fn init_shadow(&mut self, addr: *mut u8, size: usize) -> (*mut u8, usize) {
+        // A shadow byte for every granule, keeping the heap aligned to a page
+        let heap_size = (size * GRANULE / (GRANULE + 1)) & !(GRANULE * 4096 - 1);
+        let shadow_size = heap_size / GRANULE;
+        let heap = addr as usize + shadow_size;
+        // SAFETY: `AtomicU8` has the layout of `u8`, and the caller passes valid memory
+        let bytes = unsafe { core::slice::from_raw_parts(addr as *const AtomicU8, shadow_size) };
+        let shadow = Shadow {
+            start: heap,
+            size: heap_size,
+            bytes,
+        };
+        shadow.poison(heap, heap_size, TAG_UNALLOCATED);
+        self.shadow = Some(shadow);
+        (heap as *mut u8, heap_size)
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut A {
+        &mut self.inner
+    }
+
+    pub fn shadow(&self) -> Option<&Shadow> {
+        self.shadow.as_ref()
+    }
+
+    /// Frees the oldest block in the quarantine
+    fn evict(&mut self) {
+        let (ptr, layout) = self.quarantine[self.head];
+        self.head = (self.head + 1) % QUARANTINE_LEN;
+        self.len -= 1;
+        self.bytes -= layout.size();
+        let (padded, left) = padded(layout);
+        let base = ptr - left;
+        if let Some(shadow) = &self.shadow {
+            shadow.poison(base, padded.size(), TAG_UNALLOCATED);
+        }
+        unsafe { self.inner.dealloc(base as *mut u8, padded) };
+    }
+}
+
+unsafe impl<A: MutGlobalAlloc> MutGlobalAlloc for KasanAllocator<A> {
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (padded, left) = padded(layout);
+        let mut base = unsafe { self.inner.alloc(padded) };
+        // Memory held in the quarantine is released before failing an allocation
+        while base.is_null() && self.len != 0 {
+            self.evict();
+            base = unsafe { self.inner.alloc(padded) };
+        }
+        if base.is_null() {
+            return base;
+        }
+        let ptr = base as usize + left;
+        if let Some(shadow) = &self.shadow {
+            shadow.poison(base as usize, padded.size(), TAG_REDZONE);
+            shadow.unpoison(ptr, layout.size());
+        }
+        ptr as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        if let Some(shadow) = &self.shadow {
+            // A block still in the quarantine is freed twice
+            if layout.size() != 0
+                && let Err(bad) = shadow.check(ptr as usize, layout.size())
+            {
+                report(bad, AccessKind::Free, layout.size());
+            }
+            shadow.poison(ptr as usize, layout.size().next_multiple_of(GRANULE), TAG_FREED);
+        }
+
+        while self.len == QUARANTINE_LEN || (self.len != 0 && self.bytes + layout.size() > QUARANTINE_BYTES) {
+            self.evict();
+        }
+        self.quarantine[(self.head + self.len) % QUARANTINE_LEN] = (ptr as usize, layout);
+        self.len += 1;
+        self.bytes += layout.size();
+    }
+}
+
+/// Starts checking accesses to the kernel heap against `shadow`
+pub fn register(shadow: Shadow) {
+    SHADOW.call_once(|| shadow);
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::mm::allocator::linked_list::LinkedListAllocator;
+
+    fn allocator() -> KasanAllocator<LinkedListAllocator> {
+        let memory = vec![0u64; 128 * 1024].leak();
+        let mut kasan = KasanAllocator::new(LinkedListAllocator::empty());
+        unsafe {
+            let (heap, size) = kasan.init_shadow(memory.as_mut_ptr().cast(), memory.len() * 8);
+            kasan.inner.init(heap, size);
+        }
+        kasan
+    }
+
+    #[test]
+    fn kasan_redzones_and_quarantine() {
+        let mut kasan = allocator();
+        let layout = Layout::from_size_align(13, 4).unwrap();
+        let ptr = unsafe { kasan.alloc(layout) } as usize;
+        let shadow = *kasan.shadow().unwrap();
+
+        assert_eq!(shadow.check(ptr, 13), Ok(()));
+        // One byte past the end is in the partially accessible granule
+        let overflow = shadow.check(ptr + 10, 4).unwrap_err();
+        assert_eq!((overflow.addr, overflow.reason()), (ptr + 13, "out of bounds"));
+        // The left redzone
+        assert_eq!(shadow.check(ptr - 1, 1).unwrap_err().addr, ptr - 1);
+        // Memory outside of the heap is never checked
+        assert_eq!(shadow.check(0x1000, 8), Ok(()));
+
+        unsafe { kasan.dealloc(ptr as *mut u8, layout) };
+        assert_eq!(shadow.check(ptr, 1).unwrap_err().reason(), "use after free");
+        // Freed blocks aren't handed out again while in the quarantine
+        let other = unsafe { kasan.alloc(layout) } as usize;
+        assert_ne!(other, ptr);
+        assert_eq!(kasan.len, 1);
+
+        // Once evicted, the block is unallocated until reused
+        kasan.evict();
+        assert_eq!(shadow.check(ptr, 1).unwrap_err().reason(), "unallocated memory");
+    }
+}
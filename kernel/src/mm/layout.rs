@@ -0,0 +1,136 @@
+//! The runtime layout of the kernel half
+//!
+//! [`mappings`] reserves a window of the address space for each region. Without KASLR every
+//! region starts at the start of its window, with KASLR the boot code slides the direct map, the
+//! heap, the stacks and the MMIO space to a random aligned offset inside of their windows before
+//! building the kernel page table. Code that needs the address of one of these regions reads it
+//! from [`layout`], never from [`mappings`].
+
+use core::ops::Range;
+
+use crate::{
+    arch::VirtAddr,
+    mm::{
+        mappings,
+        paging::{PageSize, Size1GiB, Size2MiB},
+    },
+    sync::cell::RacyCell,
+};
+
+static LAYOUT: RacyCell<KernelLayout> = RacyCell::new(KernelLayout::FIXED);
+
+/// Returns the layout of the running kernel
+pub fn layout() -> &'static KernelLayout {
+    LAYOUT.get()
+}
+
+/// Sets the layout of the kernel
+///
+/// # Safety
+/// Must only be called once during boot, before anything has been mapped at the old layout.
+pub(crate) unsafe fn set_layout(layout: KernelLayout) {
+    *LAYOUT.get_mut() = layout;
+}
+
+const DIRECT_MAP_WINDOW: Range<usize> = mappings::PAGE_TABLE_START.as_usize()..mappings::KERNEL_HEAP_START.as_usize();
+const HEAP_WINDOW: Range<usize> = mappings::KERNEL_HEAP_START.as_usize()..mappings::KERNEL_STACK_START.as_usize();
+const STACK_WINDOW: Range<usize> = mappings::KERNEL_STACK_START.as_usize()..mappings::FRAMEBUFFER_START.as_usize();
+const MMIO_WINDOW: Range<usize> = mappings::MMIO_SPACE_START.as_usize()..mappings::MEMORY_MAPPINGS.as_usize();
+
+/// The bases of the regions of the kernel half that move with KASLR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelLayout {
+    /// The virtual address of physical address 0
+    pub direct_map: VirtAddr,
+    pub heap: VirtAddr,
+    pub stacks: VirtAddr,
+    pub mmio: VirtAddr,
+}
+
+impl KernelLayout {
+    /// The layout without KASLR, every region at the start of its window
+    pub const FIXED: Self = Self {
+        direct_map: mappings::PAGE_TABLE_START,
+        heap: mappings::KERNEL_HEAP_START,
+        stacks: mappings::KERNEL_STACK_START,
+        mmio: mappings::MMIO_SPACE_START,
+    };
+
+    /// Returns a layout with each region at a random offset in its window
+    ///
+    /// The direct map stays 1GiB aligned, and keeps room for physical memory up to `phys_end`.
+    /// The other regions are 2MiB aligned.
+    pub fn randomized(phys_end: usize, mut random: impl FnMut() -> u64) -> Self {
+        let phys_end = phys_end.next_multiple_of(Size1GiB::SIZE);
+        Self {
+            direct_map: VirtAddr::new(slide(DIRECT_MAP_WINDOW, phys_end, Size1GiB::SIZE, random())),
+            heap: VirtAddr::new(slide(HEAP_WINDOW, mappings::KERNEL_HEAP_SIZE, Size2MiB::SIZE, random())),
+            stacks: VirtAddr::new(slide(
+                STACK_WINDOW,
+                mappings::TOTAL_KERNEL_STACK_SIZE,
+                Size2MiB::SIZE,
+                random(),
+            )),
+            mmio: VirtAddr::new(slide(MMIO_WINDOW, mappings::MMIO_SPACE_SIZE, Size2MiB::SIZE, random())),
+        }
+    }
+
+    /// Returns the size of the physical memory the direct map can cover
+    pub fn direct_map_size(&self) -> usize {
+        DIRECT_MAP_WINDOW.end - self.direct_map.as_usize()
+    }
+
+    pub fn heap_end(&self) -> VirtAddr {
+        self.heap + mappings::KERNEL_HEAP_SIZE
+    }
+
+    /// Returns the end of the stack region, which is the top of the boot stack
+    pub fn stacks_end(&self) -> VirtAddr {
+        self.stacks + mappings::TOTAL_KERNEL_STACK_SIZE
+    }
+
+    pub fn mmio_end(&self) -> VirtAddr {
+        self.mmio + mappings::MMIO_SPACE_SIZE
+    }
+}
+
+/// Returns the start of a region of `size` bytes at a random `align`ed offset in the window
+///
+/// A region that doesn't fit in the window is placed at its start.
+fn slide(window: Range<usize>, size: usize, align: usize, random: u64) -> usize {
+    let Some(room) = window.len().checked_sub(size) else {
+        return window.start;
+    };
+    let slots = (room / align) as u64 + 1;
+    window.start + (random % slots) as usize * align
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_slide() {
+        assert_eq!(slide(0x1000..0x5000, 0x2000, 0x1000, 0), 0x1000);
+        assert_eq!(slide(0x1000..0x5000, 0x2000, 0x1000, 2), 0x3000);
+        // There are 3 slots, so the random value wraps around
+        assert_eq!(slide(0x1000..0x5000, 0x2000, 0x1000, 4), 0x2000);
+        assert_eq!(slide(0x1000..0x5000, 0x8000, 0x1000, 7), 0x1000);
+
+        let phys_end = 6 * Size1GiB::SIZE + 1;
+        let mut values = [u64::MAX, u64::MAX / 3, 12345, 0].into_iter();
+        let layout = KernelLayout::randomized(phys_end, || values.next().unwrap());
+        assert_ne!(layout, KernelLayout::FIXED);
+        assert!(layout.direct_map_size() >= 7 * Size1GiB::SIZE);
+        assert_eq!(layout.direct_map.as_usize() % Size1GiB::SIZE, 0);
+        for (start, end, window) in [
+            (layout.heap, layout.heap_end(), HEAP_WINDOW),
+            (layout.stacks, layout.stacks_end(), STACK_WINDOW),
+            (layout.mmio, layout.mmio_end(), MMIO_WINDOW),
+        ] {
+            assert_eq!(start.as_usize() % Size2MiB::SIZE, 0);
+            assert!(window.start <= start.as_usize() && end.as_usize() <= window.end);
+        }
+        assert_eq!(layout.mmio, KernelLayout::FIXED.mmio);
+    }
+}
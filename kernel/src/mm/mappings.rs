@@ -43,5 +43,18 @@ pub const MMIO_SPACE_SIZE: usize = MMIO_SPACE_END.as_usize() - MMIO_SPACE_START.
 pub const MEMORY_MAPPINGS: VirtAddr = VirtAddr::new(0xFFFF_F800_0000_0000);
 pub const MEMORY_MAPPINGS_SIZE: usize = 0xFFFF_F900_0000_0000 - MEMORY_MAPPINGS.as_usize();
 
+/// Images of the modules loaded by the bootloader, mapped read only
+pub const BOOT_MODULES_START: VirtAddr = VirtAddr::new(0xFFFF_F900_0000_0000);
+pub const BOOT_MODULES_END: VirtAddr = VirtAddr::new(0xFFFF_FA00_0000_0000);
+/// The Size of the Boot Modules (1 TiB)
+pub const BOOT_MODULES_SIZE: usize = BOOT_MODULES_END.as_usize() - BOOT_MODULES_START.as_usize();
+
 pub const KERNEL_TEXT_START: VirtAddr = VirtAddr::new(0xFFFF_FFFF_8000_0000);
-pub const KERNEL_TEXT_SIZE: usize = 0usize.wrapping_sub(KERNEL_TEXT_START.as_usize());
+pub const KERNEL_TEXT_SIZE: usize = MODULE_SPACE_START.as_usize() - KERNEL_TEXT_START.as_usize();
+
+/// Loaded kernel modules, within 2GiB of the kernel text so modules can call into it with 32 bit
+/// relative addressing
+pub const MODULE_SPACE_START: VirtAddr = VirtAddr::new(0xFFFF_FFFF_C000_0000);
+pub const MODULE_SPACE_END: VirtAddr = VirtAddr::new(0xFFFF_FFFF_F000_0000);
+/// The Size of the Module Space (768 MiB)
+pub const MODULE_SPACE_SIZE: usize = MODULE_SPACE_END.as_usize() - MODULE_SPACE_START.as_usize();
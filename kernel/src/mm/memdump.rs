@@ -0,0 +1,140 @@
+//! Memory dumps over serial, for post-mortem analysis without a debugger
+//!
+//! A physical or virtual address range is sent over the serial console as Intel HEX (see
+//! [`ihex`]), between marker lines and after comment lines describing the range and how to
+//! decode it, so a dump cut out of a serial log is self describing:
+//!
+//! ```text
+//! # hadron memory dump: virtual 0xffffffff80000000..0xffffffff80001000 (4096 bytes)
+//! # Intel HEX, record addresses are offsets from 0xffffffff80000000, unmapped pages are left out
+//! # decode: sed -n '/BEGIN HADRON MEMDUMP/,/END HADRON MEMDUMP/{//!p}' serial.log > dump.hex
+//! #         objcopy -I ihex -O binary --gap-fill 0 dump.hex dump.bin
+//! -----BEGIN HADRON MEMDUMP-----
+//! :020000040000FA
+//! :10000000...
+//! :00000001FF
+//! -----END HADRON MEMDUMP-----
+//! ```
+//!
+//! Only mapped memory is read, but reading memory mapped device registers can have side effects.
+
+use core::fmt::{self, Write};
+
+use crate::{
+    arch::{VirtAddr, registers::control::Cr3},
+    dev::drivers::platform::serial,
+    mm::{
+        layout,
+        page_table::KernelPageTable,
+        paging::{PageSize, Size4KiB},
+    },
+    util::ihex,
+};
+
+const BEGIN_MARKER: &str = "-----BEGIN HADRON MEMDUMP-----";
+const END_MARKER: &str = "-----END HADRON MEMDUMP-----";
+
+/// The largest range that can be dumped, the address space of Intel HEX
+pub const MAX_DUMP_SIZE: usize = 1 << 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressKind {
+    Physical,
+    Virtual,
+}
+
+impl fmt::Display for AddressKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Physical => "physical",
+            Self::Virtual => "virtual",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpError {
+    /// The range is empty, larger than [`MAX_DUMP_SIZE`], wraps around or is past the direct map
+    InvalidRange,
+    NoSerial,
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidRange => "invalid address range",
+            Self::NoSerial => "no serial console",
+        })
+    }
+}
+
+impl core::error::Error for DumpError {}
+
+/// What was sent by [`dump_serial`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DumpSummary {
+    pub dumped: usize,
+    /// Bytes left out, because they aren't mapped
+    pub skipped: usize,
+}
+
+/// Returns the kernel virtual address to read an address through, if it is mapped
+fn readable(page_table: &KernelPageTable, kind: AddressKind, addr: usize) -> Option<VirtAddr> {
+    let virt = match kind {
+        // Physical memory is read through the direct map, which only covers RAM
+        AddressKind::Physical => KernelPageTable::direct_map_start() + addr,
+        AddressKind::Virtual => VirtAddr::try_new(addr).ok()?,
+    };
+    page_table.translate(virt).map(|_| virt)
+}
+
+/// Sends `len` bytes at `start` over the serial console
+pub fn dump_serial(kind: AddressKind, start: usize, len: usize) -> Result<DumpSummary, DumpError> {
+    let end = start.checked_add(len).ok_or(DumpError::InvalidRange)?;
+    if len == 0 || len > MAX_DUMP_SIZE || (kind == AddressKind::Physical && end > layout::layout().direct_map_size()) {
+        return Err(DumpError::InvalidRange);
+    }
+    let mut serial = serial::console().ok_or(DumpError::NoSerial)?;
+
+    // Writes to the serial port can't fail
+    _ = writeln!(
+        serial,
+        "# hadron memory dump: {} {:#x}..{:#x} ({} bytes)",
+        kind, start, end, len
+    );
+    _ = writeln!(
+        serial,
+        "# Intel HEX, record addresses are offsets from {:#x}, unmapped pages are left out",
+        start
+    );
+    _ = writeln!(
+        serial,
+        "# decode: sed -n '/BEGIN HADRON MEMDUMP/,/END HADRON MEMDUMP/{{//!p}}' serial.log > dump.hex"
+    );
+    _ = writeln!(
+        serial,
+        "#         objcopy -I ihex -O binary --gap-fill 0 dump.hex dump.bin"
+    );
+    _ = writeln!(serial, "{}", BEGIN_MARKER);
+
+    let page_table = KernelPageTable::new(Cr3::addr());
+    let mut encoder = ihex::Encoder::new();
+    let mut summary = DumpSummary { dumped: 0, skipped: 0 };
+    let mut addr = start;
+    while addr < end {
+        let chunk = (Size4KiB::SIZE - addr % Size4KiB::SIZE).min(end - addr);
+        match readable(&page_table, kind, addr) {
+            Some(virt) => {
+                // SAFETY: The page is mapped, and the chunk doesn't cross into the next page
+                let data = unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), chunk) };
+                encoder.data((addr - start) as u32, data, |line| _ = writeln!(serial, "{}", line));
+                summary.dumped += chunk;
+            }
+            None => summary.skipped += chunk,
+        }
+        addr += chunk;
+    }
+    encoder.end(|line| _ = writeln!(serial, "{}", line));
+    _ = writeln!(serial, "{}", END_MARKER);
+    Ok(summary)
+}
@@ -1,3 +1,5 @@
+use core::ops::Range;
+
 use alloc::vec::Vec;
 
 use crate::{
@@ -35,6 +37,15 @@ impl MemoryRegion {
         self.bitmap.size()
     }
 
+    pub fn base(&self) -> PhysAddr {
+        self.base
+    }
+
+    /// Returns the number of allocated pages out of the pages in `range`
+    pub fn used_pages(&self, range: Range<usize>) -> usize {
+        self.bitmap.count_ones(range)
+    }
+
     pub(super) fn contains(&self, addr: PhysAddr) -> bool {
         addr >= self.base && addr < self.base + self.pages() * Size4KiB::SIZE
     }
@@ -45,6 +56,15 @@ impl MemoryRegion {
         Some(idx)
     }
 
+    /// Allocates `count` contiguous pages, returning the index of the first one
+    pub(super) fn allocate_contiguous(&mut self, count: usize) -> Option<usize> {
+        let start = self.bitmap.find_free_run(count)?;
+        for idx in start..start + count {
+            self.bitmap.set(idx, true);
+        }
+        Some(start)
+    }
+
     pub(super) fn deallocate(&mut self, idx: usize) {
         self.bitmap.set(idx, false);
     }
@@ -98,6 +118,20 @@ where
         (self.0[byte] & (1 << bit)) != 0
     }
 
+    /// Returns the number of set bits in `range`
+    pub fn count_ones(&self, range: Range<usize>) -> usize {
+        let mut count = 0;
+        let mut idx = range.start;
+        while idx < range.end {
+            let bit = idx % 64;
+            let len = (64 - bit).min(range.end - idx);
+            let mask = (u64::MAX >> (64 - len)) << bit;
+            count += (self.0[idx / 64] & mask).count_ones() as usize;
+            idx += len;
+        }
+        count
+    }
+
     pub fn find_free(&self) -> Option<usize> {
         for (idx, byte) in self.0.iter().enumerate() {
             // We don't need to check bit-by-bit, because the bitmap is a full 64-bit word
@@ -118,6 +152,20 @@ where
         None
     }
 
+    /// Returns the index of the first run of `count` clear bits
+    pub fn find_free_run(&self, count: usize) -> Option<usize> {
+        let mut start = 0;
+        for idx in 0..self.1 {
+            if idx - start == count {
+                break;
+            }
+            if self.0[idx / 64] & (1 << (idx % 64)) != 0 {
+                start = idx + 1;
+            }
+        }
+        (count != 0 && self.1 - start >= count).then_some(start)
+    }
+
     pub unsafe fn resize(&mut self, new_size: usize) {
         assert!(new_size > self.size());
         let new_size = new_size.div_ceil(64);
@@ -154,3 +202,37 @@ pub struct SpecialMemoryRegion {
     pub(crate) length: usize,
     pub(crate) tag: MemoryRegionTag,
 }
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_map_bitmap_count_ones() {
+        let mut bitmap = Bitmap::new(200);
+        for idx in [0, 5, 63, 64, 130, 199] {
+            bitmap.set(idx, true);
+        }
+        assert_eq!(bitmap.count_ones(0..200), 6);
+        assert_eq!(bitmap.count_ones(5..64), 2);
+        assert_eq!(bitmap.count_ones(6..63), 0);
+        assert_eq!(bitmap.count_ones(63..131), 3);
+        assert_eq!(bitmap.count_ones(10..10), 0);
+    }
+
+    #[test]
+    fn memory_map_bitmap_free_runs() {
+        let mut bitmap = Bitmap::new(200);
+        for idx in [0, 3, 70] {
+            bitmap.set(idx, true);
+        }
+        assert_eq!(bitmap.find_free_run(2), Some(1));
+        assert_eq!(bitmap.find_free_run(3), Some(4));
+        // Runs can cross words
+        assert_eq!(bitmap.find_free_run(66), Some(4));
+        assert_eq!(bitmap.find_free_run(67), Some(71));
+        assert_eq!(bitmap.find_free_run(129), Some(71));
+        assert_eq!(bitmap.find_free_run(130), None);
+        assert_eq!(bitmap.find_free_run(0), None);
+    }
+}
@@ -0,0 +1,163 @@
+//! Mapping of device memory into the MMIO space
+//!
+//! Registers are accessed with [`read`] and [`write`], relative to the start of the region, which
+//! `io_audit` builds check against the region, see [`io_audit`].
+
+use core::{
+    fmt,
+    panic::Location,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    arch::{PhysAddr, VirtAddr, registers::control::Cr3},
+    dev::io_audit,
+    mm::{
+        FRAME_ALLOCATOR, layout,
+        page_table::{KernelPageTable, Mapper, PageTableFlags},
+        paging::{Page, PageSize, PhysFrame, Size4KiB},
+    },
+};
+
+/// The offset of the next free virtual address in the MMIO space
+///
+/// Virtual space is never reused, unmapping a region only removes its page table entries.
+static NEXT_MMIO: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub struct MmioSpaceExhausted;
+
+impl fmt::Display for MmioSpaceExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MMIO virtual address space exhausted")
+    }
+}
+
+impl core::error::Error for MmioSpaceExhausted {}
+
+/// A region of device memory mapped uncached into the MMIO space
+#[derive(Debug)]
+pub struct MmioRegion {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: usize,
+}
+
+impl MmioRegion {
+    /// Returns the virtual address of the start of the mapped physical region
+    pub fn virt(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Reads the register at `offset`, see [`read`]
+    ///
+    /// # Safety
+    /// The register must be inside the region.
+    #[track_caller]
+    pub unsafe fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { read(self.virt, offset) }
+    }
+
+    /// Writes the register at `offset`, see [`write`]
+    ///
+    /// # Safety
+    /// The register must be inside the region.
+    #[track_caller]
+    pub unsafe fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { write(self.virt, offset, value) }
+    }
+
+    fn page_range(&self) -> (VirtAddr, usize) {
+        let offset = self.phys.as_usize() % Size4KiB::SIZE;
+        let pages = (offset + self.size).div_ceil(Size4KiB::SIZE);
+        (self.virt - offset, pages)
+    }
+}
+
+/// Maps a physical region of device memory, returning the mapping
+///
+/// # Safety
+/// The physical region must belong to a device, and not to memory managed by the frame allocator.
+#[track_caller]
+pub unsafe fn map(phys: PhysAddr, size: usize) -> Result<MmioRegion, MmioSpaceExhausted> {
+    let offset = phys.as_usize() % Size4KiB::SIZE;
+    let pages = (offset + size).div_ceil(Size4KiB::SIZE);
+    let layout = layout::layout();
+    let base = layout.mmio.as_usize() + NEXT_MMIO.fetch_add(pages * Size4KiB::SIZE, Ordering::Relaxed);
+    if base + pages * Size4KiB::SIZE > layout.mmio_end().as_usize() {
+        return Err(MmioSpaceExhausted);
+    }
+
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE | PageTableFlags::NO_CACHE;
+    let phys_base = phys.as_usize() - offset;
+    let mut page_table = KernelPageTable::new(Cr3::addr());
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    for i in 0..pages {
+        let page = Page::<Size4KiB>::from_start_address(VirtAddr::new(base + i * Size4KiB::SIZE));
+        let frame = PhysFrame::from_start_address(PhysAddr::new(phys_base + i * Size4KiB::SIZE));
+        unsafe { page_table.map_with_allocator(page, frame, flags, &mut *frame_allocator) };
+    }
+
+    io_audit::mapped(VirtAddr::new(base + offset), phys, size, Location::caller());
+    Ok(MmioRegion {
+        virt: VirtAddr::new(base + offset),
+        phys,
+        size,
+    })
+}
+
+/// Reads the register at `offset` into the region mapped at `base`
+///
+/// # Safety
+/// `base` must be the start of a mapped region, with the register inside it.
+#[track_caller]
+pub unsafe fn read<T: Copy>(base: VirtAddr, offset: usize) -> T {
+    io_audit::check_mmio(base, offset, size_of::<T>());
+    unsafe { (base + offset).as_ptr::<T>().read_volatile() }
+}
+
+/// Writes the register at `offset` into the region mapped at `base`
+///
+/// # Safety
+/// `base` must be the start of a mapped region, with the register inside it.
+#[track_caller]
+pub unsafe fn write<T: Copy>(base: VirtAddr, offset: usize, value: T) {
+    io_audit::check_mmio(base, offset, size_of::<T>());
+    unsafe { (base + offset).as_mut_ptr::<T>().write_volatile(value) }
+}
+
+/// Removes the mapping of the region
+///
+/// # Safety
+/// There must be no remaining references into the region.
+pub unsafe fn unmap(region: MmioRegion) {
+    let (start, pages) = region.page_range();
+    io_audit::unmapped(region.virt);
+    unsafe { unmap_pages(start, pages) };
+}
+
+/// Removes the mapping of a region mapped at `virt` by [`map`], when only its address is known
+///
+/// # Safety
+/// `virt` and `size` must be those of a mapped region, with no remaining references into it.
+pub unsafe fn unmap_raw(virt: VirtAddr, size: usize) {
+    let offset = virt.as_usize() % Size4KiB::SIZE;
+    io_audit::unmapped(virt);
+    unsafe { unmap_pages(virt - offset, (offset + size).div_ceil(Size4KiB::SIZE)) };
+}
+
+unsafe fn unmap_pages(start: VirtAddr, pages: usize) {
+    let mut page_table = KernelPageTable::new(Cr3::addr());
+    for i in 0..pages {
+        unsafe { page_table.unmap(Page::<Size4KiB>::from_start_address(start + i * Size4KiB::SIZE)) };
+    }
+}
@@ -1,10 +1,27 @@
 use crate::{mm::frame_allocator::KernelFrameAllocator, sync::mutex::UninitMutex};
 
+pub mod address_space;
+pub mod alloc_debug;
 pub mod allocator;
+pub mod check;
+pub mod dedup;
 pub mod frame_allocator;
+pub mod kasan;
+pub mod layout;
 pub mod mappings;
+#[cfg(target_arch = "x86_64")]
+pub mod memdump;
 pub mod memory_map;
+pub mod mmio;
 pub mod page_table;
 pub mod paging;
+pub mod scrub;
+pub mod shrink;
+pub mod stack;
+pub mod stats;
+pub mod wx;
+
+pub use alloc_debug::dump_leaks;
+pub use stats::stats;
 
 pub static FRAME_ALLOCATOR: UninitMutex<KernelFrameAllocator> = UninitMutex::<KernelFrameAllocator>::uninit();
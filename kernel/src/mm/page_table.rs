@@ -9,11 +9,20 @@ use crate::{
     arch::{PhysAddr, VirtAddr},
     kprintln,
     mm::{
-        mappings,
-        paging::{FrameAllocator, Page, PageSize, PhysFrame, Size4KiB},
+        layout,
+        paging::{FrameAllocator, Page, PageSize, PageSizeKind, PhysFrame, Size4KiB},
     },
 };
 
+/// Leaves out the no-execute bit on CPUs without NX, where it is reserved
+fn supported(flags: PageTableFlags) -> PageTableFlags {
+    #[cfg(target_arch = "x86_64")]
+    if !crate::arch::x86_64::cpu::has(crate::arch::x86_64::cpu::Feature::Nx) {
+        return flags - PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
 #[derive(Debug)]
 #[repr(C, align(4096))]
 pub struct PageTable {
@@ -60,7 +69,7 @@ impl PageTableEntry {
 
     pub fn set_addr(&mut self, addr: PhysAddr, flags: PageTableFlags) {
         assert!(addr.is_aligned(Size4KiB::SIZE), "page address is not page aligned");
-        self.entry = addr.as_u64() | flags.bits();
+        self.entry = addr.as_u64() | supported(flags).bits();
     }
 
     pub fn flags(&self) -> PageTableFlags {
@@ -68,11 +77,11 @@ impl PageTableEntry {
     }
 
     pub fn set_flags(&mut self, flags: PageTableFlags) {
-        self.entry = self.addr().as_u64() | flags.bits();
+        self.entry = self.addr().as_u64() | supported(flags).bits();
     }
 
     pub fn set_frame(&mut self, frame: PhysFrame, flags: PageTableFlags) {
-        self.entry = frame.start_address().as_u64() | flags.bits()
+        self.entry = frame.start_address().as_u64() | supported(flags).bits()
     }
 
     pub fn is_present(&self) -> bool {
@@ -92,25 +101,43 @@ impl fmt::Debug for PageTableEntry {
 /// THe Page Table for the Kernel
 ///
 /// The Page Table is implemented using the Direct Mapping Strategy
+///
+/// With 5-level paging, the root is a PML5 whose last entry is the PML4 of the kernel half.
 pub struct KernelPageTable {
     pml4: VirtAddr,
+    pml5: Option<VirtAddr>,
 }
 
 impl KernelPageTable {
-    pub const DIRECT_MAP_START: VirtAddr = mappings::PAGE_TABLE_START;
-    pub const DIRECT_MAP_OFFSET: usize = Self::DIRECT_MAP_START.as_usize();
-    pub fn new(pml4: PhysAddr) -> Self {
+    /// The PML5 entry of the kernel half with 5-level paging
+    pub const KERNEL_PML5_INDEX: usize = 511;
+
+    /// Returns the start of the direct map, which moves with KASLR
+    pub fn direct_map_start() -> VirtAddr {
+        layout::layout().direct_map
+    }
+
+    pub fn new(root: PhysAddr) -> Self {
+        let root = Self::direct_map_start() + root.as_usize();
+        if !VirtAddr::is_five_level() {
+            return Self { pml4: root, pml5: None };
+        }
+        // SAFETY: The root is a page table, which is direct mapped
+        let pml5 = unsafe { &*root.as_ptr::<PageTable>() };
+        let entry = &pml5[Self::KERNEL_PML5_INDEX];
+        assert!(entry.is_present(), "kernel half of the PML5 is not mapped");
         Self {
-            pml4: Self::DIRECT_MAP_START + pml4.as_usize(),
+            pml4: Self::direct_map_start() + entry.addr().as_usize(),
+            pml5: Some(root),
         }
     }
 }
 
 #[cfg(target_arch = "x86_64")]
 impl KernelPageTable {
-    const PAGE_TABLE_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
-        PageTableFlags::PRESENT.bits() | PageTableFlags::WRITABLE.bits() | PageTableFlags::NO_EXECUTE.bits(),
-    );
+    /// Flags of the intermediate tables, permissions are only restricted in the leaf entries
+    const PAGE_TABLE_FLAGS: PageTableFlags =
+        PageTableFlags::from_bits_truncate(PageTableFlags::PRESENT.bits() | PageTableFlags::WRITABLE.bits());
 
     fn to_pt<'a>(addr: VirtAddr) -> &'a PageTable {
         unsafe { addr.as_ptr::<PageTable>().as_ref().expect("pml4 is null!") }
@@ -131,10 +158,10 @@ impl KernelPageTable {
     fn get_or_create_pdpt(&mut self, index: usize, alloc: &mut impl FrameAllocator<Size4KiB>) -> &mut PageTable {
         let entry = &mut self.pml4_mut()[index];
         if entry.is_present() {
-            return Self::to_pt_mut(Self::DIRECT_MAP_START + entry.addr().as_usize());
+            return Self::to_pt_mut(Self::direct_map_start() + entry.addr().as_usize());
         }
         let frame = alloc.allocate_frame().expect("no frames to allocate");
-        let virt = Self::DIRECT_MAP_START + frame.start_address().as_usize();
+        let virt = Self::direct_map_start() + frame.start_address().as_usize();
         entry.set_frame(frame, Self::PAGE_TABLE_FLAGS);
         unsafe {
             self.map_with_allocator(
@@ -160,10 +187,10 @@ impl KernelPageTable {
     ) -> &mut PageTable {
         let entry = &mut self.get_or_create_pdpt(pdpt_index, alloc)[index];
         if entry.is_present() {
-            return Self::to_pt_mut(Self::DIRECT_MAP_START + entry.addr().as_usize());
+            return Self::to_pt_mut(Self::direct_map_start() + entry.addr().as_usize());
         }
         let frame = alloc.allocate_frame().expect("no frames to allocate");
-        let virt = Self::DIRECT_MAP_START + frame.start_address().as_usize();
+        let virt = Self::direct_map_start() + frame.start_address().as_usize();
         entry.set_frame(frame, Self::PAGE_TABLE_FLAGS);
         unsafe {
             invlpg(virt);
@@ -182,10 +209,10 @@ impl KernelPageTable {
     ) -> &mut PageTable {
         let entry = &mut self.get_or_create_pd(pdpt_index, pd_index, alloc)[index];
         if entry.is_present() {
-            return Self::to_pt_mut(Self::DIRECT_MAP_START + entry.addr().as_usize());
+            return Self::to_pt_mut(Self::direct_map_start() + entry.addr().as_usize());
         }
         let frame = alloc.allocate_frame().expect("no frames to allocate");
-        let virt = Self::DIRECT_MAP_START + frame.start_address().as_usize();
+        let virt = Self::direct_map_start() + frame.start_address().as_usize();
         entry.set_frame(frame, Self::PAGE_TABLE_FLAGS);
         unsafe {
             invlpg(virt);
@@ -195,7 +222,133 @@ impl KernelPageTable {
         new_pt
     }
 
+    /// Returns the existing page table for the given indices, without creating missing tables
+    fn get_pt_mut(&mut self, pdpt_index: usize, pd_index: usize, index: usize) -> Option<&mut PageTable> {
+        let mut table = self.pml4_mut();
+        for idx in [pdpt_index, pd_index, index] {
+            let entry = &table[idx];
+            if !entry.is_present() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return None;
+            }
+            table = Self::to_pt_mut(Self::direct_map_start() + entry.addr().as_usize());
+        }
+        Some(table)
+    }
+
+    /// Walks the tables for an address, returning its mapping, or the size of the unmapped
+    /// region it is in if an entry is missing
+    fn walk(&self, addr: VirtAddr) -> Result<(PhysAddr, PageSizeKind, PageTableFlags), usize> {
+        let mut table = match self.pml5 {
+            Some(pml5) => {
+                let entry = &Self::to_pt(pml5)[addr.p5_index()];
+                if !entry.is_present() {
+                    return Err(1 << 48);
+                }
+                Self::to_pt(Self::direct_map_start() + entry.addr().as_usize())
+            }
+            None => self.pml4(),
+        };
+        // Writes and user accesses need every level to allow them, execution no level to forbid it
+        let mut allowed = PageTableFlags::WRITABLE | PageTableFlags::USER;
+        let mut no_execute = PageTableFlags::empty();
+        // The number of address bits covered by an entry of the current level
+        let mut shift = 39;
+        for idx in [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()] {
+            let entry = &table[idx];
+            if !entry.is_present() {
+                return Err(1 << shift);
+            }
+            let flags = entry.flags();
+            if shift == 12 || (shift < 39 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+                // Bit 12 of a huge page entry is the PAT bit, not part of the address
+                let mask = (1 << shift) - 1;
+                let phys = PhysAddr::new((entry.addr().as_usize() & !mask) | (addr.as_usize() & mask));
+                let size = match shift {
+                    30 => PageSizeKind::Size1GiB,
+                    21 => PageSizeKind::Size2MiB,
+                    _ => PageSizeKind::Size4KiB,
+                };
+                let restricted = (PageTableFlags::WRITABLE | PageTableFlags::USER).difference(allowed);
+                return Ok((phys, size, flags.difference(restricted) | no_execute));
+            }
+            allowed &= flags;
+            no_execute |= flags & PageTableFlags::NO_EXECUTE;
+            table = Self::to_pt(Self::direct_map_start() + entry.addr().as_usize());
+            shift -= 9;
+        }
+        unreachable!()
+    }
+
+    /// Returns the physical address a virtual address is mapped to, with the size of its page
+    /// and the effective flags, combining the permissions of every level
+    pub fn translate(&self, addr: VirtAddr) -> Option<(PhysAddr, PageSizeKind, PageTableFlags)> {
+        self.walk(addr).ok()
+    }
+
+    /// Writes the mappings in `[start, end)`, merging contiguous pages with the same flags
+    pub fn dump_range(&self, start: VirtAddr, end: VirtAddr, out: &mut dyn fmt::Write) -> fmt::Result {
+        let mut result = Ok(());
+        self.for_each_mapping(start, end, |run| {
+            if result.is_ok() {
+                result = writeln!(out, "{}", run);
+            }
+        });
+        result
+    }
+
+    /// Calls `f` with the mappings in `[start, end)`, merging contiguous pages with the same flags
+    pub fn for_each_mapping(&self, start: VirtAddr, end: VirtAddr, mut f: impl FnMut(&MappingRun)) {
+        // The first address of the higher half, after the non-canonical hole
+        let higher_half = usize::MAX << (VirtAddr::bits() - 1);
+        let mut addr = start.as_usize();
+        let mut run: Option<MappingRun> = None;
+        while addr < end.as_usize() {
+            let Ok(virt) = VirtAddr::try_new(addr) else {
+                addr = higher_half;
+                continue;
+            };
+            let size = match self.walk(virt) {
+                Ok((phys, size, flags)) => {
+                    // The walk may start in the middle of a page
+                    let offset = addr % size.size();
+                    let mapping = MappingRun {
+                        virt: addr - offset,
+                        phys: phys.as_usize() - offset,
+                        len: size.size(),
+                        size,
+                        flags,
+                    };
+                    if !run.as_mut().is_some_and(|run| run.extend(&mapping))
+                        && let Some(run) = run.replace(mapping)
+                    {
+                        f(&run);
+                    }
+                    size.size()
+                }
+                Err(unmapped) => {
+                    if let Some(run) = run.take() {
+                        f(&run);
+                    }
+                    unmapped
+                }
+            };
+            // Continues at the next entry of the level the walk stopped at
+            match (addr | (size - 1)).checked_add(1) {
+                Some(next) => addr = next,
+                None => break,
+            }
+        }
+        if let Some(run) = run {
+            f(&run);
+        }
+    }
+
     pub fn dump(&self) {
+        // With 5-level paging, only the PML4 of the kernel half is dumped
+        let pml5_bits = match self.pml5 {
+            Some(_) => Self::KERNEL_PML5_INDEX << 48,
+            None => 0,
+        };
         for (pml4_idx, pml4_entry) in self.pml4().entries.iter().enumerate() {
             if !pml4_entry.is_present() {
                 continue;
@@ -203,7 +356,7 @@ impl KernelPageTable {
 
             kprintln!(Debug, "PML4[{}]: {:?}", pml4_idx, pml4_entry);
 
-            let pdpt_virt = Self::DIRECT_MAP_START + pml4_entry.addr().as_usize();
+            let pdpt_virt = Self::direct_map_start() + pml4_entry.addr().as_usize();
             let pdpt = Self::to_pt(pdpt_virt);
 
             for (pdpt_idx, pdpt_entry) in pdpt.entries.iter().enumerate() {
@@ -219,7 +372,7 @@ impl KernelPageTable {
                     continue;
                 }
 
-                let pd_virt = Self::DIRECT_MAP_START + pdpt_entry.addr().as_usize();
+                let pd_virt = Self::direct_map_start() + pdpt_entry.addr().as_usize();
                 let pd = Self::to_pt(pd_virt);
 
                 for (pd_idx, pd_entry) in pd.entries.iter().enumerate() {
@@ -235,7 +388,7 @@ impl KernelPageTable {
                         continue;
                     }
 
-                    let pt_virt = Self::DIRECT_MAP_START + pd_entry.addr().as_usize();
+                    let pt_virt = Self::direct_map_start() + pd_entry.addr().as_usize();
                     let pt = Self::to_pt(pt_virt);
 
                     for (pt_idx, pt_entry) in pt.entries.iter().enumerate() {
@@ -247,7 +400,7 @@ impl KernelPageTable {
 
                         // Calculate the virtual address this entry maps to
                         let virt_addr = VirtAddr::new_truncate(
-                            (pml4_idx << 39) | (pdpt_idx << 30) | (pd_idx << 21) | (pt_idx << 12),
+                            pml5_bits | (pml4_idx << 39) | (pdpt_idx << 30) | (pd_idx << 21) | (pt_idx << 12),
                         );
 
                         kprintln!(
@@ -263,6 +416,75 @@ impl KernelPageTable {
     }
 }
 
+/// Contiguous pages of the same size mapping contiguous physical memory with the same flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingRun {
+    pub virt: usize,
+    pub phys: usize,
+    /// The length in bytes
+    pub len: usize,
+    pub size: PageSizeKind,
+    /// The effective flags, see [`KernelPageTable::translate`]
+    pub flags: PageTableFlags,
+}
+
+impl MappingRun {
+    /// Flags that change on access, which don't prevent merging
+    const IGNORED_FLAGS: PageTableFlags =
+        PageTableFlags::from_bits_truncate(PageTableFlags::ACCESSED.bits() | PageTableFlags::DIRTY.bits());
+
+    /// Returns whether the run overlaps `[start, end)`
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.virt < end && start < self.virt + self.len
+    }
+
+    /// Appends `next` if it continues the run, returning whether it did
+    fn extend(&mut self, next: &MappingRun) -> bool {
+        let continues = next.virt == self.virt + self.len
+            && next.phys == self.phys + self.len
+            && next.size == self.size
+            && next.flags.difference(Self::IGNORED_FLAGS) == self.flags.difference(Self::IGNORED_FLAGS);
+        if continues {
+            self.len += next.len;
+        }
+        continues
+    }
+}
+
+impl fmt::Display for MappingRun {
+    /// Formats the run like `virt range -> phys range`, with `rwx` style permissions
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = self.flags;
+        let flag = |flag: PageTableFlags, set: char| if flags.contains(flag) { set } else { '-' };
+        write!(
+            f,
+            "{:016x}-{:016x} -> {:012x}-{:012x} {:>5} x {} r{}{}{}",
+            self.virt,
+            self.virt + self.len,
+            self.phys,
+            self.phys + self.len,
+            self.len / self.size.size(),
+            self.size,
+            flag(PageTableFlags::WRITABLE, 'w'),
+            if flags.contains(PageTableFlags::NO_EXECUTE) {
+                '-'
+            } else {
+                'x'
+            },
+            flag(PageTableFlags::USER, 'u'),
+        )?;
+        if flags.contains(PageTableFlags::GLOBAL) {
+            f.write_str(" global")?;
+        }
+        if flags.contains(PageTableFlags::NO_CACHE) {
+            f.write_str(" uncached")?;
+        } else if flags.contains(PageTableFlags::WRITE_THROUGH) {
+            f.write_str(" write-through")?;
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Debug for KernelPageTable {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // TODO: Implement
@@ -271,7 +493,7 @@ impl fmt::Debug for KernelPageTable {
 }
 
 bitflags::bitflags! {
-    #[derive(Debug, Clone, Copy)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct PageTableFlags: u64 {
         const PRESENT = 1 << 0;
         const WRITABLE = 1 << 1;
@@ -338,7 +560,14 @@ impl Mapper<Size4KiB> for KernelPageTable {
     }
 
     unsafe fn unmap(&mut self, page: Page<Size4KiB>) {
-        todo!()
+        #[cfg(target_arch = "x86_64")]
+        {
+            let addr = page.start_address();
+            if let Some(pt) = self.get_pt_mut(addr.p4_index(), addr.p3_index(), addr.p2_index()) {
+                pt[addr.p1_index()] = PageTableEntry::new();
+                Flush { addr }.flush();
+            }
+        }
     }
 }
 
@@ -353,3 +582,36 @@ impl Flush {
         unsafe { crate::arch::instructions::invlpg(self.addr) };
     }
 }
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    fn run(virt: usize, phys: usize, flags: PageTableFlags) -> MappingRun {
+        MappingRun {
+            virt,
+            phys,
+            len: Size4KiB::SIZE,
+            size: PageSizeKind::Size4KiB,
+            flags,
+        }
+    }
+
+    #[test]
+    fn page_table_mapping_runs() {
+        let rw = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let mut mapping = run(0x1000, 0x8000, rw);
+        // Pages only differing in the accessed and dirty bits are merged
+        assert!(mapping.extend(&run(0x2000, 0x9000, rw | PageTableFlags::ACCESSED)));
+        assert!(mapping.extend(&run(0x3000, 0xA000, rw | PageTableFlags::DIRTY)));
+        assert_eq!(mapping.len, 3 * Size4KiB::SIZE);
+        // Gaps in either address space, and other permissions, start a new run
+        assert!(!mapping.extend(&run(0x5000, 0xB000, rw)));
+        assert!(!mapping.extend(&run(0x4000, 0xC000, rw)));
+        assert!(!mapping.extend(&run(0x4000, 0xB000, PageTableFlags::PRESENT)));
+        assert_eq!(
+            alloc::format!("{}", mapping),
+            "0000000000001000-0000000000004000 -> 000000008000-00000000b000     3 x 4K rwx-"
+        );
+    }
+}
@@ -27,6 +27,34 @@ impl PageSize for Size1GiB {
     const SIZE: usize = 4096 * 512 * 512;
 }
 
+/// The size of a page when it is only known at runtime, like that of an existing mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSizeKind {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl PageSizeKind {
+    pub const fn size(&self) -> usize {
+        match self {
+            Self::Size4KiB => Size4KiB::SIZE,
+            Self::Size2MiB => Size2MiB::SIZE,
+            Self::Size1GiB => Size1GiB::SIZE,
+        }
+    }
+}
+
+impl fmt::Display for PageSizeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Size4KiB => "4K",
+            Self::Size2MiB => "2M",
+            Self::Size1GiB => "1G",
+        })
+    }
+}
+
 pub struct Page<S: PageSize = Size4KiB> {
     base: VirtAddr,
     _marker: PhantomData<S>,
@@ -0,0 +1,141 @@
+//! Zeroing free memory in the background
+//!
+//! With `mm.scrub` on the command line, the main loop takes free frames while it has nothing else
+//! to do, zeroes them, and keeps them in a pool. [`allocate_zeroed`] hands them out for memory
+//! that has to start zeroed, like user pages and page tables, so it doesn't have to zero them on
+//! the spot. Without a scrubbed frame at hand, it zeroes a fresh one like before.
+//!
+//! The pool only fills while there is memory to spare, and gives its frames back to the frame
+//! allocator through a [shrinker](super::shrink) when memory runs low.
+
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::vec::Vec;
+
+use crate::{
+    mm::{
+        FRAME_ALLOCATOR,
+        page_table::KernelPageTable,
+        paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB},
+        shrink,
+    },
+    sync::Mutex,
+};
+
+/// The frames kept zeroed by default
+pub const DEFAULT_POOL_FRAMES: usize = 1024;
+/// The frames zeroed per main loop iteration, so the loop stays responsive
+const BATCH: usize = 16;
+
+static POOL: Mutex<Vec<PhysFrame>> = Mutex::new(Vec::new());
+/// The frames the pool is filled to, 0 if scrubbing is off
+static TARGET: AtomicUsize = AtomicUsize::new(0);
+static SCRUBBED: AtomicU64 = AtomicU64::new(0);
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Turns scrubbing on with `mm.scrub` or `mm.scrub=<frames>` on the command line
+pub fn init(cmdline: crate::boot::Cmdline) {
+    let target = match cmdline.get("mm.scrub") {
+        Some(frames) => frames.parse().unwrap_or(DEFAULT_POOL_FRAMES),
+        None if cmdline.flag("mm.scrub") => DEFAULT_POOL_FRAMES,
+        None => return,
+    };
+    if target == 0 {
+        return;
+    }
+    POOL.lock().reserve_exact(target);
+    TARGET.store(target, Ordering::Relaxed);
+    shrink::register("scrub", shrink_pool);
+    crate::stats::register("scrub", dump_stats);
+}
+
+fn zero(frame: PhysFrame) {
+    let virt = KernelPageTable::direct_map_start() + frame.start_address().as_usize();
+    unsafe { ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, Size4KiB::SIZE) };
+}
+
+/// Allocates a zeroed frame, from the scrubbed pool if it has one
+pub fn allocate_zeroed() -> Option<PhysFrame> {
+    if TARGET.load(Ordering::Relaxed) != 0 {
+        // The pool lock may be held by the code we interrupted
+        if let Some(frame) = POOL.try_lock().and_then(|mut pool| pool.pop()) {
+            HITS.fetch_add(1, Ordering::Relaxed);
+            return Some(frame);
+        }
+        MISSES.fetch_add(1, Ordering::Relaxed);
+    }
+    let frame = FRAME_ALLOCATOR.lock().allocate_frame()?;
+    zero(frame);
+    Some(frame)
+}
+
+/// Returns how many frames to scrub next, leaving the free frames above the shrink watermark
+fn refill_count(free: usize, pooled: usize, target: usize) -> usize {
+    target
+        .saturating_sub(pooled)
+        .min(free.saturating_sub(shrink::HIGH_WATERMARK))
+        .min(BATCH)
+}
+
+/// Scrubs a batch of free frames into the pool, from the main loop when it has nothing else to do
+pub fn poll() {
+    let target = TARGET.load(Ordering::Relaxed);
+    if target == 0 {
+        return;
+    }
+    let pooled = POOL.lock().len();
+    let count = refill_count(crate::mm::stats().frames().free, pooled, target);
+    for _ in 0..count {
+        let Some(frame) = FRAME_ALLOCATOR.lock().allocate_frame() else {
+            return;
+        };
+        zero(frame);
+        POOL.lock().push(frame);
+        SCRUBBED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn shrink_pool(frames: usize) -> usize {
+    let Some(mut pool) = POOL.try_lock() else {
+        return 0;
+    };
+    let count = frames.min(pool.len());
+    let start = pool.len() - count;
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    for frame in pool.drain(start..) {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+    count
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(
+        out,
+        "pooled={} target={} scrubbed={} hits={} misses={}",
+        POOL.lock().len(),
+        TARGET.load(Ordering::Relaxed),
+        SCRUBBED.load(Ordering::Relaxed),
+        HITS.load(Ordering::Relaxed),
+        MISSES.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrub_refill_count() {
+        let plenty = shrink::HIGH_WATERMARK + 1000;
+        assert_eq!(refill_count(plenty, 0, 1024), BATCH);
+        assert_eq!(refill_count(plenty, 1020, 1024), 4);
+        assert_eq!(refill_count(plenty, 1024, 1024), 0);
+        // Scrubbing must not push memory into the range where caches are shrunk
+        assert_eq!(refill_count(shrink::HIGH_WATERMARK + 3, 0, 1024), 3);
+        assert_eq!(refill_count(shrink::LOW_WATERMARK, 0, 1024), 0);
+    }
+}
@@ -0,0 +1,105 @@
+//! Memory pressure
+//!
+//! Caches hold on to frames they could give back, like the block cache. They register a shrinker,
+//! which frees what it can recreate when memory runs low: on demand when an allocation through
+//! [`allocate_frame`] fails, and from [`poll`] when free frames drop below [`LOW_WATERMARK`].
+//!
+//! Shrinkers may be called with any lock held, including their own, so they must only try to take
+//! their locks and give up if that fails. They must not allocate.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use alloc::vec::Vec;
+
+use crate::{
+    mm::{
+        FRAME_ALLOCATOR,
+        paging::{FrameAllocator, PhysFrame},
+    },
+    sync::RwLock,
+    time,
+};
+
+/// Frees up to `frames` frames, returning how many it freed
+pub type ShrinkFn = fn(frames: usize) -> usize;
+
+/// The free frames below which [`poll`] starts shrinking caches
+pub const LOW_WATERMARK: usize = 1024;
+/// The free frames [`poll`] shrinks caches until
+pub const HIGH_WATERMARK: usize = 2048;
+/// How often [`poll`] checks the free frames
+const POLL_INTERVAL_NS: u64 = 100_000_000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Shrinker {
+    pub name: &'static str,
+    pub shrink: ShrinkFn,
+}
+
+static SHRINKERS: RwLock<Vec<Shrinker>> = RwLock::new(Vec::new());
+/// Set while shrinkers run, so a failed allocation inside one doesn't start over
+static SHRINKING: AtomicBool = AtomicBool::new(false);
+static FREED: AtomicU64 = AtomicU64::new(0);
+
+/// Registers a shrinker, replacing any existing one with the same name
+pub fn register(name: &'static str, shrink: ShrinkFn) {
+    let mut shrinkers = SHRINKERS.write();
+    shrinkers.retain(|shrinker| shrinker.name != name);
+    shrinkers.push(Shrinker { name, shrink });
+}
+
+/// Asks the shrinkers to free `frames` frames, returning how many they freed
+///
+/// Returns 0 without calling any if shrinkers are already running.
+pub fn shrink(frames: usize) -> usize {
+    if SHRINKING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    let mut freed = 0;
+    // Registering allocates, and may be what ran out of memory
+    if let Some(shrinkers) = SHRINKERS.try_read() {
+        for shrinker in shrinkers.iter() {
+            if freed >= frames {
+                break;
+            }
+            freed += (shrinker.shrink)(frames - freed);
+        }
+    }
+    SHRINKING.store(false, Ordering::Release);
+    FREED.fetch_add(freed as u64, Ordering::Relaxed);
+    freed
+}
+
+/// Allocates a frame, shrinking caches and trying again if there is none
+pub fn allocate_frame() -> Option<PhysFrame> {
+    if let Some(frame) = FRAME_ALLOCATOR.lock().allocate_frame() {
+        return Some(frame);
+    }
+    if shrink(1) == 0 {
+        return None;
+    }
+    FRAME_ALLOCATOR.lock().allocate_frame()
+}
+
+/// Returns the number of frames the shrinkers freed since boot
+pub fn freed() -> u64 {
+    FREED.load(Ordering::Relaxed)
+}
+
+/// Shrinks caches when free frames run low, from the main loop
+pub fn poll() {
+    static NEXT_CHECK: AtomicU64 = AtomicU64::new(0);
+    let now = time::monotonic_ns();
+    let next = NEXT_CHECK.load(Ordering::Relaxed);
+    if now < next
+        || NEXT_CHECK
+            .compare_exchange(next, now + POLL_INTERVAL_NS, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return;
+    }
+    let free = crate::mm::stats().frames().free;
+    if free < LOW_WATERMARK {
+        shrink(HIGH_WATERMARK - free);
+    }
+}
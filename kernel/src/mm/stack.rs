@@ -0,0 +1,160 @@
+//! Usage tracking of kernel stacks
+//!
+//! Kernel stacks are filled with [`POISON`] before they are first used, so the deepest point a
+//! stack has reached is where the poison stops, and [`high_watermark`] reports how much of it has
+//! ever been used. The lowest page of every stack is a canary: [`poll`] periodically checks that
+//! it is still poisoned, and panics if a stack grew into it, before the overflow corrupts whatever
+//! lies below.
+//!
+//! There are no kernel tasks yet, so the registered stacks are the boot stack and the stacks the
+//! CPU switches to for exceptions and interrupts from ring 3.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    arch::VirtAddr,
+    mm::paging::{PageSize, Size4KiB},
+    sync::RwLock,
+    time,
+};
+
+/// The pattern unused stack memory is filled with
+pub const POISON: u64 = 0x57AC_D00D_57AC_D00D;
+/// The size of the canary at the bottom of each stack
+pub const CANARY_SIZE: usize = Size4KiB::SIZE;
+/// The maximum number of stacks that can be registered
+const MAX_STACKS: usize = 16;
+/// How often [`poll`] checks the canaries
+const CHECK_INTERVAL_NS: u64 = 100_000_000;
+
+/// A registered kernel stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackId(usize);
+
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStack {
+    pub name: &'static str,
+    /// The lowest address of the stack, where the canary is
+    pub bottom: VirtAddr,
+    pub size: usize,
+}
+
+impl KernelStack {
+    fn words(&self) -> &[u64] {
+        // SAFETY: Registered stacks stay mapped, and reading the unused part races with nothing
+        unsafe { core::slice::from_raw_parts(self.bottom.as_ptr::<u64>(), self.size / size_of::<u64>()) }
+    }
+}
+
+static STACKS: RwLock<[Option<KernelStack>; MAX_STACKS]> = RwLock::new([None; MAX_STACKS]);
+
+/// Fills a stack with the poison pattern
+///
+/// # Safety
+/// The memory must be writable, and must not be in use as a stack yet.
+pub unsafe fn poison(bottom: VirtAddr, size: usize) {
+    let words = size / size_of::<u64>();
+    unsafe { core::slice::from_raw_parts_mut(bottom.as_mut_ptr::<u64>(), words).fill(POISON) };
+}
+
+/// Registers a stack filled with [`poison`], returning its id
+///
+/// Returns `None` if too many stacks are registered.
+pub fn register(name: &'static str, bottom: VirtAddr, size: usize) -> Option<StackId> {
+    assert!(size > CANARY_SIZE, "stack: {} is too small for a canary", name);
+    let mut stacks = STACKS.write();
+    let idx = stacks.iter().position(Option::is_none)?;
+    stacks[idx] = Some(KernelStack { name, bottom, size });
+    Some(StackId(idx))
+}
+
+/// Returns the bytes at the bottom of a stack that have never been written
+fn untouched(words: &[u64]) -> usize {
+    words.iter().take_while(|word| **word == POISON).count() * size_of::<u64>()
+}
+
+/// Returns the most bytes a stack has ever used
+pub fn high_watermark(id: StackId) -> Option<usize> {
+    let stack = STACKS.read().get(id.0).copied().flatten()?;
+    Some(stack.size - untouched(stack.words()))
+}
+
+/// Checks the canary of every stack, panicking if a stack has overflowed into it
+pub fn check() {
+    let stacks = *STACKS.read();
+    for stack in stacks.iter().flatten() {
+        if untouched(stack.words()) < CANARY_SIZE {
+            panic!(
+                "stack: the {} stack at {:#x} overflowed into its canary",
+                stack.name, stack.bottom
+            );
+        }
+    }
+}
+
+/// Checks the canaries every [`CHECK_INTERVAL_NS`]
+pub fn poll() {
+    static NEXT_CHECK: AtomicU64 = AtomicU64::new(0);
+    let now = time::monotonic_ns();
+    let next = NEXT_CHECK.load(Ordering::Relaxed);
+    if now < next
+        || NEXT_CHECK
+            .compare_exchange(next, now + CHECK_INTERVAL_NS, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return;
+    }
+    check();
+}
+
+/// Registers the stack usage with the stats dump
+pub fn init() {
+    crate::stats::register("stacks", dump_stats);
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    let stacks = *STACKS.read();
+    for stack in stacks.iter().flatten() {
+        let used = stack.size - untouched(stack.words());
+        writeln!(
+            out,
+            "{}: {}/{} KiB used at most ({}%)",
+            stack.name,
+            used.div_ceil(1024),
+            stack.size / 1024,
+            used * 100 / stack.size
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn stack_watermark() {
+        const WORDS: usize = 2 * CANARY_SIZE / size_of::<u64>();
+        let mut words = vec![0u64; WORDS];
+        let bottom = VirtAddr::from_ptr(words.as_mut_ptr());
+        unsafe { poison(bottom, WORDS * size_of::<u64>()) };
+        assert_eq!(untouched(&words), 2 * CANARY_SIZE);
+
+        // Stacks grow down, so usage starts at the top
+        words[WORDS - 10..].fill(0);
+        assert_eq!(untouched(&words), 2 * CANARY_SIZE - 80);
+        words[WORDS / 2] = 1;
+        assert_eq!(untouched(&words), CANARY_SIZE);
+
+        let id = register("test", bottom, WORDS * size_of::<u64>()).unwrap();
+        assert_eq!(high_watermark(id), Some(CANARY_SIZE));
+        check();
+        STACKS.write()[id.0] = None;
+        assert_eq!(high_watermark(id), None);
+    }
+}
@@ -0,0 +1,209 @@
+//! Memory statistics
+//!
+//! Physical memory is reported per zone, by the address limits of devices that can't reach all of
+//! it, and the kernel heap by what its users requested. There is no DMA API yet, so memory used
+//! for DMA is counted like any other allocated frame.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    kprintln,
+    mm::{
+        FRAME_ALLOCATOR,
+        allocator::{ALLOCATOR, HeapStats},
+        paging::{PageSize, Size4KiB},
+    },
+    stats::snapshot::Counters,
+    time,
+};
+
+/// How often [`poll`] logs the memory statistics
+const LOG_INTERVAL_NS: u64 = 60_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 16 MiB, reachable by ISA DMA
+    Dma,
+    /// Below 4 GiB, reachable by 32-bit devices
+    Dma32,
+    Normal,
+}
+
+impl Zone {
+    pub const ALL: [Zone; 3] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    /// Returns the physical address range covered by the zone
+    pub const fn range(&self) -> core::ops::Range<usize> {
+        match self {
+            Self::Dma => 0..0x100_0000,
+            Self::Dma32 => 0x100_0000..0x1_0000_0000,
+            Self::Normal => 0x1_0000_0000..usize::MAX,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Dma => "DMA",
+            Self::Dma32 => "DMA32",
+            Self::Normal => "Normal",
+        }
+    }
+}
+
+/// Frames managed by the frame allocator in a zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZoneStats {
+    pub zone: Zone,
+    pub total: usize,
+    pub free: usize,
+}
+
+impl ZoneStats {
+    pub const fn used(&self) -> usize {
+        self.total - self.free
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemStats {
+    pub zones: [ZoneStats; 3],
+    /// Frames held back from the frame allocator, like bootloader memory
+    pub reserved: usize,
+    pub heap: HeapStats,
+}
+
+impl MemStats {
+    /// Returns the frame totals of all zones
+    pub fn frames(&self) -> ZoneStats {
+        ZoneStats {
+            zone: Zone::Normal,
+            total: self.zones.iter().map(|zone| zone.total).sum(),
+            free: self.zones.iter().map(|zone| zone.free).sum(),
+        }
+    }
+}
+
+impl fmt::Display for MemStats {
+    /// Formats the statistics as a table in KiB, like `free`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kib = |frames: usize| frames * Size4KiB::SIZE / 1024;
+        let frames = self.frames();
+        writeln!(f, "{:<8} {:>12} {:>12} {:>12}", "", "total", "used", "free")?;
+        writeln!(
+            f,
+            "{:<8} {:>12} {:>12} {:>12}",
+            "Mem:",
+            kib(frames.total),
+            kib(frames.used()),
+            kib(frames.free)
+        )?;
+        for zone in &self.zones {
+            writeln!(
+                f,
+                "{:<8} {:>12} {:>12} {:>12}",
+                zone.zone.name(),
+                kib(zone.total),
+                kib(zone.used()),
+                kib(zone.free)
+            )?;
+        }
+        writeln!(
+            f,
+            "{:<8} {:>12} {:>12} {:>12}",
+            "Heap:",
+            self.heap.size / 1024,
+            self.heap.allocated / 1024,
+            self.heap.size.saturating_sub(self.heap.allocated) / 1024
+        )?;
+        writeln!(f, "reserved: {} KiB", kib(self.reserved))?;
+        write!(
+            f,
+            "heap: peak {} KiB, {} live allocations, {} since boot, {} failed",
+            self.heap.peak / 1024,
+            self.heap.allocations,
+            self.heap.total_allocations,
+            self.heap.failures
+        )
+    }
+}
+
+/// Collects the current memory statistics
+pub fn stats() -> MemStats {
+    let mut zones = Zone::ALL.map(|zone| ZoneStats {
+        zone,
+        total: 0,
+        free: 0,
+    });
+    let frame_allocator = FRAME_ALLOCATOR.lock();
+    let memory_map = frame_allocator.memory_map();
+    for region in &memory_map.entries {
+        let base = region.base().as_usize();
+        let end = base + region.pages() * Size4KiB::SIZE;
+        for stats in &mut zones {
+            let range = stats.zone.range();
+            let (start, end) = (base.max(range.start), end.min(range.end));
+            if start >= end {
+                continue;
+            }
+            let pages = (start - base) / Size4KiB::SIZE..(end - base) / Size4KiB::SIZE;
+            stats.total += pages.len();
+            stats.free += pages.len() - region.used_pages(pages);
+        }
+    }
+    let reserved = memory_map
+        .special
+        .iter()
+        .map(|region| region.length / Size4KiB::SIZE)
+        .sum();
+    MemStats {
+        zones,
+        reserved,
+        heap: ALLOCATOR.stats(),
+    }
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(out, "{}", stats())
+}
+
+/// Registers the memory statistics provider, once the frame allocator is initialized
+pub fn init() {
+    crate::stats::register("mem", dump_stats);
+    crate::stats::snapshot::register("mem", counters);
+}
+
+fn counters(counters: &mut Counters<'_>) {
+    let stats = stats();
+    counters.gauge(format_args!("frames_free"), stats.frames().free as u64);
+    counters.gauge(format_args!("heap_allocated"), stats.heap.allocated as u64);
+    counters.counter(format_args!("heap_allocations"), stats.heap.total_allocations);
+    counters.counter(format_args!("heap_failures"), stats.heap.failures);
+}
+
+/// Logs the memory usage every [`LOG_INTERVAL_NS`]
+pub fn poll() {
+    static NEXT_LOG: AtomicU64 = AtomicU64::new(0);
+    let now = time::monotonic_ns();
+    let next = NEXT_LOG.load(Ordering::Relaxed);
+    if now < next
+        || NEXT_LOG
+            .compare_exchange(next, now + LOG_INTERVAL_NS, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+    {
+        return;
+    }
+    let stats = stats();
+    let frames = stats.frames();
+    kprintln!(
+        Debug,
+        "mm: {}/{} frames free, heap {}/{} KiB (peak {} KiB)",
+        frames.free,
+        frames.total,
+        stats.heap.allocated / 1024,
+        stats.heap.size / 1024,
+        stats.heap.peak / 1024
+    );
+}
@@ -0,0 +1,142 @@
+//! Audit of kernel mapping permissions
+//!
+//! Once boot is done, [`audit`] walks the kernel half of the page tables and checks that no page
+//! is both writable and executable, that the direct map is never executable, and that the read
+//! only data of the kernel image can't be written. Violations panic, or are only logged with the
+//! `wx_warn` option, for finding all of them in one boot.
+
+use core::{fmt, ops::Range};
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::VirtAddr,
+    kprintln,
+    mm::{
+        layout, mappings,
+        page_table::{KernelPageTable, MappingRun, PageTableFlags},
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    WritableExecutable(MappingRun),
+    ExecutableDirectMap(MappingRun),
+    WritableRodata(MappingRun),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WritableExecutable(run) => write!(f, "writable and executable: {}", run),
+            Self::ExecutableDirectMap(run) => write!(f, "executable direct map: {}", run),
+            Self::WritableRodata(run) => write!(f, "writable rodata: {}", run),
+        }
+    }
+}
+
+/// The regions with stricter permissions than W^X
+#[derive(Debug, Clone)]
+pub struct Regions {
+    pub direct_map: Range<usize>,
+    pub rodata: Range<usize>,
+}
+
+impl Regions {
+    /// Returns the regions of the running kernel
+    pub fn kernel() -> Self {
+        unsafe extern "C" {
+            static _kernel_rodata_start: u8;
+            static _kernel_data_start: u8;
+        }
+        let layout = layout::layout();
+        let direct_map = layout.direct_map.as_usize();
+        Self {
+            direct_map: direct_map..direct_map + layout.direct_map_size(),
+            rodata: (&raw const _kernel_rodata_start) as usize..(&raw const _kernel_data_start) as usize,
+        }
+    }
+
+    /// Returns the violation of a mapping, if it has one
+    pub fn check(&self, run: &MappingRun) -> Option<Violation> {
+        let writable = run.flags.contains(PageTableFlags::WRITABLE);
+        let executable = !run.flags.contains(PageTableFlags::NO_EXECUTE);
+        if writable && executable {
+            Some(Violation::WritableExecutable(*run))
+        } else if executable && run.overlaps(self.direct_map.start, self.direct_map.end) {
+            Some(Violation::ExecutableDirectMap(*run))
+        } else if writable && run.overlaps(self.rodata.start, self.rodata.end) {
+            Some(Violation::WritableRodata(*run))
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the violations in the kernel half of a page table
+pub fn violations(page_table: &KernelPageTable, regions: &Regions) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    page_table.for_each_mapping(mappings::KERNEL_MEM_START, VirtAddr::new(usize::MAX), |run| {
+        violations.extend(regions.check(run));
+    });
+    violations
+}
+
+/// Checks the permissions of the current kernel mappings
+pub fn audit() {
+    #[cfg(target_arch = "x86_64")]
+    if !crate::arch::x86_64::cpu::has(crate::arch::x86_64::cpu::Feature::Nx) {
+        kprintln!(Warn, "wx: the CPU has no NX, every kernel mapping is executable");
+        return;
+    }
+    let page_table = KernelPageTable::new(crate::arch::registers::control::Cr3::addr());
+    let violations = violations(&page_table, &Regions::kernel());
+    for violation in &violations {
+        kprintln!(Error, "wx: {}", violation);
+    }
+    if violations.is_empty() {
+        kprintln!(Debug, "wx: kernel mappings are W^X");
+    } else if !crate::config::WX_WARN {
+        panic!("wx: {} kernel mappings violate W^X", violations.len());
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+    use crate::mm::paging::PageSizeKind;
+
+    fn run(virt: usize, flags: PageTableFlags) -> MappingRun {
+        MappingRun {
+            virt,
+            phys: 0,
+            len: 0x2000,
+            size: PageSizeKind::Size4KiB,
+            flags: flags | PageTableFlags::PRESENT,
+        }
+    }
+
+    #[test]
+    fn wx_check() {
+        let regions = Regions {
+            direct_map: 0x10_0000..0x20_0000,
+            rodata: 0x1000..0x3000,
+        };
+        let (rw, nx) = (PageTableFlags::WRITABLE, PageTableFlags::NO_EXECUTE);
+        assert_eq!(
+            regions.check(&run(0x40_0000, rw)),
+            Some(Violation::WritableExecutable(run(0x40_0000, rw)))
+        );
+        assert_eq!(regions.check(&run(0x40_0000, rw | nx)), None);
+        assert_eq!(regions.check(&run(0x40_0000, PageTableFlags::empty())), None);
+
+        // A run only partially inside a region still violates it
+        let text = run(0x1F_F000, PageTableFlags::empty());
+        assert_eq!(regions.check(&text), Some(Violation::ExecutableDirectMap(text)));
+        assert_eq!(regions.check(&run(0x1F_F000, rw | nx)), None);
+        let data = run(0x2000, rw | nx);
+        assert_eq!(regions.check(&data), Some(Violation::WritableRodata(data)));
+        assert_eq!(regions.check(&run(0x3000, rw | nx)), None);
+        assert_eq!(regions.check(&run(0x1000, nx)), None);
+    }
+}
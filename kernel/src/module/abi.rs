@@ -0,0 +1,209 @@
+//! The stable module ABI
+//!
+//! Everything a module and the kernel exchange is `#[repr(C)]`, with strings and slices passed as
+//! pointer and length pairs and callbacks as `extern "C"` functions, so a module keeps working
+//! with kernels built by a different compiler or with different options.
+//!
+//! Every module exports a [`ModuleInfo`] named `__hadron_module_info`. The kernel refuses modules
+//! built against an incompatible [`AbiVersion`], then negotiates capabilities: the load fails if
+//! the kernel lacks a capability the module requires, and `init` is told which of the optional
+//! ones it was granted. The kernel services themselves are reached through the table of
+//! [`api`](super::api).
+
+use core::fmt;
+
+/// The symbol of the [`ModuleInfo`] every module exports
+pub const MODULE_INFO_SYMBOL: &str = "__hadron_module_info";
+/// The first field of [`ModuleInfo`], `"HDRM"` in memory
+pub const MODULE_MAGIC: u32 = u32::from_le_bytes(*b"HDRM");
+
+/// The ABI version of this kernel
+///
+/// The major version changes when a type or function changes incompatibly, the minor version when
+/// something is added.
+pub const ABI_VERSION: AbiVersion = AbiVersion { major: 1, minor: 4 };
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbiVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl AbiVersion {
+    /// Returns whether a module built against `self` can run on a kernel providing `kernel`
+    pub fn is_compatible_with(self, kernel: AbiVersion) -> bool {
+        self.major == kernel.major && self.minor <= kernel.minor
+    }
+}
+
+impl fmt::Display for AbiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+bitflags::bitflags! {
+    /// Kernel services a module can ask for
+    #[repr(transparent)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct ModuleCaps: u64 {
+        /// `hadron_log`
+        const LOG = 1 << 0;
+        /// `hadron_alloc` and `hadron_free`
+        const HEAP = 1 << 1;
+        /// `hadron_pci_read_u32` and `hadron_pci_write_u32`
+        const PCI_CONFIG = 1 << 2;
+        /// Registering the drivers in [`ModuleInfo::pci_drivers`]
+        const PCI_DRIVERS = 1 << 3;
+        /// `hadron_monotonic_ns`
+        const TIME = 1 << 4;
+        /// [`KernelApi::mmio_map`](super::api::KernelApi::mmio_map), `mmio_unmap` and the register accessors
+        const MMIO = 1 << 5;
+        /// [`KernelApi::request_irq`](super::api::KernelApi::request_irq) and `free_irq`
+        const IRQ = 1 << 6;
+        /// [`KernelApi::dma_alloc`](super::api::KernelApi::dma_alloc) and `dma_free`
+        const DMA = 1 << 7;
+    }
+}
+
+/// The capabilities this kernel provides
+pub const KERNEL_CAPS: ModuleCaps = ModuleCaps::all();
+
+/// Returns the capabilities granted to a module, or the required ones the kernel lacks
+pub fn negotiate(required: ModuleCaps, optional: ModuleCaps, kernel: ModuleCaps) -> Result<ModuleCaps, ModuleCaps> {
+    let missing = required - kernel;
+    if !missing.is_empty() {
+        return Err(missing);
+    }
+    Ok((required | optional) & kernel)
+}
+
+/// A UTF-8 string, which is not NUL terminated
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct AbiStr {
+    ptr: *const u8,
+    len: usize,
+}
+
+unsafe impl Send for AbiStr {}
+unsafe impl Sync for AbiStr {}
+
+impl AbiStr {
+    pub const fn new(s: &'static str) -> Self {
+        Self {
+            ptr: s.as_ptr(),
+            len: s.len(),
+        }
+    }
+
+    pub fn addr_range(&self) -> (usize, usize) {
+        (self.ptr as usize, self.len)
+    }
+
+    /// Returns the string, or `"<invalid>"` if it isn't UTF-8
+    ///
+    /// # Safety
+    /// The pointer must be valid for `len` bytes for as long as the string is used.
+    pub unsafe fn as_str(&self) -> &str {
+        let bytes = unsafe { core::slice::from_raw_parts(self.ptr, self.len) };
+        core::str::from_utf8(bytes).unwrap_or("<invalid>")
+    }
+}
+
+/// A slice of `T`
+#[repr(C)]
+#[derive(Debug)]
+pub struct AbiSlice<T> {
+    ptr: *const T,
+    len: usize,
+}
+
+impl<T> Clone for AbiSlice<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for AbiSlice<T> {}
+
+unsafe impl<T: Sync> Send for AbiSlice<T> {}
+unsafe impl<T: Sync> Sync for AbiSlice<T> {}
+
+impl<T> AbiSlice<T> {
+    pub const fn new(slice: &'static [T]) -> Self {
+        Self {
+            ptr: slice.as_ptr(),
+            len: slice.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the start address and size in bytes
+    pub fn addr_range(&self) -> (usize, usize) {
+        (self.ptr as usize, self.len * size_of::<T>())
+    }
+
+    /// # Safety
+    /// The pointer must be valid for `len` elements for as long as the slice is used.
+    pub unsafe fn as_slice(&self) -> &[T] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { core::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+/// The descriptor every module exports as `__hadron_module_info`
+#[repr(C)]
+#[derive(Debug)]
+pub struct ModuleInfo {
+    /// [`MODULE_MAGIC`]
+    pub magic: u32,
+    /// The ABI version the module was built against
+    pub abi: AbiVersion,
+    pub name: AbiStr,
+    /// Capabilities the module can't work without
+    pub required: ModuleCaps,
+    /// Capabilities the module uses if the kernel provides them
+    pub optional: ModuleCaps,
+    /// Called with the granted capabilities once the module is linked, a non zero return aborts
+    /// the load
+    pub init: Option<extern "C" fn(granted: ModuleCaps) -> i32>,
+    /// Called before the module is unloaded
+    pub exit: Option<extern "C" fn()>,
+    pub pci_drivers: AbiSlice<crate::dev::drivers::pci::PciDrv>,
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abi_negotiation() {
+        let v = |major, minor| AbiVersion { major, minor };
+        assert!(v(1, 0).is_compatible_with(v(1, 0)));
+        // Modules built against an older minor version only use a subset of the ABI
+        assert!(v(1, 0).is_compatible_with(v(1, 3)));
+        assert!(!v(1, 4).is_compatible_with(v(1, 3)));
+        assert!(!v(2, 0).is_compatible_with(v(1, 3)));
+
+        let kernel = ModuleCaps::LOG | ModuleCaps::HEAP;
+        assert_eq!(
+            negotiate(ModuleCaps::LOG, ModuleCaps::HEAP | ModuleCaps::PCI_CONFIG, kernel),
+            Ok(ModuleCaps::LOG | ModuleCaps::HEAP)
+        );
+        assert_eq!(
+            negotiate(ModuleCaps::LOG | ModuleCaps::PCI_DRIVERS, ModuleCaps::empty(), kernel),
+            Err(ModuleCaps::PCI_DRIVERS)
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! The driver API
+//!
+//! Drivers reach kernel services through a [`KernelApi`] table of `extern "C"` functions, so
+//! only the table is part of the ABI, not the kernel functions behind it. A module asks for the
+//! table with the exported `hadron_api`, passing the [`AbiVersion`] it was built against, and
+//! gets nothing back if the kernel can't provide it. Fields are only ever appended within a major
+//! version, and [`KernelApi::size`] tells a module which of them it can use.
+//!
+//! Built-in drivers use the same table through [`kernel`], so everything drivers need is covered
+//! by it rather than by reaching into the kernel.
+
+use core::{
+    ffi::c_void,
+    fmt::{self, Write},
+    ptr::{self, NonNull},
+};
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use super::{
+    abi::{ABI_VERSION, AbiVersion},
+    symbols::{hadron_alloc, hadron_free, hadron_log, hadron_monotonic_ns, hadron_pci_read_u32, hadron_pci_write_u32},
+};
+use crate::{
+    arch::{PhysAddr, VirtAddr},
+    irq,
+    mm::{
+        FRAME_ALLOCATOR, mmio,
+        page_table::KernelPageTable,
+        paging::{PageSize, PhysFrame, Size4KiB},
+    },
+    sync::RwLock,
+    util::kprint::LogLevel,
+};
+
+/// An interrupt handler of a driver, called with the vector and the data it was registered with
+pub type ApiIrqHandler = extern "C" fn(vector: u8, data: *mut c_void);
+
+/// The kernel services available to drivers
+#[repr(C)]
+#[derive(Debug)]
+pub struct KernelApi {
+    /// The ABI version the table implements
+    pub version: AbiVersion,
+    /// The size of the table in bytes
+    pub size: usize,
+    /// Writes a message to the kernel log, `level` is a [`LogLevel`]
+    pub log: unsafe extern "C" fn(level: u8, msg: *const u8, len: usize),
+    /// Allocates from the kernel heap, returning null on failure
+    pub alloc: extern "C" fn(size: usize, align: usize) -> *mut c_void,
+    pub free: unsafe extern "C" fn(ptr: *mut c_void, size: usize, align: usize),
+    pub monotonic_ns: extern "C" fn() -> u64,
+    pub pci_read_u32: extern "C" fn(bus: u8, device: u8, function: u8, offset: u8) -> u32,
+    pub pci_write_u32: extern "C" fn(bus: u8, device: u8, function: u8, offset: u8, value: u32),
+    /// Maps device memory uncached, returning null on failure
+    pub mmio_map: unsafe extern "C" fn(phys: u64, size: usize) -> *mut c_void,
+    pub mmio_unmap: unsafe extern "C" fn(virt: *mut c_void, size: usize),
+    /// Registers a handler on a free vector, returning the vector or a negative error
+    pub request_irq: extern "C" fn(handler: ApiIrqHandler, data: *mut c_void) -> i32,
+    pub free_irq: extern "C" fn(vector: u8),
+    /// Allocates zeroed, physically contiguous memory for DMA, storing its physical address in
+    /// `phys`, and returning null on failure
+    pub dma_alloc: unsafe extern "C" fn(size: usize, phys: *mut u64) -> *mut c_void,
+    pub dma_free: unsafe extern "C" fn(virt: *mut c_void, size: usize),
+    /// Reads a register of mapped device memory, so register accesses can be emulated in tests
+    pub mmio_read_u32: unsafe extern "C" fn(addr: *const c_void) -> u32,
+    pub mmio_write_u32: unsafe extern "C" fn(addr: *mut c_void, value: u32),
+    /// Reads a 16-bit register, for devices that split wider accesses into several registers
+    pub mmio_read_u16: unsafe extern "C" fn(addr: *const c_void) -> u16,
+    pub mmio_write_u16: unsafe extern "C" fn(addr: *mut c_void, value: u16),
+}
+
+static KERNEL_API: KernelApi = KernelApi {
+    version: ABI_VERSION,
+    size: size_of::<KernelApi>(),
+    log: hadron_log,
+    alloc: hadron_alloc,
+    free: hadron_free,
+    monotonic_ns: hadron_monotonic_ns,
+    pci_read_u32: hadron_pci_read_u32,
+    pci_write_u32: hadron_pci_write_u32,
+    mmio_map,
+    mmio_unmap,
+    request_irq,
+    free_irq,
+    dma_alloc,
+    dma_free,
+    mmio_read_u32,
+    mmio_write_u32,
+    mmio_read_u16,
+    mmio_write_u16,
+};
+
+/// Returns the API table, or null if a module built against `version` can't use it
+#[unsafe(no_mangle)]
+pub extern "C" fn hadron_api(version: AbiVersion) -> *const KernelApi {
+    match version.is_compatible_with(ABI_VERSION) {
+        true => &KERNEL_API,
+        false => ptr::null(),
+    }
+}
+crate::export_symbol!(hadron_api);
+
+/// Returns the API table, for built-in drivers
+pub fn kernel() -> &'static KernelApi {
+    &KERNEL_API
+}
+
+impl KernelApi {
+    pub fn log(&self, level: LogLevel, args: fmt::Arguments<'_>) {
+        let mut msg = String::new();
+        _ = msg.write_fmt(args);
+        unsafe { (self.log)(level as u8, msg.as_ptr(), msg.len()) };
+    }
+
+    /// Maps device memory, returning its virtual address
+    ///
+    /// # Safety
+    /// The physical region must belong to a device.
+    pub unsafe fn map_mmio(&self, phys: PhysAddr, size: usize) -> Option<NonNull<u8>> {
+        NonNull::new(unsafe { (self.mmio_map)(phys.as_usize() as u64, size) }.cast())
+    }
+
+    /// Reads the register at `offset` into a region mapped with [`Self::map_mmio`]
+    ///
+    /// # Safety
+    /// The register must be inside the region.
+    #[track_caller]
+    pub unsafe fn read_u32(&self, base: NonNull<u8>, offset: usize) -> u32 {
+        crate::dev::io_audit::check_mmio(VirtAddr::new(base.as_ptr() as usize), offset, 4);
+        unsafe { (self.mmio_read_u32)(base.add(offset).as_ptr().cast()) }
+    }
+
+    /// Writes the register at `offset` into a region mapped with [`Self::map_mmio`]
+    ///
+    /// # Safety
+    /// The register must be inside the region.
+    #[track_caller]
+    pub unsafe fn write_u32(&self, base: NonNull<u8>, offset: usize, value: u32) {
+        crate::dev::io_audit::check_mmio(VirtAddr::new(base.as_ptr() as usize), offset, 4);
+        unsafe { (self.mmio_write_u32)(base.add(offset).as_ptr().cast(), value) }
+    }
+
+    /// Reads the 16-bit register at `offset` into a region mapped with [`Self::map_mmio`]
+    ///
+    /// # Safety
+    /// The register must be inside the region.
+    #[track_caller]
+    pub unsafe fn read_u16(&self, base: NonNull<u8>, offset: usize) -> u16 {
+        crate::dev::io_audit::check_mmio(VirtAddr::new(base.as_ptr() as usize), offset, 2);
+        unsafe { (self.mmio_read_u16)(base.add(offset).as_ptr().cast()) }
+    }
+
+    /// Writes the 16-bit register at `offset` into a region mapped with [`Self::map_mmio`]
+    ///
+    /// # Safety
+    /// The register must be inside the region.
+    #[track_caller]
+    pub unsafe fn write_u16(&self, base: NonNull<u8>, offset: usize, value: u16) {
+        crate::dev::io_audit::check_mmio(VirtAddr::new(base.as_ptr() as usize), offset, 2);
+        unsafe { (self.mmio_write_u16)(base.add(offset).as_ptr().cast(), value) }
+    }
+}
+
+unsafe extern "C" fn mmio_map(phys: u64, size: usize) -> *mut c_void {
+    match unsafe { mmio::map(PhysAddr::new(phys as usize), size) } {
+        Ok(region) => region.virt().as_mut_ptr(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn mmio_unmap(virt: *mut c_void, size: usize) {
+    unsafe { mmio::unmap_raw(VirtAddr::new(virt as usize), size) };
+}
+
+unsafe extern "C" fn mmio_read_u32(addr: *const c_void) -> u32 {
+    unsafe { addr.cast::<u32>().read_volatile() }
+}
+
+unsafe extern "C" fn mmio_write_u32(addr: *mut c_void, value: u32) {
+    unsafe { addr.cast::<u32>().write_volatile(value) }
+}
+
+unsafe extern "C" fn mmio_read_u16(addr: *const c_void) -> u16 {
+    unsafe { addr.cast::<u16>().read_volatile() }
+}
+
+unsafe extern "C" fn mmio_write_u16(addr: *mut c_void, value: u16) {
+    unsafe { addr.cast::<u16>().write_volatile(value) }
+}
+
+/// The vectors requested through the API, whose data is a boxed handler
+static API_VECTORS: RwLock<Vec<u8>> = RwLock::new(Vec::new());
+
+/// Calls the handler of a driver, with `data` pointing to the handler and its data
+fn irq_trampoline(vector: u8, data: usize) {
+    // SAFETY: Set up by `request_irq`, and only freed once the handler is removed
+    let (handler, data) = unsafe { *(data as *const (ApiIrqHandler, *mut c_void)) };
+    handler(vector, data);
+}
+
+extern "C" fn request_irq(handler: ApiIrqHandler, data: *mut c_void) -> i32 {
+    let action = Box::into_raw(Box::new((handler, data)));
+    match irq::request_any_irq("driver", irq_trampoline, action as usize) {
+        Ok(vector) => {
+            API_VECTORS.write().push(vector);
+            vector as i32
+        }
+        Err(_) => {
+            drop(unsafe { Box::from_raw(action) });
+            -1
+        }
+    }
+}
+
+extern "C" fn free_irq(vector: u8) {
+    let mut vectors = API_VECTORS.write();
+    let Some(idx) = vectors.iter().position(|other| *other == vector) else {
+        return;
+    };
+    vectors.swap_remove(idx);
+    if let Some(action) = irq::free_irq(vector) {
+        drop(unsafe { Box::from_raw(action.data as *mut (ApiIrqHandler, *mut c_void)) });
+    }
+}
+
+unsafe extern "C" fn dma_alloc(size: usize, phys: *mut u64) -> *mut c_void {
+    let Some(frame) = FRAME_ALLOCATOR
+        .lock()
+        .allocate_contiguous(size.div_ceil(Size4KiB::SIZE))
+    else {
+        return ptr::null_mut();
+    };
+    let virt = KernelPageTable::direct_map_start() + frame.start_address().as_usize();
+    unsafe {
+        ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, size);
+        phys.write(frame.start_address().as_usize() as u64);
+    }
+    virt.as_mut_ptr()
+}
+
+unsafe extern "C" fn dma_free(virt: *mut c_void, size: usize) {
+    let phys = virt as usize - KernelPageTable::direct_map_start().as_usize();
+    let frame = PhysFrame::from_start_address(PhysAddr::new(phys));
+    unsafe {
+        FRAME_ALLOCATOR
+            .lock()
+            .deallocate_contiguous(frame, size.div_ceil(Size4KiB::SIZE))
+    };
+}
@@ -0,0 +1,239 @@
+//! Linking relocatable objects into module memory
+//!
+//! The allocated sections are laid out in three page aligned groups, executable, read only and
+//! writable, so each group can get its own permissions once relocations are applied. Modules must
+//! be built with the kernel code model and without PIC, so only absolute and PC relative
+//! relocations have to be handled, and every target is within 2GiB of the kernel text.
+
+use alloc::{string::ToString, vec, vec::Vec};
+
+use crate::{
+    elf::{
+        Elf, ElfError, ElfType,
+        section::{
+            SHN_ABS, SHN_COMMON, SHN_UNDEF, SHT_NOBITS, SHT_PROGBITS, SHT_RELA, SHT_SYMTAB, STB_WEAK, SectionFlags,
+        },
+    },
+    mm::page_table::PageTableFlags,
+    module::{ModuleError, abi::MODULE_INFO_SYMBOL, memory::ModuleMemory},
+};
+
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_PC32: u32 = 2;
+pub const R_X86_64_PLT32: u32 = 4;
+pub const R_X86_64_32: u32 = 10;
+pub const R_X86_64_32S: u32 = 11;
+pub const R_X86_64_PC64: u32 = 24;
+
+const PAGE_SIZE: usize = 4096;
+
+/// A value to store at a relocated location
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Patch {
+    None,
+    U32(u32),
+    U64(u64),
+}
+
+/// Computes a relocation, where `s` is the symbol, `a` the addend and `p` the place being patched
+fn relocation(kind: u32, s: u64, a: i64, p: u64) -> Result<Patch, ModuleError> {
+    let value = s.wrapping_add_signed(a);
+    let signed = |value: u64| {
+        i32::try_from(value as i64)
+            .map(|value| Patch::U32(value as u32))
+            .map_err(|_| ModuleError::RelocationOverflow)
+    };
+    match kind {
+        R_X86_64_NONE => Ok(Patch::None),
+        R_X86_64_64 => Ok(Patch::U64(value)),
+        R_X86_64_PC64 => Ok(Patch::U64(value.wrapping_sub(p))),
+        R_X86_64_PC32 | R_X86_64_PLT32 => signed(value.wrapping_sub(p)),
+        R_X86_64_32S => signed(value),
+        R_X86_64_32 => u32::try_from(value)
+            .map(Patch::U32)
+            .map_err(|_| ModuleError::RelocationOverflow),
+        _ => Err(ElfError::UnsupportedRelocation(kind).into()),
+    }
+}
+
+/// A linked module image
+pub struct Linked {
+    pub memory: ModuleMemory,
+    /// The address of the module's `ModuleInfo`
+    pub info: usize,
+}
+
+/// Returns the permission group of an allocated section
+fn group(flags: SectionFlags) -> usize {
+    if flags.contains(SectionFlags::EXECINSTR) {
+        0
+    } else if flags.contains(SectionFlags::WRITE) {
+        2
+    } else {
+        1
+    }
+}
+
+const GROUP_FLAGS: [PageTableFlags; 3] = [
+    PageTableFlags::empty(),
+    PageTableFlags::NO_EXECUTE,
+    PageTableFlags::from_bits_truncate(PageTableFlags::WRITABLE.bits() | PageTableFlags::NO_EXECUTE.bits()),
+];
+
+/// Loads a relocatable object, resolving its undefined symbols with `resolve`
+pub fn link(elf: &Elf, resolve: impl Fn(&str) -> Option<usize>) -> Result<Linked, ModuleError> {
+    if elf.kind() != ElfType::Relocatable {
+        return Err(ModuleError::NotRelocatable);
+    }
+
+    // Assign every allocated section an offset in the image, group by group
+    let mut placement = vec![None; elf.section_count()];
+    let mut groups = [(0, 0); 3];
+    let mut size = 0usize;
+    for (group_idx, range) in groups.iter_mut().enumerate() {
+        let start = size;
+        for (idx, sh) in elf.section_headers().enumerate() {
+            let sh = sh?;
+            if !sh.flags.contains(SectionFlags::ALLOC) || sh.size == 0 || group(sh.flags) != group_idx {
+                continue;
+            }
+            if sh.kind != SHT_PROGBITS && sh.kind != SHT_NOBITS {
+                continue;
+            }
+            let offset = size.next_multiple_of(sh.align.clamp(1, PAGE_SIZE));
+            placement[idx] = Some(offset);
+            size = offset + sh.size;
+        }
+        size = size.next_multiple_of(PAGE_SIZE);
+        *range = (start, size);
+    }
+    if size == 0 {
+        return Err(ElfError::BadSection.into());
+    }
+
+    let mut memory = ModuleMemory::allocate(size)?;
+    let base = memory.start().as_usize();
+    let image = memory.as_mut_slice();
+    for (idx, sh) in elf.section_headers().enumerate() {
+        let sh = sh?;
+        if let Some(offset) = placement[idx]
+            && sh.kind == SHT_PROGBITS
+        {
+            image[offset..offset + sh.size].copy_from_slice(elf.section_data(&sh)?);
+        }
+    }
+
+    // Resolve the symbol table, symbols in sections that weren't loaded are left as `None`
+    let mut symtab = None;
+    for sh in elf.section_headers() {
+        let sh = sh?;
+        if sh.kind == SHT_SYMTAB {
+            symtab = Some(sh);
+            break;
+        }
+    }
+    let symtab = symtab.ok_or(ElfError::BadSection)?;
+    let mut symbols = Vec::new();
+    let mut info = None;
+    for sym in elf.symbols(&symtab)? {
+        let sym = sym?;
+        let addr = match sym.shndx {
+            SHN_UNDEF if sym.name.is_empty() => Some(0),
+            SHN_UNDEF => match resolve(sym.name) {
+                Some(addr) => Some(addr),
+                None if sym.bind == STB_WEAK => Some(0),
+                None => return Err(ModuleError::UndefinedSymbol(sym.name.to_string())),
+            },
+            SHN_ABS => Some(sym.value),
+            SHN_COMMON => return Err(ElfError::BadSection.into()),
+            shndx => placement
+                .get(shndx as usize)
+                .copied()
+                .flatten()
+                .map(|offset| base + offset + sym.value),
+        };
+        if sym.shndx != SHN_UNDEF && sym.name == MODULE_INFO_SYMBOL {
+            info = addr;
+        }
+        symbols.push(addr);
+    }
+
+    for sh in elf.section_headers() {
+        let sh = sh?;
+        if sh.kind != SHT_RELA {
+            continue;
+        }
+        // Relocations of sections that aren't loaded, like debug info, are skipped
+        let target = sh.info as usize;
+        let Some(target_offset) = placement.get(target).copied().flatten() else {
+            continue;
+        };
+        let target_size = elf.section_header(target)?.size;
+        for rela in elf.relocations(&sh)? {
+            let s = symbols
+                .get(rela.symbol)
+                .copied()
+                .flatten()
+                .ok_or(ModuleError::BadRelocation)?;
+            // The offset comes from the file, and is checked before anything is computed from it
+            let offset = target_offset
+                .checked_add(rela.offset)
+                .filter(|_| rela.offset < target_size)
+                .ok_or(ModuleError::BadRelocation)?;
+            let patch = relocation(rela.kind, s as u64, rela.addend, (base + offset) as u64)?;
+            let (bytes, len) = match patch {
+                Patch::None => continue,
+                Patch::U32(value) => ((value as u64).to_le_bytes(), 4),
+                Patch::U64(value) => (value.to_le_bytes(), 8),
+            };
+            if rela.offset + len > target_size {
+                return Err(ModuleError::BadRelocation);
+            }
+            image[offset..offset + len].copy_from_slice(&bytes[..len]);
+        }
+    }
+
+    for (range, flags) in groups.iter().zip(GROUP_FLAGS) {
+        if range.1 > range.0 {
+            memory.protect(range.0, range.1 - range.0, flags);
+        }
+    }
+
+    let info = info.ok_or(ModuleError::NoModuleInfo)?;
+    Ok(Linked { memory, info })
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_relocations() {
+        let text = 0xFFFF_FFFF_C000_0000u64;
+        let kernel = 0xFFFF_FFFF_8010_0000u64;
+
+        assert_eq!(relocation(R_X86_64_64, kernel, 8, text), Ok(Patch::U64(kernel + 8)));
+        // A call into the kernel, the addend accounts for the 4 byte displacement
+        assert_eq!(
+            relocation(R_X86_64_PLT32, kernel, -4, text + 0x10),
+            Ok(Patch::U32((kernel as i64 - 4 - (text + 0x10) as i64) as i32 as u32))
+        );
+        // The heap is too far away for a 32 bit displacement
+        assert_eq!(
+            relocation(R_X86_64_PC32, 0xFFFF_C000_0000_0000, 0, text),
+            Err(ModuleError::RelocationOverflow)
+        );
+        // Sign extended 32 bit addresses reach the top 2GiB, zero extended ones don't
+        assert_eq!(relocation(R_X86_64_32S, kernel, 0, 0), Ok(Patch::U32(kernel as u32)));
+        assert_eq!(
+            relocation(R_X86_64_32, kernel, 0, 0),
+            Err(ModuleError::RelocationOverflow)
+        );
+        assert_eq!(relocation(R_X86_64_NONE, 0, 0, 0), Ok(Patch::None));
+        assert_eq!(
+            relocation(9, 0, 0, 0),
+            Err(ModuleError::Elf(ElfError::UnsupportedRelocation(9)))
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! Memory for module images, in the module space next to the kernel text
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::vec::Vec;
+
+use crate::{
+    arch::{VirtAddr, registers::control::Cr3},
+    mm::{
+        FRAME_ALLOCATOR, mappings,
+        page_table::{KernelPageTable, Mapper, PageTableFlags},
+        paging::{FrameAllocator, FrameDeallocator, Page, PageSize, PhysFrame, Size4KiB},
+    },
+    module::ModuleError,
+};
+
+/// The next free virtual address in the module space
+///
+/// Like the MMIO space, virtual space is never reused, only the frames are freed on unload.
+static NEXT_MODULE: AtomicUsize = AtomicUsize::new(mappings::MODULE_SPACE_START.as_usize());
+
+/// Zeroed pages holding a module image, freed when dropped
+#[derive(Debug)]
+pub struct ModuleMemory {
+    start: VirtAddr,
+    frames: Vec<PhysFrame>,
+}
+
+impl ModuleMemory {
+    /// Maps `size` bytes of zeroed, writable and non executable memory
+    pub fn allocate(size: usize) -> Result<Self, ModuleError> {
+        let pages = size.div_ceil(Size4KiB::SIZE);
+        let start = NEXT_MODULE.fetch_add(pages * Size4KiB::SIZE, Ordering::Relaxed);
+        if start + pages * Size4KiB::SIZE > mappings::MODULE_SPACE_END.as_usize() {
+            return Err(ModuleError::SpaceExhausted);
+        }
+
+        let mut memory = Self {
+            start: VirtAddr::new(start),
+            frames: Vec::with_capacity(pages),
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let mut page_table = KernelPageTable::new(Cr3::addr());
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        for i in 0..pages {
+            // On failure, the pages mapped so far are freed when `memory` is dropped
+            let frame = frame_allocator.allocate_frame().ok_or(ModuleError::OutOfMemory)?;
+            let page = Page::<Size4KiB>::from_start_address(memory.start + i * Size4KiB::SIZE);
+            unsafe { page_table.map_with_allocator(page, frame, flags, &mut *frame_allocator) };
+            memory.frames.push(frame);
+        }
+        drop(frame_allocator);
+
+        memory.as_mut_slice().fill(0);
+        Ok(memory)
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn size(&self) -> usize {
+        self.frames.len() * Size4KiB::SIZE
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.start.as_usize()..self.start.as_usize() + self.size()).contains(&addr)
+    }
+
+    /// Returns the memory for writing the image, which only works before it is protected
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.start.as_mut_ptr::<u8>(), self.size()) }
+    }
+
+    /// Changes the permissions of the pages covering `[offset, offset + len)`
+    pub fn protect(&self, offset: usize, len: usize, flags: PageTableFlags) {
+        let first = offset / Size4KiB::SIZE;
+        let last = (offset + len).div_ceil(Size4KiB::SIZE).min(self.frames.len());
+        let mut page_table = KernelPageTable::new(Cr3::addr());
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        for (i, frame) in self.frames.iter().enumerate().take(last).skip(first) {
+            let addr = self.start + i * Size4KiB::SIZE;
+            let page = Page::<Size4KiB>::from_start_address(addr);
+            unsafe {
+                page_table.map_with_allocator(page, *frame, flags | PageTableFlags::PRESENT, &mut *frame_allocator);
+                crate::arch::instructions::invlpg(addr);
+            }
+        }
+    }
+}
+
+impl Drop for ModuleMemory {
+    fn drop(&mut self) {
+        let mut page_table = KernelPageTable::new(Cr3::addr());
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        for (i, frame) in self.frames.drain(..).enumerate() {
+            unsafe {
+                page_table.unmap(Page::<Size4KiB>::from_start_address(self.start + i * Size4KiB::SIZE));
+                frame_allocator.deallocate_frame(frame);
+            }
+        }
+    }
+}
@@ -0,0 +1,327 @@
+//! Loadable kernel modules
+//!
+//! A module is a relocatable ELF object linked into the module space at runtime. Its undefined
+//! symbols are resolved against the kernel symbol table (see [`symbols`]). It describes itself
+//! with a [`ModuleInfo`], whose ABI version and capabilities are checked before anything in the
+//! module runs (see [`abi`]), and whose PCI drivers are registered with the driver core exactly
+//! like built-in drivers.
+//!
+//! Anything holding on to code or data of a module holds a [`ModuleRef`], and a module can only be
+//! unloaded while it has none.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::{
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use crate::{
+    dev::drivers::{pci, pci::PciDrv},
+    elf::{Elf, ElfError},
+    kprintln,
+    sync::RwLock,
+};
+
+pub mod abi;
+#[cfg(target_arch = "x86_64")]
+pub mod api;
+mod loader;
+mod memory;
+pub mod symbols;
+
+use abi::{ABI_VERSION, AbiVersion, KERNEL_CAPS, MODULE_MAGIC, ModuleCaps, ModuleInfo};
+use memory::ModuleMemory;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModuleError {
+    Elf(ElfError),
+    /// The image is an executable or shared object, not an object file
+    NotRelocatable,
+    /// A module with the same name is loaded
+    Exists,
+    NotLoaded,
+    /// The module is in use, with the given number of references
+    Busy(usize),
+    UndefinedSymbol(String),
+    /// A relocation refers to a symbol or location outside of the loaded sections
+    BadRelocation,
+    /// A relocated value doesn't fit its field
+    RelocationOverflow,
+    OutOfMemory,
+    SpaceExhausted,
+    /// The module doesn't export a valid `__hadron_module_info`
+    NoModuleInfo,
+    /// The module was built against an incompatible ABI version
+    AbiMismatch(AbiVersion),
+    /// The kernel lacks capabilities the module requires
+    MissingCapabilities(ModuleCaps),
+    /// The module's `init` returned the given error
+    InitFailed(i32),
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Elf(err) => write!(f, "{}", err),
+            Self::NotRelocatable => f.write_str("not a relocatable object"),
+            Self::Exists => f.write_str("module is already loaded"),
+            Self::NotLoaded => f.write_str("module is not loaded"),
+            Self::Busy(refs) => write!(f, "module is in use ({} references)", refs),
+            Self::UndefinedSymbol(name) => write!(f, "undefined symbol '{}'", name),
+            Self::BadRelocation => f.write_str("relocation outside of the loaded sections"),
+            Self::RelocationOverflow => f.write_str("relocated value out of range"),
+            Self::OutOfMemory => f.write_str("out of memory"),
+            Self::SpaceExhausted => f.write_str("module virtual address space exhausted"),
+            Self::NoModuleInfo => f.write_str("no valid module info"),
+            Self::AbiMismatch(abi) => write!(f, "module ABI {} is incompatible with kernel ABI {}", abi, ABI_VERSION),
+            Self::MissingCapabilities(caps) => write!(f, "missing required capabilities {:?}", caps),
+            Self::InitFailed(code) => write!(f, "module init failed with {}", code),
+        }
+    }
+}
+
+impl core::error::Error for ModuleError {}
+
+impl From<ElfError> for ModuleError {
+    fn from(err: ElfError) -> Self {
+        Self::Elf(err)
+    }
+}
+
+pub struct Module {
+    name: String,
+    abi: AbiVersion,
+    caps: ModuleCaps,
+    memory: ModuleMemory,
+    refcount: AtomicUsize,
+    pci_drivers: &'static [PciDrv],
+    exit: Option<extern "C" fn()>,
+}
+
+impl Module {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the ABI version the module was built against
+    pub fn abi(&self) -> AbiVersion {
+        self.abi
+    }
+
+    /// Returns the capabilities granted to the module
+    pub fn caps(&self) -> ModuleCaps {
+        self.caps
+    }
+
+    /// Returns the address the image is loaded at
+    pub fn start(&self) -> usize {
+        self.memory.start().as_usize()
+    }
+
+    pub fn size(&self) -> usize {
+        self.memory.size()
+    }
+
+    pub fn refcount(&self) -> usize {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    /// Returns whether an address is in the image of the module
+    pub fn contains(&self, addr: usize) -> bool {
+        self.memory.contains(addr)
+    }
+
+    /// Returns the number of drivers the module registered
+    pub fn driver_count(&self) -> usize {
+        self.pci_drivers.len()
+    }
+
+    fn get(self: &Arc<Self>) -> ModuleRef {
+        self.refcount.fetch_add(1, Ordering::AcqRel);
+        ModuleRef(self.clone())
+    }
+}
+
+impl fmt::Debug for Module {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Module")
+            .field("name", &self.name)
+            .field("start", &self.memory.start())
+            .field("size", &self.size())
+            .field("refcount", &self.refcount())
+            .finish()
+    }
+}
+
+/// A reference keeping a module loaded
+#[derive(Debug)]
+pub struct ModuleRef(Arc<Module>);
+
+impl core::ops::Deref for ModuleRef {
+    type Target = Module;
+
+    fn deref(&self) -> &Module {
+        &self.0
+    }
+}
+
+impl Drop for ModuleRef {
+    fn drop(&mut self) {
+        self.0.refcount.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+static MODULES: RwLock<Vec<Arc<Module>>> = RwLock::new(Vec::new());
+
+pub fn modules() -> Vec<Arc<Module>> {
+    MODULES.read().clone()
+}
+
+/// Returns a reference to the module containing an address, such as a driver descriptor
+///
+/// Returns `None` for addresses in the kernel image.
+pub fn owner(addr: usize) -> Option<ModuleRef> {
+    MODULES
+        .read()
+        .iter()
+        .find(|module| module.contains(addr))
+        .map(Module::get)
+}
+
+/// Like [`owner`], but gives up rather than wait for a module being loaded or unloaded
+///
+/// For exception and panic context, where the CPU holding the lock may never release it.
+pub fn try_owner(addr: usize) -> Option<ModuleRef> {
+    MODULES
+        .try_read()?
+        .iter()
+        .find(|module| module.contains(addr))
+        .map(Module::get)
+}
+
+/// Checks the module info of a linked image, returning the validated descriptor
+fn module_info(memory: &ModuleMemory, addr: usize) -> Result<&ModuleInfo, ModuleError> {
+    let in_image = |(start, len): (usize, usize)| {
+        len == 0 || (memory.contains(start) && start.checked_add(len - 1).is_some_and(|end| memory.contains(end)))
+    };
+    if !in_image((addr, size_of::<ModuleInfo>())) || !addr.is_multiple_of(align_of::<ModuleInfo>()) {
+        return Err(ModuleError::NoModuleInfo);
+    }
+    // SAFETY: The descriptor is in the image, which is mapped and relocated
+    let info = unsafe { &*(addr as *const ModuleInfo) };
+    if info.magic != MODULE_MAGIC {
+        return Err(ModuleError::NoModuleInfo);
+    }
+    // Nothing past the version is read before the version is checked
+    if !info.abi.is_compatible_with(ABI_VERSION) {
+        return Err(ModuleError::AbiMismatch(info.abi));
+    }
+    let drivers = info.pci_drivers.addr_range();
+    if !in_image(info.name.addr_range())
+        || !in_image(drivers)
+        || (drivers.1 != 0 && !drivers.0.is_multiple_of(align_of::<PciDrv>()))
+    {
+        return Err(ModuleError::NoModuleInfo);
+    }
+    Ok(info)
+}
+
+/// Links a module, negotiates its capabilities, runs its `init` and registers its drivers
+pub fn load(bytes: &[u8]) -> Result<Arc<Module>, ModuleError> {
+    let linked = loader::link(&Elf::parse(bytes)?, symbols::lookup)?;
+    let info = module_info(&linked.memory, linked.info)?;
+    // SAFETY: The name and drivers were checked to be in the image, and the drivers are only used
+    // while the module, which owns the image, is loaded
+    let (name, pci_drivers) = unsafe {
+        let drivers: *const [PciDrv] = info.pci_drivers.as_slice();
+        (info.name.as_str().to_string(), &*drivers)
+    };
+    let (abi, required, optional, init, exit) = (info.abi, info.required, info.optional, info.init, info.exit);
+    if MODULES.read().iter().any(|module| module.name == name) {
+        return Err(ModuleError::Exists);
+    }
+
+    let caps = abi::negotiate(required, optional, KERNEL_CAPS).map_err(ModuleError::MissingCapabilities)?;
+    if !pci_drivers.is_empty() && !caps.contains(ModuleCaps::PCI_DRIVERS) {
+        return Err(ModuleError::MissingCapabilities(ModuleCaps::PCI_DRIVERS));
+    }
+    if let Some(init) = init {
+        let code = init(caps);
+        if code != 0 {
+            return Err(ModuleError::InitFailed(code));
+        }
+    }
+
+    let module = Arc::new(Module {
+        name,
+        abi,
+        caps,
+        memory: linked.memory,
+        refcount: AtomicUsize::new(0),
+        pci_drivers,
+        exit,
+    });
+    {
+        let mut modules = MODULES.write();
+        if modules.iter().any(|other| other.name == module.name) {
+            drop(modules);
+            if let Some(exit) = module.exit {
+                exit();
+            }
+            return Err(ModuleError::Exists);
+        }
+        modules.push(module.clone());
+    }
+
+    // Registered once the module is in the list, so drivers binding to devices find their owner
+    for drv in module.pci_drivers {
+        // SAFETY: The drivers are unregistered before the module memory is freed
+        unsafe { pci::register_driver(drv) };
+    }
+    kprintln!(
+        Info,
+        "module: loaded {} (ABI {}) at {:#x} ({} KiB, {} drivers)",
+        module.name,
+        module.abi,
+        module.memory.start().as_usize(),
+        module.size() / 1024,
+        module.driver_count()
+    );
+    Ok(module)
+}
+
+/// Unregisters the drivers of a module, runs its `exit` and frees it
+///
+/// Fails if anything still holds a [`ModuleRef`], which includes devices bound to its drivers.
+pub fn unload(name: &str) -> Result<(), ModuleError> {
+    let module = {
+        let mut modules = MODULES.write();
+        let idx = modules
+            .iter()
+            .position(|module| module.name == name)
+            .ok_or(ModuleError::NotLoaded)?;
+        // New references are only handed out by `owner`, which can't run while the list is locked
+        let refs = modules[idx].refcount();
+        if refs != 0 {
+            return Err(ModuleError::Busy(refs));
+        }
+        let module = modules.remove(idx);
+        for drv in module.pci_drivers {
+            pci::unregister_driver(drv);
+        }
+        module
+    };
+
+    if let Some(exit) = module.exit {
+        exit();
+    }
+    kprintln!(Info, "module: unloaded {}", name);
+    // The memory is freed once the last `Arc`, such as one returned by `modules`, is dropped
+    drop(module);
+    Ok(())
+}
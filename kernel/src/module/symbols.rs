@@ -0,0 +1,126 @@
+//! The kernel symbol table
+//!
+//! Only symbols exported with [`export_symbol!`](crate::export_symbol) can be used by modules.
+//! Exported functions are `extern "C"` and unmangled, so a module can declare them by name no
+//! matter which compiler version built it.
+
+use core::ffi::c_void;
+
+use crate::util::kprint::LogLevel;
+
+/// An entry of the `.ksymtab` linker section
+#[repr(C)]
+#[derive(Debug)]
+pub struct KernelSymbol {
+    pub name: &'static str,
+    pub addr: *const (),
+}
+
+unsafe impl Sync for KernelSymbol {}
+
+/// Adds an unmangled function to the kernel symbol table
+#[macro_export]
+macro_rules! export_symbol {
+    ($sym:ident) => {
+        const _: () = {
+            #[used]
+            #[cfg_attr(target_arch = "x86_64", unsafe(link_section = ".ksymtab"))]
+            static SYMBOL: $crate::module::symbols::KernelSymbol = $crate::module::symbols::KernelSymbol {
+                name: stringify!($sym),
+                addr: $sym as *const (),
+            };
+        };
+    };
+}
+
+/// Returns the exported kernel symbols
+pub fn symbols() -> &'static [KernelSymbol] {
+    unsafe extern "C" {
+        static _ksymtab_start: u8;
+        static _ksymtab_end: u8;
+    }
+    let size = (&raw const _ksymtab_end) as usize - (&raw const _ksymtab_start) as usize;
+    unsafe {
+        core::slice::from_raw_parts(
+            (&raw const _ksymtab_start).cast::<KernelSymbol>(),
+            size / size_of::<KernelSymbol>(),
+        )
+    }
+}
+
+pub fn lookup(name: &str) -> Option<usize> {
+    symbols()
+        .iter()
+        .find(|sym| sym.name == name)
+        .map(|sym| sym.addr as usize)
+}
+
+/// Writes a message to the kernel log, `level` is a [`LogLevel`]
+///
+/// # Safety
+/// `msg` must point to `len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn hadron_log(level: u8, msg: *const u8, len: usize) {
+    let level = match level {
+        0 => LogLevel::Debug,
+        1 => LogLevel::Info,
+        2 => LogLevel::Warn,
+        3 => LogLevel::Error,
+        _ => LogLevel::Fatal,
+    };
+    let msg = unsafe { core::slice::from_raw_parts(msg, len) };
+    let msg = core::str::from_utf8(msg).unwrap_or("<invalid UTF-8>");
+    crate::util::kprint::kprint_internal(format_args!(
+        "{} {} {}\n",
+        crate::util::kprint::Timestamp::now(),
+        level,
+        msg
+    ));
+}
+export_symbol!(hadron_log);
+
+/// Allocates from the kernel heap, returning null on failure
+#[unsafe(no_mangle)]
+pub extern "C" fn hadron_alloc(size: usize, align: usize) -> *mut c_void {
+    match core::alloc::Layout::from_size_align(size, align) {
+        Ok(layout) if size != 0 => unsafe { alloc::alloc::alloc(layout).cast() },
+        _ => core::ptr::null_mut(),
+    }
+}
+export_symbol!(hadron_alloc);
+
+/// Frees memory returned by [`hadron_alloc`]
+///
+/// # Safety
+/// `ptr` must have been allocated with the same size and alignment, and not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn hadron_free(ptr: *mut c_void, size: usize, align: usize) {
+    if let Ok(layout) = core::alloc::Layout::from_size_align(size, align)
+        && !ptr.is_null()
+    {
+        unsafe { alloc::alloc::dealloc(ptr.cast(), layout) };
+    }
+}
+export_symbol!(hadron_free);
+
+#[unsafe(no_mangle)]
+pub extern "C" fn hadron_monotonic_ns() -> u64 {
+    crate::time::monotonic_ns()
+}
+export_symbol!(hadron_monotonic_ns);
+
+#[cfg(target_arch = "x86_64")]
+#[unsafe(no_mangle)]
+pub extern "C" fn hadron_pci_read_u32(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    crate::dev::pci::PciAddress::new(bus, device, function).read_u32(offset)
+}
+#[cfg(target_arch = "x86_64")]
+export_symbol!(hadron_pci_read_u32);
+
+#[cfg(target_arch = "x86_64")]
+#[unsafe(no_mangle)]
+pub extern "C" fn hadron_pci_write_u32(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    crate::dev::pci::PciAddress::new(bus, device, function).write_u32(offset, value)
+}
+#[cfg(target_arch = "x86_64")]
+export_symbol!(hadron_pci_write_u32);
@@ -0,0 +1,190 @@
+//! Address Resolution Protocol
+//!
+//! Resolves IPv4 addresses on an Ethernet segment. Packets to an address that isn't in the cache
+//! yet are held back until the reply arrives.
+
+use core::net::Ipv4Addr;
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    net::{
+        MacAddr, NetInterface,
+        buf::ChecksumState,
+        ethernet::{self, ETHERTYPE_ARP, ETHERTYPE_IPV4},
+    },
+    sync::Mutex,
+    time,
+};
+
+const HTYPE_ETHERNET: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+const PACKET_LEN: usize = 28;
+
+/// The number of packets that can wait for address resolution
+const MAX_PENDING: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct ArpEntry {
+    pub ip: Ipv4Addr,
+    pub mac: MacAddr,
+    pub iface: &'static str,
+    /// The monotonic time the entry was last confirmed
+    pub updated_ns: u64,
+}
+
+/// An IPv4 packet waiting for the hardware address of its next hop
+struct Pending {
+    next_hop: Ipv4Addr,
+    iface: Arc<NetInterface>,
+    packet: Vec<u8>,
+    csum: ChecksumState,
+}
+
+struct ArpState {
+    cache: Vec<ArpEntry>,
+    pending: Vec<Pending>,
+}
+
+static ARP: Mutex<ArpState> = Mutex::new(ArpState {
+    cache: Vec::new(),
+    pending: Vec::new(),
+});
+
+/// Returns a copy of the cache
+pub fn entries() -> Vec<ArpEntry> {
+    ARP.lock().cache.clone()
+}
+
+/// Returns the number of packets waiting for address resolution
+pub fn pending() -> usize {
+    ARP.lock().pending.len()
+}
+
+/// Removes the entry for `ip`, returning whether there was one
+pub fn remove(ip: Ipv4Addr) -> bool {
+    let mut state = ARP.lock();
+    let len = state.cache.len();
+    state.cache.retain(|entry| entry.ip != ip);
+    state.cache.len() != len
+}
+
+/// Removes every entry and drops the packets waiting for resolution, returning the number of entries removed
+pub fn flush() -> usize {
+    let mut state = ARP.lock();
+    let count = state.cache.len();
+    state.cache.clear();
+    for pending in state.pending.drain(..) {
+        pending.iface.stats().tx_drop();
+    }
+    count
+}
+
+/// Returns the hardware address of `ip`, if it is cached
+pub fn lookup(ip: Ipv4Addr) -> Option<MacAddr> {
+    ARP.lock()
+        .cache
+        .iter()
+        .find(|entry| entry.ip == ip)
+        .map(|entry| entry.mac)
+}
+
+fn update(state: &mut ArpState, iface: &NetInterface, ip: Ipv4Addr, mac: MacAddr) {
+    let updated_ns = time::monotonic_ns();
+    match state.cache.iter_mut().find(|entry| entry.ip == ip) {
+        Some(entry) => {
+            entry.mac = mac;
+            entry.iface = iface.name();
+            entry.updated_ns = updated_ns;
+        }
+        None => state.cache.push(ArpEntry {
+            ip,
+            mac,
+            iface: iface.name(),
+            updated_ns,
+        }),
+    }
+}
+
+fn send_packet(iface: &NetInterface, op: u16, dst_mac: MacAddr, target_mac: MacAddr, target_ip: Ipv4Addr) {
+    let Some(addr) = iface.ipv4() else {
+        return;
+    };
+    let mut packet = [0; PACKET_LEN];
+    packet[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+    packet[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet[4] = 6;
+    packet[5] = 4;
+    packet[6..8].copy_from_slice(&op.to_be_bytes());
+    packet[8..14].copy_from_slice(&iface.mac().0);
+    packet[14..18].copy_from_slice(&addr.addr.octets());
+    packet[18..24].copy_from_slice(&target_mac.0);
+    packet[24..28].copy_from_slice(&target_ip.octets());
+    // A lost request is retried by the next packet to the same address
+    _ = ethernet::send(iface, dst_mac, ETHERTYPE_ARP, &packet);
+}
+
+/// Sends an IPv4 packet to `next_hop`, resolving its hardware address first if needed
+pub(super) fn send_ipv4(iface: Arc<NetInterface>, next_hop: Ipv4Addr, packet: Vec<u8>, csum: ChecksumState) {
+    if let Some(mac) = lookup(next_hop) {
+        _ = ethernet::send_buf(&iface, mac, ETHERTYPE_IPV4, &packet, csum);
+        return;
+    }
+
+    {
+        let mut state = ARP.lock();
+        if state.pending.len() >= MAX_PENDING {
+            iface.stats().tx_drop();
+            return;
+        }
+        state.pending.push(Pending {
+            next_hop,
+            iface: iface.clone(),
+            packet,
+            csum,
+        });
+    }
+    send_packet(&iface, OP_REQUEST, MacAddr::BROADCAST, MacAddr::ZERO, next_hop);
+}
+
+/// Handles a received ARP packet
+pub(super) fn handle(iface: &NetInterface, packet: &[u8]) {
+    if packet.len() < PACKET_LEN
+        || u16::from_be_bytes([packet[0], packet[1]]) != HTYPE_ETHERNET
+        || u16::from_be_bytes([packet[2], packet[3]]) != ETHERTYPE_IPV4
+    {
+        return;
+    }
+    let op = u16::from_be_bytes([packet[6], packet[7]]);
+    let sender_mac = MacAddr(packet[8..14].try_into().unwrap());
+    let sender_ip = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[14..18]).unwrap());
+    let target_ip = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[24..28]).unwrap());
+    let for_us = iface.ipv4().is_some_and(|addr| addr.addr == target_ip);
+
+    let ready = {
+        let mut state = ARP.lock();
+        // Only learn addresses of hosts talking to us, or ones we already know about
+        if for_us || state.cache.iter().any(|entry| entry.ip == sender_ip) {
+            update(&mut state, iface, sender_ip, sender_mac);
+        }
+        state
+            .pending
+            .extract_if(.., |pending| pending.next_hop == sender_ip)
+            .collect::<Vec<_>>()
+    };
+
+    for pending in ready {
+        _ = ethernet::send_buf(
+            &pending.iface,
+            sender_mac,
+            ETHERTYPE_IPV4,
+            &pending.packet,
+            pending.csum,
+        );
+    }
+
+    if op == OP_REQUEST && for_us {
+        send_packet(iface, OP_REPLY, sender_mac, sender_mac, sender_ip);
+    }
+}
@@ -0,0 +1,76 @@
+//! Packet buffers
+//!
+//! A [`PacketBuf`] holds a whole Ethernet frame, along with the metadata that lets checksum and
+//! segmentation work be left to devices that can do it in hardware. A packet being sent can carry
+//! a [`TxCompletion`], signalled once the device is done with it.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{net::NetError, sync::Completion};
+
+/// Signalled once the device is done with a packet, with whether it was sent
+pub type TxCompletion = Completion<Result<(), NetError>>;
+
+/// The state of the transport checksum of a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumState {
+    /// There is no checksum to compute or verify, or it was already handled in software
+    #[default]
+    None,
+    /// TX: the checksum still has to be computed over everything from `start` to the end of the
+    /// packet, and stored at `start + offset`. The checksum field holds the pseudo header sum.
+    Partial { start: u16, offset: u16 },
+    /// RX: the device verified the checksum
+    Unnecessary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GsoKind {
+    TcpV4,
+}
+
+/// Describes how a packet larger than the MTU has to be split before it hits the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GsoInfo {
+    pub kind: GsoKind,
+    /// The payload size of each segment, the TCP MSS
+    pub segment_size: u16,
+    /// The length of the Ethernet, IP and transport headers repeated in every segment
+    pub header_len: u16,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PacketBuf {
+    pub data: Vec<u8>,
+    pub csum: ChecksumState,
+    pub gso: Option<GsoInfo>,
+    /// TX: signalled by the driver once the device is done with the packet, or by the interface
+    /// if it couldn't be queued
+    pub tx_done: Option<Arc<TxCompletion>>,
+}
+
+impl PacketBuf {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            csum: ChecksumState::None,
+            gso: None,
+            tx_done: None,
+        }
+    }
+
+    /// Signals the TX completion of the packet, if it has one
+    pub fn complete_tx(&self, result: Result<(), NetError>) {
+        if let Some(done) = &self.tx_done {
+            done.complete(result);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
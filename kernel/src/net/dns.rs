@@ -0,0 +1,194 @@
+//! DNS resolver
+//!
+//! Resolves host names to IPv4 addresses with recursive queries to a single configured server.
+
+use core::{fmt, net::Ipv4Addr};
+
+use alloc::vec::Vec;
+
+use crate::{
+    net::{NetError, udp::UdpSocket},
+    sync::RwLock,
+    time,
+};
+
+const DNS_PORT: u16 = 53;
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+/// Recursion desired
+const FLAG_RD: u16 = 1 << 8;
+const FLAG_RESPONSE: u16 = 1 << 15;
+const RCODE_NXDOMAIN: u16 = 3;
+
+const TIMEOUT_NS: u64 = 2_000_000_000;
+const ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsError {
+    NoServer,
+    InvalidName,
+    /// The name doesn't exist
+    NotFound,
+    /// The server failed to answer the query
+    ServerFailure,
+    InvalidResponse,
+    Net(NetError),
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoServer => f.write_str("no DNS server configured"),
+            Self::InvalidName => f.write_str("invalid host name"),
+            Self::NotFound => f.write_str("host not found"),
+            Self::ServerFailure => f.write_str("server failure"),
+            Self::InvalidResponse => f.write_str("invalid response"),
+            Self::Net(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl core::error::Error for DnsError {}
+
+impl From<NetError> for DnsError {
+    fn from(err: NetError) -> Self {
+        Self::Net(err)
+    }
+}
+
+static SERVER: RwLock<Option<Ipv4Addr>> = RwLock::new(None);
+
+pub fn server() -> Option<Ipv4Addr> {
+    *SERVER.read()
+}
+
+pub fn set_server(server: Option<Ipv4Addr>) {
+    *SERVER.write() = server;
+}
+
+/// Resolves a host name, which may also be an address in dotted decimal form
+pub fn resolve(name: &str) -> Result<Ipv4Addr, DnsError> {
+    if let Ok(addr) = name.parse() {
+        return Ok(addr);
+    }
+    if name == "localhost" {
+        return Ok(Ipv4Addr::LOCALHOST);
+    }
+    let server = server().ok_or(DnsError::NoServer)?;
+
+    let id = time::monotonic_ns() as u16;
+    let query = build_query(id, name)?;
+    let socket = UdpSocket::bind(None)?;
+    for _ in 0..ATTEMPTS {
+        socket.send_to(server, DNS_PORT, &query)?;
+        let deadline = time::monotonic_ns() + TIMEOUT_NS;
+        while let Some(remaining) = deadline.checked_sub(time::monotonic_ns()) {
+            let reply = match socket.recv_timeout(remaining) {
+                Ok(reply) => reply,
+                Err(NetError::Timeout) => break,
+                Err(err) => return Err(err.into()),
+            };
+            // Ignore stray replies, such as late answers to an earlier attempt of another query
+            if reply.src != server || reply.src_port != DNS_PORT {
+                continue;
+            }
+            if let Some(result) = parse_response(id, &reply.data) {
+                return result;
+            }
+        }
+    }
+    Err(DnsError::Net(NetError::Timeout))
+}
+
+fn build_query(id: u16, name: &str) -> Result<Vec<u8>, DnsError> {
+    let mut query = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RD.to_be_bytes());
+    // One question, no answers or other records
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(DnsError::InvalidName);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Skips a possibly compressed name, returning the offset after it
+fn skip_name(msg: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(offset)?;
+        match len {
+            0 => return Some(offset + 1),
+            // A pointer ends the name
+            len if len & 0xC0 == 0xC0 => return Some(offset + 2),
+            len => offset += 1 + len as usize,
+        }
+    }
+}
+
+/// Parses a response, returning `None` if it isn't a response to our query
+fn parse_response(id: u16, msg: &[u8]) -> Option<Result<Ipv4Addr, DnsError>> {
+    if msg.len() < HEADER_LEN || u16::from_be_bytes([msg[0], msg[1]]) != id {
+        return None;
+    }
+    let flags = u16::from_be_bytes([msg[2], msg[3]]);
+    if flags & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    match flags & 0xF {
+        0 => {}
+        RCODE_NXDOMAIN => return Some(Err(DnsError::NotFound)),
+        _ => return Some(Err(DnsError::ServerFailure)),
+    }
+
+    let questions = u16::from_be_bytes([msg[4], msg[5]]);
+    let answers = u16::from_be_bytes([msg[6], msg[7]]);
+    let parse = || {
+        let mut offset = HEADER_LEN;
+        for _ in 0..questions {
+            offset = skip_name(msg, offset)? + 4;
+        }
+        for _ in 0..answers {
+            offset = skip_name(msg, offset)?;
+            let record = msg.get(offset..offset + 10)?;
+            let ty = u16::from_be_bytes([record[0], record[1]]);
+            let class = u16::from_be_bytes([record[2], record[3]]);
+            let len = u16::from_be_bytes([record[8], record[9]]) as usize;
+            let data = msg.get(offset + 10..offset + 10 + len)?;
+            // CNAMEs come before the address records they point to
+            if ty == TYPE_A && class == CLASS_IN && len == 4 {
+                return Some(Ok(Ipv4Addr::new(data[0], data[1], data[2], data[3])));
+            }
+            offset += 10 + len;
+        }
+        Some(Err(DnsError::NotFound))
+    };
+    Some(parse().unwrap_or(Err(DnsError::InvalidResponse)))
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dns_parse_response() {
+        let mut msg = build_query(0x1234, "example.com").unwrap();
+        msg[2] |= 0x80;
+        msg[7] = 2;
+        // A CNAME pointing elsewhere, then the address, both with compressed names
+        msg.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xC0, 12]);
+        msg.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+
+        assert_eq!(parse_response(0x1234, &msg), Some(Ok(Ipv4Addr::new(93, 184, 216, 34))));
+        assert_eq!(parse_response(0x4321, &msg), None);
+        msg[3] |= RCODE_NXDOMAIN as u8;
+        assert_eq!(parse_response(0x1234, &msg), Some(Err(DnsError::NotFound)));
+    }
+}
@@ -0,0 +1,79 @@
+//! Ethernet framing
+
+use alloc::vec::Vec;
+
+use crate::net::{
+    MacAddr, NetError, NetInterface,
+    buf::{ChecksumState, PacketBuf},
+};
+
+/// The length of an Ethernet header, without VLAN tags
+pub const HEADER_LEN: usize = 14;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EthernetHeader {
+    pub dst: MacAddr,
+    pub src: MacAddr,
+    pub ethertype: u16,
+}
+
+impl EthernetHeader {
+    /// Parses the header of a frame, returning it with the payload
+    pub fn parse(frame: &[u8]) -> Option<(Self, &[u8])> {
+        if frame.len() < HEADER_LEN {
+            return None;
+        }
+        let header = Self {
+            dst: MacAddr(frame[0..6].try_into().unwrap()),
+            src: MacAddr(frame[6..12].try_into().unwrap()),
+            ethertype: u16::from_be_bytes([frame[12], frame[13]]),
+        };
+        Some((header, &frame[HEADER_LEN..]))
+    }
+
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.dst.0);
+        buf.extend_from_slice(&self.src.0);
+        buf.extend_from_slice(&self.ethertype.to_be_bytes());
+    }
+}
+
+/// Sends `payload` in a frame to `dst`
+pub fn send(iface: &NetInterface, dst: MacAddr, ethertype: u16, payload: &[u8]) -> Result<(), NetError> {
+    send_buf(iface, dst, ethertype, payload, ChecksumState::None)
+}
+
+/// Sends `payload` in a frame to `dst`, with a checksum that is relative to the payload
+pub fn send_buf(
+    iface: &NetInterface,
+    dst: MacAddr,
+    ethertype: u16,
+    payload: &[u8],
+    csum: ChecksumState,
+) -> Result<(), NetError> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    EthernetHeader {
+        dst,
+        src: iface.mac(),
+        ethertype,
+    }
+    .write(&mut frame);
+    frame.extend_from_slice(payload);
+
+    let csum = match csum {
+        ChecksumState::Partial { start, offset } => ChecksumState::Partial {
+            start: start + HEADER_LEN as u16,
+            offset,
+        },
+        csum => csum,
+    };
+    iface.transmit_buf(PacketBuf {
+        data: frame,
+        csum,
+        gso: None,
+        tx_done: None,
+    })
+}
@@ -0,0 +1,192 @@
+//! A tiny HTTP/1.1 client
+//!
+//! Used to pull test data or kernel images from the host. Requests block, driving the network
+//! stack themselves with [`poll_until`](crate::net::poll_until), rather than being futures: the
+//! kernel has no executor to run them on yet, and TCP and DNS, which they are built on, block
+//! the same way. Requests still time out without a clock source.
+
+use core::fmt;
+
+use alloc::{string::String, vec::Vec};
+
+use crate::net::{
+    NetError,
+    dns::{self, DnsError},
+    tcp::TcpStream,
+};
+
+const TIMEOUT_NS: u64 = 10_000_000_000;
+/// The largest response we are willing to buffer
+const MAX_RESPONSE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpError {
+    /// The URL isn't an `http://` URL
+    InvalidUrl,
+    Dns(DnsError),
+    Net(NetError),
+    InvalidResponse,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUrl => f.write_str("invalid URL"),
+            Self::Dns(err) => write!(f, "failed to resolve host: {}", err),
+            Self::Net(err) => write!(f, "{}", err),
+            Self::InvalidResponse => f.write_str("invalid response"),
+        }
+    }
+}
+
+impl core::error::Error for HttpError {}
+
+impl From<NetError> for HttpError {
+    fn from(err: NetError) -> Self {
+        Self::Net(err)
+    }
+}
+
+impl From<DnsError> for HttpError {
+    fn from(err: DnsError) -> Self {
+        Self::Dns(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub path: &'a str,
+}
+
+impl<'a> Url<'a> {
+    pub fn parse(url: &'a str) -> Result<Self, HttpError> {
+        let rest = url.strip_prefix("http://").ok_or(HttpError::InvalidUrl)?;
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| HttpError::InvalidUrl)?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(HttpError::InvalidUrl);
+        }
+        Ok(Self { host, port, path })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Returns the value of a header, compared case insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Fetches a URL with a GET request, blocking until the whole response is received
+pub fn get(url: &str) -> Result<Response, HttpError> {
+    let url = Url::parse(url)?;
+    let addr = dns::resolve(url.host)?;
+    let stream = TcpStream::connect(addr, url.port, TIMEOUT_NS)?;
+
+    let request = alloc::format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: hadron\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        url.path,
+        url.host
+    );
+    stream.write_all(request.as_bytes())?;
+
+    // We asked the server to close the connection, so the response ends with the stream
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw, MAX_RESPONSE, TIMEOUT_NS)?;
+    parse_response(&raw).ok_or(HttpError::InvalidResponse)
+}
+
+fn parse_response(raw: &[u8]) -> Option<Response> {
+    let header_end = raw.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = core::str::from_utf8(&raw[..header_end]).ok()?;
+    let body = &raw[header_end + 4..];
+
+    let mut lines = head.split("\r\n");
+    let mut status_line = lines.next()?.splitn(3, ' ');
+    if !status_line.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    let status = status_line.next()?.parse().ok()?;
+    let reason = status_line.next().unwrap_or("").into();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().into(), value.trim().into()))
+        .collect();
+
+    let mut response = Response {
+        status,
+        reason,
+        headers,
+        body: Vec::new(),
+    };
+    response.body = if response
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        decode_chunked(body)?
+    } else if let Some(len) = response.header("Content-Length") {
+        body.get(..len.parse().ok()?)?.to_vec()
+    } else {
+        body.to_vec()
+    };
+    Some(response)
+}
+
+fn decode_chunked(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n")?;
+        let size_line = core::str::from_utf8(&body[..line_end]).ok()?;
+        // Chunk extensions follow a semicolon
+        let size = usize::from_str_radix(size_line.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(out);
+        }
+        out.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_parse_url() {
+        let url = Url::parse("http://10.0.2.2:8000/images/kernel").unwrap();
+        assert_eq!(url.host, "10.0.2.2");
+        assert_eq!(url.port, 8000);
+        assert_eq!(url.path, "/images/kernel");
+        assert_eq!(Url::parse("http://example.com").unwrap().path, "/");
+        assert_eq!(Url::parse("https://example.com"), Err(HttpError::InvalidUrl));
+    }
+
+    #[test]
+    fn http_parse_chunked() {
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n";
+        let response = parse_response(raw).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.reason, "OK");
+        assert_eq!(response.body, b"hello world");
+    }
+}
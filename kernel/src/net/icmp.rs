@@ -0,0 +1,93 @@
+//! Internet Control Message Protocol
+//!
+//! Answers echo requests, and collects echo replies for the `ping` shell command.
+
+use core::net::Ipv4Addr;
+
+use alloc::{collections::VecDeque, vec::Vec};
+
+use crate::{
+    net::{
+        NetError,
+        buf::ChecksumState,
+        ipv4::{self, Ipv4Header, PROTOCOL_ICMP},
+    },
+    sync::Mutex,
+    time,
+};
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const HEADER_LEN: usize = 8;
+
+/// The number of echo replies kept until they are collected
+const MAX_REPLIES: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct EchoReply {
+    pub src: Ipv4Addr,
+    pub ident: u16,
+    pub seq: u16,
+    pub ttl: u8,
+    /// The length of the ICMP message
+    pub len: usize,
+    pub received_ns: u64,
+}
+
+static REPLIES: Mutex<VecDeque<EchoReply>> = Mutex::new(VecDeque::new());
+
+fn send_echo(dst: Ipv4Addr, ty: u8, ident: u16, seq: u16, data: &[u8]) -> Result<(), NetError> {
+    let mut message = Vec::with_capacity(HEADER_LEN + data.len());
+    message.extend_from_slice(&[ty, 0, 0, 0]);
+    message.extend_from_slice(&ident.to_be_bytes());
+    message.extend_from_slice(&seq.to_be_bytes());
+    message.extend_from_slice(data);
+    let sum = ipv4::checksum(&message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+    ipv4::send(dst, PROTOCOL_ICMP, &message)
+}
+
+/// Sends an echo request
+pub fn send_echo_request(dst: Ipv4Addr, ident: u16, seq: u16, data: &[u8]) -> Result<(), NetError> {
+    send_echo(dst, TYPE_ECHO_REQUEST, ident, seq, data)
+}
+
+/// Removes and returns the echo reply matching `ident` and `seq`, if it has arrived
+pub fn take_echo_reply(ident: u16, seq: u16) -> Option<EchoReply> {
+    let mut replies = REPLIES.lock();
+    let idx = replies
+        .iter()
+        .position(|reply| reply.ident == ident && reply.seq == seq)?;
+    replies.remove(idx)
+}
+
+/// Handles a received ICMP message
+pub(super) fn handle(header: &Ipv4Header, message: &[u8], csum: ChecksumState) {
+    if message.len() < HEADER_LEN {
+        return;
+    }
+    if csum != ChecksumState::Unnecessary && ipv4::checksum(message) != 0 {
+        return;
+    }
+    let ident = u16::from_be_bytes([message[4], message[5]]);
+    let seq = u16::from_be_bytes([message[6], message[7]]);
+
+    match message[0] {
+        TYPE_ECHO_REQUEST => _ = send_echo(header.src, TYPE_ECHO_REPLY, ident, seq, &message[HEADER_LEN..]),
+        TYPE_ECHO_REPLY => {
+            let mut replies = REPLIES.lock();
+            if replies.len() >= MAX_REPLIES {
+                replies.pop_front();
+            }
+            replies.push_back(EchoReply {
+                src: header.src,
+                ident,
+                seq,
+                ttl: header.ttl,
+                len: message.len(),
+                received_ns: time::monotonic_ns(),
+            });
+        }
+        _ => {}
+    }
+}
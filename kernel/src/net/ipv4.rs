@@ -0,0 +1,276 @@
+//! Internet Protocol version 4
+
+use core::{
+    fmt,
+    net::Ipv4Addr,
+    str::FromStr,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use alloc::vec::Vec;
+
+use crate::net::{
+    InterfaceFlags, MacAddr, NetError, NetInterface, arp, buf::ChecksumState, ethernet, icmp, route, tcp, udp,
+};
+
+/// The length of an IPv4 header without options
+pub const HEADER_LEN: usize = 20;
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+const DEFAULT_TTL: u8 = 64;
+/// The Don't Fragment flag, as we never fragment or reassemble packets
+const FLAG_DF: u16 = 0x4000;
+
+/// An interface address with its subnet prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Cidr {
+    pub addr: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    pub const fn new(addr: Ipv4Addr, prefix_len: u8) -> Self {
+        Self { addr, prefix_len }
+    }
+
+    pub const fn netmask(&self) -> Ipv4Addr {
+        match self.prefix_len {
+            0 => Ipv4Addr::UNSPECIFIED,
+            len => Ipv4Addr::from_bits(u32::MAX << (32 - len)),
+        }
+    }
+
+    /// Returns whether `ip` is in the subnet
+    pub const fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = self.netmask().to_bits();
+        ip.to_bits() & mask == self.addr.to_bits() & mask
+    }
+}
+
+impl fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidCidr;
+
+impl fmt::Display for InvalidCidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid address, expected a.b.c.d/prefix")
+    }
+}
+
+impl core::error::Error for InvalidCidr {}
+
+impl FromStr for Ipv4Cidr {
+    type Err = InvalidCidr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = s.split_once('/').ok_or(InvalidCidr)?;
+        let addr = addr.parse().map_err(|_| InvalidCidr)?;
+        let prefix_len = prefix_len.parse().map_err(|_| InvalidCidr)?;
+        if prefix_len > 32 {
+            return Err(InvalidCidr);
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Adds `data` to a ones' complement sum, returning the folded sum without complementing it
+pub fn partial_sum(data: &[u8], initial: u16) -> u16 {
+    let mut sum = initial as u64;
+    let (chunks, remainder) = data.as_chunks::<2>();
+    for chunk in chunks {
+        sum += u16::from_be_bytes(*chunk) as u64;
+    }
+    if let [last] = remainder {
+        sum += (*last as u64) << 8;
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Computes the Internet checksum of `data`
+pub fn checksum(data: &[u8]) -> u16 {
+    !partial_sum(data, 0)
+}
+
+/// Returns the sum of the TCP/UDP pseudo header, which seeds the checksum of the transport header
+pub fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: u16) -> u16 {
+    let mut header = [0; 12];
+    header[0..4].copy_from_slice(&src.octets());
+    header[4..8].copy_from_slice(&dst.octets());
+    header[9] = protocol;
+    header[10..12].copy_from_slice(&len.to_be_bytes());
+    partial_sum(&header, 0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Header {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+}
+
+impl Ipv4Header {
+    /// Parses and validates the header of a packet, returning it with the payload
+    pub fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+        if packet.len() < HEADER_LEN || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (packet[0] & 0x0F) as usize * 4;
+        let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        if header_len < HEADER_LEN || total_len < header_len || total_len > packet.len() {
+            return None;
+        }
+        if checksum(&packet[..header_len]) != 0 {
+            return None;
+        }
+        let header = Self {
+            src: Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).unwrap()),
+            dst: Ipv4Addr::from(<[u8; 4]>::try_from(&packet[16..20]).unwrap()),
+            protocol: packet[9],
+            ttl: packet[8],
+        };
+        Some((header, &packet[header_len..total_len]))
+    }
+
+    pub fn write(&self, buf: &mut Vec<u8>, payload_len: usize) {
+        static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+        let start = buf.len();
+        buf.push(0x45);
+        buf.push(0);
+        buf.extend_from_slice(&((HEADER_LEN + payload_len) as u16).to_be_bytes());
+        buf.extend_from_slice(&NEXT_ID.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+        buf.extend_from_slice(&FLAG_DF.to_be_bytes());
+        buf.push(self.ttl);
+        buf.push(self.protocol);
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&self.src.octets());
+        buf.extend_from_slice(&self.dst.octets());
+        let sum = checksum(&buf[start..]);
+        buf[start + 10..start + 12].copy_from_slice(&sum.to_be_bytes());
+    }
+}
+
+/// Returns the source address used for packets to `dst`
+pub fn source_addr(dst: Ipv4Addr) -> Option<Ipv4Addr> {
+    let (iface, _) = route::lookup(dst)?;
+    if iface.flags().contains(InterfaceFlags::LOOPBACK) {
+        Some(dst)
+    } else {
+        Some(iface.ipv4()?.addr)
+    }
+}
+
+/// Sends `payload` to `dst`
+pub fn send(dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    send_inner(dst, protocol, payload, ChecksumState::None)
+}
+
+/// Sends a TCP or UDP `segment` to `dst`, leaving its checksum to offload when possible
+///
+/// `csum_offset` is the offset of the checksum field in the transport header.
+pub fn send_transport(dst: Ipv4Addr, protocol: u8, mut segment: Vec<u8>, csum_offset: u16) -> Result<(), NetError> {
+    let src = source_addr(dst).ok_or(NetError::NoRoute)?;
+    let field = csum_offset as usize;
+    let pseudo = pseudo_header_sum(src, dst, protocol, segment.len() as u16);
+    segment[field..field + 2].copy_from_slice(&pseudo.to_be_bytes());
+    let csum = ChecksumState::Partial {
+        start: HEADER_LEN as u16,
+        offset: csum_offset,
+    };
+    send_inner(dst, protocol, &segment, csum)
+}
+
+/// Verifies the checksum of a received TCP or UDP segment, unless the device already did
+pub fn verify_transport(header: &Ipv4Header, segment: &[u8], csum: ChecksumState) -> bool {
+    if csum == ChecksumState::Unnecessary {
+        return true;
+    }
+    let pseudo = pseudo_header_sum(header.src, header.dst, header.protocol, segment.len() as u16);
+    !partial_sum(segment, pseudo) == 0
+}
+
+fn send_inner(dst: Ipv4Addr, protocol: u8, payload: &[u8], csum: ChecksumState) -> Result<(), NetError> {
+    let (iface, next_hop) = route::lookup(dst).ok_or(NetError::NoRoute)?;
+    let src = if iface.flags().contains(InterfaceFlags::LOOPBACK) {
+        dst
+    } else {
+        iface.ipv4().ok_or(NetError::NoRoute)?.addr
+    };
+
+    let mut packet = Vec::with_capacity(HEADER_LEN + payload.len());
+    Ipv4Header {
+        src,
+        dst,
+        protocol,
+        ttl: DEFAULT_TTL,
+    }
+    .write(&mut packet, payload.len());
+    packet.extend_from_slice(payload);
+
+    if iface.flags().contains(InterfaceFlags::LOOPBACK) {
+        ethernet::send_buf(&iface, MacAddr::ZERO, ethernet::ETHERTYPE_IPV4, &packet, csum)
+    } else {
+        arp::send_ipv4(iface, next_hop, packet, csum);
+        Ok(())
+    }
+}
+
+/// Handles a received IPv4 packet
+pub(super) fn handle(iface: &NetInterface, packet: &[u8], csum: ChecksumState) {
+    let Some((header, payload)) = Ipv4Header::parse(packet) else {
+        iface.stats().rx_drop();
+        return;
+    };
+
+    let for_us = iface.flags().contains(InterfaceFlags::LOOPBACK)
+        || header.dst.is_broadcast()
+        || iface.ipv4().is_some_and(|addr| addr.addr == header.dst);
+    if !for_us {
+        return;
+    }
+
+    match header.protocol {
+        PROTOCOL_ICMP => icmp::handle(&header, payload, csum),
+        PROTOCOL_TCP => tcp::handle(&header, payload, csum),
+        PROTOCOL_UDP => udp::handle(&header, payload, csum),
+        _ => {}
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv4_checksum() {
+        // A UDP packet header with a known checksum of 0xb861
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8, 0x00, 0x01, 0xc0, 0xa8,
+            0x00, 0xc7,
+        ];
+        assert_eq!(checksum(&header), 0);
+        let mut zeroed = header;
+        zeroed[10] = 0;
+        zeroed[11] = 0;
+        assert_eq!(checksum(&zeroed), 0xb861);
+    }
+
+    #[test]
+    fn ipv4_cidr() {
+        let cidr: Ipv4Cidr = "10.0.2.15/24".parse().unwrap();
+        assert_eq!(cidr.netmask(), Ipv4Addr::new(255, 255, 255, 0));
+        assert!(cidr.contains(Ipv4Addr::new(10, 0, 2, 2)));
+        assert!(!cidr.contains(Ipv4Addr::new(10, 0, 3, 2)));
+        assert!("10.0.2.15/33".parse::<Ipv4Cidr>().is_err());
+    }
+}
@@ -0,0 +1,82 @@
+//! The loopback interface
+
+use core::net::Ipv4Addr;
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use crate::{
+    net::{
+        self, InterfaceFlags, MacAddr, NetDevice, NetError,
+        buf::{ChecksumState, PacketBuf},
+        ipv4::Ipv4Cidr,
+        offload::Offloads,
+    },
+    sync::Mutex,
+};
+
+/// The number of frames that can be queued before transmissions are rejected
+const QUEUE_LEN: usize = 64;
+
+/// A device that receives every frame it transmits
+#[derive(Debug)]
+pub struct Loopback {
+    queue: Mutex<VecDeque<PacketBuf>>,
+}
+
+impl NetDevice for Loopback {
+    fn mac(&self) -> MacAddr {
+        MacAddr::ZERO
+    }
+
+    fn mtu(&self) -> usize {
+        // Frames are never put on a wire, but we still need them to fit `MAX_FRAME_SIZE`
+        net::MAX_FRAME_SIZE - 14
+    }
+
+    fn offloads(&self) -> Offloads {
+        // Packets never leave memory, so checksums are never needed
+        Offloads::TX_CSUM | Offloads::RX_CSUM
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        self.transmit_buf(&PacketBuf::new(frame.to_vec()))
+    }
+
+    fn transmit_buf(&self, buf: &PacketBuf) -> Result<(), NetError> {
+        let mut queue = self.queue.lock();
+        if queue.len() >= QUEUE_LEN {
+            return Err(NetError::Busy);
+        }
+        let csum = match buf.csum {
+            ChecksumState::Partial { .. } => ChecksumState::Unnecessary,
+            csum => csum,
+        };
+        queue.push_back(PacketBuf {
+            data: buf.data.clone(),
+            csum,
+            gso: None,
+            tx_done: None,
+        });
+        buf.complete_tx(Ok(()));
+        Ok(())
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> Option<usize> {
+        let frame = self.queue.lock().pop_front()?;
+        let len = frame.len().min(buf.len());
+        buf[..len].copy_from_slice(&frame.data[..len]);
+        Some(len)
+    }
+
+    fn receive_buf(&self) -> Option<PacketBuf> {
+        self.queue.lock().pop_front()
+    }
+}
+
+pub(super) fn init() {
+    let dev = Arc::new(Loopback {
+        queue: Mutex::new(VecDeque::new()),
+    });
+    let iface = net::register("lo", dev, InterfaceFlags::LOOPBACK);
+    iface.set_ipv4(Some(Ipv4Cidr::new(Ipv4Addr::LOCALHOST, 8)));
+}
@@ -0,0 +1,493 @@
+//! Network core
+//!
+//! Drivers implement [`NetDevice`] and register it with [`register`]. Frames are sent and
+//! received through the returned [`NetInterface`], which keeps the interface statistics.
+//! Received frames are handed to the protocol layers by [`poll`].
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{stats::snapshot::Counters, sync::RwLock};
+
+pub mod arp;
+pub mod buf;
+pub mod dns;
+pub mod ethernet;
+pub mod http;
+pub mod icmp;
+pub mod ipv4;
+pub mod loopback;
+pub mod offload;
+pub mod route;
+pub mod stats;
+pub mod tcp;
+pub mod tftp;
+pub mod udp;
+
+use buf::{ChecksumState, PacketBuf};
+use ipv4::Ipv4Cidr;
+use offload::Offloads;
+use stats::NetStats;
+
+/// The largest Ethernet frame (without the FCS) the core handles
+pub const MAX_FRAME_SIZE: usize = 1514;
+/// Less than a poll of the whole stack takes, which bounds waits by counting polls without a clock
+const MIN_POLL_NS: u64 = 1000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: Self = Self([0xFF; 6]);
+    pub const ZERO: Self = Self([0; 6]);
+
+    pub const fn is_broadcast(&self) -> bool {
+        matches!(self.0, [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF])
+    }
+
+    pub const fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+impl fmt::Debug for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// The device has no room to queue the frame
+    Busy,
+    /// The frame is larger than the MTU of the device
+    TooLarge,
+    /// The interface is down
+    Down,
+    /// The device failed to send the frame
+    Io,
+    /// No interface can reach the destination
+    NoRoute,
+    /// The packet headers don't match its offload metadata
+    Malformed,
+    Timeout,
+    /// The remote host refused the connection
+    Refused,
+    /// The remote host reset the connection
+    Reset,
+    AddrInUse,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Busy => "device busy",
+            Self::TooLarge => "frame too large",
+            Self::Down => "interface is down",
+            Self::Io => "I/O error",
+            Self::NoRoute => "no route to host",
+            Self::Malformed => "malformed packet",
+            Self::Timeout => "timed out",
+            Self::Refused => "connection refused",
+            Self::Reset => "connection reset by peer",
+            Self::AddrInUse => "address in use",
+        })
+    }
+}
+
+impl core::error::Error for NetError {}
+
+/// A network device that sends and receives Ethernet frames
+pub trait NetDevice: Send + Sync {
+    fn mac(&self) -> MacAddr;
+    fn mtu(&self) -> usize {
+        1500
+    }
+    /// Returns the checksum and segmentation work the device can do
+    fn offloads(&self) -> Offloads {
+        Offloads::empty()
+    }
+    /// Queues a frame for transmission
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError>;
+    /// Queues a packet for transmission
+    ///
+    /// The packet only carries a partial checksum or segmentation hints that the device
+    /// advertised in [`offloads`](Self::offloads). Once the packet is queued, the driver signals
+    /// its [`tx_done`](PacketBuf::tx_done) when the device is done with it, the interface does if
+    /// it couldn't be queued.
+    fn transmit_buf(&self, buf: &PacketBuf) -> Result<(), NetError> {
+        self.transmit(&buf.data)?;
+        // The frame was copied
+        buf.complete_tx(Ok(()));
+        Ok(())
+    }
+    /// Copies a received frame into `buf`, returning its length
+    fn receive(&self, buf: &mut [u8]) -> Option<usize>;
+    /// Returns a received packet, with the checksum state reported by the device
+    fn receive_buf(&self) -> Option<PacketBuf> {
+        let mut data = alloc::vec![0; MAX_FRAME_SIZE];
+        let len = self.receive(&mut data)?;
+        data.truncate(len);
+        Some(PacketBuf::new(data))
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct InterfaceFlags: u32 {
+        const UP = 1 << 0;
+        const LOOPBACK = 1 << 1;
+        const BROADCAST = 1 << 2;
+    }
+}
+
+impl fmt::Display for InterfaceFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (name, _) in self.iter_names() {
+            if !first {
+                f.write_str(",")?;
+            }
+            f.write_str(name)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+/// A registered network interface
+pub struct NetInterface {
+    name: &'static str,
+    dev: Arc<dyn NetDevice>,
+    flags: RwLock<InterfaceFlags>,
+    ipv4: RwLock<Option<Ipv4Cidr>>,
+    stats: NetStats,
+}
+
+impl fmt::Debug for NetInterface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NetInterface")
+            .field("name", &self.name)
+            .field("mac", &self.dev.mac())
+            .field("flags", &self.flags())
+            .field("ipv4", &self.ipv4())
+            .finish()
+    }
+}
+
+impl NetInterface {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn mac(&self) -> MacAddr {
+        self.dev.mac()
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.dev.mtu()
+    }
+
+    pub fn flags(&self) -> InterfaceFlags {
+        *self.flags.read()
+    }
+
+    pub fn set_up(&self, up: bool) {
+        self.flags.write().set(InterfaceFlags::UP, up);
+    }
+
+    pub fn offloads(&self) -> Offloads {
+        self.dev.offloads()
+    }
+
+    pub fn ipv4(&self) -> Option<Ipv4Cidr> {
+        *self.ipv4.read()
+    }
+
+    pub fn set_ipv4(&self, addr: Option<Ipv4Cidr>) {
+        *self.ipv4.write() = addr;
+    }
+
+    /// Returns the statistics of the interface, which drivers use to report errors
+    pub fn stats(&self) -> &NetStats {
+        &self.stats
+    }
+
+    /// Sends a frame, counting it in the interface statistics
+    pub fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        if !self.flags().contains(InterfaceFlags::UP) {
+            self.stats.tx_drop();
+            return Err(NetError::Down);
+        }
+        // The MTU excludes the 14 byte Ethernet header
+        if frame.len() > self.mtu() + 14 {
+            self.stats.tx_drop();
+            return Err(NetError::TooLarge);
+        }
+        match self.dev.transmit(frame) {
+            Ok(()) => {
+                self.stats.tx(frame.len());
+                Ok(())
+            }
+            Err(err) => {
+                self.stats.tx_drop();
+                Err(err)
+            }
+        }
+    }
+
+    /// Sends a packet, doing any checksum or segmentation work the device can't do in software
+    ///
+    /// The [`tx_done`](PacketBuf::tx_done) of a packet segmented in software is signalled once
+    /// all of the segments are queued.
+    pub fn transmit_buf(&self, buf: PacketBuf) -> Result<(), NetError> {
+        let tx_done = buf.tx_done.clone();
+        let result = self.transmit_packet(buf);
+        if let (Err(err), Some(done)) = (result, tx_done) {
+            done.complete(Err(err));
+        }
+        result
+    }
+
+    fn transmit_packet(&self, mut buf: PacketBuf) -> Result<(), NetError> {
+        let offloads = self.offloads();
+        if let Some(gso) = buf.gso
+            && !offloads.supports_gso(gso.kind)
+        {
+            for segment in offload::segment(&buf)? {
+                self.transmit_buf(segment)?;
+            }
+            buf.complete_tx(Ok(()));
+            return Ok(());
+        }
+        if matches!(buf.csum, ChecksumState::Partial { .. }) && !offloads.contains(Offloads::TX_CSUM) {
+            offload::checksum(&mut buf)?;
+        }
+
+        if !self.flags().contains(InterfaceFlags::UP) {
+            self.stats.tx_drop();
+            return Err(NetError::Down);
+        }
+        // The device splits GSO packets itself, so only the segments have to fit the MTU
+        if buf.gso.is_none() && buf.len() > self.mtu() + 14 {
+            self.stats.tx_drop();
+            return Err(NetError::TooLarge);
+        }
+        match self.dev.transmit_buf(&buf) {
+            Ok(()) => {
+                self.stats.tx(buf.len());
+                Ok(())
+            }
+            Err(err) => {
+                self.stats.tx_drop();
+                Err(err)
+            }
+        }
+    }
+
+    /// Polls the device for a received frame, counting it in the interface statistics
+    pub fn receive(&self, buf: &mut [u8]) -> Option<usize> {
+        let len = self.dev.receive(buf)?;
+        self.count_rx(&buf[..len]).then_some(len)
+    }
+
+    /// Polls the device for a received packet, counting it in the interface statistics
+    pub fn receive_buf(&self) -> Option<PacketBuf> {
+        let buf = self.dev.receive_buf()?;
+        self.count_rx(&buf.data).then_some(buf)
+    }
+
+    /// Counts a received frame, returning whether it should be processed
+    fn count_rx(&self, frame: &[u8]) -> bool {
+        if !self.flags().contains(InterfaceFlags::UP) {
+            self.stats.rx_drop();
+            return false;
+        }
+        self.stats.rx(frame.len());
+        if frame.len() >= 6 && MacAddr(frame[..6].try_into().unwrap()).is_multicast() {
+            self.stats.rx_multicast();
+        }
+        true
+    }
+}
+
+static INTERFACES: RwLock<Vec<Arc<NetInterface>>> = RwLock::new(Vec::new());
+
+/// Registers a network device under the given name, with the interface initially up
+pub fn register(name: &'static str, dev: Arc<dyn NetDevice>, flags: InterfaceFlags) -> Arc<NetInterface> {
+    let iface = Arc::new(NetInterface {
+        name,
+        dev,
+        flags: RwLock::new(flags | InterfaceFlags::UP),
+        ipv4: RwLock::new(None),
+        stats: NetStats::new(),
+    });
+    let mut interfaces = INTERFACES.write();
+    if interfaces.is_empty() {
+        crate::stats::register("net", dump_stats);
+        crate::stats::snapshot::register("net", counters);
+    }
+    interfaces.push(iface.clone());
+    iface
+}
+
+/// Removes a network interface, returning it if it was registered
+pub fn unregister(name: &str) -> Option<Arc<NetInterface>> {
+    let mut interfaces = INTERFACES.write();
+    let idx = interfaces.iter().position(|iface| iface.name == name)?;
+    Some(interfaces.remove(idx))
+}
+
+/// Returns the interface with the given name
+pub fn interface(name: &str) -> Option<Arc<NetInterface>> {
+    INTERFACES.read().iter().find(|iface| iface.name == name).cloned()
+}
+
+/// Returns all registered interfaces
+pub fn interfaces() -> Vec<Arc<NetInterface>> {
+    INTERFACES.read().clone()
+}
+
+/// Writes the configuration and counters of an interface, like `ifconfig`
+pub fn write_interface(out: &mut dyn fmt::Write, iface: &NetInterface) -> fmt::Result {
+    writeln!(out, "{}: flags=<{}> mtu {}", iface.name, iface.flags(), iface.mtu())?;
+    if let Some(addr) = iface.ipv4() {
+        writeln!(out, "    inet {} netmask {}", addr.addr, addr.netmask())?;
+    }
+    writeln!(out, "    ether {}", iface.mac())?;
+    if !iface.offloads().is_empty() {
+        writeln!(out, "    offloads {}", iface.offloads())?;
+    }
+    for line in alloc::format!("{}", iface.stats.snapshot()).lines() {
+        writeln!(out, "    {}", line)?;
+    }
+    Ok(())
+}
+
+fn counters(counters: &mut Counters<'_>) {
+    for iface in interfaces() {
+        let stats = iface.stats.snapshot();
+        let name = iface.name;
+        counters.counter(format_args!("{}.rx_packets", name), stats.rx_packets);
+        counters.counter(format_args!("{}.rx_bytes", name), stats.rx_bytes);
+        counters.counter(format_args!("{}.rx_dropped", name), stats.rx_dropped);
+        counters.counter(format_args!("{}.rx_errors", name), stats.rx_errors);
+        counters.counter(format_args!("{}.tx_packets", name), stats.tx_packets);
+        counters.counter(format_args!("{}.tx_bytes", name), stats.tx_bytes);
+        counters.counter(format_args!("{}.tx_dropped", name), stats.tx_dropped);
+        counters.counter(format_args!("{}.tx_errors", name), stats.tx_errors);
+    }
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    for iface in interfaces() {
+        write_interface(out, &iface)?;
+    }
+    Ok(())
+}
+
+/// Receives pending frames on every interface and passes them to the protocol layers
+pub fn poll() {
+    for iface in interfaces() {
+        while let Some(buf) = iface.receive_buf() {
+            let Some((header, payload)) = ethernet::EthernetHeader::parse(&buf.data) else {
+                iface.stats().rx_error(stats::RxError::Length);
+                continue;
+            };
+            match header.ethertype {
+                ethernet::ETHERTYPE_IPV4 => ipv4::handle(&iface, payload, buf.csum),
+                ethernet::ETHERTYPE_ARP => arp::handle(&iface, payload),
+                _ => {}
+            }
+        }
+    }
+    tcp::poll();
+}
+
+/// Drives the network stack until `f` returns a value, or `timeout_ns` passes
+///
+/// This is how blocking network operations wait, until the kernel can sleep on events. Without a
+/// clock source the time stands still, so the wait ends after at most `timeout_ns` worth of
+/// [`MIN_POLL_NS`] polls instead.
+pub fn poll_until<T>(timeout_ns: u64, mut f: impl FnMut() -> Option<T>) -> Option<T> {
+    let clocked = crate::time::current_clocksource().is_some();
+    let deadline = crate::time::monotonic_ns().saturating_add(timeout_ns);
+    let mut polls_left = timeout_ns / MIN_POLL_NS;
+    loop {
+        poll();
+        #[cfg(target_arch = "x86_64")]
+        crate::arch::x86_64::watchdog::touch();
+        if let Some(value) = f() {
+            return Some(value);
+        }
+        let expired = if clocked {
+            crate::time::monotonic_ns() >= deadline
+        } else {
+            polls_left == 0
+        };
+        if expired {
+            return None;
+        }
+        polls_left = polls_left.saturating_sub(1);
+        core::hint::spin_loop();
+    }
+}
+
+/// Returns a port from the dynamic range for an outgoing connection
+pub fn ephemeral_port() -> u16 {
+    const FIRST: u16 = 49152;
+    static NEXT: AtomicU16 = AtomicU16::new(FIRST);
+    let port = NEXT.fetch_add(1, Ordering::Relaxed);
+    if port == u16::MAX {
+        NEXT.store(FIRST, Ordering::Relaxed);
+    }
+    port.max(FIRST)
+}
+
+/// Registers the built-in interfaces, and configures the first Ethernet interface
+///
+/// Without DHCP, the address comes from `net.ip=<addr>/<prefix>` on the command line, and the
+/// default route from `net.gateway=<addr>`. The drivers have to be initialized first.
+pub fn init(cmdline: crate::boot::Cmdline) {
+    loopback::init();
+
+    let Some(iface) = interfaces()
+        .into_iter()
+        .find(|iface| !iface.flags().contains(InterfaceFlags::LOOPBACK))
+    else {
+        return;
+    };
+    if let Some(addr) = cmdline.get("net.ip") {
+        match addr.parse::<Ipv4Cidr>() {
+            Ok(addr) => {
+                iface.set_ipv4(Some(addr));
+                crate::kprintln!(Info, "net: {} has address {}", iface.name(), addr);
+            }
+            Err(_) => crate::kprintln!(Warn, "net: ignoring invalid address '{}'", addr),
+        }
+    }
+    if let Some(gateway) = cmdline.get("net.gateway") {
+        let result = gateway
+            .parse::<core::net::Ipv4Addr>()
+            .map_err(|_| "invalid address")
+            .and_then(|gateway| {
+                let default = Ipv4Cidr::new(core::net::Ipv4Addr::UNSPECIFIED, 0);
+                route::add(default, Some(gateway), Some(iface.name())).map_err(|_| "can't add the route")
+            });
+        match result {
+            Ok(route) => crate::kprintln!(Info, "net: route {}", route),
+            Err(err) => crate::kprintln!(Warn, "net: gateway '{}': {}", gateway, err),
+        }
+    }
+}
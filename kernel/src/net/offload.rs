@@ -0,0 +1,267 @@
+//! Checksum and segmentation offloads
+//!
+//! Devices advertise which offloads they support. Anything a device can't do is done in software
+//! by the net core right before the packet is handed to the driver.
+
+use core::{fmt, net::Ipv4Addr};
+
+use alloc::vec::Vec;
+
+use crate::net::{
+    NetError,
+    buf::{ChecksumState, GsoInfo, GsoKind, PacketBuf},
+    ethernet,
+    ipv4::{self, PROTOCOL_TCP},
+};
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Offloads: u32 {
+        /// The device computes TCP/UDP checksums over IPv4 when transmitting
+        const TX_CSUM = 1 << 0;
+        /// The device verifies TCP/UDP checksums of received packets
+        const RX_CSUM = 1 << 1;
+        /// The device segments large TCP over IPv4 packets
+        const TSO4 = 1 << 2;
+    }
+}
+
+impl Offloads {
+    /// Returns whether the device can segment packets of the given kind
+    pub fn supports_gso(&self, kind: GsoKind) -> bool {
+        match kind {
+            // Segmenting without also computing checksums isn't something hardware does
+            GsoKind::TcpV4 => self.contains(Self::TSO4 | Self::TX_CSUM),
+        }
+    }
+
+    /// Translates virtio-net feature bits into offloads
+    pub fn from_virtio_net_features(features: u64) -> Self {
+        let mut offloads = Self::empty();
+        offloads.set(Self::TX_CSUM, features & virtio::F_CSUM != 0);
+        offloads.set(Self::RX_CSUM, features & virtio::F_GUEST_CSUM != 0);
+        offloads.set(Self::TSO4, features & virtio::F_HOST_TSO4 != 0);
+        offloads
+    }
+}
+
+impl fmt::Display for Offloads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (name, _) in self.iter_names() {
+            if !first {
+                f.write_str(",")?;
+            }
+            f.write_str(name)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+/// Completes a partial checksum in software
+pub fn checksum(buf: &mut PacketBuf) -> Result<(), NetError> {
+    let ChecksumState::Partial { start, offset } = buf.csum else {
+        return Ok(());
+    };
+    let (start, field) = (start as usize, start as usize + offset as usize);
+    if field + 2 > buf.data.len() {
+        return Err(NetError::Malformed);
+    }
+    // The field is seeded with the pseudo header sum, so it's included in the sum
+    let sum = ipv4::checksum(&buf.data[start..]);
+    buf.data[field..field + 2].copy_from_slice(&sum.to_be_bytes());
+    buf.csum = ChecksumState::None;
+    Ok(())
+}
+
+/// Splits a large packet into segments that fit the MTU
+///
+/// The segments are left with partial checksums, so the device can still compute those.
+pub fn segment(buf: &PacketBuf) -> Result<Vec<PacketBuf>, NetError> {
+    let Some(gso) = buf.gso else {
+        return Ok(alloc::vec![buf.clone()]);
+    };
+    match gso.kind {
+        GsoKind::TcpV4 => segment_tcp_v4(buf, gso),
+    }
+}
+
+fn segment_tcp_v4(buf: &PacketBuf, gso: GsoInfo) -> Result<Vec<PacketBuf>, NetError> {
+    const TCP_FIN: u8 = 0x01;
+    const TCP_PSH: u8 = 0x08;
+
+    let data = &buf.data;
+    let ip = ethernet::HEADER_LEN;
+    let header_len = gso.header_len as usize;
+    if header_len > data.len() || data.len() < ip + ipv4::HEADER_LEN || gso.segment_size == 0 {
+        return Err(NetError::Malformed);
+    }
+    let ihl = (data[ip] & 0x0F) as usize * 4;
+    let tcp = ip + ihl;
+    if data[ip + 9] != PROTOCOL_TCP || tcp + 20 > header_len {
+        return Err(NetError::Malformed);
+    }
+
+    let src = Ipv4Addr::from(<[u8; 4]>::try_from(&data[ip + 12..ip + 16]).unwrap());
+    let dst = Ipv4Addr::from(<[u8; 4]>::try_from(&data[ip + 16..ip + 20]).unwrap());
+    let id = u16::from_be_bytes([data[ip + 4], data[ip + 5]]);
+    let seq = u32::from_be_bytes(data[tcp + 4..tcp + 8].try_into().unwrap());
+    let flags = data[tcp + 13];
+
+    let payload = &data[header_len..];
+    let count = payload.len().div_ceil(gso.segment_size as usize);
+    let mut segments = Vec::with_capacity(count);
+    for (idx, chunk) in payload.chunks(gso.segment_size as usize).enumerate() {
+        let mut seg = Vec::with_capacity(header_len + chunk.len());
+        seg.extend_from_slice(&data[..header_len]);
+        seg.extend_from_slice(chunk);
+
+        let ip_len = (header_len - ip + chunk.len()) as u16;
+        seg[ip + 2..ip + 4].copy_from_slice(&ip_len.to_be_bytes());
+        seg[ip + 4..ip + 6].copy_from_slice(&id.wrapping_add(idx as u16).to_be_bytes());
+        seg[ip + 10..ip + 12].copy_from_slice(&[0, 0]);
+        let ip_sum = ipv4::checksum(&seg[ip..tcp]);
+        seg[ip + 10..ip + 12].copy_from_slice(&ip_sum.to_be_bytes());
+
+        let seg_seq = seq.wrapping_add((idx * gso.segment_size as usize) as u32);
+        seg[tcp + 4..tcp + 8].copy_from_slice(&seg_seq.to_be_bytes());
+        // Only the last segment finishes the stream or pushes the data
+        if idx + 1 != count {
+            seg[tcp + 13] = flags & !(TCP_FIN | TCP_PSH);
+        }
+        let tcp_len = (seg.len() - tcp) as u16;
+        let pseudo = ipv4::pseudo_header_sum(src, dst, PROTOCOL_TCP, tcp_len);
+        seg[tcp + 16..tcp + 18].copy_from_slice(&pseudo.to_be_bytes());
+
+        segments.push(PacketBuf {
+            data: seg,
+            csum: ChecksumState::Partial {
+                start: tcp as u16,
+                offset: 16,
+            },
+            gso: None,
+            tx_done: None,
+        });
+    }
+    Ok(segments)
+}
+
+/// The offload related parts of virtio-net, shared with the driver
+pub mod virtio {
+    use crate::net::buf::{ChecksumState, GsoKind, PacketBuf};
+
+    pub const F_CSUM: u64 = 1 << 0;
+    pub const F_GUEST_CSUM: u64 = 1 << 1;
+    pub const F_HOST_TSO4: u64 = 1 << 11;
+
+    pub const HDR_F_NEEDS_CSUM: u8 = 1;
+    pub const HDR_F_DATA_VALID: u8 = 2;
+    pub const HDR_GSO_NONE: u8 = 0;
+    pub const HDR_GSO_TCPV4: u8 = 1;
+
+    /// The header that precedes every packet in the virtio-net queues
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct VirtioNetHdr {
+        pub flags: u8,
+        pub gso_type: u8,
+        pub hdr_len: u16,
+        pub gso_size: u16,
+        pub csum_start: u16,
+        pub csum_offset: u16,
+        pub num_buffers: u16,
+    }
+
+    impl VirtioNetHdr {
+        /// Builds the header describing the work the device has to do for a packet
+        pub fn for_tx(buf: &PacketBuf) -> Self {
+            let mut hdr = Self::default();
+            if let ChecksumState::Partial { start, offset } = buf.csum {
+                hdr.flags = HDR_F_NEEDS_CSUM;
+                hdr.csum_start = start;
+                hdr.csum_offset = offset;
+            }
+            match buf.gso {
+                Some(gso) => {
+                    hdr.gso_type = match gso.kind {
+                        GsoKind::TcpV4 => HDR_GSO_TCPV4,
+                    };
+                    hdr.hdr_len = gso.header_len;
+                    hdr.gso_size = gso.segment_size;
+                }
+                None => hdr.gso_type = HDR_GSO_NONE,
+            }
+            hdr
+        }
+
+        /// Returns the checksum state of a received packet
+        pub fn rx_csum(&self) -> ChecksumState {
+            // A partial checksum on receive means the packet came from another guest on the
+            // same host, and was never on a wire that could corrupt it
+            if self.flags & (HDR_F_DATA_VALID | HDR_F_NEEDS_CSUM) != 0 {
+                ChecksumState::Unnecessary
+            } else {
+                ChecksumState::None
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    /// Builds a TCP over IPv4 frame with the given payload, and the checksum left to offload
+    fn tcp_frame(payload_len: usize) -> PacketBuf {
+        let mut data = alloc::vec![0u8; ethernet::HEADER_LEN + 40 + payload_len];
+        let ip = ethernet::HEADER_LEN;
+        data[ip] = 0x45;
+        data[ip + 9] = PROTOCOL_TCP;
+        data[ip + 12..ip + 16].copy_from_slice(&[10, 0, 2, 15]);
+        data[ip + 16..ip + 20].copy_from_slice(&[10, 0, 2, 2]);
+        let tcp = ip + 20;
+        data[tcp + 4..tcp + 8].copy_from_slice(&1000u32.to_be_bytes());
+        data[tcp + 12] = 5 << 4;
+        data[tcp + 13] = 0x18 | 0x01;
+        for (idx, byte) in data[tcp + 20..].iter_mut().enumerate() {
+            *byte = idx as u8;
+        }
+        PacketBuf {
+            data,
+            csum: ChecksumState::Partial {
+                start: tcp as u16,
+                offset: 16,
+            },
+            gso: Some(GsoInfo {
+                kind: GsoKind::TcpV4,
+                segment_size: 100,
+                header_len: (tcp + 20) as u16,
+            }),
+            tx_done: None,
+        }
+    }
+
+    #[test]
+    fn tso_software_segmentation() {
+        let segments = segment(&tcp_frame(250)).unwrap();
+        assert_eq!(segments.len(), 3);
+
+        let tcp = ethernet::HEADER_LEN + 20;
+        for (idx, seg) in segments.into_iter().enumerate() {
+            let (header, payload) = ipv4::Ipv4Header::parse(&seg.data[ethernet::HEADER_LEN..]).unwrap();
+            assert_eq!(header.protocol, PROTOCOL_TCP);
+            assert_eq!(payload.len(), 20 + if idx == 2 { 50 } else { 100 });
+
+            let seq = u32::from_be_bytes(seg.data[tcp + 4..tcp + 8].try_into().unwrap());
+            assert_eq!(seq, 1000 + idx as u32 * 100);
+            let fin = seg.data[tcp + 13] & 0x01 != 0;
+            assert_eq!(fin, idx == 2);
+
+            let pseudo = ipv4::pseudo_header_sum(header.src, header.dst, PROTOCOL_TCP, payload.len() as u16);
+            let mut seg = seg;
+            checksum(&mut seg).unwrap();
+            assert_eq!(!ipv4::partial_sum(&seg.data[tcp..], pseudo), 0);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! IPv4 routing table
+//!
+//! Every interface with an address has an implicit route to its subnet. Static routes are
+//! added on top of those, for example a default route through a gateway when DHCP isn't available.
+
+use core::{fmt, net::Ipv4Addr};
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    net::{self, InterfaceFlags, NetInterface, ipv4::Ipv4Cidr},
+    sync::RwLock,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub dest: Ipv4Cidr,
+    /// The next hop, or `None` if the destination is directly reachable
+    pub gateway: Option<Ipv4Addr>,
+    pub iface: &'static str,
+    /// Whether the route was added by hand, rather than derived from an interface address
+    pub is_static: bool,
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.dest.prefix_len == 0 {
+            f.write_str("default")?;
+        } else {
+            write!(f, "{}", self.dest)?;
+        }
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", gateway)?;
+        }
+        write!(f, " dev {}", self.iface)?;
+        if !self.is_static {
+            f.write_str(" scope link")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteError {
+    NoSuchInterface,
+    /// The gateway isn't on a subnet of any interface
+    GatewayUnreachable,
+    /// A static route to the destination already exists
+    Exists,
+    NotFound,
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NoSuchInterface => "no such interface",
+            Self::GatewayUnreachable => "gateway is not on a connected network",
+            Self::Exists => "route already exists",
+            Self::NotFound => "no such route",
+        })
+    }
+}
+
+impl core::error::Error for RouteError {}
+
+static ROUTES: RwLock<Vec<Route>> = RwLock::new(Vec::new());
+
+/// Adds a static route
+///
+/// If no interface is given, the interface whose subnet contains the gateway is used.
+pub fn add(dest: Ipv4Cidr, gateway: Option<Ipv4Addr>, iface: Option<&str>) -> Result<Route, RouteError> {
+    // Normalize the destination, so lookups and removal don't depend on the host bits
+    let dest = Ipv4Cidr::new(
+        Ipv4Addr::from_bits(dest.addr.to_bits() & dest.netmask().to_bits()),
+        dest.prefix_len,
+    );
+    let iface = match (iface, gateway) {
+        (Some(name), _) => net::interface(name).ok_or(RouteError::NoSuchInterface)?,
+        (None, Some(gateway)) => net::interfaces()
+            .into_iter()
+            .find(|iface| iface.ipv4().is_some_and(|addr| addr.contains(gateway)))
+            .ok_or(RouteError::GatewayUnreachable)?,
+        (None, None) => return Err(RouteError::NoSuchInterface),
+    };
+
+    let route = Route {
+        dest,
+        gateway,
+        iface: iface.name(),
+        is_static: true,
+    };
+    let mut routes = ROUTES.write();
+    if routes.iter().any(|route| route.dest == dest) {
+        return Err(RouteError::Exists);
+    }
+    routes.push(route);
+    Ok(route)
+}
+
+/// Removes the static route to `dest`
+pub fn remove(dest: Ipv4Cidr) -> Result<Route, RouteError> {
+    let mut routes = ROUTES.write();
+    let idx = routes
+        .iter()
+        .position(|route| route.dest.prefix_len == dest.prefix_len && route.dest.contains(dest.addr))
+        .ok_or(RouteError::NotFound)?;
+    Ok(routes.remove(idx))
+}
+
+/// Removes every static route, returning how many were removed
+pub fn flush() -> usize {
+    let mut routes = ROUTES.write();
+    let count = routes.len();
+    routes.clear();
+    count
+}
+
+/// Returns the routes derived from interface addresses, followed by the static routes
+pub fn routes() -> Vec<Route> {
+    let mut routes: Vec<Route> = net::interfaces()
+        .iter()
+        .filter_map(|iface| {
+            let addr = iface.ipv4()?;
+            Some(Route {
+                dest: Ipv4Cidr::new(
+                    Ipv4Addr::from_bits(addr.addr.to_bits() & addr.netmask().to_bits()),
+                    addr.prefix_len,
+                ),
+                gateway: None,
+                iface: iface.name(),
+                is_static: false,
+            })
+        })
+        .collect();
+    routes.extend(ROUTES.read().iter().copied());
+    routes
+}
+
+/// Picks the interface and next hop for a destination, preferring the longest matching prefix
+pub fn lookup(dst: Ipv4Addr) -> Option<(Arc<NetInterface>, Ipv4Addr)> {
+    let interfaces = net::interfaces();
+    let is_up = |iface: &NetInterface| iface.flags().contains(InterfaceFlags::UP);
+
+    // Traffic to one of our own addresses never leaves the machine
+    if dst.is_loopback()
+        || interfaces
+            .iter()
+            .any(|iface| iface.ipv4().is_some_and(|addr| addr.addr == dst))
+    {
+        let lo = interfaces
+            .iter()
+            .find(|iface| is_up(iface) && iface.flags().contains(InterfaceFlags::LOOPBACK))?;
+        return Some((lo.clone(), dst));
+    }
+
+    // Static routes come after connected ones, and `max_by_key` picks the last of equal prefixes
+    let route = routes()
+        .into_iter()
+        .filter(|route| route.dest.contains(dst))
+        .filter(|route| {
+            interfaces
+                .iter()
+                .any(|iface| iface.name() == route.iface && is_up(iface))
+        })
+        .max_by_key(|route| route.dest.prefix_len)?;
+    let iface = interfaces.into_iter().find(|iface| iface.name() == route.iface)?;
+    Some((iface, route.gateway.unwrap_or(dst)))
+}
@@ -0,0 +1,223 @@
+//! Per-interface traffic and error counters
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Classes of receive errors reported by drivers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RxError {
+    /// The frame failed its CRC check
+    Crc,
+    /// The frame was too short or too long
+    Length,
+    /// The receive FIFO or ring overflowed
+    Overrun,
+    /// The frame was malformed in some other way
+    Frame,
+}
+
+/// Classes of transmit errors reported by drivers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxError {
+    /// The transmission was aborted, for example due to excessive collisions
+    Aborted,
+    /// The link was down
+    Carrier,
+    /// The transmit FIFO underran
+    Fifo,
+    /// The device did not complete the transmission in time
+    Timeout,
+}
+
+/// Counters for a network interface
+///
+/// The net core counts packets passing through an interface, and drivers report drops and errors
+/// that only they can see.
+#[derive(Debug)]
+pub struct NetStats {
+    pub rx_packets: AtomicU64,
+    pub rx_bytes: AtomicU64,
+    pub rx_dropped: AtomicU64,
+    pub rx_crc_errors: AtomicU64,
+    pub rx_length_errors: AtomicU64,
+    pub rx_overrun_errors: AtomicU64,
+    pub rx_frame_errors: AtomicU64,
+    pub rx_multicast: AtomicU64,
+
+    pub tx_packets: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    pub tx_dropped: AtomicU64,
+    pub tx_aborted_errors: AtomicU64,
+    pub tx_carrier_errors: AtomicU64,
+    pub tx_fifo_errors: AtomicU64,
+    pub tx_timeout_errors: AtomicU64,
+    pub collisions: AtomicU64,
+}
+
+/// A copy of the counters of an interface at one point in time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetStatsSnapshot {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_dropped: u64,
+    pub rx_errors: u64,
+    pub rx_crc_errors: u64,
+    pub rx_length_errors: u64,
+    pub rx_overrun_errors: u64,
+    pub rx_frame_errors: u64,
+    pub rx_multicast: u64,
+
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    pub tx_dropped: u64,
+    pub tx_errors: u64,
+    pub tx_aborted_errors: u64,
+    pub tx_carrier_errors: u64,
+    pub tx_fifo_errors: u64,
+    pub tx_timeout_errors: u64,
+    pub collisions: u64,
+}
+
+impl NetStats {
+    pub const fn new() -> Self {
+        Self {
+            rx_packets: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+            rx_dropped: AtomicU64::new(0),
+            rx_crc_errors: AtomicU64::new(0),
+            rx_length_errors: AtomicU64::new(0),
+            rx_overrun_errors: AtomicU64::new(0),
+            rx_frame_errors: AtomicU64::new(0),
+            rx_multicast: AtomicU64::new(0),
+            tx_packets: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            tx_dropped: AtomicU64::new(0),
+            tx_aborted_errors: AtomicU64::new(0),
+            tx_carrier_errors: AtomicU64::new(0),
+            tx_fifo_errors: AtomicU64::new(0),
+            tx_timeout_errors: AtomicU64::new(0),
+            collisions: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a received packet
+    pub fn rx(&self, bytes: usize) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a transmitted packet
+    pub fn tx(&self, bytes: usize) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn rx_multicast(&self) {
+        self.rx_multicast.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a received packet that was dropped, for example because no buffer was available
+    pub fn rx_drop(&self) {
+        self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tx_drop(&self) {
+        self.tx_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rx_error(&self, err: RxError) {
+        let counter = match err {
+            RxError::Crc => &self.rx_crc_errors,
+            RxError::Length => &self.rx_length_errors,
+            RxError::Overrun => &self.rx_overrun_errors,
+            RxError::Frame => &self.rx_frame_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tx_error(&self, err: TxError) {
+        let counter = match err {
+            TxError::Aborted => &self.tx_aborted_errors,
+            TxError::Carrier => &self.tx_carrier_errors,
+            TxError::Fifo => &self.tx_fifo_errors,
+            TxError::Timeout => &self.tx_timeout_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn collision(&self) {
+        self.collisions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> NetStatsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut snapshot = NetStatsSnapshot {
+            rx_packets: load(&self.rx_packets),
+            rx_bytes: load(&self.rx_bytes),
+            rx_dropped: load(&self.rx_dropped),
+            rx_errors: 0,
+            rx_crc_errors: load(&self.rx_crc_errors),
+            rx_length_errors: load(&self.rx_length_errors),
+            rx_overrun_errors: load(&self.rx_overrun_errors),
+            rx_frame_errors: load(&self.rx_frame_errors),
+            rx_multicast: load(&self.rx_multicast),
+            tx_packets: load(&self.tx_packets),
+            tx_bytes: load(&self.tx_bytes),
+            tx_dropped: load(&self.tx_dropped),
+            tx_errors: 0,
+            tx_aborted_errors: load(&self.tx_aborted_errors),
+            tx_carrier_errors: load(&self.tx_carrier_errors),
+            tx_fifo_errors: load(&self.tx_fifo_errors),
+            tx_timeout_errors: load(&self.tx_timeout_errors),
+            collisions: load(&self.collisions),
+        };
+        snapshot.rx_errors =
+            snapshot.rx_crc_errors + snapshot.rx_length_errors + snapshot.rx_overrun_errors + snapshot.rx_frame_errors;
+        snapshot.tx_errors = snapshot.tx_aborted_errors
+            + snapshot.tx_carrier_errors
+            + snapshot.tx_fifo_errors
+            + snapshot.tx_timeout_errors;
+        snapshot
+    }
+}
+
+impl Default for NetStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for NetStatsSnapshot {
+    /// Formats the counters like `ifconfig`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "RX packets {} bytes {} multicast {}",
+            self.rx_packets, self.rx_bytes, self.rx_multicast
+        )?;
+        writeln!(
+            f,
+            "RX errors {} dropped {} crc {} length {} overrun {} frame {}",
+            self.rx_errors,
+            self.rx_dropped,
+            self.rx_crc_errors,
+            self.rx_length_errors,
+            self.rx_overrun_errors,
+            self.rx_frame_errors
+        )?;
+        writeln!(f, "TX packets {} bytes {}", self.tx_packets, self.tx_bytes)?;
+        writeln!(
+            f,
+            "TX errors {} dropped {} aborted {} carrier {} fifo {} timeout {} collisions {}",
+            self.tx_errors,
+            self.tx_dropped,
+            self.tx_aborted_errors,
+            self.tx_carrier_errors,
+            self.tx_fifo_errors,
+            self.tx_timeout_errors,
+            self.collisions
+        )
+    }
+}
@@ -0,0 +1,515 @@
+//! Transmission Control Protocol
+//!
+//! A minimal client side implementation: active open, in-order receive, go-back-N
+//! retransmission and orderly close. There are no listening sockets yet.
+//!
+//! Every connection with data in flight has a [`Timer`] pending for its retransmission. Timers
+//! can't send, so an expired one only flags that a connection is due, and [`poll`] retransmits.
+
+use core::{
+    net::Ipv4Addr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+
+use crate::{
+    net::{
+        self, NetError,
+        buf::ChecksumState,
+        ipv4::{self, Ipv4Header, PROTOCOL_TCP},
+    },
+    sync::Mutex,
+    time::{self, Timer},
+};
+
+const HEADER_LEN: usize = 20;
+
+const FLAG_FIN: u8 = 0x01;
+const FLAG_SYN: u8 = 0x02;
+const FLAG_RST: u8 = 0x04;
+const FLAG_PSH: u8 = 0x08;
+const FLAG_ACK: u8 = 0x10;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// The MSS assumed when the peer doesn't send one
+const DEFAULT_MSS: u16 = 536;
+/// The receive window we advertise
+const RECV_WINDOW: usize = 32 * 1024;
+const INITIAL_RTO_NS: u64 = 1_000_000_000;
+const MAX_RTO_NS: u64 = 16_000_000_000;
+/// The number of retransmissions before a connection is given up on
+const MAX_RETRIES: u8 = 6;
+
+/// Returns whether `a` comes before `b` in sequence space
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpState {
+    SynSent,
+    Established,
+    /// We sent a FIN, and wait for it to be acknowledged
+    FinWait1,
+    /// Our FIN was acknowledged, and we wait for the peer to close
+    FinWait2,
+    /// The peer closed, and we can still send
+    CloseWait,
+    /// Both sides sent a FIN at the same time
+    Closing,
+    /// The peer closed first, and we wait for our FIN to be acknowledged
+    LastAck,
+    Closed,
+}
+
+#[derive(Debug)]
+struct Tcb {
+    state: TcpState,
+    local_port: u16,
+    remote_addr: Ipv4Addr,
+    remote_port: u16,
+
+    iss: u32,
+    /// The oldest unacknowledged sequence number
+    snd_una: u32,
+    /// The next sequence number to send
+    snd_nxt: u32,
+    snd_wnd: u16,
+    mss: u16,
+    /// Data from `snd_una` on, that hasn't been acknowledged
+    tx: VecDeque<u8>,
+    close_requested: bool,
+    fin_sent: bool,
+
+    rcv_nxt: u32,
+    rx: VecDeque<u8>,
+
+    /// Expires when to retransmit, if anything is in flight
+    retransmit: Option<Timer>,
+    rto_ns: u64,
+    retries: u8,
+    error: Option<NetError>,
+}
+
+impl Tcb {
+    fn is_synchronized(&self) -> bool {
+        !matches!(self.state, TcpState::SynSent | TcpState::Closed)
+    }
+
+    fn send_segment(&self, seq: u32, flags: u8, data: &[u8]) {
+        let with_mss = flags & FLAG_SYN != 0;
+        let header_len = if with_mss { HEADER_LEN + 4 } else { HEADER_LEN };
+        let window = RECV_WINDOW.saturating_sub(self.rx.len()).min(u16::MAX as usize) as u16;
+        let ack = if flags & FLAG_ACK != 0 { self.rcv_nxt } else { 0 };
+
+        let mut segment = Vec::with_capacity(header_len + data.len());
+        segment.extend_from_slice(&self.local_port.to_be_bytes());
+        segment.extend_from_slice(&self.remote_port.to_be_bytes());
+        segment.extend_from_slice(&seq.to_be_bytes());
+        segment.extend_from_slice(&ack.to_be_bytes());
+        segment.push(((header_len / 4) as u8) << 4);
+        segment.push(flags);
+        segment.extend_from_slice(&window.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 0]);
+        if with_mss {
+            segment.extend_from_slice(&[OPTION_MSS, 4]);
+            segment.extend_from_slice(&self.mss.to_be_bytes());
+        }
+        segment.extend_from_slice(data);
+        // Lost segments are recovered by retransmission
+        _ = ipv4::send_transport(self.remote_addr, PROTOCOL_TCP, segment, 16);
+    }
+
+    fn send_ack(&self) {
+        self.send_segment(self.snd_nxt, FLAG_ACK, &[]);
+    }
+
+    fn arm_retransmit(&mut self) {
+        if self.retransmit.is_none() {
+            // Without a free timer nothing is retransmitted, and the caller's timeout ends the wait
+            self.retransmit = Timer::after(Duration::from_nanos(self.rto_ns), retransmit_due, 0).ok();
+        }
+    }
+
+    fn disarm_retransmit(&mut self) {
+        if let Some(timer) = self.retransmit.take() {
+            timer.cancel();
+        }
+    }
+
+    /// Returns whether the retransmission timer expired
+    fn retransmit_due(&self) -> bool {
+        self.retransmit.is_some_and(|timer| !timer.is_pending())
+    }
+
+    /// Sends as much queued data as the peer's window allows, followed by a FIN once closing
+    fn send_pending(&mut self) {
+        if self.fin_sent || !matches!(self.state, TcpState::Established | TcpState::CloseWait) {
+            return;
+        }
+
+        loop {
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let unsent = self.tx.len().saturating_sub(in_flight);
+            let window = (self.snd_wnd as usize).saturating_sub(in_flight);
+            let len = unsent.min(window).min(self.mss as usize);
+            if len == 0 {
+                break;
+            }
+            let data: Vec<u8> = self.tx.range(in_flight..in_flight + len).copied().collect();
+            let flags = if len == unsent { FLAG_ACK | FLAG_PSH } else { FLAG_ACK };
+            self.send_segment(self.snd_nxt, flags, &data);
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+            self.arm_retransmit();
+        }
+
+        let all_sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize == self.tx.len();
+        if self.close_requested && all_sent {
+            self.send_segment(self.snd_nxt, FLAG_FIN | FLAG_ACK, &[]);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.fin_sent = true;
+            self.state = match self.state {
+                TcpState::CloseWait => TcpState::LastAck,
+                _ => TcpState::FinWait1,
+            };
+            self.arm_retransmit();
+        }
+    }
+
+    fn retransmit(&mut self) {
+        if self.retries >= MAX_RETRIES {
+            self.state = TcpState::Closed;
+            self.error = Some(NetError::Timeout);
+            return;
+        }
+        self.retries += 1;
+        self.rto_ns = (self.rto_ns * 2).min(MAX_RTO_NS);
+        self.retransmit = None;
+
+        if self.state == TcpState::SynSent {
+            self.send_segment(self.iss, FLAG_SYN, &[]);
+            self.arm_retransmit();
+            return;
+        }
+        // Go back to the oldest unacknowledged byte and send everything again
+        self.snd_nxt = self.snd_una;
+        if self.fin_sent {
+            self.fin_sent = false;
+            self.state = match self.state {
+                TcpState::LastAck | TcpState::Closing => TcpState::CloseWait,
+                _ => TcpState::Established,
+            };
+        }
+        self.send_pending();
+    }
+
+    fn handle(&mut self, seq: u32, ack: u32, flags: u8, window: u16, options: &[u8], payload: &[u8]) {
+        if flags & FLAG_RST != 0 {
+            if self.state == TcpState::SynSent {
+                if flags & FLAG_ACK != 0 && ack == self.iss.wrapping_add(1) {
+                    self.state = TcpState::Closed;
+                    self.error = Some(NetError::Refused);
+                }
+            } else if seq == self.rcv_nxt {
+                self.state = TcpState::Closed;
+                self.error = Some(NetError::Reset);
+            }
+            return;
+        }
+
+        if self.state == TcpState::SynSent {
+            if flags & (FLAG_SYN | FLAG_ACK) == FLAG_SYN | FLAG_ACK && ack == self.iss.wrapping_add(1) {
+                self.rcv_nxt = seq.wrapping_add(1);
+                self.snd_una = ack;
+                self.snd_wnd = window;
+                self.mss = self.mss.min(parse_mss(options).unwrap_or(DEFAULT_MSS));
+                self.state = TcpState::Established;
+                self.disarm_retransmit();
+                self.retries = 0;
+                self.rto_ns = INITIAL_RTO_NS;
+                self.send_ack();
+                self.send_pending();
+            }
+            return;
+        }
+        if self.state == TcpState::Closed {
+            return;
+        }
+
+        if flags & FLAG_ACK != 0 && seq_lt(self.snd_una, ack) && seq_le(ack, self.snd_nxt) {
+            let acked = ack.wrapping_sub(self.snd_una) as usize;
+            let data_acked = acked.min(self.tx.len());
+            self.tx.drain(..data_acked);
+            self.snd_una = ack;
+            self.retries = 0;
+            self.rto_ns = INITIAL_RTO_NS;
+            self.disarm_retransmit();
+            if self.snd_una != self.snd_nxt {
+                self.arm_retransmit();
+            }
+
+            if self.fin_sent && ack == self.snd_nxt {
+                self.state = match self.state {
+                    TcpState::FinWait1 => TcpState::FinWait2,
+                    TcpState::Closing | TcpState::LastAck => TcpState::Closed,
+                    state => state,
+                };
+            }
+        }
+        if flags & FLAG_ACK != 0 {
+            self.snd_wnd = window;
+        }
+
+        let mut need_ack = false;
+        let receiving = matches!(
+            self.state,
+            TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+        );
+        if !payload.is_empty() {
+            if seq == self.rcv_nxt && receiving && self.rx.len() + payload.len() <= RECV_WINDOW {
+                self.rx.extend(payload);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(payload.len() as u32);
+            }
+            // Out of order or duplicate data gets a duplicate ACK
+            need_ack = true;
+        }
+        if flags & FLAG_FIN != 0 && seq.wrapping_add(payload.len() as u32) == self.rcv_nxt && receiving {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.state = match self.state {
+                TcpState::Established => TcpState::CloseWait,
+                TcpState::FinWait1 => TcpState::Closing,
+                _ => TcpState::Closed,
+            };
+            need_ack = true;
+        }
+
+        if need_ack {
+            self.send_ack();
+        }
+        self.send_pending();
+    }
+}
+
+fn parse_mss(mut options: &[u8]) -> Option<u16> {
+    while let [kind, rest @ ..] = options {
+        match *kind {
+            OPTION_END => break,
+            OPTION_NOP => options = rest,
+            _ => {
+                let len = *rest.first()? as usize;
+                if len < 2 || len > options.len() {
+                    return None;
+                }
+                if *kind == OPTION_MSS && len == 4 {
+                    return Some(u16::from_be_bytes([options[2], options[3]]));
+                }
+                options = &options[len..];
+            }
+        }
+    }
+    None
+}
+
+static CONNECTIONS: Mutex<Vec<Arc<Mutex<Tcb>>>> = Mutex::new(Vec::new());
+
+/// A TCP connection
+///
+/// Dropping the stream closes the connection, which finishes in the background.
+#[derive(Debug)]
+pub struct TcpStream {
+    tcb: Arc<Mutex<Tcb>>,
+}
+
+impl TcpStream {
+    /// Opens a connection, driving the network stack until it is established
+    pub fn connect(addr: Ipv4Addr, port: u16, timeout_ns: u64) -> Result<Self, NetError> {
+        let (iface, _) = net::route::lookup(addr).ok_or(NetError::NoRoute)?;
+        let iss = (time::monotonic_ns() >> 2) as u32;
+        let tcb = Tcb {
+            state: TcpState::SynSent,
+            local_port: net::ephemeral_port(),
+            remote_addr: addr,
+            remote_port: port,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_wnd: 0,
+            mss: (iface.mtu() - ipv4::HEADER_LEN - HEADER_LEN).min(u16::MAX as usize) as u16,
+            tx: VecDeque::new(),
+            close_requested: false,
+            fin_sent: false,
+            rcv_nxt: 0,
+            rx: VecDeque::new(),
+            retransmit: None,
+            rto_ns: INITIAL_RTO_NS,
+            retries: 0,
+            error: None,
+        };
+        tcb.send_segment(iss, FLAG_SYN, &[]);
+        let tcb = Arc::new(Mutex::new(tcb));
+        tcb.lock().arm_retransmit();
+        CONNECTIONS.lock().push(tcb.clone());
+
+        let stream = Self { tcb };
+        let result = net::poll_until(timeout_ns, || {
+            let tcb = stream.tcb.lock();
+            match tcb.state {
+                TcpState::SynSent => None,
+                TcpState::Closed => Some(Err(tcb.error.unwrap_or(NetError::Reset))),
+                _ => Some(Ok(())),
+            }
+        });
+        match result {
+            Some(Ok(())) => Ok(stream),
+            Some(Err(err)) => Err(err),
+            None => {
+                // Stop retrying the handshake in the background
+                let mut tcb = stream.tcb.lock();
+                tcb.state = TcpState::Closed;
+                tcb.disarm_retransmit();
+                drop(tcb);
+                Err(NetError::Timeout)
+            }
+        }
+    }
+
+    pub fn state(&self) -> TcpState {
+        self.tcb.lock().state
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.tcb.lock().local_port
+    }
+
+    /// Queues data for sending
+    pub fn write_all(&self, data: &[u8]) -> Result<(), NetError> {
+        let mut tcb = self.tcb.lock();
+        if let Some(err) = tcb.error {
+            return Err(err);
+        }
+        if !matches!(tcb.state, TcpState::Established | TcpState::CloseWait) || tcb.close_requested {
+            return Err(NetError::Reset);
+        }
+        tcb.tx.extend(data);
+        tcb.send_pending();
+        Ok(())
+    }
+
+    /// Reads received data, waiting up to `timeout_ns` for some to arrive
+    ///
+    /// Returns zero once the peer has closed its side and all data was read.
+    pub fn read(&self, buf: &mut [u8], timeout_ns: u64) -> Result<usize, NetError> {
+        let result = net::poll_until(timeout_ns, || {
+            let mut tcb = self.tcb.lock();
+            if !tcb.rx.is_empty() {
+                let len = buf.len().min(tcb.rx.len());
+                for (dst, src) in buf.iter_mut().zip(tcb.rx.drain(..len)) {
+                    *dst = src;
+                }
+                // Let the peer know the window opened up again
+                if tcb.is_synchronized() {
+                    tcb.send_ack();
+                }
+                return Some(Ok(len));
+            }
+            if let Some(err) = tcb.error {
+                return Some(Err(err));
+            }
+            match tcb.state {
+                TcpState::CloseWait | TcpState::Closing | TcpState::LastAck | TcpState::Closed => Some(Ok(0)),
+                _ => None,
+            }
+        });
+        result.unwrap_or(Err(NetError::Timeout))
+    }
+
+    /// Reads until the peer closes the connection, or `limit` bytes were read
+    pub fn read_to_end(&self, out: &mut Vec<u8>, limit: usize, timeout_ns: u64) -> Result<(), NetError> {
+        let mut buf = [0; 2048];
+        loop {
+            let len = self.read(&mut buf, timeout_ns)?;
+            if len == 0 {
+                return Ok(());
+            }
+            if out.len() + len > limit {
+                return Err(NetError::TooLarge);
+            }
+            out.extend_from_slice(&buf[..len]);
+        }
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut tcb = self.tcb.lock();
+        tcb.close_requested = true;
+        tcb.send_pending();
+    }
+}
+
+/// Handles a received TCP segment
+pub(super) fn handle(header: &Ipv4Header, segment: &[u8], csum: ChecksumState) {
+    if segment.len() < HEADER_LEN || !ipv4::verify_transport(header, segment, csum) {
+        return;
+    }
+    let data_offset = (segment[12] >> 4) as usize * 4;
+    if data_offset < HEADER_LEN || data_offset > segment.len() {
+        return;
+    }
+    let src_port = u16::from_be_bytes([segment[0], segment[1]]);
+    let dst_port = u16::from_be_bytes([segment[2], segment[3]]);
+    let seq = u32::from_be_bytes(segment[4..8].try_into().unwrap());
+    let ack = u32::from_be_bytes(segment[8..12].try_into().unwrap());
+    let flags = segment[13];
+    let window = u16::from_be_bytes([segment[14], segment[15]]);
+
+    let tcb = CONNECTIONS
+        .lock()
+        .iter()
+        .find(|tcb| {
+            let tcb = tcb.lock();
+            tcb.local_port == dst_port && tcb.remote_port == src_port && tcb.remote_addr == header.src
+        })
+        .cloned();
+    if let Some(tcb) = tcb {
+        tcb.lock().handle(
+            seq,
+            ack,
+            flags,
+            window,
+            &segment[HEADER_LEN..data_offset],
+            &segment[data_offset..],
+        );
+    }
+}
+
+/// Set by a retransmission timer, for [`poll`] to look for the connections that are due
+static RETRANSMIT_DUE: AtomicBool = AtomicBool::new(false);
+
+fn retransmit_due(_data: usize) {
+    RETRANSMIT_DUE.store(true, Ordering::Release);
+}
+
+/// Retransmits timed out segments, and forgets connections that are closed and dropped
+pub(super) fn poll() {
+    let due = RETRANSMIT_DUE.swap(false, Ordering::Acquire);
+    let mut connections = CONNECTIONS.lock();
+    if due {
+        for tcb in connections.iter() {
+            let mut tcb = tcb.lock();
+            if tcb.retransmit_due() && tcb.state != TcpState::Closed {
+                tcb.retransmit();
+            }
+        }
+    }
+    connections.retain(|tcb| Arc::strong_count(tcb) > 1 || tcb.lock().state != TcpState::Closed);
+}
@@ -0,0 +1,289 @@
+//! TFTP client and network boot
+//!
+//! Downloads files in octet mode (RFC 1350), negotiating a larger block size and the transfer
+//! size through options (RFC 2347, 2348, 2349) when the server supports them.
+//!
+//! In network boot mode, the kernel fetches a payload from the boot server once the network is up,
+//! and uses it as its initramfs. The server comes from `tftp.server=` on the command line, as there
+//! is no DHCP client yet, and the file from `tftp.file=`, [`DEFAULT_BOOT_FILE`] otherwise.
+
+use core::{fmt, net::Ipv4Addr};
+
+use alloc::vec::Vec;
+
+use crate::{
+    boot::Cmdline,
+    kprintln,
+    net::{NetError, udp::UdpSocket},
+    sync::{Once, RwLock},
+    time,
+};
+
+const TFTP_PORT: u16 = 69;
+
+const OP_RRQ: u16 = 1;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OACK: u16 = 6;
+
+const ERR_NOT_FOUND: u16 = 1;
+const ERR_ACCESS: u16 = 2;
+
+/// The block size used by servers without option support
+const DEFAULT_BLKSIZE: usize = 512;
+/// Fits a block into a standard Ethernet frame
+const PREFERRED_BLKSIZE: usize = 1428;
+/// Files larger than this are refused, so a bad server can't exhaust the heap
+const MAX_FILE_SIZE: usize = 256 * 1024 * 1024;
+
+const TIMEOUT_NS: u64 = 1_000_000_000;
+const ATTEMPTS: usize = 5;
+
+/// The file fetched in network boot mode when none is configured
+pub const DEFAULT_BOOT_FILE: &str = "initramfs";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TftpError {
+    NoServer,
+    NotFound,
+    AccessDenied,
+    /// The server sent an error with the given code
+    Remote(u16),
+    TooLarge,
+    InvalidResponse,
+    Net(NetError),
+}
+
+impl fmt::Display for TftpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoServer => f.write_str("no TFTP server configured"),
+            Self::NotFound => f.write_str("file not found"),
+            Self::AccessDenied => f.write_str("access denied"),
+            Self::Remote(code) => write!(f, "server error {}", code),
+            Self::TooLarge => f.write_str("file is too large"),
+            Self::InvalidResponse => f.write_str("invalid response"),
+            Self::Net(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl core::error::Error for TftpError {}
+
+impl From<NetError> for TftpError {
+    fn from(err: NetError) -> Self {
+        Self::Net(err)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Packet<'a> {
+    Data {
+        block: u16,
+        data: &'a [u8],
+    },
+    Error {
+        code: u16,
+    },
+    /// The options the server accepted, as `(name, value)` pairs
+    OptionAck(Vec<(&'a str, &'a str)>),
+}
+
+fn build_request(filename: &str) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(filename.len() + 32);
+    packet.extend_from_slice(&OP_RRQ.to_be_bytes());
+    for field in [filename, "octet", "blksize", "1428", "tsize", "0"] {
+        packet.extend_from_slice(field.as_bytes());
+        packet.push(0);
+    }
+    packet
+}
+
+fn build_ack(block: u16) -> [u8; 4] {
+    let [op0, op1] = OP_ACK.to_be_bytes();
+    let [b0, b1] = block.to_be_bytes();
+    [op0, op1, b0, b1]
+}
+
+fn parse_packet(packet: &[u8]) -> Option<Packet<'_>> {
+    let opcode = u16::from_be_bytes(packet.get(..2)?.try_into().unwrap());
+    let rest = &packet[2..];
+    match opcode {
+        OP_DATA => Some(Packet::Data {
+            block: u16::from_be_bytes(rest.get(..2)?.try_into().unwrap()),
+            data: &rest[2..],
+        }),
+        OP_ERROR => Some(Packet::Error {
+            code: u16::from_be_bytes(rest.get(..2)?.try_into().unwrap()),
+        }),
+        OP_OACK => {
+            let mut fields = rest.split(|&b| b == 0);
+            let mut options = Vec::new();
+            while let Some(name) = fields.next().filter(|name| !name.is_empty()) {
+                let value = fields.next()?;
+                options.push((core::str::from_utf8(name).ok()?, core::str::from_utf8(value).ok()?));
+            }
+            Some(Packet::OptionAck(options))
+        }
+        _ => None,
+    }
+}
+
+static SERVER: RwLock<Option<Ipv4Addr>> = RwLock::new(None);
+
+pub fn server() -> Option<Ipv4Addr> {
+    *SERVER.read()
+}
+
+pub fn set_server(server: Option<Ipv4Addr>) {
+    *SERVER.write() = server;
+}
+
+/// Downloads a file from a TFTP server
+pub fn fetch(server: Ipv4Addr, filename: &str) -> Result<Vec<u8>, TftpError> {
+    let socket = UdpSocket::bind(None)?;
+    let mut last_sent = build_request(filename);
+    // The server answers from a new port, which identifies the transfer
+    let mut server_port = None;
+    let mut blksize = DEFAULT_BLKSIZE;
+    let mut file = Vec::new();
+    let mut next_block: u16 = 1;
+
+    let mut attempts = 0;
+    loop {
+        socket.send_to(server, server_port.unwrap_or(TFTP_PORT), &last_sent)?;
+        let deadline = time::monotonic_ns() + TIMEOUT_NS;
+        let reply = loop {
+            let Some(remaining) = deadline.checked_sub(time::monotonic_ns()) else {
+                break None;
+            };
+            let reply = match socket.recv_timeout(remaining) {
+                Ok(reply) => reply,
+                Err(NetError::Timeout) => break None,
+                Err(err) => return Err(err.into()),
+            };
+            if reply.src != server || server_port.is_some_and(|port| port != reply.src_port) {
+                continue;
+            }
+            break Some(reply);
+        };
+        let Some(reply) = reply else {
+            attempts += 1;
+            if attempts == ATTEMPTS {
+                return Err(NetError::Timeout.into());
+            }
+            continue;
+        };
+        attempts = 0;
+        server_port = Some(reply.src_port);
+
+        match parse_packet(&reply.data).ok_or(TftpError::InvalidResponse)? {
+            Packet::Error { code: ERR_NOT_FOUND } => return Err(TftpError::NotFound),
+            Packet::Error { code: ERR_ACCESS } => return Err(TftpError::AccessDenied),
+            Packet::Error { code } => return Err(TftpError::Remote(code)),
+            Packet::OptionAck(options) => {
+                for (name, value) in options {
+                    match (name, value.parse::<usize>()) {
+                        ("blksize", Ok(size)) if (8..=PREFERRED_BLKSIZE).contains(&size) => blksize = size,
+                        ("tsize", Ok(size)) if size > MAX_FILE_SIZE => return Err(TftpError::TooLarge),
+                        ("tsize", Ok(size)) => file.reserve_exact(size),
+                        _ => {}
+                    }
+                }
+                last_sent = build_ack(0).to_vec();
+            }
+            Packet::Data { block, data } if block == next_block => {
+                if file.len() + data.len() > MAX_FILE_SIZE {
+                    return Err(TftpError::TooLarge);
+                }
+                file.extend_from_slice(data);
+                last_sent = build_ack(block).to_vec();
+                next_block = next_block.wrapping_add(1);
+                if data.len() < blksize {
+                    // The final ACK isn't retransmitted, the server resends the last block if it is lost
+                    socket.send_to(server, reply.src_port, &last_sent)?;
+                    return Ok(file);
+                }
+            }
+            // A duplicate of a block we already have, our ACK was probably lost
+            Packet::Data { .. } => {}
+        }
+    }
+}
+
+static BOOT_PAYLOAD: Once<Vec<u8>> = Once::new();
+
+/// Returns the payload fetched in network boot mode
+pub fn boot_payload() -> Option<&'static [u8]> {
+    BOOT_PAYLOAD.get().map(Vec::as_slice)
+}
+
+/// Fetches the boot payload from the boot server, if one is configured
+///
+/// Returns without doing anything if there is no server, so machines without network boot
+/// still come up normally.
+pub fn netboot(filename: Option<&str>) -> Result<Option<&'static [u8]>, TftpError> {
+    let Some(server) = server() else {
+        return Ok(None);
+    };
+    if let Some(payload) = boot_payload() {
+        return Ok(Some(payload));
+    }
+    let filename = filename.unwrap_or(DEFAULT_BOOT_FILE);
+    kprintln!(Info, "tftp: fetching {} from {}", filename, server);
+    let start = time::monotonic_ns();
+    let payload = fetch(server, filename)?;
+    kprintln!(
+        Info,
+        "tftp: fetched {} bytes in {}ms",
+        payload.len(),
+        (time::monotonic_ns() - start) / 1_000_000
+    );
+    Ok(Some(BOOT_PAYLOAD.call_once(|| payload)))
+}
+
+/// Sets the boot server from the command line, and fetches the boot payload from it
+///
+/// Called once the network is configured and before the root file system is mounted, so the
+/// payload is there to be the initramfs.
+pub fn init(cmdline: Cmdline) {
+    if let Some(addr) = cmdline.get("tftp.server") {
+        match addr.parse::<Ipv4Addr>() {
+            Ok(addr) => set_server(Some(addr)),
+            Err(_) => kprintln!(Warn, "tftp: ignoring invalid server '{}'", addr),
+        }
+    }
+    if let Err(err) = netboot(cmdline.get("tftp.file")) {
+        kprintln!(Warn, "tftp: network boot failed: {}", err);
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tftp_packets() {
+        let request = build_request("boot/initramfs");
+        assert_eq!(&request[..2], &[0, 1]);
+        assert!(request.ends_with(b"boot/initramfs\0octet\0blksize\01428\0tsize\00\0"));
+
+        assert_eq!(build_ack(0x1234), [0, 4, 0x12, 0x34]);
+
+        assert_eq!(
+            parse_packet(&[0, 3, 0, 7, b'h', b'i']),
+            Some(Packet::Data { block: 7, data: b"hi" })
+        );
+        assert_eq!(
+            parse_packet(b"\0\x05\0\x01File not found\0"),
+            Some(Packet::Error { code: 1 })
+        );
+        assert_eq!(
+            parse_packet(b"\0\x06blksize\01428\0tsize\0100\0"),
+            Some(Packet::OptionAck(alloc::vec![("blksize", "1428"), ("tsize", "100")]))
+        );
+        assert_eq!(parse_packet(&[0, 9]), None);
+        assert_eq!(parse_packet(&[0]), None);
+    }
+}
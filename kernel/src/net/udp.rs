@@ -0,0 +1,139 @@
+//! User Datagram Protocol
+
+use core::net::Ipv4Addr;
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+
+use crate::{
+    net::{
+        self, NetError,
+        buf::ChecksumState,
+        ipv4::{self, Ipv4Header, PROTOCOL_UDP},
+    },
+    sync::Mutex,
+};
+
+const HEADER_LEN: usize = 8;
+/// The number of datagrams a socket queues before dropping new ones
+const QUEUE_LEN: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    pub src: Ipv4Addr,
+    pub src_port: u16,
+    pub data: Vec<u8>,
+}
+
+type Queue = Arc<Mutex<VecDeque<Datagram>>>;
+
+static SOCKETS: Mutex<Vec<(u16, Queue)>> = Mutex::new(Vec::new());
+
+/// A bound UDP port, which is released when the socket is dropped
+#[derive(Debug)]
+pub struct UdpSocket {
+    port: u16,
+    queue: Queue,
+}
+
+impl UdpSocket {
+    /// Binds a socket to `port`, or to an ephemeral port if `None`
+    pub fn bind(port: Option<u16>) -> Result<Self, NetError> {
+        let mut sockets = SOCKETS.lock();
+        let port = match port {
+            Some(port) if sockets.iter().any(|(bound, _)| *bound == port) => return Err(NetError::AddrInUse),
+            Some(port) => port,
+            None => loop {
+                let port = net::ephemeral_port();
+                if !sockets.iter().any(|(bound, _)| *bound == port) {
+                    break port;
+                }
+            },
+        };
+        let queue = Queue::default();
+        sockets.push((port, queue.clone()));
+        Ok(Self { port, queue })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn send_to(&self, dst: Ipv4Addr, port: u16, data: &[u8]) -> Result<(), NetError> {
+        send(self.port, dst, port, data)
+    }
+
+    /// Returns the next received datagram, if there is one
+    pub fn recv(&self) -> Option<Datagram> {
+        self.queue.lock().pop_front()
+    }
+
+    /// Waits for a datagram, driving the network stack until one arrives
+    pub fn recv_timeout(&self, timeout_ns: u64) -> Result<Datagram, NetError> {
+        net::poll_until(timeout_ns, || self.recv()).ok_or(NetError::Timeout)
+    }
+}
+
+/// Sends a datagram from `src_port`, which doesn't have to be bound
+pub fn send(src_port: u16, dst: Ipv4Addr, dst_port: u16, data: &[u8]) -> Result<(), NetError> {
+    let len = HEADER_LEN + data.len();
+    if len > u16::MAX as usize {
+        return Err(NetError::TooLarge);
+    }
+    let mut segment = Vec::with_capacity(len);
+    segment.extend_from_slice(&src_port.to_be_bytes());
+    segment.extend_from_slice(&dst_port.to_be_bytes());
+    segment.extend_from_slice(&(len as u16).to_be_bytes());
+    segment.extend_from_slice(&[0, 0]);
+    segment.extend_from_slice(data);
+    ipv4::send_transport(dst, PROTOCOL_UDP, segment, 6)
+}
+
+/// Waits for a datagram to `port`, which is only bound while waiting
+///
+/// Datagrams that arrive between calls are dropped, use a [`UdpSocket`] to keep them.
+pub fn recv(port: u16, timeout_ns: u64) -> Result<Datagram, NetError> {
+    UdpSocket::bind(Some(port))?.recv_timeout(timeout_ns)
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().retain(|(port, _)| *port != self.port);
+    }
+}
+
+/// Handles a received UDP datagram
+pub(super) fn handle(header: &Ipv4Header, segment: &[u8], csum: ChecksumState) {
+    if segment.len() < HEADER_LEN {
+        return;
+    }
+    let len = u16::from_be_bytes([segment[4], segment[5]]) as usize;
+    if len < HEADER_LEN || len > segment.len() {
+        return;
+    }
+    let segment = &segment[..len];
+    // A zero checksum means the sender didn't compute one
+    let has_csum = segment[6..8] != [0, 0];
+    if has_csum && !ipv4::verify_transport(header, segment, csum) {
+        return;
+    }
+
+    let src_port = u16::from_be_bytes([segment[0], segment[1]]);
+    let dst_port = u16::from_be_bytes([segment[2], segment[3]]);
+    let Some(queue) = SOCKETS
+        .lock()
+        .iter()
+        .find(|(port, _)| *port == dst_port)
+        .map(|(_, queue)| queue.clone())
+    else {
+        return;
+    };
+
+    let mut queue = queue.lock();
+    if queue.len() < QUEUE_LEN {
+        queue.push_back(Datagram {
+            src: header.src,
+            src_port,
+            data: segment[HEADER_LEN..].to_vec(),
+        });
+    }
+}
@@ -0,0 +1,297 @@
+//! CPU hotplug
+//!
+//! A secondary CPU is taken offline with [`offline`]: registered notifiers move work such as tasks
+//! and interrupts away from it while it still runs, then an IPI makes it park itself in a `hlt`
+//! loop until [`online`] sends it another one. Parked CPUs keep their per-CPU area, so they come
+//! back exactly where they left off.
+//!
+//! The BSP can't be taken offline. It is the target of every device interrupt for now, so there
+//! are no interrupts to move off of secondary CPUs yet. A parked CPU has its local APIC timer
+//! masked, so it only wakes up for the IPI.
+//!
+//! Booting with `maxcpus=<n>` keeps every CPU from the `n`th on offline, and `nosmp` is the same as
+//! `maxcpus=1`, which helps to tell SMP races apart from other bugs. The CPUs are parked right
+//! after the timers are set up, and can't be brought online until the next boot.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::vec::Vec;
+
+use super::{CpuLocal, MAX_CPUS, ONLINE_CPUS};
+use crate::{
+    arch::{instructions::interrupts, x86_64::apic},
+    boot::Cmdline,
+    irq::{self, IrqError},
+    kprintln,
+    sync::{Once, RwLock},
+    time,
+};
+
+/// How long a CPU has to park itself after being asked to
+const PARK_TIMEOUT_NS: u64 = 100_000_000;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuState {
+    Online = 0,
+    /// Asked to park, but still running
+    Parking = 1,
+    /// Parked until it is brought back online
+    Offline = 2,
+}
+
+impl CpuState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Online,
+            1 => Self::Parking,
+            _ => Self::Offline,
+        }
+    }
+}
+
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Online => "online",
+            Self::Parking => "parking",
+            Self::Offline => "offline",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugError {
+    NoSuchCpu,
+    /// The BSP can't be taken offline
+    BootCpu,
+    /// A CPU can't take itself offline, as nothing would bring it back
+    CurrentCpu,
+    AlreadyOnline,
+    AlreadyOffline,
+    /// The CPU is past the limit given with `maxcpus=` or `nosmp`
+    OverLimit,
+    /// The local APIC isn't initialized, so there is no way to send IPIs
+    NoApic,
+    /// No vector could be allocated for the park IPI
+    Irq(IrqError),
+    /// The CPU didn't park in time, and was left online
+    Timeout,
+}
+
+impl fmt::Display for HotplugError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSuchCpu => f.write_str("no such CPU"),
+            Self::BootCpu => f.write_str("the boot CPU can't be taken offline"),
+            Self::CurrentCpu => f.write_str("a CPU can't take itself offline"),
+            Self::AlreadyOnline => f.write_str("CPU is already online"),
+            Self::AlreadyOffline => f.write_str("CPU is already offline"),
+            Self::OverLimit => f.write_str("CPU is past the limit on the command line"),
+            Self::NoApic => f.write_str("local APIC is not initialized"),
+            Self::Irq(err) => write!(f, "park IPI: {}", err),
+            Self::Timeout => f.write_str("CPU did not park in time"),
+        }
+    }
+}
+
+impl core::error::Error for HotplugError {}
+
+impl From<IrqError> for HotplugError {
+    fn from(err: IrqError) -> Self {
+        Self::Irq(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugEvent {
+    /// The CPU is about to park, and must not be given any more work
+    Offline,
+    /// The CPU is back online
+    Online,
+}
+
+/// Called with the id of a CPU changing state
+pub type HotplugNotifier = fn(cpu_id: usize, event: HotplugEvent);
+
+static NOTIFIERS: RwLock<Vec<(&'static str, HotplugNotifier)>> = RwLock::new(Vec::new());
+/// The vector of the IPI that parks and wakes CPUs, allocated on first use
+static PARK_VECTOR: Once<Result<u8, IrqError>> = Once::new();
+/// How many CPUs may be online, from `maxcpus=` or `nosmp`
+static CPU_LIMIT: AtomicUsize = AtomicUsize::new(MAX_CPUS);
+
+impl CpuLocal {
+    pub fn state(&self) -> CpuState {
+        CpuState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.state() == CpuState::Online
+    }
+
+    fn transition(&self, from: CpuState, to: CpuState) -> bool {
+        self.state
+            .compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+}
+
+/// Registers a function called whenever a CPU goes offline or comes back online
+pub fn register_notifier(name: &'static str, notifier: HotplugNotifier) {
+    let mut notifiers = NOTIFIERS.write();
+    notifiers.retain(|(other, _)| *other != name);
+    notifiers.push((name, notifier));
+}
+
+fn notify(cpu_id: usize, event: HotplugEvent) {
+    let notifiers = NOTIFIERS.read().clone();
+    for (_, notifier) in notifiers {
+        notifier(cpu_id, event);
+    }
+}
+
+fn park_vector() -> Result<u8, HotplugError> {
+    Ok((*PARK_VECTOR.call_once(|| {
+        let vector = irq::request_any_irq("cpu park", park_ipi, 0)?;
+        _ = irq::set_source(vector, irq::IrqSource::Ipi);
+        Ok(vector)
+    }))?)
+}
+
+/// Handles the park IPI on the target CPU, parking it if it was asked to
+fn park_ipi(_vector: u8, _data: usize) {
+    let cpu = super::current();
+    // The IPI that wakes a parked CPU is handled while it is parked, and returns right away
+    if !cpu.transition(CpuState::Parking, CpuState::Offline) {
+        return;
+    }
+    // Acknowledged early so the wake up IPI gets through, the second EOI after returning is a no-op
+    let lapic = apic::local_apic();
+    let timer = lapic.map(|lapic| {
+        lapic.eoi();
+        lapic.mask_timer()
+    });
+    while cpu.state() == CpuState::Offline {
+        unsafe {
+            interrupts::enable_and_hlt();
+            interrupts::disable();
+        }
+    }
+    if let (Some(lapic), Some(timer)) = (lapic, timer) {
+        lapic.restore_timer(timer);
+    }
+}
+
+/// Checks that a CPU other than the BSP and the current CPU exists, returning its area
+fn secondary_cpu(cpu_id: usize) -> Result<&'static CpuLocal, HotplugError> {
+    let cpu = super::cpu(cpu_id).ok_or(HotplugError::NoSuchCpu)?;
+    if cpu_id == 0 {
+        return Err(HotplugError::BootCpu);
+    }
+    if cpu_id == super::cpu_id() {
+        return Err(HotplugError::CurrentCpu);
+    }
+    Ok(cpu)
+}
+
+/// Moves work away from a CPU and parks it, waiting until it is parked
+pub fn offline(cpu_id: usize) -> Result<(), HotplugError> {
+    let cpu = secondary_cpu(cpu_id)?;
+    let lapic = apic::local_apic().ok_or(HotplugError::NoApic)?;
+    let vector = park_vector()?;
+    if !cpu.transition(CpuState::Online, CpuState::Parking) {
+        return Err(HotplugError::AlreadyOffline);
+    }
+
+    notify(cpu_id, HotplugEvent::Offline);
+    lapic.send_ipi(cpu.apic_id, vector);
+    let deadline = time::monotonic_ns() + PARK_TIMEOUT_NS;
+    while cpu.state() != CpuState::Offline {
+        // The CPU may still park between the deadline and taking the request back
+        if time::monotonic_ns() > deadline && cpu.transition(CpuState::Parking, CpuState::Online) {
+            notify(cpu_id, HotplugEvent::Online);
+            kprintln!(Warn, "cpu{}: did not park in time", cpu_id);
+            return Err(HotplugError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+
+    ONLINE_CPUS.fetch_sub(1, Ordering::Relaxed);
+    kprintln!(Info, "cpu{}: offline", cpu_id);
+    Ok(())
+}
+
+/// Wakes a parked CPU
+pub fn online(cpu_id: usize) -> Result<(), HotplugError> {
+    let cpu = secondary_cpu(cpu_id)?;
+    if cpu_id >= cpu_limit() {
+        return Err(HotplugError::OverLimit);
+    }
+    let lapic = apic::local_apic().ok_or(HotplugError::NoApic)?;
+    let vector = park_vector()?;
+    if !cpu.transition(CpuState::Offline, CpuState::Online) {
+        return Err(HotplugError::AlreadyOnline);
+    }
+
+    lapic.send_ipi(cpu.apic_id, vector);
+    ONLINE_CPUS.fetch_add(1, Ordering::Relaxed);
+    notify(cpu_id, HotplugEvent::Online);
+    kprintln!(Info, "cpu{}: online", cpu_id);
+    Ok(())
+}
+
+/// Returns how many CPUs may be online
+pub fn cpu_limit() -> usize {
+    CPU_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Parses `maxcpus=` and `nosmp`, returning `Err` with the value if it isn't a number
+///
+/// The BSP always runs, so the limit is at least one.
+fn parse_limit<'a>(cmdline: Cmdline<'a>) -> Result<Option<usize>, &'a str> {
+    if cmdline.flag("nosmp") {
+        return Ok(Some(1));
+    }
+    let Some(value) = cmdline.get("maxcpus") else {
+        return Ok(None);
+    };
+    let limit = value.parse::<usize>().map_err(|_| value)?;
+    Ok(Some(limit.clamp(1, MAX_CPUS)))
+}
+
+/// Applies `maxcpus=` and `nosmp`, taking every CPU past the limit offline
+pub fn limit_cpus(cmdline: Cmdline) {
+    let limit = match parse_limit(cmdline) {
+        Ok(Some(limit)) => limit,
+        Ok(None) => return,
+        Err(value) => {
+            kprintln!(Warn, "cpu: invalid maxcpus '{}', using every CPU", value);
+            return;
+        }
+    };
+    CPU_LIMIT.store(limit, Ordering::Relaxed);
+    kprintln!(Info, "cpu: limited to {} CPUs on the command line", limit);
+    for cpu in super::cpus().filter(|cpu| cpu.cpu_id as usize >= limit && cpu.is_online()) {
+        if let Err(err) = offline(cpu.cpu_id as usize) {
+            kprintln!(Warn, "cpu{}: can't take offline: {}", cpu.cpu_id, err);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hotplug_parse_limit() {
+        assert_eq!(parse_limit(Cmdline::new("quiet")), Ok(None));
+        assert_eq!(parse_limit(Cmdline::new("maxcpus=2")), Ok(Some(2)));
+        assert_eq!(parse_limit(Cmdline::new("maxcpus=0")), Ok(Some(1)));
+        assert_eq!(parse_limit(Cmdline::new("maxcpus=4 nosmp")), Ok(Some(1)));
+        assert_eq!(parse_limit(Cmdline::new("maxcpus=1000")), Ok(Some(MAX_CPUS)));
+        assert_eq!(parse_limit(Cmdline::new("maxcpus=all")), Err("all"));
+    }
+}
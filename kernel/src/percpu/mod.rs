@@ -0,0 +1,213 @@
+//! Per-CPU data
+//!
+//! Every CPU gets a [`CpuLocal`] area during bring-up, which GSBASE points to while running in
+//! the kernel. Subsystems that need their own per-CPU state, like the scheduler run queues,
+//! declare a [`PerCpu`] static that is indexed by the CPU id stored in that area.
+
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::boxed::Box;
+
+use crate::{arch::registers::msr::Msr, stats::snapshot::Counters};
+
+mod hotplug;
+
+pub use hotplug::{CpuState, HotplugError, HotplugEvent, cpu_limit, limit_cpus, offline, online, register_notifier};
+
+/// The maximum number of CPUs the kernel supports
+pub const MAX_CPUS: usize = 64;
+
+/// The size of the stack used by system calls on every CPU
+const SYSCALL_STACK_SIZE: usize = 4096 * 8;
+
+/// Counters kept separately for every CPU, so they can be updated without contention
+#[derive(Debug)]
+pub struct CpuStats {
+    pub interrupts: AtomicU64,
+}
+
+impl CpuStats {
+    const fn new() -> Self {
+        Self {
+            interrupts: AtomicU64::new(0),
+        }
+    }
+}
+
+/// The per-CPU area that GSBASE points to
+#[repr(C)]
+#[derive(Debug)]
+pub struct CpuLocal {
+    /// Points to the area itself, so it can be found with a single GS relative load
+    self_ptr: *const CpuLocal,
+    pub cpu_id: u32,
+    pub apic_id: u32,
+    /// The [`CpuState`], changed through [`offline`] and [`online`]
+    state: AtomicU8,
+    /// The task running on this CPU, owned by the scheduler
+    pub current_task: AtomicPtr<()>,
+    pub stats: CpuStats,
+    /// The top of the stack the SYSCALL entry switches to
+    pub(crate) syscall_stack: usize,
+    /// The user stack pointer, saved by the SYSCALL entry while it switches stacks
+    pub(crate) user_rsp: usize,
+    /// The kernel stack pointer to return to when a ring 3 payload exits
+    pub(crate) user_exit_rsp: usize,
+}
+
+// SAFETY: `self_ptr` is never written after the area is installed
+unsafe impl Sync for CpuLocal {}
+unsafe impl Send for CpuLocal {}
+
+static CPUS: [AtomicPtr<CpuLocal>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(0);
+/// Set once the BSP has its area, before that we are the only CPU running and have no GSBASE
+static BSP_READY: AtomicBool = AtomicBool::new(false);
+
+/// Allocates the per-CPU area for the current CPU and points GSBASE at it
+///
+/// # Safety
+/// Must be called once on each CPU, with a unique `cpu_id`, after the heap is initialized.
+pub unsafe fn init_cpu(cpu_id: u32, apic_id: u32) {
+    assert!((cpu_id as usize) < MAX_CPUS, "CPU id {} exceeds MAX_CPUS", cpu_id);
+
+    let syscall_stack = alloc::vec![0u8; SYSCALL_STACK_SIZE].leak().as_ptr_range().end as usize;
+    let area = Box::leak(Box::new(CpuLocal {
+        self_ptr: ptr::null(),
+        cpu_id,
+        apic_id,
+        state: AtomicU8::new(CpuState::Online as u8),
+        current_task: AtomicPtr::new(ptr::null_mut()),
+        stats: CpuStats::new(),
+        // The ABI requires a 16 byte aligned stack
+        syscall_stack: syscall_stack & !0xF,
+        user_rsp: 0,
+        user_exit_rsp: 0,
+    }));
+    area.self_ptr = area;
+
+    let (mut gs_base, mut kernel_gs_base) = (Msr::IA32_GS_BASE, Msr::IA32_KERNEL_GS_BASE);
+    unsafe {
+        gs_base.write(area as *const CpuLocal as u64);
+        // This becomes the user GSBASE after `swapgs` once we return to userspace
+        kernel_gs_base.write(0);
+    }
+    unsafe { crate::arch::x86_64::fpu::init_kernel_area(cpu_id as usize) };
+    CPUS[cpu_id as usize].store(area, Ordering::Release);
+    ONLINE_CPUS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Sets up the per-CPU area of the bootstrap processor, which is always CPU 0
+///
+/// # Safety
+/// Must be called once on the BSP, after the heap is initialized.
+pub unsafe fn init_bsp() {
+    unsafe { init_cpu(0, crate::arch::x86_64::cpu::initial_apic_id()) };
+    BSP_READY.store(true, Ordering::Release);
+    crate::stats::register("percpu", dump_stats);
+    crate::stats::snapshot::register("percpu", counters);
+}
+
+fn counters(counters: &mut Counters<'_>) {
+    for cpu in cpus() {
+        counters.counter(
+            format_args!("cpu{}.interrupts", cpu.cpu_id),
+            cpu.stats.interrupts.load(Ordering::Relaxed),
+        );
+    }
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    for cpu in cpus() {
+        writeln!(
+            out,
+            "cpu{}: apic_id={} state={} interrupts={}",
+            cpu.cpu_id,
+            cpu.apic_id,
+            cpu.state(),
+            cpu.stats.interrupts.load(Ordering::Relaxed)
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the per-CPU area of the current CPU, if it has been set up
+pub fn try_current() -> Option<&'static CpuLocal> {
+    if !BSP_READY.load(Ordering::Acquire) {
+        return None;
+    }
+    Some(current())
+}
+
+/// Returns the per-CPU area of the current CPU
+///
+/// # Panics
+/// Panics if the per-CPU area of the BSP hasn't been set up yet.
+pub fn current() -> &'static CpuLocal {
+    assert!(BSP_READY.load(Ordering::Acquire), "per-CPU areas are not set up yet");
+    let area: *const CpuLocal;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, gs:[0]",
+            out(reg) area,
+            options(nostack, preserves_flags, readonly)
+        );
+        &*area
+    }
+}
+
+/// Returns the id of the current CPU
+pub fn cpu_id() -> usize {
+    try_current().map_or(0, |cpu| cpu.cpu_id as usize)
+}
+
+/// Returns the per-CPU area of a CPU, if it has been brought up
+///
+/// The CPU may have been taken offline since, see [`CpuLocal::state`].
+pub fn cpu(cpu_id: usize) -> Option<&'static CpuLocal> {
+    let area = CPUS.get(cpu_id)?.load(Ordering::Acquire);
+    unsafe { area.as_ref() }
+}
+
+/// Returns the number of online CPUs
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::Relaxed)
+}
+
+/// Returns the per-CPU areas of all CPUs that have been brought up, including offline ones
+pub fn cpus() -> impl Iterator<Item = &'static CpuLocal> {
+    (0..MAX_CPUS).filter_map(cpu)
+}
+
+/// A value with a separate instance for every CPU
+///
+/// The instance of the current CPU is only stable while the task can't migrate, so callers
+/// usually disable interrupts or preemption around uses that need it.
+#[derive(Debug)]
+pub struct PerCpu<T> {
+    slots: [T; MAX_CPUS],
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(slots: [T; MAX_CPUS]) -> Self {
+        Self { slots }
+    }
+
+    /// Returns the instance of the current CPU
+    pub fn get(&self) -> &T {
+        &self.slots[cpu_id()]
+    }
+
+    /// Returns the instance of the given CPU
+    pub fn get_for(&self, cpu_id: usize) -> &T {
+        &self.slots[cpu_id]
+    }
+
+    /// Returns the instances of all CPUs, including ones that aren't online
+    pub fn iter(&self) -> core::slice::Iter<'_, T> {
+        self.slots.iter()
+    }
+}
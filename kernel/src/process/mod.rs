@@ -0,0 +1,237 @@
+//! User processes
+//!
+//! A [`Process`] owns an [`AddressSpace`] with its program image and user stack mapped in, and a
+//! table of open files. Processes currently run to completion on the CPU that starts them.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{
+    arch::VirtAddr,
+    elf::{Elf, ElfError, LoadedImage},
+    mm::{
+        address_space::{AddressSpace, AddressSpaceError},
+        page_table::PageTableFlags,
+    },
+    percpu::{MAX_CPUS, PerCpu},
+    sync::{Mutex, RwLock},
+};
+
+/// Where position independent executables are loaded
+pub const USER_PIE_BASE: usize = 0x40_0000;
+/// The top of the user stack, leaving the last page of the lower half unmapped
+pub const USER_STACK_TOP: VirtAddr = VirtAddr::new(0x0000_7FFF_FFFF_F000);
+pub const USER_STACK_SIZE: usize = 64 * 1024;
+/// Where the kernel log ring is mapped, by the `log_map` syscall
+pub const USER_LOG_RING: VirtAddr = VirtAddr::new(0x0000_7F00_0000_0000);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(pub u32);
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    Elf(ElfError),
+    Memory(AddressSpaceError),
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Elf(err) => write!(f, "failed to load ELF image: {}", err),
+            Self::Memory(err) => write!(f, "failed to map process memory: {}", err),
+        }
+    }
+}
+
+impl core::error::Error for SpawnError {}
+
+impl From<ElfError> for SpawnError {
+    fn from(err: ElfError) -> Self {
+        Self::Elf(err)
+    }
+}
+
+impl From<AddressSpaceError> for SpawnError {
+    fn from(err: AddressSpaceError) -> Self {
+        Self::Memory(err)
+    }
+}
+
+/// An open file of a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum File {
+    /// The kernel console
+    Console,
+}
+
+/// The open files of a process, indexed by file descriptor
+#[derive(Debug, Default)]
+pub struct FileTable {
+    files: Vec<Option<File>>,
+}
+
+impl FileTable {
+    /// Creates a table with stdin, stdout and stderr opened on the console
+    pub fn with_stdio() -> Self {
+        Self {
+            files: alloc::vec![Some(File::Console); 3],
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> Option<File> {
+        self.files.get(fd).copied().flatten()
+    }
+
+    /// Opens a file on the lowest free descriptor
+    pub fn insert(&mut self, file: File) -> usize {
+        match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                fd
+            }
+            None => {
+                self.files.push(Some(file));
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// Closes a descriptor, returning the file if it was open
+    pub fn close(&mut self, fd: usize) -> Option<File> {
+        self.files.get_mut(fd)?.take()
+    }
+}
+
+#[derive(Debug)]
+pub struct Process {
+    pid: Pid,
+    address_space: Mutex<AddressSpace>,
+    image: LoadedImage,
+    stack_top: VirtAddr,
+    files: Mutex<FileTable>,
+    /// The x87 and SIMD registers, while the process isn't running
+    #[cfg(target_arch = "x86_64")]
+    fpu: Mutex<crate::arch::x86_64::fpu::FpuState>,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn image(&self) -> LoadedImage {
+        self.image
+    }
+
+    pub fn stack_top(&self) -> VirtAddr {
+        self.stack_top
+    }
+
+    pub fn address_space(&self) -> &Mutex<AddressSpace> {
+        &self.address_space
+    }
+
+    pub fn files(&self) -> &Mutex<FileTable> {
+        &self.files
+    }
+
+    /// Runs the process on the current CPU until it exits, returning its exit code
+    #[cfg(target_arch = "x86_64")]
+    pub fn run(self: &Arc<Self>) -> i64 {
+        use crate::{
+            arch::registers::control::{Cr3, Cr3Flags},
+            mm::paging::PhysFrame,
+        };
+
+        let previous = PhysFrame::from_start_address(Cr3::addr());
+        *CURRENT.get().lock() = Some(self.clone());
+        let previous_task = crate::sched::switch_to(crate::sched::TaskId::process(self.pid));
+        let code = unsafe {
+            self.address_space.lock().activate();
+            self.fpu.lock().restore();
+            let code = crate::arch::x86_64::syscall::enter_user(self.image.entry, self.stack_top);
+            self.fpu.lock().save();
+            Cr3::write(previous, Cr3Flags::empty());
+            code
+        };
+        crate::sched::switch_to(previous_task);
+        *CURRENT.get().lock() = None;
+        code
+    }
+}
+
+static NEXT_PID: AtomicU32 = AtomicU32::new(1);
+static PROCESSES: RwLock<Vec<Arc<Process>>> = RwLock::new(Vec::new());
+static CURRENT: PerCpu<Mutex<Option<Arc<Process>>>> = PerCpu::new([const { Mutex::new(None) }; MAX_CPUS]);
+
+/// Creates a process from an ELF executable, ready to [`Process::run`]
+pub fn spawn_from_elf(bytes: &[u8]) -> Result<Arc<Process>, SpawnError> {
+    let mut address_space = AddressSpace::new()?;
+    let image = Elf::parse(bytes)?.load(&mut address_space, USER_PIE_BASE)?;
+
+    address_space.dedup_read_only();
+
+    let stack_flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    address_space.map_anonymous(USER_STACK_TOP - USER_STACK_SIZE, USER_STACK_SIZE, stack_flags)?;
+
+    let process = Arc::new(Process {
+        pid: Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed)),
+        address_space: Mutex::new(address_space),
+        image,
+        stack_top: USER_STACK_TOP,
+        files: Mutex::new(FileTable::with_stdio()),
+        #[cfg(target_arch = "x86_64")]
+        fpu: Mutex::new(crate::arch::x86_64::fpu::FpuState::new()),
+    });
+    PROCESSES.write().push(process.clone());
+    Ok(process)
+}
+
+/// Returns the process running on the current CPU
+pub fn current() -> Option<Arc<Process>> {
+    CURRENT.get().lock().clone()
+}
+
+/// Resolves a page fault of the current process, returning whether it can carry on
+///
+/// Only writes to present user pages are resolved, see [`AddressSpace::handle_fault`].
+pub fn handle_page_fault(addr: VirtAddr, error_code: u64) -> bool {
+    const PRESENT: u64 = 1 << 0;
+    const WRITE: u64 = 1 << 1;
+    if error_code & (PRESENT | WRITE) != PRESENT | WRITE {
+        return false;
+    }
+    let Some(process) = CURRENT.get().try_lock().and_then(|current| current.clone()) else {
+        return false;
+    };
+    // The kernel may have faulted with the address space locked
+    let Some(mut address_space) = process.address_space.try_lock() else {
+        return false;
+    };
+    address_space.handle_fault(addr).unwrap_or(false)
+}
+
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.read().iter().find(|process| process.pid == pid).cloned()
+}
+
+pub fn processes() -> Vec<Arc<Process>> {
+    PROCESSES.read().clone()
+}
+
+/// Removes a process, its memory is freed once the last reference is dropped
+pub fn reap(pid: Pid) -> Option<Arc<Process>> {
+    let mut processes = PROCESSES.write();
+    let idx = processes.iter().position(|process| process.pid == pid)?;
+    Some(processes.remove(idx))
+}
@@ -0,0 +1,233 @@
+//! Sampling profiler
+//!
+//! While the profiler runs, every tick records the instruction it interrupted into a buffer of the
+//! CPU taking it, [`SAMPLES`] per CPU, after which further samples are counted as dropped. The
+//! `profile` shell command starts, stops and clears it, and [`report`] resolves the samples to the
+//! kernel functions or modules they are in and writes a flat profile, the functions with the most
+//! samples first. `profile` on the command line starts it as soon as the tick does, to measure the
+//! rest of boot.
+//!
+//! The tick is the only sampling interrupt for now, so samples come at [`HZ`](crate::time::tick::HZ)
+//! from the CPUs that take it, and code running with interrupts disabled shows up as the
+//! instruction that enabled them again.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+
+use crate::{
+    boot::Cmdline,
+    irq, kprintln,
+    percpu::{self, MAX_CPUS, PerCpu},
+};
+
+pub mod symbols;
+
+use symbols::{Demangled, SymbolTable};
+
+/// The samples kept per CPU
+pub const SAMPLES: usize = 8192;
+
+struct CpuSamples {
+    /// [`SAMPLES`] entries, allocated when the profiler first starts
+    buffer: AtomicPtr<AtomicUsize>,
+    len: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+static CPUS: PerCpu<CpuSamples> = PerCpu::new(
+    [const {
+        CpuSamples {
+            buffer: AtomicPtr::new(core::ptr::null_mut()),
+            len: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }; MAX_CPUS],
+);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Reads the kernel symbols, and starts the profiler if `profile` is on the command line
+///
+/// The symbols are read now rather than on first use, for the exception and panic reports.
+pub fn init(cmdline: Cmdline) {
+    if let Err(err) = SymbolTable::kernel() {
+        kprintln!(Info, "profile: no kernel symbols: {}", err);
+    }
+    if cmdline.flag("profile") {
+        start();
+    }
+}
+
+/// Starts recording samples, keeping the ones already recorded
+pub fn start() {
+    for cpu in percpu::cpus() {
+        let samples = CPUS.get_for(cpu.cpu_id as usize);
+        if samples.buffer.load(Ordering::Acquire).is_null() {
+            let buffer: Box<[AtomicUsize]> = (0..SAMPLES).map(|_| AtomicUsize::new(0)).collect();
+            // Never freed, as an interrupt may be writing to it at any time
+            samples.buffer.store(Box::leak(buffer).as_mut_ptr(), Ordering::Release);
+        }
+    }
+    RUNNING.store(true, Ordering::Release);
+}
+
+pub fn stop() {
+    RUNNING.store(false, Ordering::Release);
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Acquire)
+}
+
+/// Throws the recorded samples away
+pub fn clear() {
+    for samples in CPUS.iter() {
+        samples.len.store(0, Ordering::Relaxed);
+        samples.dropped.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Records where the tick interrupted the current CPU, called from the tick
+pub fn sample() {
+    if !RUNNING.load(Ordering::Acquire) {
+        return;
+    }
+    let Some(ip) = irq::interrupted_ip() else {
+        return;
+    };
+    let samples = CPUS.get();
+    let buffer = samples.buffer.load(Ordering::Acquire);
+    let len = samples.len.load(Ordering::Relaxed);
+    // CPUs brought up after the profiler started have no buffer
+    if buffer.is_null() || len >= SAMPLES {
+        samples.dropped.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    // SAFETY: The buffer has `SAMPLES` entries, and only this CPU appends to it
+    unsafe { (*buffer.add(len)).store(ip, Ordering::Relaxed) };
+    samples.len.store(len + 1, Ordering::Release);
+}
+
+/// Where a sample was taken
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Site {
+    Function(&'static str),
+    Module(String),
+    User,
+    Unknown,
+}
+
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Function(name) => write!(f, "{}", Demangled(name)),
+            Self::Module(name) => write!(f, "[module {}]", name),
+            Self::User => f.write_str("[user]"),
+            Self::Unknown => f.write_str("[unknown]"),
+        }
+    }
+}
+
+fn site(symbols: Option<&SymbolTable>, addr: usize) -> Site {
+    if let Some(func) = symbols.and_then(|symbols| symbols.resolve(addr)) {
+        return Site::Function(func.name);
+    }
+    if let Some(module) = crate::module::owner(addr) {
+        return Site::Module(String::from(module.name()));
+    }
+    if addr < crate::mm::mappings::KERNEL_MEM_START.as_usize() {
+        return Site::User;
+    }
+    Site::Unknown
+}
+
+/// Counts the samples of every key, returning the keys with the most samples first
+fn flat_profile<K: Ord>(samples: impl Iterator<Item = usize>, key: impl Fn(usize) -> K) -> Vec<(K, u64)> {
+    let mut counts = BTreeMap::new();
+    for addr in samples {
+        *counts.entry(key(addr)).or_insert(0) += 1;
+    }
+    let mut profile: Vec<_> = counts.into_iter().collect();
+    // Stable, so equal counts stay ordered by key
+    profile.sort_by(|(_, a), (_, b)| b.cmp(a));
+    profile
+}
+
+/// Returns the samples recorded on a CPU
+fn samples(cpu_id: usize) -> impl ExactSizeIterator<Item = usize> {
+    let samples = CPUS.get_for(cpu_id);
+    let buffer = samples.buffer.load(Ordering::Acquire);
+    let len = if buffer.is_null() {
+        0
+    } else {
+        samples.len.load(Ordering::Acquire)
+    };
+    // SAFETY: The first `len` entries are written, and the buffer is never freed
+    (0..len).map(move |idx| unsafe { (*buffer.add(idx)).load(Ordering::Relaxed) })
+}
+
+/// Writes the flat profile of the samples recorded so far
+pub fn report(out: &mut dyn fmt::Write) -> fmt::Result {
+    let cpus: Vec<usize> = (0..MAX_CPUS)
+        .filter(|cpu_id| !CPUS.get_for(*cpu_id).buffer.load(Ordering::Acquire).is_null())
+        .collect();
+    let total: usize = cpus.iter().map(|cpu_id| samples(*cpu_id).len()).sum();
+    let dropped: u64 = CPUS.iter().map(|samples| samples.dropped.load(Ordering::Relaxed)).sum();
+    writeln!(
+        out,
+        "# profile: {} samples on {} CPUs, {} dropped{}",
+        total,
+        cpus.len(),
+        dropped,
+        if is_running() { ", running" } else { "" }
+    )?;
+    let symbols = match SymbolTable::kernel() {
+        Ok(symbols) => Some(symbols),
+        Err(err) => {
+            writeln!(out, "# no kernel symbols: {}", err)?;
+            None
+        }
+    };
+    if total == 0 {
+        return Ok(());
+    }
+    let all = cpus.iter().flat_map(|cpu_id| samples(*cpu_id));
+    writeln!(out, "{:>8} {:>6}  function", "samples", "%")?;
+    for (site, count) in flat_profile(all, |addr| site(symbols, addr)) {
+        let permille = count as usize * 1000 / total;
+        writeln!(out, "{:>8} {:>4}.{}  {}", count, permille / 10, permille % 10, site)?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+    use crate::profile::symbols::Function;
+
+    #[test]
+    fn profile_flat() {
+        let symbols = SymbolTable::new(alloc::vec![
+            Function {
+                start: 0x1000,
+                end: 0x1100,
+                name: "a",
+            },
+            Function {
+                start: 0x2000,
+                end: 0x2100,
+                name: "b",
+            },
+        ]);
+        let samples = [0x1000, 0x2010, 0x10FF, 0x400, 0x2000, 0x1050, 0x1100];
+        let resolve = |addr| symbols.resolve(addr).map(|func| func.name);
+        assert_eq!(
+            flat_profile(samples.into_iter(), resolve),
+            [(Some("a"), 3), (None, 2), (Some("b"), 2)]
+        );
+        assert_eq!(flat_profile(core::iter::empty(), resolve), []);
+    }
+}
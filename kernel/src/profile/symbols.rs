@@ -0,0 +1,285 @@
+//! The functions of the kernel image
+//!
+//! The bootloader passes the ELF file of the kernel on, so its `.symtab` maps addresses back to
+//! the functions they are in without a separate symbol file. The symbols are relative to where the
+//! kernel was linked, and moved by however far the text was relocated at boot. Kernels built with
+//! the symbol table stripped have no functions to resolve.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use crate::{
+    elf::{
+        Elf, ElfError,
+        section::{SHT_SYMTAB, STT_FUNC},
+    },
+    sync::Once,
+};
+
+/// The linker script symbol at the start of the text, which gives how far it was relocated
+const ANCHOR: &str = "_kernel_text_start";
+
+static KERNEL: Once<Result<SymbolTable, SymbolError>> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolError {
+    /// The bootloader didn't pass on the kernel file
+    NoKernelFile,
+    Elf(ElfError),
+    /// The kernel was built without a symbol table
+    Stripped,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoKernelFile => f.write_str("the bootloader didn't pass on the kernel file"),
+            Self::Elf(err) => write!(f, "failed to parse the kernel file: {:?}", err),
+            Self::Stripped => f.write_str("the kernel has no symbol table"),
+        }
+    }
+}
+
+impl core::error::Error for SymbolError {}
+
+impl From<ElfError> for SymbolError {
+    fn from(err: ElfError) -> Self {
+        Self::Elf(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Function {
+    pub start: usize,
+    /// The end of the function, or the start of the next one if its size is unknown
+    pub end: usize,
+    pub name: &'static str,
+}
+
+/// Functions sorted by address
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    functions: Vec<Function>,
+}
+
+impl SymbolTable {
+    pub fn new(mut functions: Vec<Function>) -> Self {
+        functions.sort_unstable_by_key(|func| func.start);
+        for idx in 1..functions.len() {
+            let prev = &functions[idx - 1];
+            if prev.end <= prev.start {
+                functions[idx - 1].end = functions[idx].start;
+            }
+        }
+        Self { functions }
+    }
+
+    /// Reads the functions of a kernel image whose text starts at `text_start`
+    pub fn parse(image: &'static [u8], text_start: usize) -> Result<Self, SymbolError> {
+        let elf = Elf::parse(image)?;
+        let symtab = elf
+            .section_headers()
+            .filter_map(Result::ok)
+            .find(|sh| sh.kind == SHT_SYMTAB)
+            .ok_or(SymbolError::Stripped)?;
+        let mut anchor = None;
+        let mut functions = Vec::new();
+        for symbol in elf.symbols(&symtab)? {
+            let symbol = symbol?;
+            if symbol.name == ANCHOR {
+                anchor = Some(symbol.value);
+            } else if symbol.kind == STT_FUNC && symbol.value != 0 {
+                functions.push(Function {
+                    start: symbol.value,
+                    end: symbol.value + symbol.size,
+                    name: symbol.name,
+                });
+            }
+        }
+        let slide = text_start.wrapping_sub(anchor.ok_or(SymbolError::Stripped)?);
+        for func in &mut functions {
+            func.start = func.start.wrapping_add(slide);
+            func.end = func.end.wrapping_add(slide);
+        }
+        Ok(Self::new(functions))
+    }
+
+    /// Returns the functions of the running kernel, read on first use
+    pub fn kernel() -> Result<&'static Self, SymbolError> {
+        KERNEL
+            .call_once(|| {
+                let image = crate::boot::kernel_file().ok_or(SymbolError::NoKernelFile)?;
+                Self::parse(image, text_start())
+            })
+            .as_ref()
+            .map_err(|err| *err)
+    }
+
+    /// Returns the functions of the running kernel if they were read already
+    ///
+    /// Reading them allocates, which exception and panic context can't.
+    pub fn loaded() -> Option<&'static Self> {
+        KERNEL.get()?.as_ref().ok()
+    }
+
+    /// Returns the function an address is in
+    pub fn resolve(&self, addr: usize) -> Option<&Function> {
+        let idx = self
+            .functions
+            .partition_point(|func| func.start <= addr)
+            .checked_sub(1)?;
+        let func = &self.functions[idx];
+        (addr < func.end).then_some(func)
+    }
+
+    pub fn len(&self) -> usize {
+        self.functions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+}
+
+#[cfg(not(feature = "test"))]
+fn text_start() -> usize {
+    unsafe extern "C" {
+        static _kernel_text_start: u8;
+    }
+    (&raw const _kernel_text_start) as usize
+}
+
+/// Host builds aren't linked with the linker script
+#[cfg(feature = "test")]
+fn text_start() -> usize {
+    0
+}
+
+/// Formats a legacy mangled Rust symbol as its path without the hash, other names as they are
+pub struct Demangled<'a>(pub &'a str);
+
+impl fmt::Display for Demangled<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(mut rest) = self.0.strip_prefix("_ZN").and_then(|name| name.strip_suffix('E')) else {
+            return f.write_str(self.0);
+        };
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let Some(len) = rest[..digits]
+                .parse::<usize>()
+                .ok()
+                .filter(|len| digits + len <= rest.len())
+            else {
+                return f.write_str(self.0);
+            };
+            segments.push(&rest[digits..digits + len]);
+            rest = &rest[digits + len..];
+        }
+        let is_hash = |segment: &&str| {
+            segment.len() == 17 && segment.starts_with('h') && segment[1..].bytes().all(|b| b.is_ascii_hexdigit())
+        };
+        if segments.last().is_some_and(is_hash) {
+            segments.pop();
+        }
+        for (idx, segment) in segments.iter().enumerate() {
+            if idx != 0 {
+                f.write_str("::")?;
+            }
+            write_segment(f, segment)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes a path segment with the escapes of the legacy mangling undone
+fn write_segment(f: &mut fmt::Formatter<'_>, segment: &str) -> fmt::Result {
+    const ESCAPES: &[(&str, &str)] = &[
+        ("$LT$", "<"),
+        ("$GT$", ">"),
+        ("$RF$", "&"),
+        ("$BP$", "*"),
+        ("$C$", ","),
+        ("$SP$", "@"),
+        ("$u20$", " "),
+        ("$u27$", "'"),
+        ("$u3b$", ";"),
+        ("$u5b$", "["),
+        ("$u5d$", "]"),
+        ("$u7b$", "{"),
+        ("$u7d$", "}"),
+        ("$u7e$", "~"),
+        ("..", "::"),
+    ];
+    // Segments that would start with an escape get an underscore in front
+    let mut rest = if segment.starts_with("_$") {
+        &segment[1..]
+    } else {
+        segment
+    };
+    'outer: while let Some(c) = rest.chars().next() {
+        for (escape, replacement) in ESCAPES {
+            if let Some(after) = rest.strip_prefix(escape) {
+                f.write_str(replacement)?;
+                rest = after;
+                continue 'outer;
+            }
+        }
+        write!(f, "{}", c)?;
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn symbols_resolve() {
+        let table = SymbolTable::new(alloc::vec![
+            Function {
+                start: 0x2000,
+                end: 0x2000,
+                name: "b",
+            },
+            Function {
+                start: 0x1000,
+                end: 0x1100,
+                name: "a",
+            },
+            Function {
+                start: 0x3000,
+                end: 0x3010,
+                name: "c",
+            },
+        ]);
+        let name = |addr| table.resolve(addr).map(|func| func.name);
+        assert_eq!(name(0xFFF), None);
+        assert_eq!(name(0x1000), Some("a"));
+        assert_eq!(name(0x1100), None);
+        // Without a size, up to the next function
+        assert_eq!(name(0x2FFF), Some("b"));
+        assert_eq!(name(0x300F), Some("c"));
+        assert_eq!(name(0x3010), None);
+
+        let demangled = |name| Demangled(name).to_string();
+        assert_eq!(
+            demangled("_ZN13hadron_kernel4util6kprint5flush17h0123456789abcdefE"),
+            "hadron_kernel::util::kprint::flush"
+        );
+        assert_eq!(
+            demangled("_ZN4core3ptr46drop_in_place$LT$alloc..vec..Vec$LT$u8$GT$$GT$17h0123456789abcdefE"),
+            "core::ptr::drop_in_place<alloc::vec::Vec<u8>>"
+        );
+        assert_eq!(
+            demangled("_ZN57_$LT$hadron_kernel..Foo$u20$as$u20$core..fmt..Display$GT$3fmt17h0123456789abcdefE"),
+            "<hadron_kernel::Foo as core::fmt::Display>::fmt"
+        );
+        assert_eq!(demangled("memcpy"), "memcpy");
+        assert_eq!(demangled("_ZN99broken"), "_ZN99broken");
+    }
+}
@@ -0,0 +1,73 @@
+//! The ChaCha20 block function, as specified in RFC 8439
+
+/// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646E, 0x7962_2D32, 0x6B20_6574];
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// Returns the keystream block for a key, block counter and nonce
+pub fn block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u32; 16] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter;
+    input[13..].copy_from_slice(nonce);
+
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, input) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(input);
+    }
+    state
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chacha_block() {
+        // The block function test vector of RFC 8439, section 2.3.2
+        let key = core::array::from_fn(|idx| u32::from_le_bytes(core::array::from_fn(|byte| (idx * 4 + byte) as u8)));
+        let block = block(&key, 1, &[0x0900_0000, 0x4A00_0000, 0]);
+        assert_eq!(
+            block,
+            [
+                0xE4E7_F110,
+                0x1559_3BD1,
+                0x1FDD_0F50,
+                0xC471_20A3,
+                0xC7F4_D1C7,
+                0x0368_C033,
+                0x9AAA_2204,
+                0x4E6C_D4C3,
+                0x4664_82D2,
+                0x09AA_9F07,
+                0x05D7_C214,
+                0xA202_8BD9,
+                0xD19C_12B5,
+                0xB94E_16DE,
+                0xE883_D0CB,
+                0x4E3C_50A2,
+            ]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! Entropy from CPU execution time jitter
+//!
+//! The time a short loop over a buffer larger than the L1 cache takes varies with cache and TLB
+//! state, branch prediction, interrupts and, under a hypervisor, the host. The collector times
+//! that loop with the TSC and folds the deltas together. It follows the jitterentropy design Linux
+//! uses: deltas that are zero, or whose first or second derivative is zero, are stuck and get no
+//! credit, and the raw deltas go through the repetition count and adaptive proportion tests of
+//! NIST SP 800-90B, which catch a TSC that is too coarse or a loop the CPU has stopped varying.
+//!
+//! Each non-stuck sample is credited with [`BITS_PER_SAMPLE`], far below what measurements on real
+//! hardware show, as the timing depends on a machine this kernel can't characterize.
+
+use core::fmt;
+
+use alloc::{vec, vec::Vec};
+
+/// The entropy credited to each non-stuck sample
+pub const BITS_PER_SAMPLE: f32 = 1.0 / 8.0;
+/// The non-stuck samples folded into each output word
+const SAMPLES_PER_WORD: usize = 64;
+/// How many samples may be taken for each one that is needed, before giving up
+const MAX_SAMPLE_RATIO: usize = 4;
+/// The size of the buffer walked in each sample, larger than the L1 data cache
+const MEMORY_SIZE: usize = 64 * 1024;
+/// Bytes touched per sample
+const MEMORY_ACCESSES: usize = 128;
+/// Just over a cache line, so every access touches a different one
+const MEMORY_STRIDE: usize = 67;
+
+/// How many identical deltas in a row fail the repetition count test, `1 + 20 / H`
+const REPETITION_CUTOFF: u32 = 1 + (20.0 / BITS_PER_SAMPLE) as u32;
+/// The samples in each window of the adaptive proportion test
+const PROPORTION_WINDOW: u32 = 512;
+/// How many samples in a window may equal its first, before the test fails
+///
+/// The binomial tail for an entropy of [`BITS_PER_SAMPLE`] has a false positive rate of about 2^-20.
+const PROPORTION_CUTOFF: u32 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JitterError {
+    /// The same delta kept repeating
+    Repetition,
+    /// Too many deltas in a window were the same
+    Proportion,
+    /// Too many samples were stuck, the timer is likely too coarse
+    Stuck,
+}
+
+impl fmt::Display for JitterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Repetition => f.write_str("repetition count test failed"),
+            Self::Proportion => f.write_str("adaptive proportion test failed"),
+            Self::Stuck => f.write_str("too many stuck samples, the timer is too coarse"),
+        }
+    }
+}
+
+impl core::error::Error for JitterError {}
+
+/// The online health tests run on every sample
+#[derive(Debug, Default)]
+struct HealthTests {
+    last_delta: u64,
+    last_delta2: u64,
+    repetitions: u32,
+    window_first: u64,
+    window_len: u32,
+    window_matches: u32,
+}
+
+impl HealthTests {
+    /// Returns whether a delta is stuck, updating the derivatives
+    fn stuck(&mut self, delta: u64) -> bool {
+        let delta2 = delta.wrapping_sub(self.last_delta);
+        let delta3 = delta2.wrapping_sub(self.last_delta2);
+        self.last_delta = delta;
+        self.last_delta2 = delta2;
+        delta == 0 || delta2 == 0 || delta3 == 0
+    }
+
+    /// Runs the repetition count and adaptive proportion tests on a delta
+    fn check(&mut self, delta: u64) -> Result<(), JitterError> {
+        if delta == self.last_delta {
+            self.repetitions += 1;
+            if self.repetitions >= REPETITION_CUTOFF {
+                return Err(JitterError::Repetition);
+            }
+        } else {
+            self.repetitions = 1;
+        }
+
+        if self.window_len == 0 {
+            self.window_first = delta;
+        } else if delta == self.window_first {
+            self.window_matches += 1;
+            if self.window_matches >= PROPORTION_CUTOFF {
+                return Err(JitterError::Proportion);
+            }
+        }
+        self.window_len += 1;
+        if self.window_len == PROPORTION_WINDOW {
+            self.window_len = 0;
+            self.window_matches = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Folds timing samples into words with at least the requested entropy
+///
+/// Words are finished after [`SAMPLES_PER_WORD`] non-stuck samples, stuck ones are still mixed in.
+fn fold(bits: u32, mut sample: impl FnMut() -> u64) -> Result<Vec<u64>, JitterError> {
+    let needed = (bits as f32 / BITS_PER_SAMPLE) as usize;
+    let mut tests = HealthTests::default();
+    let mut words = Vec::with_capacity(needed.div_ceil(SAMPLES_PER_WORD));
+    let mut word = 0u64;
+    let mut good = 0;
+    for _ in 0..needed * MAX_SAMPLE_RATIO {
+        let delta = sample();
+        // The derivatives are updated by the stuck test, so the repetition count runs first
+        tests.check(delta)?;
+        let stuck = tests.stuck(delta);
+        word = word.rotate_left(7) ^ delta;
+        if stuck {
+            continue;
+        }
+        good += 1;
+        if good % SAMPLES_PER_WORD == 0 {
+            words.push(word);
+            word = 0;
+        }
+        if good >= needed {
+            return Ok(words);
+        }
+    }
+    Err(JitterError::Stuck)
+}
+
+/// Collects at least `bits` of entropy by timing memory accesses
+#[cfg(target_arch = "x86_64")]
+pub fn collect(bits: u32) -> Result<Vec<u64>, JitterError> {
+    use crate::time::tsc;
+
+    let mut memory = vec![0u8; MEMORY_SIZE];
+    let mut idx = 0;
+    fold(bits, || {
+        let start = tsc::read();
+        for _ in 0..MEMORY_ACCESSES {
+            idx = (idx + MEMORY_STRIDE) % MEMORY_SIZE;
+            memory[idx] = core::hint::black_box(memory[idx].wrapping_add(1));
+        }
+        tsc::read().wrapping_sub(start)
+    })
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_health() {
+        // A constant timer fails the repetition count test
+        assert_eq!(fold(64, || 100), Err(JitterError::Repetition));
+
+        // A steadily increasing delta is always stuck
+        let mut ramp = 0;
+        assert_eq!(
+            fold(64, || {
+                ramp += 3;
+                ramp
+            }),
+            Err(JitterError::Stuck)
+        );
+
+        // Small values from an LCG pass, and give a word per 64 good samples
+        let mut state = 1u64;
+        let words = fold(64, || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            100 + (state >> 60)
+        })
+        .unwrap();
+        assert_eq!(words.len(), 8);
+
+        // Mostly one value, but never long runs of it
+        let mut tests = HealthTests::default();
+        let result = (0..PROPORTION_WINDOW).try_for_each(|idx| tests.check(if idx % 60 == 59 { 7 } else { 5 }));
+        assert_eq!(result, Err(JitterError::Proportion));
+    }
+}
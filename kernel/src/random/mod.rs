@@ -0,0 +1,327 @@
+//! The kernel random number generator
+//!
+//! Entropy sources feed a pool with [`add_entropy`], crediting the bits of entropy they vouch for.
+//! Once the pool holds [`RESEED_BITS`] new bits, it reseeds a ChaCha20 generator, which hands out
+//! random bytes with [`fill_bytes`] and rekeys after every request so earlier output can't be
+//! recovered from its state.
+//!
+//! RDRAND is the preferred seed. Without it, and without a device like virtio-rng to provide one,
+//! the generator is seeded from CPU execution time jitter, see [`jitter`]. That is better than
+//! nothing, but weak, so [`health`] tells security sensitive users how much to trust the output,
+//! and [`warn_if_weak`] logs a warning for them.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::{kprintln, sync::Mutex};
+
+pub mod chacha;
+pub mod jitter;
+
+/// How many new bits of entropy the pool collects before it reseeds the generator
+pub const RESEED_BITS: u32 = 256;
+
+/// Separates the keystreams used for mixing the pool, rekeying and output
+const NONCE_POOL: [u32; 3] = [0x706F_6F6C, 0, 0];
+const NONCE_REKEY: [u32; 3] = [0x6B65_7921, 0, 0];
+const NONCE_OUTPUT: [u32; 3] = [0, 0, 0];
+
+/// Where entropy comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntropySource {
+    /// The RDRAND instruction
+    Rdrand,
+    /// A hardware random number generator device, like virtio-rng
+    Device(&'static str),
+    /// The execution time jitter collector
+    Jitter,
+    /// Timestamps and other values that are hard to guess, credited with nothing
+    Timing,
+}
+
+impl EntropySource {
+    /// Whether the source is a hardware generator, which is trusted for a strong seed
+    fn is_hardware(&self) -> bool {
+        matches!(self, Self::Rdrand | Self::Device(_))
+    }
+
+    /// The index of the source in the credit counters, which don't tell devices apart
+    fn index(&self) -> usize {
+        match self {
+            Self::Rdrand => 0,
+            Self::Device(_) => 1,
+            Self::Jitter => 2,
+            Self::Timing => 3,
+        }
+    }
+}
+
+const SOURCE_NAMES: [&str; 4] = ["rdrand", "device", "jitter", "timing"];
+
+/// How much the output of the generator can be trusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Health {
+    /// No source passed its health tests, the output is predictable
+    Unseeded,
+    /// Seeded from jitter and timing only
+    Weak,
+    /// Seeded with [`RESEED_BITS`] bits from a hardware generator
+    Strong,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Unseeded => "unseeded",
+            Self::Weak => "weak",
+            Self::Strong => "strong",
+        })
+    }
+}
+
+/// The entropy pool, a 256 bit state that input is compressed into with ChaCha20
+struct Pool {
+    state: [u32; 8],
+    /// The number of chunks absorbed, separating their keystreams
+    absorbed: u32,
+    /// The entropy credited since the last reseed
+    pending_bits: u32,
+    /// The entropy credited from hardware sources since the last reseed
+    pending_hardware_bits: u32,
+}
+
+impl Pool {
+    const fn new() -> Self {
+        Self {
+            state: [0; 8],
+            absorbed: 0,
+            pending_bits: 0,
+            pending_hardware_bits: 0,
+        }
+    }
+
+    /// Mixes bytes into the pool
+    ///
+    /// Each 32 byte chunk is XORed into the state and used as a ChaCha20 key, and the keystream
+    /// block is XORed back into the state, so the output depends on every input but can't be run
+    /// backwards.
+    fn absorb(&mut self, data: &[u8]) {
+        for chunk in data.chunks(32) {
+            let mut key = self.state;
+            for (idx, byte) in chunk.iter().enumerate() {
+                key[idx / 4] ^= (*byte as u32) << (idx % 4 * 8);
+            }
+            let block = chacha::block(&key, self.absorbed, &NONCE_POOL);
+            for (word, new) in self.state.iter_mut().zip(block) {
+                *word ^= new;
+            }
+            self.absorbed = self.absorbed.wrapping_add(1);
+        }
+    }
+}
+
+/// A ChaCha20 keystream generator with fast key erasure
+struct Generator {
+    key: [u32; 8],
+}
+
+impl Generator {
+    /// Replaces the key with the start of a keystream block that is never output
+    fn rekey(&mut self) {
+        let block = chacha::block(&self.key, 0, &NONCE_REKEY);
+        self.key.copy_from_slice(&block[..8]);
+    }
+
+    /// Replaces the key with one derived from the old key and the pool
+    fn reseed(&mut self, pool: &[u32; 8]) {
+        for (word, pool) in self.key.iter_mut().zip(pool) {
+            *word ^= pool;
+        }
+        self.rekey();
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for (counter, chunk) in buf.chunks_mut(64).enumerate() {
+            let block = chacha::block(&self.key, counter as u32, &NONCE_OUTPUT);
+            for (idx, byte) in chunk.iter_mut().enumerate() {
+                *byte = (block[idx / 4] >> (idx % 4 * 8)) as u8;
+            }
+        }
+        // Erases the key, so this request can't be recovered from a later state
+        self.rekey();
+    }
+}
+
+struct State {
+    pool: Pool,
+    generator: Generator,
+    /// The entropy credited by each kind of source since boot
+    credited: [u64; 4],
+    reseeds: u64,
+    jitter: Option<Result<(), jitter::JitterError>>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    pool: Pool::new(),
+    generator: Generator { key: [0; 8] },
+    credited: [0; 4],
+    reseeds: 0,
+    jitter: None,
+});
+static HEALTH: AtomicU8 = AtomicU8::new(Health::Unseeded as u8);
+
+/// Returns how much the output of the generator can be trusted
+pub fn health() -> Health {
+    match HEALTH.load(Ordering::Relaxed) {
+        2 => Health::Strong,
+        1 => Health::Weak,
+        _ => Health::Unseeded,
+    }
+}
+
+/// Logs a warning if the generator isn't strongly seeded, returning whether it is weak
+///
+/// For features whose security depends on unpredictable values, like KASLR or stack canaries.
+pub fn warn_if_weak(feature: &str) -> bool {
+    match health() {
+        Health::Strong => false,
+        health => {
+            kprintln!(
+                Warn,
+                "random: {} relies on a {} random seed, it can be predicted",
+                feature,
+                health
+            );
+            true
+        }
+    }
+}
+
+/// Mixes data into the pool, crediting it with `bits` of entropy
+///
+/// The generator is reseeded once enough entropy was credited.
+pub fn add_entropy(source: EntropySource, data: &[u8], bits: u32) {
+    let mut state = STATE.lock();
+    let state = &mut *state;
+    state.pool.absorb(data);
+    let bits = if source == EntropySource::Timing { 0 } else { bits };
+    state.pool.pending_bits += bits;
+    if source.is_hardware() {
+        state.pool.pending_hardware_bits += bits;
+    }
+    state.credited[source.index()] += bits as u64;
+
+    if state.pool.pending_bits >= RESEED_BITS {
+        let health = if state.pool.pending_hardware_bits >= RESEED_BITS {
+            Health::Strong
+        } else {
+            Health::Weak
+        };
+        state.generator.reseed(&state.pool.state);
+        state.pool.pending_bits = 0;
+        state.pool.pending_hardware_bits = 0;
+        state.reseeds += 1;
+        HEALTH.fetch_max(health as u8, Ordering::Relaxed);
+    }
+}
+
+/// Fills a buffer with random bytes
+///
+/// Check [`health`] before relying on them for anything security sensitive.
+pub fn fill_bytes(buf: &mut [u8]) {
+    STATE.lock().generator.fill(buf);
+}
+
+pub fn next_u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+/// Seeds the generator from RDRAND, or from execution time jitter without it
+pub fn init() {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use crate::arch::x86_64::random;
+
+        add_entropy(EntropySource::Timing, &crate::time::tsc::read().to_le_bytes(), 0);
+        // Half the bits are credited, in case RDRAND is weaker than it claims
+        let mut values = [0u64; 8];
+        if values
+            .iter_mut()
+            .all(|value| random::rdrand().map(|random| *value = random).is_some())
+        {
+            add_entropy(EntropySource::Rdrand, bytemuck::cast_slice(&values), RESEED_BITS);
+        } else {
+            let result = jitter::collect(RESEED_BITS)
+                .map(|words| add_entropy(EntropySource::Jitter, bytemuck::cast_slice(&words), RESEED_BITS));
+            if let Err(err) = result {
+                kprintln!(Warn, "random: jitter entropy: {}", err);
+            }
+            STATE.lock().jitter = Some(result);
+        }
+    }
+    if health() == Health::Unseeded {
+        // Still better than the all zero key
+        let mut state = STATE.lock();
+        let pool = state.pool.state;
+        state.generator.reseed(&pool);
+    }
+    kprintln!(Info, "random: seeded, {}", health());
+    crate::stats::register("random", dump_stats);
+
+    // The layout was randomized before the heap existed, from RDRAND or TSC jitter directly
+    #[cfg(target_arch = "x86_64")]
+    if *crate::mm::layout::layout() != crate::mm::layout::KernelLayout::FIXED
+        && crate::arch::x86_64::random::early_was_weak()
+    {
+        kprintln!(Warn, "random: kaslr relies on a weak random seed, it can be predicted");
+    }
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    let state = STATE.lock();
+    writeln!(out, "health: {}", health())?;
+    writeln!(
+        out,
+        "reseeds: {}, pending: {} bits",
+        state.reseeds, state.pool.pending_bits
+    )?;
+    for (name, bits) in SOURCE_NAMES.iter().zip(state.credited) {
+        writeln!(out, "{}: {} bits", name, bits)?;
+    }
+    match state.jitter {
+        Some(Ok(())) => writeln!(out, "jitter health tests: passed"),
+        Some(Err(err)) => writeln!(out, "jitter health tests: {}", err),
+        None => writeln!(out, "jitter health tests: not run"),
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_generator() {
+        let mut pool = Pool::new();
+        pool.absorb(b"seed");
+        let seeded = pool.state;
+        pool.absorb(b"seed");
+        assert_ne!(
+            pool.state, seeded,
+            "absorbing the same input twice must change the pool"
+        );
+
+        let mut generator = Generator { key: [0; 8] };
+        generator.reseed(&pool.state);
+        let mut first = [0u8; 100];
+        let mut second = [0u8; 100];
+        generator.fill(&mut first);
+        generator.fill(&mut second);
+        assert_ne!(first, second, "the key must change after every request");
+        assert_ne!(first[..32], first[64..96]);
+    }
+}
@@ -0,0 +1,101 @@
+//! Load balancing
+//!
+//! Every CPU runs the work queued on it, so a CPU that interrupts keep scheduling work on can fall
+//! behind while the others spin. Every [`INTERVAL`] a timer queues a balancing pass, which
+//! finds the online CPUs with the most and the least work queued and moves half of the difference
+//! over, as long as the difference is at least [`IMBALANCE`] and the work may run there, see
+//! [`Work::set_affinity`](crate::workqueue::Work::set_affinity). Pinning busy work with its
+//! affinity keeps it, and whatever it is balanced against, off of latency-sensitive CPUs.
+//!
+//! A CPU that goes offline has all of its work moved to the other online CPUs first, ignoring the
+//! affinity of work that may only run on CPUs that are offline.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    kprintln,
+    percpu::{self, HotplugEvent, MAX_CPUS},
+    time::Timer,
+    workqueue::{self, Work},
+};
+
+/// The time between two balancing passes
+pub const INTERVAL: Duration = Duration::from_millis(100);
+/// The smallest difference in queued work between two CPUs that is balanced
+pub const IMBALANCE: usize = 2;
+
+static BALANCE: Work = Work::new(|_| balance(), 0);
+static PASSES: AtomicU64 = AtomicU64::new(0);
+static MIGRATED: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    if let Err(err) = Timer::after(INTERVAL, expired, 0) {
+        kprintln!(Warn, "balance: {}", err);
+    }
+    percpu::register_notifier("balance", hotplug);
+    crate::stats::register("balance", dump_stats);
+}
+
+/// Queues a balancing pass and arms the timer for the next one
+fn expired(_data: usize) {
+    workqueue::schedule_work(&BALANCE);
+    _ = Timer::after(INTERVAL, expired, 0);
+}
+
+/// Returns the busiest and the idlest CPU of `(cpu_id, queued)` pairs, and how much work to move
+/// from one to the other, if they are out of balance
+fn plan(loads: &[(usize, usize)]) -> Option<(usize, usize, usize)> {
+    let (busiest, most) = *loads.iter().max_by_key(|(_, queued)| *queued)?;
+    let (idlest, least) = *loads.iter().min_by_key(|(_, queued)| *queued)?;
+    let difference = most - least;
+    (difference >= IMBALANCE).then_some((busiest, idlest, difference / 2))
+}
+
+fn balance() {
+    PASSES.fetch_add(1, Ordering::Relaxed);
+    let mut loads = [(0, 0); MAX_CPUS];
+    let mut count = 0;
+    for cpu in percpu::cpus().filter(|cpu| cpu.is_online()) {
+        let cpu_id = cpu.cpu_id as usize;
+        loads[count] = (cpu_id, workqueue::queued(cpu_id));
+        count += 1;
+    }
+    if let Some((from, to, amount)) = plan(&loads[..count]) {
+        let moved = workqueue::migrate(from, to, amount);
+        MIGRATED.fetch_add(moved as u64, Ordering::Relaxed);
+    }
+}
+
+fn hotplug(cpu_id: usize, event: HotplugEvent) {
+    if event == HotplugEvent::Offline {
+        let moved = workqueue::evacuate(cpu_id);
+        MIGRATED.fetch_add(moved as u64, Ordering::Relaxed);
+    }
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(
+        out,
+        "passes: {}, migrated: {}",
+        PASSES.load(Ordering::Relaxed),
+        MIGRATED.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn balance_plan() {
+        assert_eq!(plan(&[]), None);
+        assert_eq!(plan(&[(0, 5)]), None);
+        assert_eq!(plan(&[(0, 3), (1, 2)]), None);
+        assert_eq!(plan(&[(0, 1), (1, 9), (2, 4)]), Some((1, 0, 4)));
+        assert_eq!(plan(&[(0, 0), (3, 3)]), Some((3, 0, 1)));
+    }
+}
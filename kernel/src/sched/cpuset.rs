@@ -0,0 +1,98 @@
+//! Sets of CPUs, used as the affinity masks of tasks
+
+use core::fmt;
+
+use crate::percpu::MAX_CPUS;
+
+const _: () = assert!(MAX_CPUS <= u64::BITS as usize, "a CpuSet has a bit for every CPU");
+
+/// A set of CPU ids, a bit for each of the [`MAX_CPUS`] CPUs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CpuSet(u64);
+
+impl CpuSet {
+    pub const EMPTY: Self = Self(0);
+    pub const ALL: Self = Self(u64::MAX);
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Returns the set with only the given CPU
+    pub const fn single(cpu_id: usize) -> Self {
+        Self::EMPTY.with(cpu_id)
+    }
+
+    /// Returns the set with the given CPU added
+    pub const fn with(self, cpu_id: usize) -> Self {
+        assert!(cpu_id < MAX_CPUS, "CPU id exceeds MAX_CPUS");
+        Self(self.0 | 1 << cpu_id)
+    }
+
+    /// Returns the set with the given CPU removed
+    pub const fn without(self, cpu_id: usize) -> Self {
+        assert!(cpu_id < MAX_CPUS, "CPU id exceeds MAX_CPUS");
+        Self(self.0 & !(1 << cpu_id))
+    }
+
+    pub const fn contains(self, cpu_id: usize) -> bool {
+        cpu_id < MAX_CPUS && self.0 & 1 << cpu_id != 0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns the CPU ids in the set, in ascending order
+    pub fn iter(self) -> impl Iterator<Item = usize> {
+        (0..MAX_CPUS).filter(move |cpu_id| self.contains(*cpu_id))
+    }
+}
+
+/// Formats the set as a list of ranges, like `0-3,6`
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        let mut cpus = self.iter().peekable();
+        while let Some(start) = cpus.next() {
+            let mut end = start;
+            while cpus.next_if_eq(&(end + 1)).is_some() {
+                end += 1;
+            }
+            if !first {
+                f.write_str(",")?;
+            }
+            first = false;
+            match end - start {
+                0 => write!(f, "{}", start)?,
+                _ => write!(f, "{}-{}", start, end)?,
+            }
+        }
+        if first {
+            f.write_str("none")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::{string::ToString, vec::Vec};
+
+    use super::*;
+
+    #[test]
+    fn cpuset_ops() {
+        let set = CpuSet::single(0).with(1).with(2).with(5).with(63);
+        assert!(set.contains(5) && !set.contains(4) && !set.contains(MAX_CPUS));
+        assert_eq!(set.iter().collect::<Vec<_>>(), [0, 1, 2, 5, 63]);
+        assert_eq!(set.to_string(), "0-2,5,63");
+        assert_eq!(set.without(1).to_string(), "0,2,5,63");
+        assert_eq!(CpuSet::EMPTY.to_string(), "none");
+        assert!(CpuSet::single(3).without(3).is_empty());
+    }
+}
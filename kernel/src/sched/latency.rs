@@ -0,0 +1,262 @@
+//! Latency budgets
+//!
+//! Code that mustn't hold up its CPU for long, like interrupt handlers and sections with interrupts
+//! disabled, can be given a [`Budget`]. The time spent in it is measured with the TSC, and a section
+//! that takes longer than its budget logs a warning with a backtrace, so a stutter can be pinned on
+//! whoever caused it instead of on whatever it delayed. IRQ handlers get one with
+//! [`irq::set_budget`](crate::irq::set_budget), work items with
+//! [`Work::with_budget`](crate::workqueue::Work::with_budget), and any other section with
+//! [`Budget::enter`].
+//!
+//! The backtrace at the end of a section shows who ran it, not what took so long. The NMI
+//! [watchdog](crate::arch::x86_64::watchdog) checks the open sections of its CPU, and captures where
+//! the CPU is if it is still in one past its budget, which the warning shows as well. Only the
+//! [`MAX_DEPTH`] outermost nested sections are checked, and the watchdog only fires about every
+//! second, so it only catches sections that overrun by a lot.
+//!
+//! Every overrun is counted in the `latency` statistics, but only the 1st, 2nd, 4th, 8th and so on
+//! of each budget are logged, so a handler that overruns on every interrupt doesn't flood the log.
+//! The counts are kept for up to [`MAX_BUDGETS`] budgets, since interrupt handlers can't allocate,
+//! and the overruns of any more are logged every time.
+
+use core::{
+    fmt,
+    marker::PhantomData,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+    arch::{
+        instructions::interrupts,
+        x86_64::core::idt::{Backtrace, ExceptionFrame},
+    },
+    kprintln,
+    percpu::{MAX_CPUS, PerCpu},
+    sync::{Mutex, cell::RacyCell},
+    time::tsc,
+};
+
+/// How many nested sections of a CPU the watchdog checks
+pub const MAX_DEPTH: usize = 8;
+/// How many budgets the overruns are counted for
+pub const MAX_BUDGETS: usize = 64;
+
+/// The longest a section may take, and the name it is reported as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Budget {
+    pub name: &'static str,
+    pub limit_ns: u64,
+}
+
+impl Budget {
+    pub const fn new(name: &'static str, limit_ns: u64) -> Self {
+        Self { name, limit_ns }
+    }
+
+    /// Starts a section that should end within the budget, which ends when it is dropped
+    ///
+    /// Sections aren't measured until the TSC is calibrated.
+    pub fn enter(self) -> Section {
+        let khz = tsc::frequency_khz();
+        if khz == 0 {
+            return Section {
+                budget: self,
+                start: 0,
+                depth: MAX_DEPTH,
+                _cpu: PhantomData,
+            };
+        }
+        let cpu = SECTIONS.get();
+        let depth = cpu.depth.fetch_add(1, Ordering::Relaxed);
+        let start = tsc::read();
+        if let Some(slot) = cpu.slots.get(depth) {
+            slot.limit
+                .store(self.limit_ns.saturating_mul(khz) / 1_000_000, Ordering::Relaxed);
+            slot.start.store(start, Ordering::Release);
+        }
+        Section {
+            budget: self,
+            start,
+            depth,
+            _cpu: PhantomData,
+        }
+    }
+}
+
+/// A section of code running under a [`Budget`]
+pub struct Section {
+    budget: Budget,
+    /// The TSC at the start, zero if the section isn't measured
+    start: u64,
+    depth: usize,
+    /// Bound to the CPU it was started on
+    _cpu: PhantomData<*const ()>,
+}
+
+impl Drop for Section {
+    fn drop(&mut self) {
+        if self.start == 0 {
+            return;
+        }
+        let elapsed_ns = tsc::cycles_to_ns(tsc::read().wrapping_sub(self.start));
+        let cpu = SECTIONS.get();
+        let caught = cpu.slots.get(self.depth).and_then(|slot| {
+            // Closed first, so the watchdog doesn't touch the backtrace while it is taken
+            slot.start.store(0, Ordering::Release);
+            slot.caught
+                .swap(false, Ordering::Acquire)
+                .then(|| slot.backtrace.get_mut().take())
+                .flatten()
+        });
+        cpu.depth.fetch_sub(1, Ordering::Relaxed);
+        if elapsed_ns > self.budget.limit_ns {
+            overrun(self.budget, elapsed_ns, caught);
+        }
+    }
+}
+
+/// A section the watchdog can see
+struct Slot {
+    /// The TSC at the start, zero if the slot is unused
+    start: AtomicU64,
+    /// The budget in TSC cycles
+    limit: AtomicU64,
+    /// Whether the watchdog captured the backtrace
+    caught: AtomicBool,
+    backtrace: RacyCell<Option<Backtrace>>,
+}
+
+struct CpuSections {
+    depth: AtomicUsize,
+    slots: [Slot; MAX_DEPTH],
+}
+
+impl CpuSections {
+    const fn new() -> Self {
+        Self {
+            depth: AtomicUsize::new(0),
+            slots: [const {
+                Slot {
+                    start: AtomicU64::new(0),
+                    limit: AtomicU64::new(0),
+                    caught: AtomicBool::new(false),
+                    backtrace: RacyCell::new(None),
+                }
+            }; MAX_DEPTH],
+        }
+    }
+}
+
+static SECTIONS: PerCpu<CpuSections> = PerCpu::new([const { CpuSections::new() }; MAX_CPUS]);
+
+/// Captures where the CPU is for every open section past its budget, called from the watchdog NMI
+pub fn watchdog_check(frame: &ExceptionFrame) {
+    let now = tsc::read();
+    let cpu = SECTIONS.get();
+    let depth = cpu.depth.load(Ordering::Relaxed).min(MAX_DEPTH);
+    let mut backtrace = None;
+    for slot in &cpu.slots[..depth] {
+        let start = slot.start.load(Ordering::Acquire);
+        if start == 0 || slot.caught.load(Ordering::Relaxed) {
+            continue;
+        }
+        if now.wrapping_sub(start) > slot.limit.load(Ordering::Relaxed) {
+            let backtrace =
+                *backtrace.get_or_insert_with(|| Backtrace::capture(frame.rip as usize, frame.rbp as usize));
+            // The section can't end while the NMI runs on its CPU
+            *slot.backtrace.get_mut() = Some(backtrace);
+            slot.caught.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// The overruns of a budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Overruns {
+    budget: Budget,
+    count: u64,
+    worst_ns: u64,
+}
+
+static OVERRUNS: Mutex<[Option<Overruns>; MAX_BUDGETS]> = Mutex::new([None; MAX_BUDGETS]);
+
+/// Counts an overrun of the budget, returning how many there were, or `None` if the table is full
+fn record(overruns: &mut [Option<Overruns>], budget: Budget, elapsed_ns: u64) -> Option<u64> {
+    let entry = match overruns
+        .iter()
+        .position(|entry| entry.is_some_and(|entry| entry.budget == budget))
+    {
+        Some(idx) => &mut overruns[idx],
+        None => overruns.iter_mut().find(|entry| entry.is_none())?,
+    };
+    let entry = entry.get_or_insert(Overruns {
+        budget,
+        count: 0,
+        worst_ns: 0,
+    });
+    entry.count += 1;
+    entry.worst_ns = entry.worst_ns.max(elapsed_ns);
+    Some(entry.count)
+}
+
+fn overrun(budget: Budget, elapsed_ns: u64, caught: Option<Backtrace>) {
+    let count = interrupts::without_interrupts(|| record(&mut *OVERRUNS.lock(), budget, elapsed_ns));
+    if count.is_some_and(|count| !count.is_power_of_two()) {
+        return;
+    }
+    kprintln!(
+        Warn,
+        "latency: {} took {}us, over its budget of {}us\nended at:\n{}",
+        budget.name,
+        elapsed_ns / 1000,
+        budget.limit_ns / 1000,
+        Backtrace::here()
+    );
+    if let Some(caught) = caught {
+        kprintln!(
+            Warn,
+            "latency: {} was caught by the watchdog at:\n{}",
+            budget.name,
+            caught
+        );
+    }
+}
+
+/// Registers the `latency` statistics
+pub fn init() {
+    crate::stats::register("latency", dump_stats);
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    let overruns = interrupts::without_interrupts(|| *OVERRUNS.lock());
+    for entry in overruns.iter().flatten() {
+        writeln!(
+            out,
+            "{}: {} overruns of {}us, worst {}us",
+            entry.budget.name,
+            entry.count,
+            entry.budget.limit_ns / 1000,
+            entry.worst_ns / 1000
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_overruns() {
+        let disk = Budget::new("nvme", 50_000);
+        let console = Budget::new("serial", 20_000);
+        let mut overruns = [None; 2];
+        assert_eq!(record(&mut overruns, disk, 80_000), Some(1));
+        assert_eq!(record(&mut overruns, console, 30_000), Some(1));
+        assert_eq!(record(&mut overruns, disk, 60_000), Some(2));
+        let disk = overruns[0].unwrap();
+        assert_eq!((disk.count, disk.worst_ns), (2, 80_000));
+        // Full, so a new budget isn't counted
+        assert_eq!(record(&mut overruns, Budget::new("ahci", 50_000), 90_000), None);
+    }
+}
@@ -0,0 +1,66 @@
+//! Scheduling
+//!
+//! There is no scheduler yet: the main loop of each CPU runs deferred work and ring 3 payloads to
+//! completion. They are still tracked as tasks, so [`trace`] can record when each of them runs and
+//! how long it waited, and the numbers carry over once tasks are preempted.
+//!
+//! Each CPU has its own queue of work, and [`balance`] evens them out, within the [`CpuSet`] each
+//! work item is allowed to run on.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    percpu::{MAX_CPUS, PerCpu},
+    process::Pid,
+    workqueue::Work,
+};
+
+pub mod balance;
+mod cpuset;
+pub mod latency;
+pub mod trace;
+
+pub use cpuset::CpuSet;
+
+/// Identifies a task in traces
+///
+/// Processes are their pid, and work items the kernel address of their [`Work`], so the two never
+/// collide.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(pub u64);
+
+impl TaskId {
+    /// The main loop of a CPU, which runs whenever nothing else does, and the default
+    pub const IDLE: Self = Self(0);
+
+    pub fn process(pid: Pid) -> Self {
+        Self(pid.0 as u64)
+    }
+
+    pub fn work(work: &Work) -> Self {
+        Self(work as *const Work as u64)
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+static CURRENT: PerCpu<AtomicU64> = PerCpu::new([const { AtomicU64::new(0) }; MAX_CPUS]);
+
+/// Returns the task running on the current CPU
+pub fn current() -> TaskId {
+    TaskId(CURRENT.get().load(Ordering::Relaxed))
+}
+
+/// Records that the current CPU runs `task` from now on, returning the task it ran before
+pub fn switch_to(task: TaskId) -> TaskId {
+    let previous = TaskId(CURRENT.get().swap(task.0, Ordering::Relaxed));
+    trace::switch(previous, task);
+    previous
+}
@@ -0,0 +1,259 @@
+//! Scheduler tracing
+//!
+//! While tracing is on, wakeups, enqueues, context switches and migrations are recorded with a
+//! timestamp into a ring of the last [`CAPACITY`] events. It is turned on with `sched.trace` on the
+//! command line or the `schedtrace on` shell command, and costs a relaxed load per event when off.
+//!
+//! [`dump`] writes the events one per line, for scripts on the host to pick apart:
+//!
+//! ```text
+//! # schedtrace v1 events=<count> lost=<count>
+//! <time_ns> <cpu> <event> <task> <arg>
+//! # latency count=<count> mean_ns=<ns> max_ns=<ns> max_task=<task>
+//! ```
+//!
+//! Tasks are hexadecimal [`TaskId`]s. The argument is the waker for `wakeup`, the target CPU for
+//! `enqueue` and `migrate`, and the task switched away from for `switch`. The latency is from the
+//! first wakeup or enqueue of a task to the switch to it.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::collections::BTreeMap;
+
+use super::TaskId;
+use crate::{arch::instructions::interrupts, percpu, sync::Mutex, time};
+
+/// The number of events kept, older ones are overwritten
+pub const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// A task became runnable
+    Wakeup,
+    /// A task was queued on a CPU
+    Enqueue,
+    /// A CPU started running a task
+    Switch,
+    /// A task moved to another CPU
+    Migrate,
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Wakeup => "wakeup",
+            Self::Enqueue => "enqueue",
+            Self::Switch => "switch",
+            Self::Migrate => "migrate",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub time_ns: u64,
+    pub cpu: u32,
+    pub kind: EventKind,
+    pub task: TaskId,
+    pub arg: u64,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {:#x}",
+            self.time_ns, self.cpu, self.kind, self.task, self.arg
+        )
+    }
+}
+
+struct Trace {
+    events: [Option<Event>; CAPACITY],
+    /// The number of events recorded since the trace was cleared
+    recorded: usize,
+}
+
+impl Trace {
+    /// The recorded events, oldest first
+    fn iter(&self) -> impl Iterator<Item = Event> + '_ {
+        let start = self.recorded.saturating_sub(CAPACITY);
+        (start..self.recorded).filter_map(|idx| self.events[idx % CAPACITY])
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TRACE: Mutex<Trace> = Mutex::new(Trace {
+    events: [None; CAPACITY],
+    recorded: 0,
+});
+
+/// Turns tracing on if `sched.trace` is on the command line
+pub fn init(cmdline: crate::boot::Cmdline) {
+    if cmdline.flag("sched.trace") {
+        ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Drops the recorded events
+pub fn clear() {
+    interrupts::without_interrupts(|| TRACE.lock().recorded = 0);
+}
+
+fn record(kind: EventKind, task: TaskId, arg: u64) {
+    if !enabled() {
+        return;
+    }
+    let event = Event {
+        time_ns: time::monotonic_ns(),
+        cpu: percpu::cpu_id() as u32,
+        kind,
+        task,
+        arg,
+    };
+    // Events are recorded from interrupt handlers too
+    interrupts::without_interrupts(|| {
+        let mut trace = TRACE.lock();
+        let idx = trace.recorded % CAPACITY;
+        trace.events[idx] = Some(event);
+        trace.recorded += 1;
+    });
+}
+
+/// Records that `task` became runnable, woken by the current task
+pub fn wakeup(task: TaskId) {
+    crate::trace!(SchedWakeup, task.0, super::current().0);
+    record(EventKind::Wakeup, task, super::current().0);
+}
+
+/// Records that `task` was queued on a CPU
+pub fn enqueue(task: TaskId, cpu: usize) {
+    crate::trace!(SchedEnqueue, task.0, cpu);
+    record(EventKind::Enqueue, task, cpu as u64);
+}
+
+/// Records that the current CPU switched from `previous` to `next`
+pub fn switch(previous: TaskId, next: TaskId) {
+    crate::trace!(SchedSwitch, previous.0, next.0);
+    record(EventKind::Switch, next, previous.0);
+}
+
+/// Records that `task` moved from the current CPU to another one
+pub fn migrate(task: TaskId, cpu: usize) {
+    crate::trace!(SchedMigrate, task.0, cpu);
+    record(EventKind::Migrate, task, cpu as u64);
+}
+
+/// How long tasks waited to run once they were runnable
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Latency {
+    pub count: u64,
+    pub total_ns: u64,
+    pub max_ns: u64,
+    pub max_task: TaskId,
+}
+
+/// Measures the wakeup latencies in a sequence of events, oldest first
+pub fn latency(events: impl Iterator<Item = Event>) -> Latency {
+    let mut runnable = BTreeMap::new();
+    let mut latency = Latency::default();
+    for event in events {
+        match event.kind {
+            EventKind::Wakeup | EventKind::Enqueue => {
+                runnable.entry(event.task).or_insert(event.time_ns);
+            }
+            EventKind::Switch => {
+                let Some(since) = runnable.remove(&event.task) else {
+                    continue;
+                };
+                let waited = event.time_ns.saturating_sub(since);
+                latency.count += 1;
+                latency.total_ns += waited;
+                if waited >= latency.max_ns {
+                    latency.max_ns = waited;
+                    latency.max_task = event.task;
+                }
+            }
+            EventKind::Migrate => {}
+        }
+    }
+    latency
+}
+
+/// Writes the recorded events and their latency in the format described above
+pub fn dump(out: &mut dyn fmt::Write) -> fmt::Result {
+    // Writing the output may record events, so they are copied out first
+    let (events, recorded) = interrupts::without_interrupts(|| {
+        let trace = TRACE.lock();
+        (trace.iter().collect::<alloc::vec::Vec<_>>(), trace.recorded)
+    });
+    writeln!(
+        out,
+        "# schedtrace v1 events={} lost={}",
+        events.len(),
+        recorded - events.len()
+    )?;
+    for event in &events {
+        writeln!(out, "{}", event)?;
+    }
+    let latency = latency(events.into_iter());
+    writeln!(
+        out,
+        "# latency count={} mean_ns={} max_ns={} max_task={}",
+        latency.count,
+        latency.total_ns.checked_div(latency.count).unwrap_or(0),
+        latency.max_ns,
+        latency.max_task
+    )
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn schedtrace_latency() {
+        let event = |time_ns, kind, task| Event {
+            time_ns,
+            cpu: 0,
+            kind,
+            task: TaskId(task),
+            arg: 0,
+        };
+        let events = [
+            event(100, EventKind::Wakeup, 1),
+            // Only the first wakeup counts
+            event(150, EventKind::Enqueue, 1),
+            event(120, EventKind::Enqueue, 2),
+            event(300, EventKind::Switch, 1),
+            event(400, EventKind::Switch, 0),
+            event(420, EventKind::Switch, 2),
+            // Never woken within the trace
+            event(500, EventKind::Switch, 3),
+        ];
+        let latency = latency(events.into_iter());
+        assert_eq!(
+            latency,
+            Latency {
+                count: 2,
+                total_ns: 500,
+                max_ns: 300,
+                max_task: TaskId(2),
+            }
+        );
+        assert_eq!(events[3].to_string(), "300 0 switch 0x1 0x0");
+    }
+}
@@ -0,0 +1,153 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The number of buckets in a [`LatencyHistogram`]
+pub const HISTOGRAM_BUCKETS: usize = 32;
+
+/// A lock-free histogram of latencies with power of two buckets
+///
+/// Bucket `0` counts latencies below 1µs, and bucket `n` counts latencies in `[2^(n-1), 2^n)` µs.
+/// The last bucket also counts everything above it.
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    count: AtomicU64,
+    sum_ns: AtomicU64,
+    max_ns: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; HISTOGRAM_BUCKETS],
+            count: AtomicU64::new(0),
+            sum_ns: AtomicU64::new(0),
+            max_ns: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the bucket a latency falls into
+    pub const fn bucket(latency_ns: u64) -> usize {
+        let us = latency_ns / 1000;
+        let bucket = (u64::BITS - us.leading_zeros()) as usize;
+        if bucket < HISTOGRAM_BUCKETS {
+            bucket
+        } else {
+            HISTOGRAM_BUCKETS - 1
+        }
+    }
+
+    /// Returns the exclusive upper bound of a bucket in µs
+    pub const fn bucket_limit_us(bucket: usize) -> u64 {
+        1 << bucket
+    }
+
+    pub fn record(&self, latency_ns: u64) {
+        self.buckets[Self::bucket(latency_ns)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_ns.fetch_add(latency_ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(latency_ns, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn max_ns(&self) -> u64 {
+        self.max_ns.load(Ordering::Relaxed)
+    }
+
+    pub fn mean_ns(&self) -> u64 {
+        self.sum_ns
+            .load(Ordering::Relaxed)
+            .checked_div(self.count())
+            .unwrap_or(0)
+    }
+
+    pub fn buckets(&self) -> [u64; HISTOGRAM_BUCKETS] {
+        core::array::from_fn(|idx| self.buckets[idx].load(Ordering::Relaxed))
+    }
+
+    /// Returns an upper bound in µs for the given percentile (0-100)
+    pub fn percentile_us(&self, percentile: u64) -> u64 {
+        let count = self.count();
+        if count == 0 {
+            return 0;
+        }
+        let target = (count * percentile).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (bucket, n) in self.buckets().iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return Self::bucket_limit_us(bucket);
+            }
+        }
+        Self::bucket_limit_us(HISTOGRAM_BUCKETS - 1)
+    }
+
+    pub fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.sum_ns.store(0, Ordering::Relaxed);
+        self.max_ns.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for LatencyHistogram {
+    /// Prints the non-empty buckets, one per line
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "count={} mean={}ns max={}ns p50<{}us p99<{}us",
+            self.count(),
+            self.mean_ns(),
+            self.max_ns(),
+            self.percentile_us(50),
+            self.percentile_us(99)
+        )?;
+        for (bucket, n) in self.buckets().iter().enumerate() {
+            if *n != 0 {
+                writeln!(f, "  <{:>10}us: {}", Self::bucket_limit_us(bucket), n)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets() {
+        assert_eq!(LatencyHistogram::bucket(0), 0);
+        assert_eq!(LatencyHistogram::bucket(999), 0);
+        assert_eq!(LatencyHistogram::bucket(1_000), 1);
+        assert_eq!(LatencyHistogram::bucket(1_999), 1);
+        assert_eq!(LatencyHistogram::bucket(2_000), 2);
+        assert_eq!(LatencyHistogram::bucket(u64::MAX), HISTOGRAM_BUCKETS - 1);
+    }
+
+    #[test]
+    fn histogram_percentiles() {
+        let hist = LatencyHistogram::new();
+        for _ in 0..99 {
+            hist.record(500);
+        }
+        hist.record(10_000);
+        assert_eq!(hist.count(), 100);
+        assert_eq!(hist.max_ns(), 10_000);
+        assert_eq!(hist.percentile_us(50), 1);
+        assert_eq!(hist.percentile_us(100), 16);
+    }
+}
@@ -0,0 +1,57 @@
+//! Kernel statistics
+//!
+//! Subsystems register a named provider that can print their counters, so they can all be
+//! dumped from a single place (the debug shell, and later a procfs-like interface). Their raw
+//! counters are also collected by [`snapshot`], to show how fast they change.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use crate::sync::RwLock;
+
+pub mod histogram;
+pub mod snapshot;
+
+/// Writes the statistics of a subsystem
+pub type StatsDumpFn = fn(&mut dyn fmt::Write) -> fmt::Result;
+
+#[derive(Debug, Clone, Copy)]
+pub struct StatsProvider {
+    pub name: &'static str,
+    pub dump: StatsDumpFn,
+}
+
+static PROVIDERS: RwLock<Vec<StatsProvider>> = RwLock::new(Vec::new());
+
+/// Registers a statistics provider, replacing any existing provider with the same name
+pub fn register(name: &'static str, dump: StatsDumpFn) {
+    let mut providers = PROVIDERS.write();
+    providers.retain(|provider| provider.name != name);
+    providers.push(StatsProvider { name, dump });
+}
+
+/// Returns the names of all registered providers
+pub fn providers() -> Vec<&'static str> {
+    PROVIDERS.read().iter().map(|provider| provider.name).collect()
+}
+
+/// Dumps the statistics of a single provider
+pub fn dump(name: &str, out: &mut dyn fmt::Write) -> Option<fmt::Result> {
+    let provider = PROVIDERS
+        .read()
+        .iter()
+        .find(|provider| provider.name == name)
+        .copied()?;
+    Some((provider.dump)(out))
+}
+
+/// Dumps the statistics of every provider
+pub fn dump_all(out: &mut dyn fmt::Write) -> fmt::Result {
+    let providers = PROVIDERS.read().clone();
+    for provider in providers {
+        writeln!(out, "[{}]", provider.name)?;
+        (provider.dump)(out)?;
+    }
+    Ok(())
+}
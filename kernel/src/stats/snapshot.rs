@@ -0,0 +1,209 @@
+//! Snapshots of kernel counters, and the deltas between them
+//!
+//! Subsystems register a function that reports their counters by name. A [`Snapshot`] collects
+//! all of them at one point in time, and two snapshots give a [`Delta`] with the rate of every
+//! counter over the time between them.
+
+use core::fmt;
+
+use alloc::{string::String, vec::Vec};
+
+use crate::{sync::RwLock, time};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterKind {
+    /// Only ever increases, so the delta is a rate
+    Counter,
+    /// A current level like a queue depth, so the latest value is reported rather than a delta
+    Gauge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counter {
+    /// The name of the counter, prefixed by its source
+    pub name: String,
+    pub kind: CounterKind,
+    pub value: u64,
+}
+
+/// Collects the counters reported by a source
+pub struct Counters<'a> {
+    source: &'static str,
+    counters: &'a mut Vec<Counter>,
+}
+
+impl Counters<'_> {
+    fn push(&mut self, kind: CounterKind, name: fmt::Arguments<'_>, value: u64) {
+        use fmt::Write;
+        let mut full = String::from(self.source);
+        _ = write!(full, ".{}", name);
+        self.counters.push(Counter {
+            name: full,
+            kind,
+            value,
+        });
+    }
+
+    pub fn counter(&mut self, name: fmt::Arguments<'_>, value: u64) {
+        self.push(CounterKind::Counter, name, value);
+    }
+
+    pub fn gauge(&mut self, name: fmt::Arguments<'_>, value: u64) {
+        self.push(CounterKind::Gauge, name, value);
+    }
+}
+
+/// Reports the counters of a subsystem
+pub type CountersFn = fn(&mut Counters<'_>);
+
+static SOURCES: RwLock<Vec<(&'static str, CountersFn)>> = RwLock::new(Vec::new());
+
+/// Registers a counter source, replacing any existing source with the same name
+pub fn register(source: &'static str, counters: CountersFn) {
+    let mut sources = SOURCES.write();
+    sources.retain(|(name, _)| *name != source);
+    sources.push((source, counters));
+}
+
+/// The counters of every source at one point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub time_ns: u64,
+    pub counters: Vec<Counter>,
+}
+
+impl Snapshot {
+    pub fn take() -> Self {
+        let sources = SOURCES.read().clone();
+        let mut counters = Vec::new();
+        for (source, report) in sources {
+            report(&mut Counters {
+                source,
+                counters: &mut counters,
+            });
+        }
+        Self {
+            time_ns: time::monotonic_ns(),
+            counters,
+        }
+    }
+
+    /// Returns the change of every counter since `earlier`
+    ///
+    /// Counters that didn't exist in `earlier`, like those of a newly added device, count from 0.
+    pub fn delta(&self, earlier: &Snapshot) -> Delta {
+        let counters = self
+            .counters
+            .iter()
+            .map(|counter| {
+                let value = match counter.kind {
+                    CounterKind::Counter => {
+                        let before = earlier
+                            .counters
+                            .iter()
+                            .find(|other| other.name == counter.name)
+                            .map_or(0, |other| other.value);
+                        counter.value.saturating_sub(before)
+                    }
+                    CounterKind::Gauge => counter.value,
+                };
+                Counter {
+                    name: counter.name.clone(),
+                    kind: counter.kind,
+                    value,
+                }
+            })
+            .collect();
+        Delta {
+            interval_ns: self.time_ns.saturating_sub(earlier.time_ns),
+            counters,
+        }
+    }
+}
+
+/// The change of counters between two snapshots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+    pub interval_ns: u64,
+    /// The increase of counters, and the latest value of gauges
+    pub counters: Vec<Counter>,
+}
+
+impl Delta {
+    /// Returns the rate of a counter per second, or the value of a gauge
+    pub fn rate(&self, counter: &Counter) -> u64 {
+        match counter.kind {
+            CounterKind::Counter if self.interval_ns != 0 => {
+                (counter.value as u128 * 1_000_000_000 / self.interval_ns as u128) as u64
+            }
+            CounterKind::Counter => 0,
+            CounterKind::Gauge => counter.value,
+        }
+    }
+}
+
+impl fmt::Display for Delta {
+    /// Prints the counters that changed as rates per second, and all gauges
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "over {}ms:", self.interval_ns / 1_000_000)?;
+        for counter in &self.counters {
+            match counter.kind {
+                CounterKind::Counter if counter.value != 0 => {
+                    writeln!(f, "  {:<40} {:>12}/s", counter.name, self.rate(counter))?
+                }
+                CounterKind::Counter => {}
+                CounterKind::Gauge => writeln!(f, "  {:<40} {:>12}", counter.name, counter.value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    fn counter(name: &str, kind: CounterKind, value: u64) -> Counter {
+        Counter {
+            name: String::from(name),
+            kind,
+            value,
+        }
+    }
+
+    #[test]
+    fn snapshot_delta() {
+        let earlier = Snapshot {
+            time_ns: 1_000_000_000,
+            counters: vec![
+                counter("net.eth0.rx_packets", CounterKind::Counter, 100),
+                counter("block.vda.in_flight", CounterKind::Gauge, 4),
+            ],
+        };
+        let later = Snapshot {
+            time_ns: 3_000_000_000,
+            counters: vec![
+                counter("net.eth0.rx_packets", CounterKind::Counter, 300),
+                counter("block.vda.in_flight", CounterKind::Gauge, 1),
+                counter("net.eth1.rx_packets", CounterKind::Counter, 10),
+            ],
+        };
+        let delta = later.delta(&earlier);
+        assert_eq!(delta.interval_ns, 2_000_000_000);
+        let rates: Vec<_> = delta
+            .counters
+            .iter()
+            .map(|counter| (counter.name.as_str(), delta.rate(counter)))
+            .collect();
+        assert_eq!(
+            rates,
+            [
+                ("net.eth0.rx_packets", 100),
+                ("block.vda.in_flight", 1),
+                ("net.eth1.rx_packets", 5)
+            ]
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Single-shot completions
+//!
+//! A [`Completion`] is how a driver tells whoever started a request, usually a DMA transfer, that
+//! the device is done with it. The interrupt handler signals it with [`Completion::complete`],
+//! which neither locks nor allocates, passing the result along, and the request is waited on by
+//! polling [`Completion::try_take`] or by awaiting `&Completion` as a future.
+//!
+//! A completion is signalled at most once, until it is [`reset`](Completion::reset) for the next
+//! request. The result is taken once, so there is only one waiter.
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    mem::MaybeUninit,
+    pin::Pin,
+    sync::atomic::{AtomicU8, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/// Nothing signalled yet
+const EMPTY: u8 = 0;
+/// The result is being written or dropped
+const BUSY: u8 = 1;
+/// Signalled, the result is there
+const DONE: u8 = 2;
+/// Signalled, and the result was taken
+const TAKEN: u8 = 3;
+
+/// Nobody is touching the waker
+const WAKER_IDLE: u8 = 0;
+const WAKER_REGISTERING: u8 = 1 << 0;
+const WAKER_WAKING: u8 = 1 << 1;
+
+/// The waker of the task awaiting a completion, which can be woken from an interrupt handler
+/// while it is being registered
+struct AtomicWaker {
+    state: AtomicU8,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+impl AtomicWaker {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(WAKER_IDLE),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    fn register(&self, waker: &Waker) {
+        match self
+            .state
+            .compare_exchange(WAKER_IDLE, WAKER_REGISTERING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                // SAFETY: Registering excludes everyone else from the waker
+                unsafe { *self.waker.get() = Some(waker.clone()) };
+                if self
+                    .state
+                    .compare_exchange(WAKER_REGISTERING, WAKER_IDLE, Ordering::AcqRel, Ordering::Acquire)
+                    .is_err()
+                {
+                    // Woken meanwhile, which left the waking to us
+                    let waker = unsafe { (*self.waker.get()).take() };
+                    self.state.store(WAKER_IDLE, Ordering::Release);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            Err(WAKER_WAKING) => waker.wake_by_ref(),
+            // Registered by someone else at the same time, only one waiter is supported
+            Err(_) => {}
+        }
+    }
+
+    fn wake(&self) {
+        if self.state.fetch_or(WAKER_WAKING, Ordering::AcqRel) == WAKER_IDLE {
+            // SAFETY: Waking excludes everyone else from the waker
+            let waker = unsafe { (*self.waker.get()).take() };
+            self.state.fetch_and(!WAKER_WAKING, Ordering::Release);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// The end of a request, signalled once with a result of type `T`
+pub struct Completion<T = ()> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+    waker: AtomicWaker,
+}
+
+// SAFETY: The value is only written by the one caller that won the transition to BUSY, and only
+// read by the one that won the transition to TAKEN
+unsafe impl<T: Send> Send for Completion<T> {}
+unsafe impl<T: Send> Sync for Completion<T> {}
+
+impl<T> Completion<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(EMPTY),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Signals the completion with its result, and wakes the task awaiting it
+    ///
+    /// Safe to call from interrupt handlers, as long as the waker of the task is. Returns `false`
+    /// if it was signalled already, the result is dropped then.
+    pub fn complete(&self, value: T) -> bool {
+        if self
+            .state
+            .compare_exchange(EMPTY, BUSY, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return false;
+        }
+        // SAFETY: BUSY gives us the value
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(DONE, Ordering::Release);
+        self.waker.wake();
+        true
+    }
+
+    /// Returns whether the completion was signalled, even if the result was taken since
+    pub fn is_done(&self) -> bool {
+        matches!(self.state.load(Ordering::Acquire), DONE | TAKEN)
+    }
+
+    /// Takes the result, if the completion was signalled and it wasn't taken yet
+    pub fn try_take(&self) -> Option<T> {
+        self.state
+            .compare_exchange(DONE, TAKEN, Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        // SAFETY: The value was written before DONE, and TAKEN gives it to us
+        Some(unsafe { (*self.value.get()).assume_init_read() })
+    }
+
+    /// Makes the completion ready to be signalled again, dropping a result that wasn't taken
+    ///
+    /// Returns `false` if it is being signalled right now.
+    pub fn reset(&self) -> bool {
+        match self
+            .state
+            .compare_exchange(DONE, BUSY, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                // SAFETY: BUSY gives us the value, which was written before DONE
+                unsafe { (*self.value.get()).assume_init_drop() };
+                self.state.store(EMPTY, Ordering::Release);
+                true
+            }
+            Err(TAKEN) => self
+                .state
+                .compare_exchange(TAKEN, EMPTY, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok(),
+            Err(state) => state == EMPTY,
+        }
+    }
+}
+
+impl<T> Default for Completion<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == DONE {
+            // SAFETY: The value was written and never taken
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+impl<T> fmt::Debug for Completion<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state.load(Ordering::Relaxed) {
+            EMPTY => "pending",
+            BUSY => "busy",
+            DONE => "done",
+            _ => "taken",
+        };
+        f.debug_struct("Completion").field("state", &state).finish()
+    }
+}
+
+/// Resolves to the result once the completion is signalled, never if it was already taken
+impl<T> Future for &Completion<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        if let Some(value) = self.try_take() {
+            return Poll::Ready(value);
+        }
+        self.waker.register(cx.waker());
+        // Signalled before the waker was registered
+        match self.try_take() {
+            Some(value) => Poll::Ready(value),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use core::sync::atomic::AtomicBool;
+
+    use alloc::{sync::Arc, task::Wake};
+
+    use super::*;
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn completion_signal_and_await() {
+        let completion = Completion::<Arc<u32>>::new();
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut future = &completion;
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Pending);
+        let value = Arc::new(7);
+        assert!(completion.complete(value.clone()));
+        assert!(flag.0.load(Ordering::Relaxed));
+        // Single-shot
+        assert!(!completion.complete(Arc::new(8)));
+        assert!(completion.is_done());
+        assert_eq!(Pin::new(&mut future).poll(&mut cx), Poll::Ready(value.clone()));
+        assert_eq!(completion.try_take(), None);
+
+        // A result that isn't taken is dropped by the reset
+        assert!(completion.reset());
+        assert!(!completion.is_done());
+        assert!(completion.complete(value.clone()));
+        assert_eq!(Arc::strong_count(&value), 2);
+        assert!(completion.reset());
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+}
@@ -0,0 +1,332 @@
+//! Lock debugging, enabled with the `lock_debug` option
+//!
+//! Every lock belongs to a class, the place it was created at, so locks created by the same
+//! constructor, like the lock of every device, share one. Each CPU keeps a stack of the locks it
+//! holds, and every lock records the CPU, task and call site that owns it.
+//!
+//! Taking a lock the CPU already holds would spin forever, so it is reported instead. Taking a
+//! lock of class B while holding one of class A records that A comes before B, and if B was ever
+//! taken before A, two CPUs taking them in opposite orders can deadlock (an ABBA inversion).
+//! Reports are panics with the call sites on both sides, because the logger may be one of the
+//! locks involved.
+
+use core::{
+    fmt,
+    panic::Location,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+};
+
+use crate::{
+    arch::instructions::interrupts,
+    percpu::{self, MAX_CPUS, PerCpu},
+    sync::cell::RacyCell,
+};
+
+/// The most locks a CPU can hold at once
+const MAX_HELD: usize = 32;
+/// The most distinct orderings between two classes that are remembered
+const MAX_EDGES: usize = 512;
+
+/// Where a lock was created
+pub type Class = &'static Location<'static>;
+/// Where a lock was taken
+pub type Site = &'static Location<'static>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// A mutex, or a writer of a read-write lock
+    Exclusive,
+    Shared,
+}
+
+/// The owner of a lock
+#[derive(Debug, Clone, Copy)]
+pub struct Owner {
+    pub cpu: usize,
+    /// The task running on the CPU, or 0 if there was none
+    pub task: usize,
+    pub site: Site,
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CPU {}", self.cpu)?;
+        if self.task != 0 {
+            write!(f, " task {:#x}", self.task)?;
+        }
+        write!(f, " at {}", self.site)
+    }
+}
+
+/// The bookkeeping stored in every lock
+#[derive(Debug)]
+pub struct LockInfo {
+    class: Class,
+    /// The CPU id plus one, or 0 if no CPU holds the lock exclusively
+    owner_cpu: AtomicUsize,
+    owner_task: AtomicUsize,
+    owner_site: AtomicPtr<Location<'static>>,
+}
+
+impl LockInfo {
+    pub const fn new(class: Class) -> Self {
+        Self {
+            class,
+            owner_cpu: AtomicUsize::new(0),
+            owner_task: AtomicUsize::new(0),
+            owner_site: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    pub fn class(&self) -> Class {
+        self.class
+    }
+
+    /// Returns the owner of the lock, if it is held exclusively
+    pub fn owner(&self) -> Option<Owner> {
+        let cpu = self.owner_cpu.load(Ordering::Acquire).checked_sub(1)?;
+        let site = self.owner_site.load(Ordering::Relaxed);
+        Some(Owner {
+            cpu,
+            task: self.owner_task.load(Ordering::Relaxed),
+            // SAFETY: Sites are always `&'static Location`
+            site: unsafe { site.as_ref()? },
+        })
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Held {
+    lock: usize,
+    class: Class,
+    access: Access,
+    site: Site,
+}
+
+/// The locks held by a CPU, in the order they were taken
+struct HeldLocks {
+    locks: [Option<Held>; MAX_HELD],
+    len: usize,
+}
+
+static HELD: PerCpu<RacyCell<HeldLocks>> = PerCpu::new(
+    [const {
+        RacyCell::new(HeldLocks {
+            locks: [None; MAX_HELD],
+            len: 0,
+        })
+    }; MAX_CPUS],
+);
+
+/// A class that was taken while holding another
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+    before: Class,
+    after: Class,
+    /// Where the first lock was taken
+    before_site: Site,
+    /// Where the second lock was taken while holding the first
+    after_site: Site,
+}
+
+/// The order in which lock classes have been taken
+struct LockGraph {
+    edges: [Option<Edge>; MAX_EDGES],
+    len: usize,
+}
+
+/// Two classes taken in both orders
+#[derive(Debug, Clone, Copy)]
+struct Inversion {
+    seen: Edge,
+    now: Edge,
+}
+
+impl fmt::Display for Inversion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "lockdep: lock order inversion")?;
+        writeln!(f, "taking lock {} at {}", self.now.after, self.now.after_site)?;
+        writeln!(
+            f,
+            "while holding lock {} taken at {}",
+            self.now.before, self.now.before_site
+        )?;
+        writeln!(
+            f,
+            "but earlier, lock {} was taken at {}",
+            self.seen.after, self.seen.after_site
+        )?;
+        write!(
+            f,
+            "while holding lock {} taken at {}",
+            self.seen.before, self.seen.before_site
+        )
+    }
+}
+
+fn same(a: Class, b: Class) -> bool {
+    core::ptr::eq(a, b)
+}
+
+impl LockGraph {
+    const fn new() -> Self {
+        Self {
+            edges: [None; MAX_EDGES],
+            len: 0,
+        }
+    }
+
+    /// Records an edge, returning the edge in the opposite order if there is one
+    fn add(&mut self, edge: Edge) -> Result<(), Inversion> {
+        let edges = self.edges[..self.len].iter().flatten();
+        for seen in edges {
+            if same(seen.before, edge.after) && same(seen.after, edge.before) {
+                return Err(Inversion { seen: *seen, now: edge });
+            }
+        }
+        let edges = &self.edges[..self.len];
+        let known = edges
+            .iter()
+            .flatten()
+            .any(|seen| same(seen.before, edge.before) && same(seen.after, edge.after));
+        // When the graph is full, new orders are no longer checked
+        if !known && self.len < MAX_EDGES {
+            self.edges[self.len] = Some(edge);
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Uses the spin lock directly, as the tracked ones call into here
+static GRAPH: spin::Mutex<LockGraph> = spin::Mutex::new(LockGraph::new());
+/// Host tests run on many threads that all look like CPU 0, and can't disable interrupts
+static ENABLED: AtomicBool = AtomicBool::new(!cfg!(test));
+
+fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Stops checking locks, so the panic handler can take whatever locks it needs
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Checks that a lock can be taken, before spinning on it
+///
+/// Panics if the CPU already holds the lock, or if taking it inverts a known lock order.
+pub fn check(info: &LockInfo, access: Access, site: Site) {
+    if !enabled() {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let held = HELD.get().get();
+        for lock in held.locks[..held.len].iter().flatten() {
+            if lock.lock == info.key() {
+                // Readers may nest, as long as no writer is involved
+                if lock.access == Access::Shared && access == Access::Shared {
+                    continue;
+                }
+                panic!(
+                    "lockdep: CPU {} takes lock {} at {} which it already holds since {}",
+                    percpu::cpu_id(),
+                    info.class,
+                    site,
+                    lock.site
+                );
+            }
+            if same(lock.class, info.class) {
+                continue;
+            }
+            let edge = Edge {
+                before: lock.class,
+                after: info.class,
+                before_site: lock.site,
+                after_site: site,
+            };
+            let added = GRAPH.lock().add(edge);
+            if let Err(inversion) = added {
+                panic!("{}", inversion);
+            }
+        }
+    });
+}
+
+/// Records that the current CPU took a lock
+pub fn acquired(info: &LockInfo, access: Access, site: Site) {
+    if !enabled() {
+        return;
+    }
+    if access == Access::Exclusive {
+        let task = percpu::try_current().map_or(0, |cpu| cpu.current_task.load(Ordering::Relaxed) as usize);
+        info.owner_site.store(site as *const _ as *mut _, Ordering::Relaxed);
+        info.owner_task.store(task, Ordering::Relaxed);
+        info.owner_cpu.store(percpu::cpu_id() + 1, Ordering::Release);
+    }
+    interrupts::without_interrupts(|| {
+        let held = HELD.get().get_mut();
+        if held.len == MAX_HELD {
+            panic!("lockdep: CPU {} holds more than {} locks", percpu::cpu_id(), MAX_HELD);
+        }
+        held.locks[held.len] = Some(Held {
+            lock: info.key(),
+            class: info.class,
+            access,
+            site,
+        });
+        held.len += 1;
+    });
+}
+
+/// Records that the current CPU released a lock
+///
+/// Locks may be released in any order.
+pub fn released(info: &LockInfo, access: Access) {
+    if !enabled() {
+        return;
+    }
+    if access == Access::Exclusive {
+        info.owner_cpu.store(0, Ordering::Release);
+    }
+    interrupts::without_interrupts(|| {
+        let held = HELD.get().get_mut();
+        let locks = &mut held.locks[..held.len];
+        // Guards moved to another CPU are never found, which only loses their bookkeeping
+        if let Some(idx) = locks
+            .iter()
+            .rposition(|lock| lock.is_some_and(|lock| lock.lock == info.key()))
+        {
+            locks[idx..].rotate_left(1);
+            held.len -= 1;
+            held.locks[held.len] = None;
+        }
+    });
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockdep_inversion() {
+        let (a, b, c) = (Location::caller(), Location::caller(), Location::caller());
+        let edge = |before, after| Edge {
+            before,
+            after,
+            before_site: before,
+            after_site: after,
+        };
+        let mut graph = LockGraph::new();
+        assert!(graph.add(edge(a, b)).is_ok());
+        assert!(graph.add(edge(a, b)).is_ok());
+        assert!(graph.add(edge(b, c)).is_ok());
+        assert_eq!(graph.len, 2);
+        let inversion = graph.add(edge(b, a)).unwrap_err();
+        assert!(same(inversion.seen.before, a) && same(inversion.now.before, b));
+        assert!(graph.add(edge(a, c)).is_ok());
+    }
+}
@@ -1,4 +1,12 @@
 pub mod cell;
+pub mod completion;
+#[cfg(kconfig = "lock_debug")]
+pub mod lockdep;
 pub mod mutex;
+pub mod rcu;
+pub mod rwlock;
 
-pub use spin::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use completion::Completion;
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use spin::Once;
@@ -1,9 +1,116 @@
 use core::{
+    fmt,
     ops::{Deref, DerefMut},
     sync::atomic::AtomicBool,
 };
 
+#[cfg(kconfig = "lock_debug")]
+use core::panic::Location;
+
 use crate::sync::cell::UninitCell;
+#[cfg(kconfig = "lock_debug")]
+use crate::sync::lockdep::{self, Access, LockInfo};
+
+/// A spin lock, checked by [`lockdep`](super::lockdep) with the `lock_debug` feature
+pub struct Mutex<T: ?Sized> {
+    #[cfg(kconfig = "lock_debug")]
+    info: LockInfo,
+    inner: spin::Mutex<T>,
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+    #[cfg(kconfig = "lock_debug")]
+    info: &'a LockInfo,
+    inner: spin::MutexGuard<'a, T>,
+}
+
+impl<T> Mutex<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Self {
+            #[cfg(kconfig = "lock_debug")]
+            info: LockInfo::new(Location::caller()),
+            inner: spin::Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        #[cfg(kconfig = "lock_debug")]
+        lockdep::check(&self.info, Access::Exclusive, Location::caller());
+        let inner = self.inner.lock();
+        #[cfg(kconfig = "lock_debug")]
+        lockdep::acquired(&self.info, Access::Exclusive, Location::caller());
+        MutexGuard {
+            #[cfg(kconfig = "lock_debug")]
+            info: &self.info,
+            inner,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let inner = self.inner.try_lock()?;
+        #[cfg(kconfig = "lock_debug")]
+        lockdep::acquired(&self.info, Access::Exclusive, Location::caller());
+        Some(MutexGuard {
+            #[cfg(kconfig = "lock_debug")]
+            info: &self.info,
+            inner,
+        })
+    }
+
+    /// Releases the lock without a guard
+    ///
+    /// # Safety
+    /// Whoever holds the lock must never use it again, this is only meant for panics.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(kconfig = "lock_debug")]
+        lockdep::released(&self.info, Access::Exclusive);
+        unsafe { self.inner.force_unlock() };
+    }
+
+    /// Returns the CPU, task and call site holding the lock
+    #[cfg(kconfig = "lock_debug")]
+    pub fn owner(&self) -> Option<lockdep::Owner> {
+        self.info.owner()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+#[cfg(kconfig = "lock_debug")]
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::released(self.info, Access::Exclusive);
+    }
+}
 
 pub struct UninitMutex<T> {
     lock: AtomicBool,
@@ -0,0 +1,205 @@
+//! Read-copy-update
+//!
+//! For data that is read far more often than it changes, like the interrupt handler table.
+//! Readers only mark their CPU as reading with [`read_lock`], so they never wait for a writer.
+//! Writers publish a new version with an atomic pointer swap in an [`RcuBox`], and the old version
+//! is only freed after a grace period: once every read-side section that could still see it has
+//! ended, see [`synchronize`].
+//!
+//! Read-side sections must be short and must not wait for a grace period themselves. A CPU that
+//! is parked by [`crate::percpu::offline`] is parked inside its interrupt handler, so parked CPUs
+//! are skipped, and the park handler is never removed.
+
+use core::{
+    marker::PhantomData,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicU32, AtomicU64, Ordering},
+};
+
+use alloc::boxed::Box;
+
+use crate::percpu::{self, CpuState, MAX_CPUS, PerCpu};
+
+/// The number of the latest grace period, starting at one so zero can mean "not reading"
+static GP_SEQ: AtomicU64 = AtomicU64::new(1);
+static STATE: PerCpu<RcuCpu> = PerCpu::new([const { RcuCpu::new() }; MAX_CPUS]);
+
+/// The read-side state of a CPU
+struct RcuCpu {
+    /// How many read-side sections are nested, interrupts can start one inside another
+    nesting: AtomicU32,
+    /// The grace period the outermost section started in, or zero outside of one
+    active: AtomicU64,
+}
+
+impl RcuCpu {
+    const fn new() -> Self {
+        Self {
+            nesting: AtomicU32::new(0),
+            active: AtomicU64::new(0),
+        }
+    }
+
+    fn enter(&self) {
+        let outermost = self.nesting.load(Ordering::Relaxed) == 0;
+        if outermost {
+            self.active.store(GP_SEQ.load(Ordering::SeqCst), Ordering::SeqCst);
+        }
+        self.nesting.fetch_add(1, Ordering::Relaxed);
+        // An interrupt before the nesting was raised may have run a section of its own, and
+        // cleared the mark when it ended
+        if outermost && self.active.load(Ordering::SeqCst) == 0 {
+            self.active.store(GP_SEQ.load(Ordering::SeqCst), Ordering::SeqCst);
+        }
+    }
+
+    fn exit(&self) {
+        if self.nesting.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.active.store(0, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns whether the CPU has no section that started before grace period `target`
+    fn passed(&self, target: u64) -> bool {
+        let active = self.active.load(Ordering::SeqCst);
+        active == 0 || active >= target
+    }
+}
+
+/// A read-side section, ended when dropped
+///
+/// Tied to the CPU it was started on.
+pub struct RcuGuard {
+    _cpu: PhantomData<*const ()>,
+}
+
+impl Drop for RcuGuard {
+    fn drop(&mut self) {
+        STATE.get().exit();
+    }
+}
+
+/// Starts a read-side section, during which nothing read from an [`RcuBox`] is freed
+pub fn read_lock() -> RcuGuard {
+    STATE.get().enter();
+    RcuGuard { _cpu: PhantomData }
+}
+
+/// Waits until every read-side section that was running when it was called has ended
+///
+/// # Panics
+/// Panics if called inside a read-side section, as it would wait for itself.
+pub fn synchronize() {
+    assert!(
+        STATE.get().nesting.load(Ordering::Relaxed) == 0,
+        "rcu: synchronize inside a read-side section"
+    );
+    let target = GP_SEQ.fetch_add(1, Ordering::SeqCst) + 1;
+    for (cpu_id, state) in STATE.iter().enumerate() {
+        if percpu::cpu(cpu_id).is_some_and(|cpu| cpu.state() == CpuState::Offline) {
+            continue;
+        }
+        while !state.passed(target) {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// An optional boxed value that can be read inside a read-side section while it is replaced
+#[derive(Debug)]
+pub struct RcuBox<T> {
+    ptr: AtomicPtr<T>,
+}
+
+impl<T> RcuBox<T> {
+    pub const fn empty() -> Self {
+        Self {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Returns the current value, which stays valid until the section ends
+    pub fn get<'a>(&'a self, _guard: &'a RcuGuard) -> Option<&'a T> {
+        unsafe { self.ptr.load(Ordering::SeqCst).as_ref() }
+    }
+
+    /// Returns whether there is a value, without reading it
+    pub fn is_some(&self) -> bool {
+        !self.ptr.load(Ordering::Relaxed).is_null()
+    }
+
+    /// Publishes a new value, returning the old one
+    ///
+    /// Readers may still be using the old value, so it is only given back after a grace period.
+    pub fn swap(&self, value: Option<Box<T>>) -> Retired<T> {
+        let new = value.map_or(ptr::null_mut(), Box::into_raw);
+        let old = self.ptr.swap(new, Ordering::SeqCst);
+        Retired((!old.is_null()).then(|| unsafe { Box::from_raw(old) }))
+    }
+}
+
+impl<T> Drop for RcuBox<T> {
+    fn drop(&mut self) {
+        // Nobody can be reading through a shared reference anymore
+        let old = *self.ptr.get_mut();
+        if !old.is_null() {
+            drop(unsafe { Box::from_raw(old) });
+        }
+    }
+}
+
+unsafe impl<T: Send + Sync> Sync for RcuBox<T> {}
+unsafe impl<T: Send> Send for RcuBox<T> {}
+
+/// A value taken out of an [`RcuBox`], which readers may still be using
+///
+/// Waits for a grace period before handing the value back or dropping it.
+#[must_use = "dropping the old value waits for a grace period"]
+pub struct Retired<T>(Option<Box<T>>);
+
+impl<T> Retired<T> {
+    /// Waits for a grace period, then returns the old value
+    pub fn into_inner(mut self) -> Option<Box<T>> {
+        if self.0.is_some() {
+            synchronize();
+        }
+        self.0.take()
+    }
+}
+
+impl<T> Drop for Retired<T> {
+    fn drop(&mut self) {
+        if self.0.is_some() {
+            synchronize();
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rcu_grace_period() {
+        // A CPU of our own, as the tests run in parallel on what looks like CPU 0
+        let cpu = RcuCpu::new();
+        assert!(cpu.passed(GP_SEQ.load(Ordering::SeqCst) + 1));
+
+        cpu.enter();
+        cpu.enter();
+        let target = cpu.active.load(Ordering::SeqCst) + 1;
+        assert!(!cpu.passed(target));
+        cpu.exit();
+        assert!(!cpu.passed(target), "the outer section is still running");
+        cpu.exit();
+        assert!(cpu.passed(target));
+
+        let cell = RcuBox::empty();
+        assert!(cell.swap(Some(Box::new(1))).0.is_none());
+        let guard = read_lock();
+        assert_eq!(cell.get(&guard), Some(&1));
+        drop(guard);
+        assert_eq!(cell.swap(None).into_inner().as_deref(), Some(&1));
+        assert!(!cell.is_some());
+    }
+}
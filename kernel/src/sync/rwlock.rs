@@ -0,0 +1,147 @@
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+#[cfg(kconfig = "lock_debug")]
+use core::panic::Location;
+
+#[cfg(kconfig = "lock_debug")]
+use crate::sync::lockdep::{self, Access, LockInfo};
+
+/// A spin read-write lock, checked by [`lockdep`](super::lockdep) with the `lock_debug` feature
+pub struct RwLock<T: ?Sized> {
+    #[cfg(kconfig = "lock_debug")]
+    info: LockInfo,
+    inner: spin::RwLock<T>,
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    #[cfg(kconfig = "lock_debug")]
+    info: &'a LockInfo,
+    inner: spin::RwLockReadGuard<'a, T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    #[cfg(kconfig = "lock_debug")]
+    info: &'a LockInfo,
+    inner: spin::RwLockWriteGuard<'a, T>,
+}
+
+impl<T> RwLock<T> {
+    #[track_caller]
+    pub const fn new(value: T) -> Self {
+        Self {
+            #[cfg(kconfig = "lock_debug")]
+            info: LockInfo::new(Location::caller()),
+            inner: spin::RwLock::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        #[cfg(kconfig = "lock_debug")]
+        lockdep::check(&self.info, Access::Shared, Location::caller());
+        let inner = self.inner.read();
+        #[cfg(kconfig = "lock_debug")]
+        lockdep::acquired(&self.info, Access::Shared, Location::caller());
+        RwLockReadGuard {
+            #[cfg(kconfig = "lock_debug")]
+            info: &self.info,
+            inner,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let inner = self.inner.try_read()?;
+        #[cfg(kconfig = "lock_debug")]
+        lockdep::acquired(&self.info, Access::Shared, Location::caller());
+        Some(RwLockReadGuard {
+            #[cfg(kconfig = "lock_debug")]
+            info: &self.info,
+            inner,
+        })
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        #[cfg(kconfig = "lock_debug")]
+        lockdep::check(&self.info, Access::Exclusive, Location::caller());
+        let inner = self.inner.write();
+        #[cfg(kconfig = "lock_debug")]
+        lockdep::acquired(&self.info, Access::Exclusive, Location::caller());
+        RwLockWriteGuard {
+            #[cfg(kconfig = "lock_debug")]
+            info: &self.info,
+            inner,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let inner = self.inner.try_write()?;
+        #[cfg(kconfig = "lock_debug")]
+        lockdep::acquired(&self.info, Access::Exclusive, Location::caller());
+        Some(RwLockWriteGuard {
+            #[cfg(kconfig = "lock_debug")]
+            info: &self.info,
+            inner,
+        })
+    }
+
+    /// Returns the CPU, task and call site holding the lock for writing
+    #[cfg(kconfig = "lock_debug")]
+    pub fn owner(&self) -> Option<lockdep::Owner> {
+        self.info.owner()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+#[cfg(kconfig = "lock_debug")]
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::released(self.info, Access::Shared);
+    }
+}
+
+#[cfg(kconfig = "lock_debug")]
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        lockdep::released(self.info, Access::Exclusive);
+    }
+}
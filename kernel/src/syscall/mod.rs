@@ -0,0 +1,181 @@
+//! System calls
+//!
+//! The architecture entry code calls [`dispatch`] with the syscall number and raw arguments.
+//! Handlers return a non-negative value on success, or a negated [`SyscallError`].
+
+use core::fmt;
+
+use crate::{
+    mm::page_table::PageTableFlags,
+    process::{self, File, USER_LOG_RING},
+    time,
+    util::logring,
+};
+
+/// The end of the lower half, where user memory ends
+const USER_END: usize = 0x0000_8000_0000_0000;
+
+pub const SYS_EXIT: usize = 0;
+pub const SYS_WRITE: usize = 1;
+pub const SYS_CLOCK_GETTIME: usize = 2;
+pub const SYS_LOG_MAP: usize = 3;
+pub const SYS_LOG_POLL: usize = 4;
+
+pub const CLOCK_MONOTONIC: usize = 0;
+pub const CLOCK_REALTIME: usize = 1;
+
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+/// Error codes returned to userspace, matching their Linux values
+#[repr(isize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    BadFd = 9,
+    NoMem = 12,
+    Fault = 14,
+    NoDev = 19,
+    Invalid = 22,
+    NoSys = 38,
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::BadFd => "bad file descriptor",
+            Self::NoMem => "out of memory",
+            Self::Fault => "bad address",
+            Self::NoDev => "no such device",
+            Self::Invalid => "invalid argument",
+            Self::NoSys => "function not implemented",
+        })
+    }
+}
+
+impl core::error::Error for SyscallError {}
+
+pub type SyscallResult = Result<usize, SyscallError>;
+pub type SyscallHandler = fn(args: [usize; 6]) -> SyscallResult;
+
+pub struct Syscall {
+    pub name: &'static str,
+    pub handler: SyscallHandler,
+}
+
+/// The syscall table, indexed by syscall number
+static SYSCALLS: &[Syscall] = &[
+    Syscall {
+        name: "exit",
+        handler: sys_exit,
+    },
+    Syscall {
+        name: "write",
+        handler: sys_write,
+    },
+    Syscall {
+        name: "clock_gettime",
+        handler: sys_clock_gettime,
+    },
+    Syscall {
+        name: "log_map",
+        handler: sys_log_map,
+    },
+    Syscall {
+        name: "log_poll",
+        handler: sys_log_poll,
+    },
+];
+
+/// Runs a system call, returning the value for the return register
+pub fn dispatch(number: usize, args: [usize; 6]) -> isize {
+    let result = match SYSCALLS.get(number) {
+        Some(syscall) => (syscall.handler)(args),
+        None => Err(SyscallError::NoSys),
+    };
+    match result {
+        Ok(value) => value as isize,
+        Err(err) => -(err as isize),
+    }
+}
+
+/// Returns the name of a syscall, for tracing
+pub fn name(number: usize) -> Option<&'static str> {
+    SYSCALLS.get(number).map(|syscall| syscall.name)
+}
+
+/// Checks that a user buffer lies entirely in user memory
+///
+/// # Safety
+/// The memory must be mapped, as page faults in the kernel aren't recoverable yet.
+unsafe fn user_slice<'a>(ptr: usize, len: usize) -> Result<&'a [u8], SyscallError> {
+    let end = ptr.checked_add(len).ok_or(SyscallError::Fault)?;
+    if ptr == 0 || end > USER_END {
+        return Err(SyscallError::Fault);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len) })
+}
+
+/// `exit(code)`: ends the ring 3 payload
+fn sys_exit([code, ..]: [usize; 6]) -> SyscallResult {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        crate::arch::x86_64::syscall::exit_user(code as i64)
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let _ = code;
+        Err(SyscallError::NoSys)
+    }
+}
+
+/// `write(fd, buf, len)`: writes to an open file, returning the number of bytes written
+fn sys_write([fd, ptr, len, ..]: [usize; 6]) -> SyscallResult {
+    // Payloads started without a process only have the standard streams
+    let file = match process::current() {
+        Some(process) => process.files().lock().get(fd),
+        None => (fd == STDOUT || fd == STDERR).then_some(File::Console),
+    };
+    let File::Console = file.ok_or(SyscallError::BadFd)?;
+    let bytes = unsafe { user_slice(ptr, len)? };
+    for chunk in bytes.utf8_chunks() {
+        crate::util::kprint::kprint_internal(format_args!("{}", chunk.valid()));
+        if !chunk.invalid().is_empty() {
+            crate::util::kprint::kprint_internal(format_args!("\u{FFFD}"));
+        }
+    }
+    Ok(len)
+}
+
+/// `clock_gettime(clock)`: returns the time of a clock in nanoseconds
+fn sys_clock_gettime([clock, ..]: [usize; 6]) -> SyscallResult {
+    match clock {
+        CLOCK_MONOTONIC => Ok(time::monotonic_ns() as usize),
+        CLOCK_REALTIME => Ok(time::wall_clock().as_nanos() as usize),
+        _ => Err(SyscallError::Invalid),
+    }
+}
+
+/// `log_map()`: maps the kernel log ring read-only into the process, returning its address
+fn sys_log_map(_: [usize; 6]) -> SyscallResult {
+    let process = process::current().ok_or(SyscallError::Invalid)?;
+    let (phys, pages) = logring::frames().ok_or(SyscallError::NoDev)?;
+    let mut address_space = process.address_space().lock();
+    if address_space.translate(USER_LOG_RING) != Some(phys) {
+        address_space
+            .map_shared(USER_LOG_RING, phys, pages, PageTableFlags::NO_EXECUTE)
+            .map_err(|_| SyscallError::NoMem)?;
+    }
+    Ok(USER_LOG_RING.as_usize())
+}
+
+/// `log_poll(seq, timeout_ns)`: waits until the log ring head passes `seq`, returning the head
+fn sys_log_poll([seq, timeout, ..]: [usize; 6]) -> SyscallResult {
+    let deadline = time::monotonic_ns().saturating_add(timeout as u64);
+    loop {
+        let head = logring::head();
+        if head > seq as u64 || time::monotonic_ns() >= deadline {
+            return Ok(head as usize);
+        }
+        core::hint::spin_loop();
+    }
+}
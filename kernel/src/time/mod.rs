@@ -0,0 +1,197 @@
+//! Kernel timekeeping
+
+use core::{
+    sync::atomic::{AtomicI64, Ordering},
+    time::Duration,
+};
+
+use alloc::vec::Vec;
+
+use crate::{arch::instructions::interrupts, sync::RwLock};
+
+pub use timer::Timer;
+
+pub mod date;
+pub mod selftest;
+pub mod tick;
+pub mod timer;
+#[cfg(target_arch = "x86_64")]
+pub mod tsc;
+#[cfg(target_arch = "x86_64")]
+pub mod tsc_sync;
+
+/// The maximum number of clock sources or clock event devices that can be registered
+const MAX_CLOCKS: usize = 8;
+
+/// A free running counter that the kernel can read the time from
+#[derive(Debug)]
+pub struct ClockSource {
+    pub name: &'static str,
+    /// Higher rated sources are preferred
+    pub rating: u32,
+    /// Reads the counter, converted to nanoseconds
+    pub read_ns: fn() -> u64,
+}
+
+bitflags::bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct ClockEventFeatures: u8 {
+        const PERIODIC = 1 << 0;
+        const ONESHOT = 1 << 1;
+    }
+}
+
+/// A device that can raise timer interrupts
+#[derive(Debug)]
+pub struct ClockEventDevice {
+    pub name: &'static str,
+    /// Higher rated devices are preferred
+    pub rating: u32,
+    pub features: ClockEventFeatures,
+    /// The interrupt vector the device fires on
+    pub vector: u8,
+    /// Fires an interrupt every `period_ns`
+    pub set_periodic: fn(period_ns: u64),
+    /// Fires a single interrupt after `delta_ns`
+    pub set_oneshot: fn(delta_ns: u64),
+    /// Stops the device from firing
+    pub shutdown: fn(),
+}
+
+struct ClockState {
+    sources: [Option<&'static ClockSource>; MAX_CLOCKS],
+    events: [Option<&'static ClockEventDevice>; MAX_CLOCKS],
+    current: Option<&'static ClockSource>,
+    /// Added to the current source, so time stays monotonic when switching sources
+    offset: u64,
+}
+
+static CLOCKS: RwLock<ClockState> = RwLock::new(ClockState {
+    sources: [None; MAX_CLOCKS],
+    events: [None; MAX_CLOCKS],
+    current: None,
+    offset: 0,
+});
+
+/// Registers a clock source, switching to it if it is rated higher than the current one
+pub fn register_clocksource(source: &'static ClockSource) {
+    interrupts::without_interrupts(|| {
+        let mut clocks = CLOCKS.write();
+        if let Some(slot) = clocks.sources.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(source);
+        }
+
+        let better = clocks.current.is_none_or(|current| source.rating > current.rating);
+        if better {
+            let now = clocks
+                .current
+                .map(|current| (current.read_ns)() + clocks.offset)
+                .unwrap_or(0);
+            clocks.offset = now.saturating_sub((source.read_ns)());
+            clocks.current = Some(source);
+        }
+    });
+}
+
+/// Removes a clock source that turned out to be unreliable
+///
+/// If it was the current source, the highest rated remaining one takes over, continuing from the
+/// same time.
+pub fn unregister_clocksource(source: &'static ClockSource) {
+    interrupts::without_interrupts(|| {
+        let mut clocks = CLOCKS.write();
+        for slot in clocks.sources.iter_mut() {
+            if slot.is_some_and(|other| core::ptr::eq(other, source)) {
+                *slot = None;
+            }
+        }
+        if !clocks.current.is_some_and(|current| core::ptr::eq(current, source)) {
+            return;
+        }
+
+        let now = (source.read_ns)() + clocks.offset;
+        let next = clocks
+            .sources
+            .iter()
+            .flatten()
+            .max_by_key(|other| other.rating)
+            .copied();
+        clocks.offset = next.map_or(now, |next| now.saturating_sub((next.read_ns)()));
+        clocks.current = next;
+    });
+}
+
+/// Registers a clock event device
+pub fn register_clock_event(device: &'static ClockEventDevice) {
+    interrupts::without_interrupts(|| {
+        let mut clocks = CLOCKS.write();
+        if let Some(slot) = clocks.events.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(device);
+        }
+    });
+}
+
+/// Returns the registered clock sources
+pub fn clocksources() -> Vec<&'static ClockSource> {
+    CLOCKS.read().sources.iter().flatten().copied().collect()
+}
+
+/// Returns the clock source currently used for timekeeping
+pub fn current_clocksource() -> Option<&'static ClockSource> {
+    CLOCKS.read().current
+}
+
+/// Returns the highest rated clock event device with the given features
+pub fn best_clock_event(features: ClockEventFeatures) -> Option<&'static ClockEventDevice> {
+    CLOCKS
+        .read()
+        .events
+        .iter()
+        .flatten()
+        .filter(|dev| dev.features.contains(features))
+        .max_by_key(|dev| dev.rating)
+        .copied()
+}
+
+/// Returns the monotonic time since the kernel started keeping time
+///
+/// Returns zero until a clock source has been registered.
+pub fn time_since_boot() -> Duration {
+    Duration::from_nanos(monotonic_ns())
+}
+
+/// Returns the monotonic time in nanoseconds from the best available clock source
+pub fn monotonic_ns() -> u64 {
+    let clocks = CLOCKS.read();
+    match clocks.current {
+        Some(source) => (source.read_ns)() + clocks.offset,
+        None => 0,
+    }
+}
+
+/// The UNIX time in nanoseconds at which the monotonic clock was zero
+static BOOT_WALL_NS: AtomicI64 = AtomicI64::new(0);
+
+/// Sets the wall clock from a known UNIX time, in seconds
+pub fn set_wall_clock(unix_secs: i64) {
+    BOOT_WALL_NS.store(unix_secs * 1_000_000_000 - monotonic_ns() as i64, Ordering::Relaxed);
+}
+
+/// Reads the wall clock from the RTC
+#[cfg(target_arch = "x86_64")]
+pub fn init_wall_clock() {
+    set_wall_clock(crate::arch::x86_64::rtc::read().to_unix());
+}
+
+/// Returns the time since the UNIX epoch
+///
+/// This is derived from the monotonic clock, so it never jumps backwards on its own.
+pub fn wall_clock() -> Duration {
+    let ns = BOOT_WALL_NS.load(Ordering::Relaxed) + monotonic_ns() as i64;
+    Duration::from_nanos(ns.max(0) as u64)
+}
+
+/// Returns the current date in UTC
+pub fn now() -> date::DateTime {
+    date::DateTime::from_unix(wall_clock().as_secs() as i64)
+}
@@ -0,0 +1,130 @@
+//! Clock source cross-checks
+//!
+//! Every registered clock source measures the same interval, and each is compared against the
+//! median of them all. A source that disagrees by more than [`THRESHOLD_PPM`] is miscalibrated, or
+//! the reference it was calibrated against was, which otherwise only shows as log timestamps that
+//! run too fast or too slow. The check runs at boot, and on demand with the `clocks` shell command.
+
+use core::fmt;
+
+use alloc::vec::Vec;
+
+use crate::{arch::instructions::interrupts, kprintln, time::ClockSource};
+
+/// The interval measured at boot
+pub const BOOT_INTERVAL_NS: u64 = 20_000_000;
+/// How far a source may be off before it is reported, 0.5%
+pub const THRESHOLD_PPM: i64 = 5000;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ClockCheckError {
+    /// There is nothing to compare with
+    TooFewSources,
+}
+
+impl fmt::Display for ClockCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooFewSources => f.write_str("less than two clock sources"),
+        }
+    }
+}
+
+impl core::error::Error for ClockCheckError {}
+
+/// What a clock source measured
+#[derive(Debug, Clone, Copy)]
+pub struct Measurement {
+    pub source: &'static ClockSource,
+    pub elapsed_ns: u64,
+    /// How much faster the source ran than the median, in parts per million
+    pub deviation_ppm: i64,
+}
+
+impl Measurement {
+    pub fn diverges(&self) -> bool {
+        self.deviation_ppm.abs() > THRESHOLD_PPM
+    }
+}
+
+/// Returns how far each interval is from their median, in parts per million
+///
+/// With an even number of intervals the lower of the middle two is the median, so with two
+/// sources the slower one is the reference.
+fn deviations(elapsed: &[u64]) -> Vec<i64> {
+    let mut sorted = elapsed.to_vec();
+    sorted.sort_unstable();
+    let median = sorted[(sorted.len() - 1) / 2].max(1) as i128;
+    elapsed
+        .iter()
+        .map(|ns| ((*ns as i128 - median) * 1_000_000 / median) as i64)
+        .collect()
+}
+
+/// Measures `interval_ns` on the current clock source with every registered source
+///
+/// Interrupts are disabled meanwhile, so keep the interval short.
+pub fn run(interval_ns: u64) -> Result<Vec<Measurement>, ClockCheckError> {
+    let sources = super::clocksources();
+    if sources.len() < 2 {
+        return Err(ClockCheckError::TooFewSources);
+    }
+    let elapsed: Vec<u64> = interrupts::without_interrupts(|| {
+        let start: Vec<u64> = sources.iter().map(|source| (source.read_ns)()).collect();
+        let deadline = super::monotonic_ns() + interval_ns;
+        while super::monotonic_ns() < deadline {
+            core::hint::spin_loop();
+        }
+        sources
+            .iter()
+            .zip(start)
+            .map(|(source, start)| (source.read_ns)().saturating_sub(start))
+            .collect()
+    });
+    Ok(sources
+        .into_iter()
+        .zip(deviations(&elapsed))
+        .zip(elapsed)
+        .map(|((source, deviation_ppm), elapsed_ns)| Measurement {
+            source,
+            elapsed_ns,
+            deviation_ppm,
+        })
+        .collect())
+}
+
+/// Runs the check at boot, warning about every source that diverges
+pub fn boot_check() {
+    let measurements = match run(BOOT_INTERVAL_NS) {
+        Ok(measurements) => measurements,
+        Err(err) => {
+            kprintln!(Debug, "time: skipping clock source check, {}", err);
+            return;
+        }
+    };
+    for measurement in measurements {
+        if measurement.diverges() {
+            kprintln!(
+                Warn,
+                "time: clock source {} is off by {} ppm, measured {} ns in {} ns",
+                measurement.source.name,
+                measurement.deviation_ppm,
+                measurement.elapsed_ns,
+                BOOT_INTERVAL_NS
+            );
+        }
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_deviations() {
+        assert_eq!(deviations(&[20_000_000, 20_001_000, 19_999_000]), [0, 50, -50]);
+        // A TSC calibrated 10% fast against the other two
+        assert_eq!(deviations(&[22_000_000, 20_000_000, 20_000_100])[0], 99_994);
+        assert_eq!(deviations(&[1000, 1010]), [0, 10_000]);
+    }
+}
@@ -0,0 +1,156 @@
+//! The periodic tick
+//!
+//! [`init`] programs the best clock event device to interrupt [`HZ`] times a second, 250 by
+//! default, or 100 or 1000 with the `tick_100` and `tick_1000` kconfig options. Fewer ticks spend
+//! less time in interrupts, more make anything driven by the tick more responsive. Devices that
+//! can only fire once are re-armed from every tick.
+//!
+//! Every tick advances [`jiffies`] by the periods since the last one. A tick that comes in more
+//! than a period late, because interrupts were disabled for that long or the device was re-armed
+//! late, counts the periods it missed as skipped, so the `tick` statistics show per CPU how many
+//! ticks were delivered and how many were skipped, which is also what stopping the tick on idle
+//! CPUs will save.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering},
+};
+
+use crate::{
+    percpu::{self, MAX_CPUS, PerCpu},
+    time::{self, ClockEventDevice, ClockEventFeatures},
+};
+
+/// The ticks per second
+pub const HZ: u64 = if crate::config::TICK_100 {
+    100
+} else if crate::config::TICK_1000 {
+    1000
+} else {
+    250
+};
+/// The time between two ticks
+pub const PERIOD_NS: u64 = 1_000_000_000 / HZ;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickError {
+    /// No clock event device is registered
+    NoDevice,
+}
+
+impl fmt::Display for TickError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoDevice => f.write_str("no clock event device"),
+        }
+    }
+}
+
+impl core::error::Error for TickError {}
+
+struct CpuTicks {
+    delivered: AtomicU64,
+    skipped: AtomicU64,
+}
+
+static TICKS: PerCpu<CpuTicks> = PerCpu::new(
+    [const {
+        CpuTicks {
+            delivered: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+        }
+    }; MAX_CPUS],
+);
+static JIFFIES: AtomicU64 = AtomicU64::new(0);
+/// When the last period the tick accounted for ended
+static LAST_NS: AtomicU64 = AtomicU64::new(0);
+static DEVICE: AtomicPtr<ClockEventDevice> = AtomicPtr::new(core::ptr::null_mut());
+/// Whether the device has to be re-armed for every tick
+static ONESHOT: AtomicBool = AtomicBool::new(false);
+
+/// Starts the tick on the best clock event device, preferring devices that fire periodically
+pub fn init() -> Result<&'static ClockEventDevice, TickError> {
+    let (device, oneshot) = match time::best_clock_event(ClockEventFeatures::PERIODIC) {
+        Some(device) => (device, false),
+        None => (
+            time::best_clock_event(ClockEventFeatures::ONESHOT).ok_or(TickError::NoDevice)?,
+            true,
+        ),
+    };
+    ONESHOT.store(oneshot, Ordering::Relaxed);
+    LAST_NS.store(time::monotonic_ns(), Ordering::Relaxed);
+    DEVICE.store(device as *const _ as *mut _, Ordering::Release);
+    if oneshot {
+        (device.set_oneshot)(PERIOD_NS);
+    } else {
+        (device.set_periodic)(PERIOD_NS);
+    }
+    crate::stats::register("tick", dump_stats);
+    Ok(device)
+}
+
+/// Returns the ticks since the tick was started, counting the skipped ones
+pub fn jiffies() -> u64 {
+    JIFFIES.load(Ordering::Relaxed)
+}
+
+/// Returns how many periods a tick at `now_ns` accounts for, at least one, and when they end
+///
+/// Ticks come in a little early or late, so the periods are rounded to the nearest.
+fn periods_since(last_ns: u64, now_ns: u64) -> (u64, u64) {
+    let periods = (now_ns.saturating_sub(last_ns) + PERIOD_NS / 2) / PERIOD_NS;
+    let periods = periods.max(1);
+    (periods, last_ns + periods * PERIOD_NS)
+}
+
+/// Accounts for a tick, called from the interrupt of the clock event device
+pub fn handle() {
+    let Some(device) = (unsafe { DEVICE.load(Ordering::Acquire).as_ref() }) else {
+        return;
+    };
+    let now = time::monotonic_ns();
+    let (periods, end) = periods_since(LAST_NS.load(Ordering::Relaxed), now);
+    LAST_NS.store(end, Ordering::Relaxed);
+    JIFFIES.fetch_add(periods, Ordering::Relaxed);
+    let ticks = TICKS.get();
+    ticks.delivered.fetch_add(1, Ordering::Relaxed);
+    ticks.skipped.fetch_add(periods - 1, Ordering::Relaxed);
+    crate::profile::sample();
+    #[cfg(target_arch = "x86_64")]
+    crate::arch::x86_64::watchdog::touch();
+    if ONESHOT.load(Ordering::Relaxed) {
+        (device.set_oneshot)((end + PERIOD_NS).saturating_sub(now));
+    }
+    time::timer::tick();
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(out, "hz: {}, jiffies: {}", HZ, jiffies())?;
+    for cpu in percpu::cpus() {
+        let ticks = TICKS.get_for(cpu.cpu_id as usize);
+        writeln!(
+            out,
+            "cpu{}: delivered={} skipped={}",
+            cpu.cpu_id,
+            ticks.delivered.load(Ordering::Relaxed),
+            ticks.skipped.load(Ordering::Relaxed)
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tick_periods_since() {
+        assert_eq!(periods_since(0, PERIOD_NS), (1, PERIOD_NS));
+        // Early and late ticks still count as one period
+        assert_eq!(periods_since(0, PERIOD_NS - 100), (1, PERIOD_NS));
+        assert_eq!(periods_since(0, PERIOD_NS + 100), (1, PERIOD_NS));
+        // Two periods were missed
+        assert_eq!(periods_since(PERIOD_NS, 4 * PERIOD_NS + 100), (3, 4 * PERIOD_NS));
+        assert_eq!(periods_since(PERIOD_NS, 0), (1, 2 * PERIOD_NS));
+    }
+}
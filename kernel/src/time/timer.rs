@@ -0,0 +1,385 @@
+//! Kernel timers
+//!
+//! [`Timer::after`] calls a function with its data once a duration has passed, and the handle it
+//! returns can [`cancel`](Timer::cancel) it until then. Like [`Work`], timers are a function and
+//! data rather than boxed closures, and live in a table of [`MAX_TIMERS`] entries, so they can be
+//! armed and cancelled from interrupt handlers too. Expired timers run from a tasklet on the CPU
+//! that takes the tick, with interrupts enabled, so the function must not block or allocate either,
+//! and should schedule work for anything longer. A timer may arm itself again to run periodically.
+//!
+//! The pending timers are kept in a hierarchical wheel of [`LEVELS`] levels of [`SLOTS`] slots,
+//! each level 8 times coarser than the one below: level 0 has a slot per tick, so timers less than
+//! [`SLOTS`] ticks away expire exactly on time, and timers further away are placed in a level
+//! where their expiry, rounded up to the slot, is less than a rotation away. They never move
+//! between levels, so arming and cancelling are constant time, at the cost of expiring up to about
+//! an eighth of their duration late. Timers never expire early, and timers further away than the
+//! wheel reaches, about [`MAX_TICKS`] ticks, expire at the end of it.
+//!
+//! [`Work`]: crate::workqueue::Work
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    arch::instructions::interrupts,
+    sync::Mutex,
+    time::tick,
+    workqueue::{self, Work},
+};
+
+/// The most timers that can be pending at once
+pub const MAX_TIMERS: usize = 256;
+pub const LEVELS: usize = 8;
+pub const SLOTS: usize = 64;
+/// How much coarser every level is than the one below, as a shift
+const LEVEL_SHIFT: usize = 3;
+/// The furthest a timer can be in the future, in ticks
+pub const MAX_TICKS: u64 = (SLOTS as u64 - 1) << (LEVEL_SHIFT * (LEVELS - 1));
+
+/// The function of a timer, called with its data once it expires
+pub type TimerFn = fn(data: usize);
+
+const NIL: u16 = u16::MAX;
+/// The list of expired timers that haven't run yet, after the slots of the wheel
+const EXPIRED: usize = LEVELS * SLOTS;
+
+const _: () = assert!(MAX_TIMERS < NIL as usize, "entries are indexed with u16");
+const _: () = assert!(EXPIRED < NIL as usize, "lists are indexed with u16");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// All [`MAX_TIMERS`] timers are pending
+    Full,
+}
+
+impl fmt::Display for TimerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => write!(f, "all {} timers are pending", MAX_TIMERS),
+        }
+    }
+}
+
+impl core::error::Error for TimerError {}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    func: TimerFn,
+    data: usize,
+    /// Bumped whenever the entry is freed, so handles to the timer it was are stale
+    generation: u32,
+    /// The list the timer is in, or [`NIL`] if the entry is free
+    list: u16,
+    prev: u16,
+    next: u16,
+}
+
+impl Entry {
+    const FREE: Self = Self {
+        func: |_| {},
+        data: 0,
+        generation: 0,
+        list: NIL,
+        prev: NIL,
+        next: NIL,
+    };
+}
+
+struct Wheel {
+    /// The last tick the wheel expired timers for
+    clk: u64,
+    entries: [Entry; MAX_TIMERS],
+    /// The first timer of every slot, followed by the expired timers
+    heads: [u16; EXPIRED + 1],
+    /// Free entries, linked through `next`
+    free: u16,
+    /// Entries from here on have never been used, and aren't on the free list
+    unused: u16,
+    pending: usize,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Self {
+            clk: 0,
+            entries: [Entry::FREE; MAX_TIMERS],
+            heads: [NIL; EXPIRED + 1],
+            free: NIL,
+            unused: 0,
+            pending: 0,
+        }
+    }
+
+    /// Returns the slot list a timer expiring at `expires` goes in
+    fn list_for(&self, expires: u64) -> usize {
+        let expires = expires.clamp(self.clk + 1, self.clk + MAX_TICKS);
+        for level in 0..LEVELS {
+            let shift = LEVEL_SHIFT * level;
+            let rounded = expires.next_multiple_of(1 << shift);
+            if rounded - self.clk <= (SLOTS as u64) << shift {
+                return level * SLOTS + (rounded >> shift) as usize % SLOTS;
+            }
+        }
+        unreachable!("the expiry is clamped to the reach of the wheel")
+    }
+
+    fn link(&mut self, idx: u16, list: usize) {
+        let head = self.heads[list];
+        let entry = &mut self.entries[idx as usize];
+        entry.list = list as u16;
+        entry.prev = NIL;
+        entry.next = head;
+        if head != NIL {
+            self.entries[head as usize].prev = idx;
+        }
+        self.heads[list] = idx;
+    }
+
+    fn unlink(&mut self, idx: u16) {
+        let Entry { list, prev, next, .. } = self.entries[idx as usize];
+        match prev {
+            NIL => self.heads[list as usize] = next,
+            prev => self.entries[prev as usize].next = next,
+        }
+        if next != NIL {
+            self.entries[next as usize].prev = prev;
+        }
+    }
+
+    fn release(&mut self, idx: u16) {
+        let entry = &mut self.entries[idx as usize];
+        entry.generation = entry.generation.wrapping_add(1);
+        entry.list = NIL;
+        entry.next = self.free;
+        self.free = idx;
+        self.pending -= 1;
+    }
+
+    /// Arms a timer expiring at tick `expires`, `now` being the current tick
+    fn arm(&mut self, now: u64, expires: u64, func: TimerFn, data: usize) -> Result<Timer, TimerError> {
+        // The wheel only advances while timers are pending, so an idle one catches up first,
+        // as timers are placed relative to it
+        if self.pending == 0 {
+            self.clk = self.clk.max(now);
+        }
+        let idx = match self.free {
+            NIL if (self.unused as usize) < MAX_TIMERS => {
+                self.unused += 1;
+                self.unused - 1
+            }
+            NIL => return Err(TimerError::Full),
+            idx => {
+                self.free = self.entries[idx as usize].next;
+                idx
+            }
+        };
+        let entry = &mut self.entries[idx as usize];
+        entry.func = func;
+        entry.data = data;
+        let generation = entry.generation;
+        self.link(idx, self.list_for(expires));
+        self.pending += 1;
+        Ok(Timer { idx, generation })
+    }
+
+    fn is_pending(&self, timer: Timer) -> bool {
+        let entry = &self.entries[timer.idx as usize];
+        entry.generation == timer.generation && entry.list != NIL
+    }
+
+    fn cancel(&mut self, timer: Timer) -> bool {
+        if !self.is_pending(timer) {
+            return false;
+        }
+        self.unlink(timer.idx);
+        self.release(timer.idx);
+        true
+    }
+
+    /// Takes the next timer that expired by tick `now`, advancing the wheel as far as needed
+    fn expire(&mut self, now: u64) -> Option<(TimerFn, usize)> {
+        while self.heads[EXPIRED] == NIL && self.clk < now {
+            if self.pending == 0 {
+                self.clk = now;
+                return None;
+            }
+            self.clk += 1;
+            for level in 0..LEVELS {
+                let shift = LEVEL_SHIFT * level;
+                if !self.clk.is_multiple_of(1 << shift) {
+                    break;
+                }
+                let list = level * SLOTS + (self.clk >> shift) as usize % SLOTS;
+                while self.heads[list] != NIL {
+                    let idx = self.heads[list];
+                    self.unlink(idx);
+                    self.link(idx, EXPIRED);
+                }
+            }
+        }
+        let idx = self.heads[EXPIRED];
+        if idx == NIL {
+            return None;
+        }
+        let Entry { func, data, .. } = self.entries[idx as usize];
+        self.unlink(idx);
+        self.release(idx);
+        Some((func, data))
+    }
+}
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+static EXPIRE: Work = Work::new(|_| run_expired(), 0);
+/// How many timers are pending, so the tick doesn't take the lock for nothing
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static FIRED: AtomicU64 = AtomicU64::new(0);
+static CANCELLED: AtomicU64 = AtomicU64::new(0);
+
+fn with_wheel<R>(func: impl FnOnce(&mut Wheel) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        let result = func(&mut wheel);
+        PENDING.store(wheel.pending, Ordering::Relaxed);
+        result
+    })
+}
+
+/// A handle to a timer, which goes stale once the timer expires or is cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timer {
+    idx: u16,
+    generation: u32,
+}
+
+impl Timer {
+    /// Calls `func` with `data` once `duration` has passed, rounded up to whole ticks
+    pub fn after(duration: Duration, func: TimerFn, data: usize) -> Result<Self, TimerError> {
+        let ticks = (duration.as_nanos() as u64).div_ceil(tick::PERIOD_NS).max(1);
+        with_wheel(|wheel| {
+            let now = tick::jiffies();
+            wheel.arm(now, now.saturating_add(ticks), func, data)
+        })
+    }
+
+    /// Stops the timer from expiring, returning `false` if it already expired or was cancelled
+    pub fn cancel(self) -> bool {
+        let cancelled = with_wheel(|wheel| wheel.cancel(self));
+        if cancelled {
+            CANCELLED.fetch_add(1, Ordering::Relaxed);
+        }
+        cancelled
+    }
+
+    /// Returns whether the timer has neither expired nor been cancelled yet
+    pub fn is_pending(self) -> bool {
+        with_wheel(|wheel| wheel.is_pending(self))
+    }
+}
+
+/// Queues the expired timers to run, called from the tick
+pub fn tick() {
+    if PENDING.load(Ordering::Relaxed) > 0 {
+        workqueue::schedule_tasklet(&EXPIRE);
+    }
+}
+
+/// Runs the expired timers one by one, without holding the lock so they can arm timers again
+fn run_expired() {
+    let now = tick::jiffies();
+    while let Some((func, data)) = with_wheel(|wheel| wheel.expire(now)) {
+        func(data);
+        FIRED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn init() {
+    crate::stats::register("timer", dump_stats);
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    writeln!(
+        out,
+        "pending: {}/{}, fired: {}, cancelled: {}",
+        PENDING.load(Ordering::Relaxed),
+        MAX_TIMERS,
+        FIRED.load(Ordering::Relaxed),
+        CANCELLED.load(Ordering::Relaxed)
+    )
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::{boxed::Box, vec::Vec};
+
+    use super::*;
+
+    /// Advances the wheel tick by tick, returning the data of every timer with the tick it expired
+    fn run(wheel: &mut Wheel, until: u64) -> Vec<(usize, u64)> {
+        let mut expired = Vec::new();
+        for now in wheel.clk + 1..=until {
+            while let Some((_, data)) = wheel.expire(now) {
+                expired.push((data, now));
+            }
+        }
+        expired
+    }
+
+    #[test]
+    fn timer_wheel() {
+        let mut wheel = Box::new(Wheel::new());
+        let expiries = [1, 5, 63, 64, 65, 100, 1000, 4097, 100_000];
+        for (idx, expires) in expiries.iter().enumerate() {
+            wheel.arm(0, *expires, |_| {}, idx).unwrap();
+        }
+        let cancelled = wheel.arm(0, 50, |_| {}, 99).unwrap();
+        assert!(wheel.cancel(cancelled));
+        assert!(!wheel.cancel(cancelled));
+
+        let expired = run(&mut wheel, 120_000);
+        assert_eq!(expired.len(), expiries.len());
+        for (idx, at) in expired {
+            // Never early, and no later than an eighth of the duration
+            let expires = expiries[idx];
+            assert!(
+                at >= expires && at - expires <= expires / 8,
+                "{} expired at {}",
+                expires,
+                at
+            );
+            if expires <= SLOTS as u64 {
+                assert_eq!(at, expires);
+            }
+        }
+        assert_eq!(wheel.pending, 0);
+
+        // Armed relative to the ticks already expired, and stale once it expires
+        let timer = wheel.arm(wheel.clk, wheel.clk + 3, |_| {}, 0).unwrap();
+        assert!(timer != cancelled && wheel.is_pending(timer));
+        let until = wheel.clk + 3;
+        assert_eq!(run(&mut wheel, until), [(0, 120_003)]);
+        assert!(!wheel.is_pending(timer) && !wheel.cancel(timer));
+
+        for _ in 0..MAX_TIMERS {
+            wheel.arm(0, u64::MAX, |_| {}, 0).unwrap();
+        }
+        assert_eq!(wheel.arm(0, 1, |_| {}, 0), Err(TimerError::Full));
+    }
+
+    #[test]
+    fn timer_wheel_idle() {
+        let mut wheel = Box::new(Wheel::new());
+        wheel.arm(0, 10, |_| {}, 0).unwrap();
+        assert_eq!(run(&mut wheel, 10), [(0, 10)]);
+
+        // Nothing expired the wheel while it was idle, so it lags behind the ticks
+        let now = 10_000;
+        wheel.arm(now, now + 1, |_| {}, 1).unwrap();
+        wheel.arm(now, now + 100, |_| {}, 2).unwrap();
+        assert_eq!(wheel.clk, now);
+        // Exactly on time in level 0, and rounded up to the 8 tick slot of level 1
+        assert_eq!(run(&mut wheel, now + 200), [(1, now + 1), (2, now + 104)]);
+    }
+}
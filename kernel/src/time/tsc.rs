@@ -0,0 +1,170 @@
+//! Time Stamp Counter clock source
+//!
+//! The TSC is calibrated at boot, either from CPUID leaf 0x15 or against PIT channel 2,
+//! and then converted to nanoseconds using a fixed point multiplier.
+//! Once a more precise clock source is available, it can be recalibrated with [`recalibrate`].
+
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{
+    arch::x86_64::{
+        cpu::{self, Feature},
+        pit,
+    },
+    time::{ClockSource, register_clocksource},
+};
+
+/// Shift used for the cycles to nanoseconds conversion
+const NS_SHIFT: u32 = 32;
+
+/// Duration of the calibration window in milliseconds
+const CALIBRATE_MS: u64 = 10;
+
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+static TSC_MULT: AtomicU64 = AtomicU64::new(0);
+static TSC_INVARIANT: AtomicBool = AtomicBool::new(false);
+/// Whether the frequency was enumerated by CPUID, rather than measured
+static TSC_ENUMERATED: AtomicBool = AtomicBool::new(false);
+
+static TSC_CLOCKSOURCE: ClockSource = ClockSource {
+    name: "tsc",
+    rating: 300,
+    read_ns: monotonic_ns,
+};
+
+/// A TSC that can drift with frequency changes is only used when nothing better is available
+static TSC_UNSTABLE_CLOCKSOURCE: ClockSource = ClockSource {
+    name: "tsc-unstable",
+    rating: 100,
+    read_ns: monotonic_ns,
+};
+
+/// An error returned when the TSC cannot be used as a clock source
+#[derive(Debug, Clone, Copy)]
+pub struct TscUnavailable;
+
+impl core::fmt::Display for TscUnavailable {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("TSC is not available or could not be calibrated")
+    }
+}
+
+impl core::error::Error for TscUnavailable {}
+
+/// Reads the raw value of the TSC
+#[inline]
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Detects and calibrates the TSC, starting the monotonic clock
+///
+/// # Safety
+/// Must only be called once, on the BSP, with interrupts disabled, after [`crate::arch::x86_64::cpu::init`].
+pub unsafe fn init() -> Result<(), TscUnavailable> {
+    if !cpu::has(Feature::Tsc) {
+        return Err(TscUnavailable);
+    }
+
+    TSC_INVARIANT.store(cpu::has(Feature::InvariantTsc), Ordering::Relaxed);
+
+    let khz = match cpuid_frequency_khz() {
+        Some(khz) => {
+            TSC_ENUMERATED.store(true, Ordering::Relaxed);
+            khz
+        }
+        // SAFETY: Nothing uses the PIT yet
+        None => unsafe { pit::calibrate_against(read, CALIBRATE_MS) }.map_or(0, |hz| hz / 1000),
+    };
+    if khz == 0 {
+        return Err(TscUnavailable);
+    }
+
+    TSC_MULT.store((1_000_000u64 << NS_SHIFT) / khz, Ordering::Relaxed);
+    TSC_BASE.store(read(), Ordering::Relaxed);
+    TSC_KHZ.store(khz, Ordering::Release);
+
+    register_clocksource(clocksource());
+    Ok(())
+}
+
+/// Returns the clock source the TSC is registered as, depending on whether it is invariant
+pub fn clocksource() -> &'static ClockSource {
+    match is_invariant() {
+        true => &TSC_CLOCKSOURCE,
+        false => &TSC_UNSTABLE_CLOCKSOURCE,
+    }
+}
+
+/// Measures the TSC frequency against a reference clock, if it was not enumerated by CPUID
+///
+/// The monotonic time is preserved across the recalibration.
+///
+/// # Safety
+/// Must be called on the BSP with interrupts disabled.
+pub unsafe fn recalibrate(reference: &ClockSource) {
+    if frequency_khz() == 0 || TSC_ENUMERATED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let ref_start = (reference.read_ns)();
+    let start = read();
+    while (reference.read_ns)() - ref_start < CALIBRATE_MS * 1_000_000 {
+        core::hint::spin_loop();
+    }
+    let ref_elapsed = (reference.read_ns)() - ref_start;
+    let cycles = read() - start;
+
+    let khz = (cycles as u128 * 1_000_000 / ref_elapsed as u128) as u64;
+    if khz == 0 {
+        return;
+    }
+
+    let now_ns = monotonic_ns();
+    let mult = (1_000_000u64 << NS_SHIFT) / khz;
+    let elapsed_cycles = (((now_ns as u128) << NS_SHIFT) / mult as u128) as u64;
+    TSC_MULT.store(mult, Ordering::Relaxed);
+    TSC_BASE.store(read().saturating_sub(elapsed_cycles), Ordering::Relaxed);
+    TSC_KHZ.store(khz, Ordering::Release);
+}
+
+/// Returns whether the TSC runs at a constant rate across P-, C- and T-states
+pub fn is_invariant() -> bool {
+    TSC_INVARIANT.load(Ordering::Relaxed)
+}
+
+/// Returns the calibrated TSC frequency in kHz, or zero if it is not calibrated
+pub fn frequency_khz() -> u64 {
+    TSC_KHZ.load(Ordering::Acquire)
+}
+
+/// Converts a number of TSC cycles into nanoseconds
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    ((cycles as u128 * TSC_MULT.load(Ordering::Relaxed) as u128) >> NS_SHIFT) as u64
+}
+
+/// Returns the nanoseconds elapsed since the TSC was calibrated
+pub fn monotonic_ns() -> u64 {
+    if frequency_khz() == 0 {
+        return 0;
+    }
+    cycles_to_ns(read().saturating_sub(TSC_BASE.load(Ordering::Relaxed)))
+}
+
+/// Reads the TSC frequency from CPUID leaf 0x15, if it is enumerated
+fn cpuid_frequency_khz() -> Option<u64> {
+    let max_leaf = __cpuid(0).eax;
+    if max_leaf < 0x15 {
+        return None;
+    }
+    let res = __cpuid(0x15);
+    // EAX: denominator, EBX: numerator, ECX: crystal frequency in Hz
+    if res.eax == 0 || res.ebx == 0 || res.ecx == 0 {
+        return None;
+    }
+    Some(res.ecx as u64 * res.ebx as u64 / res.eax as u64 / 1000)
+}
@@ -0,0 +1,343 @@
+//! TSC synchronization between CPUs
+//!
+//! Every CPU has its own TSC, and firmware doesn't always start them at the same time, so a
+//! timestamp taken on one CPU can be behind one taken earlier on another. The BSP measures the
+//! offset of every other CPU with a ping-pong over a shared cache line: it reads its TSC, asks the
+//! other CPU for its TSC, and reads its own again once the reply arrives. The round trip with the
+//! shortest delay gives the tightest bound on the offset.
+//!
+//! An offset larger than the round trip is compensated with `IA32_TSC_ADJUST` where the CPU has
+//! it. If that isn't possible, or the CPUs still disagree by more than [`MAX_SKEW_NS`], the TSC
+//! is removed as a clock source and the next best one, usually the HPET, takes over.
+
+use core::{
+    arch::x86_64::{_mm_lfence, _rdtsc},
+    fmt,
+    sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+};
+
+use crate::{
+    arch::{
+        instructions::interrupts,
+        registers::msr::Msr,
+        x86_64::{
+            apic,
+            cpu::{self, Feature},
+        },
+    },
+    irq::{self, IrqError},
+    kprintln,
+    percpu::{self, MAX_CPUS, PerCpu},
+    sync::Once,
+    time::{self, tsc},
+};
+
+/// The number of round trips measured per CPU
+const ROUNDS: u64 = 64;
+/// How long either side waits for the other before giving up
+const TIMEOUT_NS: u64 = 10_000_000;
+/// The largest offset between two CPUs that the TSC is still used as a clock source with
+pub const MAX_SKEW_NS: u64 = 1_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TscSyncError {
+    NoSuchCpu,
+    /// A CPU can't measure its offset against itself
+    CurrentCpu,
+    /// The local APIC isn't initialized, so there is no way to send IPIs
+    NoApic,
+    /// The TSC isn't calibrated, so offsets can't be converted to time
+    NoTsc,
+    /// No vector could be allocated for the sync IPI
+    Irq(IrqError),
+    /// The other CPU didn't answer in time
+    Timeout,
+}
+
+impl fmt::Display for TscSyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSuchCpu => f.write_str("no such CPU"),
+            Self::CurrentCpu => f.write_str("a CPU can't be synchronized with itself"),
+            Self::NoApic => f.write_str("local APIC is not initialized"),
+            Self::NoTsc => f.write_str("TSC is not calibrated"),
+            Self::Irq(err) => write!(f, "sync IPI: {}", err),
+            Self::Timeout => f.write_str("CPU did not answer in time"),
+        }
+    }
+}
+
+impl core::error::Error for TscSyncError {}
+
+impl From<IrqError> for TscSyncError {
+    fn from(err: IrqError) -> Self {
+        Self::Irq(err)
+    }
+}
+
+/// One round trip, as TSC values
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// The TSC of the measuring CPU before the request
+    before: u64,
+    /// The TSC of the other CPU when it answered
+    remote: u64,
+    /// The TSC of the measuring CPU after the reply
+    after: u64,
+}
+
+/// The measured offset of a CPU's TSC from the BSP's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Skew {
+    /// How far the other TSC is ahead, in cycles
+    pub offset: i64,
+    /// Half of the shortest round trip, which the offset can be wrong by in either direction
+    pub uncertainty: u64,
+}
+
+impl Skew {
+    /// Returns the offset that is certainly there, zero if it is within the uncertainty
+    pub fn min_offset(&self) -> u64 {
+        self.offset.unsigned_abs().saturating_sub(self.uncertainty)
+    }
+}
+
+impl fmt::Display for Skew {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:+} cycles (±{})", self.offset, self.uncertainty)
+    }
+}
+
+/// Estimates the offset from the sample with the shortest round trip
+///
+/// The other CPU answered somewhere between `before` and `after`, so assuming it did halfway
+/// is off by at most half the round trip.
+fn estimate(samples: &[Sample]) -> Option<Skew> {
+    let best = samples
+        .iter()
+        .filter(|sample| sample.after >= sample.before)
+        .min_by_key(|sample| sample.after - sample.before)?;
+    let midpoint = (best.before as i128 + best.after as i128) / 2;
+    Some(Skew {
+        offset: (best.remote as i128 - midpoint) as i64,
+        uncertainty: (best.after - best.before).div_ceil(2),
+    })
+}
+
+/// The state shared between the two CPUs during a measurement
+///
+/// `seq` is odd while the other CPU has the turn. It first sets it to one once it is ready,
+/// then every request is the next even number and the reply the odd number after it.
+struct Handshake {
+    seq: AtomicU64,
+    remote: AtomicU64,
+    /// Added to `IA32_TSC_ADJUST` by the other CPU before it answers
+    adjust: AtomicI64,
+}
+
+static HANDSHAKE: Handshake = Handshake {
+    seq: AtomicU64::new(0),
+    remote: AtomicU64::new(0),
+    adjust: AtomicI64::new(0),
+};
+static SYNC_VECTOR: Once<Result<u8, IrqError>> = Once::new();
+/// The last measured skew of every CPU, as offset and uncertainty in cycles
+static SKEWS: PerCpu<(AtomicI64, AtomicU64)> =
+    PerCpu::new([const { (AtomicI64::new(0), AtomicU64::new(0)) }; MAX_CPUS]);
+static STATS_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Reads the TSC without letting it move across the surrounding loads and stores
+#[inline]
+fn read_ordered() -> u64 {
+    unsafe {
+        _mm_lfence();
+        let tsc = _rdtsc();
+        _mm_lfence();
+        tsc
+    }
+}
+
+/// Spins until `seq` reaches `value`, giving up after [`TIMEOUT_NS`]
+fn wait_for(value: u64) -> bool {
+    let deadline = tsc::monotonic_ns() + TIMEOUT_NS;
+    while HANDSHAKE.seq.load(Ordering::Acquire) != value {
+        if tsc::monotonic_ns() > deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+fn sync_vector() -> Result<u8, TscSyncError> {
+    Ok((*SYNC_VECTOR.call_once(|| {
+        let vector = irq::request_any_irq("tsc sync", sync_ipi, 0)?;
+        _ = irq::set_source(vector, irq::IrqSource::Ipi);
+        Ok(vector)
+    }))?)
+}
+
+/// Answers the requests of the measuring CPU, on the CPU being measured
+fn sync_ipi(_vector: u8, _data: usize) {
+    let adjust = HANDSHAKE.adjust.load(Ordering::Relaxed);
+    if adjust != 0 {
+        let mut msr = Msr::IA32_TSC_ADJUST;
+        unsafe { msr.write(msr.read().wrapping_add_signed(adjust)) };
+    }
+
+    HANDSHAKE.seq.store(1, Ordering::Release);
+    for round in 0..ROUNDS {
+        if !wait_for(round * 2 + 2) {
+            return;
+        }
+        HANDSHAKE.remote.store(read_ordered(), Ordering::Relaxed);
+        HANDSHAKE.seq.store(round * 2 + 3, Ordering::Release);
+    }
+}
+
+/// Measures the offset of a CPU, after it added `adjust` to its TSC
+fn measure(apic_id: u32, adjust: i64) -> Result<Skew, TscSyncError> {
+    let lapic = apic::local_apic().ok_or(TscSyncError::NoApic)?;
+    let vector = sync_vector()?;
+
+    interrupts::without_interrupts(|| {
+        HANDSHAKE.seq.store(0, Ordering::Relaxed);
+        HANDSHAKE.adjust.store(adjust, Ordering::Relaxed);
+        lapic.send_ipi(apic_id, vector);
+        if !wait_for(1) {
+            return Err(TscSyncError::Timeout);
+        }
+
+        let mut samples = [const {
+            Sample {
+                before: 0,
+                remote: 0,
+                after: 0,
+            }
+        }; ROUNDS as usize];
+        for (round, sample) in samples.iter_mut().enumerate() {
+            let request = round as u64 * 2 + 2;
+            sample.before = read_ordered();
+            HANDSHAKE.seq.store(request, Ordering::Release);
+            if !wait_for(request + 1) {
+                return Err(TscSyncError::Timeout);
+            }
+            sample.after = read_ordered();
+            sample.remote = HANDSHAKE.remote.load(Ordering::Relaxed);
+        }
+        estimate(&samples).ok_or(TscSyncError::Timeout)
+    })
+}
+
+/// Measures the TSC offset of a CPU against the current one, compensating for it if possible
+///
+/// Falls back to another clock source if the offset can't be brought below [`MAX_SKEW_NS`].
+/// Called for every CPU once it is up, and it must be handling interrupts.
+pub fn sync_cpu(cpu_id: usize) -> Result<Skew, TscSyncError> {
+    let cpu = percpu::cpu(cpu_id).ok_or(TscSyncError::NoSuchCpu)?;
+    if cpu_id == percpu::cpu_id() {
+        return Err(TscSyncError::CurrentCpu);
+    }
+    if tsc::frequency_khz() == 0 {
+        return Err(TscSyncError::NoTsc);
+    }
+    register_stats();
+
+    let mut skew = measure(cpu.apic_id, 0)?;
+    if skew.min_offset() > 0 {
+        kprintln!(Warn, "tsc: cpu{} is off by {}", cpu_id, skew);
+        if cpu::has(Feature::TscAdjust) {
+            skew = measure(cpu.apic_id, -skew.offset)?;
+            kprintln!(Info, "tsc: adjusted cpu{}, now off by {}", cpu_id, skew);
+        }
+    }
+
+    let (offset, uncertainty) = SKEWS.get_for(cpu_id);
+    offset.store(skew.offset, Ordering::Relaxed);
+    uncertainty.store(skew.uncertainty, Ordering::Relaxed);
+
+    let source = tsc::clocksource();
+    if tsc::cycles_to_ns(skew.min_offset()) > MAX_SKEW_NS
+        && time::clocksources().iter().any(|other| core::ptr::eq(*other, source))
+    {
+        time::unregister_clocksource(source);
+        let next = time::current_clocksource().map_or("none", |next| next.name);
+        kprintln!(
+            Warn,
+            "tsc: not synchronized across CPUs, switching clock source to {}",
+            next
+        );
+    }
+    Ok(skew)
+}
+
+/// Synchronizes every online CPU other than the current one
+pub fn sync_all() {
+    let current = percpu::cpu_id();
+    for cpu in percpu::cpus().filter(|cpu| cpu.is_online() && cpu.cpu_id as usize != current) {
+        if let Err(err) = sync_cpu(cpu.cpu_id as usize) {
+            kprintln!(Warn, "tsc: cpu{}: {}", cpu.cpu_id, err);
+        }
+    }
+}
+
+fn register_stats() {
+    if !STATS_REGISTERED.swap(true, Ordering::Relaxed) {
+        crate::stats::register("tsc_sync", dump_stats);
+    }
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    for cpu in percpu::cpus().skip(1) {
+        let (offset, uncertainty) = SKEWS.get_for(cpu.cpu_id as usize);
+        let skew = Skew {
+            offset: offset.load(Ordering::Relaxed),
+            uncertainty: uncertainty.load(Ordering::Relaxed),
+        };
+        writeln!(out, "cpu{}: {}", cpu.cpu_id, skew)?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tsc_sync_estimate() {
+        let samples = [
+            Sample {
+                before: 1000,
+                remote: 1900,
+                after: 1400,
+            },
+            // The shortest round trip wins, the other CPU is 500 cycles ahead
+            Sample {
+                before: 2000,
+                remote: 2550,
+                after: 2100,
+            },
+            Sample {
+                before: 3000,
+                remote: 3100,
+                after: 3300,
+            },
+        ];
+        let skew = estimate(&samples).unwrap();
+        assert_eq!(
+            skew,
+            Skew {
+                offset: 500,
+                uncertainty: 50
+            }
+        );
+        assert_eq!(skew.min_offset(), 450);
+
+        let close = Skew {
+            offset: -30,
+            uncertainty: 50,
+        };
+        assert_eq!(close.min_offset(), 0);
+        assert!(estimate(&[]).is_none());
+    }
+}
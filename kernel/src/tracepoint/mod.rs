@@ -0,0 +1,383 @@
+//! Tracepoints
+//!
+//! [`trace!`](crate::trace) records an [`Event`] with up to [`MAX_ARGS`] arguments into a ring of
+//! the CPU it runs on, [`EVENTS`] per CPU, overwriting the oldest events once it is full. Only the
+//! owning CPU writes to a ring, reserving a slot with an atomic increment, so recording takes no
+//! lock and doesn't allocate, and works from interrupt handlers and the allocator, which nest
+//! inside of each other. Every slot has a sequence number that is cleared while it is written, so
+//! reading the rings while they are written skips the events being written instead of tearing them.
+//!
+//! The `trace` shell command turns tracing on for all or some events, and dumps the events of all
+//! CPUs merged into one trace ordered by time, with tracing paused so the dump doesn't trace itself.
+//! Unlike logging, a tracepoint costs a load and a branch while tracing is off, and recording an
+//! event little more than reading the TSC, so it hardly perturbs the timing it is meant to show.
+//! `trace` on the command line turns on all events during boot, once the timers are up.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicPtr, AtomicU64, Ordering, fence},
+};
+
+use alloc::{boxed::Box, vec::Vec};
+
+use crate::{
+    boot::Cmdline,
+    percpu::{self, MAX_CPUS, PerCpu},
+};
+
+/// The events kept per CPU
+pub const EVENTS: usize = 4096;
+/// The most arguments an event records
+pub const MAX_ARGS: usize = 3;
+
+/// The events with a tracepoint, and the names of their arguments
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Event {
+    SchedWakeup,
+    SchedEnqueue,
+    SchedSwitch,
+    SchedMigrate,
+    Alloc,
+    Free,
+    IrqEntry,
+    IrqExit,
+    PageFault,
+}
+
+impl Event {
+    pub const ALL: [Self; 9] = [
+        Self::SchedWakeup,
+        Self::SchedEnqueue,
+        Self::SchedSwitch,
+        Self::SchedMigrate,
+        Self::Alloc,
+        Self::Free,
+        Self::IrqEntry,
+        Self::IrqExit,
+        Self::PageFault,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::SchedWakeup => "sched_wakeup",
+            Self::SchedEnqueue => "sched_enqueue",
+            Self::SchedSwitch => "sched_switch",
+            Self::SchedMigrate => "sched_migrate",
+            Self::Alloc => "alloc",
+            Self::Free => "free",
+            Self::IrqEntry => "irq_entry",
+            Self::IrqExit => "irq_exit",
+            Self::PageFault => "page_fault",
+        }
+    }
+
+    /// The names of the arguments the event is recorded with
+    pub const fn args(self) -> &'static [&'static str] {
+        match self {
+            Self::SchedWakeup => &["task", "waker"],
+            Self::SchedEnqueue => &["task", "cpu"],
+            Self::SchedSwitch => &["prev", "next"],
+            Self::SchedMigrate => &["task", "cpu"],
+            Self::Alloc => &["ptr", "size", "align"],
+            Self::Free => &["ptr", "size"],
+            Self::IrqEntry => &["vector", "ip"],
+            Self::IrqExit => &["vector"],
+            Self::PageFault => &["addr", "error", "ip"],
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.name() == name)
+    }
+
+    fn from_id(id: u64) -> Option<Self> {
+        Self::ALL.get(id as usize).copied()
+    }
+
+    const fn bit(self) -> u64 {
+        1 << self as u8
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// An event read back from a ring
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub time_ns: u64,
+    pub cpu: usize,
+    pub event: Event,
+    pub args: [u64; MAX_ARGS],
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let micros = self.time_ns / 1000;
+        write!(
+            f,
+            "{:>6}.{:06} cpu{:<3} {:<14}",
+            micros / 1_000_000,
+            micros % 1_000_000,
+            self.cpu,
+            self.event
+        )?;
+        for (name, value) in self.event.args().iter().zip(self.args) {
+            write!(f, " {}={:#x}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A slot of a ring, written as a whole like a seqlock
+struct Slot {
+    /// One more than the index of the event in the slot, or 0 while it is written
+    seq: AtomicU64,
+    time_ns: AtomicU64,
+    event: AtomicU64,
+    args: [AtomicU64; MAX_ARGS],
+}
+
+/// The events recorded on a CPU
+struct Ring {
+    /// [`EVENTS`] slots, allocated when tracing first starts
+    slots: AtomicPtr<Slot>,
+    /// How many events were ever recorded, the index of the next one
+    head: AtomicU64,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            slots: AtomicPtr::new(core::ptr::null_mut()),
+            head: AtomicU64::new(0),
+        }
+    }
+
+    fn allocate(&self) {
+        if !self.slots.load(Ordering::Acquire).is_null() {
+            return;
+        }
+        let slots: Box<[Slot]> = (0..EVENTS)
+            .map(|_| Slot {
+                seq: AtomicU64::new(0),
+                time_ns: AtomicU64::new(0),
+                event: AtomicU64::new(0),
+                args: [const { AtomicU64::new(0) }; MAX_ARGS],
+            })
+            .collect();
+        // Never freed, as an interrupt may be writing to it at any time
+        self.slots.store(Box::leak(slots).as_mut_ptr(), Ordering::Release);
+    }
+
+    fn slot(&self, seq: u64) -> Option<&Slot> {
+        let slots = self.slots.load(Ordering::Acquire);
+        // SAFETY: The slots are never freed, and the index is less than `EVENTS`
+        unsafe { slots.as_ref().map(|_| &*slots.add(seq as usize % EVENTS)) }
+    }
+
+    /// Records an event, only ever called on the CPU owning the ring
+    fn push(&self, time_ns: u64, event: Event, args: &[u64]) {
+        if self.slots.load(Ordering::Acquire).is_null() {
+            return;
+        }
+        // Interrupts that record events while this one is written take the next slots
+        let seq = self.head.fetch_add(1, Ordering::Relaxed);
+        let Some(slot) = self.slot(seq) else {
+            return;
+        };
+        slot.seq.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        slot.time_ns.store(time_ns, Ordering::Relaxed);
+        slot.event.store(event as u64, Ordering::Relaxed);
+        for (idx, arg) in slot.args.iter().enumerate() {
+            arg.store(args.get(idx).copied().unwrap_or(0), Ordering::Relaxed);
+        }
+        slot.seq.store(seq + 1, Ordering::Release);
+    }
+
+    /// Returns the events in the ring, oldest first, skipping the ones being written
+    fn records(&self, cpu: usize) -> impl Iterator<Item = Record> + '_ {
+        let head = self.head.load(Ordering::Acquire);
+        (head.saturating_sub(EVENTS as u64)..head).filter_map(move |seq| {
+            let slot = self.slot(seq)?;
+            if slot.seq.load(Ordering::Acquire) != seq + 1 {
+                return None;
+            }
+            let time_ns = slot.time_ns.load(Ordering::Relaxed);
+            let event = slot.event.load(Ordering::Relaxed);
+            let args = core::array::from_fn(|idx| slot.args[idx].load(Ordering::Relaxed));
+            fence(Ordering::Acquire);
+            if slot.seq.load(Ordering::Relaxed) != seq + 1 {
+                return None;
+            }
+            Some(Record {
+                time_ns,
+                cpu,
+                event: Event::from_id(event)?,
+                args,
+            })
+        })
+    }
+
+    /// How many events were overwritten before they were read
+    fn overwritten(&self) -> u64 {
+        self.head.load(Ordering::Relaxed).saturating_sub(EVENTS as u64)
+    }
+}
+
+static RINGS: PerCpu<Ring> = PerCpu::new([const { Ring::new() }; MAX_CPUS]);
+/// The events that are traced, none while tracing is off
+static ENABLED: AtomicU64 = AtomicU64::new(0);
+
+/// Records an event if it is traced, see [`tracepoint`](crate::tracepoint)
+///
+/// The arguments are cast to `u64`, and are only evaluated if the event is traced.
+#[macro_export]
+macro_rules! trace {
+    ($event:ident $(, $arg:expr)* $(,)?) => {{
+        let event = $crate::tracepoint::Event::$event;
+        if $crate::tracepoint::is_enabled(event) {
+            $crate::tracepoint::record(event, &[$(($arg) as u64),*]);
+        }
+    }};
+}
+
+/// Turns on all events if `trace` is on the command line
+pub fn init(cmdline: Cmdline) {
+    if cmdline.flag("trace") {
+        start(&Event::ALL);
+    }
+}
+
+/// Starts tracing the given events, and stops tracing the others
+pub fn start(events: &[Event]) {
+    for cpu in percpu::cpus() {
+        RINGS.get_for(cpu.cpu_id as usize).allocate();
+    }
+    let mask = events.iter().fold(0, |mask, event| mask | event.bit());
+    ENABLED.store(mask, Ordering::Release);
+}
+
+pub fn stop() {
+    ENABLED.store(0, Ordering::Release);
+}
+
+/// Returns the events that are traced
+pub fn enabled() -> impl Iterator<Item = Event> {
+    let mask = ENABLED.load(Ordering::Relaxed);
+    Event::ALL.into_iter().filter(move |event| mask & event.bit() != 0)
+}
+
+pub fn is_enabled(event: Event) -> bool {
+    ENABLED.load(Ordering::Relaxed) & event.bit() != 0
+}
+
+/// Records an event on the current CPU, use [`trace!`](crate::trace) instead
+#[doc(hidden)]
+pub fn record(event: Event, args: &[u64]) {
+    // CPUs brought up after tracing started have no slots, and drop their events
+    RINGS.get().push(now(), event, args);
+}
+
+/// The TSC doesn't take a lock, unlike the clock sources, which could be held when tracing
+#[cfg(target_arch = "x86_64")]
+fn now() -> u64 {
+    crate::time::tsc::monotonic_ns()
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn now() -> u64 {
+    crate::time::monotonic_ns()
+}
+
+/// Throws the recorded events away
+pub fn clear() {
+    let enabled = ENABLED.swap(0, Ordering::AcqRel);
+    for ring in RINGS.iter() {
+        ring.head.store(0, Ordering::Relaxed);
+    }
+    ENABLED.store(enabled, Ordering::Release);
+}
+
+/// Merges the events of several CPUs into one trace ordered by time
+fn merge(rings: impl Iterator<Item = impl Iterator<Item = Record>>) -> Vec<Record> {
+    let mut records: Vec<Record> = rings.flatten().collect();
+    // Events that nest in others can be recorded out of order, so the rings are sorted too
+    records.sort_by_key(|record| (record.time_ns, record.cpu));
+    records
+}
+
+/// Writes the events recorded on all CPUs, ordered by time
+pub fn dump(out: &mut dyn fmt::Write) -> fmt::Result {
+    // Paused, so the allocations of the dump aren't traced
+    let enabled = ENABLED.swap(0, Ordering::AcqRel);
+    let records = merge((0..MAX_CPUS).map(|cpu| RINGS.get_for(cpu).records(cpu)));
+    let overwritten: u64 = RINGS.iter().map(Ring::overwritten).sum();
+    let result = (|| {
+        writeln!(
+            out,
+            "# trace: {} events, {} overwritten{}",
+            records.len(),
+            overwritten,
+            if enabled != 0 { ", running" } else { "" }
+        )?;
+        for record in &records {
+            writeln!(out, "{}", record)?;
+        }
+        Ok(())
+    })();
+    drop(records);
+    ENABLED.store(enabled, Ordering::Release);
+    result
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn tracepoint_rings() {
+        let ring = Ring::new();
+        // Nothing is recorded until the slots are allocated
+        ring.push(1, Event::IrqExit, &[0x20]);
+        assert_eq!(ring.records(0).count(), 0);
+
+        ring.allocate();
+        for time_ns in 0..EVENTS as u64 + 2 {
+            ring.push(time_ns * 10, Event::Alloc, &[0x1000, time_ns, 8]);
+        }
+        assert_eq!(ring.overwritten(), 2);
+        let records: Vec<Record> = ring.records(1).collect();
+        assert_eq!(records.len(), EVENTS);
+        assert_eq!(
+            records[0],
+            Record {
+                time_ns: 20,
+                cpu: 1,
+                event: Event::Alloc,
+                args: [0x1000, 2, 8],
+            }
+        );
+
+        let other = Ring::new();
+        other.allocate();
+        other.push(15, Event::IrqEntry, &[0x20, 0xFFFF_8000_0000_1234]);
+        other.push(5, Event::IrqExit, &[0x20]);
+        let merged = merge([ring.records(1), other.records(0)].into_iter());
+        assert_eq!(merged.len(), EVENTS + 2);
+        let times: Vec<u64> = merged.iter().take(4).map(|record| record.time_ns).collect();
+        assert_eq!(times, [5, 15, 20, 30]);
+        assert_eq!(
+            merged[1].to_string(),
+            "     0.000000 cpu0   irq_entry      vector=0x20 ip=0xffff800000001234"
+        );
+        assert_eq!(Event::from_name("page_fault"), Some(Event::PageFault));
+    }
+}
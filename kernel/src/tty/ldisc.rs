@@ -0,0 +1,156 @@
+//! The line discipline
+//!
+//! Turns the bytes received from a terminal into what readers see. In canonical mode input is
+//! edited a line at a time and only becomes readable once the line is ended, otherwise every byte
+//! is readable as soon as it arrives.
+
+use core::fmt;
+
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+
+/// The longest line canonical mode accepts, further bytes are dropped
+pub const MAX_LINE: usize = 256;
+
+/// Backspace, and the delete key that most terminals send instead
+const ERASE: [u8; 2] = [0x08, 0x7F];
+/// Ctrl-U, erasing the whole line
+const KILL: u8 = 0x15;
+/// Ctrl-W, erasing the last word
+const WERASE: u8 = 0x17;
+
+/// How a terminal processes its input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Termios {
+    /// Edit input a line at a time
+    pub canonical: bool,
+    /// Write received bytes back to the terminal
+    pub echo: bool,
+}
+
+impl Termios {
+    pub const fn cooked() -> Self {
+        Self {
+            canonical: true,
+            echo: true,
+        }
+    }
+
+    pub const fn raw() -> Self {
+        Self {
+            canonical: false,
+            echo: false,
+        }
+    }
+}
+
+impl Default for Termios {
+    fn default() -> Self {
+        Self::cooked()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LineDiscipline {
+    termios: Termios,
+    /// The line being edited
+    line: String,
+    /// The bytes readers can take, ended lines in canonical mode
+    ready: VecDeque<u8>,
+}
+
+impl LineDiscipline {
+    pub fn new(termios: Termios) -> Self {
+        Self {
+            termios,
+            ..Default::default()
+        }
+    }
+
+    pub fn termios(&self) -> Termios {
+        self.termios
+    }
+
+    /// Changes the mode, leaving canonical mode makes the line being edited readable
+    pub fn set_termios(&mut self, termios: Termios) {
+        if self.termios.canonical && !termios.canonical {
+            self.ready.extend(core::mem::take(&mut self.line).bytes());
+        }
+        self.termios = termios;
+    }
+
+    /// Handles a received byte, writing the echo to `echo`
+    ///
+    /// Returns whether the byte made more input readable.
+    pub fn receive(&mut self, byte: u8, echo: &mut dyn fmt::Write) -> bool {
+        if !self.termios.canonical {
+            self.ready.push_back(byte);
+            if self.termios.echo {
+                _ = echo.write_char(byte as char);
+            }
+            return true;
+        }
+        match byte {
+            b'\r' | b'\n' => {
+                self.ready.extend(core::mem::take(&mut self.line).bytes());
+                self.ready.push_back(b'\n');
+                self.echo(echo, "\n");
+                return true;
+            }
+            byte if ERASE.contains(&byte) => self.erase(1, echo),
+            KILL => self.erase(self.line.len(), echo),
+            WERASE => {
+                let word = self.line.trim_end().rfind(' ').map_or(0, |space| space + 1);
+                self.erase(self.line.len() - word, echo);
+            }
+            byte if (byte.is_ascii_graphic() || byte == b' ') && self.line.len() < MAX_LINE => {
+                self.line.push(byte as char);
+                if self.termios.echo {
+                    _ = echo.write_char(byte as char);
+                }
+            }
+            _ => {}
+        }
+        false
+    }
+
+    fn echo(&self, echo: &mut dyn fmt::Write, s: &str) {
+        if self.termios.echo {
+            _ = echo.write_str(s);
+        }
+    }
+
+    /// Removes up to `count` characters from the end of the line, and from the terminal
+    fn erase(&mut self, count: usize, echo: &mut dyn fmt::Write) {
+        for _ in 0..count {
+            if self.line.pop().is_none() {
+                break;
+            }
+            self.echo(echo, "\x08 \x08");
+        }
+    }
+
+    /// Takes the next ended line, without the line feed
+    pub fn read_line(&mut self) -> Option<String> {
+        let end = self.ready.iter().position(|byte| *byte == b'\n')?;
+        let line: Vec<u8> = self.ready.drain(..=end).take(end).collect();
+        // Only ASCII is put in a line
+        Some(String::from_utf8(line).unwrap_or_default())
+    }
+
+    /// Takes up to `buf.len()` readable bytes, returning how many were read
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.ready.len());
+        for (dst, src) in buf.iter_mut().zip(self.ready.drain(..count)) {
+            *dst = src;
+        }
+        count
+    }
+
+    /// Returns whether a reader would get anything
+    pub fn has_input(&self) -> bool {
+        match self.termios.canonical {
+            true => self.ready.contains(&b'\n'),
+            false => !self.ready.is_empty(),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Terminals
+//!
+//! A [`Tty`] sits between the drivers that receive input and the code reading it. Drivers hand
+//! every received byte to [`Tty::receive`], and the line discipline decides what readers get, see
+//! [`ldisc`]. Readers either poll with [`Tty::try_read_line`], or await [`Tty::read_line`].
+//!
+//! The console terminal gets the input of every console device, and echoes to all of them.
+
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use alloc::string::String;
+
+use crate::sync::{Mutex, Once};
+
+pub mod ldisc;
+
+pub use ldisc::{LineDiscipline, Termios};
+
+/// Writes the echo of a terminal
+struct Echo(fn(&str));
+
+impl fmt::Write for Echo {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        (self.0)(s);
+        Ok(())
+    }
+}
+
+pub struct Tty {
+    name: &'static str,
+    ldisc: Mutex<LineDiscipline>,
+    /// The reader waiting in [`Tty::read_line`]
+    waker: Mutex<Option<Waker>>,
+    output: fn(&str),
+}
+
+impl Tty {
+    pub fn new(name: &'static str, termios: Termios, output: fn(&str)) -> Self {
+        Self {
+            name,
+            ldisc: Mutex::new(LineDiscipline::new(termios)),
+            waker: Mutex::new(None),
+            output,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn termios(&self) -> Termios {
+        self.ldisc.lock().termios()
+    }
+
+    pub fn set_termios(&self, termios: Termios) {
+        self.ldisc.lock().set_termios(termios);
+        self.wake();
+    }
+
+    /// Handles a byte received by a driver
+    pub fn receive(&self, byte: u8) {
+        if self.ldisc.lock().receive(byte, &mut Echo(self.output)) {
+            self.wake();
+        }
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+
+    /// Takes the next line if one has been entered, without the line feed
+    pub fn try_read_line(&self) -> Option<String> {
+        self.ldisc.lock().read_line()
+    }
+
+    /// Waits for the next line, without the line feed
+    pub fn read_line(&self) -> ReadLine<'_> {
+        ReadLine(self)
+    }
+
+    /// Takes the readable bytes that fit in `buf`, returning how many were read
+    ///
+    /// In canonical mode these are the bytes of the entered lines, line feeds included.
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        self.ldisc.lock().read(buf)
+    }
+}
+
+/// The future returned by [`Tty::read_line`]
+pub struct ReadLine<'a>(&'a Tty);
+
+impl Future for ReadLine<'_> {
+    type Output = String;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<String> {
+        if let Some(line) = self.0.try_read_line() {
+            return Poll::Ready(line);
+        }
+        *self.0.waker.lock() = Some(cx.waker().clone());
+        // A line may have been entered before the waker was stored
+        match self.0.try_read_line() {
+            Some(line) => Poll::Ready(line),
+            None => Poll::Pending,
+        }
+    }
+}
+
+static CONSOLE: Once<Tty> = Once::new();
+
+fn console_output(s: &str) {
+    crate::util::kprint::kprint_internal(format_args!("{}", s));
+}
+
+/// Returns the terminal of the console devices
+pub fn console() -> &'static Tty {
+    CONSOLE.call_once(|| Tty::new("console", Termios::cooked(), console_output))
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tty_line_editing() {
+        let mut ldisc = LineDiscipline::new(Termios::cooked());
+        let mut echo = String::new();
+        for byte in b"lx\x7fs -l\x17\x17cat a\x15echo hi" {
+            assert!(!ldisc.receive(*byte, &mut echo));
+        }
+        assert!(!ldisc.has_input() && ldisc.read_line().is_none());
+        assert!(ldisc.receive(b'\r', &mut echo));
+        assert_eq!(ldisc.read_line().as_deref(), Some("echo hi"));
+        assert!(echo.starts_with("lx\x08 \x08s -l\x08 \x08\x08 \x08\x08 \x08"));
+        assert!(echo.ends_with("echo hi\n"));
+
+        // Raw mode passes everything through, the partial line first
+        ldisc.receive(b'a', &mut echo);
+        ldisc.set_termios(Termios::raw());
+        assert!(ldisc.receive(0x7F, &mut echo));
+        let mut buf = [0; 4];
+        assert_eq!(ldisc.read(&mut buf), 2);
+        assert_eq!(&buf[..2], b"a\x7f");
+    }
+
+    #[test]
+    fn tty_read_line() {
+        fn output(_: &str) {}
+        let tty = Tty::new("test", Termios::cooked(), output);
+        let mut read = core::pin::pin!(tty.read_line());
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(read.as_mut().poll(&mut cx).is_pending());
+        for byte in b"ok\n" {
+            tty.receive(*byte);
+        }
+        assert_eq!(read.poll(&mut cx), Poll::Ready(String::from("ok")));
+    }
+}
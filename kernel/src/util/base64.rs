@@ -0,0 +1,65 @@
+//! Base64 encoding (RFC 4648), for sending binary data over text consoles
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The input bytes per line of [`encode_lines`], giving the 76 character lines of MIME
+pub const LINE_INPUT: usize = 57;
+
+/// Encodes up to 3 bytes into 4 characters, padding with `=`
+fn encode_chunk(chunk: &[u8]) -> [u8; 4] {
+    let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+    let n = ((b[0] as u32) << 16) | ((b[1] as u32) << 8) | b[2] as u32;
+    let mut out = [b'='; 4];
+    for (i, c) in out.iter_mut().enumerate().take(chunk.len() + 1) {
+        *c = ALPHABET[((n >> (18 - 6 * i)) & 0x3F) as usize];
+    }
+    out
+}
+
+/// Encodes `data`, passing each line of at most 76 characters to `emit`, without a line ending
+pub fn encode_lines(data: &[u8], mut emit: impl FnMut(&str)) {
+    let mut line = [0u8; LINE_INPUT / 3 * 4];
+    for input in data.chunks(LINE_INPUT) {
+        let mut len = 0;
+        for chunk in input.chunks(3) {
+            line[len..len + 4].copy_from_slice(&encode_chunk(chunk));
+            len += 4;
+        }
+        // The alphabet is ASCII
+        emit(core::str::from_utf8(&line[..len]).unwrap());
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::{string::String, vec};
+
+    use super::*;
+
+    fn encode(data: &[u8]) -> String {
+        let mut out = String::new();
+        encode_lines(data, |line| {
+            out.push_str(line);
+            out.push('\n');
+        });
+        out
+    }
+
+    #[test]
+    fn base64_encoding() {
+        // The test vectors of RFC 4648
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(b"f"), "Zg==\n");
+        assert_eq!(encode(b"fo"), "Zm8=\n");
+        assert_eq!(encode(b"foo"), "Zm9v\n");
+        assert_eq!(encode(b"foob"), "Zm9vYg==\n");
+        assert_eq!(encode(b"fooba"), "Zm9vYmE=\n");
+        assert_eq!(encode(b"foobar"), "Zm9vYmFy\n");
+
+        let lines = encode(&vec![0xFF; LINE_INPUT + 1]);
+        let mut lines = lines.lines();
+        assert_eq!(lines.next().map(str::len), Some(76));
+        assert_eq!(lines.next(), Some("/w=="));
+        assert_eq!(lines.next(), None);
+    }
+}
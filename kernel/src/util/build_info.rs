@@ -0,0 +1,54 @@
+//! What the kernel was built from
+//!
+//! Nothing here depends on when or where the kernel was built: the build date is
+//! `SOURCE_DATE_EPOCH` or the date of the commit, see `kernel/build.rs`, so two builds of a commit
+//! can be compared byte for byte with `make verify-repro`.
+
+use core::fmt;
+
+use crate::time::date::DateTime;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The abbreviated hash of the commit, or `unknown` outside of a git checkout
+pub const COMMIT: &str = env!("HADRON_BUILD_COMMIT");
+/// The build date in seconds since the UNIX epoch
+pub const EPOCH: u64 = parse_epoch(env!("HADRON_BUILD_EPOCH"));
+
+const fn parse_epoch(digits: &str) -> u64 {
+    let digits = digits.as_bytes();
+    let mut epoch = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        assert!(digits[i].is_ascii_digit(), "HADRON_BUILD_EPOCH is not a number");
+        epoch = epoch * 10 + (digits[i] - b'0') as u64;
+        i += 1;
+    }
+    epoch
+}
+
+/// Returns the build date
+pub fn date() -> DateTime {
+    DateTime::from_unix(EPOCH as i64)
+}
+
+/// The version, commit and build date, as shown at boot and by `hostctl`
+#[derive(Debug, Clone, Copy)]
+pub struct Banner;
+
+impl fmt::Display for Banner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "hadron {} ({}, built {})", VERSION, COMMIT, date())
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_info_epoch() {
+        assert_eq!(parse_epoch("0"), 0);
+        assert_eq!(parse_epoch("1700000000"), 1_700_000_000);
+        assert_eq!(parse_epoch(""), 0);
+    }
+}
@@ -0,0 +1,127 @@
+//! Intel HEX encoding, for sending binary data with per record checksums over text consoles
+//!
+//! Every line is a record `:LLAAAATT<data>CC`, with the data length, the low 16 bits of the
+//! address, the record type, the data and a checksum making the sum of all bytes zero. The upper
+//! 16 bits of the address are set by extended linear address records, so up to 4 GiB can be
+//! encoded. Host tools like `objcopy -I ihex` and `srec_cat` understand the format.
+
+/// The data bytes per record, the most common choice
+pub const RECORD_DATA: usize = 16;
+
+const RECORD_DATA_TYPE: u8 = 0x00;
+const RECORD_END: u8 = 0x01;
+const RECORD_EXTENDED_LINEAR: u8 = 0x04;
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+/// Formats a single record and passes it to `emit`, without a line ending
+fn record(kind: u8, addr: u16, data: &[u8], emit: &mut impl FnMut(&str)) {
+    debug_assert!(data.len() <= RECORD_DATA);
+    let mut line = [0u8; 1 + (4 + RECORD_DATA + 1) * 2];
+    let mut len = 1;
+    line[0] = b':';
+    let mut sum = 0u8;
+    let [addr_hi, addr_lo] = addr.to_be_bytes();
+    for byte in [data.len() as u8, addr_hi, addr_lo, kind].iter().chain(data) {
+        sum = sum.wrapping_add(*byte);
+        line[len] = HEX[(byte >> 4) as usize];
+        line[len + 1] = HEX[(byte & 0xF) as usize];
+        len += 2;
+    }
+    let checksum = sum.wrapping_neg();
+    line[len] = HEX[(checksum >> 4) as usize];
+    line[len + 1] = HEX[(checksum & 0xF) as usize];
+    len += 2;
+    // The digits are ASCII
+    emit(core::str::from_utf8(&line[..len]).unwrap());
+}
+
+/// Encodes data as records, which can be written in any order and with gaps
+#[derive(Debug)]
+pub struct Encoder {
+    /// The upper 16 bits of the address set by the last extended linear address record
+    upper: Option<u16>,
+}
+
+impl Encoder {
+    pub const fn new() -> Self {
+        Self { upper: None }
+    }
+
+    /// Encodes `data` at `addr`, passing each record to `emit`
+    ///
+    /// Records never cross a 64 KiB boundary, so data at and after a boundary is preceded by an
+    /// extended linear address record. Data past 4 GiB is not encoded.
+    pub fn data(&mut self, addr: u32, data: &[u8], mut emit: impl FnMut(&str)) {
+        let mut addr = addr as u64;
+        let mut data = &data[..data.len().min((1 << 32) - addr as usize)];
+        while !data.is_empty() {
+            let upper = (addr >> 16) as u16;
+            if self.upper != Some(upper) {
+                record(RECORD_EXTENDED_LINEAR, 0, &upper.to_be_bytes(), &mut emit);
+                self.upper = Some(upper);
+            }
+            let len = data.len().min(RECORD_DATA).min(0x10000 - (addr & 0xFFFF) as usize);
+            record(RECORD_DATA_TYPE, addr as u16, &data[..len], &mut emit);
+            addr += len as u64;
+            data = &data[len..];
+        }
+    }
+
+    /// Passes the end of file record to `emit`
+    pub fn end(self, mut emit: impl FnMut(&str)) {
+        record(RECORD_END, 0, &[], &mut emit);
+    }
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::{string::String, vec::Vec};
+
+    use super::*;
+
+    fn lines(f: impl FnOnce(&mut dyn FnMut(&str))) -> Vec<String> {
+        let mut out = Vec::new();
+        f(&mut |line: &str| out.push(String::from(line)));
+        out
+    }
+
+    #[test]
+    fn ihex_records() {
+        // The example of the Intel HEX specification
+        let data = [
+            0x21, 0x46, 0x01, 0x36, 0x01, 0x21, 0x47, 0x01, 0x36, 0x00, 0x7E, 0xFE, 0x09, 0xD2, 0x19, 0x01,
+        ];
+        let out = lines(|emit| {
+            let mut encoder = Encoder::new();
+            encoder.data(0x0100, &data, &mut *emit);
+            encoder.end(emit);
+        });
+        assert_eq!(
+            out,
+            [
+                ":020000040000FA",
+                ":10010000214601360121470136007EFE09D2190140",
+                ":00000001FF"
+            ]
+        );
+
+        // Records are split at 64 KiB boundaries, with a new upper address
+        let out = lines(|emit| Encoder::new().data(0x1_FFFE, &[0xAA; 4], emit));
+        assert_eq!(
+            out,
+            [
+                ":020000040001F9",
+                ":02FFFE00AAAAAD",
+                ":020000040002F8",
+                ":02000000AAAAAA"
+            ]
+        );
+    }
+}
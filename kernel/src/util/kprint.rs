@@ -1,14 +1,15 @@
 use core::fmt;
 
+use crate::sync::Mutex;
 use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use noalloc::ringbuf::RingBuf;
-use spin::Mutex;
 
 use crate::dev::Device;
 
 pub static LOGGER: Mutex<Logger> = Mutex::new(Logger::empty());
 
 pub struct Logger {
+    /// The start of the messages logged before the [log ring](super::logring) is set up
     pub ringbuf: RingBuf<u8, 4096>,
     pub loggers: Vec<Box<dyn LogConsole>>,
 }
@@ -24,6 +25,11 @@ impl Logger {
 
 impl fmt::Write for Logger {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !super::logring::write(s.as_bytes()) {
+            for b in s.as_bytes() {
+                _ = self.ringbuf.try_push(*b);
+            }
+        }
         for logger in self.loggers.iter_mut() {
             for b in s.as_bytes() {
                 logger.write_byte(*b);
@@ -59,22 +65,49 @@ impl fmt::Display for LogLevel {
     }
 }
 
+/// A timestamp prefix for log messages, formatted as seconds since boot
+pub struct Timestamp(core::time::Duration);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Self(crate::time::time_since_boot())
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:>5}.{:06}]", self.0.as_secs(), self.0.subsec_micros())
+    }
+}
+
 pub fn kprint_internal(args: fmt::Arguments) {
     use fmt::Write;
-    _ = LOGGER.lock().write_fmt(args);
+    let mut logger = match LOGGER.try_lock() {
+        Some(logger) => logger,
+        // Whoever holds the logger was stopped by the panic, or is the panicking code itself
+        None if super::panicking::is_panic_cpu() => {
+            // SAFETY: The holder won't touch it again
+            unsafe { LOGGER.force_unlock() };
+            LOGGER.lock()
+        }
+        None => LOGGER.lock(),
+    };
+    _ = logger.write_fmt(args);
 }
 
 #[macro_export]
 macro_rules! kprint {
     ($level:ident, $fmt:expr) => {
         $crate::util::kprint::kprint_internal(format_args!(
-            concat!("{} ", $fmt),
+            concat!("{} {} ", $fmt),
+            $crate::util::kprint::Timestamp::now(),
             $crate::util::kprint::LogLevel::$level,
         ))
     };
     ($level:ident, $fmt:expr, $($arg:tt)*) => {
         $crate::util::kprint::kprint_internal(format_args!(
-            concat!("{} ", $fmt),
+            concat!("{} {} ", $fmt),
+            $crate::util::kprint::Timestamp::now(),
             $crate::util::kprint::LogLevel::$level,
             $($arg)*,
         ))
@@ -0,0 +1,229 @@
+//! The kernel log ring, shared with userspace
+//!
+//! Every log message is appended to a ring in its own frames, which processes can map read-only
+//! with the `log_map` syscall and read without copying anything through the kernel. The first page
+//! holds a [`LogRingHeader`], and the data follows it.
+//!
+//! The byte with sequence number `n` is at `data[n % data_size]`. The writer first raises `next`
+//! to the end of what it is about to write, then writes the bytes, then raises `head` to match.
+//! Readers poll `head` (or wait for it with the `log_poll` syscall), copy the bytes from where they
+//! left off up to `head`, then read `next`: bytes below `next - data_size` may have been overwritten
+//! during the copy, and are lost. See [`read`], which userspace readers mirror.
+//!
+//! Messages logged before the frame allocator is up are kept in the small buffer of the
+//! [`Logger`](super::kprint::Logger), and moved into the ring by [`init`].
+
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicU64, Ordering, fence},
+};
+
+use crate::{
+    arch::PhysAddr,
+    mm::{
+        FRAME_ALLOCATOR,
+        page_table::KernelPageTable,
+        paging::{PageSize, Size4KiB},
+    },
+    sync::Once,
+};
+
+/// "HLOG"
+pub const MAGIC: u32 = 0x474F_4C48;
+pub const VERSION: u32 = 1;
+/// The pages of log data after the header page, from the `log_size` kconfig option
+pub const DATA_PAGES: usize = crate::config::LOG_SIZE.div_ceil(Size4KiB::SIZE);
+
+/// The first page of the ring, as userspace sees it
+#[repr(C)]
+#[derive(Debug)]
+pub struct LogRingHeader {
+    pub magic: u32,
+    pub version: u32,
+    /// Where the data starts, from the start of the header
+    pub data_offset: u32,
+    pub data_size: u32,
+    /// The sequence number of the first byte that isn't written yet
+    pub head: AtomicU64,
+    /// The sequence number of the first byte that isn't being written yet
+    pub next: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRingError {
+    OutOfMemory,
+}
+
+impl fmt::Display for LogRingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfMemory => f.write_str("out of memory for the log ring"),
+        }
+    }
+}
+
+impl core::error::Error for LogRingError {}
+
+struct LogRing {
+    phys: PhysAddr,
+    header: &'static LogRingHeader,
+    data: *mut u8,
+    size: usize,
+}
+
+unsafe impl Send for LogRing {}
+unsafe impl Sync for LogRing {}
+
+static RING: Once<LogRing> = Once::new();
+
+/// Appends bytes to a ring, only called by one writer at a time
+fn append(header: &LogRingHeader, data: *mut u8, size: usize, bytes: &[u8]) {
+    // Older bytes than a whole ring would overwrite themselves
+    let bytes = &bytes[bytes.len().saturating_sub(size)..];
+    let start = header.head.load(Ordering::Relaxed);
+    let end = start + bytes.len() as u64;
+    header.next.store(end, Ordering::Relaxed);
+    fence(Ordering::Release);
+    for (idx, byte) in bytes.iter().enumerate() {
+        let offset = ((start + idx as u64) % size as u64) as usize;
+        unsafe { data.add(offset).write_volatile(*byte) };
+    }
+    header.head.store(end, Ordering::Release);
+}
+
+/// The result of a [`read`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadResult {
+    /// The bytes copied to the start of the buffer
+    pub len: usize,
+    /// The sequence number to continue from
+    pub next: u64,
+    /// The bytes that were overwritten before they could be read
+    pub lost: u64,
+}
+
+/// Copies the bytes from sequence number `seq` on into `buf`
+fn read_ring(header: &LogRingHeader, data: *const u8, size: usize, seq: u64, buf: &mut [u8]) -> ReadResult {
+    let head = header.head.load(Ordering::Acquire);
+    let mut start = seq.min(head).max(head.saturating_sub(size as u64));
+    let end = head.min(start + buf.len() as u64);
+    for (idx, seq) in (start..end).enumerate() {
+        buf[idx] = unsafe { data.add((seq % size as u64) as usize).read_volatile() };
+    }
+    fence(Ordering::Acquire);
+    // Anything the writer may have started overwriting during the copy is dropped
+    let oldest = header.next.load(Ordering::Relaxed).saturating_sub(size as u64);
+    if oldest > start {
+        let skipped = (oldest - start).min(end - start);
+        buf.copy_within(skipped as usize..(end - start) as usize, 0);
+        start += skipped;
+    }
+    ReadResult {
+        len: (end - start) as usize,
+        next: end,
+        lost: start - seq.min(start),
+    }
+}
+
+/// Allocates the ring and moves the messages logged so far into it
+pub fn init() -> Result<(), LogRingError> {
+    let frame = FRAME_ALLOCATOR
+        .lock()
+        .allocate_contiguous(1 + DATA_PAGES)
+        .ok_or(LogRingError::OutOfMemory)?;
+    let phys = frame.start_address();
+    let virt = KernelPageTable::direct_map_start() + phys.as_usize();
+    unsafe { ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, (1 + DATA_PAGES) * Size4KiB::SIZE) };
+    let size = DATA_PAGES * Size4KiB::SIZE;
+    let header = unsafe { &mut *virt.as_mut_ptr::<LogRingHeader>() };
+    header.magic = MAGIC;
+    header.version = VERSION;
+    header.data_offset = Size4KiB::SIZE as u32;
+    header.data_size = size as u32;
+
+    let mut logger = super::kprint::LOGGER.lock();
+    let ring = RING.call_once(|| LogRing {
+        phys,
+        header,
+        data: (virt + Size4KiB::SIZE).as_mut_ptr(),
+        size,
+    });
+    while let Some(byte) = logger.ringbuf.pop() {
+        append(ring.header, ring.data, ring.size, &[byte]);
+    }
+    Ok(())
+}
+
+/// Appends to the ring, called with the logger locked
+pub(super) fn write(bytes: &[u8]) -> bool {
+    let Some(ring) = RING.get() else {
+        return false;
+    };
+    append(ring.header, ring.data, ring.size, bytes);
+    true
+}
+
+/// Returns the physical address of the ring and its size in pages, header included
+pub fn frames() -> Option<(PhysAddr, usize)> {
+    RING.get().map(|ring| (ring.phys, 1 + DATA_PAGES))
+}
+
+/// Returns the sequence number of the next byte to be logged
+pub fn head() -> u64 {
+    RING.get().map_or(0, |ring| ring.header.head.load(Ordering::Acquire))
+}
+
+/// Copies the log from sequence number `seq` on into `buf`
+pub fn read(seq: u64, buf: &mut [u8]) -> ReadResult {
+    match RING.get() {
+        Some(ring) => read_ring(ring.header, ring.data, ring.size, seq, buf),
+        None => ReadResult {
+            len: 0,
+            next: seq,
+            lost: 0,
+        },
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logring_wraps() {
+        let header = LogRingHeader {
+            magic: MAGIC,
+            version: VERSION,
+            data_offset: 0,
+            data_size: 8,
+            head: AtomicU64::new(0),
+            next: AtomicU64::new(0),
+        };
+        let mut data = [0u8; 8];
+        let data = data.as_mut_ptr();
+        let mut buf = [0u8; 16];
+
+        append(&header, data, 8, b"hello");
+        let result = read_ring(&header, data, 8, 0, &mut buf);
+        assert_eq!((&buf[..result.len], result.next, result.lost), (&b"hello"[..], 5, 0));
+
+        append(&header, data, 8, b" world");
+        let result = read_ring(&header, data, 8, 5, &mut buf);
+        assert_eq!((&buf[..result.len], result.next, result.lost), (&b" world"[..], 11, 0));
+
+        // The reader fell behind by more than the ring
+        append(&header, data, 8, b"!!!");
+        let result = read_ring(&header, data, 8, 0, &mut buf);
+        assert_eq!(
+            (&buf[..result.len], result.next, result.lost),
+            (&b"world!!!"[..], 14, 6)
+        );
+
+        // A write that started during the copy invalidates what it may overwrite
+        header.next.store(16, Ordering::Relaxed);
+        let result = read_ring(&header, data, 8, 12, &mut buf[..1]);
+        assert_eq!((result.len, result.next, result.lost), (1, 13, 0));
+        let result = read_ring(&header, data, 8, 6, &mut buf);
+        assert_eq!((&buf[..result.len], result.next, result.lost), (&b"rld!!!"[..], 14, 2));
+    }
+}
@@ -1,4 +1,10 @@
+pub mod base64;
 pub mod bits;
+pub mod build_info;
+pub mod ihex;
 pub mod kprint;
+pub mod logring;
 pub mod machine_state;
 pub mod panicking;
+pub mod ring;
+pub mod tar;
@@ -1,23 +1,394 @@
-use core::panic::PanicInfo;
+//! The panic pipeline
+//!
+//! Every panic goes through the same [`PanicStage`]s, in order: the other CPUs are stopped with an
+//! NMI, the panic is reported to the log along with what the other CPUs were doing, drawn onto the
+//! screen or beeped on the PC speaker if there is no screen, dumped over serial if the policy asks
+//! for it, and the logs are flushed. Stages registered with [`register_stage`] run after those,
+//! then [`after_panic`] does what the [`PanicPolicy`] given with `panic=` on the command line says:
+//! halting for a debugger by default, or rebooting after a few seconds for machines that run
+//! unattended.
+//!
+//! A panic inside of a stage carries on with the next stage, so a broken framebuffer can't keep
+//! the policy from being applied. During boot, before the logger is set up, the boot path reports
+//! panics itself with an [alternate handler](set_alternate_panic_handler), which hands the panic
+//! back to the pipeline with [`resume`].
+//!
+//! A stopped CPU may have been holding the logger, so once the kernel panics the panicking CPU
+//! takes the logger over if it can't get it, see [`is_panic_cpu`].
 
-use spin::Mutex;
+use core::{
+    fmt::{self, Write},
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    arch::instructions::interrupts,
+    boot::Cmdline,
+    dev::drivers::platform::fb::{self, Framebuffer},
+    display::gfx::{self, Canvas},
+    sync::{Mutex, cell::RacyCell},
+    time,
+};
 
 use crate::kprintln;
 
+/// The seconds before rebooting, when the policy doesn't say
+const DEFAULT_REBOOT_SECONDS: u32 = 10;
+/// The stack dumped by [`PanicPolicy::Dump`], from the stack pointer on
+const DUMP_STACK_SIZE: usize = 16 * 1024;
+
+/// The most stages [`register_stage`] can add
+const MAX_EXTRA_STAGES: usize = 4;
+/// The panic screen, a band across the top of the framebuffer
+const SCREEN_BACKGROUND: u32 = 0xA00000;
+const SCREEN_TEXT: u32 = 0xFFFFFF;
+const SCREEN_PADDING: u32 = 8;
+/// The beep when there is no screen to show the panic on
+const BEEP_FREQUENCY: u32 = 880;
+const BEEP_DURATION: Duration = Duration::from_secs(1);
+
 static ALT_PANIC_HANDLER: Mutex<Option<fn(&PanicInfo) -> !>> = Mutex::new(None);
+static PANICKING: AtomicBool = AtomicBool::new(false);
+/// Whether the panic screen was drawn, if not the panic is beeped
+static SCREEN_SHOWN: AtomicBool = AtomicBool::new(false);
+static POLICY: RacyCell<PanicPolicy> = RacyCell::new(PanicPolicy::Halt);
+/// The CPU that panicked first, the only one running the pipeline
+static PANIC_CPU: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// The next stage to run, counting the registered ones after the built-in ones
+static NEXT_STAGE: AtomicUsize = AtomicUsize::new(0);
+/// [`NEXT_STAGE`] once every stage has run and the policy is being applied
+const STAGES_DONE: usize = usize::MAX;
+static EXTRA_STAGES: Mutex<[Option<PanicStage>; MAX_EXTRA_STAGES]> = Mutex::new([None; MAX_EXTRA_STAGES]);
+
+/// A step of the panic pipeline
+#[derive(Debug, Clone, Copy)]
+pub struct PanicStage {
+    pub name: &'static str,
+    pub run: fn(&PanicInfo),
+}
+
+/// The built-in stages, in the order they run
+static STAGES: [PanicStage; 6] = [
+    PanicStage {
+        name: "stop cpus",
+        run: stop_cpus,
+    },
+    PanicStage {
+        name: "report",
+        run: report,
+    },
+    PanicStage {
+        name: "panic screen",
+        run: panic_screen,
+    },
+    PanicStage {
+        name: "beep",
+        run: beep,
+    },
+    PanicStage {
+        name: "crash dump",
+        run: crash_dump,
+    },
+    PanicStage {
+        name: "flush logs",
+        run: flush_logs,
+    },
+];
+
+/// What the kernel does once a panic is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Halts, leaving the machine as it is for a debugger
+    Halt,
+    /// Reboots after a number of seconds
+    Reboot { seconds: u32 },
+    /// Sends the log and the stack over serial, then reboots after a number of seconds
+    Dump { seconds: u32 },
+}
+
+impl PanicPolicy {
+    /// Parses `halt`, `reboot[:<seconds>]` or `dump[:<seconds>]`
+    pub fn parse(policy: &str) -> Option<Self> {
+        let (name, seconds) = match policy.split_once(':') {
+            Some((name, seconds)) => (name, Some(seconds.parse().ok()?)),
+            None => (policy, None),
+        };
+        let seconds_or_default = seconds.unwrap_or(DEFAULT_REBOOT_SECONDS);
+        match (name, seconds) {
+            ("halt", None) => Some(Self::Halt),
+            ("reboot", _) => Some(Self::Reboot {
+                seconds: seconds_or_default,
+            }),
+            ("dump", _) => Some(Self::Dump {
+                seconds: seconds_or_default,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PanicPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Halt => f.write_str("halt"),
+            Self::Reboot { seconds } => write!(f, "reboot after {}s", seconds),
+            Self::Dump { seconds } => write!(f, "crash dump, then reboot after {}s", seconds),
+        }
+    }
+}
+
+/// Returns whether the kernel has panicked, for output that should be seen no matter what
+pub fn is_panicking() -> bool {
+    PANICKING.load(Ordering::Relaxed)
+}
+
+/// Returns whether the current CPU is running the panic pipeline
+///
+/// Every other CPU is stopped or about to be, so locks they held can be taken over.
+pub fn is_panic_cpu() -> bool {
+    PANIC_CPU.load(Ordering::Acquire) == crate::percpu::cpu_id()
+}
+
+pub fn policy() -> PanicPolicy {
+    *POLICY.get()
+}
+
+/// Sets the panic policy from `panic=` on the command line
+pub fn init(cmdline: Cmdline) {
+    // For the beep
+    crate::arch::x86_64::pit::init();
+    let Some(policy) = cmdline.get("panic") else {
+        return;
+    };
+    let Some(policy) = PanicPolicy::parse(policy) else {
+        kprintln!(Warn, "panic: invalid policy '{}', halting on panic", policy);
+        return;
+    };
+    if policy != PanicPolicy::Halt {
+        crate::arch::x86_64::reset::init();
+    }
+    // SAFETY: Only set during boot, before anything can panic on another CPU
+    *POLICY.get_mut() = policy;
+    kprintln!(Info, "panic: policy is {}", policy);
+}
+
+/// Adds a stage to run after the built-in ones, such as a driver putting its device in a safe state
+///
+/// Returns `false` if too many stages are registered already.
+pub fn register_stage(stage: PanicStage) -> bool {
+    let mut stages = EXTRA_STAGES.lock();
+    let Some(slot) = stages.iter_mut().find(|slot| slot.is_none()) else {
+        return false;
+    };
+    *slot = Some(stage);
+    true
+}
 
 #[cfg(not(feature = "test"))]
 #[panic_handler]
 fn kernel_panic(info: &PanicInfo) -> ! {
+    unsafe { interrupts::disable() };
+    let cpu = crate::percpu::cpu_id();
+    match PANIC_CPU.compare_exchange(usize::MAX, cpu, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => {
+            PANICKING.store(true, Ordering::Relaxed);
+            #[cfg(kconfig = "lock_debug")]
+            crate::sync::lockdep::disable();
+        }
+        // A panic inside of a stage, which was skipped by taking it
+        Err(first) if first == cpu => {}
+        // Another CPU is running the pipeline, and would interleave its output with ours
+        Err(_) => stop(),
+    }
+    resume(info)
+}
+
+/// Runs the stages of the pipeline that haven't run yet, then applies the policy
+pub fn resume(info: &PanicInfo) -> ! {
+    loop {
+        // A panic while applying the policy, such as from the reboot, would apply it again forever
+        if NEXT_STAGE.load(Ordering::Acquire) == STAGES_DONE {
+            stop();
+        }
+        let idx = NEXT_STAGE.fetch_add(1, Ordering::AcqRel);
+        let stage = match STAGES.get(idx) {
+            Some(stage) => *stage,
+            None => match EXTRA_STAGES
+                .try_lock()
+                .and_then(|stages| stages.get(idx - STAGES.len()).copied())
+            {
+                Some(Some(stage)) => stage,
+                Some(None) => continue,
+                None => break,
+            },
+        };
+        (stage.run)(info);
+    }
+    NEXT_STAGE.store(STAGES_DONE, Ordering::Release);
+    after_panic()
+}
+
+/// Parks a CPU for good
+fn stop() -> ! {
+    unsafe { interrupts::disable() };
+    loop {
+        crate::arch::instructions::hlt();
+    }
+}
+
+/// Stops the other CPUs, so nothing changes under the report and their output doesn't mix with it
+///
+/// CPUs that panic after this one stop themselves in [`kernel_panic`], the rest get an NMI.
+fn stop_cpus(_info: &PanicInfo) {
+    let running = crate::arch::x86_64::panic_stop::stop_others();
+    if running != 0 {
+        kprintln!(Fatal, "panic: {} CPUs did not stop", running);
+    }
+}
+
+/// What the stopped CPUs were doing
+struct OtherCpus;
+
+impl fmt::Display for OtherCpus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        crate::arch::x86_64::panic_stop::write_report(f)
+    }
+}
+
+fn report(info: &PanicInfo) {
     if let Some(handler) = *ALT_PANIC_HANDLER.lock() {
         handler(info);
-    } else {
-        unsafe { crate::util::kprint::LOGGER.force_unlock() };
-        kprintln!(Fatal, "panic: {}", info);
     }
-    loop {}
+    kprintln!(Fatal, "panic: on cpu{}: {}", crate::percpu::cpu_id(), info);
+    if crate::percpu::cpus().nth(1).is_some() {
+        kprintln!(Fatal, "panic: other CPUs:\n{}", OtherCpus);
+    }
 }
 
+/// Writes a line of text across the panic screen, cutting it off at the right edge
+struct ScreenLine<'a> {
+    fb: &'a mut Framebuffer,
+    x: u32,
+    y: u32,
+}
+
+impl Write for ScreenLine<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let mut bytes = [0; 4];
+            let c = if c == '\n' { " " } else { c.encode_utf8(&mut bytes) };
+            if self.x + gfx::text_width(c) + SCREEN_PADDING > self.fb.info.width {
+                return Err(fmt::Error);
+            }
+            self.x += self.fb.text(self.x as i32, self.y as i32, c, SCREEN_TEXT);
+        }
+        Ok(())
+    }
+}
+
+/// Draws the panic over the top of the framebuffer, which shows it even over the splash
+fn panic_screen(info: &PanicInfo) {
+    let shown = fb::draw(|fb| {
+        let line = gfx::text_height();
+        let width = fb.info.width;
+        fb.fill_rect(0, 0, width, 3 * line + 2 * SCREEN_PADDING, SCREEN_BACKGROUND);
+        let mut out = ScreenLine {
+            fb,
+            x: SCREEN_PADDING,
+            y: SCREEN_PADDING,
+        };
+        _ = write!(out, "KERNEL PANIC on cpu{}", crate::percpu::cpu_id());
+        (out.x, out.y) = (SCREEN_PADDING, SCREEN_PADDING + line);
+        _ = write!(out, "{}", info.message());
+        if let Some(location) = info.location() {
+            (out.x, out.y) = (SCREEN_PADDING, SCREEN_PADDING + 2 * line);
+            _ = write!(out, "at {}", location);
+        }
+    });
+    SCREEN_SHOWN.store(shown, Ordering::Relaxed);
+}
+
+/// Beeps the PC speaker if there is no screen, which may be the only sign of the panic
+fn beep(_info: &PanicInfo) {
+    if !SCREEN_SHOWN.load(Ordering::Relaxed) {
+        crate::arch::x86_64::speaker::beep(BEEP_FREQUENCY, BEEP_DURATION);
+    }
+}
+
+/// Sends everything still queued for the serial ports
+fn flush_logs(_info: &PanicInfo) {
+    crate::dev::drivers::platform::serial::flush();
+}
+
+/// Does what the panic policy says, once the stages have run
+pub fn after_panic() -> ! {
+    unsafe { interrupts::disable() };
+    #[cfg(all(test, not(feature = "test")))]
+    hadron_test::exit_qemu(hadron_test::ExitCode::Failed);
+    let seconds = match *POLICY.get() {
+        PanicPolicy::Halt => stop(),
+        PanicPolicy::Reboot { seconds } | PanicPolicy::Dump { seconds } => seconds,
+    };
+    kprintln!(Fatal, "panic: rebooting in {} seconds", seconds);
+    // The clock may not run yet during boot, which reboots right away
+    let deadline = time::monotonic_ns() + seconds as u64 * 1_000_000_000;
+    while time::monotonic_ns() != 0 && time::monotonic_ns() < deadline {
+        core::hint::spin_loop();
+    }
+    crate::arch::x86_64::reset::reboot()
+}
+
+/// Sends the log ring and the stack of the panicking CPU over serial, if the policy says so
+fn crash_dump(_info: &PanicInfo) {
+    if !matches!(*POLICY.get(), PanicPolicy::Dump { .. }) {
+        return;
+    }
+    use crate::mm::{
+        memdump::{self, AddressKind},
+        paging::{PageSize, Size4KiB},
+    };
+
+    kprintln!(Fatal, "panic: sending a crash dump over serial");
+    if let Some((phys, pages)) = crate::util::logring::frames() {
+        let len = pages * Size4KiB::SIZE;
+        if let Err(err) = memdump::dump_serial(AddressKind::Physical, phys.as_usize(), len) {
+            kprintln!(Fatal, "panic: can't dump the log: {}", err);
+        }
+    }
+    let rsp: usize;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    if let Err(err) = memdump::dump_serial(AddressKind::Virtual, rsp, DUMP_STACK_SIZE) {
+        kprintln!(Fatal, "panic: can't dump the stack: {}", err);
+    }
+}
+
+/// Replaces the report of panics, for when the logger can't be used
+///
+/// The handler runs in place of the report stage. To carry on with the pipeline it calls
+/// [`resume`], tests can also end the panic themselves.
 pub fn set_alternate_panic_handler(panic: Option<fn(&PanicInfo) -> !>) {
     *ALT_PANIC_HANDLER.lock() = panic;
 }
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panicking_parse_policy() {
+        assert_eq!(PanicPolicy::parse("halt"), Some(PanicPolicy::Halt));
+        assert_eq!(
+            PanicPolicy::parse("reboot"),
+            Some(PanicPolicy::Reboot {
+                seconds: DEFAULT_REBOOT_SECONDS
+            })
+        );
+        assert_eq!(PanicPolicy::parse("reboot:0"), Some(PanicPolicy::Reboot { seconds: 0 }));
+        assert_eq!(PanicPolicy::parse("dump:30"), Some(PanicPolicy::Dump { seconds: 30 }));
+        assert_eq!(PanicPolicy::parse("halt:5"), None);
+        assert_eq!(PanicPolicy::parse("reboot:soon"), None);
+        assert_eq!(PanicPolicy::parse("poweroff"), None);
+    }
+}
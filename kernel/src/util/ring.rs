@@ -0,0 +1,102 @@
+//! Fixed size byte rings
+//!
+//! For buffering between a device and its users, where allocating in the interrupt handler isn't
+//! an option. The ring has no locking of its own, its owner decides how it is shared.
+
+/// A FIFO of at most `N` bytes
+#[derive(Debug, Clone)]
+pub struct ByteRing<const N: usize> {
+    buf: [u8; N],
+    /// The index of the oldest byte
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> ByteRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub const fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Appends a byte, returning `false` if the ring is full
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    /// Appends a byte, dropping the oldest one if the ring is full
+    pub fn push_overwrite(&mut self, byte: u8) {
+        if self.is_full() {
+            self.pop();
+        }
+        self.push(byte);
+    }
+
+    /// Removes the oldest byte
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    /// Returns the bytes from the oldest to the newest, without removing them
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.len).map(|idx| self.buf[(self.head + idx) % N])
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for ByteRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_wraps() {
+        let mut ring = ByteRing::<4>::new();
+        assert!(ring.is_empty());
+        for byte in 0..4 {
+            assert!(ring.push(byte));
+        }
+        assert!(ring.is_full() && !ring.push(4));
+        assert_eq!(ring.pop(), Some(0));
+        assert_eq!(ring.pop(), Some(1));
+        assert!(ring.push(4) && ring.push(5));
+        ring.push_overwrite(6);
+        assert!(ring.iter().eq([3, 4, 5, 6]));
+        let drained: [Option<u8>; 5] = core::array::from_fn(|_| ring.pop());
+        assert_eq!(drained, [Some(3), Some(4), Some(5), Some(6), None]);
+    }
+}
@@ -0,0 +1,130 @@
+//! Reading tar archives
+//!
+//! Just enough for the initramfs: the files of a ustar or old style archive, found by path. Long
+//! names from GNU or pax extension headers aren't supported, those entries are skipped.
+
+use alloc::string::String;
+
+const BLOCK_SIZE: usize = 512;
+
+const TYPE_FILE: u8 = b'0';
+/// Old archives mark regular files with a NUL instead
+const TYPE_FILE_OLD: u8 = 0;
+
+/// A regular file in an archive
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    /// The directory of a ustar entry whose path didn't fit the name field, empty otherwise
+    prefix: &'a str,
+    name: &'a str,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    /// Returns whether the entry has `path`, ignoring a leading `./` or `/`
+    pub fn is(&self, path: &str) -> bool {
+        let path = trim_path(path);
+        if self.prefix.is_empty() {
+            return trim_path(self.name) == path;
+        }
+        path.strip_prefix(trim_path(self.prefix))
+            .and_then(|rest| rest.strip_prefix('/'))
+            .is_some_and(|rest| rest == self.name)
+    }
+
+    /// Returns the path of the entry, without a leading `./` or `/`
+    pub fn path(&self) -> String {
+        match self.prefix.is_empty() {
+            true => String::from(trim_path(self.name)),
+            false => alloc::format!("{}/{}", trim_path(self.prefix), self.name),
+        }
+    }
+}
+
+fn trim_path(path: &str) -> &str {
+    path.trim_start_matches("./").trim_start_matches('/')
+}
+
+/// Returns a NUL terminated header field as a string
+fn field(bytes: &[u8]) -> Option<&str> {
+    let len = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+/// Parses an octal header field
+fn octal(bytes: &[u8]) -> Option<usize> {
+    let digits = field(bytes)?.trim_matches(|c| c == ' ' || c == '\0');
+    usize::from_str_radix(digits, 8).ok()
+}
+
+/// Iterates over the regular files of an archive, stopping at the end or the first bad header
+pub fn entries(archive: &[u8]) -> impl Iterator<Item = Entry<'_>> {
+    let mut offset = 0;
+    core::iter::from_fn(move || {
+        loop {
+            let header = archive.get(offset..offset + BLOCK_SIZE)?;
+            // The archive ends with zeroed blocks
+            if header.iter().all(|byte| *byte == 0) {
+                return None;
+            }
+            let size = octal(&header[124..136])?;
+            let data = archive.get(offset + BLOCK_SIZE..offset + BLOCK_SIZE + size)?;
+            offset += BLOCK_SIZE + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+            if !matches!(header[156], TYPE_FILE | TYPE_FILE_OLD) {
+                continue;
+            }
+            let prefix = match &header[257..262] == b"ustar" {
+                true => field(&header[345..500])?,
+                false => "",
+            };
+            return Some(Entry {
+                prefix,
+                name: field(&header[..100])?,
+                data,
+            });
+        }
+    })
+}
+
+/// Returns the contents of the file at `path`
+pub fn find<'a>(archive: &'a [u8], path: &str) -> Option<&'a [u8]> {
+    entries(archive).find(|entry| entry.is(path)).map(|entry| entry.data)
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn header(name: &str, prefix: &str, size: usize, ty: u8) -> [u8; BLOCK_SIZE] {
+        let mut header = [0; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = alloc::format!("{:011o}", size);
+        header[124..135].copy_from_slice(size.as_bytes());
+        header[156] = ty;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        header
+    }
+
+    #[test]
+    fn tar_find() {
+        let mut archive = Vec::new();
+        archive.extend_from_slice(&header("./etc", "", 0, b'5'));
+        archive.extend_from_slice(&header("hello.txt", "", 5, TYPE_FILE));
+        archive.extend_from_slice(b"hello");
+        archive.resize(3 * BLOCK_SIZE, 0);
+        archive.extend_from_slice(&header("splash.qoi", "boot", 2, TYPE_FILE));
+        archive.extend_from_slice(b"hi");
+        archive.resize(5 * BLOCK_SIZE + 2 * BLOCK_SIZE, 0);
+
+        assert_eq!(entries(&archive).count(), 2);
+        assert_eq!(find(&archive, "/hello.txt"), Some(&b"hello"[..]));
+        assert_eq!(find(&archive, "boot/splash.qoi"), Some(&b"hi"[..]));
+        assert_eq!(find(&archive, "splash.qoi"), None);
+        assert_eq!(find(&archive, "etc"), None);
+        // A truncated archive ends at the bad entry
+        assert_eq!(find(&archive[..4 * BLOCK_SIZE], "boot/splash.qoi"), None);
+    }
+}
@@ -0,0 +1,407 @@
+//! Deferred work
+//!
+//! Interrupt handlers should only do what can't wait, like acknowledging the device and taking
+//! its data, and defer the rest. A [`Work`] is a function and its data, scheduled on the CPU the
+//! handler runs on:
+//! - [`schedule_work`] queues it for the kernel main loop, which runs it with interrupts enabled.
+//!   There is no scheduler yet, so the main loop stands in for the worker task. Work goes to the
+//!   current CPU unless its [affinity](Work::set_affinity) excludes it, and
+//!   [load balancing](crate::sched::balance) moves pending work between CPUs.
+//! - [`schedule_tasklet`] queues high priority work, run as soon as the interrupt handler
+//!   returns, after the end of interrupt, with interrupts enabled, and before any other work.
+//!
+//! Work items are statics rather than boxed closures, because interrupt handlers can't allocate:
+//! the heap lock doesn't disable interrupts, so the handler could spin on a lock held by the code
+//! it interrupted. Closures that don't capture anything coerce to [`WorkFn`], and the data carries
+//! their state. Scheduling a work item that is still pending does nothing, and a work item may
+//! schedule itself again while it runs.
+//!
+//! The work lists are stacks that any CPU can push to or empty without a lock, so work can be
+//! queued on and moved between CPUs from interrupt handlers too.
+
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+    arch::instructions::interrupts,
+    percpu::{self, MAX_CPUS, PerCpu},
+    sched::{self, CpuSet, TaskId, latency::Budget},
+};
+
+/// A deferred function, called with the data of its [`Work`]
+pub type WorkFn = fn(data: usize);
+
+/// A deferred function and its data
+#[derive(Debug)]
+pub struct Work {
+    func: WorkFn,
+    data: AtomicUsize,
+    pending: AtomicBool,
+    next: AtomicPtr<Work>,
+    budget: Option<Budget>,
+    /// The [`CpuSet`] the work may run on
+    affinity: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AffinityError {
+    /// Work has to be allowed to run on at least one CPU
+    Empty,
+}
+
+impl fmt::Display for AffinityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => f.write_str("the affinity has no CPUs"),
+        }
+    }
+}
+
+impl core::error::Error for AffinityError {}
+
+impl Work {
+    pub const fn new(func: WorkFn, data: usize) -> Self {
+        Self {
+            func,
+            data: AtomicUsize::new(data),
+            pending: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+            budget: None,
+            affinity: AtomicU64::new(CpuSet::ALL.bits()),
+        }
+    }
+
+    /// Sets the CPUs the work may run on, see [`Self::set_affinity`]
+    pub const fn with_affinity(mut self, cpus: CpuSet) -> Self {
+        assert!(!cpus.is_empty(), "the affinity has no CPUs");
+        self.affinity = AtomicU64::new(cpus.bits());
+        self
+    }
+
+    /// Sets the longest the function should take, a warning is logged when it takes longer
+    pub const fn with_budget(mut self, budget: Budget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Changes the data the function is called with the next time it runs
+    pub fn set_data(&self, data: usize) {
+        self.data.store(data, Ordering::Relaxed);
+    }
+
+    /// Returns the CPUs the work may run on
+    pub fn affinity(&self) -> CpuSet {
+        CpuSet::from_bits(self.affinity.load(Ordering::Relaxed))
+    }
+
+    /// Sets the CPUs the work may run on, all of them by default
+    ///
+    /// Takes effect the next time the work is scheduled or balanced, work that is already queued
+    /// on a CPU that was removed still runs there once. If none of the CPUs is online when the work
+    /// is scheduled, it runs on the current CPU instead.
+    pub fn set_affinity(&self, cpus: CpuSet) -> Result<(), AffinityError> {
+        if cpus.is_empty() {
+            return Err(AffinityError::Empty);
+        }
+        self.affinity.store(cpus.bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Returns whether the work is queued and hasn't started running yet
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    fn run(&self) {
+        // Cleared first, so the function can schedule itself again
+        self.pending.store(false, Ordering::Release);
+        let previous = sched::switch_to(TaskId::work(self));
+        let section = self.budget.map(Budget::enter);
+        (self.func)(self.data.load(Ordering::Relaxed));
+        drop(section);
+        sched::switch_to(previous);
+    }
+}
+
+/// A list of pending work
+struct WorkList {
+    /// The most recently scheduled work, linked to the ones before it
+    head: AtomicPtr<Work>,
+    /// The work in the list, counting work that is still being pushed
+    len: AtomicUsize,
+}
+
+impl WorkList {
+    const fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(ptr::null_mut()),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds work to the list, returning `false` if it is already pending
+    fn push(&self, work: &'static Work) -> bool {
+        if work.pending.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        self.requeue(work);
+        true
+    }
+
+    /// Adds work that is still pending after it was taken off a list
+    fn requeue(&self, work: &'static Work) {
+        // Counted first, so taking the work never takes the length below zero
+        self.len.fetch_add(1, Ordering::Relaxed);
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            work.next.store(head, Ordering::Relaxed);
+            let new = work as *const Work as *mut Work;
+            match self
+                .head
+                .compare_exchange_weak(head, new, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Empties the list, returning its work in the order it was scheduled
+    fn take(&self) -> Batch {
+        let mut work: *const Work = self.head.swap(ptr::null_mut(), Ordering::Acquire);
+        let mut reversed: *const Work = ptr::null();
+        let mut count = 0;
+        while let Some(item) = unsafe { work.as_ref() } {
+            work = item.next.swap(reversed.cast_mut(), Ordering::Relaxed);
+            reversed = item;
+            count += 1;
+        }
+        self.len.fetch_sub(count, Ordering::Relaxed);
+        Batch(reversed)
+    }
+}
+
+/// Work taken off a list, in the order it was scheduled
+struct Batch(*const Work);
+
+impl Iterator for Batch {
+    type Item = &'static Work;
+
+    fn next(&mut self) -> Option<&'static Work> {
+        // SAFETY: Only `'static` work is ever pushed
+        let work: &'static Work = unsafe { self.0.as_ref()? };
+        self.0 = work.next.swap(ptr::null_mut(), Ordering::Relaxed);
+        Some(work)
+    }
+}
+
+struct CpuWork {
+    work: WorkList,
+    tasklets: WorkList,
+    /// Whether the CPU is running tasklets, which interrupts arriving meanwhile leave to it
+    in_tasklets: AtomicBool,
+    work_run: AtomicU64,
+    tasklets_run: AtomicU64,
+}
+
+impl CpuWork {
+    const fn new() -> Self {
+        Self {
+            work: WorkList::new(),
+            tasklets: WorkList::new(),
+            in_tasklets: AtomicBool::new(false),
+            work_run: AtomicU64::new(0),
+            tasklets_run: AtomicU64::new(0),
+        }
+    }
+}
+
+static WORK: PerCpu<CpuWork> = PerCpu::new([const { CpuWork::new() }; MAX_CPUS]);
+
+/// Returns the online CPU of a set with the least work queued
+fn least_busy(cpus: CpuSet) -> Option<usize> {
+    cpus.iter()
+        .filter(|cpu_id| percpu::cpu(*cpu_id).is_some_and(|cpu| cpu.is_online()))
+        .min_by_key(|cpu_id| queued(*cpu_id))
+}
+
+/// Returns the CPU to queue work with the given affinity on
+///
+/// That is the current CPU if the work may run on it, otherwise the online CPU it may run on with
+/// the least work queued, or the current CPU if there is none.
+fn target_cpu(affinity: CpuSet) -> usize {
+    let current = percpu::cpu_id();
+    if affinity.contains(current) {
+        return current;
+    }
+    least_busy(affinity).unwrap_or(current)
+}
+
+/// Queues work for the main loop of a CPU it may run on, returning `false` if it was already
+/// pending
+pub fn schedule_work(work: &'static Work) -> bool {
+    let cpu_id = target_cpu(work.affinity());
+    let queued = interrupts::without_interrupts(|| WORK.get_for(cpu_id).work.push(work));
+    if queued {
+        sched::trace::enqueue(TaskId::work(work), cpu_id);
+    }
+    queued
+}
+
+/// Queues work to run when the current interrupt handler returns, or at the next interrupt or
+/// main loop iteration if there is no interrupt handler running
+///
+/// Tasklets always run on the CPU that scheduled them, whatever their affinity. Returns `false` if
+/// the work was already pending.
+pub fn schedule_tasklet(work: &'static Work) -> bool {
+    let queued = interrupts::without_interrupts(|| WORK.get().tasklets.push(work));
+    if queued {
+        sched::trace::enqueue(TaskId::work(work), percpu::cpu_id());
+    }
+    queued
+}
+
+/// Runs the work of a list until it stays empty, with interrupts enabled
+fn drain(list: &WorkList, count: &AtomicU64) {
+    loop {
+        let batch = interrupts::without_interrupts(|| list.take());
+        let mut ran = 0;
+        for work in batch {
+            work.run();
+            ran += 1;
+        }
+        if ran == 0 {
+            return;
+        }
+        count.fetch_add(ran, Ordering::Relaxed);
+    }
+}
+
+/// Runs the pending tasklets of the current CPU
+///
+/// Called when an interrupt handler returns, with interrupts disabled, which are enabled while
+/// the tasklets run. Does nothing if the interrupt arrived while tasklets were running, the
+/// interrupted loop picks up whatever the handler scheduled.
+pub(crate) fn run_tasklets() {
+    let cpu = WORK.get();
+    if cpu.in_tasklets.swap(true, Ordering::Acquire) {
+        return;
+    }
+    let enabled = interrupts::are_enabled();
+    unsafe { interrupts::enable() };
+    drain(&cpu.tasklets, &cpu.tasklets_run);
+    if !enabled {
+        unsafe { interrupts::disable() };
+    }
+    cpu.in_tasklets.store(false, Ordering::Release);
+}
+
+/// Runs the pending tasklets and work of the current CPU, from the main loop
+pub fn poll() {
+    run_tasklets();
+    let cpu = WORK.get();
+    drain(&cpu.work, &cpu.work_run);
+}
+
+/// Returns how much work is queued for the main loop of a CPU
+pub fn queued(cpu_id: usize) -> usize {
+    WORK.get_for(cpu_id).work.len()
+}
+
+/// Moves up to `count` pending work items from the main loop of one CPU to another, oldest
+/// first, returning how many were moved
+///
+/// Work that may not run on `to` stays where it was.
+pub(crate) fn migrate(from: usize, to: usize, count: usize) -> usize {
+    let (source, target) = (&WORK.get_for(from).work, &WORK.get_for(to).work);
+    let mut moved = 0;
+    for work in source.take() {
+        if moved < count && work.affinity().contains(to) {
+            target.requeue(work);
+            sched::trace::migrate(TaskId::work(work), to);
+            moved += 1;
+        } else {
+            source.requeue(work);
+        }
+    }
+    moved
+}
+
+/// Moves all pending work off the main loop of a CPU going offline, returning how much was moved
+///
+/// Work goes to the least busy online CPU it may run on, or if there is none, to the least busy
+/// online CPU regardless of its affinity.
+pub(crate) fn evacuate(from: usize) -> usize {
+    let source = &WORK.get_for(from).work;
+    let mut moved = 0;
+    for work in source.take() {
+        let others = CpuSet::ALL.without(from);
+        let Some(to) = least_busy(work.affinity().without(from)).or_else(|| least_busy(others)) else {
+            source.requeue(work);
+            continue;
+        };
+        WORK.get_for(to).work.requeue(work);
+        sched::trace::migrate(TaskId::work(work), to);
+        moved += 1;
+    }
+    moved
+}
+
+pub fn init() {
+    crate::stats::register("workqueue", dump_stats);
+}
+
+fn dump_stats(out: &mut dyn fmt::Write) -> fmt::Result {
+    for (cpu_id, cpu) in WORK.iter().enumerate() {
+        if percpu::cpu(cpu_id).is_none() {
+            continue;
+        }
+        writeln!(
+            out,
+            "cpu{}: {} work, {} tasklets, {} queued",
+            cpu_id,
+            cpu.work_run.load(Ordering::Relaxed),
+            cpu.tasklets_run.load(Ordering::Relaxed),
+            cpu.work.len()
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use alloc::{boxed::Box, vec::Vec};
+
+    use super::*;
+
+    #[test]
+    fn workqueue_order() {
+        let list = WorkList::new();
+        let works: Vec<&'static Work> = (0..3)
+            .map(|idx| &*Box::leak(Box::new(Work::new(|_| {}, idx))))
+            .collect();
+        assert!(list.push(works[0]));
+        assert!(list.push(works[1]));
+        assert!(!list.push(works[0]));
+        assert!(list.push(works[2]));
+        assert!(works[1].is_pending());
+        assert_eq!(list.len(), 3);
+
+        let order: Vec<usize> = list.take().map(|work| work.data.load(Ordering::Relaxed)).collect();
+        assert_eq!(order, [0, 1, 2]);
+        assert_eq!(list.take().count(), 0);
+        assert_eq!(list.len(), 0);
+
+        // Taken work is still pending until it runs
+        assert!(!list.push(works[1]));
+        works[1].run();
+        assert!(list.push(works[1]));
+        assert_eq!(list.take().count(), 1);
+    }
+}